@@ -1,74 +1,1099 @@
-use anyhow::{bail, Result};
-use console::style;
+use crate::retry::{is_transport_error, is_transport_error_chain, Retry};
+use anyhow::{anyhow, bail, Error, Result};
+use console::{style, Term};
+use prost::Message;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 use tokio::{
-    fs::{create_dir_all, read, remove_file, File},
-    io::AsyncWriteExt,
+    fs::{create_dir_all, read, remove_dir_all, remove_file, write},
+    process::Command,
 };
-use tonic::Code::NotFound;
-use tracing::info;
-use vorpal_schema::vorpal::{
-    artifact::v0::{
-        artifact_service_client::ArtifactServiceClient, Artifact, ArtifactBuildRequest, ArtifactId,
-        ArtifactSystem,
-    },
-    registry::v0::{
-        registry_service_client::RegistryServiceClient, RegistryKind, RegistryPushRequest,
-        RegistryRequest,
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    codec::CompressionEncoding,
+    transport::Channel,
+    Code::{NotFound, OutOfRange, Unimplemented},
+};
+use tracing::{debug, info, warn};
+use vorpal_registry::EXISTS_BATCH_SIZE_MAX;
+use vorpal_schema::{
+    get_artifact_system, is_artifact_system_buildable,
+    sources::SourceError,
+    vorpal::{
+        artifact::v0::{
+            artifact_service_client::ArtifactServiceClient, Artifact, ArtifactBuildDuration,
+            ArtifactBuildDurationKind, ArtifactBuildEvent, ArtifactBuildPhase,
+            ArtifactBuildRequest, ArtifactBuildState, ArtifactBuildStatusRequest,
+            ArtifactBuildStream, ArtifactId, ArtifactInfoRequest, ArtifactProvenance,
+            ArtifactProvenanceEnvelope, ArtifactPushPolicy, ArtifactSourceId,
+            ArtifactSourceRevision, ArtifactStepEnvironment, ArtifactSystem,
+            ArtifactSystem::UnknownSystem,
+        },
+        config::v0::ConfigSourceRevision,
+        registry::v0::{
+            registry_service_client::RegistryServiceClient, RegistryCompression,
+            RegistryDeltaEntry, RegistryDeltaIndexRequest, RegistryDeltaRecipe,
+            RegistryExistsBatchRequest, RegistryKind, RegistryListRequest, RegistryPushRequest,
+            RegistryRequest,
+        },
     },
 };
 use vorpal_store::{
-    archives::unpack_zstd,
+    archives::{unpack_archive, ArchiveWriter},
+    blobs::{dedup_path, is_store_dedup_enabled},
+    chunks::{stream_chunks, wait_network_limit, ChunkBounds},
+    deltas::{
+        encode_delta, get_delta_entries, DeltaEntry, DELTA_ARCHIVE_SIZE_MIN, DELTA_SIZE_RATIO_MAX,
+    },
+    dictionaries::{decode_archive, decompress_archive_dictionary_file, get_dictionary_id},
+    digests::{verify_pulled_data, ArchiveDigest, ArtifactDigest},
+    grpc::{get_channel, get_max_message_size, RegistryChannel, RegistryClients, RegistryUrls},
+    hashes::{get_file_hashes, get_hashes_digest},
+    manifests::{find_manifest_name, get_artifact_manifest, write_manifest},
     paths::{
-        get_artifact_path, get_cache_archive_path, get_file_paths, get_private_key_path,
-        set_timestamps,
+        get_artifact_lock_path, get_artifact_path, get_cache_archive_path, get_dictionary_path,
+        get_file_paths, get_private_key_path, get_public_key_path, sanitize_file,
     },
-    temps::create_sandbox_file,
+    temps::{create_sandbox_dir, create_sandbox_file},
 };
+use vorpal_worker::push::PushPolicy;
 
-const DEFAULT_CHUNKS_SIZE: usize = 8192; // default grpc limit
+// Minimum registry requests checked with `exists_batch` instead of one `exists` each
+const EXISTS_BATCH_SIZE_MIN: usize = 4;
 
 fn get_prefix(name: &str) -> String {
     style(format!("{} |>", name)).bold().to_string()
 }
 
-pub async fn build(
-    artifact: &Artifact,
+pub fn get_bytes_display(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1}{}", size, units[unit])
+}
+
+/// Returns the line shown for a build event, the legacy `output` sent with it when there is one
+/// so details such as the manifest hash and transfer summary are kept.
+fn get_event_display(event: &ArtifactBuildEvent, output: &str) -> String {
+    let phase = ArtifactBuildPhase::try_from(event.phase)
+        .unwrap_or(ArtifactBuildPhase::UnknownPhase)
+        .as_str_name()
+        .to_lowercase();
+
+    if !output.is_empty() {
+        return format!("{}: {}", phase, output);
+    }
+
+    let mut display = phase;
+
+    if !event.source.is_empty() {
+        display = format!("{} {}", display, event.source);
+    }
+
+    match (event.bytes_done, event.bytes_total) {
+        (0, 0) => display,
+        (done, 0) => format!("{}: {}", display, get_bytes_display(done)),
+        (done, total) => format!(
+            "{}: {}/{} ({}%)",
+            display,
+            get_bytes_display(done),
+            get_bytes_display(total),
+            done.saturating_mul(100) / total
+        ),
+    }
+}
+
+// Checks that `dictionary` is the dictionary archives compressed with `dictionary_id` need
+fn verify_dictionary_id(dictionary: &[u8], dictionary_id: u32) -> Result<()> {
+    let id = get_dictionary_id(dictionary)?;
+
+    if id != dictionary_id {
+        bail!(
+            "dictionary id mismatch: expected {}, got {}",
+            dictionary_id,
+            id
+        );
+    }
+
+    Ok(())
+}
+
+async fn get_dictionary(
+    dictionary_id: u32,
+    registry: &mut RegistryServiceClient<RegistryChannel>,
+) -> Result<Vec<u8>> {
+    let dictionary_path = get_dictionary_path(&dictionary_id.to_string());
+
+    if dictionary_path.exists() {
+        let dictionary = read(&dictionary_path).await?;
+
+        match verify_dictionary_id(&dictionary, dictionary_id) {
+            Ok(()) => return Ok(dictionary),
+            Err(err) => warn!("pulling dictionary again: {}", err),
+        }
+    }
+
+    let pull_request = RegistryRequest {
+        accept_dictionary: false,
+        hash: dictionary_id.to_string(),
+        kind: RegistryKind::Dictionary as i32,
+        name: "dictionary".to_string(),
+    };
+
+    let mut response = registry.pull(pull_request).await?.into_inner();
+    let mut dictionary = vec![];
+    let mut dictionary_digest = None;
+
+    while let Some(res) = response.message().await? {
+        if !res.data_digest.is_empty() {
+            dictionary_digest = Some(res.data_digest.parse::<ArchiveDigest>()?);
+        }
+
+        dictionary.extend(res.data);
+    }
+
+    verify_pulled_data(dictionary_digest.as_ref(), &dictionary, "dictionary")?;
+
+    verify_dictionary_id(&dictionary, dictionary_id)?;
+
+    write(&dictionary_path, &dictionary).await?;
+
+    Ok(dictionary)
+}
+
+/// Returns whether each of `requests` exists in the registry, in order, retrying requests
+/// interrupted by connection failures.
+async fn get_registry_exists(
+    registry: &mut RegistryServiceClient<RegistryChannel>,
+    requests: Vec<RegistryRequest>,
+) -> Result<Vec<bool>> {
+    let mut retry = Retry::new("registry exists");
+
+    loop {
+        match try_get_registry_exists(registry, requests.clone()).await {
+            Err(err) if is_transport_error_chain(&err) => retry.wait(format!("{:#}", err)).await?,
+            result => return result,
+        }
+    }
+}
+
+/// Returns whether each of `requests` exists in any of the registries, in order. Registries are
+/// asked in order of priority for the requests not found yet, and a registry failing to answer
+/// is skipped with a warning unless no registry answered.
+async fn get_registries_exists(
+    registries: &RegistryClients,
+    requests: Vec<RegistryRequest>,
+) -> Result<Vec<bool>> {
+    let mut answered = false;
+    let mut error = None;
+    let mut exists = vec![false; requests.len()];
+
+    for (index, registry) in registries.clients().iter().enumerate() {
+        let missing = (0..requests.len())
+            .filter(|request| !exists[*request])
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            break;
+        }
+
+        let missing_requests = missing
+            .iter()
+            .map(|request| requests[*request].clone())
+            .collect();
+
+        let mut client = registry.client.clone();
+
+        match get_registry_exists(&mut client, missing_requests).await {
+            Ok(found) => {
+                answered = true;
+
+                for (request, found) in missing.into_iter().zip(found) {
+                    exists[request] = found;
+                }
+            }
+
+            Err(err) => {
+                if index + 1 < registries.clients().len() {
+                    warn!(
+                        "registry {} failed to check, trying the next registry: {:#}",
+                        registry.url, err
+                    );
+                }
+
+                error = Some(err);
+            }
+        }
+    }
+
+    match (answered, error) {
+        (false, Some(err)) => Err(err),
+        _ => Ok(exists),
+    }
+}
+
+/// Returns whether each of `requests` exists in the registry, in order. More than a handful of
+/// requests are checked with `exists_batch` round trips, falling back to one `exists` request
+/// each for registries without it.
+async fn try_get_registry_exists(
+    registry: &mut RegistryServiceClient<RegistryChannel>,
+    requests: Vec<RegistryRequest>,
+) -> Result<Vec<bool>> {
+    if requests.len() >= EXISTS_BATCH_SIZE_MIN {
+        let mut exists = vec![];
+
+        for chunk in requests.chunks(EXISTS_BATCH_SIZE_MAX) {
+            let request = RegistryExistsBatchRequest {
+                requests: chunk.to_vec(),
+            };
+
+            match registry.exists_batch(request).await {
+                Ok(response) => exists.extend(response.into_inner().exists),
+                Err(status) if status.code() == Unimplemented => break,
+                Err(status) => return Err(Error::new(status).context("registry exists error")),
+            }
+        }
+
+        if exists.len() == requests.len() {
+            return Ok(exists);
+        }
+
+        debug!("registry does not support exists batches");
+    }
+
+    let mut exists = vec![];
+
+    for request in requests {
+        match registry.exists(request).await {
+            Ok(_) => exists.push(true),
+            Err(status) if status.code() == NotFound => exists.push(false),
+            Err(status) => return Err(Error::new(status).context("registry exists error")),
+        }
+    }
+
+    Ok(exists)
+}
+
+/// Returns the name of the artifact `digest` from the local manifests, or else the first of the
+/// registries listing it.
+pub async fn get_artifact_name(
+    registries: &RegistryClients,
+    digest: &ArtifactDigest,
+) -> Result<String> {
+    if let Some(name) = find_manifest_name(digest).await? {
+        return Ok(name);
+    }
+
+    let mut answered = false;
+    let mut error = None;
+
+    for (index, registry) in registries.clients().iter().enumerate() {
+        let mut client = registry.client.clone();
+
+        match get_registry_artifact_name(&mut client, digest.as_str()).await {
+            Ok(Some(name)) => return Ok(name),
+            Ok(None) => answered = true,
+            Err(err) => {
+                if index + 1 < registries.clients().len() {
+                    warn!(
+                        "registry {} failed to list artifacts, trying the next registry: {:#}",
+                        registry.url, err
+                    );
+                }
+
+                error = Some(err);
+            }
+        }
+    }
+
+    match (answered, error) {
+        (false, Some(err)) => Err(err),
+        _ => bail!("artifact not found: {}", digest),
+    }
+}
+
+async fn get_registry_artifact_name(
+    registry: &mut RegistryServiceClient<RegistryChannel>,
+    digest: &str,
+) -> Result<Option<String>> {
+    let mut page_token = String::new();
+
+    loop {
+        let response = registry
+            .list(RegistryListRequest {
+                name_prefix: String::new(),
+                page_size: 0,
+                page_token,
+            })
+            .await?
+            .into_inner();
+
+        if let Some(artifact) = response.artifacts.iter().find(|a| a.hash == digest) {
+            return Ok(Some(artifact.name.clone()));
+        }
+
+        if response.next_page_token.is_empty() {
+            return Ok(None);
+        }
+
+        page_token = response.next_page_token;
+    }
+}
+
+/// Pulls the provenance of an artifact and verifies its signature against any of the trusted
+/// public keys.
+pub async fn get_provenance(
+    registries: &RegistryUrls,
+    digest: &str,
+    name: Option<String>,
+    public_key_paths: &[PathBuf],
+) -> Result<ArtifactProvenance> {
+    let registries = registries.connect().await?;
+
+    let name = match name {
+        Some(name) => name,
+        None => get_artifact_name(&registries, &digest.parse()?).await?,
+    };
+
+    let pull_request = RegistryRequest {
+        accept_dictionary: false,
+        hash: digest.to_string(),
+        kind: RegistryKind::ArtifactProvenance as i32,
+        name: name.clone(),
+    };
+
+    let mut registry = match registries.find(&pull_request).await {
+        Ok(Some(registry)) => registry,
+        Ok(None) => bail!("provenance not found: {}-{}", name, digest),
+        Err(status) => bail!("failed to pull provenance: {}", status),
+    };
+
+    let mut response = registry.client.pull(pull_request).await?.into_inner();
+
+    let mut provenance_data = vec![];
+    let mut provenance_digest = None;
+
+    while let Some(res) = response.message().await? {
+        if !res.data_digest.is_empty() {
+            provenance_digest = Some(res.data_digest.parse::<ArchiveDigest>()?);
+        }
+
+        provenance_data.extend(res.data);
+    }
+
+    verify_pulled_data(provenance_digest.as_ref(), &provenance_data, &name)?;
+
+    let envelope: ArtifactProvenanceEnvelope = serde_json::from_slice(&provenance_data)?;
+
+    let signature = vorpal_notary::decode_signature(&envelope.signature)?;
+
+    let mut verified = false;
+
+    for public_key_path in public_key_paths {
+        if !public_key_path.exists() {
+            bail!("public key not found: {}", public_key_path.display());
+        }
+
+        let result = vorpal_notary::verify(
+            public_key_path.clone(),
+            envelope.payload.as_bytes(),
+            &signature,
+        )
+        .await;
+
+        if result.is_ok() {
+            debug!("provenance verified: {}", public_key_path.display());
+
+            verified = true;
+
+            break;
+        }
+    }
+
+    if !verified {
+        bail!("provenance signature not trusted: {}-{}", name, digest);
+    }
+
+    let provenance: ArtifactProvenance = serde_json::from_str(&envelope.payload)?;
+
+    // The signed payload must describe the requested artifact
+
+    match provenance.artifact.as_ref() {
+        Some(artifact) if artifact.hash == digest && artifact.name == name => Ok(provenance),
+        _ => bail!("provenance does not match artifact: {}-{}", name, digest),
+    }
+}
+
+/// Parses `NAME=value` secrets, reading the value from the environment when only `NAME` is given.
+pub fn get_secrets(secrets: &[String]) -> Result<Vec<ArtifactStepEnvironment>> {
+    let mut environments = vec![];
+
+    for secret in secrets {
+        let (key, value) = match secret.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => match env::var(secret) {
+                Ok(value) => (secret.to_string(), value),
+                Err(_) => bail!("secret not found in environment: {}", secret),
+            },
+        };
+
+        if key.is_empty() {
+            bail!("secret name is missing: {}", secret);
+        }
+
+        environments.push(ArtifactStepEnvironment { key, value });
+    }
+
+    Ok(environments)
+}
+
+/// Checks the keys builds sign and encrypt with exist and parse, before any source work starts.
+pub async fn check_keys() -> Result<()> {
+    vorpal_notary::get_private_key(get_private_key_path()).await?;
+    vorpal_notary::get_public_key(get_public_key_path()).await?;
+
+    Ok(())
+}
+
+/// Encrypts secret values with the public key, so only workers holding the private key can read
+/// them from build requests.
+pub async fn encrypt_secrets(
+    public_key_path: &Path,
+    secrets: Vec<ArtifactStepEnvironment>,
+) -> Result<Vec<ArtifactStepEnvironment>> {
+    let mut encrypted = vec![];
+
+    for secret in secrets {
+        let value = vorpal_notary::encrypt(public_key_path.to_path_buf(), secret.value.as_bytes())
+            .await
+            .map_err(|err| anyhow!("failed to encrypt secret {}: {}", secret.key, err))?;
+
+        encrypted.push(ArtifactStepEnvironment {
+            key: secret.key,
+            value: vorpal_notary::encode_encrypted(&value),
+        });
+    }
+
+    Ok(encrypted)
+}
+
+/// Workers by the system they build, the first worker of each system is used.
+pub type ArtifactWorkers = BTreeMap<ArtifactSystem, ArtifactServiceClient<Channel>>;
+
+/// Connects to each worker of `--service`, given as `URL` or `SYSTEM=URL`.
+///
+/// Workers without a system prefix are asked for the system they build.
+pub async fn get_workers(services: &[String]) -> Result<ArtifactWorkers> {
+    let mut workers = ArtifactWorkers::new();
+
+    for service in services {
+        let (system, url) = match service.split_once('=') {
+            Some((system, url)) if !system.contains("://") => (Some(system), url),
+            _ => (None, service.as_str()),
+        };
+
+        // Build requests carry the whole artifact, compressed so large manifests fit
+
+        let mut worker = match get_channel(url).await {
+            Ok(channel) => ArtifactServiceClient::new(channel)
+                .send_compressed(CompressionEncoding::Gzip)
+                .max_decoding_message_size(get_max_message_size())
+                .max_encoding_message_size(get_max_message_size()),
+            Err(err) => bail!("failed to connect to worker: {}", err),
+        };
+
+        let system = match system {
+            Some(system) => get_artifact_system::<ArtifactSystem>(system),
+            None => match worker.info(ArtifactInfoRequest {}).await {
+                Ok(response) => response.into_inner().system(),
+                Err(status) => bail!("failed to get system of worker {}: {}", url, status),
+            },
+        };
+
+        if system == UnknownSystem {
+            bail!("unknown system of worker: {}", service);
+        }
+
+        debug!("worker {}: {}", system.as_str_name(), url);
+
+        workers.entry(system).or_insert(worker);
+    }
+
+    Ok(workers)
+}
+
+fn get_worker_system(workers: &ArtifactWorkers, target: ArtifactSystem) -> Result<ArtifactSystem> {
+    if workers.contains_key(&target) {
+        return Ok(target);
+    }
+
+    // Cross-built targets have no worker of their own
+
+    let worker = workers
+        .keys()
+        .find(|system| is_artifact_system_buildable(**system, target));
+
+    match worker {
+        Some(system) => Ok(*system),
+        None => bail!(
+            "no worker for target {} (workers: {})",
+            target.as_str_name(),
+            workers
+                .keys()
+                .map(|system| system.as_str_name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn get_worker(
+    workers: &ArtifactWorkers,
+    target: ArtifactSystem,
+) -> Result<ArtifactServiceClient<Channel>> {
+    let system = get_worker_system(workers, target)?;
+
+    Ok(workers[&system].clone())
+}
+
+/// Where an artifact would come from in a build, shown by `vorpal artifact --plan`.
+#[derive(Clone, Copy)]
+pub enum ArtifactPlanStatus {
+    Build(Option<ArtifactSystem>),
+    Cached,
+    Pull,
+}
+
+impl ArtifactPlanStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArtifactPlanStatus::Build(_) => "build",
+            ArtifactPlanStatus::Cached => "cached",
+            ArtifactPlanStatus::Pull => "pull",
+        }
+    }
+}
+
+/// Returns whether the artifact is complete in the local store, as checked first by `build`.
+fn is_artifact_cached(artifact_id: &ArtifactId) -> Result<bool> {
+    let artifact_path = get_artifact_path(&artifact_id.hash.parse()?, &artifact_id.name);
+    let artifact_lock_path = get_artifact_lock_path(&artifact_id.hash.parse()?, &artifact_id.name);
+
+    Ok(artifact_path.exists() && !artifact_lock_path.exists())
+}
+
+fn print_plan_tree(
     artifact_id: &ArtifactId,
+    artifacts: &HashMap<ArtifactId, Artifact>,
+    statuses: &HashMap<ArtifactId, ArtifactPlanStatus>,
+    printed: &mut HashSet<ArtifactId>,
+    prefix: &str,
+    branch: &str,
+) {
+    let (status, system) = match statuses.get(artifact_id) {
+        Some(ArtifactPlanStatus::Build(Some(system))) => ("build", system.as_str_name()),
+        Some(ArtifactPlanStatus::Build(None)) => ("build", "no worker"),
+        Some(ArtifactPlanStatus::Cached) => ("cached", ""),
+        Some(ArtifactPlanStatus::Pull) => ("pull", ""),
+        None => ("", ""),
+    };
+
+    // Artifacts shared by several dependents are expanded once, like `cargo tree`
+
+    let expanded = !printed.insert(artifact_id.clone());
+
+    println!(
+        "{:<6}  {:<16}  {}{}{}",
+        status,
+        system,
+        branch,
+        artifact_id.name,
+        if expanded { " (*)" } else { "" }
+    );
+
+    if expanded {
+        return;
+    }
+
+    let Some(artifact) = artifacts.get(artifact_id) else {
+        return;
+    };
+
+    for (index, dependency) in artifact.artifacts.iter().enumerate() {
+        let last = index == artifact.artifacts.len() - 1;
+
+        let dependency_branch = format!("{}{}", prefix, if last { "└── " } else { "├── " });
+        let dependency_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+
+        print_plan_tree(
+            dependency,
+            artifacts,
+            statuses,
+            printed,
+            &dependency_prefix,
+            &dependency_branch,
+        );
+    }
+}
+
+/// Returns whether each artifact is cached in the local store, can be pulled from the registry
+/// or must be built, and on which worker system.
+pub async fn get_plan_statuses(
+    artifacts: &HashMap<ArtifactId, Artifact>,
     artifact_target: ArtifactSystem,
-    registry: &str,
-    service: &str,
-) -> Result<()> {
-    // 1. Check if artifact exists (local)
+    registries: &RegistryUrls,
+    workers: &ArtifactWorkers,
+) -> Result<HashMap<ArtifactId, ArtifactPlanStatus>> {
+    let registries = registries.connect().await?;
+
+    let mut statuses = HashMap::new();
+
+    let mut registry_ids = vec![];
+    let mut registry_requests = vec![];
+
+    for id in artifacts.keys() {
+        if is_artifact_cached(id)? {
+            statuses.insert(id.clone(), ArtifactPlanStatus::Cached);
 
-    let artifact_path = get_artifact_path(&artifact_id.hash, &artifact_id.name);
+            continue;
+        }
+
+        registry_ids.push(id.clone());
+        registry_requests.push(RegistryRequest {
+            accept_dictionary: true,
+            hash: id.hash.clone(),
+            kind: RegistryKind::Artifact as i32,
+            name: id.name.clone(),
+        });
+    }
+
+    let registry_exists = get_registries_exists(&registries, registry_requests).await?;
+
+    for (id, exists) in registry_ids.into_iter().zip(registry_exists) {
+        let status = match exists {
+            true => ArtifactPlanStatus::Pull,
+            false => ArtifactPlanStatus::Build(get_worker_system(workers, artifact_target).ok()),
+        };
+
+        statuses.insert(id, status);
+    }
+
+    Ok(statuses)
+}
+
+/// Prints the dependency tree of `artifact_id` with the plan status of each artifact.
+///
+/// Returns the number of artifacts that must be built.
+pub async fn print_plan(
+    artifact_id: &ArtifactId,
+    artifacts: &HashMap<ArtifactId, Artifact>,
+    artifact_target: ArtifactSystem,
+    registries: &RegistryUrls,
+    workers: &ArtifactWorkers,
+) -> Result<usize> {
+    let statuses = get_plan_statuses(artifacts, artifact_target, registries, workers).await?;
+
+    println!("{:<6}  {:<16}  ARTIFACT", "STATUS", "SYSTEM");
+
+    print_plan_tree(
+        artifact_id,
+        artifacts,
+        &statuses,
+        &mut HashSet::new(),
+        "",
+        "",
+    );
+
+    let mut build = 0;
+    let mut cached = 0;
+    let mut pull = 0;
+
+    for status in statuses.values() {
+        match status {
+            ArtifactPlanStatus::Build(_) => build += 1,
+            ArtifactPlanStatus::Cached => cached += 1,
+            ArtifactPlanStatus::Pull => pull += 1,
+        }
+    }
+
+    println!("{} cached, {} pull, {} build", cached, pull, build);
+
+    Ok(build)
+}
 
+/// Artifacts built by a worker even when cached in the store or registry.
+#[derive(Clone, Debug, Default)]
+pub struct ArtifactForce {
+    /// Forces every artifact of the build (`--no-cache`).
+    pub all: bool,
+    /// Names or digests of forced artifacts (`--force-build`).
+    pub artifacts: Vec<String>,
+    /// Compares the output of forced artifacts with the cached output.
+    pub check_reproducibility: bool,
+}
+
+impl ArtifactForce {
+    pub fn is_forced(&self, artifact_id: &ArtifactId) -> bool {
+        self.all
+            || self
+                .artifacts
+                .iter()
+                .any(|a| *a == artifact_id.name || *a == artifact_id.hash)
+    }
+}
+
+/// Hashes of output files by path relative to `path`.
+fn get_artifact_file_hashes(path: &Path) -> Result<BTreeMap<String, String>> {
+    let files = get_file_paths(&path.to_path_buf(), vec![], vec![])?
+        .into_iter()
+        .filter(|file| file.is_symlink() || file.is_file())
+        .collect::<Vec<_>>();
+
+    let hashes = get_file_hashes(&files)?;
+
+    let mut file_hashes = BTreeMap::new();
+
+    for (file, hash) in files.iter().zip(hashes) {
+        let relative_path = file.strip_prefix(path)?.display().to_string();
+
+        file_hashes.insert(relative_path, hash);
+    }
+
+    Ok(file_hashes)
+}
+
+fn get_artifact_files_digest(file_hashes: &BTreeMap<String, String>) -> Result<String> {
+    get_hashes_digest(
+        file_hashes
+            .iter()
+            .map(|(path, hash)| format!("{}:{}", path, hash))
+            .collect(),
+    )
+}
+
+/// Hashes the cached output from the store, or the registry when not in the store, and removes
+/// it from the store so the forced build can not be confused with it.
+async fn get_cached_file_hashes(
+    artifact_id: &ArtifactId,
+    artifact_path: &Path,
+    registries: &RegistryClients,
+) -> Result<BTreeMap<String, String>> {
     if artifact_path.exists() {
+        let file_hashes = get_artifact_file_hashes(artifact_path)?;
+
+        remove_dir_all(artifact_path).await?;
+
+        return Ok(file_hashes);
+    }
+
+    let pull_path = create_sandbox_dir().await?;
+
+    let pulled = pull_artifact(artifact_id, &pull_path, registries).await;
+
+    let file_hashes = match pulled {
+        Ok(true) => get_artifact_file_hashes(&pull_path),
+        Ok(false) => Err(anyhow!(
+            "no cached output to check reproducibility: {}-{}",
+            artifact_id.name,
+            artifact_id.hash
+        )),
+        Err(err) => Err(err),
+    };
+
+    remove_dir_all(&pull_path).await?;
+
+    file_hashes
+}
+
+/// Fails listing the differing files when the built output does not match `cached_hashes`.
+fn check_reproducibility(
+    artifact_id: &ArtifactId,
+    artifact_path: &Path,
+    cached_hashes: &BTreeMap<String, String>,
+) -> Result<()> {
+    if !artifact_path.exists() {
+        bail!(
+            "built output not found in the local store, reproducibility checks require a local worker: {}",
+            artifact_path.display()
+        );
+    }
+
+    let built_hashes = get_artifact_file_hashes(artifact_path)?;
+
+    let cached_digest = get_artifact_files_digest(cached_hashes)?;
+    let built_digest = get_artifact_files_digest(&built_hashes)?;
+
+    if cached_digest == built_digest {
+        info!(
+            "{} reproducible: {}",
+            get_prefix(&artifact_id.name),
+            built_digest
+        );
+
         return Ok(());
     }
 
-    // 2. Check if artifact exists (registry)
+    warn!(
+        "{} not reproducible: {} (cached) != {} (built)",
+        get_prefix(&artifact_id.name),
+        cached_digest,
+        built_digest
+    );
+
+    let paths = cached_hashes
+        .keys()
+        .chain(built_hashes.keys())
+        .collect::<BTreeSet<_>>();
+
+    for path in paths {
+        match (cached_hashes.get(path), built_hashes.get(path)) {
+            (Some(cached), Some(built)) if cached != built => {
+                warn!("  changed: {} ({} != {})", path, cached, built)
+            }
+            (Some(_), None) => warn!("  removed: {}", path),
+            (None, Some(_)) => warn!("  added: {}", path),
+            _ => {}
+        }
+    }
+
+    bail!("artifact not reproducible: {}", artifact_id.name)
+}
+
+// Set to the environment name inside `vorpal shell`
+pub const SHELL_ENV_KEY: &str = "VORPAL_ENV";
+
+/// Entrypoint, arguments and environment variables to run from a built artifact.
+pub struct ArtifactRun {
+    pub arguments: Vec<String>,
+    pub entrypoint: Option<String>,
+    pub environments: BTreeMap<String, String>,
+}
+
+/// Runs `$SHELL` (or `command` with it) through the `bin/activate` script of shell artifact
+/// `name`.
+pub fn get_shell_run(name: &str, command: Option<&str>) -> ArtifactRun {
+    if let Ok(current) = env::var(SHELL_ENV_KEY) {
+        warn!("already inside shell environment: {}", current);
+    }
+
+    let shell = env::var("SHELL").unwrap_or("/bin/bash".to_string());
+
+    let mut arguments = vec![shell];
+
+    if let Some(command) = command {
+        arguments.push("-c".to_string());
+        arguments.push(command.to_string());
+    }
+
+    ArtifactRun {
+        arguments,
+        entrypoint: Some("bin/activate".to_string()),
+        environments: BTreeMap::from([(SHELL_ENV_KEY.to_string(), name.to_string())]),
+    }
+}
+
+/// Runs the entrypoint of the built artifact, `bin/<name>` by default, with the `bin` paths of
+/// the artifact and its artifacts prepended to `PATH`. Returns the exit code of the entrypoint.
+pub async fn run_entrypoint(
+    artifact_id: &ArtifactId,
+    build_order: &[ArtifactId],
+    run: &ArtifactRun,
+) -> Result<i32> {
+    let artifact_path = get_artifact_path(&artifact_id.hash.parse()?, &artifact_id.name);
+
+    let entrypoint = match &run.entrypoint {
+        Some(entrypoint) => artifact_path.join(entrypoint),
+        None => artifact_path.join("bin").join(&artifact_id.name),
+    };
+
+    if !entrypoint.is_file() {
+        bail!("entrypoint not found: {}", entrypoint.display());
+    }
+
+    // Dependents come later in the build order, so reversed the artifact itself is first
+
+    let mut paths = vec![];
+
+    for id in build_order.iter().rev() {
+        let bin_path = get_artifact_path(&id.hash.parse()?, &id.name).join("bin");
+
+        if bin_path.is_dir() && !paths.contains(&bin_path) {
+            paths.push(bin_path);
+        }
+    }
+
+    if let Some(path) = env::var_os("PATH") {
+        paths.extend(env::split_paths(&path));
+    }
+
+    let status = Command::new(&entrypoint)
+        .args(&run.arguments)
+        .env("PATH", env::join_paths(paths)?)
+        .envs(&run.environments)
+        .status()
+        .await
+        .map_err(|err| anyhow!("failed to run {}: {}", entrypoint.display(), err))?;
+
+    // Signals are reported the way shells do
+
+    Ok(status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1))
+}
+
+/// Outcome of one artifact build, collected for the summary of `vorpal artifact`.
+pub struct ArtifactBuildSummary {
+    pub cached: bool,
+    pub delta: ArtifactDeltaSummary,
+    pub duration: Duration,
+    pub durations: Vec<ArtifactBuildDuration>,
+    pub name: String,
+}
+
+/// Bytes of sources pushed as deltas, and of the archives they replaced.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ArtifactDeltaSummary {
+    pub archive_bytes: u64,
+    pub pushed_bytes: u64,
+}
+
+impl ArtifactDeltaSummary {
+    fn add(&mut self, other: &Self) {
+        self.archive_bytes += other.archive_bytes;
+        self.pushed_bytes += other.pushed_bytes;
+    }
+}
+
+impl std::fmt::Display for ArtifactDeltaSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {}, {:.0}% saved",
+            get_bytes_display(self.pushed_bytes),
+            get_bytes_display(self.archive_bytes),
+            (1.0 - self.pushed_bytes as f64 / self.archive_bytes.max(1) as f64) * 100.0
+        )
+    }
+}
+
+pub fn get_duration_display(duration_ms: u64) -> String {
+    format!("{:.1}s", duration_ms as f64 / 1000.0)
+}
+
+/// Prints where build time went, slowest first, with cache hit and built counts.
+pub fn print_summary(summaries: &[ArtifactBuildSummary]) {
+    let mut rows = vec![];
+
+    for summary in summaries.iter().filter(|s| !s.cached) {
+        rows.push((
+            summary.duration.as_millis() as u64,
+            "artifact",
+            summary.name.clone(),
+            String::new(),
+        ));
+
+        for duration in summary.durations.iter() {
+            let kind = match duration.kind() {
+                ArtifactBuildDurationKind::Source => "source",
+                ArtifactBuildDurationKind::Step => "step",
+                ArtifactBuildDurationKind::UnknownDuration => continue,
+            };
+
+            rows.push((
+                duration.duration_ms,
+                kind,
+                summary.name.clone(),
+                duration.name.clone(),
+            ));
+        }
+    }
+
+    rows.sort_by(|a, b| b.0.cmp(&a.0));
+
+    if !rows.is_empty() {
+        eprintln!(
+            "{:>10}  {:<8}  {:<32}  NAME",
+            "DURATION", "KIND", "ARTIFACT"
+        );
+
+        for (duration_ms, kind, artifact, name) in rows {
+            eprintln!(
+                "{:>10}  {:<8}  {:<32}  {}",
+                get_duration_display(duration_ms),
+                kind,
+                artifact,
+                name
+            );
+        }
+    }
+
+    let cached = summaries.iter().filter(|s| s.cached).count();
+
+    eprintln!("{} built, {} cached", summaries.len() - cached, cached);
+
+    let mut delta = ArtifactDeltaSummary::default();
+
+    for summary in summaries {
+        delta.add(&summary.delta);
+    }
+
+    if delta.archive_bytes > 0 {
+        eprintln!("sources pushed as deltas: {}", delta);
+    }
+}
+
+/// Pulls the artifact like `try_pull_artifact`, retrying requests interrupted by connection
+/// failures.
+async fn pull_artifact(
+    artifact_id: &ArtifactId,
+    artifact_path: &Path,
+    registries: &RegistryClients,
+) -> Result<bool> {
+    let mut retry = Retry::new(format!("pull of {}", artifact_id.name));
+
+    loop {
+        match try_pull_artifact(artifact_id, artifact_path, registries).await {
+            Err(err) if is_transport_error_chain(&err) => retry.wait(format!("{:#}", err)).await?,
+            result => return result,
+        }
+    }
+}
 
+/// Pulls the artifact from the first registry with it and unpacks it into `artifact_path`,
+/// returning whether any registry had it.
+async fn try_pull_artifact(
+    artifact_id: &ArtifactId,
+    artifact_path: &Path,
+    registries: &RegistryClients,
+) -> Result<bool> {
     let pull_request = RegistryRequest {
+        accept_dictionary: true,
         hash: artifact_id.hash.clone(),
         kind: RegistryKind::Artifact as i32,
         name: artifact_id.name.clone(),
     };
 
-    let mut registry = RegistryServiceClient::connect(registry.to_owned())
-        .await
-        .expect("failed to connect to store");
+    match registries.find(&pull_request).await {
+        Err(status) => return Err(Error::new(status).context("registry pull error")),
 
-    match registry.exists(pull_request.clone()).await {
-        Err(status) => {
-            if status.code() != NotFound {
-                bail!("Registry pull error: {:?}", status);
-            }
-        }
+        Ok(None) => {}
 
-        Ok(_) => match registry.pull(pull_request.clone()).await {
+        Ok(Some(mut registry)) => match registry.client.pull(pull_request.clone()).await {
             Err(status) => {
                 if status.code() != NotFound {
-                    bail!("Registry pull error: {:?}", status);
+                    return Err(Error::new(status).context("registry pull error"));
                 }
             }
 
@@ -80,40 +1105,63 @@ pub async fn build(
                 );
 
                 let mut response = response.into_inner();
-                let mut response_data = Vec::new();
+                let mut response_digest = None;
+
+                // Chunks go straight to the archive file so large artifacts are never held in
+                // memory
+
+                let archive_path = create_sandbox_file(Some("tar.zst")).await?;
+
+                let mut archive = ArchiveWriter::create(&archive_path).await?;
 
                 loop {
                     match response.message().await {
                         Ok(res) => match res {
                             Some(response) => {
+                                if !response.data_digest.is_empty() {
+                                    response_digest =
+                                        Some(response.data_digest.parse::<ArchiveDigest>()?);
+                                }
+
                                 if !response.data.is_empty() {
-                                    response_data.extend_from_slice(&response.data);
+                                    wait_network_limit(response.data.len()).await;
+
+                                    archive.write(&response.data).await?;
                                 }
                             }
 
                             None => break,
                         },
 
-                        Err(err) => {
-                            bail!("Stream error: {:?}", err);
+                        Err(status) => {
+                            let _ = remove_file(&archive_path).await;
+
+                            return Err(Error::new(status).context("registry pull stream error"));
                         }
                     };
                 }
 
-                if response_data.is_empty() {
+                if archive.size() == 0 {
+                    remove_file(&archive_path).await.expect("failed to remove");
+
                     bail!("artifact data not found: {:?}", artifact_id);
                 }
 
-                let archive_path = create_sandbox_file(Some("tar.zst")).await?;
+                // Reject corrupt or tampered data before anything lands in the store
 
-                let mut archive = File::create(&archive_path)
+                let dictionary_id = match archive
+                    .finish(response_digest.as_ref(), &artifact_id.name)
                     .await
-                    .expect("failed to create artifact archive");
+                {
+                    Ok(dictionary_id) => dictionary_id,
+                    Err(err) => bail!("artifact data rejected: {:?}: {}", artifact_id, err),
+                };
 
-                archive
-                    .write_all(&response_data)
-                    .await
-                    .expect("failed to write artifact archive");
+                if let Some(dictionary_id) = dictionary_id {
+                    let dictionary = get_dictionary(dictionary_id, &mut registry.client).await?;
+
+                    decompress_archive_dictionary_file(&archive_path, &dictionary)?;
+                }
 
                 info!(
                     "{} unpacking: {}",
@@ -121,29 +1169,390 @@ pub async fn build(
                     artifact_id.hash
                 );
 
-                create_dir_all(&artifact_path)
+                create_dir_all(artifact_path)
                     .await
                     .expect("failed to create artifact path");
 
-                unpack_zstd(&artifact_path, &archive_path).await?;
+                unpack_archive(artifact_path, &archive_path).await?;
 
-                let artifact_files = get_file_paths(&artifact_path, vec![], vec![])?;
+                let artifact_files = get_file_paths(&artifact_path.to_path_buf(), vec![], vec![])?;
 
                 if artifact_files.is_empty() {
                     bail!("Artifact files not found: {:?}", artifact_path);
                 }
 
                 for artifact_files in &artifact_files {
-                    set_timestamps(artifact_files).await?;
+                    sanitize_file(artifact_files).await?;
                 }
 
                 remove_file(&archive_path).await.expect("failed to remove");
 
-                return Ok(());
+                if is_store_dedup_enabled() {
+                    match dedup_path(artifact_path) {
+                        Ok(summary) => debug!(
+                            "{} dedup: {} bytes saved",
+                            get_prefix(&artifact_id.name),
+                            summary.saved_bytes
+                        ),
+                        Err(err) => {
+                            warn!("{} dedup failed: {}", get_prefix(&artifact_id.name), err)
+                        }
+                    }
+                }
+
+                return Ok(true);
             }
         },
     }
 
+    Ok(false)
+}
+
+/// Asks the worker for the state of a build after its stream was lost, waiting for the worker
+/// to answer again.
+async fn get_worker_build_state(
+    artifact_id: &ArtifactId,
+    retry: &mut Retry,
+    worker: &mut ArtifactServiceClient<Channel>,
+) -> Result<ArtifactBuildState> {
+    let request = ArtifactBuildStatusRequest {
+        hash: artifact_id.hash.clone(),
+        name: artifact_id.name.clone(),
+    };
+
+    loop {
+        match worker.build_status(request.clone()).await {
+            Ok(response) => return Ok(response.into_inner().state()),
+            Err(status) if is_transport_error(&status) => retry.wait(&status).await?,
+            Err(status) if status.code() == Unimplemented => bail!(
+                "build of {} not retried, worker does not report build status",
+                artifact_id.name
+            ),
+            Err(status) => bail!(
+                "failed to get build status of {}: {}",
+                artifact_id.name,
+                status
+            ),
+        }
+    }
+}
+
+/// Sends a build request to the worker and prints its output until the build finishes.
+///
+/// Connection failures keep their `Status` in the error chain, see `is_transport_error_chain`.
+async fn stream_build(
+    artifact: &Artifact,
+    artifact_id: &ArtifactId,
+    durations: &mut Vec<ArtifactBuildDuration>,
+    request: ArtifactBuildRequest,
+    worker: &mut ArtifactServiceClient<Channel>,
+) -> Result<()> {
+    let request_size = request.encoded_len();
+
+    let response = match worker.build(request).await {
+        Ok(response) => response,
+        Err(status) if status.code() == OutOfRange => bail!(
+            "artifact `{}` is {} bytes serialized, over the message size limit of {} bytes (--max-message-size): {}",
+            artifact.name,
+            request_size,
+            get_max_message_size(),
+            status.message()
+        ),
+        Err(status) if is_transport_error(&status) => {
+            return Err(Error::new(status).context("failed to build"))
+        }
+        Err(status) => bail!("failed to build: {}", status),
+    };
+
+    let mut stream = response.into_inner();
+
+    let term = Term::stderr();
+    let mut term_progress = false;
+
+    loop {
+        match stream.message().await {
+            Ok(res) => match res {
+                Some(response) => {
+                    durations.extend(response.durations.iter().cloned());
+
+                    // Progress-only events (no legacy output) redraw a single line
+
+                    if let Some(event) = &response.event {
+                        let event_display = format!(
+                            "{} {}",
+                            get_prefix(&artifact_id.name),
+                            get_event_display(event, &response.output)
+                        );
+
+                        if response.output.is_empty() {
+                            if term.is_term() {
+                                let _ = term.clear_line();
+                                let _ = term.write_str(&event_display);
+
+                                term_progress = true;
+                            }
+
+                            continue;
+                        }
+
+                        if term_progress {
+                            let _ = term.clear_line();
+
+                            term_progress = false;
+                        }
+
+                        info!("{}", event_display);
+
+                        continue;
+                    }
+
+                    if term_progress {
+                        let _ = term.clear_line();
+
+                        term_progress = false;
+                    }
+
+                    if !response.output.is_empty() {
+                        let output = match response.stream() {
+                            ArtifactBuildStream::Stderr => style(response.output).red().to_string(),
+                            _ => response.output,
+                        };
+
+                        info!("{} {}", get_prefix(&artifact_id.name), output);
+                    }
+                }
+
+                None => break,
+            },
+
+            Err(status) => {
+                if term_progress {
+                    let _ = term.clear_line();
+                }
+
+                if let Some(source_error) = SourceError::from_status(&status) {
+                    warn!(
+                        "{} hint: {}",
+                        get_prefix(&artifact_id.name),
+                        source_error.hint()
+                    );
+                }
+
+                return Err(Error::new(status).context("build stream error"));
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// Pushes a source as a delta against the source of the same name pushed last, returning
+/// whether it was pushed. Small sources, sources without an earlier push and deltas saving too
+/// little are left to be pushed whole.
+async fn push_source_delta(
+    archive_data: &[u8],
+    artifact_name: &str,
+    chunk_bounds: ChunkBounds,
+    private_key_fingerprint: &str,
+    private_key_path: &Path,
+    registry: &mut RegistryServiceClient<RegistryChannel>,
+    source: &ArtifactSourceId,
+) -> Result<Option<ArtifactDeltaSummary>> {
+    if archive_data.len() < DELTA_ARCHIVE_SIZE_MIN {
+        return Ok(None);
+    }
+
+    let index_request = RegistryDeltaIndexRequest {
+        kind: RegistryKind::ArtifactSource as i32,
+        name: source.name.clone(),
+    };
+
+    let index = match registry.delta_index(index_request).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => {
+            debug!(
+                "{} no delta base for source {}: {}",
+                get_prefix(artifact_name),
+                source.name,
+                status.message()
+            );
+
+            return Ok(None);
+        }
+    };
+
+    // The registry re-creates the archive from the delta, so the archive signed is the one it
+    // can reproduce rather than the cached one
+
+    let data = decode_archive(archive_data, None)?;
+
+    let archive = encode_delta(&data)?;
+
+    let recipe = RegistryDeltaRecipe {
+        entries: get_delta_entries(&index.chunks, &data)
+            .into_iter()
+            .map(|entry| match entry {
+                DeltaEntry::Base(base_chunk) => RegistryDeltaEntry {
+                    base_chunk,
+                    data: vec![],
+                },
+                DeltaEntry::Data(data) => RegistryDeltaEntry {
+                    base_chunk: 0,
+                    data: data.to_vec(),
+                },
+            })
+            .collect(),
+    };
+
+    let recipe_data = encode_delta(&recipe.encode_to_vec())?;
+    let recipe_size = recipe_data.len();
+
+    if recipe_size as f64 > archive_data.len() as f64 * DELTA_SIZE_RATIO_MAX {
+        debug!(
+            "{} delta of source {} saves too little: {} of {} bytes",
+            get_prefix(artifact_name),
+            source.name,
+            recipe_size,
+            archive_data.len()
+        );
+
+        return Ok(None);
+    }
+
+    let signature = vorpal_notary::sign(private_key_path.to_path_buf(), &archive).await?;
+
+    let push_fingerprint = private_key_fingerprint.to_string();
+    let push_hash = source.hash.clone();
+    let push_name = source.name.clone();
+    let push_base = index.hash.clone();
+
+    let (push_stream, _) =
+        stream_chunks(recipe_data, chunk_bounds, move |data| RegistryPushRequest {
+            compression: RegistryCompression::Zstd as i32,
+            data,
+            data_signature: signature.to_vec(),
+            data_signature_fingerprint: push_fingerprint.clone(),
+            delta_base: push_base.clone(),
+            hash: push_hash.clone(),
+            kind: RegistryKind::ArtifactSource as i32,
+            name: push_name.clone(),
+        });
+
+    if let Err(status) = registry.push(ReceiverStream::new(push_stream)).await {
+        warn!(
+            "{} delta push of source {}-{} failed, pushing it whole: {}",
+            get_prefix(artifact_name),
+            source.name,
+            source.hash,
+            status.message()
+        );
+
+        return Ok(None);
+    }
+
+    let delta = ArtifactDeltaSummary {
+        archive_bytes: archive_data.len() as u64,
+        pushed_bytes: recipe_size as u64,
+    };
+
+    info!(
+        "{} pushed source delta: {}-{} ({})",
+        get_prefix(artifact_name),
+        source.name,
+        source.hash,
+        delta
+    );
+
+    Ok(Some(delta))
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Revisions of the sources of `artifact_name`, sorted by source name.
+fn get_source_revisions(
+    artifact_name: &str,
+    revisions: &[ConfigSourceRevision],
+) -> Vec<ArtifactSourceRevision> {
+    let mut source_revisions = revisions
+        .iter()
+        .filter(|revision| revision.artifact == artifact_name)
+        .map(|revision| ArtifactSourceRevision {
+            commit: revision.commit.clone(),
+            name: revision.name.clone(),
+            revision: revision.revision.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    source_revisions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    source_revisions
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn build(
+    artifact: &Artifact,
+    artifact_id: &ArtifactId,
+    artifact_target: ArtifactSystem,
+    chunk_bounds: ChunkBounds,
+    force: &ArtifactForce,
+    push_policy: Option<PushPolicy>,
+    registries: &RegistryUrls,
+    secrets: &[ArtifactStepEnvironment],
+    source_revisions: &[ConfigSourceRevision],
+    workers: &ArtifactWorkers,
+) -> Result<ArtifactBuildSummary> {
+    let started = Instant::now();
+
+    let forced = force.is_forced(artifact_id);
+
+    let mut summary = ArtifactBuildSummary {
+        cached: true,
+        delta: ArtifactDeltaSummary::default(),
+        duration: Duration::ZERO,
+        durations: vec![],
+        name: artifact_id.name.clone(),
+    };
+
+    // Keep the manifest locally, so the artifact can be inspected and rebuilt offline
+
+    let (manifest_digest, manifest_json) = get_artifact_manifest(artifact, artifact_target)?;
+
+    if let Err(err) = write_manifest(&manifest_digest, &artifact_id.name, &manifest_json).await {
+        warn!(
+            "{} failed to keep manifest: {}",
+            get_prefix(&artifact_id.name),
+            err
+        );
+    }
+
+    // 1. Check if artifact exists (local)
+
+    let artifact_path = get_artifact_path(&artifact_id.hash.parse()?, &artifact_id.name);
+
+    // Locked paths are still being built by a worker
+
+    let artifact_lock_path = get_artifact_lock_path(&artifact_id.hash.parse()?, &artifact_id.name);
+
+    if artifact_path.exists() && !artifact_lock_path.exists() && !forced {
+        return Ok(summary);
+    }
+
+    // 2. Check if artifact exists (registries)
+
+    let registries = registries.connect().await?;
+
+    if !forced && pull_artifact(artifact_id, &artifact_path, &registries).await? {
+        summary.duration = started.elapsed();
+
+        return Ok(summary);
+    }
+
+    // 2a. Hash the cached output before a forced build replaces it
+
+    let cached_hashes = match forced && force.check_reproducibility {
+        true => Some(get_cached_file_hashes(artifact_id, &artifact_path, &registries).await?),
+        false => None,
+    };
+
     // 3. Push artifact source(s) to registry (registry)
 
     let private_key_path = get_private_key_path();
@@ -152,98 +1561,361 @@ pub async fn build(
         bail!("Private key not found: {}", private_key_path.display());
     }
 
-    for source in artifact.sources.clone() {
-        let exists_request = RegistryRequest {
+    let private_key_fingerprint =
+        vorpal_notary::get_private_key_fingerprint(private_key_path.clone()).await?;
+
+    let sources_requests = artifact
+        .sources
+        .iter()
+        .map(|source| RegistryRequest {
+            accept_dictionary: false,
             hash: source.hash.clone(),
             kind: RegistryKind::ArtifactSource as i32,
             name: source.name.clone(),
-        };
+        })
+        .collect();
 
-        match registry.exists(exists_request.clone()).await {
-            Ok(_) => {}
+    let sources_exists = get_registries_exists(&registries, sources_requests).await?;
 
-            Err(status) => {
-                if status.code() != NotFound {
-                    bail!("Registry pull error: {:?}", status);
-                }
+    for (source, exists) in artifact.sources.clone().into_iter().zip(sources_exists) {
+        if exists {
+            continue;
+        }
 
-                let cache_archive_path = get_cache_archive_path(&source.hash, &source.name);
+        let mut registry = registries.push()?;
 
-                if !cache_archive_path.exists() {
-                    bail!("cache archive not found: {:?}", cache_archive_path);
-                }
+        let cache_archive_path = get_cache_archive_path(&source.hash.parse()?, &source.name);
+
+        if !cache_archive_path.exists() {
+            bail!("cache archive not found: {:?}", cache_archive_path);
+        }
 
-                let cache_archive_data = read(&cache_archive_path).await.expect("failed to read");
+        let cache_archive_data = read(&cache_archive_path).await.expect("failed to read");
 
-                let cache_signature =
-                    vorpal_notary::sign(private_key_path.clone(), &cache_archive_data).await?;
+        let delta = push_source_delta(
+            &cache_archive_data,
+            &artifact_id.name,
+            chunk_bounds,
+            &private_key_fingerprint,
+            &private_key_path,
+            &mut registry,
+            &source,
+        )
+        .await
+        .unwrap_or_else(|err| {
+            warn!(
+                "{} delta of source {} failed, pushing it whole: {}",
+                get_prefix(&artifact_id.name),
+                source.name,
+                err
+            );
 
-                let mut push_stream = vec![];
+            None
+        });
 
-                for chunk in cache_archive_data.chunks(DEFAULT_CHUNKS_SIZE) {
-                    push_stream.push(RegistryPushRequest {
-                        data: chunk.to_vec(),
-                        data_signature: cache_signature.clone().to_vec(),
-                        hash: source.hash.clone(),
-                        kind: RegistryKind::ArtifactSource as i32,
-                        name: source.name.clone(),
-                    });
-                }
+        if let Some(delta) = delta {
+            summary.delta.add(&delta);
 
-                info!(
-                    "{} pushing source: {}-{}",
-                    get_prefix(&artifact_id.name),
-                    source.name,
-                    source.hash
-                );
+            continue;
+        }
 
-                let response = registry
-                    .push(tokio_stream::iter(push_stream))
-                    .await
-                    .expect("failed to push");
+        let cache_signature =
+            vorpal_notary::sign(private_key_path.clone(), &cache_archive_data).await?;
 
-                let response = response.into_inner();
+        let push_fingerprint = private_key_fingerprint.clone();
+        let push_hash = source.hash.clone();
+        let push_name = source.name.clone();
 
-                if !response.success {
-                    bail!("Registry push failed");
+        let (push_stream, push_summary) =
+            stream_chunks(cache_archive_data, chunk_bounds, move |data| {
+                RegistryPushRequest {
+                    compression: RegistryCompression::Zstd as i32,
+                    data,
+                    data_signature: cache_signature.to_vec(),
+                    data_signature_fingerprint: push_fingerprint.clone(),
+                    delta_base: String::new(),
+                    hash: push_hash.clone(),
+                    kind: RegistryKind::ArtifactSource as i32,
+                    name: push_name.clone(),
                 }
-            }
+            });
+
+        info!(
+            "{} pushing source: {}-{}",
+            get_prefix(&artifact_id.name),
+            source.name,
+            source.hash
+        );
+
+        let response = registry
+            .push(ReceiverStream::new(push_stream))
+            .await
+            .expect("failed to push");
+
+        let response = response.into_inner();
+
+        if !response.success {
+            bail!("Registry push failed");
+        }
+
+        if let Ok(summary) = push_summary.await {
+            debug!(
+                "{} pushed source: {}-{} ({})",
+                get_prefix(&artifact_id.name),
+                source.name,
+                source.hash,
+                summary
+            );
         }
     }
 
     // Build artifact
 
-    let mut worker = ArtifactServiceClient::connect(service.to_owned())
+    let mut worker = get_worker(workers, artifact_target)?;
+
+    let request = ArtifactBuildRequest {
+        artifact: Some(artifact.clone()),
+        force: forced,
+        push_policy: push_policy.map_or(ArtifactPushPolicy::UnknownPushPolicy, |policy| {
+            policy.as_request()
+        }) as i32,
+        secrets: secrets.to_vec(),
+        source_revisions: get_source_revisions(&artifact_id.name, source_revisions),
+        system: artifact_target as i32,
+    };
+
+    // Lost connections re-send the build only when the worker holds no partial output of it
+
+    let mut retry = Retry::new(format!("build of {}", artifact_id.name));
+
+    loop {
+        let err = match stream_build(
+            artifact,
+            artifact_id,
+            &mut summary.durations,
+            request.clone(),
+            &mut worker,
+        )
         .await
-        .expect("failed to connect to artifact");
+        {
+            Ok(_) => break,
+            Err(err) if is_transport_error_chain(&err) => err,
+            Err(err) => return Err(err),
+        };
 
-    let response = worker
-        .build(ArtifactBuildRequest {
-            artifact: Some(artifact.clone()),
-            system: artifact_target as i32,
-        })
+        retry.wait(format!("{:#}", err)).await?;
+
+        match get_worker_build_state(artifact_id, &mut retry, &mut worker).await? {
+            ArtifactBuildState::Completed => break,
+            ArtifactBuildState::Building | ArtifactBuildState::Missing => {}
+            state => bail!(
+                "build of {} not retried, worker reports {} for {}: {:#}",
+                artifact_id.name,
+                state.as_str_name(),
+                artifact_id.hash,
+                err
+            ),
+        }
+    }
+
+    summary.cached = false;
+    summary.duration = started.elapsed();
+
+    if let Some(cached_hashes) = cached_hashes {
+        check_reproducibility(artifact_id, &artifact_path, &cached_hashes)?;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_event_display() {
+        let event = ArtifactBuildEvent {
+            bytes_done: 512,
+            bytes_total: 2048,
+            phase: ArtifactBuildPhase::Hash as i32,
+            source: "example".to_string(),
+        };
+
+        assert_eq!(
+            get_event_display(&event, ""),
+            "hash example: 512.0B/2.0KB (25%)"
+        );
+
+        let event = ArtifactBuildEvent {
+            bytes_done: 2048,
+            phase: ArtifactBuildPhase::Download as i32,
+            source: "example".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(get_event_display(&event, ""), "download example: 2.0KB");
+
+        // Details only in the legacy output are kept
+
+        let event = ArtifactBuildEvent {
+            bytes_done: 1024,
+            bytes_total: 1024,
+            phase: ArtifactBuildPhase::Push as i32,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            get_event_display(&event, "pushed: abc123 (1.0KB in 1 chunks)"),
+            "push: pushed: abc123 (1.0KB in 1 chunks)"
+        );
+    }
+
+    #[test]
+    fn test_verify_dictionary_id() {
+        let samples = (0..100)
+            .map(|index| format!("{{\"name\":\"package-{}\",\"steps\":[]}}", index).into_bytes())
+            .collect::<Vec<_>>();
+
+        let dictionary = vorpal_store::dictionaries::train_dictionary(&samples, 1024).unwrap();
+        let dictionary_id = get_dictionary_id(&dictionary).unwrap();
+
+        verify_dictionary_id(&dictionary, dictionary_id).unwrap();
+
+        let err = verify_dictionary_id(&dictionary, dictionary_id.wrapping_add(1)).unwrap_err();
+
+        assert!(err.to_string().contains("dictionary id mismatch"));
+
+        // Data that is not a dictionary, such as an error page served by a proxy
+
+        assert!(verify_dictionary_id(b"<html></html>", dictionary_id).is_err());
+    }
+
+    const SECRET_VALUE: &str = "hunter2-plaintext";
+
+    async fn generate_test_keys() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let key_dir = tempfile::tempdir().unwrap();
+
+        let private_key_path = key_dir.path().join("private.pem");
+        let public_key_path = key_dir.path().join("public.pem");
+
+        vorpal_notary::generate_keys(
+            key_dir.path().to_path_buf(),
+            private_key_path.clone(),
+            public_key_path.clone(),
+        )
         .await
-        .expect("failed to build");
+        .unwrap();
 
-    let mut stream = response.into_inner();
+        (key_dir, private_key_path, public_key_path)
+    }
 
-    loop {
-        match stream.message().await {
-            Ok(res) => match res {
-                Some(response) => {
-                    if !response.output.is_empty() {
-                        info!("{} {}", get_prefix(&artifact_id.name), response.output);
-                    }
-                }
+    fn get_test_secrets() -> Vec<ArtifactStepEnvironment> {
+        get_secrets(&[format!("TOKEN={}", SECRET_VALUE)]).unwrap()
+    }
 
-                None => break,
-            },
+    #[tokio::test]
+    async fn test_encrypt_secrets_round_trip() {
+        let (_key_dir, private_key_path, public_key_path) = generate_test_keys().await;
 
-            Err(err) => {
-                bail!("Stream error: {:?}", err);
-            }
+        let encrypted = encrypt_secrets(&public_key_path, get_test_secrets())
+            .await
+            .unwrap();
+
+        assert_eq!(encrypted.len(), 1);
+        assert_eq!(encrypted[0].key, "TOKEN");
+
+        let value = vorpal_notary::decode_encrypted(&encrypted[0].value).unwrap();
+        let value = vorpal_notary::decrypt(private_key_path, &value)
+            .await
+            .unwrap();
+
+        assert_eq!(value, SECRET_VALUE.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_secrets_json_has_no_plaintext() {
+        let (_key_dir, _private_key_path, public_key_path) = generate_test_keys().await;
+
+        let secrets = encrypt_secrets(&public_key_path, get_test_secrets())
+            .await
+            .unwrap();
+
+        let request = ArtifactBuildRequest {
+            artifact: Some(Artifact {
+                name: "example".to_string(),
+                ..Default::default()
+            }),
+            secrets,
+            ..Default::default()
         };
+
+        // Secrets are left out of exported JSON, and only sent encrypted
+
+        let json = serde_json::to_string_pretty(&request).unwrap();
+
+        assert!(!json.contains(SECRET_VALUE));
+
+        let encoded = prost::Message::encode_to_vec(&request);
+
+        assert!(encoded.windows(5).any(|window| window == b"TOKEN"));
+        assert!(!encoded
+            .windows(SECRET_VALUE.len())
+            .any(|window| window == SECRET_VALUE.as_bytes()));
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_encrypt_secrets_missing_key() {
+        let key_dir = tempfile::tempdir().unwrap();
+
+        let err = encrypt_secrets(&key_dir.path().join("public.pem"), get_test_secrets())
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("failed to encrypt secret TOKEN"));
+        assert!(err.to_string().contains("run 'vorpal keys generate'"));
+    }
+
+    #[test]
+    fn test_get_source_revisions() {
+        let revision = |artifact: &str, name: &str| ConfigSourceRevision {
+            artifact: artifact.to_string(),
+            commit: format!("{}-commit", name),
+            name: name.to_string(),
+            revision: "v1.0.0".to_string(),
+        };
+
+        let revisions = vec![
+            revision("example", "src"),
+            revision("other", "other"),
+            revision("example", "docs"),
+        ];
+
+        let source_revisions = get_source_revisions("example", &revisions);
+
+        assert_eq!(
+            source_revisions
+                .iter()
+                .map(|r| (r.name.as_str(), r.commit.as_str(), r.revision.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("docs", "docs-commit", "v1.0.0"),
+                ("src", "src-commit", "v1.0.0"),
+            ]
+        );
+
+        // Revisions are sent to the worker for provenance, never into the manifest
+
+        let request = ArtifactBuildRequest {
+            artifact: Some(Artifact {
+                name: "example".to_string(),
+                ..Default::default()
+            }),
+            source_revisions,
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string(&request).unwrap();
+
+        assert!(!json.contains("src-commit"));
+    }
 }
@@ -2,18 +2,47 @@ use anyhow::{bail, Result};
 use petgraph::algo::toposort;
 use petgraph::graphmap::DiGraphMap;
 use std::collections::HashMap;
-use tonic::transport::Channel;
-use vorpal_schema::vorpal::{
-    artifact::v0::{Artifact, ArtifactId},
-    config::v0::config_service_client::ConfigServiceClient,
+use vorpal_schema::{
+    get_artifact_cycle_error, validate_artifact_dependencies,
+    vorpal::{
+        artifact::v0::{Artifact, ArtifactId},
+        config::v0::config_service_client::ConfigServiceClient,
+    },
 };
+use vorpal_sdk::config::service::ConfigChannel;
 
 pub async fn get_artifacts(
+    artifact_id: &ArtifactId,
     artifact: &Artifact,
     artifact_map: &mut HashMap<ArtifactId, Artifact>,
-    config_service: &mut ConfigServiceClient<Channel>,
+    config_service: &mut ConfigServiceClient<ConfigChannel>,
+) -> Result<()> {
+    let mut artifact_path = vec![artifact_id.clone()];
+
+    get_artifacts_path(artifact, &mut artifact_path, artifact_map, config_service).await
+}
+
+async fn get_artifacts_path(
+    artifact: &Artifact,
+    artifact_path: &mut Vec<ArtifactId>,
+    artifact_map: &mut HashMap<ArtifactId, Artifact>,
+    config_service: &mut ConfigServiceClient<ConfigChannel>,
 ) -> Result<()> {
     for output in artifact.artifacts.iter() {
+        // Dependencies leading back to an artifact being expanded would never finish
+
+        if let Some(index) = artifact_path.iter().position(|id| id == output) {
+            let mut cycle = artifact_path[index..].to_vec();
+
+            cycle.push(output.clone());
+
+            bail!("{}", get_artifact_cycle_error(&cycle));
+        }
+
+        if artifact_map.contains_key(output) {
+            continue;
+        }
+
         let request = tonic::Request::new(output.clone());
 
         let response = match config_service.get_artifact(request).await {
@@ -27,12 +56,47 @@ pub async fn get_artifacts(
 
         artifact_map.insert(output.clone(), artifact.clone());
 
-        Box::pin(get_artifacts(&artifact, artifact_map, config_service)).await?;
+        artifact_path.push(output.clone());
+
+        Box::pin(get_artifacts_path(
+            &artifact,
+            artifact_path,
+            artifact_map,
+            config_service,
+        ))
+        .await?;
+
+        artifact_path.pop();
     }
 
     Ok(())
 }
 
+/// Collects `artifact_id` and its dependencies from the artifacts of an evaluated config.
+pub fn get_artifacts_evaluated(
+    artifact_id: &ArtifactId,
+    artifacts: &HashMap<ArtifactId, Artifact>,
+) -> Result<HashMap<ArtifactId, Artifact>> {
+    let mut artifact_map = HashMap::new();
+    let mut pending = vec![artifact_id.clone()];
+
+    while let Some(id) = pending.pop() {
+        if artifact_map.contains_key(&id) {
+            continue;
+        }
+
+        let Some(artifact) = artifacts.get(&id) else {
+            bail!("artifact not found: {}", id.name);
+        };
+
+        pending.extend(artifact.artifacts.iter().cloned());
+
+        artifact_map.insert(id, artifact.clone());
+    }
+
+    Ok(artifact_map)
+}
+
 pub async fn get_order(build_artifact: &HashMap<ArtifactId, Artifact>) -> Result<Vec<ArtifactId>> {
     // Populate the build graph
 
@@ -47,7 +111,10 @@ pub async fn get_order(build_artifact: &HashMap<ArtifactId, Artifact>) -> Result
     }
 
     let build_order = match toposort(&artifact_graph, None) {
-        Err(err) => bail!("{:?}", err),
+        Err(err) => match validate_artifact_dependencies(err.node_id(), build_artifact) {
+            Err(cycle) => bail!("{}", cycle),
+            Ok(_) => bail!("{:?}", err),
+        },
         Ok(order) => order,
     };
 
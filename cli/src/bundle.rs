@@ -0,0 +1,492 @@
+use crate::artifact::get_artifact_name;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+use tokio::fs::{create_dir_all, read, read_to_string, remove_dir_all, remove_file, write};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Code::{NotFound, Unimplemented};
+use tracing::{info, warn};
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactBuildRequest,
+    registry::v0::{
+        registry_service_client::RegistryServiceClient, RegistryClosureRequest,
+        RegistryCompression, RegistryKind, RegistryPushRequest, RegistryRequest,
+    },
+};
+use vorpal_store::{
+    archives::{create_tar, unpack_archive, unpack_tar, ArchiveCompression},
+    chunks::{stream_chunks, wait_network_limit, ChunkBounds, CHUNK_MESSAGE_SIZE_LIMIT},
+    digests::{verify_pulled_data, ArchiveDigest, ArtifactDigest},
+    grpc::{RegistryClients, RegistryUrls},
+    manifests::read_manifest,
+    paths::{
+        get_artifact_path, get_file_paths, get_private_key_path, get_store_dir_name, sanitize_file,
+    },
+    temps::{create_sandbox_dir, create_sandbox_file},
+};
+
+// Index of the bundle objects and its hex encoded signature, at the root of the bundle
+const BUNDLE_INDEX: &str = "index.json";
+const BUNDLE_INDEX_SIGNATURE: &str = "index.json.sig";
+
+#[derive(Deserialize, Serialize)]
+struct BundleObject {
+    digest: String,
+    hash: String,
+    kind: String,
+    name: String,
+}
+
+impl BundleObject {
+    fn get_path(&self) -> String {
+        format!(
+            "objects/{}.{}",
+            get_store_dir_name(&self.hash, &self.name),
+            self.kind.to_lowercase()
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct BundleIndex {
+    hash: String,
+    name: String,
+    objects: Vec<BundleObject>,
+}
+
+async fn pull_object(
+    registries: &RegistryClients,
+    kind: RegistryKind,
+    hash: &str,
+    name: &str,
+) -> Result<Option<Vec<u8>>> {
+    let request = RegistryRequest {
+        accept_dictionary: false,
+        hash: hash.to_string(),
+        kind: kind as i32,
+        name: name.to_string(),
+    };
+
+    let mut registry = match registries.find(&request).await {
+        Ok(Some(registry)) => registry,
+        Ok(None) => return Ok(None),
+        Err(status) => bail!("failed to check {}-{}: {}", name, hash, status),
+    };
+
+    let mut response = registry.client.pull(request).await?.into_inner();
+
+    let mut data = vec![];
+    let mut data_digest = None;
+
+    while let Some(res) = response.message().await? {
+        if !res.data_digest.is_empty() {
+            data_digest = Some(res.data_digest.parse::<ArchiveDigest>()?);
+        }
+
+        wait_network_limit(res.data.len()).await;
+
+        data.extend(res.data);
+    }
+
+    verify_pulled_data(data_digest.as_ref(), &data, name)?;
+
+    Ok(Some(data))
+}
+
+/// Fetches the manifests of artifact `hash` and its dependencies in one request, from the first
+/// registry that supports closures and has the artifact. Manifests left out of a partial closure,
+/// or all of them when no registry answered, are empty here and pulled one at a time instead.
+async fn get_manifest_closure(
+    registries: &RegistryClients,
+    hash: &str,
+    name: &str,
+) -> BTreeMap<(String, String), Vec<u8>> {
+    let mut manifests = BTreeMap::new();
+
+    for registry in registries.clients() {
+        let request = RegistryClosureRequest {
+            hash: hash.to_string(),
+            name: name.to_string(),
+        };
+
+        let response = match registry.client.clone().closure(request).await {
+            Ok(response) => response.into_inner(),
+            Err(status) if [NotFound, Unimplemented].contains(&status.code()) => continue,
+            Err(status) => {
+                warn!(
+                    "registry {} failed to get closure of {}-{}: {}",
+                    registry.url,
+                    name,
+                    hash,
+                    status.message()
+                );
+
+                continue;
+            }
+        };
+
+        for warning in response.warnings.iter() {
+            warn!("registry {} closure: {}", registry.url, warning);
+        }
+
+        for manifest in response.manifests {
+            let Some(artifact) = manifest.artifact else {
+                continue;
+            };
+
+            wait_network_limit(manifest.data.len()).await;
+
+            // Manifests not matching their digest are left to the checked single pulls
+
+            if ArtifactDigest::from_manifest(&manifest.data).as_str() != artifact.hash {
+                warn!(
+                    "registry {} closure has a corrupt manifest: {}-{}",
+                    registry.url, artifact.name, artifact.hash
+                );
+
+                continue;
+            }
+
+            manifests.insert((artifact.name, artifact.hash), manifest.data);
+        }
+
+        break;
+    }
+
+    manifests
+}
+
+/// Writes the artifact `digest` with its transitive artifacts, sources, manifests and provenance
+/// from the registry to a tar bundle at `output`.
+pub async fn export(
+    registries: &RegistryUrls,
+    digest: &str,
+    name: Option<String>,
+    output: &str,
+) -> Result<()> {
+    let private_key_path = get_private_key_path();
+
+    if !private_key_path.exists() {
+        bail!("private key not found: {}", private_key_path.display());
+    }
+
+    let registries = registries.connect().await?;
+
+    let name = match name {
+        Some(name) => name,
+        None => get_artifact_name(&registries, &digest.parse()?).await?,
+    };
+
+    let bundle_path = create_sandbox_dir().await?;
+
+    create_dir_all(bundle_path.join("objects")).await?;
+
+    // 1. Collect the closure through the artifact manifests
+
+    let mut index = BundleIndex {
+        hash: digest.to_string(),
+        name: name.clone(),
+        objects: vec![],
+    };
+
+    let mut closure = get_manifest_closure(&registries, digest, &name).await;
+
+    let mut pending = vec![(name.clone(), digest.to_string())];
+    let mut visited = BTreeSet::new();
+
+    let mut objects = vec![];
+
+    while let Some((artifact_name, artifact_hash)) = pending.pop() {
+        if !visited.insert((artifact_name.clone(), artifact_hash.clone())) {
+            continue;
+        }
+
+        let closure_data = closure.remove(&(artifact_name.clone(), artifact_hash.clone()));
+
+        let manifest_data = match read_manifest(&artifact_hash.parse()?, &artifact_name).await? {
+            Some(manifest_json) => Some(manifest_json.into_bytes()),
+            None if closure_data.is_some() => closure_data,
+            None => {
+                pull_object(
+                    &registries,
+                    RegistryKind::ArtifactManifest,
+                    &artifact_hash,
+                    &artifact_name,
+                )
+                .await?
+            }
+        };
+
+        let Some(manifest_data) = manifest_data else {
+            bail!(
+                "artifact manifest not found: {}-{}",
+                artifact_name,
+                artifact_hash
+            );
+        };
+
+        let manifest: ArtifactBuildRequest = serde_json::from_slice(&manifest_data)?;
+
+        let artifact = manifest
+            .artifact
+            .ok_or_else(|| anyhow!("artifact manifest is empty: {}", artifact_name))?;
+
+        for source in artifact.sources.iter() {
+            objects.push((
+                RegistryKind::ArtifactSource,
+                source.hash.clone(),
+                source.name.clone(),
+            ));
+        }
+
+        for dependency in artifact.artifacts.iter() {
+            pending.push((dependency.name.clone(), dependency.hash.clone()));
+        }
+
+        objects.push((
+            RegistryKind::Artifact,
+            artifact_hash.clone(),
+            artifact_name.clone(),
+        ));
+
+        objects.push((
+            RegistryKind::ArtifactManifest,
+            artifact_hash.clone(),
+            artifact_name.clone(),
+        ));
+
+        objects.push((
+            RegistryKind::ArtifactProvenance,
+            artifact_hash,
+            artifact_name,
+        ));
+    }
+
+    objects.sort();
+    objects.dedup();
+
+    // 2. Write the objects and the signed index
+
+    let mut bundle_files = vec![];
+
+    for (kind, hash, name) in objects {
+        let Some(data) = pull_object(&registries, kind, &hash, &name).await? else {
+            // Provenance is only recorded by newer workers
+
+            if kind == RegistryKind::ArtifactProvenance {
+                continue;
+            }
+
+            bail!("{} not found: {}-{}", kind.as_str_name(), name, hash);
+        };
+
+        let object = BundleObject {
+            digest: ArchiveDigest::from_data(&data).to_string(),
+            hash,
+            kind: kind.as_str_name().to_string(),
+            name,
+        };
+
+        let object_path = bundle_path.join(object.get_path());
+
+        write(&object_path, &data).await?;
+
+        bundle_files.push(object_path);
+
+        index.objects.push(object);
+    }
+
+    let index_data = serde_json::to_vec_pretty(&index)?;
+
+    let index_signature = vorpal_notary::sign(private_key_path, &index_data).await?;
+
+    write(bundle_path.join(BUNDLE_INDEX), &index_data).await?;
+
+    write(
+        bundle_path.join(BUNDLE_INDEX_SIGNATURE),
+        vorpal_notary::encode_signature(&index_signature),
+    )
+    .await?;
+
+    bundle_files.push(bundle_path.join(BUNDLE_INDEX));
+    bundle_files.push(bundle_path.join(BUNDLE_INDEX_SIGNATURE));
+
+    create_tar(&bundle_path, &bundle_files, &PathBuf::from(output)).await?;
+
+    remove_dir_all(&bundle_path).await?;
+
+    info!("exported {} objects: {}", index.objects.len(), output);
+
+    Ok(())
+}
+
+async fn unpack_artifact(data: &[u8], artifact_path: &PathBuf) -> Result<()> {
+    let archive_path = create_sandbox_file(Some("tar.zst")).await?;
+
+    write(&archive_path, data).await?;
+
+    create_dir_all(artifact_path).await?;
+
+    unpack_archive(artifact_path, &archive_path).await?;
+
+    for path in get_file_paths(artifact_path, vec![], vec![])?.iter() {
+        sanitize_file(path).await?;
+    }
+
+    remove_file(&archive_path).await?;
+
+    Ok(())
+}
+
+async fn verify_index(bundle_path: &Path, public_key_paths: &[PathBuf]) -> Result<BundleIndex> {
+    let index_data = read(bundle_path.join(BUNDLE_INDEX)).await?;
+    let index_signature = read_to_string(bundle_path.join(BUNDLE_INDEX_SIGNATURE)).await?;
+
+    let index_signature = vorpal_notary::decode_signature(index_signature.trim())?;
+
+    for public_key_path in public_key_paths {
+        if !public_key_path.exists() {
+            bail!("public key not found: {}", public_key_path.display());
+        }
+
+        let result =
+            vorpal_notary::verify(public_key_path.clone(), &index_data, &index_signature).await;
+
+        if result.is_ok() {
+            return Ok(serde_json::from_slice(&index_data)?);
+        }
+    }
+
+    bail!("bundle signature not trusted")
+}
+
+/// Verifies a bundle written by `export` and pushes its objects to the push registry, unpacking
+/// artifacts into the local store. Objects already in the push registry are skipped.
+pub async fn import(
+    registries: &RegistryUrls,
+    chunk_bounds: ChunkBounds,
+    input: &str,
+    public_key_paths: &[PathBuf],
+) -> Result<()> {
+    let private_key_path = get_private_key_path();
+
+    if !private_key_path.exists() {
+        bail!("private key not found: {}", private_key_path.display());
+    }
+
+    let private_key_fingerprint =
+        vorpal_notary::get_private_key_fingerprint(private_key_path.clone()).await?;
+
+    let bundle_path = create_sandbox_dir().await?;
+
+    unpack_tar(&bundle_path, Path::new(input)).await?;
+
+    let index = verify_index(&bundle_path, public_key_paths).await?;
+
+    // Verify every object before anything is imported
+
+    let mut objects = vec![];
+
+    for object in index.objects.iter() {
+        let kind = RegistryKind::from_str_name(&object.kind)
+            .ok_or_else(|| anyhow!("unsupported bundle object kind: {}", object.kind))?;
+
+        let data = read(bundle_path.join(object.get_path())).await?;
+
+        let digest = object.digest.parse::<ArchiveDigest>()?;
+
+        if let Err(err) = digest.verify(&data) {
+            bail!(
+                "bundle object rejected: {}-{}: {}",
+                object.name,
+                object.hash,
+                err
+            );
+        }
+
+        objects.push((kind, object, data));
+    }
+
+    let mut registry = RegistryServiceClient::new(registries.get_channel(&registries.push).await?)
+        .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT);
+
+    let mut imported = 0;
+    let mut skipped = 0;
+
+    for (kind, object, data) in objects {
+        if kind == RegistryKind::Artifact {
+            let artifact_path = get_artifact_path(&object.hash.parse()?, &object.name);
+
+            if !artifact_path.exists() {
+                unpack_artifact(&data, &artifact_path).await?;
+            }
+        }
+
+        let exists_request = RegistryRequest {
+            accept_dictionary: false,
+            hash: object.hash.clone(),
+            kind: kind as i32,
+            name: object.name.clone(),
+        };
+
+        match registry.exists(exists_request).await {
+            Ok(_) => {
+                skipped += 1;
+
+                continue;
+            }
+
+            Err(status) if status.code() == NotFound => {}
+
+            Err(status) => bail!(
+                "failed to check {}-{}: {}",
+                object.name,
+                object.hash,
+                status
+            ),
+        }
+
+        let compression = match ArchiveCompression::from_data(&data) {
+            Some(ArchiveCompression::Bzip2) => RegistryCompression::Bzip2,
+            Some(ArchiveCompression::Gzip) => RegistryCompression::Gzip,
+            Some(ArchiveCompression::Xz) => RegistryCompression::Xz,
+            _ => RegistryCompression::Zstd,
+        };
+
+        let data_signature = vorpal_notary::sign(private_key_path.clone(), &data).await?;
+
+        let request_fingerprint = private_key_fingerprint.clone();
+        let request_hash = object.hash.clone();
+        let request_name = object.name.clone();
+
+        let (request_stream, _) =
+            stream_chunks(data, chunk_bounds, move |data| RegistryPushRequest {
+                compression: compression as i32,
+                data,
+                data_signature: data_signature.to_vec(),
+                data_signature_fingerprint: request_fingerprint.clone(),
+                delta_base: String::new(),
+                hash: request_hash.clone(),
+                kind: kind as i32,
+                name: request_name.clone(),
+            });
+
+        registry
+            .push(ReceiverStream::new(request_stream))
+            .await
+            .map_err(|status| {
+                anyhow!("failed to push {}-{}: {}", object.name, object.hash, status)
+            })?;
+
+        imported += 1;
+    }
+
+    remove_dir_all(&bundle_path).await?;
+
+    println!("{} imported, {} skipped", imported, skipped);
+
+    Ok(())
+}
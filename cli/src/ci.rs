@@ -0,0 +1,233 @@
+use crate::{
+    artifact::{build, get_duration_display, ArtifactBuildSummary, ArtifactForce, ArtifactWorkers},
+    build::{get_artifacts_evaluated, get_order},
+};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{collections::HashMap, time::Duration};
+use tracing::{error, warn};
+use vorpal_schema::{
+    get_artifact_system_name, validate_artifact_system,
+    vorpal::{
+        artifact::v0::{Artifact, ArtifactId, ArtifactStepEnvironment, ArtifactSystem},
+        config::v0::ConfigSourceRevision,
+    },
+};
+use vorpal_store::{chunks::ChunkBounds, grpc::RegistryUrls, paths::get_artifact_path};
+use vorpal_worker::push::PushPolicy;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CiStatus {
+    Failed,
+    Passed,
+    Skipped,
+}
+
+impl CiStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CiStatus::Failed => "failed",
+            CiStatus::Passed => "passed",
+            CiStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Result of an artifact requested with `vorpal ci --name`, `duration_ms` is the time of its own
+/// build or pull.
+#[derive(Debug, Serialize)]
+pub struct CiTarget {
+    pub digest: String,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub status: CiStatus,
+}
+
+/// Summary printed by `vorpal ci --json`.
+#[derive(Debug, Serialize)]
+pub struct CiSummary {
+    pub system: &'static str,
+    pub targets: Vec<CiTarget>,
+}
+
+impl CiSummary {
+    pub fn failed(&self) -> usize {
+        self.targets
+            .iter()
+            .filter(|target| target.status == CiStatus::Failed)
+            .count()
+    }
+}
+
+/// Builds `targets` and their dependencies as one graph, so dependencies shared between targets
+/// are built once.
+///
+/// Artifacts that fail to build fail their dependents only, other targets still build unless
+/// `fail_fast` is set.
+#[allow(clippy::too_many_arguments)]
+pub async fn build_targets(
+    artifacts: &HashMap<ArtifactId, Artifact>,
+    chunk_bounds: ChunkBounds,
+    fail_fast: bool,
+    force: &ArtifactForce,
+    push_policy: Option<PushPolicy>,
+    registries: &RegistryUrls,
+    secrets: &[ArtifactStepEnvironment],
+    source_revisions: &[ConfigSourceRevision],
+    system: ArtifactSystem,
+    targets: &[ArtifactId],
+    workers: &ArtifactWorkers,
+) -> Result<(CiSummary, Vec<ArtifactBuildSummary>)> {
+    let mut graph = HashMap::new();
+
+    for target in targets {
+        graph.extend(get_artifacts_evaluated(target, artifacts)?);
+    }
+
+    let build_order = get_order(&graph).await?;
+
+    // Artifacts not built, with their status and reason
+
+    let mut errors = HashMap::<&ArtifactId, (CiStatus, String)>::new();
+    let mut durations = HashMap::<&ArtifactId, Duration>::new();
+    let mut stopped = false;
+    let mut summaries = vec![];
+
+    for artifact_id in &build_order {
+        let Some(artifact) = graph.get(artifact_id) else {
+            bail!("Build artifact not found: {}", artifact_id.name);
+        };
+
+        if stopped {
+            errors.insert(
+                artifact_id,
+                (
+                    CiStatus::Skipped,
+                    "not built after a failure (--fail-fast)".to_string(),
+                ),
+            );
+
+            continue;
+        }
+
+        let dependency = artifact
+            .artifacts
+            .iter()
+            .find(|dependency| errors.contains_key(dependency));
+
+        if let Some(dependency) = dependency {
+            errors.insert(
+                artifact_id,
+                (
+                    CiStatus::Failed,
+                    format!("dependency `{}` was not built", dependency.name),
+                ),
+            );
+
+            continue;
+        }
+
+        if let Err(err) = validate_artifact_system(artifact, system) {
+            warn!("skipping {}", err);
+
+            errors.insert(artifact_id, (CiStatus::Skipped, err));
+
+            continue;
+        }
+
+        let summary = build(
+            artifact,
+            artifact_id,
+            system,
+            chunk_bounds,
+            force,
+            push_policy,
+            registries,
+            secrets,
+            source_revisions,
+            workers,
+        )
+        .await;
+
+        match summary {
+            Ok(summary) => {
+                durations.insert(artifact_id, summary.duration);
+
+                summaries.push(summary);
+            }
+
+            Err(err) => {
+                error!("{} failed: {}", artifact_id.name, err);
+
+                errors.insert(artifact_id, (CiStatus::Failed, err.to_string()));
+
+                stopped = fail_fast;
+            }
+        }
+    }
+
+    let mut ci_targets = vec![];
+
+    for target in targets {
+        let duration_ms = durations
+            .get(target)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+
+        let ci_target = match errors.get(target) {
+            Some((status, error)) => CiTarget {
+                digest: target.hash.clone(),
+                duration_ms,
+                error: Some(error.clone()),
+                name: target.name.clone(),
+                path: None,
+                status: *status,
+            },
+
+            None => CiTarget {
+                digest: target.hash.clone(),
+                duration_ms,
+                error: None,
+                name: target.name.clone(),
+                path: Some(
+                    get_artifact_path(&target.hash.parse()?, &target.name)
+                        .display()
+                        .to_string(),
+                ),
+                status: CiStatus::Passed,
+            },
+        };
+
+        ci_targets.push(ci_target);
+    }
+
+    let ci_summary = CiSummary {
+        system: get_artifact_system_name(system),
+        targets: ci_targets,
+    };
+
+    Ok((ci_summary, summaries))
+}
+
+/// Prints the status of each target, with the reason of targets not passed.
+pub fn print_targets(summary: &CiSummary) {
+    eprintln!("{:<32}  {:<7}  {:>10}", "ARTIFACT", "STATUS", "DURATION");
+
+    for target in summary.targets.iter() {
+        eprintln!(
+            "{:<32}  {:<7}  {:>10}",
+            target.name,
+            target.status.as_str(),
+            get_duration_display(target.duration_ms)
+        );
+
+        if let Some(error) = &target.error {
+            eprintln!("  {}", error);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use crate::watch::{get_snapshot, WatchSnapshot};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{create_dir_all, read, read_to_string, write},
+    ops::Range,
+    path::Path,
+};
+use toml::{Spanned, Value};
+use tonic::Code;
+use tracing::warn;
+use vorpal_schema::{
+    vorpal::{
+        artifact::v0::{Artifact, ArtifactId, ArtifactSystem},
+        config::v0::{config_service_client::ConfigServiceClient, Config, ConfigVersionRequest},
+    },
+    CONFIG_PROTOCOL_VERSION,
+};
+use vorpal_sdk::config::service::ConfigChannel;
+use vorpal_store::{
+    hashes::{get_file_hash, get_hash_digest},
+    paths::get_config_cache_path,
+};
+
+// Keys accepted at the top level and in `[artifacts.<name>]` tables
+const CONFIG_KEYS: [&str; 8] = [
+    "env",
+    "language",
+    "rust_bin",
+    "rust_path",
+    "source_mirrors",
+    "source_revision",
+    "toolchain",
+    "variables",
+];
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct ConfigSettings {
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    pub language: Option<String>,
+    pub rust_bin: Option<String>,
+    pub rust_path: Option<String>,
+    #[serde(default)]
+    pub source_mirrors: Vec<String>,
+    pub source_revision: Option<String>,
+    pub toolchain: Option<String>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+impl ConfigSettings {
+    /// Returns these settings with unset values taken from `defaults`.
+    fn or(self, defaults: &ConfigSettings) -> Self {
+        let mut source_mirrors = self.source_mirrors;
+
+        source_mirrors.extend(defaults.source_mirrors.iter().cloned());
+
+        let mut env = defaults.env.clone();
+
+        env.extend(self.env);
+
+        let mut variables = defaults.variables.clone();
+
+        variables.extend(self.variables);
+
+        Self {
+            env,
+            language: self.language.or(defaults.language.clone()),
+            rust_bin: self.rust_bin.or(defaults.rust_bin.clone()),
+            rust_path: self.rust_path.or(defaults.rust_path.clone()),
+            source_mirrors,
+            source_revision: self.source_revision.or(defaults.source_revision.clone()),
+            toolchain: self.toolchain.or(defaults.toolchain.clone()),
+            variables,
+        }
+    }
+}
+
+/// Settings of `Vorpal.toml`, with optional `[artifacts.<name>]` tables overriding the top
+/// level settings for the artifact selected with `--name`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    artifacts: BTreeMap<String, ConfigSettings>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    language: Option<String>,
+    rust_bin: Option<String>,
+    rust_path: Option<String>,
+    #[serde(default)]
+    source_mirrors: Vec<String>,
+    source_revision: Option<String>,
+    toolchain: Option<String>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFileArtifactKeys {
+    #[serde(default)]
+    artifacts: BTreeMap<String, BTreeMap<Spanned<String>, Value>>,
+}
+
+fn get_location(data: &str, span: Range<usize>) -> String {
+    let before = &data[..span.start.min(data.len())];
+
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+
+    format!("{}:{}", line, column)
+}
+
+fn warn_unknown_keys(
+    path: &Path,
+    data: &str,
+    keys: Vec<&Spanned<String>>,
+    known: &[&str],
+    table: &str,
+) {
+    for key in keys {
+        if known.contains(&key.get_ref().as_str()) {
+            continue;
+        }
+
+        warn!(
+            "{}:{}: unknown key `{}` in {}",
+            path.display(),
+            get_location(data, key.span()),
+            key.get_ref(),
+            table
+        );
+    }
+}
+
+impl ConfigFile {
+    /// Loads `path`, or empty settings when it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = read_to_string(path)?;
+
+        let file: ConfigFile = toml::from_str(&data)
+            .map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))?;
+
+        // Unknown keys are ignored, warn with their location so typos are not silent
+
+        let keys: BTreeMap<Spanned<String>, Value> = toml::from_str(&data)
+            .map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))?;
+
+        let mut known = CONFIG_KEYS.to_vec();
+
+        known.push("artifacts");
+
+        warn_unknown_keys(path, &data, keys.keys().collect(), &known, "config");
+
+        let artifact_keys: ConfigFileArtifactKeys = toml::from_str(&data)
+            .map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))?;
+
+        for (name, keys) in artifact_keys.artifacts.iter() {
+            warn_unknown_keys(
+                path,
+                &data,
+                keys.keys().collect(),
+                &CONFIG_KEYS,
+                &format!("[artifacts.{}]", name),
+            );
+        }
+
+        Ok(file)
+    }
+
+    /// Returns the settings for artifact `name`: its `[artifacts.<name>]` table when declared,
+    /// falling back to the top level settings.
+    pub fn get_settings(&self, name: Option<&str>) -> ConfigSettings {
+        let settings = ConfigSettings {
+            env: self.env.clone(),
+            language: self.language.clone(),
+            rust_bin: self.rust_bin.clone(),
+            rust_path: self.rust_path.clone(),
+            source_mirrors: self.source_mirrors.clone(),
+            source_revision: self.source_revision.clone(),
+            toolchain: self.toolchain.clone(),
+            variables: self.variables.clone(),
+        };
+
+        match name.and_then(|name| self.artifacts.get(name)) {
+            Some(artifact) => artifact.clone().or(&settings),
+            None => settings,
+        }
+    }
+}
+
+/// Inputs of a config evaluation, hashed into the key of its cache entry.
+#[derive(Serialize)]
+struct ConfigCacheKey<'a> {
+    config: String,
+    context: &'a Path,
+    env: &'a BTreeMap<String, String>,
+    registries: &'a [String],
+    source_lock: Option<String>,
+    source_mirrors: &'a [String],
+    system: &'a str,
+    variables: &'a BTreeMap<String, String>,
+}
+
+/// Artifacts of an evaluated config, reused while the config binary, its inputs and the files of
+/// its local sources are unchanged.
+#[derive(Deserialize, Serialize)]
+pub struct ConfigCache {
+    artifacts: Vec<(ArtifactId, Artifact)>,
+    config: Config,
+    sources: WatchSnapshot,
+}
+
+impl ConfigCache {
+    pub fn new(config: Config, artifacts: &HashMap<ArtifactId, Artifact>) -> Result<Self> {
+        let sources = get_snapshot(&config.sources)?;
+
+        let mut artifacts: Vec<(ArtifactId, Artifact)> = artifacts
+            .iter()
+            .map(|(id, artifact)| (id.clone(), artifact.clone()))
+            .collect();
+
+        artifacts.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(Self {
+            artifacts,
+            config,
+            sources,
+        })
+    }
+
+    /// Returns the key of evaluations by `config_file` with these inputs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_key(
+        config_file: &Path,
+        context_path: &Path,
+        env: &BTreeMap<String, String>,
+        registries: &[String],
+        source_lock_path: &Path,
+        source_mirrors: &[String],
+        system: ArtifactSystem,
+        variables: &BTreeMap<String, String>,
+    ) -> Result<String> {
+        let source_lock = match source_lock_path.exists() {
+            true => Some(get_file_hash(source_lock_path)?),
+            false => None,
+        };
+
+        let key = ConfigCacheKey {
+            config: get_file_hash(config_file)?,
+            context: context_path,
+            env,
+            registries,
+            source_lock,
+            source_mirrors,
+            system: system.as_str_name(),
+            variables,
+        };
+
+        Ok(get_hash_digest(&serde_json::to_string(&key)?))
+    }
+
+    /// Loads the entry of `key`, or none when it is missing, unreadable or its local sources
+    /// changed since.
+    pub fn load(key: &str) -> Result<Option<Self>> {
+        let path = get_config_cache_path(key);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let Ok(cache) = serde_json::from_slice::<Self>(&read(&path)?) else {
+            warn!("ignoring unreadable config cache: {}", path.display());
+
+            return Ok(None);
+        };
+
+        if get_snapshot(&cache.config.sources)? != cache.sources {
+            return Ok(None);
+        }
+
+        Ok(Some(cache))
+    }
+
+    pub fn save(&self, key: &str) -> Result<()> {
+        let path = get_config_cache_path(key);
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        write(&path, serde_json::to_vec(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn into_parts(self) -> (Config, HashMap<ArtifactId, Artifact>) {
+        (self.config, self.artifacts.into_iter().collect())
+    }
+}
+
+/// Fails unless the config program was built against `CONFIG_PROTOCOL_VERSION`.
+///
+/// SDKs from before the handshake do not implement it and count as version 0.
+pub async fn check_config_protocol_version(
+    service: &mut ConfigServiceClient<ConfigChannel>,
+) -> Result<()> {
+    let protocol_version = match service.get_version(ConfigVersionRequest {}).await {
+        Ok(response) => response.into_inner().protocol_version,
+        Err(status) if status.code() == Code::Unimplemented => 0,
+        Err(status) => bail!("failed to get config protocol version: {}", status),
+    };
+
+    if protocol_version != CONFIG_PROTOCOL_VERSION {
+        bail!(
+            "config built against protocol v{}, CLI requires v{} (update `vorpal-sdk` in the config program, e.g. `cargo update -p vorpal-sdk`, and run again)",
+            protocol_version,
+            CONFIG_PROTOCOL_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{
+        service::interceptor::InterceptedService,
+        transport::{Channel, Server},
+        Request, Response, Status,
+    };
+    use vorpal_schema::vorpal::{
+        artifact::v0::{ArtifactTemplateRequest, ArtifactTemplateResponse},
+        config::v0::{
+            config_service_server::{ConfigService, ConfigServiceServer},
+            ConfigRequest, ConfigVersionResponse,
+        },
+    };
+    use vorpal_sdk::config::service::ConfigTokenInterceptor;
+
+    // Answers the handshake like a config program built against `protocol_version`, or like
+    // one from before the handshake when it is `None`
+    struct StubConfig {
+        protocol_version: Option<u32>,
+    }
+
+    #[tonic::async_trait]
+    impl ConfigService for StubConfig {
+        async fn get_config(
+            &self,
+            _request: Request<ConfigRequest>,
+        ) -> Result<Response<Config>, Status> {
+            Err(Status::unimplemented("stub"))
+        }
+
+        async fn get_artifact(
+            &self,
+            _request: Request<ArtifactId>,
+        ) -> Result<Response<Artifact>, Status> {
+            Err(Status::unimplemented("stub"))
+        }
+
+        async fn get_template(
+            &self,
+            _request: Request<ArtifactTemplateRequest>,
+        ) -> Result<Response<ArtifactTemplateResponse>, Status> {
+            Err(Status::unimplemented("stub"))
+        }
+
+        async fn get_version(
+            &self,
+            _request: Request<ConfigVersionRequest>,
+        ) -> Result<Response<ConfigVersionResponse>, Status> {
+            match self.protocol_version {
+                Some(protocol_version) => {
+                    Ok(Response::new(ConfigVersionResponse { protocol_version }))
+                }
+                None => Err(Status::unimplemented("get_version")),
+            }
+        }
+    }
+
+    async fn get_stub_client(protocol_version: Option<u32>) -> ConfigServiceClient<ConfigChannel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(ConfigServiceServer::new(StubConfig { protocol_version }))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let channel = Channel::from_shared(address)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        let interceptor = ConfigTokenInterceptor::new("token").unwrap();
+
+        ConfigServiceClient::new(InterceptedService::new(channel, interceptor))
+    }
+
+    #[tokio::test]
+    async fn test_check_config_protocol_version() {
+        let mut service = get_stub_client(Some(CONFIG_PROTOCOL_VERSION)).await;
+
+        check_config_protocol_version(&mut service).await.unwrap();
+
+        let mut service = get_stub_client(Some(CONFIG_PROTOCOL_VERSION + 1)).await;
+
+        let error = check_config_protocol_version(&mut service)
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().starts_with(&format!(
+            "config built against protocol v{}, CLI requires v{}",
+            CONFIG_PROTOCOL_VERSION + 1,
+            CONFIG_PROTOCOL_VERSION
+        )));
+
+        // SDKs from before the handshake
+
+        let mut service = get_stub_client(None).await;
+
+        let error = check_config_protocol_version(&mut service)
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().starts_with(&format!(
+            "config built against protocol v0, CLI requires v{}",
+            CONFIG_PROTOCOL_VERSION
+        )));
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, bail, Result};
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{create_dir_all, remove_dir_all},
+    process::Command,
+};
+use tracing::{info, warn};
+use vorpal_store::{hashes::get_hash_digest, paths::get_context_cache_path};
+
+// Fetched when a remote context names no `#<ref>`
+const CONTEXT_REF_DEFAULT: &str = "HEAD";
+
+/// Returns whether `context` names a git repository (`https://`, `ssh://`, `git://` or
+/// `git@host:path`) instead of a local directory.
+pub fn is_remote_context(context: &str) -> bool {
+    context.contains("://") || (context.starts_with("git@") && context.contains(':'))
+}
+
+/// Returns the path of `config` within `context`, a local directory or a git repository
+/// `<url>[#<ref>]` cloned into the context cache.
+pub async fn get_context_config_path(context: &str, config: &str) -> Result<PathBuf> {
+    if !is_remote_context(context) {
+        return Ok(Path::new(context).join(config));
+    }
+
+    let (url, reference) = match context.rsplit_once('#') {
+        Some((url, reference)) if !reference.is_empty() => (url, reference),
+        Some((url, _)) => (url, CONTEXT_REF_DEFAULT),
+        None => (context, CONTEXT_REF_DEFAULT),
+    };
+
+    let clone_path = get_context_clone(url, reference).await?;
+
+    let config_path = clone_path.join(config);
+
+    if !config_path.exists() {
+        bail!("context {} has no {}", context, config);
+    }
+
+    Ok(config_path)
+}
+
+async fn get_git_output(path: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to run git: {}", e))?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Shallow clones `reference` of `url` once per url and ref, later runs fetch it again and only
+/// fall back to the existing clone when the fetch fails. Authentication is left to git, so ssh
+/// agents and credential helpers apply as they do for `git clone`.
+async fn get_context_clone(url: &str, reference: &str) -> Result<PathBuf> {
+    let clone_path = get_context_cache_path(&get_hash_digest(&format!("{}#{}", url, reference)));
+
+    let clone_exists = clone_path.join(".git").exists();
+
+    if !clone_exists {
+        create_dir_all(&clone_path)
+            .await
+            .map_err(|e| anyhow!("failed to create {}: {}", clone_path.display(), e))?;
+
+        get_git_output(&clone_path, &["init", "--quiet"]).await?;
+        get_git_output(&clone_path, &["remote", "add", "origin", url]).await?;
+    }
+
+    let fetch_args = ["fetch", "--depth", "1", "--quiet", "origin", reference];
+
+    if let Err(error) = get_git_output(&clone_path, &fetch_args).await {
+        if !clone_exists {
+            // Otherwise the next run would fall back to the empty clone
+
+            remove_dir_all(&clone_path)
+                .await
+                .map_err(|e| anyhow!("failed to remove {}: {}", clone_path.display(), e))?;
+
+            return Err(anyhow!("failed to fetch {}#{}: {}", url, reference, error));
+        }
+
+        warn!(
+            "failed to fetch {}#{}, using the cached clone: {}",
+            url, reference, error
+        );
+
+        return Ok(clone_path);
+    }
+
+    let head = get_git_output(&clone_path, &["rev-parse", "--verify", "--quiet", "HEAD"])
+        .await
+        .ok();
+
+    let fetch_head = get_git_output(&clone_path, &["rev-parse", "FETCH_HEAD"]).await?;
+
+    if head.as_deref() == Some(fetch_head.as_str()) {
+        info!("context {}#{} up to date: {}", url, reference, fetch_head);
+
+        return Ok(clone_path);
+    }
+
+    get_git_output(
+        &clone_path,
+        &["checkout", "--force", "--quiet", "--detach", "FETCH_HEAD"],
+    )
+    .await?;
+
+    info!("context {}#{} checked out: {}", url, reference, fetch_head);
+
+    Ok(clone_path)
+}
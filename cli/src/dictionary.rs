@@ -0,0 +1,244 @@
+use anyhow::{bail, Result};
+use std::{collections::BTreeSet, path::Path};
+use tokio::fs::{read, read_dir, write};
+use tonic::Code;
+use tracing::{info, warn};
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactBuildRequest,
+    registry::v0::{
+        registry_service_client::RegistryServiceClient, RegistryKind, RegistryListRequest,
+        RegistryRequest,
+    },
+};
+use vorpal_store::{
+    dictionaries::{
+        decode_archive, get_archive_dictionary_id, get_dictionary_id, get_dictionary_sizes,
+        train_dictionary, DICTIONARY_ARCHIVE_SIZE_MAX,
+    },
+    grpc::{get_registry_channel, get_registry_token, RegistryChannel},
+    paths::{get_manifest_dir_path, get_store_dir_path},
+};
+
+// Archives pulled from a registry to sample, enough to train a dictionary without pulling the
+// whole registry
+const REGISTRY_SAMPLES_MAX: usize = 1000;
+
+// Collects small archives of `sample_extension` from `store_dir_path`
+async fn get_store_archives(store_dir_path: &Path, sample_extension: &str) -> Result<Vec<Vec<u8>>> {
+    let mut archives = vec![];
+    let mut store_dir = read_dir(store_dir_path).await?;
+
+    while let Some(entry) = store_dir.next_entry().await? {
+        let path = entry.path();
+
+        if !path.display().to_string().ends_with(sample_extension) {
+            continue;
+        }
+
+        if entry.metadata().await?.len() as usize > DICTIONARY_ARCHIVE_SIZE_MAX {
+            continue;
+        }
+
+        let data = read(&path).await?;
+
+        if get_archive_dictionary_id(&data).is_some() {
+            continue;
+        }
+
+        archives.push(data);
+    }
+
+    Ok(archives)
+}
+
+// Pulls an object, returning `None` when it is missing or larger than dictionaries are used for
+async fn pull_sample(
+    registry: &mut RegistryServiceClient<RegistryChannel>,
+    kind: RegistryKind,
+    hash: &str,
+    name: &str,
+) -> Result<Option<Vec<u8>>> {
+    let request = RegistryRequest {
+        accept_dictionary: false,
+        hash: hash.to_string(),
+        kind: kind as i32,
+        name: name.to_string(),
+    };
+
+    let mut response = match registry.pull(request).await {
+        Ok(response) => response.into_inner(),
+        Err(status) if status.code() == Code::NotFound => return Ok(None),
+        Err(status) => return Err(status.into()),
+    };
+
+    let mut data = vec![];
+
+    while let Some(res) = response.message().await? {
+        data.extend(res.data);
+
+        if data.len() > DICTIONARY_ARCHIVE_SIZE_MAX {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(data))
+}
+
+// Collects small archives of `kind` through the registry, so every backend can be sampled.
+// Sources are not listed by the registry and are found from the manifests of its artifacts.
+// Manifests are sampled as the JSON they were pushed as, the registry decodes them on pull.
+async fn get_registry_archives(registry_url: &str, kind: RegistryKind) -> Result<Vec<Vec<u8>>> {
+    let mut registry = RegistryServiceClient::new(
+        get_registry_channel(registry_url, get_registry_token().as_deref()).await?,
+    );
+
+    let mut archives = vec![];
+    let mut page_token = String::new();
+    let mut sources = BTreeSet::new();
+
+    loop {
+        let response = registry
+            .list(RegistryListRequest {
+                name_prefix: String::new(),
+                page_size: 0,
+                page_token,
+            })
+            .await?
+            .into_inner();
+
+        for artifact in response.artifacts.iter() {
+            if archives.len() >= REGISTRY_SAMPLES_MAX {
+                return Ok(archives);
+            }
+
+            if kind == RegistryKind::Artifact {
+                let sample = pull_sample(&mut registry, kind, &artifact.hash, &artifact.name);
+
+                if let Some(data) = sample.await? {
+                    archives.push(data);
+                }
+
+                continue;
+            }
+
+            let manifest = pull_sample(
+                &mut registry,
+                RegistryKind::ArtifactManifest,
+                &artifact.hash,
+                &artifact.name,
+            )
+            .await?;
+
+            let Some(manifest) = manifest else {
+                continue;
+            };
+
+            if kind == RegistryKind::ArtifactManifest {
+                archives.push(manifest);
+
+                continue;
+            }
+
+            let manifest = match serde_json::from_slice::<ArtifactBuildRequest>(&manifest) {
+                Ok(manifest) => manifest,
+                Err(err) => {
+                    warn!(
+                        "skipping manifest {}-{}: {}",
+                        artifact.name, artifact.hash, err
+                    );
+
+                    continue;
+                }
+            };
+
+            for source in manifest.artifact.iter().flat_map(|a| a.sources.iter()) {
+                if !sources.insert((source.name.clone(), source.hash.clone())) {
+                    continue;
+                }
+
+                let sample = pull_sample(&mut registry, kind, &source.hash, &source.name);
+
+                if let Some(data) = sample.await? {
+                    archives.push(data);
+                }
+            }
+        }
+
+        if response.next_page_token.is_empty() {
+            return Ok(archives);
+        }
+
+        page_token = response.next_page_token;
+    }
+}
+
+/// Trains a dictionary from archives of `sample_kind` and writes it to `output`. Archives are
+/// pulled through the registry at `registry_url`, whatever its backend, or read from the local
+/// store without one.
+pub async fn train(
+    registry_url: Option<&str>,
+    sample_kind: &str,
+    output: &str,
+    size: usize,
+) -> Result<()> {
+    let (kind, store_dir_path, sample_extension) = match sample_kind {
+        "artifact" => (
+            RegistryKind::Artifact,
+            get_store_dir_path(),
+            "artifact.tar.zst",
+        ),
+        "manifest" => (
+            RegistryKind::ArtifactManifest,
+            get_manifest_dir_path(),
+            ".json",
+        ),
+        "source" => (
+            RegistryKind::ArtifactSource,
+            get_store_dir_path(),
+            "source.tar.zst",
+        ),
+        _ => bail!("unsupported sample kind: {}", sample_kind),
+    };
+
+    let archives = match registry_url {
+        Some(registry_url) => get_registry_archives(registry_url, kind).await?,
+        None => get_store_archives(&store_dir_path, sample_extension).await?,
+    };
+
+    if archives.is_empty() {
+        bail!("no {} archives found to sample", sample_kind);
+    }
+
+    let mut samples = vec![];
+
+    for archive in &archives {
+        match kind {
+            RegistryKind::ArtifactManifest => samples.push(archive.clone()),
+            _ => samples.push(decode_archive(archive, None)?),
+        }
+    }
+
+    let dictionary = train_dictionary(&samples, size)?;
+
+    // Measure savings over the sampled archives
+
+    let (size_before, size_after) = get_dictionary_sizes(&samples, &dictionary)?;
+
+    write(output, &dictionary).await?;
+
+    let savings = 100.0 - (size_after as f64 / size_before as f64 * 100.0);
+
+    info!(
+        "dictionary {} ({} bytes) trained from {} archives",
+        get_dictionary_id(&dictionary)?,
+        dictionary.len(),
+        archives.len()
+    );
+
+    info!(
+        "storage: {} -> {} bytes ({:.1}% saved)",
+        size_before, size_after, savings
+    );
+
+    Ok(())
+}
@@ -0,0 +1,412 @@
+use crate::{artifact::get_bytes_display, get_service_status};
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+use tokio::fs::{remove_file, write};
+use tonic::Code;
+use vorpal_notary::{get_key_fingerprint, get_private_key, get_public_key};
+use vorpal_schema::vorpal::{
+    artifact::v0::{
+        artifact_service_client::ArtifactServiceClient, artifact_service_server,
+        ArtifactInfoRequest,
+    },
+    registry::v0::{
+        registry_service_client::RegistryServiceClient, registry_service_server,
+        RegistryInfoRequest,
+    },
+};
+use vorpal_store::{
+    grpc::{get_channel, RegistryUrls},
+    paths::{get_private_key_path, get_public_key_path, get_sandbox_dir_path, get_store_dir_path},
+};
+use vorpal_worker::{artifact::DEFAULT_MIN_DISK_BYTES, limits::get_available_disk_bytes};
+
+// Free space below this multiple of the worker minimum is reported as a warning
+const DISK_SPACE_WARN_FACTOR: u64 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum DoctorSeverity {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl DoctorSeverity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DoctorSeverity::Pass => "PASS",
+            DoctorSeverity::Warn => "WARN",
+            DoctorSeverity::Fail => "FAIL",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            DoctorSeverity::Pass => 0,
+            DoctorSeverity::Warn => 1,
+            DoctorSeverity::Fail => 2,
+        }
+    }
+}
+
+struct DoctorCheck {
+    detail: String,
+    hint: Option<String>,
+    name: String,
+    severity: DoctorSeverity,
+}
+
+impl DoctorCheck {
+    fn pass(name: &str, detail: impl Into<String>) -> Self {
+        Self {
+            detail: detail.into(),
+            hint: None,
+            name: name.to_string(),
+            severity: DoctorSeverity::Pass,
+        }
+    }
+
+    fn warn(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            detail: detail.into(),
+            hint: Some(hint.into()),
+            name: name.to_string(),
+            severity: DoctorSeverity::Warn,
+        }
+    }
+
+    fn fail(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            detail: detail.into(),
+            hint: Some(hint.into()),
+            name: name.to_string(),
+            severity: DoctorSeverity::Fail,
+        }
+    }
+}
+
+async fn check_keys() -> Vec<DoctorCheck> {
+    let hint = "run 'vorpal keys generate', or copy both keys from the machine signing pushes";
+
+    let private_key_path = get_private_key_path();
+    let public_key_path = get_public_key_path();
+
+    let private_key = get_private_key(private_key_path.clone()).await;
+    let public_key = get_public_key(public_key_path.clone()).await;
+
+    let mut checks = vec![];
+
+    match &private_key {
+        Ok(_) => checks.push(DoctorCheck::pass(
+            "private key",
+            private_key_path.display().to_string(),
+        )),
+        Err(err) => checks.push(DoctorCheck::fail("private key", err.to_string(), hint)),
+    }
+
+    match &public_key {
+        Ok(_) => checks.push(DoctorCheck::pass(
+            "public key",
+            public_key_path.display().to_string(),
+        )),
+        Err(err) => checks.push(DoctorCheck::fail("public key", err.to_string(), hint)),
+    }
+
+    if let (Ok(private_key), Ok(public_key)) = (&private_key, &public_key) {
+        let private_fingerprint = get_key_fingerprint(&private_key.to_public_key());
+        let public_fingerprint = get_key_fingerprint(public_key);
+
+        match (private_fingerprint, public_fingerprint) {
+            (Ok(private), Ok(public)) if private == public => {
+                checks.push(DoctorCheck::pass("key pair", public))
+            }
+            (Ok(private), Ok(public)) => checks.push(DoctorCheck::fail(
+                "key pair",
+                format!(
+                    "private key {} does not match public key {}",
+                    private, public
+                ),
+                hint,
+            )),
+            (Err(err), _) | (_, Err(err)) => {
+                checks.push(DoctorCheck::fail("key pair", err.to_string(), hint))
+            }
+        }
+    }
+
+    checks
+}
+
+async fn check_writable(name: &str, dir_path: &Path) -> DoctorCheck {
+    let hint = "run 'bash ./script/install.sh' to create the vorpal directories for your user";
+
+    if !dir_path.is_dir() {
+        return DoctorCheck::fail(name, format!("not found: {}", dir_path.display()), hint);
+    }
+
+    let probe_path = dir_path.join(format!(".doctor-{}", std::process::id()));
+
+    if let Err(err) = write(&probe_path, b"").await {
+        return DoctorCheck::fail(
+            name,
+            format!("not writable: {} ({})", dir_path.display(), err),
+            hint,
+        );
+    }
+
+    let _ = remove_file(&probe_path).await;
+
+    DoctorCheck::pass(name, format!("writable: {}", dir_path.display()))
+}
+
+fn check_disk_space(dir_path: &Path) -> DoctorCheck {
+    let name = "disk space";
+    let hint = "free space with 'vorpal store prune-sandboxes' or by removing unused artifacts";
+
+    let available = match get_available_disk_bytes(dir_path) {
+        Ok(available) => available,
+        Err(err) => {
+            return DoctorCheck::warn(
+                name,
+                format!(
+                    "failed to read free space of {}: {}",
+                    dir_path.display(),
+                    err
+                ),
+                "check the store directory exists",
+            )
+        }
+    };
+
+    let detail = format!(
+        "{} free (workers require {})",
+        get_bytes_display(available),
+        get_bytes_display(DEFAULT_MIN_DISK_BYTES)
+    );
+
+    if available < DEFAULT_MIN_DISK_BYTES {
+        return DoctorCheck::fail(name, detail, hint);
+    }
+
+    if available < DEFAULT_MIN_DISK_BYTES * DISK_SPACE_WARN_FACTOR {
+        return DoctorCheck::warn(name, detail, hint);
+    }
+
+    DoctorCheck::pass(name, detail)
+}
+
+fn check_sandbox() -> Vec<DoctorCheck> {
+    if !cfg!(target_os = "linux") {
+        return vec![];
+    }
+
+    let version = Command::new("bwrap")
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output();
+
+    let version = match version {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        Ok(output) => {
+            return vec![DoctorCheck::fail(
+                "bwrap",
+                format!("'bwrap --version' exited with {}", output.status),
+                "reinstall bubblewrap with your package manager",
+            )]
+        }
+        Err(err) => {
+            return vec![DoctorCheck::fail(
+                "bwrap",
+                format!("not found in PATH ({})", err),
+                "install bubblewrap with your package manager",
+            )]
+        }
+    };
+
+    let mut checks = vec![DoctorCheck::pass("bwrap", version)];
+
+    let namespaces = Command::new("bwrap")
+        .args(["--unshare-all", "--ro-bind", "/", "/", "true"])
+        .stdout(Stdio::null())
+        .output();
+
+    match namespaces {
+        Ok(output) if output.status.success() => {
+            checks.push(DoctorCheck::pass("user namespaces", "supported"))
+        }
+        Ok(output) => {
+            let error = String::from_utf8_lossy(&output.stderr);
+
+            checks.push(DoctorCheck::fail(
+                "user namespaces",
+                error.lines().next().unwrap_or("bwrap failed").to_string(),
+                "allow unprivileged user namespaces (sysctl kernel.unprivileged_userns_clone=1, or kernel.apparmor_restrict_unprivileged_userns=0 on Ubuntu)",
+            ))
+        }
+        Err(err) => checks.push(DoctorCheck::fail(
+            "user namespaces",
+            err.to_string(),
+            "install bubblewrap with your package manager",
+        )),
+    }
+
+    checks
+}
+
+fn check_version(name: &str, address: &str, version: &str) -> DoctorCheck {
+    let cli_version = env!("CARGO_PKG_VERSION");
+    let hint = format!(
+        "run the same vorpal version ({}) for the cli and services",
+        cli_version
+    );
+
+    if version.is_empty() {
+        return DoctorCheck::warn(
+            name,
+            format!("{} does not report its version", address),
+            hint,
+        );
+    }
+
+    if version != cli_version {
+        return DoctorCheck::warn(
+            name,
+            format!("{} is {} (cli is {})", address, version, cli_version),
+            hint,
+        );
+    }
+
+    DoctorCheck::pass(name, format!("{} is {}", address, version))
+}
+
+fn check_protocol_error(name: &str, address: &str, status: tonic::Status) -> DoctorCheck {
+    match status.code() {
+        Code::Unimplemented => DoctorCheck::fail(
+            name,
+            format!("{} does not implement the protocol of this cli", address),
+            format!(
+                "run the same vorpal version ({}) for the cli and services",
+                env!("CARGO_PKG_VERSION")
+            ),
+        ),
+        Code::PermissionDenied | Code::Unauthenticated => DoctorCheck::warn(
+            name,
+            status.message().to_string(),
+            "set '--registry-token' to a token accepted by the registry",
+        ),
+        _ => DoctorCheck::warn(
+            name,
+            format!("{}: {}", status.code(), status.message()),
+            "check the logs of 'vorpal start'",
+        ),
+    }
+}
+
+async fn check_service(name: &str, address: &str, service_name: &str) -> Option<DoctorCheck> {
+    let status = get_service_status(address, service_name).await;
+
+    let check = match status.as_str() {
+        "SERVING" => return None,
+        "UNREACHABLE" => DoctorCheck::fail(
+            name,
+            format!("{} is unreachable", address),
+            "start the services with 'vorpal start', or point '--registry' and '--service' at running ones",
+        ),
+        "NOT_STARTED" => DoctorCheck::fail(
+            name,
+            format!("{} is not running this service", address),
+            "include it in 'vorpal start --services'",
+        ),
+        _ => DoctorCheck::fail(
+            name,
+            format!("{} is {}", address, status),
+            "check the logs of 'vorpal start' for why the service is not serving",
+        ),
+    };
+
+    Some(check)
+}
+
+async fn check_worker(address: &str) -> DoctorCheck {
+    let name = "worker";
+
+    if let Some(check) = check_service(name, address, artifact_service_server::SERVICE_NAME).await {
+        return check;
+    }
+
+    let channel = match get_channel(address).await {
+        Ok(channel) => channel,
+        Err(err) => {
+            return DoctorCheck::fail(name, err.to_string(), "check the '--service' address")
+        }
+    };
+
+    match ArtifactServiceClient::new(channel)
+        .info(ArtifactInfoRequest {})
+        .await
+    {
+        Ok(response) => check_version(name, address, &response.into_inner().version),
+        Err(status) => check_protocol_error(name, address, status),
+    }
+}
+
+async fn check_registry(registries: &RegistryUrls, address: &str) -> DoctorCheck {
+    let name = "registry";
+
+    if let Some(check) = check_service(name, address, registry_service_server::SERVICE_NAME).await {
+        return check;
+    }
+
+    let channel = match registries.get_channel(address).await {
+        Ok(channel) => channel,
+        Err(err) => {
+            return DoctorCheck::fail(name, err.to_string(), "check the '--registry' address")
+        }
+    };
+
+    match RegistryServiceClient::new(channel)
+        .info(RegistryInfoRequest {})
+        .await
+    {
+        Ok(response) => check_version(name, address, &response.into_inner().version),
+        Err(status) => check_protocol_error(name, address, status),
+    }
+}
+
+/// Runs every check, printing one line each, and returns the exit code of the worst result:
+/// 0 when all pass, 1 with warnings and 2 with failures.
+pub async fn run_doctor(registries: &RegistryUrls, service: &str) -> i32 {
+    let mut checks = check_keys().await;
+
+    checks.push(check_writable("store", &get_store_dir_path()).await);
+    checks.push(check_writable("sandbox", &get_sandbox_dir_path()).await);
+    checks.push(check_disk_space(&get_store_dir_path()));
+    checks.extend(check_sandbox());
+    checks.push(check_worker(service).await);
+    for registry in registries.urls.iter() {
+        checks.push(check_registry(registries, registry).await);
+    }
+
+    for check in checks.iter() {
+        println!(
+            "{}  {:<16}  {}",
+            check.severity.as_str(),
+            check.name,
+            check.detail
+        );
+
+        if let Some(hint) = &check.hint {
+            println!("      {:<16}  hint: {}", "", hint);
+        }
+    }
+
+    checks
+        .iter()
+        .map(|check| check.severity)
+        .max()
+        .unwrap_or(DoctorSeverity::Pass)
+        .exit_code()
+}
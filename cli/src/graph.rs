@@ -0,0 +1,166 @@
+use crate::artifact::ArtifactPlanStatus;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use vorpal_schema::vorpal::artifact::v0::{Artifact, ArtifactId};
+
+// Digest characters shown in node labels
+const GRAPH_DIGEST_LENGTH: usize = 12;
+
+/// Artifact of `vorpal artifact graph`, with the digests of the artifacts it depends on.
+#[derive(Debug, Serialize)]
+pub struct GraphNode {
+    pub dependencies: Vec<String>,
+    pub digest: String,
+    pub name: String,
+    pub status: &'static str,
+    /// Set when `--depth` hides the dependencies of this artifact.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// Dependency graph printed by `vorpal artifact graph --format json`, nodes are ordered by
+/// distance from `root` then name.
+#[derive(Debug, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub root: String,
+}
+
+/// Returns the graph of `artifact_id` and its dependencies, up to `depth` edges away when set.
+pub fn get_graph(
+    artifact_id: &ArtifactId,
+    artifacts: &HashMap<ArtifactId, Artifact>,
+    statuses: &HashMap<ArtifactId, ArtifactPlanStatus>,
+    depth: Option<usize>,
+) -> Graph {
+    let mut nodes = vec![];
+    let mut queue = VecDeque::from([(artifact_id, 0)]);
+    let mut visited = HashSet::from([artifact_id]);
+
+    // Breadth first, so each artifact is at its shortest distance from the root
+
+    while let Some((id, id_depth)) = queue.pop_front() {
+        let dependencies = artifacts
+            .get(id)
+            .map(|artifact| artifact.artifacts.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let truncated = depth.is_some_and(|depth| id_depth >= depth) && !dependencies.is_empty();
+
+        if !truncated {
+            for dependency in dependencies.iter() {
+                if visited.insert(dependency) {
+                    queue.push_back((dependency, id_depth + 1));
+                }
+            }
+        }
+
+        nodes.push((
+            id_depth,
+            GraphNode {
+                dependencies: match truncated {
+                    true => vec![],
+                    false => dependencies.iter().map(|d| d.hash.clone()).collect(),
+                },
+                digest: id.hash.clone(),
+                name: id.name.clone(),
+                status: statuses.get(id).map(|s| s.as_str()).unwrap_or_default(),
+                truncated,
+            },
+        ));
+    }
+
+    nodes.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.name.cmp(&b.1.name)));
+
+    Graph {
+        nodes: nodes.into_iter().map(|(_, node)| node).collect(),
+        root: artifact_id.hash.clone(),
+    }
+}
+
+fn get_dot_id(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns `graph` as Graphviz DOT, edges point from dependents to their dependencies.
+///
+/// Cached artifacts are filled grey, pullable ones blue, and artifacts with dependencies hidden
+/// by `--depth` are dashed.
+pub fn get_graph_dot(graph: &Graph) -> String {
+    let mut lines = vec![
+        "digraph vorpal {".to_string(),
+        "  rankdir=LR;".to_string(),
+        "  node [fontname=\"monospace\", shape=box];".to_string(),
+    ];
+
+    for node in graph.nodes.iter() {
+        let mut styles = vec![];
+
+        let fillcolor = match node.status {
+            "cached" => Some("lightgrey"),
+            "pull" => Some("lightblue"),
+            _ => None,
+        };
+
+        if fillcolor.is_some() {
+            styles.push("filled");
+        }
+
+        if node.truncated {
+            styles.push("dashed");
+        }
+
+        // Names are escaped before joining, `\n` is a line break in DOT labels
+
+        let label = get_dot_id(&node.name);
+
+        let mut attributes = vec![format!(
+            "label={}\\n{}\"",
+            &label[..label.len() - 1],
+            &node.digest[..node.digest.len().min(GRAPH_DIGEST_LENGTH)]
+        )];
+
+        if !styles.is_empty() {
+            attributes.push(format!("style={}", get_dot_id(&styles.join(","))));
+        }
+
+        if let Some(fillcolor) = fillcolor {
+            attributes.push(format!("fillcolor={}", fillcolor));
+        }
+
+        lines.push(format!(
+            "  {} [{}];",
+            get_dot_id(&node.digest),
+            attributes.join(", ")
+        ));
+    }
+
+    for node in graph.nodes.iter() {
+        for dependency in node.dependencies.iter() {
+            lines.push(format!(
+                "  {} -> {};",
+                get_dot_id(&node.digest),
+                get_dot_id(dependency)
+            ));
+        }
+    }
+
+    lines.push("}".to_string());
+
+    lines.join("\n")
+}
+
+/// Prints `graph` to stdout in `format`, `dot` or `json`.
+pub fn print_graph(graph: &Graph, format: &str) -> Result<()> {
+    match format {
+        "dot" => println!("{}", get_graph_dot(graph)),
+        "json" => println!("{}", serde_json::to_string_pretty(graph)?),
+        _ => bail!(
+            "unknown graph format: {} (expected `dot` or `json`)",
+            format
+        ),
+    }
+
+    Ok(())
+}
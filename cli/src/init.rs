@@ -0,0 +1,352 @@
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    path::{Path, PathBuf},
+};
+use toml::Value;
+
+// Directory of the generated config program, a member of the project workspace
+const INIT_CONFIG_DIR: &str = "vorpal";
+
+/// Project files found in the current directory.
+struct InitProject {
+    language: Option<String>,
+    name: Option<String>,
+    workspace_members: Option<Vec<String>>,
+}
+
+fn get_package_name(cargo_toml: &Value) -> Option<String> {
+    cargo_toml
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+}
+
+fn get_project(path: &Path) -> Result<InitProject> {
+    let cargo_toml_path = path.join("Cargo.toml");
+
+    if cargo_toml_path.exists() {
+        let cargo_toml: Value = toml::from_str(&read_to_string(&cargo_toml_path)?)
+            .map_err(|e| anyhow!("failed to parse {}: {}", cargo_toml_path.display(), e))?;
+
+        let workspace_members =
+            cargo_toml
+                .get("workspace")
+                .map(|workspace| match workspace.get("members") {
+                    Some(Value::Array(members)) => members
+                        .iter()
+                        .filter_map(|member| member.as_str().map(|m| m.to_string()))
+                        .collect(),
+                    _ => vec![],
+                });
+
+        // Workspaces without a root package are named after their first member package
+
+        let name = get_package_name(&cargo_toml).or_else(|| {
+            workspace_members.iter().flatten().find_map(|member| {
+                let member_path = path.join(member).join("Cargo.toml");
+
+                read_to_string(member_path)
+                    .ok()
+                    .and_then(|data| toml::from_str::<Value>(&data).ok())
+                    .and_then(|cargo_toml| get_package_name(&cargo_toml))
+            })
+        });
+
+        return Ok(InitProject {
+            language: Some("rust".to_string()),
+            name,
+            workspace_members,
+        });
+    }
+
+    if path.join("go.mod").exists() {
+        return Ok(InitProject {
+            language: Some("go".to_string()),
+            name: None,
+            workspace_members: None,
+        });
+    }
+
+    Ok(InitProject {
+        language: None,
+        name: None,
+        workspace_members: None,
+    })
+}
+
+fn get_config_file(language: &str, rust_bin: &str) -> String {
+    format!(
+        "language = \"{}\"\nrust_bin = \"{}\"\nrust_path = \".\"\n",
+        language, rust_bin
+    )
+}
+
+fn get_config_program_cargo_toml(rust_bin: &str) -> String {
+    format!(
+        r#"[package]
+name = "{rust_bin}"
+version = "0.1.0"
+edition = "2021"
+
+[[bin]]
+name = "{rust_bin}"
+path = "src/main.rs"
+
+[dependencies]
+anyhow = {{ default-features = false, version = "1" }}
+tokio = {{ default-features = false, features = ["macros", "rt-multi-thread"], version = "1" }}
+vorpal-sdk = {{ default-features = false, git = "https://github.com/ALT-F4-LLC/vorpal" }}
+"#
+    )
+}
+
+fn get_config_program_main(name: &str) -> String {
+    format!(
+        r#"use anyhow::Result;
+use vorpal_sdk::config::{{
+    artifact::language::rust::{{rust_shell, RustBuilder}},
+    get_context,
+}};
+
+#[tokio::main]
+async fn main() -> Result<()> {{
+    // Get the context
+    let context = &mut get_context().await?;
+
+    // Create artifacts
+    let artifacts = vec![
+        RustBuilder::new("{name}").build(context).await?,
+        rust_shell(context, "{name}").await?,
+    ];
+
+    // Run the context
+    context.run(artifacts).await
+}}
+"#
+    )
+}
+
+/// Writes `Vorpal.toml` at `config_path` and, unless `no_program`, a config program in the
+/// `vorpal` directory next to it.
+///
+/// The language and artifact name default to what the project files in the directory declare,
+/// existing files are only replaced with `force`.
+pub fn init(
+    config_path: &Path,
+    force: bool,
+    language: Option<String>,
+    name: Option<String>,
+    no_program: bool,
+    rust_bin: &str,
+) -> Result<()> {
+    let project_path = match config_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let project = get_project(&project_path)?;
+
+    let Some(language) = language.or(project.language) else {
+        bail!("no project found, specify `--language`");
+    };
+
+    // Config programs can only be written in Rust, other SDKs are not available yet
+
+    if language != "rust" {
+        bail!("unsupported language: {}", language);
+    }
+
+    let name = name
+        .or(project.name)
+        .or_else(|| {
+            project_path
+                .canonicalize()
+                .ok()
+                .and_then(|path| path.file_name().map(|n| n.to_string_lossy().to_string()))
+        })
+        .ok_or_else(|| anyhow!("no `--name` specified"))?;
+
+    let program_path = config_path.with_file_name(INIT_CONFIG_DIR);
+
+    let mut files = vec![(
+        config_path.to_path_buf(),
+        get_config_file(&language, rust_bin),
+    )];
+
+    if !no_program {
+        files.push((
+            program_path.join("Cargo.toml"),
+            get_config_program_cargo_toml(rust_bin),
+        ));
+
+        files.push((
+            program_path.join("src").join("main.rs"),
+            get_config_program_main(&name),
+        ));
+    }
+
+    // Check every file first so a refused init writes nothing
+
+    if !force {
+        for (path, _) in files.iter() {
+            if path.exists() {
+                bail!(
+                    "{} already exists (use `--force` to overwrite)",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    for (path, data) in files.iter() {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        write(path, data)?;
+
+        println!("created {}", path.display());
+    }
+
+    if no_program {
+        return Ok(());
+    }
+
+    // Config programs are built with cargo from the workspace root
+
+    match project.workspace_members {
+        Some(members) if members.iter().any(|member| member == INIT_CONFIG_DIR) => {}
+        Some(_) => eprintln!(
+            "add \"{}\" to the workspace members of Cargo.toml to build the config program",
+            INIT_CONFIG_DIR
+        ),
+        None => eprintln!(
+            "rust artifacts build Cargo workspaces, add a [workspace] with \"{}\" to Cargo.toml",
+            INIT_CONFIG_DIR
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigFile;
+
+    fn get_test_workspace() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        create_dir_all(dir.path().join("app")).unwrap();
+
+        write(
+            dir.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"app\", \"vorpal\"]\n",
+        )
+        .unwrap();
+
+        write(
+            dir.path().join("app/Cargo.toml"),
+            "[package]\nname = \"example-app\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn test_init_writes_project_files() {
+        let dir = get_test_workspace();
+        let config_path = dir.path().join("Vorpal.toml");
+
+        init(&config_path, false, None, None, false, "example-config").unwrap();
+
+        // Settings point `vorpal` at the generated program
+
+        let settings = ConfigFile::load(&config_path).unwrap().get_settings(None);
+
+        assert_eq!(settings.language.as_deref(), Some("rust"));
+        assert_eq!(settings.rust_bin.as_deref(), Some("example-config"));
+        assert_eq!(settings.rust_path.as_deref(), Some("."));
+
+        let cargo_toml: Value =
+            toml::from_str(&read_to_string(dir.path().join("vorpal/Cargo.toml")).unwrap()).unwrap();
+
+        assert_eq!(
+            get_package_name(&cargo_toml).as_deref(),
+            Some("example-config")
+        );
+        assert_eq!(cargo_toml["bin"][0]["path"].as_str(), Some("src/main.rs"));
+
+        // Named after the first workspace member package
+
+        let main = read_to_string(dir.path().join("vorpal/src/main.rs")).unwrap();
+
+        assert!(main.contains("RustBuilder::new(\"example-app\")"));
+        assert!(main.contains("rust_shell(context, \"example-app\")"));
+    }
+
+    #[test]
+    fn test_init_refuses_existing_files() {
+        let dir = get_test_workspace();
+        let config_path = dir.path().join("Vorpal.toml");
+
+        create_dir_all(dir.path().join("vorpal/src")).unwrap();
+        write(dir.path().join("vorpal/src/main.rs"), "fn main() {}").unwrap();
+
+        let error = init(&config_path, false, None, None, false, "example-config").unwrap_err();
+
+        assert!(error.to_string().contains("already exists"));
+
+        // Nothing is written when any file exists
+
+        assert!(!config_path.exists());
+
+        init(
+            &config_path,
+            true,
+            None,
+            Some("other".to_string()),
+            false,
+            "example-config",
+        )
+        .unwrap();
+
+        let main = read_to_string(dir.path().join("vorpal/src/main.rs")).unwrap();
+
+        assert!(main.contains("RustBuilder::new(\"other\")"));
+    }
+
+    #[test]
+    fn test_init_languages() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("Vorpal.toml");
+
+        let error = init(&config_path, false, None, None, false, "example-config").unwrap_err();
+
+        assert_eq!(error.to_string(), "no project found, specify `--language`");
+
+        write(dir.path().join("go.mod"), "module example\n").unwrap();
+
+        let error = init(&config_path, false, None, None, false, "example-config").unwrap_err();
+
+        assert_eq!(error.to_string(), "unsupported language: go");
+
+        // Only settings are written without a program
+
+        init(
+            &config_path,
+            false,
+            Some("rust".to_string()),
+            Some("example".to_string()),
+            true,
+            "example-config",
+        )
+        .unwrap();
+
+        assert!(config_path.exists());
+        assert!(!dir.path().join("vorpal").exists());
+    }
+}
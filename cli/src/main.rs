@@ -1,84 +1,488 @@
-use crate::{artifact::build, rust::get_rust_toolchain_version};
+use crate::{
+    artifact::{
+        build, check_keys, encrypt_secrets, get_bytes_display, get_plan_statuses, get_provenance,
+        get_secrets, get_shell_run, get_workers, print_plan, print_summary, run_entrypoint,
+        ArtifactForce, ArtifactRun, ArtifactWorkers,
+    },
+    config::{check_config_protocol_version, ConfigCache, ConfigFile, ConfigSettings},
+    context::get_context_config_path,
+    rust::get_rust_toolchain_version,
+    select::ArtifactChoice,
+};
 use anyhow::{anyhow, bail, Result};
-use clap::{Parser, Subcommand};
-use port_selector::random_free_port;
+use clap::{Args, Parser, Subcommand};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env::{
         consts::{ARCH, OS},
         var,
     },
     path::{Path, PathBuf},
     process::Stdio,
+    time::Duration,
 };
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::{process, process::Child};
+use tokio::{
+    process,
+    process::Child,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    time::timeout,
+};
 use tokio_stream::{wrappers::LinesStream, StreamExt};
-use tonic::transport::{Channel, Server};
-use tracing::{info, warn, Level};
+use tonic::{codec::CompressionEncoding, service::interceptor::InterceptedService, Code};
+use tonic_health::{
+    pb::{health_client::HealthClient, HealthCheckRequest},
+    ServingStatus,
+};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::fmt::writer::MakeWriterExt;
 use tracing_subscriber::FmtSubscriber;
-use vorpal_registry::{RegistryBackend, RegistryServer, RegistryServerBackend};
+use vorpal_registry::{
+    RegistryAuth, RegistryAuthMode, RegistryBackend, RegistryServer, RegistryServerBackend,
+};
 use vorpal_schema::{
-    get_artifact_system,
+    get_artifact_system, get_artifact_system_name, validate_artifact_system,
     vorpal::{
+        agent::v0::{
+            agent_service_client::AgentServiceClient,
+            agent_service_server::{self, AgentServiceServer},
+            AgentStatsRequest,
+        },
         artifact::v0::{
-            artifact_service_server::ArtifactServiceServer, Artifact, ArtifactId, ArtifactSystem,
+            artifact_service_server::{self, ArtifactServiceServer},
+            Artifact, ArtifactId, ArtifactStepEnvironment, ArtifactSystem,
             ArtifactSystem::UnknownSystem,
         },
-        config::v0::{config_service_client::ConfigServiceClient, ConfigRequest},
-        registry::v0::registry_service_server::RegistryServiceServer,
+        config::v0::{
+            config_service_client::ConfigServiceClient, Config, ConfigArtifactSource, ConfigRequest,
+        },
+        registry::v0::{
+            registry_service_client::RegistryServiceClient,
+            registry_service_server::{self, RegistryServiceServer},
+            RegistryListRequest, RegistryPruneRequest, RegistrySearchRequest,
+        },
     },
 };
 use vorpal_sdk::config::{
     artifact::{language::rust, toolchain::protoc},
+    locks::{SourceLock, SOURCE_LOCK_FILE},
+    mirrors::SourceMirrors,
+    service::{ConfigChannel, ConfigReady, ConfigTokenInterceptor},
     ConfigContext,
 };
-use vorpal_store::paths::{get_artifact_path, get_public_key_path};
-use vorpal_worker::artifact::ArtifactServer;
+use vorpal_store::{
+    archives::ArchiveCompression,
+    blobs::{dedup_path, prune_blobs, BlobSummary, STORE_DEDUP_ENV},
+    caches::{
+        get_source_cache_entries, get_source_cache_size_max, DEFAULT_SOURCE_CACHE_SIZE_MAX,
+        SOURCE_CACHE_SIZE_MAX_ENV,
+    },
+    chunks::{
+        set_network_limit, ChunkBounds, CHUNK_MESSAGE_SIZE_LIMIT, DEFAULT_CHUNK_SIZE_MAX,
+        DEFAULT_CHUNK_SIZE_MIN,
+    },
+    dictionaries::DICTIONARY_SIZE_DEFAULT,
+    grpc::{
+        get_channel, get_max_message_size, get_server_builder, RegistryUrls, AGENT_ENV,
+        DEFAULT_MESSAGE_SIZE_MAX, MESSAGE_SIZE_MAX_ENV, REGISTRY_TOKEN_ENV, TLS_CA_ENV,
+        TLS_CLIENT_CERT_ENV, TLS_CLIENT_KEY_ENV,
+    },
+    http::{CA_BUNDLE_ENV, INSECURE_SKIP_TLS_VERIFY_ENV},
+    manifests::verify_manifests,
+    metrics::serve_metrics,
+    paths::{
+        get_artifact_path, get_cache_dir_path, get_manifest_dir_path, get_public_key_path,
+        get_store_dir_path,
+    },
+    temps::prune_sandboxes,
+};
+use vorpal_worker::{
+    agent::AgentServer,
+    artifact::{
+        log_signing_key_fingerprint, remove_interrupted_builds, ArtifactServer,
+        DEFAULT_MIN_DISK_BYTES,
+    },
+    push::{get_pending_pushes, push_pending, PushPolicy},
+    queue::get_default_max_concurrent_builds,
+    webhook::EventWebhook,
+};
 
 mod artifact;
 mod build;
+mod bundle;
+mod ci;
+mod config;
+mod context;
+mod dictionary;
+mod doctor;
+mod graph;
+mod init;
+mod processes;
+mod retry;
+mod select;
+mod sources;
+mod watch;
 
 #[derive(Subcommand)]
 enum Command {
+    #[clap(subcommand)]
+    Agent(CommandAgent),
+
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Artifact {
+        #[command(subcommand)]
+        command: Option<CommandArtifact>,
+
         #[arg(default_value_t = false, long)]
         export: bool,
 
         #[arg(long)]
-        name: String,
+        name: Option<String>,
+
+        #[command(flatten)]
+        options: ArtifactOptions,
+
+        #[arg(default_value_t = false, long)]
+        plan: bool,
+
+        #[arg(default_value_t = false, long)]
+        watch: bool,
+    },
+
+    Ci {
+        #[arg(default_value_t = false, long)]
+        fail_fast: bool,
+
+        #[arg(default_value_t = false, long)]
+        json: bool,
+
+        #[arg(long = "name", required = true)]
+        names: Vec<String>,
+
+        #[command(flatten)]
+        options: ArtifactOptions,
+    },
 
+    Doctor {
         #[clap(default_value = "http://localhost:23151", long)]
         service: String,
+    },
+
+    Init {
+        #[arg(default_value_t = false, long)]
+        force: bool,
+
+        #[arg(long)]
+        language: Option<String>,
+
+        #[arg(long)]
+        name: Option<String>,
 
-        #[arg(default_value_t = get_default_system(), long)]
-        system: String,
+        #[arg(default_value_t = false, long)]
+        no_program: bool,
     },
 
     #[clap(subcommand)]
     Keys(CommandKeys),
 
+    #[clap(subcommand)]
+    Process(CommandProcess),
+
+    #[clap(subcommand)]
+    Registry(CommandRegistry),
+
+    Status {
+        #[clap(default_value = "http://localhost:23151", long)]
+        service: String,
+
+        #[arg(default_value = "artifact,registry", long)]
+        services: String,
+    },
+
+    Shell {
+        #[arg(long)]
+        command: Option<String>,
+
+        #[arg(long)]
+        name: String,
+
+        #[command(flatten)]
+        options: ArtifactOptions,
+    },
+
     Start {
+        #[arg(long = "agent-host")]
+        agent_hosts: Vec<String>,
+
+        #[arg(default_value_t = ArchiveCompression::default(), long)]
+        archive_compression: ArchiveCompression,
+
+        #[arg(long = "env-passthrough")]
+        env_passthrough: Vec<String>,
+
+        #[arg(long)]
+        event_webhook: Option<String>,
+
+        #[arg(long)]
+        event_webhook_secret_file: Option<String>,
+
+        #[arg(default_value_t = get_default_max_concurrent_builds(), long)]
+        max_concurrent_builds: usize,
+
+        #[arg(default_value_t = DEFAULT_MIN_DISK_BYTES, long)]
+        min_disk_bytes: u64,
+
+        #[arg(long)]
+        metrics_port: Option<u16>,
+
         #[clap(default_value = "23151", long)]
         port: u16,
 
+        #[arg(default_value_t = PushPolicy::default(), long)]
+        push_policy: PushPolicy,
+
         #[arg(default_value = "artifact,registry", long)]
         services: String,
 
+        #[arg(default_value_t = RegistryAuthMode::default(), long)]
+        registry_auth_mode: RegistryAuthMode,
+
+        #[arg(long)]
+        registry_auth_token_file: Option<String>,
+
         #[arg(default_value = "local", long)]
         registry_backend: String,
 
+        #[arg(long)]
+        registry_backend_oci_repo: Option<String>,
+
         #[arg(long)]
         registry_backend_s3_bucket: Option<String>,
+
+        #[arg(long)]
+        registry_dictionary: Option<String>,
+
+        #[arg(default_value_t = false, long)]
+        step_cache: bool,
+
+        #[arg(long)]
+        tls_cert: Option<String>,
+
+        #[arg(long)]
+        tls_client_ca: Option<String>,
+
+        #[arg(long)]
+        tls_key: Option<String>,
+    },
+
+    #[clap(subcommand)]
+    Store(CommandStore),
+}
+
+#[derive(Args)]
+pub struct ArtifactOptions {
+    #[arg(default_value_t = false, long)]
+    check_reproducibility: bool,
+
+    #[arg(long = "force-build")]
+    force_builds: Vec<String>,
+
+    #[arg(default_value_t = false, long)]
+    no_cache: bool,
+
+    #[arg(default_value_t = false, long)]
+    no_config_cache: bool,
+
+    #[arg(default_value_t = false, long)]
+    no_summary: bool,
+
+    #[arg(long)]
+    push_policy: Option<PushPolicy>,
+
+    #[arg(long = "secret")]
+    secrets: Vec<String>,
+
+    #[clap(default_value = "http://localhost:23151", long)]
+    service: Vec<String>,
+
+    #[arg(long = "source-mirror")]
+    source_mirrors: Vec<String>,
+
+    #[arg(long)]
+    source_revision: Option<String>,
+
+    #[arg(default_values_t = [get_default_system()], long = "system")]
+    systems: Vec<String>,
+
+    #[arg(default_value_t = false, long)]
+    update_locks: bool,
+
+    #[arg(long = "variable")]
+    variables: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum CommandAgent {
+    Stats {},
+}
+
+#[derive(Subcommand)]
+pub enum CommandArtifact {
+    Graph {
+        #[arg(long)]
+        depth: Option<usize>,
+
+        #[arg(default_value = "dot", long)]
+        format: String,
+
+        #[arg(long)]
+        name: String,
+
+        #[command(flatten)]
+        options: ArtifactOptions,
+    },
+
+    List {
+        #[arg(long)]
+        filter: Option<String>,
+    },
+
+    Provenance {
+        #[arg(long)]
+        digest: String,
+
+        #[arg(long = "key")]
+        keys: Vec<String>,
+
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    Run {
+        #[arg(last = true)]
+        arguments: Vec<String>,
+
+        #[arg(long)]
+        entrypoint: Option<String>,
+
+        #[arg(long)]
+        name: String,
+
+        #[command(flatten)]
+        options: ArtifactOptions,
+    },
+
+    Search {
+        term: String,
+    },
+
+    Sources {
+        #[arg(long)]
+        diff: Option<String>,
+
+        #[arg(long)]
+        name: String,
+
+        #[command(flatten)]
+        options: ArtifactOptions,
     },
 }
 
 #[derive(Subcommand)]
 pub enum CommandKeys {
+    Fingerprint {
+        #[arg(long)]
+        public: Option<String>,
+    },
+
     Generate {},
 }
 
+#[derive(Subcommand)]
+pub enum CommandProcess {
+    Logs {
+        #[arg(default_value_t = false, long)]
+        follow: bool,
+
+        #[arg(long)]
+        name: String,
+    },
+
+    Status {
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CommandRegistry {
+    Prune {
+        #[arg(default_value_t = false, long)]
+        dry_run: bool,
+
+        #[arg(default_value_t = 30, long)]
+        min_age_days: u64,
+    },
+
+    TrainDictionary {
+        /// Samples archives in the local store instead of pulling them through the registry
+        #[arg(long)]
+        local: bool,
+
+        #[arg(long)]
+        output: String,
+
+        #[arg(default_value = "artifact", long)]
+        sample_kind: String,
+
+        #[arg(default_value_t = DICTIONARY_SIZE_DEFAULT, long)]
+        size: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CommandStore {
+    CacheInfo {},
+
+    Dedup {},
+
+    Export {
+        #[arg(long)]
+        digest: String,
+
+        #[arg(long)]
+        name: Option<String>,
+
+        #[arg(long)]
+        output: String,
+    },
+
+    Import {
+        input: String,
+
+        #[arg(long = "key")]
+        keys: Vec<String>,
+    },
+
+    PruneSandboxes {
+        #[arg(default_value_t = false, long)]
+        dry_run: bool,
+
+        #[arg(default_value_t = 24, long)]
+        min_age_hours: u64,
+    },
+
+    Push {
+        #[arg(long)]
+        digest: String,
+    },
+
+    VerifyManifests {},
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -86,45 +490,145 @@ pub struct Cli {
     #[command(subcommand)]
     command: Command,
 
+    #[arg(global = true, long)]
+    agent: Option<String>,
+
+    #[arg(global = true, long)]
+    ca_bundle: Option<String>,
+
+    #[arg(default_value_t = DEFAULT_CHUNK_SIZE_MAX, global = true, long)]
+    chunk_size_max: usize,
+
+    #[arg(default_value_t = DEFAULT_CHUNK_SIZE_MIN, global = true, long)]
+    chunk_size_min: usize,
+
     #[arg(default_value = "Vorpal.toml", long, short)]
     config: String,
 
-    #[arg(default_value = "rust", long)]
-    language: String,
+    #[arg(long)]
+    context: Option<String>,
+
+    #[arg(long)]
+    language: Option<String>,
+
+    #[arg(default_value_t = false, global = true, long)]
+    insecure_skip_tls_verify: bool,
 
     #[arg(default_value_t = Level::INFO, global = true, long)]
     level: Level,
 
-    #[clap(default_value = "http://localhost:23151", long, short)]
-    registry: String,
+    #[arg(default_value_t = DEFAULT_MESSAGE_SIZE_MAX, global = true, long)]
+    max_message_size: usize,
+
+    #[arg(global = true, long)]
+    network_limit: Option<String>,
+
+    #[arg(global = true, long)]
+    push_registry: Option<String>,
 
-    #[arg(default_value = "vorpal-config", long)]
+    #[clap(
+        default_value = "http://localhost:23151",
+        global = true,
+        long = "registry",
+        short
+    )]
+    registries: Vec<String>,
+
+    #[arg(global = true, long)]
+    registry_token: Option<String>,
+
+    #[arg(long)]
     rust_bin: Option<String>,
 
-    #[arg(default_value = ".", long)]
+    #[arg(long)]
     rust_path: Option<String>,
+
+    #[arg(default_value_t = DEFAULT_SOURCE_CACHE_SIZE_MAX, global = true, long)]
+    source_cache_max_bytes: u64,
+
+    #[arg(default_value_t = false, global = true, long)]
+    store_dedup: bool,
+
+    #[arg(global = true, long)]
+    tls_ca: Option<String>,
+
+    #[arg(global = true, long)]
+    tls_client_cert: Option<String>,
+
+    #[arg(global = true, long)]
+    tls_client_key: Option<String>,
+
+    #[arg(long)]
+    toolchain: Option<String>,
 }
 
+// Agent of `vorpal agent` commands without `--agent`
+const DEFAULT_AGENT: &str = "http://localhost:23151";
+
+// Settings used when neither flags nor `Vorpal.toml` set them
+const DEFAULT_LANGUAGE: &str = "rust";
+const DEFAULT_RUST_BIN: &str = "vorpal-config";
+const DEFAULT_RUST_PATH: &str = ".";
+
+// Time for the config process to resolve its artifacts and print the ready line, which includes
+// fetching sources without a cached hash
+const CONFIG_READY_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Exit code of `vorpal artifact --plan` when artifacts must be built
+const PLAN_BUILD_EXIT_CODE: i32 = 2;
+
 fn get_default_system() -> String {
     format!("{}-{}", ARCH, OS)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn start_config(
+    context_path: &Path,
     file: String,
-    registry: String,
-) -> Result<(Child, ConfigServiceClient<Channel>)> {
-    let port = random_free_port().ok_or_else(|| anyhow!("failed to find free port"))?;
-
+    registries: &RegistryUrls,
+    source_lock_path: &Path,
+    source_mirrors: &[String],
+    source_revision: Option<String>,
+    system: ArtifactSystem,
+    update_locks: bool,
+    variables: &BTreeMap<String, String>,
+) -> Result<(Child, ConfigServiceClient<ConfigChannel>)> {
     let mut command = process::Command::new(file);
 
+    command.kill_on_drop(true);
+
     command.args([
         "start",
+        "--context",
+        &context_path.display().to_string(),
         "--port",
-        &port.to_string(),
-        "--registry",
-        &registry,
+        "0",
+        "--source-lock",
+        &source_lock_path.display().to_string(),
+        "--target",
+        get_artifact_system_name(system),
     ]);
 
+    for registry in registries.urls.iter() {
+        command.args(["--registry", registry]);
+    }
+
+    for source_mirror in source_mirrors {
+        command.args(["--source-mirror", source_mirror]);
+    }
+
+    if let Some(source_revision) = source_revision {
+        command.args(["--source-revision", &source_revision]);
+    }
+
+    if update_locks {
+        command.arg("--update-locks");
+    }
+
+    for (key, value) in variables {
+        command.args(["--variable", &format!("{}={}", key, value)]);
+    }
+
     let mut process = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -134,27 +638,65 @@ async fn start_config(
     let stdout = process.stdout.take().unwrap();
     let stderr = process.stderr.take().unwrap();
 
-    let stdout = LinesStream::new(BufReader::new(stdout).lines());
-    let stderr = LinesStream::new(BufReader::new(stderr).lines());
+    let stdout = LinesStream::new(BufReader::new(stdout).lines()).map(|line| (false, line));
+    let stderr = LinesStream::new(BufReader::new(stderr).lines()).map(|line| (true, line));
 
     let mut stdio_merged = StreamExt::merge(stdout, stderr);
 
-    let host = format!("http://localhost:{:?}", port);
+    // Wait for the ready line, keeping stderr to explain early exits
 
-    while let Some(line) = stdio_merged.next().await {
-        let line = line.map_err(|err| anyhow!("failed to read line: {:?}", err))?;
+    let mut stderr_lines = vec![];
+
+    let ready = timeout(CONFIG_READY_TIMEOUT, async {
+        while let Some((is_stderr, line)) = stdio_merged.next().await {
+            let line = line.map_err(|err| anyhow!("failed to read line: {:?}", err))?;
+
+            if !is_stderr {
+                if let Ok(ready) = serde_json::from_str::<ConfigReady>(&line) {
+                    return Ok(Some(ready));
+                }
+            }
 
-        if !line.contains("Config listening") {
             info!("{}", line);
+
+            if is_stderr {
+                stderr_lines.push(line);
+            }
+        }
+
+        Ok::<_, anyhow::Error>(None)
+    })
+    .await;
+
+    let ready = match ready {
+        Ok(Ok(Some(ready))) => ready,
+        Ok(Ok(None)) => {
+            let status = process
+                .wait()
+                .await
+                .map_err(|err| anyhow!("failed to wait for config server: {}", err))?;
+
+            bail!(
+                "config server exited before ready ({}):\n{}",
+                status,
+                stderr_lines.join("\n")
+            );
         }
+        Ok(Err(err)) => return Err(err),
+        Err(_) => {
+            let _ = process.kill().await;
 
-        if line.contains("Config listening") {
-            break;
+            bail!(
+                "config server not ready after {}s",
+                CONFIG_READY_TIMEOUT.as_secs()
+            );
         }
-    }
+    };
+
+    let host = format!("http://127.0.0.1:{}", ready.port);
 
-    let service = match ConfigServiceClient::connect(host).await {
-        Ok(srv) => srv,
+    let channel = match get_channel(&host).await {
+        Ok(channel) => channel,
         Err(e) => {
             let _ = process
                 .kill()
@@ -165,35 +707,146 @@ async fn start_config(
         }
     };
 
-    Ok((process, service))
-}
+    let interceptor = ConfigTokenInterceptor::new(&ready.token)?;
 
-async fn get_config_file_path(
-    artifact_system: ArtifactSystem,
-    language: String,
-    registry: String,
-    rust_bin: Option<String>,
-    rust_path: Option<String>,
-    service: String,
-) -> Result<PathBuf> {
-    match language.as_str() {
-        "rust" => {
-            if rust_bin.is_none() {
-                bail!("no `--rust-bin` specified");
-            }
+    let mut service = ConfigServiceClient::new(InterceptedService::new(channel, interceptor))
+        .accept_compressed(CompressionEncoding::Gzip)
+        .max_decoding_message_size(get_max_message_size());
 
-            if rust_path.is_none() {
-                bail!("no `--rust-path` specified");
-            }
+    // Configs built against another SDK fail to decode requests, so compare versions first
+
+    if let Err(err) = check_config_protocol_version(&mut service).await {
+        let _ = process.kill().await;
+
+        return Err(err);
+    }
+
+    Ok((process, service))
+}
+
+/// Runs the config at `config_file` and returns its config with every artifact it declares.
+#[allow(clippy::too_many_arguments)]
+async fn evaluate_config(
+    config_file: &Path,
+    context_path: &Path,
+    registries: &RegistryUrls,
+    source_lock_path: &Path,
+    source_mirrors: &[String],
+    source_revision: Option<String>,
+    system: ArtifactSystem,
+    update_locks: bool,
+    variables: &BTreeMap<String, String>,
+) -> Result<(Config, HashMap<ArtifactId, Artifact>)> {
+    let (mut config_process, mut config_service) = start_config(
+        context_path,
+        config_file.display().to_string(),
+        registries,
+        source_lock_path,
+        source_mirrors,
+        source_revision,
+        system,
+        update_locks,
+        variables,
+    )
+    .await?;
+
+    let config_response = match config_service.get_config(ConfigRequest {}).await {
+        Ok(res) => res,
+        Err(error) => {
+            bail!("failed to evaluate config: {}", error);
+        }
+    };
+
+    let config_response = config_response.into_inner();
+
+    let mut config_artifacts = HashMap::<ArtifactId, Artifact>::new();
+
+    for artifact_id in &config_response.artifacts {
+        if config_artifacts.contains_key(artifact_id) {
+            continue;
+        }
+
+        let artifact_request = tonic::Request::new(artifact_id.clone());
+
+        let artifact = match config_service.get_artifact(artifact_request).await {
+            Ok(res) => res.into_inner(),
+            Err(error) if error.code() == Code::OutOfRange => bail!(
+                "artifact `{}` is over the message size limit of {} bytes (--max-message-size): {}",
+                artifact_id.name,
+                get_max_message_size(),
+                error.message()
+            ),
+            Err(error) => {
+                bail!("failed to evaluate artifact: {}", error);
+            }
+        };
+
+        config_artifacts.insert(artifact_id.clone(), artifact.clone());
+
+        build::get_artifacts(
+            artifact_id,
+            &artifact,
+            &mut config_artifacts,
+            &mut config_service,
+        )
+        .await?;
+    }
+
+    config_process
+        .kill()
+        .await
+        .map_err(|_| anyhow!("failed to kill config server"))?;
+
+    Ok((config_response, config_artifacts))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_config_file_path(
+    artifact_system: ArtifactSystem,
+    chunk_bounds: ChunkBounds,
+    context_path: &Path,
+    env: &BTreeMap<String, String>,
+    language: String,
+    registries: &RegistryUrls,
+    rust_bin: Option<String>,
+    rust_path: Option<String>,
+    source_mirrors: &[String],
+    toolchain: Option<String>,
+    workers: &ArtifactWorkers,
+) -> Result<PathBuf> {
+    match language.as_str() {
+        "rust" => {
+            if rust_bin.is_none() {
+                bail!("no `--rust-bin` specified");
+            }
+
+            if rust_path.is_none() {
+                bail!("no `--rust-path` specified");
+            }
 
             // Setup context
 
-            let mut build_context = ConfigContext::new(0, registry.clone(), artifact_system);
+            let source_mirrors = SourceMirrors::load(source_mirrors)?;
+
+            let mut build_context = ConfigContext::new(
+                context_path.to_path_buf(),
+                0,
+                registries.urls.clone(),
+                None,
+                source_mirrors,
+                None,
+                artifact_system,
+                BTreeMap::new(),
+            );
 
             // Setup toolchain artifacts
 
+            let toolchain_version = toolchain.unwrap_or_else(get_rust_toolchain_version);
+
             let protoc = protoc::artifact(&mut build_context).await?;
-            let toolchain = rust::toolchain_artifact(&mut build_context, "vorpal").await?;
+            let toolchain =
+                rust::toolchain_artifact(&mut build_context, "vorpal", None, &toolchain_version)
+                    .await?;
 
             // Setup build
 
@@ -211,7 +864,19 @@ async fn get_config_file_path(
                             }
                         }
 
-                        build(artifact, artifact_id, artifact_system, &registry, &service).await?;
+                        build(
+                            artifact,
+                            artifact_id,
+                            artifact_system,
+                            chunk_bounds,
+                            &ArtifactForce::default(),
+                            None,
+                            registries,
+                            &[],
+                            &[],
+                            workers,
+                        )
+                        .await?;
 
                         ready_artifacts.push(artifact_id);
                     }
@@ -222,7 +887,7 @@ async fn get_config_file_path(
 
             let protoc_path = Path::new(&format!(
                 "{}/bin/protoc",
-                get_artifact_path(&protoc.hash, &protoc.name).display()
+                get_artifact_path(&protoc.hash.parse()?, &protoc.name).display()
             ))
             .to_path_buf();
 
@@ -232,14 +897,13 @@ async fn get_config_file_path(
 
             // Get toolchain
 
-            let toolchain_path = get_artifact_path(&toolchain.hash, &toolchain.name);
+            let toolchain_path = get_artifact_path(&toolchain.hash.parse()?, &toolchain.name);
 
             if !toolchain_path.exists() {
                 bail!("config toolchain not found: {}", toolchain_path.display());
             }
 
             let toolchain_target = rust::get_toolchain_target(artifact_system)?;
-            let toolchain_version = get_rust_toolchain_version();
 
             let toolchain_bin_path = Path::new(&format!(
                 "{}/toolchains/{}-{}/bin",
@@ -267,7 +931,7 @@ async fn get_config_file_path(
                 format!(
                     "{}:{}/bin:{}",
                     toolchain_bin_path.display(),
-                    get_artifact_path(&protoc.hash, &protoc.name).display(),
+                    get_artifact_path(&protoc.hash.parse()?, &protoc.name).display(),
                     var("PATH").unwrap_or_default()
                 )
                 .as_str(),
@@ -280,6 +944,10 @@ async fn get_config_file_path(
                 format!("{}-{}", toolchain_version, toolchain_target),
             );
 
+            // Variables of the `[env]` table, such as `RUSTFLAGS`
+
+            command.envs(env);
+
             // Setup command
 
             let config_bin = rust_bin.as_ref().unwrap();
@@ -319,27 +987,936 @@ async fn get_config_file_path(
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Returns the `--variable NAME=VALUE` flags by name, later flags override earlier ones.
+///
+/// Names of the form `NAMESPACE.NAME` are scoped to a namespace, and can only be set once.
+fn get_variables(variables: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut parsed = BTreeMap::new();
+
+    for variable in variables {
+        let Some((key, value)) = variable.split_once('=') else {
+            bail!("`--variable` must be NAME=VALUE: {}", variable);
+        };
+
+        if key.is_empty() {
+            bail!("variable name is missing: {}", variable);
+        }
+
+        if let Some((namespace, name)) = key.split_once('.') {
+            if namespace.is_empty() || name.is_empty() {
+                bail!("`--variable` must be NAMESPACE.NAME=VALUE: {}", variable);
+            }
+
+            if parsed.contains_key(key) {
+                bail!("variable set more than once: {}", key);
+            }
+        }
+
+        parsed.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(parsed)
+}
+
+/// Returns `settings` with the flags of the artifact applied, flags take precedence over the
+/// `Vorpal.toml` settings.
+#[allow(clippy::too_many_arguments)]
+fn get_artifact_settings(
+    settings: ConfigSettings,
+    language: &Option<String>,
+    rust_bin: &Option<String>,
+    rust_path: &Option<String>,
+    source_mirrors: &[String],
+    source_revision: &Option<String>,
+    toolchain: &Option<String>,
+    variables: &[String],
+) -> Result<ConfigSettings> {
+    let mut settings_source_mirrors = source_mirrors.to_vec();
+
+    settings_source_mirrors.extend(settings.source_mirrors);
+
+    let mut settings_variables = settings.variables;
+
+    settings_variables.extend(get_variables(variables)?);
+
+    Ok(ConfigSettings {
+        env: settings.env,
+        language: language
+            .clone()
+            .or(settings.language)
+            .or(Some(DEFAULT_LANGUAGE.to_string())),
+        rust_bin: rust_bin
+            .clone()
+            .or(settings.rust_bin)
+            .or(Some(DEFAULT_RUST_BIN.to_string())),
+        rust_path: rust_path
+            .clone()
+            .or(settings.rust_path)
+            .or(Some(DEFAULT_RUST_PATH.to_string())),
+        source_mirrors: settings_source_mirrors,
+        source_revision: source_revision.clone().or(settings.source_revision),
+        toolchain: toolchain.clone().or(settings.toolchain),
+        variables: settings_variables,
+    })
+}
+
+fn get_context_path(config_path: &Path) -> Result<PathBuf> {
+    let context_path = match config_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    context_path
+        .canonicalize()
+        .map_err(|e| anyhow!("failed to resolve {}: {}", context_path.display(), e))
+}
+
+async fn get_service_status(address: &str, service_name: &str) -> String {
+    let Ok(channel) = get_channel(address).await else {
+        return "UNREACHABLE".to_string();
+    };
+
+    let request = HealthCheckRequest {
+        service: service_name.to_string(),
+    };
+
+    match HealthClient::new(channel).check(request).await {
+        Ok(response) => response.into_inner().status().as_str_name().to_string(),
+        Err(status) if status.code() == tonic::Code::NotFound => "NOT_STARTED".to_string(),
+        Err(_) => "UNREACHABLE".to_string(),
+    }
+}
+
+/// Resolves on ctrl-c or `SIGTERM`, when the services stop accepting requests.
+async fn get_shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+
+    tokio::select! {
+        _ = ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Evaluates the config for `system`, or reuses a cached evaluation, and records the source
+/// locks it resolved.
+#[allow(clippy::too_many_arguments)]
+async fn get_config_evaluation(
+    config_file: &Path,
+    context_path: &Path,
+    env: &BTreeMap<String, String>,
+    no_config_cache: bool,
+    registries: &RegistryUrls,
+    source_lock_path: &Path,
+    source_mirrors: &[String],
+    source_revision: Option<String>,
+    system: ArtifactSystem,
+    update_locks: bool,
+    variables: &BTreeMap<String, String>,
+) -> Result<(Config, HashMap<ArtifactId, Artifact>)> {
+    // Reuse a previous evaluation of the config, except for revisions which can move
+
+    let config_cacheable = source_revision.is_none();
+
+    let config_cache = match config_cacheable && !no_config_cache && !update_locks {
+        true => ConfigCache::load(&ConfigCache::get_key(
+            config_file,
+            context_path,
+            env,
+            &registries.urls,
+            source_lock_path,
+            source_mirrors,
+            system,
+            variables,
+        )?)?,
+        false => None,
+    };
+
+    let config_evaluated = config_cache.is_none();
+
+    let (config_response, config_artifacts) = match config_cache {
+        Some(config_cache) => {
+            info!(
+                "using cached config evaluation: {}",
+                get_artifact_system_name(system)
+            );
+
+            config_cache.into_parts()
+        }
+
+        None => {
+            evaluate_config(
+                config_file,
+                context_path,
+                registries,
+                source_lock_path,
+                source_mirrors,
+                source_revision,
+                system,
+                update_locks,
+                variables,
+            )
+            .await?
+        }
+    };
+
+    // Record digests resolved for remote sources without a hash
+
+    let mut source_lock = SourceLock::load(source_lock_path)?;
+
+    if source_lock.update(config_response.locks.clone()) {
+        source_lock.save(source_lock_path)?;
+
+        info!("updated source locks: {}", source_lock_path.display());
+    }
+
+    // Keyed after the lock updates, which the next run reads
+
+    if config_cacheable && config_evaluated {
+        let key = ConfigCache::get_key(
+            config_file,
+            context_path,
+            env,
+            &registries.urls,
+            source_lock_path,
+            source_mirrors,
+            system,
+            variables,
+        )?;
+
+        ConfigCache::new(config_response.clone(), &config_artifacts)?.save(&key)?;
+    }
+
+    Ok((config_response, config_artifacts))
+}
+
+/// Build settings resolved from the flags and the `Vorpal.toml` settings of the artifact, shared
+/// by the commands that evaluate the config.
+struct ArtifactBuildOptions {
+    chunk_bounds: ChunkBounds,
+    context_path: PathBuf,
+    env: BTreeMap<String, String>,
+    force: ArtifactForce,
+    language: String,
+    no_config_cache: bool,
+    no_summary: bool,
+    push_policy: Option<PushPolicy>,
+    registries: RegistryUrls,
+    rust_bin: Option<String>,
+    rust_path: Option<String>,
+    secrets: Vec<ArtifactStepEnvironment>,
+    source_lock_path: PathBuf,
+    source_mirrors: Vec<String>,
+    source_revision: Option<String>,
+    toolchain: Option<String>,
+    update_locks: bool,
+    variables: BTreeMap<String, String>,
+    workers: ArtifactWorkers,
+}
+
+impl ArtifactBuildOptions {
+    /// Prepares the config program, which runs on this host whatever system it evaluates for.
+    async fn get_config_file(&self) -> Result<PathBuf> {
+        let config_file = get_config_file_path(
+            get_artifact_system(&get_default_system()),
+            self.chunk_bounds,
+            &self.context_path,
+            &self.env,
+            self.language.clone(),
+            &self.registries,
+            self.rust_bin.clone(),
+            self.rust_path.clone(),
+            &self.source_mirrors,
+            self.toolchain.clone(),
+            &self.workers,
+        )
+        .await?;
+
+        if !config_file.exists() {
+            bail!("config file not found: {}", config_file.display());
+        }
+
+        Ok(config_file)
+    }
+
+    async fn get_config_evaluation(
+        &self,
+        config_file: &Path,
+        system: ArtifactSystem,
+    ) -> Result<(Config, HashMap<ArtifactId, Artifact>)> {
+        get_config_evaluation(
+            config_file,
+            &self.context_path,
+            &self.env,
+            self.no_config_cache,
+            &self.registries,
+            &self.source_lock_path,
+            &self.source_mirrors,
+            self.source_revision.clone(),
+            system,
+            self.update_locks,
+            &self.variables,
+        )
+        .await
+    }
+}
+
+/// What an artifact command does with the evaluated config.
+enum ArtifactAction<'a> {
+    Build {
+        export: bool,
+        plan: bool,
+        watch: bool,
+    },
+    Ci {
+        fail_fast: bool,
+        json: bool,
+        names: &'a [String],
+    },
+    Graph {
+        depth: Option<usize>,
+        format: &'a str,
+    },
+    Run(ArtifactRun),
+    Sources {
+        diff: Option<&'a str>,
+    },
+}
+
+async fn run_artifact(
+    options: &ArtifactBuildOptions,
+    name: &str,
+    export_artifact: bool,
+    plan: bool,
+    run: Option<&ArtifactRun>,
+    systems: &[ArtifactSystem],
+) -> Result<Vec<ConfigArtifactSource>> {
+    let config_file = options.get_config_file().await?;
+
+    // Output of more than one system is grouped by system
+
+    let systems_grouped = systems.len() > 1;
+
+    let mut exports = BTreeMap::new();
+    let mut sources = vec![];
+
+    for system in systems.iter().copied() {
+        let system_name = get_artifact_system_name(system);
+
+        let (config_response, config_artifacts) =
+            options.get_config_evaluation(&config_file, system).await?;
+
+        let artifact_id_selected = config_response
+            .clone()
+            .artifacts
+            .into_iter()
+            .find(|a| a.name == name)
+            .ok_or_else(|| anyhow!("artifact not found: {}", name))?;
+
+        // Get the artifact and its dependencies
+
+        let artifact = build::get_artifacts_evaluated(&artifact_id_selected, &config_artifacts)?;
+
+        // Fail before building dependencies when the selected artifact can not target the
+        // system, other requested systems still build
+
+        if let Some(artifact_selected) = artifact.get(&artifact_id_selected) {
+            if let Err(err) = validate_artifact_system(artifact_selected, system) {
+                if !systems_grouped {
+                    bail!(err);
+                }
+
+                warn!("skipping {}", err);
+
+                continue;
+            }
+        }
+
+        if export_artifact {
+            let mut artifacts = artifact.values().cloned().collect::<Vec<_>>();
+
+            artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+
+            exports.insert(system_name, artifacts);
+
+            continue;
+        }
+
+        if plan {
+            let builds = print_plan(
+                &artifact_id_selected,
+                &artifact,
+                system,
+                &options.registries,
+                &options.workers,
+            )
+            .await?;
+
+            if builds > 0 {
+                std::process::exit(PLAN_BUILD_EXIT_CODE);
+            }
+
+            return Ok(vec![]);
+        }
+
+        // Create the artifact graph and map
+
+        let build_order = build::get_order(&artifact).await?;
+
+        let mut ready_artifacts = vec![];
+        let mut skipped_artifacts = vec![];
+        let mut summaries = vec![];
+
+        for artifact_id in &build_order {
+            match artifact.get(artifact_id) {
+                None => bail!("Build artifact not found: {}", artifact_id.name),
+                Some(artifact) => {
+                    for dependency in &artifact.artifacts {
+                        if skipped_artifacts.contains(&dependency) {
+                            bail!(
+                                "artifact `{}` depends on `{}`, which does not support `{}`",
+                                artifact_id.name,
+                                dependency.name,
+                                system.as_str_name()
+                            );
+                        }
+
+                        if !ready_artifacts.contains(&dependency) {
+                            bail!("Artifact not found: {}", dependency.name);
+                        }
+                    }
+
+                    // Dependencies declared for other systems are skipped, only artifacts using
+                    // them fail
+
+                    if let Err(err) = validate_artifact_system(artifact, system) {
+                        warn!("skipping {}", err);
+
+                        skipped_artifacts.push(artifact_id);
+
+                        continue;
+                    }
+
+                    let summary = build(
+                        artifact,
+                        artifact_id,
+                        system,
+                        options.chunk_bounds,
+                        &options.force,
+                        options.push_policy,
+                        &options.registries,
+                        &options.secrets,
+                        &config_response.revisions,
+                        &options.workers,
+                    )
+                    .await?;
+
+                    summaries.push(summary);
+
+                    ready_artifacts.push(artifact_id);
+
+                    if artifact_id.name == name && run.is_none() {
+                        let artifact_path =
+                            get_artifact_path(&artifact_id.hash.parse()?, &artifact_id.name);
+
+                        match systems_grouped {
+                            true => println!("{} {}", system_name, artifact_path.display()),
+                            false => println!("{}", artifact_path.display()),
+                        }
+                    }
+                }
+            }
+        }
+
+        if !options.no_summary {
+            print_summary(&summaries);
+        }
+
+        if let Some(run) = run {
+            let code = run_entrypoint(&artifact_id_selected, &build_order, run).await?;
+
+            std::process::exit(code);
+        }
+
+        // Collect local sources of the built artifacts to watch
+
+        for source in config_response.sources {
+            if artifact.keys().any(|a| a.name == source.artifact) && !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    }
+
+    if export_artifact {
+        let export_json = match systems_grouped {
+            true => serde_json::to_string_pretty(&exports)?,
+            false => {
+                serde_json::to_string_pretty(&exports.into_values().flatten().collect::<Vec<_>>())?
+            }
+        };
+
+        println!("{}", export_json);
+
+        return Ok(vec![]);
+    }
+
+    Ok(sources)
+}
+
+/// Prints the dependency graph of artifact `name` with the plan status of each artifact, in
+/// `format` (`dot` or `json`), truncated `depth` edges away from it when set.
+async fn run_graph(
+    options: &ArtifactBuildOptions,
+    name: &str,
+    format: &str,
+    depth: Option<usize>,
+    system: ArtifactSystem,
+) -> Result<()> {
+    if !["dot", "json"].contains(&format) {
+        bail!(
+            "unknown graph format: {} (expected `dot` or `json`)",
+            format
+        );
+    }
+
+    let config_file = options.get_config_file().await?;
+
+    let (config_response, config_artifacts) =
+        options.get_config_evaluation(&config_file, system).await?;
+
+    let artifact_id = config_response
+        .artifacts
+        .into_iter()
+        .find(|a| a.name == name)
+        .ok_or_else(|| anyhow!("artifact not found: {}", name))?;
+
+    let artifacts = build::get_artifacts_evaluated(&artifact_id, &config_artifacts)?;
+
+    let statuses =
+        get_plan_statuses(&artifacts, system, &options.registries, &options.workers).await?;
+
+    let graph = graph::get_graph(&artifact_id, &artifacts, &statuses, depth);
+
+    graph::print_graph(&graph, format)
+}
+
+/// Lists the files each source of artifact `name` hashes, to find what changes its digest.
+async fn run_sources(
+    options: &ArtifactBuildOptions,
+    name: &str,
+    diff: Option<&str>,
+    system: ArtifactSystem,
+) -> Result<()> {
+    let config_file = options.get_config_file().await?;
+
+    let (config_response, config_artifacts) =
+        options.get_config_evaluation(&config_file, system).await?;
+
+    let artifact = config_response
+        .artifacts
+        .iter()
+        .find(|a| a.name == name)
+        .and_then(|artifact_id| config_artifacts.get(artifact_id))
+        .ok_or_else(|| anyhow!("artifact not found: {}", name))?;
+
+    sources::print_sources(artifact, &config_response.sources, diff).await
+}
+
+/// Evaluates the config for `system` and returns its artifacts sorted by name, with the systems
+/// each declares.
+#[allow(clippy::too_many_arguments)]
+async fn get_artifact_choices(
+    chunk_bounds: ChunkBounds,
+    context_path: &Path,
+    no_config_cache: bool,
+    registries: &RegistryUrls,
+    settings: ConfigSettings,
+    source_lock_path: &Path,
+    system: ArtifactSystem,
+    update_locks: bool,
+    workers: &ArtifactWorkers,
+) -> Result<Vec<ArtifactChoice>> {
+    let config_file = get_config_file_path(
+        get_artifact_system(&get_default_system()),
+        chunk_bounds,
+        context_path,
+        &settings.env,
+        settings.language.unwrap_or_default(),
+        registries,
+        settings.rust_bin,
+        settings.rust_path,
+        &settings.source_mirrors,
+        settings.toolchain,
+        workers,
+    )
+    .await?;
+
+    if !config_file.exists() {
+        bail!("config file not found: {}", config_file.display());
+    }
+
+    let (config_response, config_artifacts) = get_config_evaluation(
+        &config_file,
+        context_path,
+        &settings.env,
+        no_config_cache,
+        registries,
+        source_lock_path,
+        &settings.source_mirrors,
+        settings.source_revision,
+        system,
+        update_locks,
+        &settings.variables,
+    )
+    .await?;
+
+    let mut choices = config_response
+        .artifacts
+        .iter()
+        .map(|artifact_id| ArtifactChoice {
+            name: artifact_id.name.clone(),
+            systems: config_artifacts
+                .get(artifact_id)
+                .map(|artifact| {
+                    artifact
+                        .systems
+                        .iter()
+                        .filter_map(|system| ArtifactSystem::try_from(*system).ok())
+                        .map(|system| get_artifact_system_name(system).to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+
+    choices.sort_by(|a, b| a.name.cmp(&b.name));
+    choices.dedup_by(|a, b| a.name == b.name);
+
+    Ok(choices)
+}
+
+/// Builds the artifacts of `vorpal ci` from one evaluation of the config, failing when any of
+/// them failed after all were attempted (or the first failure with `fail_fast`).
+async fn run_ci(
+    options: &ArtifactBuildOptions,
+    names: &[String],
+    fail_fast: bool,
+    json: bool,
+    system: ArtifactSystem,
+) -> Result<()> {
+    let config_file = options.get_config_file().await?;
+
+    let (config_response, config_artifacts) =
+        options.get_config_evaluation(&config_file, system).await?;
+
+    let mut targets = vec![];
+
+    for name in names {
+        let target = config_response
+            .artifacts
+            .iter()
+            .find(|a| &a.name == name)
+            .ok_or_else(|| anyhow!("artifact not found: {}", name))?;
+
+        if !targets.contains(target) {
+            targets.push(target.clone());
+        }
+    }
+
+    let (ci_summary, summaries) = ci::build_targets(
+        &config_artifacts,
+        options.chunk_bounds,
+        fail_fast,
+        &options.force,
+        options.push_policy,
+        &options.registries,
+        &options.secrets,
+        &config_response.revisions,
+        system,
+        &targets,
+        &options.workers,
+    )
+    .await?;
+
+    if !options.no_summary {
+        print_summary(&summaries);
+    }
+
+    ci::print_targets(&ci_summary);
+
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&ci_summary)?),
+        false => {
+            for path in ci_summary.targets.iter().filter_map(|t| t.path.as_ref()) {
+                println!("{}", path);
+            }
+        }
+    }
+
+    let failed = ci_summary.failed();
+
+    if failed > 0 {
+        bail!(
+            "{} of {} artifacts failed",
+            failed,
+            ci_summary.targets.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Exported before the runtime starts its threads, so HTTP clients of this process and the
+    // config process share the settings
+
+    if let Some(agent) = &cli.agent {
+        std::env::set_var(AGENT_ENV, agent);
+    }
+
+    if let Some(ca_bundle) = &cli.ca_bundle {
+        std::env::set_var(CA_BUNDLE_ENV, ca_bundle);
+    }
+
+    if cli.insecure_skip_tls_verify {
+        std::env::set_var(INSECURE_SKIP_TLS_VERIFY_ENV, "1");
+    }
+
+    std::env::set_var(MESSAGE_SIZE_MAX_ENV, cli.max_message_size.to_string());
+
+    if let Some(registry_token) = &cli.registry_token {
+        std::env::set_var(REGISTRY_TOKEN_ENV, registry_token);
+    }
+
+    std::env::set_var(
+        SOURCE_CACHE_SIZE_MAX_ENV,
+        cli.source_cache_max_bytes.to_string(),
+    );
+
+    if cli.store_dedup {
+        std::env::set_var(STORE_DEDUP_ENV, "1");
+    }
+
+    if let Some(tls_ca) = &cli.tls_ca {
+        std::env::set_var(TLS_CA_ENV, tls_ca);
+    }
+
+    if let Some(tls_client_cert) = &cli.tls_client_cert {
+        std::env::set_var(TLS_CLIENT_CERT_ENV, tls_client_cert);
+    }
+
+    if let Some(tls_client_key) = &cli.tls_client_key {
+        std::env::set_var(TLS_CLIENT_KEY_ENV, tls_client_key);
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
     let Cli {
         command,
-        config: _,
+        agent,
+        chunk_size_max,
+        chunk_size_min,
+        config,
+        context,
         language,
         level,
-        registry,
+        max_message_size,
+        network_limit,
+        push_registry,
+        registries,
         rust_bin,
         rust_path,
+        toolchain,
+        ..
     } = cli;
 
+    let chunk_bounds = ChunkBounds::new(chunk_size_min, chunk_size_max);
+
+    let registries = RegistryUrls::new(registries, push_registry)?;
+
+    if let Some(network_limit) = network_limit {
+        set_network_limit(&network_limit)?;
+    }
+
     match &command {
+        Command::Agent(CommandAgent::Stats {}) => {
+            let agent = agent.as_deref().unwrap_or(DEFAULT_AGENT);
+
+            let mut client = AgentServiceClient::new(get_channel(agent).await?);
+
+            let stats = client
+                .get_agent_stats(AgentStatsRequest {})
+                .await?
+                .into_inner();
+
+            let cache_total = stats.cache_hits + stats.cache_misses;
+            let cache_rate = match cache_total {
+                0 => 0.0,
+                _ => stats.cache_hits as f64 / cache_total as f64 * 100.0,
+            };
+
+            println!(
+                "cache: {} hits, {} misses, {} errors ({:.1}% hit rate)",
+                stats.cache_hits, stats.cache_misses, stats.cache_errors, cache_rate
+            );
+            println!(
+                "bytes: {} from cache, {} from upstream",
+                get_bytes_display(stats.bytes_cache),
+                get_bytes_display(stats.bytes_upstream)
+            );
+            println!(
+                "preparations: {} active, {} clients",
+                stats.preparations_active, stats.clients_active
+            );
+
+            Ok(())
+        }
+
         Command::Artifact {
-            export: export_artifact,
-            name,
-            service,
-            system,
+            command: Some(CommandArtifact::List { filter }),
+            ..
+        } => {
+            let mut registry =
+                RegistryServiceClient::new(registries.get_channel(&registries.push).await?);
+
+            let mut page_token = String::new();
+
+            println!("{:<64}  NAME", "DIGEST");
+
+            loop {
+                let response = registry
+                    .list(RegistryListRequest {
+                        name_prefix: filter.clone().unwrap_or_default(),
+                        page_size: 0,
+                        page_token,
+                    })
+                    .await?
+                    .into_inner();
+
+                for artifact in response.artifacts {
+                    println!("{:<64}  {}", artifact.hash, artifact.name);
+                }
+
+                if response.next_page_token.is_empty() {
+                    break;
+                }
+
+                page_token = response.next_page_token;
+            }
+
+            Ok(())
+        }
+
+        Command::Artifact {
+            command: Some(CommandArtifact::Search { term }),
+            ..
         } => {
+            let mut registry =
+                RegistryServiceClient::new(registries.get_channel(&registries.push).await?);
+
+            let response = registry
+                .search(RegistrySearchRequest { term: term.clone() })
+                .await?
+                .into_inner();
+
+            println!(
+                "{:<64}  {:<10}  {:<32}  NAME",
+                "DIGEST", "CREATED", "SYSTEMS"
+            );
+
+            for result in response.artifacts {
+                let artifact = result.artifact.clone().unwrap_or_default();
+
+                let systems = result
+                    .systems()
+                    .map(|system| system.as_str_name())
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                println!(
+                    "{:<64}  {:<10}  {:<32}  {}",
+                    artifact.hash, result.created_at, systems, artifact.name
+                );
+            }
+
+            Ok(())
+        }
+
+        Command::Artifact {
+            command: Some(CommandArtifact::Provenance { digest, keys, name }),
+            ..
+        } => {
+            let mut public_key_paths = keys.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+            if public_key_paths.is_empty() {
+                public_key_paths.push(get_public_key_path());
+            }
+
+            let provenance =
+                get_provenance(&registries, digest, name.clone(), &public_key_paths).await?;
+
+            let artifact = provenance.artifact.clone().unwrap_or_default();
+
+            println!("artifact:    {}-{}", artifact.name, artifact.hash);
+            println!("system:      {}", provenance.system().as_str_name());
+            println!("built at:    {} (unix)", provenance.built_at);
+            println!("built by:    {}@{}", provenance.user, provenance.host);
+            println!("host system: {}", provenance.host_system().as_str_name());
+            println!("version:     {}", provenance.version);
+
+            println!("sources:");
+
+            for source in provenance.sources.iter() {
+                println!("  {}  {}", source.hash, source.name);
+            }
+
+            if !provenance.source_revisions.is_empty() {
+                println!("source revisions:");
+
+                for revision in provenance.source_revisions.iter() {
+                    println!(
+                        "  {}  {} ({})",
+                        revision.commit, revision.name, revision.revision
+                    );
+                }
+            }
+
+            println!("artifacts:");
+
+            for artifact in provenance.artifacts.iter() {
+                println!("  {}  {}", artifact.hash, artifact.name);
+            }
+
+            println!("steps:");
+
+            for step in provenance.steps.iter() {
+                match step.cached {
+                    true => println!("  {}  cached", step.index),
+                    false => println!("  {}  {}ms", step.index, step.duration_ms),
+                }
+            }
+
+            Ok(())
+        }
+
+        command @ (Command::Artifact {
+            command:
+                None
+                | Some(
+                    CommandArtifact::Graph { .. }
+                    | CommandArtifact::Run { .. }
+                    | CommandArtifact::Sources { .. },
+                ),
+            ..
+        }
+        | Command::Ci { .. }
+        | Command::Shell { .. }) => {
             let stderr_writer = std::io::stderr.with_max_level(level);
 
             let mut subscriber = FmtSubscriber::builder()
@@ -357,121 +1934,441 @@ async fn main() -> Result<()> {
             tracing::subscriber::set_global_default(subscriber)
                 .expect("setting default subscriber");
 
+            let (action, name, options) = match command {
+                Command::Artifact {
+                    command:
+                        Some(CommandArtifact::Graph {
+                            depth,
+                            format,
+                            name,
+                            options,
+                        }),
+                    ..
+                } => {
+                    let action = ArtifactAction::Graph {
+                        depth: *depth,
+                        format: format.as_str(),
+                    };
+
+                    (action, Some(name.as_str()), options)
+                }
+
+                Command::Artifact {
+                    command:
+                        Some(CommandArtifact::Run {
+                            arguments,
+                            entrypoint,
+                            name,
+                            options,
+                        }),
+                    ..
+                } => {
+                    let run = ArtifactRun {
+                        arguments: arguments.clone(),
+                        entrypoint: entrypoint.clone(),
+                        environments: BTreeMap::new(),
+                    };
+
+                    (ArtifactAction::Run(run), Some(name.as_str()), options)
+                }
+
+                Command::Artifact {
+                    command:
+                        Some(CommandArtifact::Sources {
+                            diff,
+                            name,
+                            options,
+                        }),
+                    ..
+                } => {
+                    let action = ArtifactAction::Sources {
+                        diff: diff.as_deref(),
+                    };
+
+                    (action, Some(name.as_str()), options)
+                }
+
+                Command::Artifact {
+                    export,
+                    name,
+                    options,
+                    plan,
+                    watch,
+                    ..
+                } => {
+                    let action = ArtifactAction::Build {
+                        export: *export,
+                        plan: *plan,
+                        watch: *watch,
+                    };
+
+                    (action, name.as_deref(), options)
+                }
+
+                Command::Ci {
+                    fail_fast,
+                    json,
+                    names,
+                    options,
+                } => {
+                    let action = ArtifactAction::Ci {
+                        fail_fast: *fail_fast,
+                        json: *json,
+                        names: names.as_slice(),
+                    };
+
+                    (action, names.first().map(|name| name.as_str()), options)
+                }
+
+                Command::Shell {
+                    command,
+                    name,
+                    options,
+                } => {
+                    let run = get_shell_run(name, command.as_deref());
+
+                    (ArtifactAction::Run(run), Some(name.as_str()), options)
+                }
+
+                _ => unreachable!(),
+            };
+
+            let ArtifactOptions {
+                check_reproducibility,
+                force_builds,
+                no_cache,
+                no_config_cache,
+                no_summary,
+                push_policy,
+                secrets,
+                service,
+                source_mirrors,
+                source_revision,
+                systems,
+                update_locks,
+                variables,
+            } = options;
+
             if service.is_empty() {
                 bail!("no `--artifact-service` specified");
             }
 
-            let system: ArtifactSystem = get_artifact_system(system);
+            if let ArtifactAction::Build {
+                export,
+                plan,
+                watch,
+            } = action
+            {
+                if export && watch {
+                    bail!("`--export` cannot be used with `--watch`");
+                }
 
-            if system == UnknownSystem {
-                bail!("unknown target: {}", system.as_str_name());
+                if plan && (export || watch) {
+                    bail!("`--plan` cannot be used with `--export` or `--watch`");
+                }
             }
 
-            let config_file = get_config_file_path(
-                system,
-                language,
-                registry.clone(),
-                rust_bin.clone(),
-                rust_path.clone(),
-                service.clone(),
-            )
-            .await?;
-
-            if !config_file.exists() {
-                bail!("config file not found: {}", config_file.display());
+            if *check_reproducibility && force_builds.is_empty() && !no_cache {
+                bail!("`--check-reproducibility` requires `--force-build` or `--no-cache`");
             }
 
-            let (mut config_process, mut config_service) =
-                start_config(config_file.display().to_string(), registry.clone()).await?;
-
-            let config_response = match config_service.get_config(ConfigRequest {}).await {
-                Ok(res) => res,
-                Err(error) => {
-                    bail!("failed to evaluate config: {}", error);
-                }
+            let force = ArtifactForce {
+                all: *no_cache,
+                artifacts: force_builds.clone(),
+                check_reproducibility: *check_reproducibility,
             };
 
-            let config_response = config_response.into_inner();
+            let mut artifact_systems: Vec<ArtifactSystem> = vec![];
 
-            let artifact_id_selected = config_response
-                .clone()
-                .artifacts
-                .into_iter()
-                .find(|a| a.name == *name)
-                .ok_or_else(|| anyhow!("artifact not found: {}", name))?;
+            for system in systems {
+                let artifact_system = get_artifact_system(system);
 
-            // Get the artifact
+                if artifact_system == UnknownSystem {
+                    bail!("unknown target: {}", system);
+                }
 
-            let artifact_request = tonic::Request::new(artifact_id_selected.clone());
+                if !artifact_systems.contains(&artifact_system) {
+                    artifact_systems.push(artifact_system);
+                }
+            }
 
-            let artifact_response = match config_service.get_artifact(artifact_request).await {
-                Ok(res) => res,
-                Err(error) => {
-                    bail!("failed to evaluate artifact: {}", error);
+            if artifact_systems.len() > 1 {
+                match action {
+                    ArtifactAction::Build { plan: true, .. } => {
+                        bail!("`--plan` cannot be used with more than one `--system`")
+                    }
+                    ArtifactAction::Build { .. } => {}
+                    ArtifactAction::Ci { .. } => {
+                        bail!("`vorpal ci` builds for a single `--system`")
+                    }
+                    ArtifactAction::Graph { .. } => {
+                        bail!("`vorpal artifact graph` builds for a single `--system`")
+                    }
+                    ArtifactAction::Run(_) => {
+                        bail!("`--system` can only be given once to run an artifact")
+                    }
+                    ArtifactAction::Sources { .. } => {
+                        bail!("`vorpal artifact sources` evaluates for a single `--system`")
+                    }
                 }
-            };
+            }
 
-            let artifact_selected = artifact_response.into_inner();
+            // Builds sign their sources with the keys, so fail before any source work starts
 
-            let mut artifact = HashMap::<ArtifactId, Artifact>::new();
+            if !matches!(
+                action,
+                ArtifactAction::Build { plan: true, .. }
+                    | ArtifactAction::Graph { .. }
+                    | ArtifactAction::Sources { .. }
+            ) {
+                check_keys().await?;
+            }
+
+            let secrets = encrypt_secrets(&get_public_key_path(), get_secrets(secrets)?).await?;
 
-            artifact.insert(artifact_id_selected.clone(), artifact_selected.clone());
+            // `--config` resolves within `--context`, cloned first when it is a git repository
 
-            build::get_artifacts(&artifact_selected, &mut artifact, &mut config_service).await?;
+            let config = match &context {
+                Some(context) => get_context_config_path(context, &config).await?,
+                None => PathBuf::from(&config),
+            };
 
-            if *export_artifact {
-                let mut artifacts = vec![];
+            let config_file = ConfigFile::load(&config)?;
+
+            let source_lock_path = config.with_file_name(SOURCE_LOCK_FILE);
+
+            // Local sources of the config resolve from the directory of its `Vorpal.toml`
+
+            let context_path = get_context_path(&config)?;
+
+            let workers = get_workers(service).await?;
+
+            // Without `--name`, the artifacts of the config evaluated with its top level settings
+            // are offered, the chosen one then builds as if it was named
+
+            let name = match name {
+                Some(name) => name.to_string(),
+                None => {
+                    let settings = get_artifact_settings(
+                        config_file.get_settings(None),
+                        &language,
+                        &rust_bin,
+                        &rust_path,
+                        source_mirrors,
+                        source_revision,
+                        &toolchain,
+                        variables,
+                    )?;
+
+                    let choices = get_artifact_choices(
+                        chunk_bounds,
+                        &context_path,
+                        *no_config_cache,
+                        &registries,
+                        settings,
+                        &source_lock_path,
+                        artifact_systems[0],
+                        *update_locks,
+                        &workers,
+                    )
+                    .await?;
 
-                for a in artifact.values() {
-                    artifacts.push(a.clone());
+                    select::select_artifact(&choices)?
                 }
+            };
 
-                artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+            let name = name.as_str();
 
-                let export_json = serde_json::to_string_pretty(&artifacts).unwrap();
+            let settings = config_file.get_settings(Some(name));
 
-                println!("{}", export_json);
+            // One evaluation serves every artifact of `vorpal ci`, so they must share settings
 
-                return Ok(());
+            if let ArtifactAction::Ci { names, .. } = action {
+                for ci_name in names {
+                    if config_file.get_settings(Some(ci_name)) != settings {
+                        bail!(
+                            "artifacts `{}` and `{}` use different settings in {}, build them separately",
+                            name,
+                            ci_name,
+                            config.display()
+                        );
+                    }
+                }
             }
 
-            // Create the artifact graph and map
+            // Flags take precedence over the `Vorpal.toml` settings of the artifact
 
-            let build_order = build::get_order(&artifact).await?;
+            let ConfigSettings {
+                env,
+                language,
+                rust_bin,
+                rust_path,
+                source_mirrors,
+                source_revision,
+                toolchain,
+                variables: config_variables,
+            } = get_artifact_settings(
+                settings,
+                &language,
+                &rust_bin,
+                &rust_path,
+                source_mirrors,
+                source_revision,
+                &toolchain,
+                variables,
+            )?;
+
+            let options = ArtifactBuildOptions {
+                chunk_bounds,
+                context_path,
+                env,
+                force,
+                language: language.unwrap_or_default(),
+                no_config_cache: *no_config_cache,
+                no_summary: *no_summary,
+                push_policy: *push_policy,
+                registries,
+                rust_bin,
+                rust_path,
+                secrets,
+                source_lock_path,
+                source_mirrors,
+                source_revision,
+                toolchain,
+                update_locks: *update_locks,
+                variables: config_variables,
+                workers,
+            };
 
-            let mut ready_artifacts = vec![];
+            let system = artifact_systems[0];
 
-            for artifact_id in &build_order {
-                match artifact.get(artifact_id) {
-                    None => bail!("Build artifact not found: {}", artifact_id.name),
-                    Some(artifact) => {
-                        for artifact in &artifact.artifacts {
-                            if !ready_artifacts.contains(&artifact) {
-                                bail!("Artifact not found: {}", artifact.name);
-                            }
-                        }
+            let (export, plan, watch, run) = match action {
+                ArtifactAction::Build {
+                    export,
+                    plan,
+                    watch,
+                } => (export, plan, watch, None),
 
-                        build(artifact, artifact_id, system, &registry, service).await?;
+                ArtifactAction::Ci {
+                    fail_fast,
+                    json,
+                    names,
+                } => return run_ci(&options, names, fail_fast, json, system).await,
 
-                        ready_artifacts.push(artifact_id);
+                ArtifactAction::Graph { depth, format } => {
+                    return run_graph(&options, name, format, depth, system).await
+                }
 
-                        if artifact_id.name == *name {
-                            println!(
-                                "{}",
-                                get_artifact_path(&artifact_id.hash, &artifact_id.name).display()
-                            );
-                        }
-                    }
+                ArtifactAction::Run(run) => (false, false, false, Some(run)),
+
+                ArtifactAction::Sources { diff } => {
+                    return run_sources(&options, name, diff, system).await
                 }
+            };
+
+            let mut sources = run_artifact(
+                &options,
+                name,
+                export,
+                plan,
+                run.as_ref(),
+                &artifact_systems,
+            )
+            .await?;
+
+            if !watch {
+                return Ok(());
             }
 
-            config_process
-                .kill()
+            loop {
+                info!("watching {} sources for changes", sources.len());
+
+                let changes = watch::wait_for_changes(&sources).await?;
+
+                for change in &changes {
+                    info!("changed: {}", change.display());
+                }
+
+                match run_artifact(
+                    &options,
+                    name,
+                    export,
+                    plan,
+                    run.as_ref(),
+                    &artifact_systems,
+                )
                 .await
-                .map_err(|_| anyhow!("failed to kill config server"))
+                {
+                    Ok(watch_sources) => sources = watch_sources,
+                    Err(error) => error!("failed to build artifact: {}", error),
+                }
+            }
+        }
+
+        Command::Doctor { service } => {
+            let code = doctor::run_doctor(&registries, service).await;
+
+            if code != 0 {
+                std::process::exit(code);
+            }
+
+            Ok(())
         }
 
+        Command::Init {
+            force,
+            language: init_language,
+            name,
+            no_program,
+        } => init::init(
+            Path::new(&config),
+            *force,
+            init_language.clone().or(language),
+            name.clone(),
+            *no_program,
+            &rust_bin.unwrap_or(DEFAULT_RUST_BIN.to_string()),
+        ),
+
         Command::Keys(keys) => match keys {
+            CommandKeys::Fingerprint { public } => {
+                let public_key_path = match public {
+                    Some(path) => PathBuf::from(path),
+                    None => vorpal_store::paths::get_public_key_path(),
+                };
+
+                if !public_key_path.exists() {
+                    bail!("public key not found: {}", public_key_path.display());
+                }
+
+                let public_key_fingerprint =
+                    vorpal_notary::get_public_key_fingerprint(public_key_path.clone()).await?;
+
+                println!("{}  {}", public_key_fingerprint, public_key_path.display());
+
+                // Signatures verify with the public half of the private key, which may differ
+                // from the public key copied next to it
+
+                let private_key_path = vorpal_store::paths::get_private_key_path();
+
+                if public.is_none() && private_key_path.exists() {
+                    let private_key_fingerprint =
+                        vorpal_notary::get_private_key_fingerprint(private_key_path.clone())
+                            .await?;
+
+                    println!(
+                        "{}  {}",
+                        private_key_fingerprint,
+                        private_key_path.display()
+                    );
+
+                    if private_key_fingerprint != public_key_fingerprint {
+                        warn!("private key does not match public key");
+                    }
+                }
+
+                Ok(())
+            }
+
             CommandKeys::Generate {} => {
                 let key_dir_path = vorpal_store::paths::get_key_dir_path();
                 let private_key_path = vorpal_store::paths::get_private_key_path();
@@ -498,11 +2395,134 @@ async fn main() -> Result<()> {
             }
         },
 
+        Command::Process(command_process) => match command_process {
+            CommandProcess::Logs { follow, name } => processes::logs(name, *follow).await,
+
+            CommandProcess::Status { name } => processes::status(name),
+        },
+
+        Command::Registry(command_registry) => match command_registry {
+            CommandRegistry::Prune {
+                dry_run,
+                min_age_days,
+            } => {
+                let mut registry =
+                    RegistryServiceClient::new(registries.get_channel(&registries.push).await?);
+
+                let response = registry
+                    .prune(RegistryPruneRequest {
+                        dry_run: *dry_run,
+                        min_age_seconds: min_age_days * 24 * 60 * 60,
+                    })
+                    .await?
+                    .into_inner();
+
+                println!("{:<18}  {:>12}  {:>6}  OBJECT", "KIND", "BYTES", "DAYS");
+
+                for object in response.objects.iter() {
+                    println!(
+                        "{:<18}  {:>12}  {:>6}  {}-{}",
+                        object.kind().as_str_name(),
+                        object.size,
+                        object.age_seconds / (24 * 60 * 60),
+                        object.name,
+                        object.hash
+                    );
+                }
+
+                let action = if *dry_run { "reclaimable" } else { "reclaimed" };
+
+                println!(
+                    "{} objects, {} bytes {}, {} retained",
+                    response.objects.len(),
+                    response.reclaimable_bytes,
+                    action,
+                    response.retained
+                );
+
+                Ok(())
+            }
+
+            CommandRegistry::TrainDictionary {
+                local,
+                output,
+                sample_kind,
+                size,
+            } => {
+                let subscriber = FmtSubscriber::builder()
+                    .with_max_level(level)
+                    .with_target(false)
+                    .without_time()
+                    .finish();
+
+                tracing::subscriber::set_global_default(subscriber)
+                    .expect("setting default subscriber");
+
+                let registry_url = match local {
+                    true => None,
+                    false => Some(registries.push.as_str()),
+                };
+
+                dictionary::train(registry_url, sample_kind, output, *size).await
+            }
+        },
+
+        Command::Status { service, services } => {
+            let mut unhealthy = vec![];
+
+            println!("{:<10}  {:<32}  STATUS", "SERVICE", "ADDRESS");
+
+            for name in services.split(',').map(str::trim) {
+                let (addresses, service_name) = match name {
+                    "agent" => (vec![service], agent_service_server::SERVICE_NAME),
+                    "artifact" => (vec![service], artifact_service_server::SERVICE_NAME),
+                    "registry" => (
+                        registries.urls.iter().collect(),
+                        registry_service_server::SERVICE_NAME,
+                    ),
+                    _ => bail!("unknown service: {}", name),
+                };
+
+                for address in addresses {
+                    let status = get_service_status(address, service_name).await;
+
+                    if status != "SERVING" && !unhealthy.contains(&name) {
+                        unhealthy.push(name);
+                    }
+
+                    println!("{:<10}  {:<32}  {}", name, address, status);
+                }
+            }
+
+            if !unhealthy.is_empty() {
+                bail!("unhealthy services: {}", unhealthy.join(", "));
+            }
+
+            Ok(())
+        }
+
         Command::Start {
+            agent_hosts,
+            archive_compression,
+            env_passthrough,
+            event_webhook,
+            event_webhook_secret_file,
+            max_concurrent_builds,
+            metrics_port,
+            min_disk_bytes,
             port,
+            push_policy,
+            registry_auth_mode,
+            registry_auth_token_file,
             registry_backend,
+            registry_backend_oci_repo,
             registry_backend_s3_bucket,
+            registry_dictionary,
             services,
+            step_cache,
+            tls_cert,
+            tls_client_ca,
+            tls_key,
         } => {
             let mut subscriber = FmtSubscriber::builder()
                 .with_target(false)
@@ -526,13 +2546,99 @@ async fn main() -> Result<()> {
                 ));
             }
 
-            let (_, health_service) = tonic_health::server::health_reporter();
+            let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+
+            let mut health_serving = true;
+
+            let mut push_queue = None;
+
+            let mut router = get_server_builder(
+                tls_cert.as_deref(),
+                tls_key.as_deref(),
+                tls_client_ca.as_deref(),
+            )?
+            .add_service(health_service);
+
+            if services.contains("agent") {
+                if agent_hosts.is_empty() {
+                    bail!(
+                        "the agent service requires at least one `--agent-host` to download from"
+                    );
+                }
+
+                health_reporter
+                    .set_service_status(agent_service_server::SERVICE_NAME, ServingStatus::Serving)
+                    .await;
 
-            let mut router = Server::builder().add_service(health_service);
+                info!("agent service: [::]:{}", port);
+                info!("agent hosts: {}", agent_hosts.join(", "));
+
+                router = router.add_service(AgentServiceServer::new(
+                    AgentServer::default().with_hosts(agent_hosts),
+                ));
+            }
 
             if services.contains("artifact") {
+                if *max_concurrent_builds == 0 {
+                    bail!("`--max-concurrent-builds` must be at least 1");
+                }
+
+                if event_webhook.is_none() && event_webhook_secret_file.is_some() {
+                    bail!("`--event-webhook-secret-file` requires `--event-webhook`");
+                }
+
+                let event_webhook = match event_webhook {
+                    Some(url) => {
+                        Some(EventWebhook::new(url, event_webhook_secret_file.as_deref()).await?)
+                    }
+                    None => None,
+                };
+
+                if let Some(event_webhook) = &event_webhook {
+                    info!("artifact event webhook: {}", event_webhook.url());
+                }
+
                 let system = get_artifact_system(format!("{}-{}", ARCH, OS).as_str());
-                let service = ArtifactServiceServer::new(ArtifactServer::new(registry, system));
+                let server = ArtifactServer::new(
+                    *archive_compression,
+                    chunk_bounds,
+                    env_passthrough.clone(),
+                    event_webhook,
+                    *max_concurrent_builds,
+                    *min_disk_bytes,
+                    *push_policy,
+                    registries.clone(),
+                    *step_cache,
+                    system,
+                );
+
+                push_queue = Some(server.push_queue.clone());
+
+                info!("artifact push policy: {}", push_policy);
+
+                if let Err(err) = remove_interrupted_builds().await {
+                    warn!("failed to remove interrupted builds: {}", err);
+                }
+
+                log_signing_key_fingerprint().await;
+
+                let status = match server.check() {
+                    Ok(_) => ServingStatus::Serving,
+                    Err(err) => {
+                        warn!("artifact service not serving: {}", err);
+                        ServingStatus::NotServing
+                    }
+                };
+
+                health_serving &= status == ServingStatus::Serving;
+
+                health_reporter
+                    .set_service_status(artifact_service_server::SERVICE_NAME, status)
+                    .await;
+
+                let service = ArtifactServiceServer::new(server)
+                    .accept_compressed(CompressionEncoding::Gzip)
+                    .max_decoding_message_size(max_message_size);
 
                 info!("artifact service: [::]:{}", port);
 
@@ -543,6 +2649,7 @@ async fn main() -> Result<()> {
                 let backend = match registry_backend.as_str() {
                     "gha" => RegistryServerBackend::GHA,
                     "local" => RegistryServerBackend::Local,
+                    "oci" => RegistryServerBackend::OCI,
                     "s3" => RegistryServerBackend::S3,
                     _ => RegistryServerBackend::Unknown,
                 };
@@ -555,10 +2662,22 @@ async fn main() -> Result<()> {
                     bail!("s3 backend requires '--registry-backend-s3-bucket' parameter");
                 }
 
+                if backend == RegistryServerBackend::OCI && registry_backend_oci_repo.is_none() {
+                    bail!("oci backend requires '--registry-backend-oci-repo' parameter");
+                }
+
+                if backend == RegistryServerBackend::GHA && registry_dictionary.is_some() {
+                    bail!("gha backend does not support '--registry-dictionary' parameter");
+                }
+
                 let backend: Box<dyn RegistryBackend> = match backend {
                     RegistryServerBackend::Local => {
                         Box::new(vorpal_registry::LocalRegistryBackend::new()?)
                     }
+                    RegistryServerBackend::OCI => Box::new(
+                        vorpal_registry::OciRegistryBackend::new(registry_backend_oci_repo.clone())
+                            .await?,
+                    ),
                     RegistryServerBackend::S3 => Box::new(
                         vorpal_registry::S3RegistryBackend::new(registry_backend_s3_bucket.clone())
                             .await?,
@@ -569,23 +2688,254 @@ async fn main() -> Result<()> {
                     RegistryServerBackend::Unknown => unreachable!(),
                 };
 
-                let service = RegistryServiceServer::new(RegistryServer::new(backend));
+                let mut registry_server = RegistryServer::new(backend, chunk_bounds);
+
+                if let Some(registry_dictionary) = registry_dictionary {
+                    let dictionary = tokio::fs::read(registry_dictionary).await?;
+
+                    registry_server = registry_server.with_dictionary(dictionary).await?;
+                }
+
+                if let Some(registry_auth_token_file) = registry_auth_token_file {
+                    let auth = RegistryAuth::from_file(
+                        Path::new(&registry_auth_token_file),
+                        *registry_auth_mode,
+                    )?;
+
+                    info!("registry auth mode: {}", registry_auth_mode);
+
+                    registry_server = registry_server.with_auth(auth);
+                }
+
+                let status = match registry_server.check().await {
+                    Ok(_) => ServingStatus::Serving,
+                    Err(err) => {
+                        warn!("registry service not serving: {}", err.message());
+                        ServingStatus::NotServing
+                    }
+                };
+
+                health_serving &= status == ServingStatus::Serving;
+
+                health_reporter
+                    .set_service_status(registry_service_server::SERVICE_NAME, status)
+                    .await;
+
+                let service = RegistryServiceServer::new(registry_server)
+                    .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT);
 
                 info!("registry service: [::]:{}", port);
 
                 router = router.add_service(service);
             }
 
+            if !health_serving {
+                health_reporter
+                    .set_service_status("", ServingStatus::NotServing)
+                    .await;
+            }
+
+            if let Some(metrics_port) = *metrics_port {
+                tokio::spawn(async move {
+                    if let Err(err) = serve_metrics(metrics_port).await {
+                        error!("metrics server stopped: {}", err);
+                    }
+                });
+            }
+
             let address = format!("[::]:{}", port)
                 .parse()
                 .expect("failed to parse address");
 
             router
-                .serve(address)
+                .serve_with_shutdown(address, get_shutdown_signal())
                 .await
                 .expect("failed to start worker server");
 
+            // Pushes of `--push-policy async` builds finish before the worker exits
+
+            if let Some(push_queue) = push_queue {
+                if push_queue.pending() > 0 {
+                    info!("flushing {} queued pushes", push_queue.pending());
+
+                    push_queue.flush().await;
+                }
+            }
+
             Ok(())
         }
+
+        Command::Store(command_store) => match command_store {
+            CommandStore::Export {
+                digest,
+                name,
+                output,
+            } => bundle::export(&registries, digest, name.clone(), output).await,
+
+            CommandStore::Import { input, keys } => {
+                let mut public_key_paths = keys.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+                if public_key_paths.is_empty() {
+                    public_key_paths.push(get_public_key_path());
+                }
+
+                bundle::import(&registries, chunk_bounds, input, &public_key_paths).await
+            }
+
+            CommandStore::CacheInfo {} => {
+                let entries = get_source_cache_entries()?;
+
+                let size = entries.iter().map(|entry| entry.size).sum::<u64>();
+
+                println!(
+                    "{} source cache archives, {} of {} ({})",
+                    entries.len(),
+                    get_bytes_display(size),
+                    get_bytes_display(get_source_cache_size_max()),
+                    get_cache_dir_path().display()
+                );
+
+                let mut largest = entries;
+
+                largest.sort_by(|a, b| b.size.cmp(&a.size));
+
+                for entry in largest.iter().take(10) {
+                    println!(
+                        "{:>10} {}",
+                        get_bytes_display(entry.size),
+                        entry.path.file_name().unwrap_or_default().to_string_lossy()
+                    );
+                }
+
+                Ok(())
+            }
+
+            CommandStore::Dedup {} => {
+                let store_dir_path = get_store_dir_path();
+
+                let mut summary = BlobSummary::default();
+
+                // Unpacked artifacts are the directories of the store, archives are left as is
+
+                for entry in std::fs::read_dir(&store_dir_path)? {
+                    let entry = entry?;
+
+                    if entry.file_type()?.is_dir() {
+                        summary.add(dedup_path(&entry.path())?);
+                    }
+                }
+
+                let (pruned, pruned_bytes) = prune_blobs()?;
+
+                println!(
+                    "{} files, {} linked to blobs, {} bytes saved",
+                    summary.files, summary.linked, summary.saved_bytes
+                );
+
+                println!("{} unused blobs removed ({} bytes)", pruned, pruned_bytes);
+
+                Ok(())
+            }
+
+            CommandStore::PruneSandboxes {
+                dry_run,
+                min_age_hours,
+            } => {
+                let min_age = Duration::from_secs(min_age_hours * 60 * 60);
+
+                let paths = prune_sandboxes(*dry_run, min_age).await?;
+
+                for path in paths.iter() {
+                    println!("{}", path.display());
+                }
+
+                let action = if *dry_run { "reclaimable" } else { "removed" };
+
+                println!("{} sandboxes {}", paths.len(), action);
+
+                Ok(())
+            }
+
+            CommandStore::Push { digest } => {
+                let artifact_ids = get_pending_pushes(&digest.parse()?).await?;
+
+                if artifact_ids.is_empty() {
+                    bail!("no pending push: {}", digest);
+                }
+
+                for artifact_id in artifact_ids.iter() {
+                    push_pending(chunk_bounds, &registries.push, artifact_id).await?;
+
+                    println!("pushed: {}-{}", artifact_id.name, artifact_id.hash);
+                }
+
+                Ok(())
+            }
+
+            CommandStore::VerifyManifests {} => {
+                let (checked, removed) = verify_manifests().await?;
+
+                for path in removed.iter() {
+                    println!("{}", path.display());
+                }
+
+                println!(
+                    "{} manifests verified, {} corrupt removed ({})",
+                    checked,
+                    removed.len(),
+                    get_manifest_dir_path().display()
+                );
+
+                Ok(())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_variables(variables: &[&str]) -> Result<BTreeMap<String, String>> {
+        get_variables(&variables.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_get_variables() {
+        let variables =
+            get_test_variables(&["name=a", "release.name=b", "release.url=c=d"]).unwrap();
+
+        assert_eq!(
+            variables,
+            BTreeMap::from([
+                ("name".to_string(), "a".to_string()),
+                ("release.name".to_string(), "b".to_string()),
+                ("release.url".to_string(), "c=d".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_get_variables_rejects_invalid() {
+        for variable in ["name", "=a", ".name=a", "release.=a"] {
+            assert!(
+                get_test_variables(&[variable]).is_err(),
+                "accepted {}",
+                variable
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_variables_duplicates() {
+        let error = get_test_variables(&["release.name=a", "release.name=b"]).unwrap_err();
+
+        assert!(error.to_string().contains("release.name"));
+
+        // Unscoped variables keep the last value
+
+        let variables = get_test_variables(&["name=a", "name=b"]).unwrap();
+
+        assert_eq!(variables.get("name").map(String::as_str), Some("b"));
     }
 }
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::{
+    fs::{read_dir, read_to_string},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::Duration,
+};
+use tokio::{
+    fs::{metadata, File},
+    io::{stdout, AsyncReadExt, AsyncWriteExt},
+    time::sleep,
+};
+use vorpal_store::paths::{
+    get_process_log_path, get_process_pid_path, get_process_state_path, get_store_dir_path,
+};
+
+// Interval between polls of a followed log
+const PROCESS_LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Bytes of the log read and written at a time
+const PROCESS_LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+/// State written by the `-start` script of a process artifact.
+#[derive(Deserialize)]
+struct ProcessState {
+    arguments: Vec<String>,
+    artifact: String,
+    entrypoint: String,
+    pid: u32,
+    started_at: u64,
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Returns the store directories of process artifacts named `name`.
+fn get_process_artifact_paths(name: &str) -> Result<Vec<PathBuf>> {
+    let store_dir_path = get_store_dir_path();
+
+    if !store_dir_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut paths = vec![];
+
+    for entry in read_dir(&store_dir_path)? {
+        let path = entry?.path();
+
+        let Some(store_name) = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| file_name.strip_suffix(".artifact"))
+        else {
+            continue;
+        };
+
+        // Store directories are named `<name>-<hash>`, names can contain dashes
+
+        let Some((artifact_name, _)) = store_name.rsplit_once('-') else {
+            continue;
+        };
+
+        if artifact_name == name && path.join(format!("bin/{}-start", name)).exists() {
+            paths.push(path);
+        }
+    }
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Prints whether the process of the artifact `name` is running, from the state of its last start.
+pub fn status(name: &str) -> Result<()> {
+    let state_path = get_process_state_path(name);
+
+    if !state_path.exists() {
+        let artifact_paths = get_process_artifact_paths(name)?;
+
+        if artifact_paths.is_empty() {
+            bail!("process artifact not found: {}", name);
+        }
+
+        println!("name:       {}", name);
+        println!("status:     stopped");
+
+        for artifact_path in artifact_paths {
+            println!("artifact:   {}", artifact_path.display());
+        }
+
+        return Ok(());
+    }
+
+    let state = serde_json::from_str::<ProcessState>(&read_to_string(&state_path)?)
+        .map_err(|e| anyhow!("failed to parse {}: {}", state_path.display(), e))?;
+
+    // The stop script removes the pid file, one left for a dead process was never stopped
+
+    let status = match get_process_pid_path(name).exists() {
+        false => "stopped",
+        true if is_process_alive(state.pid) => "running",
+        true => "stopped (stale pid)",
+    };
+
+    println!("name:       {}", name);
+    println!("status:     {}", status);
+    println!("pid:        {}", state.pid);
+    println!("started_at: {}", state.started_at);
+    println!("artifact:   {}", state.artifact);
+    println!("entrypoint: {}", state.entrypoint);
+    println!("arguments:  {}", state.arguments.join(" "));
+
+    Ok(())
+}
+
+/// Writes the log of the process of the artifact `name` to stdout, and with `follow` keeps
+/// writing new lines until interrupted.
+///
+/// Logs replaced or truncated by rotation are read again from the start.
+pub async fn logs(name: &str, follow: bool) -> Result<()> {
+    let log_path = get_process_log_path(name);
+
+    if !follow && !log_path.exists() {
+        bail!("process log not found: {}", log_path.display());
+    }
+
+    let mut buffer = vec![0; PROCESS_LOG_BUFFER_SIZE];
+    let mut log: Option<(File, u64)> = None;
+    let mut log_position = 0;
+    let mut output = stdout();
+
+    loop {
+        // Finish the open log first, lines written before a rotation are not lost
+
+        if let Some((file, _)) = log.as_mut() {
+            loop {
+                let read = file.read(&mut buffer).await?;
+
+                if read == 0 {
+                    break;
+                }
+
+                output.write_all(&buffer[..read]).await?;
+
+                log_position += read as u64;
+            }
+
+            output.flush().await?;
+        }
+
+        if let Ok(log_metadata) = metadata(&log_path).await {
+            let rotated = match &log {
+                None => true,
+                Some((_, inode)) => {
+                    *inode != log_metadata.ino() || log_metadata.len() < log_position
+                }
+            };
+
+            if rotated {
+                log = Some((File::open(&log_path).await?, log_metadata.ino()));
+                log_position = 0;
+
+                continue;
+            }
+        }
+
+        if !follow {
+            return Ok(());
+        }
+
+        sleep(PROCESS_LOG_POLL_INTERVAL).await;
+    }
+}
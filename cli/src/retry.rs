@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Error};
+use std::{error::Error as _, time::Duration};
+use tokio::time::sleep;
+use tonic::{Code::Unavailable, Status};
+use tracing::warn;
+
+// Attempts of an operation interrupted by connection failures, the first one included
+const RETRY_ATTEMPTS_MAX: u32 = 6;
+
+// Delay before the first retry, doubled after each one up to `RETRY_DELAY_MAX`
+const RETRY_DELAY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// Returns whether `status` is a connection failure, such as a worker restarting mid-stream,
+/// rather than an error returned by the service.
+///
+/// Transport errors carry their cause as a source, `Unavailable` is retryable by gRPC convention.
+pub fn is_transport_error(status: &Status) -> bool {
+    status.code() == Unavailable || status.source().is_some()
+}
+
+/// Returns whether `error` was caused by a transport error of a request.
+pub fn is_transport_error_chain(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<Status>()
+            .is_some_and(is_transport_error)
+    })
+}
+
+/// Failed attempts of an operation retried with exponential backoff, reported with the final
+/// error.
+///
+/// Channels reconnect on the next request, so retries reuse the client of the failed attempt.
+pub struct Retry {
+    attempts: Vec<String>,
+    delay: Duration,
+    operation: String,
+}
+
+impl Retry {
+    pub fn new(operation: impl Into<String>) -> Self {
+        Self {
+            attempts: vec![],
+            delay: RETRY_DELAY_INITIAL,
+            operation: operation.into(),
+        }
+    }
+
+    /// Records a failed attempt and waits before the next one, or returns the error with the
+    /// retry history once all attempts failed.
+    pub async fn wait(&mut self, error: impl std::fmt::Display) -> Result<(), Error> {
+        let attempt = self.attempts.len() as u32 + 1;
+
+        if attempt >= RETRY_ATTEMPTS_MAX {
+            self.attempts
+                .push(format!("attempt {}: {}", attempt, error));
+
+            return Err(anyhow!(
+                "{} failed after {} attempts:\n  {}",
+                self.operation,
+                attempt,
+                self.attempts.join("\n  ")
+            ));
+        }
+
+        warn!(
+            "{} interrupted (attempt {}/{}), retrying in {}s: {}",
+            self.operation,
+            attempt,
+            RETRY_ATTEMPTS_MAX,
+            self.delay.as_secs(),
+            error
+        );
+
+        self.attempts.push(format!(
+            "attempt {}: {} (retried after {}s)",
+            attempt,
+            error,
+            self.delay.as_secs()
+        ));
+
+        sleep(self.delay).await;
+
+        self.delay = (self.delay * 2).min(RETRY_DELAY_MAX);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+    use tonic::Code::{Internal, NotFound};
+
+    #[test]
+    fn test_is_transport_error() {
+        assert!(is_transport_error(&Status::unavailable(
+            "connection refused"
+        )));
+
+        // Errors of the connection itself are kept as the source of the status
+
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+
+        assert!(is_transport_error(&Status::from_error(Box::new(io_error))));
+
+        // Errors returned by the service are final
+
+        assert!(!is_transport_error(&Status::new(NotFound, "missing")));
+        assert!(!is_transport_error(&Status::new(Internal, "build failed")));
+    }
+
+    #[test]
+    fn test_is_transport_error_chain() {
+        let transport =
+            Error::new(Status::unavailable("worker restarting")).context("failed to build");
+
+        assert!(is_transport_error_chain(&transport));
+
+        let application =
+            Error::new(Status::new(Internal, "step failed")).context("failed to build");
+
+        assert!(!is_transport_error_chain(&application));
+        assert!(!is_transport_error_chain(&anyhow!("failed to build")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_backoff_and_history() {
+        let mut retry = Retry::new("build of example");
+        let mut delays = vec![];
+
+        for attempt in 1..RETRY_ATTEMPTS_MAX {
+            let started = Instant::now();
+
+            retry
+                .wait(format!("interrupted {}", attempt))
+                .await
+                .unwrap();
+
+            delays.push(started.elapsed().as_secs());
+        }
+
+        assert_eq!(delays, [1, 2, 4, 8, 16]);
+
+        // Delays double up to the maximum
+
+        assert_eq!(retry.delay, RETRY_DELAY_MAX);
+
+        let started = Instant::now();
+        let err = retry.wait("interrupted 6").await.unwrap_err();
+
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(
+            err.to_string(),
+            "build of example failed after 6 attempts:\n  \
+             attempt 1: interrupted 1 (retried after 1s)\n  \
+             attempt 2: interrupted 2 (retried after 2s)\n  \
+             attempt 3: interrupted 3 (retried after 4s)\n  \
+             attempt 4: interrupted 4 (retried after 8s)\n  \
+             attempt 5: interrupted 5 (retried after 16s)\n  \
+             attempt 6: interrupted 6"
+        );
+    }
+}
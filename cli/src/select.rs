@@ -0,0 +1,94 @@
+use anyhow::{bail, Result};
+use console::Term;
+use std::io::{stdin, stdout, IsTerminal};
+
+/// Artifact of the config offered when `--name` is omitted.
+pub struct ArtifactChoice {
+    pub name: String,
+    pub systems: Vec<String>,
+}
+
+impl ArtifactChoice {
+    fn get_display(&self) -> String {
+        match self.systems.is_empty() {
+            true => self.name.clone(),
+            false => format!("{} ({})", self.name, self.systems.join(", ")),
+        }
+    }
+}
+
+fn get_choices_display(choices: &[&ArtifactChoice]) -> String {
+    choices
+        .iter()
+        .enumerate()
+        .map(|(index, choice)| format!("{:>4}) {}", index + 1, choice.get_display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Prompts for one of `choices` on a terminal, by number or by filtering the names. Outside a
+/// terminal it fails with the available names instead.
+pub fn select_artifact(choices: &[ArtifactChoice]) -> Result<String> {
+    if choices.is_empty() {
+        bail!("no `--name` specified, and the config defines no artifacts");
+    }
+
+    let all = choices.iter().collect::<Vec<_>>();
+
+    if !stdin().is_terminal() || !stdout().is_terminal() {
+        bail!(
+            "no `--name` specified, available artifacts:\n{}",
+            get_choices_display(&all)
+        );
+    }
+
+    let term = Term::stderr();
+
+    let mut filtered = all.clone();
+
+    loop {
+        term.write_line(&get_choices_display(&filtered))?;
+        term.write_str("artifact (number, or text to filter): ")?;
+
+        let mut input = String::new();
+
+        if stdin().read_line(&mut input)? == 0 {
+            bail!("no artifact selected");
+        }
+
+        let input = input.trim();
+
+        if input.is_empty() {
+            filtered = all.clone();
+
+            continue;
+        }
+
+        if let Ok(number) = input.parse::<usize>() {
+            match filtered.get(number.wrapping_sub(1)) {
+                Some(choice) => return Ok(choice.name.clone()),
+                None => {
+                    term.write_line(&format!("no artifact numbered {}", number))?;
+
+                    continue;
+                }
+            }
+        }
+
+        if let Some(choice) = all.iter().find(|choice| choice.name == input) {
+            return Ok(choice.name.clone());
+        }
+
+        let matches = all
+            .iter()
+            .filter(|choice| choice.name.contains(input))
+            .copied()
+            .collect::<Vec<_>>();
+
+        match matches.as_slice() {
+            [] => term.write_line(&format!("no artifact matches '{}'", input))?,
+            [choice] => return Ok(choice.name.clone()),
+            _ => filtered = matches,
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, bail, Result};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tokio::fs::remove_dir_all;
+use tracing::Level;
+use vorpal_schema::vorpal::{artifact::v0::Artifact, config::v0::ConfigArtifactSource};
+use vorpal_store::{
+    archives::unpack_archive,
+    digests::SourceDigest,
+    hashes::{get_file_hashes, get_hashes_digest},
+    paths::{
+        copy_files, get_cache_archive_path, get_file_paths, get_source_archive_path,
+        get_source_excludes, sanitize_symlinks,
+    },
+    temps::create_sandbox_dir,
+};
+
+/// Hashes of the files of a source by path relative to the source root.
+pub type SourceFiles = BTreeMap<String, String>;
+
+// Hashes the files of `root_path` the way sources are hashed, returning them with the digest
+async fn get_source_files(root_path: &Path, files: Vec<PathBuf>) -> Result<(String, SourceFiles)> {
+    sanitize_symlinks(root_path, &files).await?;
+
+    let files = files
+        .into_iter()
+        .filter(|file| file.is_symlink() || file.is_file())
+        .collect::<Vec<_>>();
+
+    let hashes = get_file_hashes(&files)?;
+
+    let digest = get_hashes_digest(hashes.clone())?;
+
+    let source_files = files
+        .iter()
+        .zip(hashes)
+        .map(|(file, hash)| {
+            let path = file.strip_prefix(root_path).unwrap_or(file);
+
+            (path.display().to_string(), hash)
+        })
+        .collect();
+
+    Ok((digest, source_files))
+}
+
+/// Resolves the files of a local source as an evaluation does: copied with its includes,
+/// excludes and `.vorpalignore` into a sandbox, then hashed from there.
+pub async fn get_local_source_files(
+    source: &ConfigArtifactSource,
+) -> Result<(String, SourceFiles)> {
+    let source_path = PathBuf::from(&source.path);
+
+    if !source_path.exists() {
+        bail!("`source.{}.path` not found: {}", source.name, source.path);
+    }
+
+    let source_files = get_file_paths(
+        &source_path,
+        get_source_excludes(&source_path, &source.excludes)?,
+        source.includes.clone(),
+    )?;
+
+    let sandbox_path = create_sandbox_dir().await?;
+
+    copy_files(&source_path, source_files, &sandbox_path).await?;
+
+    let sandbox_files = get_file_paths(
+        &sandbox_path,
+        source.excludes.clone(),
+        source.includes.clone(),
+    )?;
+
+    let result = get_source_files(&sandbox_path, sandbox_files).await;
+
+    remove_dir_all(&sandbox_path).await?;
+
+    result
+}
+
+/// Resolves the files of a source recorded earlier with `digest`, from the source cache of
+/// evaluations or the sources kept in the store.
+pub async fn get_recorded_source_files(
+    digest: &SourceDigest,
+    name: &str,
+) -> Result<Option<SourceFiles>> {
+    let Some(archive_path) = [
+        get_cache_archive_path(digest, name),
+        get_source_archive_path(digest, name),
+    ]
+    .into_iter()
+    .find(|path| path.exists()) else {
+        return Ok(None);
+    };
+
+    let sandbox_path = create_sandbox_dir().await?;
+
+    unpack_archive(&sandbox_path, &archive_path)
+        .await
+        .map_err(|e| anyhow!("failed to unpack {}: {}", archive_path.display(), e))?;
+
+    let result = match get_file_paths(&sandbox_path, vec![], vec![]) {
+        Ok(files) => get_source_files(&sandbox_path, files).await,
+        Err(err) => Err(err),
+    };
+
+    remove_dir_all(&sandbox_path).await?;
+
+    Ok(Some(result?.1))
+}
+
+/// Prints the files of each source of `artifact` with its digest, per-file hashes at debug
+/// verbosity. With `diff`, files are compared to the source recorded with that digest.
+pub async fn print_sources(
+    artifact: &Artifact,
+    config_sources: &[ConfigArtifactSource],
+    diff: Option<&str>,
+) -> Result<()> {
+    if artifact.sources.is_empty() {
+        println!("{} has no sources", artifact.name);
+
+        return Ok(());
+    }
+
+    let show_hashes = tracing::enabled!(Level::DEBUG);
+
+    let mut diff_found = diff.is_none();
+
+    for source in artifact.sources.iter() {
+        let config_source = config_sources
+            .iter()
+            .find(|s| s.artifact == artifact.name && s.name == source.name);
+
+        let Some(config_source) = config_source else {
+            println!("source: {} (remote)", source.name);
+            println!("  digest: {} (pinned)", source.hash);
+
+            if diff == Some(source.hash.as_str()) {
+                println!("  unchanged from {}", source.hash);
+
+                diff_found = true;
+            }
+
+            continue;
+        };
+
+        println!("source: {} ({})", source.name, config_source.path);
+
+        let (digest, files) = get_local_source_files(config_source).await?;
+
+        for (path, hash) in files.iter() {
+            match show_hashes {
+                true => println!("  {}  {}", hash, path),
+                false => println!("  {}", path),
+            }
+        }
+
+        println!("  digest: {} ({} files)", source.hash, files.len());
+
+        // The digest also covers `rename`, `executable` and `--source-revision`, which the
+        // listing of the working tree does not apply
+
+        if digest != source.hash {
+            println!("  listed files hash to {}", digest);
+        }
+
+        let Some(diff) = diff else {
+            continue;
+        };
+
+        let Some(previous) = get_recorded_source_files(&diff.parse()?, &source.name).await? else {
+            continue;
+        };
+
+        diff_found = true;
+
+        println!("  changes from {}:", diff);
+
+        print_diff(&previous, &files);
+    }
+
+    if let Some(diff) = diff {
+        if !diff_found {
+            bail!(
+                "no source recorded with digest {} for {}, sources are recorded when evaluated",
+                diff,
+                artifact.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the changes from `previous` to `current`: `+` added, `~` changed and `-` removed
+fn get_diff(previous: &SourceFiles, current: &SourceFiles) -> Vec<String> {
+    let mut changes = vec![];
+
+    for (path, hash) in current.iter() {
+        match previous.get(path) {
+            None => changes.push(format!("+ {}", path)),
+            Some(previous_hash) if previous_hash != hash => changes.push(format!("~ {}", path)),
+            Some(_) => continue,
+        }
+    }
+
+    for path in previous.keys().filter(|path| !current.contains_key(*path)) {
+        changes.push(format!("- {}", path));
+    }
+
+    changes
+}
+
+fn print_diff(previous: &SourceFiles, current: &SourceFiles) {
+    let changes = get_diff(previous, current);
+
+    if changes.is_empty() {
+        println!("    none");
+    }
+
+    for change in changes {
+        println!("    {}", change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_file, write};
+
+    async fn get_test_source_files(root_path: &Path) -> (String, SourceFiles) {
+        let files = get_file_paths(&root_path.to_path_buf(), vec![], vec![]).unwrap();
+
+        get_source_files(root_path, files).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_source_files() {
+        let source = tempfile::tempdir().unwrap();
+
+        create_dir_all(source.path().join("src")).unwrap();
+
+        write(source.path().join("a.txt"), "a").unwrap();
+        write(source.path().join("src").join("b.txt"), "b").unwrap();
+
+        let (digest, files) = get_test_source_files(source.path()).await;
+
+        // Paths are relative to the source root, directories are not listed
+
+        assert_eq!(
+            files.keys().cloned().collect::<Vec<_>>(),
+            vec!["a.txt".to_string(), "src/b.txt".to_string()]
+        );
+
+        assert_eq!(files["a.txt"].len(), 64);
+        assert_ne!(files["a.txt"], files["src/b.txt"]);
+
+        let (digest_again, _) = get_test_source_files(source.path()).await;
+
+        assert_eq!(digest, digest_again);
+    }
+
+    #[tokio::test]
+    async fn test_get_diff() {
+        let source = tempfile::tempdir().unwrap();
+
+        write(source.path().join("changed.txt"), "a").unwrap();
+        write(source.path().join("removed.txt"), "a").unwrap();
+        write(source.path().join("unchanged.txt"), "a").unwrap();
+
+        let (previous_digest, previous) = get_test_source_files(source.path()).await;
+
+        assert!(get_diff(&previous, &previous).is_empty());
+
+        write(source.path().join("added.txt"), "a").unwrap();
+        write(source.path().join("changed.txt"), "b").unwrap();
+        remove_file(source.path().join("removed.txt")).unwrap();
+
+        let (current_digest, current) = get_test_source_files(source.path()).await;
+
+        assert_ne!(previous_digest, current_digest);
+
+        assert_eq!(
+            get_diff(&previous, &current),
+            vec![
+                "+ added.txt".to_string(),
+                "~ changed.txt".to_string(),
+                "- removed.txt".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::{anyhow, bail, Result};
+use notify::{recommended_watcher, Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::mpsc, task::spawn_blocking, time::timeout};
+use vorpal_schema::vorpal::config::v0::ConfigArtifactSource;
+use vorpal_store::paths::{get_file_paths, get_source_excludes};
+
+// Interval the sources must stay free of file events before a rebuild starts
+const WATCH_DEBOUNCE_INTERVAL: Duration = Duration::from_millis(250);
+
+pub type WatchSnapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Returns the modification time and size of the files of `sources`.
+pub fn get_snapshot(sources: &[ConfigArtifactSource]) -> Result<WatchSnapshot> {
+    let mut snapshot = WatchSnapshot::new();
+
+    for source in sources {
+        let source_path = Path::new(&source.path).to_path_buf();
+
+        if !source_path.exists() {
+            continue;
+        }
+
+        let source_files = get_file_paths(
+            &source_path,
+            get_source_excludes(&source_path, &source.excludes)?,
+            source.includes.clone(),
+        )?;
+
+        for file in source_files {
+            if let Ok(metadata) = file.metadata() {
+                if metadata.is_file() {
+                    snapshot.insert(file, (metadata.modified().ok(), metadata.len()));
+                }
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+// Walks the sources on the blocking pool so large trees do not stall the runtime
+async fn get_snapshot_blocking(sources: &[ConfigArtifactSource]) -> Result<WatchSnapshot> {
+    let sources = sources.to_vec();
+
+    spawn_blocking(move || get_snapshot(&sources))
+        .await
+        .map_err(|err| anyhow!("failed to snapshot sources: {}", err))?
+}
+
+fn get_changes(previous: &WatchSnapshot, current: &WatchSnapshot) -> Vec<PathBuf> {
+    let mut changes = vec![];
+
+    for (path, state) in current {
+        if previous.get(path) != Some(state) {
+            changes.push(path.clone());
+        }
+    }
+
+    for path in previous.keys() {
+        if !current.contains_key(path) {
+            changes.push(path.clone());
+        }
+    }
+
+    changes.sort();
+    changes.dedup();
+
+    changes
+}
+
+// Reads are reported by some platforms (inotify close events) and never change a source
+fn is_change_event(event: &Event) -> bool {
+    !matches!(event.kind, EventKind::Access(_))
+}
+
+/// Waits until files in `sources` change and returns the changed paths.
+///
+/// The sources are watched with platform file events. Events are debounced until the sources
+/// stay quiet, so editors writing several files at once trigger a single rebuild, and the
+/// sources are then compared against a snapshot so changes to excluded files are ignored.
+pub async fn wait_for_changes(sources: &[ConfigArtifactSource]) -> Result<Vec<PathBuf>> {
+    wait_for_changes_interval(sources, WATCH_DEBOUNCE_INTERVAL).await
+}
+
+async fn wait_for_changes_interval(
+    sources: &[ConfigArtifactSource],
+    debounce_interval: Duration,
+) -> Result<Vec<PathBuf>> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    // The watcher is kept alive until this function returns
+
+    let mut watcher = recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })
+    .map_err(|err| anyhow!("failed to create source watcher: {}", err))?;
+
+    for source in sources {
+        let source_path = Path::new(&source.path);
+
+        if !source_path.exists() {
+            continue;
+        }
+
+        watcher
+            .watch(source_path, RecursiveMode::Recursive)
+            .map_err(|err| anyhow!("failed to watch source {}: {}", source.path, err))?;
+    }
+
+    // Snapshot after watching starts so changes made in between still wake the loop
+
+    let snapshot = get_snapshot_blocking(sources).await?;
+
+    loop {
+        loop {
+            let Some(event) = rx.recv().await else {
+                bail!("source watcher stopped");
+            };
+
+            let event = event.map_err(|err| anyhow!("failed to watch sources: {}", err))?;
+
+            if is_change_event(&event) {
+                break;
+            }
+        }
+
+        loop {
+            match timeout(debounce_interval, rx.recv()).await {
+                Ok(Some(event)) => {
+                    event.map_err(|err| anyhow!("failed to watch sources: {}", err))?;
+                }
+
+                Ok(None) => bail!("source watcher stopped"),
+
+                Err(_) => break,
+            }
+        }
+
+        let current = get_snapshot_blocking(sources).await?;
+
+        let changes = get_changes(&snapshot, &current);
+
+        if !changes.is_empty() {
+            return Ok(changes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, remove_file, write};
+    use tokio::time::sleep;
+
+    fn get_test_sources(path: &Path) -> Vec<ConfigArtifactSource> {
+        vec![ConfigArtifactSource {
+            path: path.display().to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn test_get_changes() {
+        let source = tempfile::tempdir().unwrap();
+
+        write(source.path().join("changed.txt"), "a").unwrap();
+        write(source.path().join("removed.txt"), "a").unwrap();
+        write(source.path().join("unchanged.txt"), "a").unwrap();
+
+        let sources = get_test_sources(source.path());
+
+        let previous = get_snapshot(&sources).unwrap();
+
+        assert!(get_changes(&previous, &get_snapshot(&sources).unwrap()).is_empty());
+
+        write(source.path().join("added.txt"), "a").unwrap();
+        write(source.path().join("changed.txt"), "ab").unwrap();
+        remove_file(source.path().join("removed.txt")).unwrap();
+
+        let changes = get_changes(&previous, &get_snapshot(&sources).unwrap());
+
+        assert_eq!(
+            changes,
+            vec![
+                source.path().join("added.txt"),
+                source.path().join("changed.txt"),
+                source.path().join("removed.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_snapshot_excludes() {
+        let source = tempfile::tempdir().unwrap();
+
+        write(source.path().join("kept.txt"), "a").unwrap();
+        write(source.path().join("skipped.log"), "a").unwrap();
+
+        let sources = vec![ConfigArtifactSource {
+            excludes: vec!["skipped.log".to_string()],
+            path: source.path().display().to_string(),
+            ..Default::default()
+        }];
+
+        let snapshot = get_snapshot(&sources).unwrap();
+
+        assert_eq!(
+            snapshot.keys().cloned().collect::<Vec<_>>(),
+            vec![source.path().join("kept.txt")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_changes_debounces_writes() {
+        let source = tempfile::tempdir().unwrap();
+
+        write(source.path().join("a.txt"), "a").unwrap();
+
+        let sources = get_test_sources(source.path());
+
+        let debounce_interval = Duration::from_millis(200);
+
+        let waiting = tokio::spawn({
+            let sources = sources.clone();
+
+            async move { wait_for_changes_interval(&sources, debounce_interval).await }
+        });
+
+        // Writes spaced closer than the debounce interval are reported together
+
+        let source_path = source.path().to_path_buf();
+
+        let writer = tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+
+            for (index, name) in ["a.txt", "b.txt", "c.txt"].iter().enumerate() {
+                write(source_path.join(name), "b".repeat(index + 2)).unwrap();
+
+                sleep(Duration::from_millis(40)).await;
+            }
+        });
+
+        let changes = timeout(Duration::from_secs(10), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        writer.await.unwrap();
+
+        assert_eq!(
+            changes,
+            vec![
+                source.path().join("a.txt"),
+                source.path().join("b.txt"),
+                source.path().join("c.txt"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_changes_ignores_excluded() {
+        let source = tempfile::tempdir().unwrap();
+
+        write(source.path().join("kept.txt"), "a").unwrap();
+
+        let sources = vec![ConfigArtifactSource {
+            excludes: vec!["skipped.log".to_string()],
+            path: source.path().display().to_string(),
+            ..Default::default()
+        }];
+
+        let waiting = tokio::spawn({
+            let sources = sources.clone();
+
+            async move { wait_for_changes_interval(&sources, Duration::from_millis(20)).await }
+        });
+
+        // Events for excluded files are seen but do not end the wait
+
+        let source_path = source.path().to_path_buf();
+
+        sleep(Duration::from_millis(100)).await;
+
+        write(source_path.join("skipped.log"), "a").unwrap();
+
+        sleep(Duration::from_millis(200)).await;
+
+        assert!(!waiting.is_finished());
+
+        write(source_path.join("kept.txt"), "ab").unwrap();
+
+        let changes = timeout(Duration::from_secs(10), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(changes, vec![source.path().join("kept.txt")]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_changes_ignores_unchanged() {
+        let source = tempfile::tempdir().unwrap();
+
+        write(source.path().join("a.txt"), "a").unwrap();
+
+        let sources = get_test_sources(source.path());
+
+        // Reading a source produces access events only
+
+        let source_path = source.path().to_path_buf();
+
+        let reader = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+
+            read(source_path.join("a.txt")).unwrap();
+        });
+
+        let waiting = wait_for_changes_interval(&sources, Duration::from_millis(10));
+
+        assert!(timeout(Duration::from_millis(300), waiting).await.is_err());
+
+        reader.await.unwrap();
+    }
+}
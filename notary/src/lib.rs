@@ -1,13 +1,16 @@
-use anyhow::Result;
-use rand::rngs::OsRng;
+use anyhow::{anyhow, bail, Result};
+use rand::{rngs::OsRng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
 use rsa::pkcs8::{
     DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
 };
-use rsa::pss::SigningKey;
-use rsa::sha2::Sha256;
+use rsa::pss::{Signature, SigningKey, VerifyingKey};
+use rsa::sha2::{Digest, Sha256};
 use rsa::signature::RandomizedSigner;
 use rsa::signature::SignatureEncoding;
-use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::signature::Verifier;
+use rsa::traits::PublicKeyParts;
+use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
 use std::path::PathBuf;
 use tokio::fs;
 use tokio::fs::create_dir_all;
@@ -15,6 +18,13 @@ use tracing::warn;
 
 const BITS: usize = 2048;
 
+// AES-256-GCM key encrypting the data of `encrypt`
+const DATA_KEY_SIZE: usize = 32;
+
+const ENCRYPTED_PREFIX: &str = "vorpal-encrypted:";
+
+const ENCRYPTED_VERSION: u8 = 1;
+
 pub async fn generate_keys(
     key_path: PathBuf,
     private_key_path: PathBuf,
@@ -58,23 +68,73 @@ pub async fn generate_keys(
 }
 
 pub async fn get_private_key(private_key_path: PathBuf) -> Result<RsaPrivateKey> {
-    let key_data = fs::read(private_key_path)
-        .await
-        .expect("failed to read private key");
+    if !private_key_path.exists() {
+        bail!(
+            "private key not found: {} (run 'vorpal keys generate')",
+            private_key_path.display()
+        );
+    }
 
-    let key = std::str::from_utf8(&key_data).expect("failed to convert key to string");
+    let key_data = fs::read_to_string(&private_key_path).await.map_err(|err| {
+        anyhow!(
+            "failed to read private key {}: {}",
+            private_key_path.display(),
+            err
+        )
+    })?;
 
-    Ok(RsaPrivateKey::from_pkcs8_pem(key).expect("failed to parse private key"))
+    RsaPrivateKey::from_pkcs8_pem(&key_data).map_err(|err| {
+        anyhow!(
+            "failed to parse private key {}: {}",
+            private_key_path.display(),
+            err
+        )
+    })
 }
 
 pub async fn get_public_key(public_key_path: PathBuf) -> Result<RsaPublicKey> {
-    let key_data = fs::read(public_key_path)
-        .await
-        .expect("failed to read public key");
+    if !public_key_path.exists() {
+        bail!(
+            "public key not found: {} (run 'vorpal keys generate')",
+            public_key_path.display()
+        );
+    }
 
-    let key = std::str::from_utf8(&key_data).expect("failed to convert key to string");
+    let key_data = fs::read_to_string(&public_key_path).await.map_err(|err| {
+        anyhow!(
+            "failed to read public key {}: {}",
+            public_key_path.display(),
+            err
+        )
+    })?;
+
+    RsaPublicKey::from_public_key_pem(&key_data).map_err(|err| {
+        anyhow!(
+            "failed to parse public key {}: {}",
+            public_key_path.display(),
+            err
+        )
+    })
+}
+
+/// Returns the fingerprint of a public key: the SHA-256 of its DER SubjectPublicKeyInfo, in hex.
+pub fn get_key_fingerprint(public_key: &RsaPublicKey) -> Result<String> {
+    let public_key_der = public_key
+        .to_public_key_der()
+        .map_err(|err| anyhow!("failed to encode public key: {:?}", err))?;
+
+    Ok(encode_signature(&Sha256::digest(public_key_der.as_bytes())))
+}
+
+/// Returns the fingerprint of the public key file at `public_key_path`.
+pub async fn get_public_key_fingerprint(public_key_path: PathBuf) -> Result<String> {
+    get_key_fingerprint(&get_public_key(public_key_path).await?)
+}
 
-    Ok(RsaPublicKey::from_public_key_pem(key).expect("failed to parse public key"))
+/// Returns the fingerprint of the public half of the private key at `private_key_path`, which
+/// is the key its signatures verify with.
+pub async fn get_private_key_fingerprint(private_key_path: PathBuf) -> Result<String> {
+    get_key_fingerprint(&get_private_key(private_key_path).await?.to_public_key())
 }
 
 pub async fn sign(private_key_path: PathBuf, source_data: &[u8]) -> Result<Box<[u8]>> {
@@ -90,3 +150,251 @@ pub async fn sign(private_key_path: PathBuf, source_data: &[u8]) -> Result<Box<[
 
     Ok(signature_bytes)
 }
+
+pub async fn verify(public_key_path: PathBuf, source_data: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key = get_public_key(public_key_path).await?;
+
+    let verifying_key = VerifyingKey::<Sha256>::new(public_key);
+
+    let signature = Signature::try_from(signature)
+        .map_err(|err| anyhow!("failed to parse signature: {:?}", err))?;
+
+    verifying_key
+        .verify(source_data, &signature)
+        .map_err(|err| anyhow!("invalid signature: {:?}", err))
+}
+
+/// Encrypts `source_data` for the holder of the private key: a random AES-256-GCM key encrypts
+/// the data and RSA-OAEP (SHA-256) encrypts the key.
+///
+/// The result is the version, the encrypted key, the nonce and the sealed data with its tag.
+pub async fn encrypt(public_key_path: PathBuf, source_data: &[u8]) -> Result<Vec<u8>> {
+    if !public_key_path.exists() {
+        bail!(
+            "public key not found: {} (run 'vorpal keys generate' or copy it from the worker)",
+            public_key_path.display()
+        );
+    }
+
+    let public_key = get_public_key(public_key_path).await?;
+
+    let mut rng = OsRng;
+
+    let mut data_key = [0u8; DATA_KEY_SIZE];
+    let mut nonce = [0u8; NONCE_LEN];
+
+    rng.fill_bytes(&mut data_key);
+    rng.fill_bytes(&mut nonce);
+
+    let data_key_encrypted = public_key
+        .encrypt(&mut rng, Oaep::new::<Sha256>(), &data_key)
+        .map_err(|err| anyhow!("failed to encrypt: {:?}", err))?;
+
+    let mut data = source_data.to_vec();
+
+    get_data_key(&data_key)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| anyhow!("failed to encrypt data"))?;
+
+    let mut encrypted = vec![ENCRYPTED_VERSION];
+
+    encrypted.extend(data_key_encrypted);
+    encrypted.extend(nonce);
+    encrypted.extend(data);
+
+    Ok(encrypted)
+}
+
+/// Decrypts data from `encrypt` with the private key.
+pub async fn decrypt(private_key_path: PathBuf, source_data: &[u8]) -> Result<Vec<u8>> {
+    let private_key = get_private_key(private_key_path).await?;
+
+    let Some((&version, source_data)) = source_data.split_first() else {
+        bail!("invalid encrypted data: empty");
+    };
+
+    if version != ENCRYPTED_VERSION {
+        bail!("unsupported encrypted data version: {}", version);
+    }
+
+    let data_key_size = private_key.size();
+
+    if source_data.len() < data_key_size + NONCE_LEN + AES_256_GCM.tag_len() {
+        bail!("invalid encrypted data length: {}", source_data.len() + 1);
+    }
+
+    let (data_key_encrypted, source_data) = source_data.split_at(data_key_size);
+    let (nonce, data) = source_data.split_at(NONCE_LEN);
+
+    let data_key = private_key
+        .decrypt(Oaep::new::<Sha256>(), data_key_encrypted)
+        .map_err(|err| anyhow!("failed to decrypt: {:?}", err))?;
+
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow!("invalid encrypted data nonce"))?;
+
+    let mut data = data.to_vec();
+
+    let decrypted = get_data_key(&data_key)?
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| {
+            anyhow!("failed to decrypt: data was modified or encrypted for another key")
+        })?;
+
+    Ok(decrypted.to_vec())
+}
+
+fn get_data_key(data_key: &[u8]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, data_key)
+        .map_err(|_| anyhow!("invalid data key length: {}", data_key.len()))?;
+
+    Ok(LessSafeKey::new(key))
+}
+
+/// Encodes data from `encrypt` for text fields, like secret values of build requests.
+pub fn encode_encrypted(encrypted: &[u8]) -> String {
+    format!("{}{}", ENCRYPTED_PREFIX, encode_hex(encrypted))
+}
+
+pub fn decode_encrypted(encrypted: &str) -> Result<Vec<u8>> {
+    let Some(encoded) = encrypted.strip_prefix(ENCRYPTED_PREFIX) else {
+        bail!(
+            "invalid encrypted value encoding: missing '{}' prefix",
+            ENCRYPTED_PREFIX
+        );
+    };
+
+    decode_hex(encoded).map_err(|_| anyhow!("invalid encrypted value encoding"))
+}
+
+pub fn encode_signature(signature: &[u8]) -> String {
+    encode_hex(signature)
+}
+
+pub fn decode_signature(signature: &str) -> Result<Vec<u8>> {
+    decode_hex(signature).map_err(|err| anyhow!("invalid signature encoding: {}", err))
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len() * 2);
+
+    for byte in data {
+        encoded.push_str(&format!("{:02x}", byte));
+    }
+
+    encoded
+}
+
+fn decode_hex(data: &str) -> Result<Vec<u8>> {
+    if data.len() % 2 != 0 || !data.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("not hex");
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&data[index..index + 2], 16).map_err(|err| anyhow!(err)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    async fn generate_test_keys() -> (TempDir, PathBuf, PathBuf) {
+        let key_dir = tempfile::tempdir().unwrap();
+
+        let private_key_path = key_dir.path().join("private.pem");
+        let public_key_path = key_dir.path().join("public.pem");
+
+        generate_keys(
+            key_dir.path().to_path_buf(),
+            private_key_path.clone(),
+            public_key_path.clone(),
+        )
+        .await
+        .unwrap();
+
+        (key_dir, private_key_path, public_key_path)
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_round_trip() {
+        let (_key_dir, private_key_path, public_key_path) = generate_test_keys().await;
+
+        // Empty, shorter and longer than one RSA block
+        for data in [vec![], b"secret".to_vec(), vec![7u8; 4096]] {
+            let encrypted = encrypt(public_key_path.clone(), &data).await.unwrap();
+
+            assert_eq!(
+                decrypt(private_key_path.clone(), &encrypted).await.unwrap(),
+                data
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_is_randomized() {
+        let (_key_dir, _private_key_path, public_key_path) = generate_test_keys().await;
+
+        let first = encrypt(public_key_path.clone(), b"secret").await.unwrap();
+        let second = encrypt(public_key_path, b"secret").await.unwrap();
+
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_rejects_modified_data() {
+        let (_key_dir, private_key_path, public_key_path) = generate_test_keys().await;
+
+        let mut encrypted = encrypt(public_key_path, b"secret").await.unwrap();
+
+        let last = encrypted.len() - 1;
+
+        encrypted[last] ^= 1;
+
+        let err = decrypt(private_key_path.clone(), &encrypted)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("data was modified"));
+
+        let err = decrypt(private_key_path, &encrypted[..8])
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("invalid encrypted data length"));
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_rejects_other_key() {
+        let (_key_dir, _private_key_path, public_key_path) = generate_test_keys().await;
+        let (_other_key_dir, other_private_key_path, _) = generate_test_keys().await;
+
+        let encrypted = encrypt(public_key_path, b"secret").await.unwrap();
+
+        assert!(decrypt(other_private_key_path, &encrypted).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_missing_public_key() {
+        let key_dir = tempfile::tempdir().unwrap();
+
+        let err = encrypt(key_dir.path().join("public.pem"), b"secret")
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("run 'vorpal keys generate'"));
+    }
+
+    #[test]
+    fn test_encode_encrypted() {
+        let encoded = encode_encrypted(&[0, 1, 254, 255]);
+
+        assert_eq!(encoded, "vorpal-encrypted:0001feff");
+        assert_eq!(decode_encrypted(&encoded).unwrap(), vec![0, 1, 254, 255]);
+
+        assert!(decode_encrypted("0001feff").is_err());
+        assert!(decode_encrypted("vorpal-encrypted:0g").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::{bail, Error, Result};
+use std::{fmt, fs::read_to_string, path::Path, str::FromStr};
+use tonic::{Request, Status};
+
+/// Registry requests which require the auth token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RegistryAuthMode {
+    ReadWrite,
+    #[default]
+    WriteOnly,
+}
+
+impl fmt::Display for RegistryAuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadWrite => write!(f, "read-write"),
+            Self::WriteOnly => write!(f, "write-only"),
+        }
+    }
+}
+
+impl FromStr for RegistryAuthMode {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "read-write" => Ok(Self::ReadWrite),
+            "write-only" => Ok(Self::WriteOnly),
+            _ => bail!(
+                "unsupported registry auth mode: {} (expected read-write or write-only)",
+                value
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RegistryAuth {
+    mode: RegistryAuthMode,
+    token: String,
+}
+
+/// Compares without exiting on the first mismatched byte so timing does not leak the token.
+fn is_token_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl RegistryAuth {
+    /// Reads the token from `path`, ignoring surrounding whitespace.
+    pub fn from_file(path: &Path, mode: RegistryAuthMode) -> Result<Self> {
+        let token = read_to_string(path)?.trim().to_string();
+
+        if token.is_empty() {
+            bail!("registry auth token file is empty: {}", path.display());
+        }
+
+        Ok(Self { mode, token })
+    }
+
+    /// Requires a matching `authorization: Bearer <token>` header on writes, and on reads in
+    /// `read-write` mode.
+    pub fn check<T>(&self, request: &Request<T>, write: bool) -> Result<(), Status> {
+        if !write && self.mode == RegistryAuthMode::WriteOnly {
+            return Ok(());
+        }
+
+        let Some(header) = request.metadata().get("authorization") else {
+            return Err(Status::unauthenticated("missing registry token"));
+        };
+
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        if !is_token_equal(token.as_bytes(), self.token.as_bytes()) {
+            return Err(Status::unauthenticated("invalid registry token"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use tonic::Status;
+use vorpal_schema::{
+    get_artifact_cycle_error,
+    vorpal::{
+        artifact::v0::{ArtifactBuildRequest, ArtifactId},
+        registry::v0::{RegistryClosureManifest, RegistryClosureResponse},
+    },
+};
+
+use vorpal_store::chunks::ChunkBounds;
+
+use crate::{decode_manifest, RegistryBackend};
+
+// Pulls stored manifests decoded to the JSON they were pushed as
+struct ManifestReader<'a> {
+    backend: &'a dyn RegistryBackend,
+    chunk_bounds: ChunkBounds,
+    dictionary: &'a Option<Vec<u8>>,
+}
+
+impl ManifestReader<'_> {
+    async fn pull(&self, artifact_id: &ArtifactId) -> Result<Option<Vec<u8>>, Status> {
+        let Some(data) = self.backend.pull_manifest(artifact_id).await? else {
+            return Ok(None);
+        };
+
+        decode_manifest(self.backend, self.dictionary, data, self.chunk_bounds)
+            .await
+            .map(Some)
+    }
+}
+
+/// Collects the stored manifest of `artifact_id` and of every artifact it depends on, root first.
+///
+/// A missing root is `NotFound`. Missing, unreadable and cyclic dependencies are left out of the
+/// response with a warning, so clients can fetch what is missing one manifest at a time.
+pub async fn get_closure_response(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    artifact_id: &ArtifactId,
+    chunk_bounds: ChunkBounds,
+) -> Result<RegistryClosureResponse, Status> {
+    let reader = ManifestReader {
+        backend,
+        chunk_bounds,
+        dictionary,
+    };
+
+    let Some(data) = reader.pull(artifact_id).await? else {
+        return Err(Status::not_found(format!(
+            "artifact manifest not found: {}-{}",
+            artifact_id.name, artifact_id.hash
+        )));
+    };
+
+    let mut response = RegistryClosureResponse {
+        manifests: vec![],
+        warnings: vec![],
+    };
+
+    let mut path = vec![];
+    let mut visited = HashSet::new();
+
+    visited.insert(artifact_id.clone());
+
+    add_manifest(
+        &reader,
+        artifact_id,
+        data,
+        &mut path,
+        &mut visited,
+        &mut response,
+    )
+    .await?;
+
+    Ok(response)
+}
+
+async fn add_manifest(
+    reader: &ManifestReader<'_>,
+    artifact_id: &ArtifactId,
+    data: Vec<u8>,
+    path: &mut Vec<ArtifactId>,
+    visited: &mut HashSet<ArtifactId>,
+    response: &mut RegistryClosureResponse,
+) -> Result<(), Status> {
+    let manifest = serde_json::from_slice::<ArtifactBuildRequest>(&data);
+
+    response.manifests.push(RegistryClosureManifest {
+        artifact: Some(artifact_id.clone()),
+        data,
+    });
+
+    let artifact = match manifest {
+        Ok(ArtifactBuildRequest {
+            artifact: Some(artifact),
+            ..
+        }) => artifact,
+        Ok(_) => {
+            response.warnings.push(format!(
+                "artifact manifest is empty: {}-{}",
+                artifact_id.name, artifact_id.hash
+            ));
+
+            return Ok(());
+        }
+        Err(err) => {
+            response.warnings.push(format!(
+                "failed to parse manifest {}-{}: {}",
+                artifact_id.name, artifact_id.hash, err
+            ));
+
+            return Ok(());
+        }
+    };
+
+    path.push(artifact_id.clone());
+
+    for dependency in artifact.artifacts.iter() {
+        // Dependencies leading back to an artifact being walked would never finish
+
+        if let Some(index) = path.iter().position(|id| id == dependency) {
+            let mut cycle = path[index..].to_vec();
+
+            cycle.push(dependency.clone());
+
+            response.warnings.push(get_artifact_cycle_error(&cycle));
+
+            continue;
+        }
+
+        if !visited.insert(dependency.clone()) {
+            continue;
+        }
+
+        let Some(data) = reader.pull(dependency).await? else {
+            response.warnings.push(format!(
+                "artifact manifest not found: {}-{} (dependency of {})",
+                dependency.name, dependency.hash, artifact_id.name
+            ));
+
+            continue;
+        };
+
+        Box::pin(add_manifest(
+            reader, dependency, data, path, visited, response,
+        ))
+        .await?;
+    }
+
+    path.pop();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryRegistryBackend;
+    use std::time::SystemTime;
+    use vorpal_schema::vorpal::{artifact::v0::Artifact, registry::v0::RegistryKind};
+    use vorpal_store::dictionaries::{compress_dictionary, train_dictionary};
+
+    const TEST_MANIFESTS: &str = include_str!("../../store/tests/fixtures/manifests.jsonl");
+
+    fn get_artifact_id(name: &str, hash: char) -> ArtifactId {
+        ArtifactId {
+            hash: hash.to_string().repeat(64),
+            name: name.to_string(),
+        }
+    }
+
+    // Stores the manifest of `artifact_id` depending on `dependencies`
+    fn insert_manifest(
+        backend: &MemoryRegistryBackend,
+        artifact_id: &ArtifactId,
+        dependencies: &[&ArtifactId],
+    ) {
+        insert_manifest_dictionary(backend, artifact_id, dependencies, None);
+    }
+
+    // Stores the manifest of `artifact_id`, compressed with `dictionary` as the registry does
+    fn insert_manifest_dictionary(
+        backend: &MemoryRegistryBackend,
+        artifact_id: &ArtifactId,
+        dependencies: &[&ArtifactId],
+        dictionary: Option<&[u8]>,
+    ) {
+        let request = ArtifactBuildRequest {
+            artifact: Some(Artifact {
+                artifacts: dependencies.iter().map(|id| (*id).clone()).collect(),
+                name: artifact_id.name.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        backend.insert(
+            RegistryKind::ArtifactManifest,
+            &artifact_id.name,
+            &artifact_id.hash,
+            match dictionary {
+                Some(dictionary) => {
+                    compress_dictionary(&serde_json::to_vec(&request).unwrap(), dictionary)
+                        .unwrap()
+                        .unwrap()
+                }
+                None => serde_json::to_vec(&request).unwrap(),
+            },
+            SystemTime::now(),
+        );
+    }
+
+    fn get_names(response: &RegistryClosureResponse) -> Vec<String> {
+        response
+            .manifests
+            .iter()
+            .map(|manifest| manifest.artifact.as_ref().unwrap().name.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_closure_returns_root_first() {
+        let backend = MemoryRegistryBackend::default();
+
+        let app = get_artifact_id("app", 'a');
+        let lib = get_artifact_id("lib", 'b');
+        let toolchain = get_artifact_id("toolchain", 'c');
+
+        insert_manifest(&backend, &app, &[&lib, &toolchain]);
+        insert_manifest(&backend, &lib, &[&toolchain]);
+        insert_manifest(&backend, &toolchain, &[]);
+
+        let response = get_closure_response(&backend, &None, &app, ChunkBounds::default())
+            .await
+            .unwrap();
+
+        assert_eq!(get_names(&response), ["app", "lib", "toolchain"]);
+        assert!(response.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_closure_decodes_dictionary_manifests() {
+        let backend = MemoryRegistryBackend::default();
+
+        let samples = TEST_MANIFESTS
+            .lines()
+            .map(|line| line.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        let dictionary = train_dictionary(&samples, 4096).unwrap();
+
+        let app = get_artifact_id("app", 'a');
+        let lib = get_artifact_id("lib", 'b');
+
+        insert_manifest_dictionary(&backend, &app, &[&lib], Some(&dictionary));
+        insert_manifest(&backend, &lib, &[]);
+
+        let response =
+            get_closure_response(&backend, &Some(dictionary), &app, ChunkBounds::default())
+                .await
+                .unwrap();
+
+        assert_eq!(get_names(&response), ["app", "lib"]);
+        assert!(response.warnings.is_empty());
+
+        let manifest =
+            serde_json::from_slice::<ArtifactBuildRequest>(&response.manifests[0].data).unwrap();
+
+        assert_eq!(manifest.artifact.unwrap().artifacts, [lib]);
+    }
+
+    #[tokio::test]
+    async fn test_closure_warns_on_missing_dependency() {
+        let backend = MemoryRegistryBackend::default();
+
+        let app = get_artifact_id("app", 'a');
+        let missing = get_artifact_id("missing", 'b');
+
+        insert_manifest(&backend, &app, &[&missing]);
+
+        let response = get_closure_response(&backend, &None, &app, ChunkBounds::default())
+            .await
+            .unwrap();
+
+        assert_eq!(get_names(&response), ["app"]);
+        assert_eq!(
+            response.warnings,
+            [format!(
+                "artifact manifest not found: missing-{} (dependency of app)",
+                missing.hash
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closure_warns_on_cycle() {
+        let backend = MemoryRegistryBackend::default();
+
+        let a = get_artifact_id("a", 'a');
+        let b = get_artifact_id("b", 'b');
+        let c = get_artifact_id("c", 'c');
+
+        insert_manifest(&backend, &a, &[&b]);
+        insert_manifest(&backend, &b, &[&c]);
+        insert_manifest(&backend, &c, &[&a]);
+
+        let response = get_closure_response(&backend, &None, &a, ChunkBounds::default())
+            .await
+            .unwrap();
+
+        assert_eq!(get_names(&response), ["a", "b", "c"]);
+        assert_eq!(
+            response.warnings,
+            ["artifact dependency cycle: a -> b -> c -> a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closure_of_unknown_root_is_not_found() {
+        let backend = MemoryRegistryBackend::default();
+
+        let status = get_closure_response(
+            &backend,
+            &None,
+            &get_artifact_id("app", 'a'),
+            ChunkBounds::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+}
@@ -0,0 +1,584 @@
+use prost::Message;
+use std::{io::SeekFrom, mem::take, path::Path};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
+use tonic::Status;
+use tracing::warn;
+use vorpal_schema::vorpal::registry::v0::{
+    RegistryDeltaIndexResponse, RegistryDeltaRecipe, RegistryKind, RegistryRequest,
+};
+use vorpal_store::{
+    chunks::{ChunkBounds, CHUNK_CHANNEL_SIZE},
+    deltas::{get_delta_chunk_digest, DeltaChunker, DeltaEncoder},
+    dictionaries::{decode_archive, get_archive_dictionary_id, ArchiveDecoder},
+};
+
+use crate::{
+    get_dictionary,
+    spool::{Spool, SpoolData},
+    ObjectDigest, RegistryBackend,
+};
+
+// Leading bytes of an archive holding its zstd frame header, which names its dictionary
+const ARCHIVE_HEADER_SIZE: usize = 18;
+
+// Size of the reads from spooled archives being indexed
+const DELTA_READ_SIZE: usize = 1024 * 1024; // 1MB
+
+/// Chunk of a delta base, by its offset in the decoded base and its size.
+struct DeltaBaseChunk {
+    offset: u64,
+    size: usize,
+}
+
+/// Decoded data of a delta base, spooled to a file, with its chunks and their digests.
+struct DeltaBase {
+    chunks: Vec<DeltaBaseChunk>,
+    data: SpoolData,
+    index: Vec<String>,
+}
+
+/// Spools decoded data and splits it into chunks as it arrives.
+struct DeltaBaseWriter {
+    chunker: DeltaChunker,
+    chunks: Vec<DeltaBaseChunk>,
+    index: Vec<String>,
+    offset: u64,
+    spool: Spool,
+}
+
+impl DeltaBaseWriter {
+    async fn create() -> Result<Self, Status> {
+        Ok(Self {
+            chunker: DeltaChunker::default(),
+            chunks: vec![],
+            index: vec![],
+            offset: 0,
+            spool: Spool::create().await?,
+        })
+    }
+
+    fn add_chunks(&mut self, chunks: Vec<Vec<u8>>) {
+        for chunk in chunks {
+            self.chunks.push(DeltaBaseChunk {
+                offset: self.offset,
+                size: chunk.len(),
+            });
+
+            self.index.push(get_delta_chunk_digest(&chunk));
+
+            self.offset += chunk.len() as u64;
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+        self.spool.write(data).await?;
+
+        let chunks = self.chunker.update(data);
+
+        self.add_chunks(chunks);
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<DeltaBase, Status> {
+        let chunks = take(&mut self.chunker).finish();
+
+        self.add_chunks(chunks);
+
+        Ok(DeltaBase {
+            chunks: self.chunks,
+            data: self.spool.finish().await?,
+            index: self.index,
+        })
+    }
+}
+
+fn get_decode_status(request: &RegistryRequest, err: anyhow::Error) -> Status {
+    Status::internal(format!(
+        "failed to decode {}-{}: {:?}",
+        request.name, request.hash, err
+    ))
+}
+
+/// Pulls a stored archive and decodes it as it arrives into a spooled file, chunked the way
+/// deltas are, so large bases are never held in memory.
+async fn get_delta_base(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    request: &RegistryRequest,
+    chunk_bounds: ChunkBounds,
+) -> Result<DeltaBase, Status> {
+    let (tx, mut rx) = mpsc::channel(CHUNK_CHANNEL_SIZE);
+
+    let pull = backend.pull(request, tx, chunk_bounds);
+
+    let decode = async {
+        let mut base = DeltaBaseWriter::create().await?;
+        let mut decoder = None;
+        let mut header = vec![];
+
+        while let Some(response) = rx.recv().await {
+            let mut data = response?.data;
+
+            // The archive header names the dictionary the decoder needs
+
+            if decoder.is_none() {
+                header.extend(data);
+
+                if header.len() < ARCHIVE_HEADER_SIZE {
+                    continue;
+                }
+
+                data = take(&mut header);
+
+                decoder = Some(
+                    get_archive_decoder(backend, dictionary, &data, chunk_bounds)
+                        .await
+                        .map_err(|err| get_decode_status(request, err))?,
+                );
+            }
+
+            let Some(decoder) = decoder.as_mut() else {
+                continue;
+            };
+
+            let decoded = decoder
+                .write(&data)
+                .map_err(|err| get_decode_status(request, err))?;
+
+            base.write(&decoded).await?;
+        }
+
+        // Archives shorter than a header are decoded once the pull ends
+
+        let decoder = match decoder {
+            Some(decoder) => decoder,
+            None => {
+                let mut decoder = get_archive_decoder(backend, dictionary, &header, chunk_bounds)
+                    .await
+                    .map_err(|err| get_decode_status(request, err))?;
+
+                let decoded = decoder
+                    .write(&header)
+                    .map_err(|err| get_decode_status(request, err))?;
+
+                base.write(&decoded).await?;
+
+                decoder
+            }
+        };
+
+        let decoded = decoder
+            .finish()
+            .map_err(|err| get_decode_status(request, err))?;
+
+        base.write(&decoded).await?;
+
+        base.finish().await
+    };
+
+    let (pull, base) = tokio::join!(pull, decode);
+
+    pull?;
+
+    base
+}
+
+async fn get_archive_decoder(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    header: &[u8],
+    chunk_bounds: ChunkBounds,
+) -> anyhow::Result<ArchiveDecoder> {
+    let archive_dictionary = match get_archive_dictionary_id(header) {
+        Some(dictionary_id) => {
+            Some(get_dictionary(backend, dictionary, dictionary_id, chunk_bounds).await?)
+        }
+        None => None,
+    };
+
+    ArchiveDecoder::new(archive_dictionary.as_deref())
+}
+
+/// Returns the delta index of a pushed zstd archive, read from its spool file, recorded with
+/// the source so delta index requests don't decode it again.
+pub async fn get_push_delta_index(path: &Path) -> Result<Vec<String>, Status> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to open push file: {:?}", err)))?;
+
+    let mut chunker = DeltaChunker::default();
+    let mut decoder = ArchiveDecoder::new(None)
+        .map_err(|err| Status::internal(format!("failed to decode push: {:?}", err)))?;
+    let mut index = vec![];
+    let mut buffer = vec![0; DELTA_READ_SIZE];
+
+    loop {
+        let size = file
+            .read(&mut buffer)
+            .await
+            .map_err(|err| Status::internal(format!("failed to read push file: {:?}", err)))?;
+
+        let decoded = match size {
+            0 => break,
+            size => decoder.write(&buffer[..size]),
+        }
+        .map_err(|err| Status::internal(format!("failed to decode push: {:?}", err)))?;
+
+        index.extend(
+            chunker
+                .update(&decoded)
+                .iter()
+                .map(|c| get_delta_chunk_digest(c)),
+        );
+    }
+
+    let decoded = decoder
+        .finish()
+        .map_err(|err| Status::internal(format!("failed to decode push: {:?}", err)))?;
+
+    index.extend(
+        chunker
+            .update(&decoded)
+            .iter()
+            .map(|c| get_delta_chunk_digest(c)),
+    );
+    index.extend(chunker.finish().iter().map(|c| get_delta_chunk_digest(c)));
+
+    Ok(index)
+}
+
+/// Returns the chunks of the most recently pushed source named `name`, which clients push
+/// deltas against.
+///
+/// The index recorded at push time is served when there is one. Otherwise it is computed from
+/// the source and recorded for the next request.
+pub async fn get_delta_index_response(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    name: &str,
+    chunk_bounds: ChunkBounds,
+) -> Result<RegistryDeltaIndexResponse, Status> {
+    let Some(object) = backend.latest(RegistryKind::ArtifactSource, name).await? else {
+        return Err(Status::not_found(format!("no source pushed for {}", name)));
+    };
+
+    if let Some(chunks) = backend.pull_delta_index(&object.hash, name).await? {
+        return Ok(RegistryDeltaIndexResponse {
+            chunks,
+            hash: object.hash.to_string(),
+        });
+    }
+
+    let request = RegistryRequest {
+        accept_dictionary: false,
+        hash: object.hash.to_string(),
+        kind: RegistryKind::ArtifactSource as i32,
+        name: name.to_string(),
+    };
+
+    let base = get_delta_base(backend, dictionary, &request, chunk_bounds).await?;
+
+    push_delta_index(backend, &object.hash, name, &base.index).await;
+
+    Ok(RegistryDeltaIndexResponse {
+        chunks: base.index,
+        hash: object.hash.to_string(),
+    })
+}
+
+/// Archive re-created from a delta push, spooled like a whole push, with its delta index.
+pub struct DeltaArchive {
+    pub data: SpoolData,
+    pub index: Vec<String>,
+}
+
+/// Re-creates the archive of a delta push from its compressed recipe and the source it was
+/// made against, reading base chunks from the spooled base as the recipe refers to them.
+///
+/// A base that can not be read fails with `FailedPrecondition`, clients then push the whole
+/// archive instead.
+pub async fn get_delta_archive(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    base_request: &RegistryRequest,
+    recipe_data: &[u8],
+    chunk_bounds: ChunkBounds,
+) -> Result<DeltaArchive, Status> {
+    let base = get_delta_base(backend, dictionary, base_request, chunk_bounds)
+        .await
+        .map_err(|status| {
+            Status::failed_precondition(format!(
+                "delta base {}-{} unavailable: {}",
+                base_request.name,
+                base_request.hash,
+                status.message()
+            ))
+        })?;
+
+    let recipe = decode_archive(recipe_data, None)
+        .map_err(|err| Status::invalid_argument(format!("failed to decode delta: {:?}", err)))?;
+
+    let recipe = RegistryDeltaRecipe::decode(recipe.as_slice())
+        .map_err(|err| Status::invalid_argument(format!("failed to parse delta: {:?}", err)))?;
+
+    let mut base_file = File::open(base.data.file.path())
+        .await
+        .map_err(|err| Status::internal(format!("failed to open delta base: {:?}", err)))?;
+
+    let mut chunker = DeltaChunker::default();
+    let mut encoder = DeltaEncoder::new()
+        .map_err(|err| Status::internal(format!("failed to encode delta archive: {:?}", err)))?;
+    let mut index = vec![];
+    let mut spool = Spool::create().await?;
+
+    for entry in recipe.entries {
+        let data = match entry.data.is_empty() {
+            false => entry.data,
+            true => {
+                let Some(chunk) = base.chunks.get(entry.base_chunk as usize) else {
+                    return Err(Status::invalid_argument(format!(
+                        "delta refers to missing base chunk {}",
+                        entry.base_chunk
+                    )));
+                };
+
+                let mut data = vec![0; chunk.size];
+
+                base_file
+                    .seek(SeekFrom::Start(chunk.offset))
+                    .await
+                    .map_err(|err| {
+                        Status::internal(format!("failed to read delta base: {:?}", err))
+                    })?;
+
+                base_file.read_exact(&mut data).await.map_err(|err| {
+                    Status::internal(format!("failed to read delta base: {:?}", err))
+                })?;
+
+                data
+            }
+        };
+
+        index.extend(
+            chunker
+                .update(&data)
+                .iter()
+                .map(|c| get_delta_chunk_digest(c)),
+        );
+
+        let encoded = encoder.write(&data).map_err(|err| {
+            Status::internal(format!("failed to encode delta archive: {:?}", err))
+        })?;
+
+        spool.write(&encoded).await?;
+    }
+
+    index.extend(chunker.finish().iter().map(|c| get_delta_chunk_digest(c)));
+
+    let encoded = encoder
+        .finish()
+        .map_err(|err| Status::internal(format!("failed to encode delta archive: {:?}", err)))?;
+
+    spool.write(&encoded).await?;
+
+    Ok(DeltaArchive {
+        data: spool.finish().await?,
+        index,
+    })
+}
+
+/// Records the delta index of a pushed source, logging failures since pushes don't depend on it.
+pub async fn push_delta_index(
+    backend: &dyn RegistryBackend,
+    hash: &ObjectDigest,
+    name: &str,
+    index: &[String],
+) {
+    if let Err(status) = backend.push_delta_index(hash, name, index).await {
+        warn!(
+            "failed to record delta index of {}-{}: {}",
+            name,
+            hash,
+            status.message()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryRegistryBackend;
+    use std::time::SystemTime;
+    use tokio::fs::{create_dir_all, read};
+    use tonic::Code;
+    use vorpal_schema::vorpal::registry::v0::RegistryDeltaEntry;
+    use vorpal_store::{
+        deltas::{encode_delta, get_delta_entries, get_delta_index, DeltaEntry},
+        paths::get_sandbox_dir_path,
+    };
+
+    const SOURCE_NAME: &str = "example";
+
+    // Deterministic data with repeats, so chunk boundaries fall at varying offsets
+    fn get_test_data(size: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+
+                (state >> 16) as u8 % 16
+            })
+            .collect()
+    }
+
+    fn get_source_request(hash: &str) -> RegistryRequest {
+        RegistryRequest {
+            accept_dictionary: false,
+            hash: hash.to_string(),
+            kind: RegistryKind::ArtifactSource as i32,
+            name: SOURCE_NAME.to_string(),
+        }
+    }
+
+    fn get_recipe(base: &[u8], data: &[u8]) -> Vec<u8> {
+        let recipe = RegistryDeltaRecipe {
+            entries: get_delta_entries(&get_delta_index(base), data)
+                .into_iter()
+                .map(|entry| match entry {
+                    DeltaEntry::Base(base_chunk) => RegistryDeltaEntry {
+                        base_chunk,
+                        data: vec![],
+                    },
+                    DeltaEntry::Data(data) => RegistryDeltaEntry {
+                        base_chunk: 0,
+                        data: data.to_vec(),
+                    },
+                })
+                .collect(),
+        };
+
+        encode_delta(&recipe.encode_to_vec()).unwrap()
+    }
+
+    async fn get_backend(base: &[u8]) -> MemoryRegistryBackend {
+        create_dir_all(get_sandbox_dir_path()).await.unwrap();
+
+        let backend = MemoryRegistryBackend::default();
+
+        backend.insert(
+            RegistryKind::ArtifactSource,
+            SOURCE_NAME,
+            &"a".repeat(64),
+            encode_delta(base).unwrap(),
+            SystemTime::now(),
+        );
+
+        backend
+    }
+
+    #[tokio::test]
+    async fn test_get_delta_archive() {
+        let base = get_test_data(2 * 1024 * 1024, 1);
+
+        let mut data = base.clone();
+
+        data.splice(1_000_000..1_000_100, get_test_data(5000, 2));
+        data.extend(get_test_data(100_000, 3));
+
+        let backend = get_backend(&base).await;
+
+        let archive = get_delta_archive(
+            &backend,
+            &None,
+            &get_source_request(&"a".repeat(64)),
+            &get_recipe(&base, &data),
+            ChunkBounds::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            read(archive.data.file.path()).await.unwrap(),
+            encode_delta(&data).unwrap()
+        );
+
+        assert_eq!(archive.index, get_delta_index(&data));
+    }
+
+    #[tokio::test]
+    async fn test_get_delta_archive_missing_base() {
+        let base = get_test_data(1024 * 1024, 1);
+
+        let backend = get_backend(&base).await;
+
+        let status = get_delta_archive(
+            &backend,
+            &None,
+            &get_source_request(&"b".repeat(64)),
+            &get_recipe(&base, &base),
+            ChunkBounds::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn test_get_delta_index_response() {
+        let base = get_test_data(1024 * 1024, 1);
+
+        let backend = get_backend(&base).await;
+
+        // Sources without a recorded index are indexed once, then served from the record
+
+        let response =
+            get_delta_index_response(&backend, &None, SOURCE_NAME, ChunkBounds::default())
+                .await
+                .unwrap();
+
+        assert_eq!(response.hash, "a".repeat(64));
+        assert_eq!(response.chunks, get_delta_index(&base));
+        assert_eq!(
+            backend.delta_index(SOURCE_NAME, &"a".repeat(64)),
+            Some(get_delta_index(&base))
+        );
+
+        let hash = ObjectDigest::parse(RegistryKind::ArtifactSource, &"a".repeat(64)).unwrap();
+
+        backend
+            .push_delta_index(&hash, SOURCE_NAME, &["recorded".to_string()])
+            .await
+            .unwrap();
+
+        let response =
+            get_delta_index_response(&backend, &None, SOURCE_NAME, ChunkBounds::default())
+                .await
+                .unwrap();
+
+        assert_eq!(response.chunks, vec!["recorded".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_push_delta_index() {
+        create_dir_all(get_sandbox_dir_path()).await.unwrap();
+
+        let data = get_test_data(3 * 1024 * 1024, 4);
+
+        let spool = Spool::from_data(&encode_delta(&data).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_push_delta_index(spool.file.path()).await.unwrap(),
+            get_delta_index(&data)
+        );
+    }
+}
@@ -7,14 +7,25 @@ use reqwest::{
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::{read, write},
+    fs::{read, write, File},
+    io::AsyncReadExt,
     sync::mpsc,
 };
 use tonic::{async_trait, Status};
 use tracing::info;
-use vorpal_schema::vorpal::registry::v0::{RegistryKind, RegistryPullResponse, RegistryRequest};
+use vorpal_schema::vorpal::registry::v0::{
+    RegistryKind, RegistryListRequest, RegistryListResponse, RegistryPullResponse, RegistryRequest,
+};
+use vorpal_store::{
+    chunks::ChunkBounds,
+    digests::ArchiveDigest,
+    http::{get_http_client, get_http_client_builder},
+};
 
-use crate::{PushMetadata, RegistryBackend, RegistryError, DEFAULT_GRPC_CHUNK_SIZE};
+use crate::{
+    exists_concurrent, get_request_digest, send_chunks, ObjectDigest, PushMetadata,
+    RegistryBackend, RegistryError, RegistryObject,
+};
 
 const API_VERSION: &str = "6.0-preview.1";
 const DEFAULT_GHA_CHUNK_SIZE: usize = 32 * 1024 * 1024; // 32MB
@@ -75,7 +86,7 @@ impl CacheClient {
             HeaderValue::from_str(&format!("Bearer {token}"))?,
         );
 
-        let client = Client::builder()
+        let client = get_http_client_builder()?
             .user_agent("vorpal/github-actions-cache")
             .default_headers(headers)
             .build()?;
@@ -140,15 +151,29 @@ impl CacheClient {
         Ok(response)
     }
 
-    pub async fn save_cache(&self, cache_id: u64, buffer: &[u8], chunk_size: usize) -> Result<()> {
-        let buffer_size = buffer.len() as u64;
+    /// Uploads the `buffer_size` bytes of file `path` in chunks of `chunk_size`, reading one
+    /// chunk at a time.
+    pub async fn save_cache(
+        &self,
+        cache_id: u64,
+        path: &Path,
+        buffer_size: u64,
+        chunk_size: usize,
+    ) -> Result<()> {
         let url = format!("{}_apis/artifactcache/caches/{}", self.base_url, cache_id);
 
         info!("Uploading cache buffer with size: {} bytes", buffer_size);
 
-        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
-            let chunk_len = chunk.len() as u64;
-            let chunk_start = (i * chunk_size) as u64;
+        let mut file = File::open(path).await?;
+        let mut chunk_start = 0;
+
+        while chunk_start < buffer_size {
+            let chunk_len = (buffer_size - chunk_start).min(chunk_size as u64);
+
+            let mut chunk = vec![0; chunk_len as usize];
+
+            file.read_exact(&mut chunk).await?;
+
             let chunk_end = chunk_start + chunk_len - 1;
             let chunk_range = format!("bytes {}-{}/{}", chunk_start, chunk_end, buffer_size);
 
@@ -159,7 +184,7 @@ impl CacheClient {
                 .patch(&url)
                 .header(CONTENT_TYPE, "application/octet-stream")
                 .header(CONTENT_RANGE, &chunk_range)
-                .body(chunk.to_vec())
+                .body(chunk)
                 .send()
                 .await?
                 .error_for_status()?;
@@ -169,6 +194,8 @@ impl CacheClient {
                 chunk_range,
                 response.status()
             );
+
+            chunk_start += chunk_len;
         }
 
         // Commit the cache
@@ -187,13 +214,16 @@ impl CacheClient {
     }
 }
 
-fn get_cache_key(name: &str, hash: &str, kind: RegistryKind) -> Result<String> {
+fn get_cache_key(name: &str, hash: &ObjectDigest, kind: RegistryKind) -> Result<String> {
     let prefix = "vorpal-registry";
     let affix = format!("{}-{}", name, hash);
 
     match kind {
         RegistryKind::Artifact => Ok(format!("{}-{}-artifact", prefix, affix)),
         RegistryKind::ArtifactSource => Ok(format!("{}-{}-source", prefix, affix)),
+        RegistryKind::ArtifactStep => Ok(format!("{}-{}-step", prefix, affix)),
+        RegistryKind::ArtifactManifest => Ok(format!("{}-{}-manifest", prefix, affix)),
+        RegistryKind::ArtifactProvenance => Ok(format!("{}-{}-provenance", prefix, affix)),
         _ => Err(anyhow::anyhow!("unsupported store kind")),
     }
 }
@@ -214,8 +244,25 @@ impl GhaRegistryBackend {
 
 #[async_trait]
 impl RegistryBackend for GhaRegistryBackend {
+    async fn check(&self) -> Result<(), Status> {
+        // A lookup of a missing key fails when the runtime token is invalid
+
+        self.cache_client
+            .get_cache_entry("vorpal-registry-check", "check")
+            .await
+            .map_err(|err| Status::unavailable(format!("failed to reach cache: {:?}", err)))?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, _object: &RegistryObject) -> Result<(), Status> {
+        Err(Status::unimplemented(
+            "gha backend does not support deleting",
+        ))
+    }
+
     async fn exists(&self, request: &RegistryRequest) -> Result<(), Status> {
-        let cache_key = get_cache_key(&request.name, &request.hash, request.kind())
+        let cache_key = get_cache_key(&request.name, &get_request_digest(request)?, request.kind())
             .expect("failed to get cache key");
         let cache_key_file = format!("/tmp/{}", cache_key);
         let cache_key_file_path = Path::new(&cache_key_file);
@@ -243,12 +290,33 @@ impl RegistryBackend for GhaRegistryBackend {
         Ok(())
     }
 
+    async fn exists_batch(&self, requests: &[RegistryRequest]) -> Result<Vec<bool>, Status> {
+        exists_concurrent(self, requests).await
+    }
+
+    async fn list(&self, _request: &RegistryListRequest) -> Result<RegistryListResponse, Status> {
+        // The cache service only supports lookups by key
+
+        Err(Status::unimplemented(
+            "gha backend does not support listing",
+        ))
+    }
+
+    async fn list_objects(&self) -> Result<Vec<RegistryObject>, Status> {
+        // Entries are evicted by the cache service itself
+
+        Err(Status::unimplemented(
+            "gha backend does not support pruning",
+        ))
+    }
+
     async fn pull(
         &self,
         request: &RegistryRequest,
         tx: mpsc::Sender<Result<RegistryPullResponse, Status>>,
+        chunk_bounds: ChunkBounds,
     ) -> Result<(), Status> {
-        let cache_key = get_cache_key(&request.name, &request.hash, request.kind())
+        let cache_key = get_cache_key(&request.name, &get_request_digest(request)?, request.kind())
             .expect("failed to get cache key");
         let cache_key_file = format!("/tmp/{}", cache_key);
         let cache_key_file_path = Path::new(&cache_key_file);
@@ -258,15 +326,7 @@ impl RegistryBackend for GhaRegistryBackend {
                 .await
                 .map_err(|err| Status::internal(err.to_string()))?;
 
-            for chunk in data.chunks(DEFAULT_GRPC_CHUNK_SIZE) {
-                tx.send(Ok(RegistryPullResponse {
-                    data: chunk.to_vec(),
-                }))
-                .await
-                .map_err(|err| {
-                    Status::internal(format!("failed to send store chunk: {:?}", err))
-                })?;
-            }
+            send_chunks(&data, chunk_bounds, &tx).await?;
 
             return Ok(());
         }
@@ -286,19 +346,16 @@ impl RegistryBackend for GhaRegistryBackend {
             cache_entry.archive_location
         );
 
-        let response = reqwest::get(&cache_entry.archive_location)
+        let response = get_http_client()
+            .map_err(|err| Status::internal(format!("failed to create http client: {:?}", err)))?
+            .get(&cache_entry.archive_location)
+            .send()
             .await
             .expect("failed to get");
 
         let response_bytes = response.bytes().await.expect("failed to read response");
 
-        for chunk in response_bytes.chunks(DEFAULT_GRPC_CHUNK_SIZE) {
-            tx.send(Ok(RegistryPullResponse {
-                data: chunk.to_vec(),
-            }))
-            .await
-            .map_err(|err| Status::internal(format!("failed to send store chunk: {:?}", err)))?;
-        }
+        send_chunks(&response_bytes, chunk_bounds, &tx).await?;
 
         write(&cache_key_file_path, &response_bytes)
             .await
@@ -307,22 +364,33 @@ impl RegistryBackend for GhaRegistryBackend {
         Ok(())
     }
 
+    async fn pull_digest(
+        &self,
+        _request: &RegistryRequest,
+    ) -> Result<Option<ArchiveDigest>, Status> {
+        // Cache entries only hold the data, clients skip verification without a digest
+
+        Ok(None)
+    }
+
     async fn push(&self, metadata: PushMetadata) -> Result<(), Status> {
         let PushMetadata {
             data_kind,
+            data_path,
+            data_size,
             hash,
             name,
-            data,
+            ..
         } = metadata;
 
         let cache_key = get_cache_key(&name, &hash, data_kind)
             .map_err(|err| Status::internal(format!("failed to get cache key: {:?}", err)))?;
 
-        let cache_size = data.len() as u64;
+        let cache_size = data_size;
 
         let cache_reserve = &self
             .cache_client
-            .reserve_cache(cache_key, hash.clone(), Some(cache_size))
+            .reserve_cache(cache_key, hash.to_string(), Some(cache_size))
             .await
             .map_err(|e| {
                 Status::internal(format!("failed to reserve cache: {:?}", e.to_string()))
@@ -333,13 +401,22 @@ impl RegistryBackend for GhaRegistryBackend {
         }
 
         self.cache_client
-            .save_cache(cache_reserve.cache_id, &data, DEFAULT_GHA_CHUNK_SIZE)
+            .save_cache(
+                cache_reserve.cache_id,
+                &data_path,
+                data_size,
+                DEFAULT_GHA_CHUNK_SIZE,
+            )
             .await
             .map_err(|e| Status::internal(format!("failed to save cache: {:?}", e.to_string())))?;
 
         Ok(())
     }
 
+    fn name(&self) -> &'static str {
+        "gha"
+    }
+
     fn box_clone(&self) -> Box<dyn RegistryBackend> {
         Box::new(self.clone())
     }
@@ -1,27 +1,77 @@
 use anyhow::Result;
+use futures_util::future::join_all;
 use rsa::{
     pss::{Signature, VerifyingKey},
     sha2::Sha256,
-    signature::Verifier,
+    signature::DigestVerifier,
+};
+use spool::Spool;
+use std::{
+    fmt,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::{Instant, SystemTime},
 };
 use tokio::sync::mpsc;
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::{transport::Server, Request, Response, Status, Streaming};
-use tracing::error;
-use vorpal_notary::get_public_key;
-use vorpal_schema::vorpal::registry::v0::{
-    registry_service_server::{RegistryService, RegistryServiceServer},
-    RegistryKind::{self, UnknownStoreKind},
-    RegistryPullResponse, RegistryPushRequest, RegistryRequest, RegistryResponse,
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::{
+    metadata::MetadataValue, transport::Server, Code::NotFound, Request, Response, Status,
+    Streaming,
+};
+use tracing::{debug, error, info, warn};
+use vorpal_notary::{get_key_fingerprint, get_public_key};
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactId,
+    registry::v0::{
+        registry_service_server::{RegistryService, RegistryServiceServer},
+        RegistryClosureRequest, RegistryClosureResponse, RegistryCompression,
+        RegistryDeltaIndexRequest, RegistryDeltaIndexResponse, RegistryExistsBatchRequest,
+        RegistryExistsBatchResponse, RegistryInfoRequest, RegistryInfoResponse,
+        RegistryKind::{self, UnknownStoreKind},
+        RegistryListRequest, RegistryListResponse, RegistryPruneRequest, RegistryPruneResponse,
+        RegistryPullResponse, RegistryPushRequest, RegistryRequest, RegistryResponse,
+        RegistrySearchArtifact, RegistrySearchRequest, RegistrySearchResponse,
+    },
+};
+use vorpal_store::{
+    archives::ArchiveCompression,
+    chunks::{ChunkBounds, ChunkSizer, ChunkSummary, CHUNK_CHANNEL_SIZE, CHUNK_MESSAGE_SIZE_LIMIT},
+    dictionaries::{
+        compress_archive_dictionary, compress_dictionary, decode_archive,
+        decompress_archive_dictionary, get_archive_dictionary_id, get_dictionary_id,
+        DICTIONARY_ARCHIVE_SIZE_MAX,
+    },
+    digests::{ArchiveDigest, ArtifactDigest, SourceDigest, StepDigest},
+    paths::get_public_key_path,
 };
-use vorpal_store::paths::get_public_key_path;
 
+pub mod auth;
+pub mod closure;
+pub mod delta;
 pub mod gha;
 pub mod local;
+#[cfg(test)]
+mod memory;
+pub mod oci;
+pub mod prune;
 pub mod s3;
+pub mod spool;
+pub mod stats;
+pub use auth::{RegistryAuth, RegistryAuthMode};
 pub use gha::GhaRegistryBackend;
 pub use local::LocalRegistryBackend;
+pub use oci::OciRegistryBackend;
 pub use s3::S3RegistryBackend;
+pub use stats::RegistryStats;
+
+// Artifacts returned per list page
+pub const LIST_PAGE_SIZE_DEFAULT: u32 = 100;
+pub const LIST_PAGE_SIZE_MAX: u32 = 1000;
+
+// Requests per exists batch, and lookups in flight for backends checking concurrently
+pub const EXISTS_BATCH_SIZE_MAX: usize = 1000;
+pub const EXISTS_BATCH_CONCURRENCY: usize = 16;
 
 #[derive(thiserror::Error, Debug)]
 pub enum RegistryError {
@@ -30,15 +80,82 @@ pub enum RegistryError {
 
     #[error("failed to create GHA cache client: {0}")]
     FailedToCreateGhaClient(String),
+
+    #[error("missing oci repository")]
+    MissingOciRepo,
+
+    #[error("invalid oci repository: {0}")]
+    InvalidOciRepo(String),
+
+    #[error("failed to create OCI registry client: {0}")]
+    FailedToCreateOciClient(String),
 }
 
-const DEFAULT_GRPC_CHUNK_SIZE: usize = 2 * 1024 * 1024; // 2MB
+/// Digest of a stored object, typed by the kind of object so backends only build keys from
+/// validated digests. Manifests and provenance are addressed by the digest of their artifact.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ObjectDigest {
+    Artifact(ArtifactDigest),
+    Dictionary(u32),
+    Source(SourceDigest),
+    Step(StepDigest),
+}
+
+impl ObjectDigest {
+    /// Parses `hash` as the digest of an object of `kind`.
+    pub fn parse(kind: RegistryKind, hash: &str) -> Result<Self, Status> {
+        let digest = match kind {
+            RegistryKind::Artifact
+            | RegistryKind::ArtifactManifest
+            | RegistryKind::ArtifactProvenance => hash.parse().map(Self::Artifact),
+            RegistryKind::ArtifactSource => hash.parse().map(Self::Source),
+            RegistryKind::ArtifactStep => hash.parse().map(Self::Step),
+            RegistryKind::Dictionary => hash
+                .parse()
+                .map(Self::Dictionary)
+                .map_err(|_| anyhow::anyhow!("invalid dictionary id: {:?}", hash)),
+            _ => return Err(Status::invalid_argument("unsupported store kind")),
+        };
+
+        digest.map_err(|err| Status::invalid_argument(err.to_string()))
+    }
+}
+
+impl fmt::Display for ObjectDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Artifact(digest) => digest.fmt(f),
+            Self::Dictionary(id) => id.fmt(f),
+            Self::Source(digest) => digest.fmt(f),
+            Self::Step(digest) => digest.fmt(f),
+        }
+    }
+}
+
+/// Parses the digest of the object `request` addresses.
+pub fn get_request_digest(request: &RegistryRequest) -> Result<ObjectDigest, Status> {
+    ObjectDigest::parse(request.kind(), &request.hash)
+}
 
 pub struct PushMetadata {
+    compression: RegistryCompression,
+    data_digest: ArchiveDigest,
     data_kind: RegistryKind,
-    hash: String,
+    data_path: PathBuf,
+    data_signature: Vec<u8>,
+    data_size: u64,
+    hash: ObjectDigest,
     name: String,
-    data: Vec<u8>,
+}
+
+/// A stored object as listed by a backend for pruning.
+#[derive(Clone, Debug)]
+pub struct RegistryObject {
+    hash: ObjectDigest,
+    kind: RegistryKind,
+    modified: SystemTime,
+    name: String,
+    size: u64,
 }
 
 #[derive(Clone, Debug, Default, PartialEq)]
@@ -47,23 +164,156 @@ pub enum RegistryServerBackend {
     Unknown,
     GHA,
     Local,
+    OCI,
     S3,
 }
 
 #[tonic::async_trait]
 pub trait RegistryBackend: Send + Sync + 'static {
+    /// Checks the backend can serve requests, used for the health status at startup.
+    async fn check(&self) -> Result<(), Status>;
+
+    /// Deletes a stored object and its recorded digest.
+    async fn delete(&self, object: &RegistryObject) -> Result<(), Status>;
+
     async fn exists(&self, request: &RegistryRequest) -> Result<(), Status>;
+
+    /// Returns whether each of `requests` exists, in order. Checks one request at a time by
+    /// default, backends with remote lookups check them concurrently.
+    async fn exists_batch(&self, requests: &[RegistryRequest]) -> Result<Vec<bool>, Status> {
+        let mut exists = vec![];
+
+        for request in requests {
+            exists.push(get_exists(self.exists(request).await)?);
+        }
+
+        Ok(exists)
+    }
+
+    /// Lists one page of stored artifacts, ordered by store name, after `page_token`.
+    async fn list(&self, request: &RegistryListRequest) -> Result<RegistryListResponse, Status>;
+
+    /// Lists every stored artifact, manifest, source and step object with its size and push time.
+    async fn list_objects(&self) -> Result<Vec<RegistryObject>, Status>;
+
+    /// Name of the backend, used to label its metrics.
+    fn name(&self) -> &'static str;
+
     async fn pull(
         &self,
         request: &RegistryRequest,
         tx: mpsc::Sender<Result<RegistryPullResponse, Status>>,
+        chunk_bounds: ChunkBounds,
     ) -> Result<(), Status>;
+
+    /// Returns the stored manifest of `artifact_id`, or `None` when there is none. Only backends
+    /// reading objects directly support dependency closures.
+    async fn pull_manifest(&self, _artifact_id: &ArtifactId) -> Result<Option<Vec<u8>>, Status> {
+        Err(Status::unimplemented(format!(
+            "closures are not supported by the {} backend",
+            self.name()
+        )))
+    }
+
+    /// Returns the digest of the stored data recorded at push time, if the backend has one.
+    async fn pull_digest(&self, request: &RegistryRequest)
+        -> Result<Option<ArchiveDigest>, Status>;
+
     async fn push(&self, metadata: PushMetadata) -> Result<(), Status>;
 
+    /// Returns the most recently pushed object of `kind` named `name`, the base of delta pushes.
+    /// Looked up in `list_objects` by default, backends that can list by name override it.
+    async fn latest(
+        &self,
+        kind: RegistryKind,
+        name: &str,
+    ) -> Result<Option<RegistryObject>, Status> {
+        Ok(get_latest_object(self.list_objects().await?, kind, name))
+    }
+
+    /// Returns the chunk index of a source recorded at push time (see `push_delta_index`), or
+    /// `None` when there is none and it is computed from the source instead.
+    async fn pull_delta_index(
+        &self,
+        _hash: &ObjectDigest,
+        _name: &str,
+    ) -> Result<Option<Vec<String>>, Status> {
+        Ok(None)
+    }
+
+    /// Records the chunk index of a pushed source, served to clients pushing deltas against it.
+    /// Backends without a place for it keep nothing.
+    async fn push_delta_index(
+        &self,
+        _hash: &ObjectDigest,
+        _name: &str,
+        _index: &[String],
+    ) -> Result<(), Status> {
+        Ok(())
+    }
+
+    /// Returns the stored artifacts with `term` in their name. Only backends keeping an index of
+    /// their manifests support searching.
+    async fn search(&self, _term: &str) -> Result<Vec<RegistrySearchArtifact>, Status> {
+        Err(Status::unimplemented(format!(
+            "search is not supported by the {} backend",
+            self.name()
+        )))
+    }
+
     /// Return a new `Box<dyn RegistryBackend>` cloned from `self`.
     fn box_clone(&self) -> Box<dyn RegistryBackend>;
 }
 
+/// Returns the most recently pushed of `objects` of `kind` named `name`.
+pub(crate) fn get_latest_object(
+    objects: Vec<RegistryObject>,
+    kind: RegistryKind,
+    name: &str,
+) -> Option<RegistryObject> {
+    objects
+        .into_iter()
+        .filter(|object| object.kind == kind && object.name == name)
+        .max_by_key(|object| object.modified)
+}
+
+/// Maps an `exists` result to whether the object exists, keeping errors other than not found.
+pub fn get_exists(result: Result<(), Status>) -> Result<bool, Status> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(status) if status.code() == NotFound => Ok(false),
+        Err(status) => Err(status),
+    }
+}
+
+/// Checks `requests` in waves of `EXISTS_BATCH_CONCURRENCY` concurrent lookups, keeping
+/// their order.
+pub async fn exists_concurrent(
+    backend: &dyn RegistryBackend,
+    requests: &[RegistryRequest],
+) -> Result<Vec<bool>, Status> {
+    let mut exists = vec![];
+
+    for chunk in requests.chunks(EXISTS_BATCH_CONCURRENCY) {
+        let results = join_all(chunk.iter().map(|request| backend.exists(request))).await;
+
+        for result in results {
+            exists.push(get_exists(result)?);
+        }
+    }
+
+    Ok(exists)
+}
+
+pub fn get_archive_compression(compression: RegistryCompression) -> ArchiveCompression {
+    match compression {
+        RegistryCompression::Bzip2 => ArchiveCompression::Bzip2,
+        RegistryCompression::Gzip => ArchiveCompression::Gzip,
+        RegistryCompression::Xz => ArchiveCompression::Xz,
+        RegistryCompression::Zstd => ArchiveCompression::default(),
+    }
+}
+
 impl Clone for Box<dyn RegistryBackend> {
     fn clone(&self) -> Self {
         self.box_clone()
@@ -71,23 +321,319 @@ impl Clone for Box<dyn RegistryBackend> {
 }
 
 pub struct RegistryServer {
+    pub auth: Option<RegistryAuth>,
     pub backend: Box<dyn RegistryBackend>,
+    pub chunk_bounds: ChunkBounds,
+    pub dictionary: Option<Vec<u8>>,
+    pub stats: Arc<RegistryStats>,
 }
 
 impl RegistryServer {
-    pub fn new(backend: Box<dyn RegistryBackend>) -> Self {
-        Self { backend }
+    pub fn new(backend: Box<dyn RegistryBackend>, chunk_bounds: ChunkBounds) -> Self {
+        let stats = Arc::new(RegistryStats::new(backend.name()));
+
+        Self {
+            auth: None,
+            backend,
+            chunk_bounds,
+            dictionary: None,
+            stats,
+        }
+    }
+
+    /// Runs the backend self-check for the health status.
+    pub async fn check(&self) -> Result<(), Status> {
+        self.backend.check().await
+    }
+
+    /// Requires the token of `auth` on pushes and prunes, and on all requests in `read-write`
+    /// mode.
+    pub fn with_auth(mut self, auth: RegistryAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    fn authorize<T>(&self, request: &Request<T>, write: bool) -> Result<(), Status> {
+        match &self.auth {
+            Some(auth) => auth.check(request, write),
+            None => Ok(()),
+        }
+    }
+
+    /// Compresses small pushed archives and manifests with `dictionary` and stores it in the
+    /// backend so clients can fetch it by id.
+    pub async fn with_dictionary(mut self, dictionary: Vec<u8>) -> Result<Self> {
+        let dictionary_id = get_dictionary_id(&dictionary)?;
+
+        let data = Spool::from_data(&dictionary)
+            .await
+            .map_err(|status| anyhow::anyhow!("failed to spool dictionary: {}", status))?;
+
+        self.backend
+            .push(PushMetadata {
+                compression: RegistryCompression::Zstd,
+                data_digest: ArchiveDigest::from_hasher(data.hasher),
+                data_kind: RegistryKind::Dictionary,
+                data_path: data.file.path().to_path_buf(),
+                data_signature: vec![],
+                data_size: data.size,
+                hash: ObjectDigest::Dictionary(dictionary_id),
+                name: "dictionary".to_string(),
+            })
+            .await
+            .map_err(|status| anyhow::anyhow!("failed to push dictionary: {}", status))?;
+
+        info!("registry dictionary: {}", dictionary_id);
+
+        self.dictionary = Some(dictionary);
+
+        Ok(self)
+    }
+}
+
+/// Sends a single chunk to a pull stream and records its throughput.
+pub async fn send_chunk(
+    data: Vec<u8>,
+    sizer: &mut ChunkSizer,
+    tx: &mpsc::Sender<Result<RegistryPullResponse, Status>>,
+) -> Result<(), Status> {
+    let chunk_len = data.len();
+    let chunk_buffered = tx.capacity() > 0;
+    let chunk_started = Instant::now();
+
+    let response = RegistryPullResponse {
+        data,
+        data_digest: String::new(),
+    };
+
+    if let Err(err) = tx.send(Ok(response)).await {
+        sizer.record_error();
+
+        return Err(Status::internal(format!(
+            "failed to send store chunk: {:?}",
+            err
+        )));
+    }
+
+    match chunk_buffered {
+        true => sizer.record_buffered(chunk_len),
+        false => sizer.record(chunk_len, chunk_started.elapsed()),
+    }
+
+    Ok(())
+}
+
+/// Sends `data` to a pull stream in adaptively sized chunks.
+pub async fn send_chunks(
+    data: &[u8],
+    chunk_bounds: ChunkBounds,
+    tx: &mpsc::Sender<Result<RegistryPullResponse, Status>>,
+) -> Result<ChunkSummary, Status> {
+    let mut sizer = ChunkSizer::new(chunk_bounds);
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let end = (offset + sizer.size()).min(data.len());
+
+        send_chunk(data[offset..end].to_vec(), &mut sizer, tx).await?;
+
+        offset = end;
+    }
+
+    let summary = sizer.summary();
+
+    debug!("pull transfer: {}", summary);
+
+    Ok(summary)
+}
+
+/// Sends the digest of the data that follows as the first message of a pull stream.
+pub async fn send_digest(
+    digest: &ArchiveDigest,
+    tx: &mpsc::Sender<Result<RegistryPullResponse, Status>>,
+) -> Result<(), Status> {
+    let response = RegistryPullResponse {
+        data: vec![],
+        data_digest: digest.to_string(),
+    };
+
+    tx.send(Ok(response))
+        .await
+        .map_err(|err| Status::internal(format!("failed to send store digest: {:?}", err)))
+}
+
+/// Pulls from `backend`, sending the recorded digest first when there is one.
+async fn pull_with_digest(
+    backend: &dyn RegistryBackend,
+    request: &RegistryRequest,
+    tx: &mpsc::Sender<Result<RegistryPullResponse, Status>>,
+    chunk_bounds: ChunkBounds,
+) -> Result<(), Status> {
+    if let Some(digest) = backend.pull_digest(request).await? {
+        send_digest(&digest, tx).await?;
     }
+
+    backend.pull(request, tx.clone(), chunk_bounds).await
+}
+
+/// Collects a full pull from `backend` into memory.
+pub(crate) async fn pull_data(
+    backend: &dyn RegistryBackend,
+    request: &RegistryRequest,
+    chunk_bounds: ChunkBounds,
+) -> Result<Vec<u8>, Status> {
+    let (tx, mut rx) = mpsc::channel(CHUNK_CHANNEL_SIZE);
+
+    let pull = backend.pull(request, tx, chunk_bounds);
+
+    let collect = async {
+        let mut data = vec![];
+
+        while let Some(response) = rx.recv().await {
+            data.extend(response?.data);
+        }
+
+        Ok::<_, Status>(data)
+    };
+
+    let (pull, data) = tokio::join!(pull, collect);
+
+    pull?;
+
+    data
+}
+
+pub(crate) async fn get_dictionary(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    dictionary_id: u32,
+    chunk_bounds: ChunkBounds,
+) -> Result<Vec<u8>, Status> {
+    if let Some(dictionary) = dictionary {
+        if get_dictionary_id(dictionary).ok() == Some(dictionary_id) {
+            return Ok(dictionary.clone());
+        }
+    }
+
+    let request = RegistryRequest {
+        accept_dictionary: false,
+        hash: dictionary_id.to_string(),
+        kind: RegistryKind::Dictionary as i32,
+        name: "dictionary".to_string(),
+    };
+
+    pull_data(backend, &request, chunk_bounds).await
+}
+
+/// Returns stored manifest `data` as it was pushed, decoding manifests the registry compressed
+/// with a dictionary.
+pub(crate) async fn decode_manifest(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    data: Vec<u8>,
+    chunk_bounds: ChunkBounds,
+) -> Result<Vec<u8>, Status> {
+    let Some(dictionary_id) = get_archive_dictionary_id(&data) else {
+        return Ok(data);
+    };
+
+    let dictionary = get_dictionary(backend, dictionary, dictionary_id, chunk_bounds).await?;
+
+    decode_archive(&data, Some(&dictionary))
+        .map_err(|err| Status::internal(format!("failed to decode manifest: {:?}", err)))
+}
+
+/// Pulls from `backend` for clients without dictionary support.
+///
+/// Archives compressed with a dictionary are re-compressed without one before sending, and
+/// manifests compressed with one are decoded. All other data streams through unchanged.
+async fn pull_without_dictionary(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    request: &RegistryRequest,
+    tx: &mpsc::Sender<Result<RegistryPullResponse, Status>>,
+    chunk_bounds: ChunkBounds,
+) -> Result<(), Status> {
+    let (backend_tx, mut backend_rx) = mpsc::channel(CHUNK_CHANNEL_SIZE);
+
+    let mut digest = backend.pull_digest(request).await?;
+
+    let pull = backend.pull(request, backend_tx, chunk_bounds);
+
+    let forward = async {
+        let mut data = vec![];
+        let mut dictionary_id = None;
+
+        while let Some(response) = backend_rx.recv().await {
+            let response = response?;
+
+            if data.is_empty() && dictionary_id.is_none() {
+                dictionary_id = get_archive_dictionary_id(&response.data);
+
+                // Archives sent unchanged keep the digest recorded at push time
+
+                if dictionary_id.is_none() {
+                    if let Some(digest) = digest.take() {
+                        send_digest(&digest, tx).await?;
+                    }
+                }
+            }
+
+            if dictionary_id.is_none() {
+                tx.send(Ok(response))
+                    .await
+                    .map_err(|err| Status::internal(format!("failed to send chunk: {:?}", err)))?;
+
+                continue;
+            }
+
+            data.extend(response.data);
+        }
+
+        Ok::<_, Status>(dictionary_id.map(|id| (id, data)))
+    };
+
+    let (pull, forward) = tokio::join!(pull, forward);
+
+    pull?;
+
+    let Some((dictionary_id, data)) = forward? else {
+        return Ok(());
+    };
+
+    // Check the stored archive before re-compressing it, the client verifies the new digest
+
+    if let Some(digest) = &digest {
+        digest
+            .verify(&data)
+            .map_err(|err| Status::data_loss(format!("stored archive is corrupt: {}", err)))?;
+    }
+
+    let dictionary = get_dictionary(backend, dictionary, dictionary_id, chunk_bounds).await?;
+
+    let data = match request.kind() {
+        RegistryKind::ArtifactManifest => decode_archive(&data, Some(&dictionary)),
+        _ => decompress_archive_dictionary(&data, &dictionary),
+    }
+    .map_err(|err| Status::internal(format!("failed to decompress archive: {:?}", err)))?;
+
+    send_digest(&ArchiveDigest::from_data(&data), tx).await?;
+
+    send_chunks(&data, chunk_bounds, tx).await?;
+
+    Ok(())
 }
 
 #[tonic::async_trait]
 impl RegistryService for RegistryServer {
-    type PullStream = ReceiverStream<Result<RegistryPullResponse, Status>>;
+    type PullStream = Pin<Box<dyn Stream<Item = Result<RegistryPullResponse, Status>> + Send>>;
 
     async fn exists(
         &self,
         request: Request<RegistryRequest>,
     ) -> Result<Response<RegistryResponse>, Status> {
+        self.authorize(&request, false)?;
+
         let request = request.into_inner();
 
         if request.hash.is_empty() {
@@ -98,18 +644,68 @@ impl RegistryService for RegistryServer {
             return Err(Status::invalid_argument("missing store name"));
         }
 
-        self.backend.exists(&request).await?;
+        let exists = self.backend.exists(&request).await;
+
+        self.stats.record_exists(&get_exists(exists.clone()));
+
+        exists?;
 
         Ok(Response::new(RegistryResponse { success: true }))
     }
 
+    async fn exists_batch(
+        &self,
+        request: Request<RegistryExistsBatchRequest>,
+    ) -> Result<Response<RegistryExistsBatchResponse>, Status> {
+        self.authorize(&request, false)?;
+
+        let request = request.into_inner();
+
+        if request.requests.len() > EXISTS_BATCH_SIZE_MAX {
+            return Err(Status::invalid_argument(format!(
+                "exists batch exceeds {} requests",
+                EXISTS_BATCH_SIZE_MAX
+            )));
+        }
+
+        for request in request.requests.iter() {
+            if request.hash.is_empty() {
+                return Err(Status::invalid_argument("missing store id"));
+            }
+
+            if request.name.is_empty() {
+                return Err(Status::invalid_argument("missing store name"));
+            }
+        }
+
+        let exists = match self.backend.exists_batch(&request.requests).await {
+            Ok(exists) => exists,
+            Err(status) => {
+                self.stats.record_exists(&Err(status.clone()));
+
+                return Err(status);
+            }
+        };
+
+        for found in exists.iter() {
+            self.stats.record_exists(&Ok(*found));
+        }
+
+        Ok(Response::new(RegistryExistsBatchResponse { exists }))
+    }
+
     async fn pull(
         &self,
         request: Request<RegistryRequest>,
     ) -> Result<Response<Self::PullStream>, Status> {
-        let (tx, rx) = mpsc::channel(100);
+        self.authorize(&request, false)?;
+
+        let (tx, rx) = mpsc::channel(CHUNK_CHANNEL_SIZE);
 
         let backend = self.backend.clone();
+        let chunk_bounds = self.chunk_bounds;
+        let dictionary = self.dictionary.clone();
+        let request_kind = request.get_ref().kind();
 
         tokio::spawn(async move {
             let request = request.into_inner();
@@ -125,39 +721,97 @@ impl RegistryService for RegistryServer {
                 return;
             }
 
-            if let Err(err) = backend.pull(&request, tx.clone()).await {
+            // Manifests are JSON to every client, whatever it accepts
+
+            let pull = match request.kind() {
+                RegistryKind::ArtifactManifest => {
+                    pull_without_dictionary(
+                        backend.as_ref(),
+                        &dictionary,
+                        &request,
+                        &tx,
+                        chunk_bounds,
+                    )
+                    .await
+                }
+
+                RegistryKind::Artifact | RegistryKind::ArtifactSource
+                    if !request.accept_dictionary =>
+                {
+                    pull_without_dictionary(
+                        backend.as_ref(),
+                        &dictionary,
+                        &request,
+                        &tx,
+                        chunk_bounds,
+                    )
+                    .await
+                }
+
+                _ => pull_with_digest(backend.as_ref(), &request, &tx, chunk_bounds).await,
+            };
+
+            if let Err(err) = pull {
                 if let Err(err) = tx.send(Err(err)).await {
                     error!("failed to send store error: {:?}", err);
                 }
             }
         });
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+        // Count bytes as they are sent, the guard ends the active pull when the stream drops
+
+        let stats_guard = self.stats.start_pull(request_kind);
+
+        let stream = ReceiverStream::new(rx).map(move |response| {
+            if let Ok(response) = &response {
+                stats_guard.record_bytes(response.data.len());
+            }
+
+            response
+        });
+
+        Ok(Response::new(Box::pin(stream)))
     }
 
     async fn push(
         &self,
         request: Request<Streaming<RegistryPushRequest>>,
     ) -> Result<Response<RegistryResponse>, Status> {
-        let mut data: Vec<u8> = vec![];
+        // Checked before reading the stream so unauthorized pushes store nothing
+
+        self.authorize(&request, true)?;
+
+        let push_started = Instant::now();
+
+        // Chunks are spooled to a file as they arrive, so pushes are never held in memory
+
+        let mut spool = Spool::create().await?;
+        let mut data_compression = RegistryCompression::Zstd;
+        let mut data_delta_base = String::new();
         let mut data_hash = None;
         let mut data_kind = UnknownStoreKind;
         let mut data_name = None;
         let mut data_signature = vec![];
+        let mut data_signature_fingerprint = String::new();
         let mut stream = request.into_inner();
 
         while let Some(result) = stream.next().await {
             let result = result.map_err(|err| Status::internal(err.to_string()))?;
 
-            data.extend_from_slice(&result.data);
+            spool.write(&result.data).await?;
 
+            data_compression = result.compression();
+            data_delta_base = result.delta_base;
             data_hash = Some(result.hash);
             data_kind = RegistryKind::try_from(result.kind).unwrap_or(UnknownStoreKind);
             data_name = Some(result.name);
             data_signature = result.data_signature;
+            data_signature_fingerprint = result.data_signature_fingerprint;
         }
 
-        if data.is_empty() {
+        let mut data = spool.finish().await?;
+
+        if data.size == 0 {
             return Err(Status::invalid_argument("missing `data` field"));
         }
 
@@ -173,42 +827,355 @@ impl RegistryService for RegistryServer {
             return Err(Status::invalid_argument("missing `kind` field"));
         }
 
+        let data_hash = ObjectDigest::parse(data_kind, &data_hash)?;
+
         if data_signature.is_empty() {
             return Err(Status::invalid_argument("missing `data_signature` field"));
         }
 
+        // Delta pushes carry a recipe, the signature covers the archive it re-creates
+
+        let delta = !data_delta_base.is_empty();
+
+        let mut data_delta_index = None;
+
+        if delta {
+            if data_kind != RegistryKind::ArtifactSource
+                || data_compression != RegistryCompression::Zstd
+            {
+                return Err(Status::invalid_argument(
+                    "delta pushes are only supported for zstd sources",
+                ));
+            }
+
+            let base_request = RegistryRequest {
+                accept_dictionary: false,
+                hash: data_delta_base.clone(),
+                kind: RegistryKind::ArtifactSource as i32,
+                name: data_name.clone(),
+            };
+
+            let recipe_size = data.size;
+
+            let archive = delta::get_delta_archive(
+                self.backend.as_ref(),
+                &self.dictionary,
+                &base_request,
+                &data.read().await?,
+                self.chunk_bounds,
+            )
+            .await?;
+
+            data = archive.data;
+            data_delta_index = Some(archive.index);
+
+            info!(
+                "delta push: {}-{} from {} ({} of {} bytes, {:.0}% saved)",
+                data_name,
+                data_hash,
+                data_delta_base,
+                recipe_size,
+                data.size,
+                (1.0 - recipe_size as f64 / data.size.max(1) as f64) * 100.0
+            );
+        }
+
         let public_key_path = get_public_key_path();
 
         let public_key = get_public_key(public_key_path).await.map_err(|err| {
             Status::internal(format!("failed to get public key: {:?}", err.to_string()))
         })?;
 
-        let data_signature = Signature::try_from(data_signature.as_slice())
+        let signature = Signature::try_from(data_signature.as_slice())
             .map_err(|err| Status::internal(format!("failed to parse signature: {:?}", err)))?;
 
+        let public_key_fingerprint = get_key_fingerprint(&public_key)
+            .map_err(|err| Status::internal(format!("failed to get public key: {:?}", err)))?;
+
         let verifying_key = VerifyingKey::<Sha256>::new(public_key);
 
-        if let Err(msg) = verifying_key.verify(&data, &data_signature) {
-            return Err(Status::invalid_argument(format!(
-                "invalid data signature: {:?}",
-                msg
-            )));
+        if let Err(msg) = verifying_key.verify_digest(data.hasher.clone(), &signature) {
+            // e.g. a client with another zstd version, it pushes the whole archive instead
+
+            if delta {
+                return Err(Status::failed_precondition(format!(
+                    "delta of {}-{} does not re-create the signed archive",
+                    data_name, data_hash
+                )));
+            }
+
+            self.stats.record_signature_failure();
+
+            return Err(get_signature_status(
+                &msg.to_string(),
+                &data_signature_fingerprint,
+                &public_key_fingerprint,
+            ));
+        }
+
+        // Archives must match their declared compression so pulls can rely on it
+
+        if [
+            RegistryKind::Artifact,
+            RegistryKind::ArtifactSource,
+            RegistryKind::ArtifactStep,
+        ]
+        .contains(&data_kind)
+        {
+            let compression = get_archive_compression(data_compression);
+
+            if !ArchiveCompression::from_data(&data.prefix)
+                .is_some_and(|c| c.is_format(&compression))
+            {
+                return Err(Status::invalid_argument(format!(
+                    "data is not a {} archive",
+                    compression
+                )));
+            }
+        }
+
+        // Sources are indexed for delta pushes against them, before dictionary compression
+
+        if data_kind == RegistryKind::ArtifactSource
+            && data_compression == RegistryCompression::Zstd
+            && data_delta_index.is_none()
+        {
+            match delta::get_push_delta_index(data.file.path()).await {
+                Ok(index) => data_delta_index = Some(index),
+                Err(status) => warn!("failed to index source: {}", status.message()),
+            }
         }
 
+        // Compress small archives and manifests with the dictionary, signatures cover the pushed
+        // data only
+
+        if let Some(dictionary) = &self.dictionary {
+            if data.size <= DICTIONARY_ARCHIVE_SIZE_MAX as u64 {
+                let compressed = match data_kind {
+                    RegistryKind::Artifact | RegistryKind::ArtifactSource
+                        if data_compression == RegistryCompression::Zstd =>
+                    {
+                        compress_archive_dictionary(&data.read().await?, dictionary)
+                    }
+
+                    RegistryKind::ArtifactManifest => {
+                        compress_dictionary(&data.read().await?, dictionary)
+                    }
+
+                    _ => Ok(None),
+                };
+
+                match compressed {
+                    Ok(Some(compressed)) => {
+                        debug!(
+                            "dictionary compressed: {} -> {} bytes",
+                            data.size,
+                            compressed.len()
+                        );
+
+                        data = Spool::from_data(&compressed).await?;
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!("failed to compress with dictionary: {:?}", err),
+                }
+            }
+        }
+
+        self.stats
+            .record_push(data_kind, data.size as usize, push_started.elapsed());
+
         let hash = data_hash;
         let name = data_name;
 
+        // Record the digest of the stored bytes so clients can verify pulls
+
+        let data_digest = ArchiveDigest::from_hasher(data.hasher);
+
+        // Backends move or copy the file, whatever is left is removed with the spool
+
         self.backend
             .push(PushMetadata {
+                compression: data_compression,
+                data_digest,
                 data_kind,
-                hash,
-                name,
-                data,
+                data_path: data.file.path().to_path_buf(),
+                data_signature,
+                data_size: data.size,
+                hash: hash.clone(),
+                name: name.clone(),
             })
             .await?;
 
+        if let Some(index) = data_delta_index {
+            delta::push_delta_index(self.backend.as_ref(), &hash, &name, &index).await;
+        }
+
         Ok(Response::new(RegistryResponse { success: true }))
     }
+
+    async fn info(
+        &self,
+        _request: Request<RegistryInfoRequest>,
+    ) -> Result<Response<RegistryInfoResponse>, Status> {
+        Ok(Response::new(RegistryInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+    }
+
+    async fn list(
+        &self,
+        request: Request<RegistryListRequest>,
+    ) -> Result<Response<RegistryListResponse>, Status> {
+        self.authorize(&request, false)?;
+
+        let mut request = request.into_inner();
+
+        request.page_size = match request.page_size {
+            0 => LIST_PAGE_SIZE_DEFAULT,
+            size => size.min(LIST_PAGE_SIZE_MAX),
+        };
+
+        let response = self.backend.list(&request).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn search(
+        &self,
+        request: Request<RegistrySearchRequest>,
+    ) -> Result<Response<RegistrySearchResponse>, Status> {
+        self.authorize(&request, false)?;
+
+        let request = request.into_inner();
+
+        let artifacts = self.backend.search(&request.term).await?;
+
+        Ok(Response::new(RegistrySearchResponse { artifacts }))
+    }
+
+    async fn delta_index(
+        &self,
+        request: Request<RegistryDeltaIndexRequest>,
+    ) -> Result<Response<RegistryDeltaIndexResponse>, Status> {
+        self.authorize(&request, false)?;
+
+        let request = request.into_inner();
+
+        if request.kind() != RegistryKind::ArtifactSource {
+            return Err(Status::invalid_argument(
+                "deltas are only supported for sources",
+            ));
+        }
+
+        if request.name.is_empty() {
+            return Err(Status::invalid_argument("missing store name"));
+        }
+
+        let response = delta::get_delta_index_response(
+            self.backend.as_ref(),
+            &self.dictionary,
+            &request.name,
+            self.chunk_bounds,
+        )
+        .await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn closure(
+        &self,
+        request: Request<RegistryClosureRequest>,
+    ) -> Result<Response<RegistryClosureResponse>, Status> {
+        self.authorize(&request, false)?;
+
+        let request = request.into_inner();
+
+        if request.hash.is_empty() {
+            return Err(Status::invalid_argument("missing `hash` field"));
+        }
+
+        if request.name.is_empty() {
+            return Err(Status::invalid_argument("missing `name` field"));
+        }
+
+        let artifact_id = ArtifactId {
+            hash: request.hash,
+            name: request.name,
+        };
+
+        let response = closure::get_closure_response(
+            self.backend.as_ref(),
+            &self.dictionary,
+            &artifact_id,
+            self.chunk_bounds,
+        )
+        .await?;
+
+        if !response.warnings.is_empty() {
+            warn!(
+                "partial closure of {}-{}: {}",
+                artifact_id.name,
+                artifact_id.hash,
+                response.warnings.join(", ")
+            );
+        }
+
+        Ok(Response::new(response))
+    }
+
+    async fn prune(
+        &self,
+        request: Request<RegistryPruneRequest>,
+    ) -> Result<Response<RegistryPruneResponse>, Status> {
+        self.authorize(&request, true)?;
+
+        let request = request.into_inner();
+
+        let response = prune::prune(
+            self.backend.as_ref(),
+            &self.dictionary,
+            &request,
+            self.chunk_bounds,
+        )
+        .await?;
+
+        Ok(Response::new(response))
+    }
+}
+
+/// Returns the rejection of a push whose signature does not verify, with the fingerprint of the
+/// key it was verified against and of the key it was signed with, when the client reported it.
+///
+/// Fingerprints are also set as `vorpal-signing-key-fingerprint` and
+/// `vorpal-verifying-key-fingerprint` metadata for clients matching on them.
+fn get_signature_status(
+    error: &str,
+    signing_key_fingerprint: &str,
+    verifying_key_fingerprint: &str,
+) -> Status {
+    let signing_key = match signing_key_fingerprint {
+        "" => "unknown",
+        fingerprint => fingerprint,
+    };
+
+    let mut status = Status::invalid_argument(format!(
+        "invalid data signature: {} (signed with key {}, verified with key {})",
+        error, signing_key, verifying_key_fingerprint
+    ));
+
+    for (key, value) in [
+        ("vorpal-signing-key-fingerprint", signing_key),
+        (
+            "vorpal-verifying-key-fingerprint",
+            verifying_key_fingerprint,
+        ),
+    ] {
+        if let Ok(value) = MetadataValue::try_from(value) {
+            status.metadata_mut().insert(key, value);
+        }
+    }
+
+    status
 }
 
 pub async fn listen(port: u16) -> Result<()> {
@@ -224,8 +1191,11 @@ pub async fn listen(port: u16) -> Result<()> {
         .parse()
         .map_err(|err| anyhow::anyhow!("failed to parse address: {:?}", err))?;
 
-    let registry_service =
-        RegistryServiceServer::new(RegistryServer::new(Box::new(LocalRegistryBackend)));
+    let registry_service = RegistryServiceServer::new(RegistryServer::new(
+        Box::new(LocalRegistryBackend::new()?),
+        ChunkBounds::default(),
+    ))
+    .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT);
 
     Server::builder()
         .add_service(registry_service)
@@ -235,3 +1205,41 @@ pub async fn listen(port: u16) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_digest_parse() {
+        let hash = "a".repeat(64);
+
+        assert!(matches!(
+            ObjectDigest::parse(RegistryKind::ArtifactManifest, &hash),
+            Ok(ObjectDigest::Artifact(_))
+        ));
+        assert!(matches!(
+            ObjectDigest::parse(RegistryKind::ArtifactSource, &hash),
+            Ok(ObjectDigest::Source(_))
+        ));
+        assert!(matches!(
+            ObjectDigest::parse(RegistryKind::Dictionary, "42"),
+            Ok(ObjectDigest::Dictionary(42))
+        ));
+
+        let hash_digest = ObjectDigest::parse(RegistryKind::ArtifactStep, &hash).unwrap();
+
+        assert_eq!(hash_digest.to_string(), hash);
+
+        for (kind, hash) in [
+            (RegistryKind::Artifact, "../escape"),
+            (RegistryKind::ArtifactSource, "abc"),
+            (RegistryKind::Dictionary, &hash),
+            (RegistryKind::UnknownStoreKind, &hash),
+        ] {
+            let status = ObjectDigest::parse(kind, hash).unwrap_err();
+
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+    }
+}
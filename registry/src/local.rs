@@ -1,38 +1,400 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::{
-    fs::{read, write},
-    sync::mpsc,
+    fs::{metadata, read, read_dir, read_to_string, remove_file, rename, write},
+    sync::{mpsc, Mutex},
 };
 use tonic::{async_trait, Status};
-use vorpal_schema::vorpal::registry::v0::{RegistryKind, RegistryPullResponse, RegistryRequest};
-use vorpal_store::paths::{get_artifact_archive_path, get_source_archive_path, set_timestamps};
+use tracing::warn;
+use vorpal_schema::vorpal::{
+    artifact::v0::{ArtifactBuildRequest, ArtifactId},
+    registry::v0::{
+        RegistryKind, RegistryListRequest, RegistryListResponse, RegistryPullResponse,
+        RegistryRequest, RegistrySearchArtifact,
+    },
+};
+use vorpal_store::{
+    chunks::ChunkBounds,
+    dictionaries::{decode_archive, get_archive_dictionary_id},
+    digests::{ArchiveDigest, ArtifactDigest},
+    paths::{
+        copy_file, get_artifact_archive_path, get_artifact_index_path, get_artifact_manifest_path,
+        get_artifact_provenance_path, get_dictionary_path, get_source_archive_path,
+        get_step_archive_path, get_store_dir_path, set_timestamps,
+    },
+};
 
-use crate::{PushMetadata, RegistryBackend, RegistryError, DEFAULT_GRPC_CHUNK_SIZE};
+use crate::{
+    get_latest_object, get_request_digest, send_chunks, ObjectDigest, PushMetadata,
+    RegistryBackend, RegistryError, RegistryObject,
+};
 
 #[derive(Clone, Debug)]
-pub struct LocalRegistryBackend;
+pub struct LocalRegistryBackend {
+    // Serializes updates of the artifact index between concurrent pushes and deletes
+    index_lock: Arc<Mutex<()>>,
+}
 
 impl LocalRegistryBackend {
     pub fn new() -> Result<Self, RegistryError> {
-        Ok(Self)
+        Ok(Self {
+            index_lock: Arc::new(Mutex::new(())),
+        })
     }
 }
 
 fn get_registry_path(
     kind: RegistryKind,
-    hash: &str,
+    hash: &ObjectDigest,
     name: &str,
-) -> Result<std::path::PathBuf, Status> {
-    match kind {
-        RegistryKind::Artifact => Ok(get_artifact_archive_path(hash, name)),
-        RegistryKind::ArtifactSource => Ok(get_source_archive_path(hash, name)),
+) -> Result<PathBuf, Status> {
+    match (kind, hash) {
+        (RegistryKind::Artifact, ObjectDigest::Artifact(digest)) => {
+            Ok(get_artifact_archive_path(digest, name))
+        }
+        (RegistryKind::ArtifactSource, ObjectDigest::Source(digest)) => {
+            Ok(get_source_archive_path(digest, name))
+        }
+        (RegistryKind::ArtifactStep, ObjectDigest::Step(digest)) => {
+            Ok(get_step_archive_path(digest, name))
+        }
+        (RegistryKind::ArtifactManifest, ObjectDigest::Artifact(digest)) => {
+            Ok(get_artifact_manifest_path(digest, name))
+        }
+        (RegistryKind::ArtifactProvenance, ObjectDigest::Artifact(digest)) => {
+            Ok(get_artifact_provenance_path(digest, name))
+        }
+        (RegistryKind::Dictionary, ObjectDigest::Dictionary(id)) => {
+            Ok(get_dictionary_path(&id.to_string()))
+        }
         _ => Err(Status::invalid_argument("unsupported store kind")),
     }
 }
 
+// Digests of stored data are kept next to it with this suffix
+const DIGEST_SUFFIX: &str = "sha256";
+
+// Chunk indexes of stored sources are kept next to them with this suffix
+const DELTA_INDEX_SUFFIX: &str = "chunks";
+
+fn get_sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut sidecar_path = path.as_os_str().to_owned();
+
+    sidecar_path.push(format!(".{}", suffix));
+
+    PathBuf::from(sidecar_path)
+}
+
+fn get_digest_path(path: &Path) -> PathBuf {
+    get_sidecar_path(path, DIGEST_SUFFIX)
+}
+
+fn get_delta_index_path(hash: &ObjectDigest, name: &str) -> Result<PathBuf, Status> {
+    let path = get_registry_path(RegistryKind::ArtifactSource, hash, name)?;
+
+    Ok(get_sidecar_path(&path, DELTA_INDEX_SUFFIX))
+}
+
+// Suffix of artifact archives in the store directory
+const ARTIFACT_ARCHIVE_SUFFIX: &str = ".artifact.tar.zst";
+
+// Suffixes of prunable objects in the store directory, unpacked artifacts are never listed
+const OBJECT_SUFFIXES: [(&str, RegistryKind); 5] = [
+    (ARTIFACT_ARCHIVE_SUFFIX, RegistryKind::Artifact),
+    (".artifact.json", RegistryKind::ArtifactManifest),
+    (".provenance", RegistryKind::ArtifactProvenance),
+    (".source.tar.zst", RegistryKind::ArtifactSource),
+    (".step.tar.zst", RegistryKind::ArtifactStep),
+];
+
+fn get_object_kind(file_name: &str) -> Option<(RegistryKind, &str, &str)> {
+    for (suffix, kind) in OBJECT_SUFFIXES {
+        if let Some(store_name) = file_name.strip_suffix(suffix) {
+            let (name, hash) = store_name.rsplit_once('-')?;
+
+            return Some((kind, name, hash));
+        }
+    }
+
+    None
+}
+
+/// An artifact of a stored manifest, as recorded in the artifact index.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LocalIndexArtifact {
+    created_at: u64,
+    name: String,
+    systems: Vec<i32>,
+}
+
+// Artifacts of stored manifests by digest
+type LocalIndex = BTreeMap<String, LocalIndexArtifact>;
+
+fn get_index_artifact(data: &[u8], name: &str, created_at: SystemTime) -> LocalIndexArtifact {
+    // Manifests are build requests, the systems are kept empty for any other data
+
+    let systems = serde_json::from_slice::<ArtifactBuildRequest>(data)
+        .ok()
+        .and_then(|request| request.artifact)
+        .map(|artifact| artifact.systems)
+        .unwrap_or_default();
+
+    LocalIndexArtifact {
+        created_at: created_at
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default(),
+        name: name.to_string(),
+        systems,
+    }
+}
+
+// Reads a stored manifest, decoding manifests the registry compressed with a dictionary
+async fn read_manifest(path: &Path) -> Result<Vec<u8>, Status> {
+    let data = read(path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to read manifest: {:?}", err)))?;
+
+    let Some(dictionary_id) = get_archive_dictionary_id(&data) else {
+        return Ok(data);
+    };
+
+    let dictionary = read(get_dictionary_path(&dictionary_id.to_string()))
+        .await
+        .map_err(|err| Status::internal(format!("failed to read dictionary: {:?}", err)))?;
+
+    decode_archive(&data, Some(&dictionary))
+        .map_err(|err| Status::internal(format!("failed to decode manifest: {:?}", err)))
+}
+
+/// Builds the artifact index from the manifests in the store directory.
+async fn get_index_from_manifests() -> Result<LocalIndex, Status> {
+    let mut entries = read_dir(get_store_dir_path())
+        .await
+        .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?;
+
+    let mut index = LocalIndex::new();
+
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?
+    {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        let Some((RegistryKind::ArtifactManifest, name, hash)) = get_object_kind(&file_name) else {
+            continue;
+        };
+
+        let data = read_manifest(&entry.path()).await?;
+
+        // Manifests have sanitized timestamps, the digest file records the push time
+
+        let created_at = metadata(get_digest_path(&entry.path()))
+            .await
+            .and_then(|digest| digest.modified())
+            .unwrap_or(UNIX_EPOCH);
+
+        index.insert(
+            hash.to_string(),
+            get_index_artifact(&data, name, created_at),
+        );
+    }
+
+    Ok(index)
+}
+
+/// Reads the artifact index, rebuilding it from the stored manifests when it is missing or
+/// can not be parsed.
+async fn read_index() -> Result<LocalIndex, Status> {
+    let index_path = get_artifact_index_path();
+
+    match read(&index_path).await {
+        Ok(data) => match serde_json::from_slice::<LocalIndex>(&data) {
+            Ok(index) => return Ok(index),
+            Err(err) => warn!("rebuilding corrupt artifact index: {}", err),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => warn!("rebuilding unreadable artifact index: {}", err),
+    }
+
+    let index = get_index_from_manifests().await?;
+
+    write_index(&index).await?;
+
+    Ok(index)
+}
+
+async fn write_index(index: &LocalIndex) -> Result<(), Status> {
+    let index_path = get_artifact_index_path();
+
+    let data = serde_json::to_vec(index)
+        .map_err(|err| Status::internal(format!("failed to serialize index: {:?}", err)))?;
+
+    // Replace the index in one rename so readers never see a partial file
+
+    let mut index_temp_path = index_path.as_os_str().to_owned();
+
+    index_temp_path.push(".tmp");
+
+    write(&index_temp_path, &data)
+        .await
+        .map_err(|err| Status::internal(format!("failed to write index: {:?}", err)))?;
+
+    rename(&index_temp_path, &index_path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to write index: {:?}", err)))?;
+
+    Ok(())
+}
+
+impl LocalRegistryBackend {
+    /// Applies `update` to the artifact index. Stored objects are kept when the index can not
+    /// be updated, the index is removed instead so the next read rebuilds it.
+    async fn update_index(&self, update: impl FnOnce(&mut LocalIndex)) {
+        let _index_guard = self.index_lock.lock().await;
+
+        let result = match read_index().await {
+            Ok(mut index) => {
+                update(&mut index);
+
+                write_index(&index).await
+            }
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            warn!("failed to update artifact index: {}", err.message());
+
+            let _ = remove_file(get_artifact_index_path()).await;
+        }
+    }
+}
+
+fn get_artifact_id(store_name: &str) -> Option<ArtifactId> {
+    let (name, hash) = store_name.rsplit_once('-')?;
+
+    hash.parse::<ArtifactDigest>().ok()?;
+
+    Some(ArtifactId {
+        hash: hash.to_string(),
+        name: name.to_string(),
+    })
+}
+
+/// Lists the stored objects with file names starting with `prefix`, other entries are skipped
+/// before reading their metadata.
+async fn list_store_objects(prefix: &str) -> Result<Vec<RegistryObject>, Status> {
+    let mut entries = read_dir(get_store_dir_path())
+        .await
+        .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?;
+
+    let mut objects = vec![];
+
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?
+    {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        if !file_name.starts_with(prefix) {
+            continue;
+        }
+
+        let Some((kind, name, hash)) = get_object_kind(&file_name) else {
+            continue;
+        };
+
+        // Objects not addressed by a valid digest were not pushed through the registry
+
+        let Ok(hash) = ObjectDigest::parse(kind, hash) else {
+            continue;
+        };
+
+        let entry_metadata = entry
+            .metadata()
+            .await
+            .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?;
+
+        // Stored data has sanitized timestamps, the digest file records the push time
+
+        let modified = metadata(get_digest_path(&entry.path()))
+            .await
+            .and_then(|digest| digest.modified())
+            .or_else(|_| entry_metadata.modified())
+            .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?;
+
+        objects.push(RegistryObject {
+            hash,
+            kind,
+            modified,
+            name: name.to_string(),
+            size: entry_metadata.len(),
+        });
+    }
+
+    Ok(objects)
+}
+
 #[async_trait]
 impl RegistryBackend for LocalRegistryBackend {
+    async fn check(&self) -> Result<(), Status> {
+        let check_path = get_store_dir_path().join(".vorpal-check");
+
+        write(&check_path, [])
+            .await
+            .map_err(|err| Status::unavailable(format!("store is not writable: {:?}", err)))?;
+
+        remove_file(&check_path)
+            .await
+            .map_err(|err| Status::unavailable(format!("store is not writable: {:?}", err)))?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, object: &RegistryObject) -> Result<(), Status> {
+        let path = get_registry_path(object.kind, &object.hash, &object.name)?;
+
+        remove_file(&path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to remove store path: {:?}", err)))?;
+
+        let digest_path = get_digest_path(&path);
+
+        if digest_path.exists() {
+            remove_file(&digest_path)
+                .await
+                .map_err(|err| Status::internal(format!("failed to remove digest: {:?}", err)))?;
+        }
+
+        if object.kind == RegistryKind::ArtifactSource {
+            let index_path = get_delta_index_path(&object.hash, &object.name)?;
+
+            if index_path.exists() {
+                remove_file(&index_path).await.map_err(|err| {
+                    Status::internal(format!("failed to remove delta index: {:?}", err))
+                })?;
+            }
+        }
+
+        if object.kind == RegistryKind::ArtifactManifest {
+            self.update_index(|index| {
+                index.remove(&object.hash.to_string());
+            })
+            .await;
+        }
+
+        Ok(())
+    }
+
     async fn exists(&self, request: &RegistryRequest) -> Result<(), Status> {
-        let path = get_registry_path(request.kind(), &request.hash, &request.name)?;
+        let path = get_registry_path(request.kind(), &get_request_digest(request)?, &request.name)?;
 
         if !path.exists() {
             return Err(Status::not_found("store path not found"));
@@ -41,12 +403,81 @@ impl RegistryBackend for LocalRegistryBackend {
         Ok(())
     }
 
+    async fn list(&self, request: &RegistryListRequest) -> Result<RegistryListResponse, Status> {
+        let page_size = request.page_size as usize;
+
+        let mut entries = read_dir(get_store_dir_path())
+            .await
+            .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?;
+
+        // Keep only the first `page_size + 1` names after the token so memory stays bounded
+
+        let mut page = BTreeSet::new();
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| Status::internal(format!("failed to read store: {:?}", err)))?
+        {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+
+            let Some(store_name) = file_name.strip_suffix(ARTIFACT_ARCHIVE_SUFFIX) else {
+                continue;
+            };
+
+            if !store_name.starts_with(&request.name_prefix)
+                || store_name <= request.page_token.as_str()
+            {
+                continue;
+            }
+
+            page.insert(store_name.to_string());
+
+            if page.len() > page_size + 1 {
+                page.pop_last();
+            }
+        }
+
+        let mut next_page_token = String::new();
+
+        if page.len() > page_size {
+            page.pop_last();
+
+            next_page_token = page.last().cloned().unwrap_or_default();
+        }
+
+        let artifacts = page
+            .iter()
+            .filter_map(|name| get_artifact_id(name))
+            .collect();
+
+        Ok(RegistryListResponse {
+            artifacts,
+            next_page_token,
+        })
+    }
+
+    async fn list_objects(&self) -> Result<Vec<RegistryObject>, Status> {
+        list_store_objects("").await
+    }
+
+    async fn latest(
+        &self,
+        kind: RegistryKind,
+        name: &str,
+    ) -> Result<Option<RegistryObject>, Status> {
+        let objects = list_store_objects(&format!("{}-", name)).await?;
+
+        Ok(get_latest_object(objects, kind, name))
+    }
+
     async fn pull(
         &self,
         request: &RegistryRequest,
         tx: mpsc::Sender<Result<RegistryPullResponse, Status>>,
+        chunk_bounds: ChunkBounds,
     ) -> Result<(), Status> {
-        let path = get_registry_path(request.kind(), &request.hash, &request.name)?;
+        let path = get_registry_path(request.kind(), &get_request_digest(request)?, &request.name)?;
 
         if !path.exists() {
             return Err(Status::not_found("store path not found"));
@@ -56,23 +487,99 @@ impl RegistryBackend for LocalRegistryBackend {
             .await
             .map_err(|err| Status::internal(err.to_string()))?;
 
-        for chunk in data.chunks(DEFAULT_GRPC_CHUNK_SIZE) {
-            tx.send(Ok(RegistryPullResponse {
-                data: chunk.to_vec(),
-            }))
+        send_chunks(&data, chunk_bounds, &tx).await?;
+
+        Ok(())
+    }
+
+    async fn pull_manifest(&self, artifact_id: &ArtifactId) -> Result<Option<Vec<u8>>, Status> {
+        let path = get_registry_path(
+            RegistryKind::ArtifactManifest,
+            &ObjectDigest::parse(RegistryKind::ArtifactManifest, &artifact_id.hash)?,
+            &artifact_id.name,
+        )?;
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = read(&path)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Some(data))
+    }
+
+    async fn pull_digest(
+        &self,
+        request: &RegistryRequest,
+    ) -> Result<Option<ArchiveDigest>, Status> {
+        let path = get_registry_path(request.kind(), &get_request_digest(request)?, &request.name)?;
+        let digest_path = get_digest_path(&path);
+
+        // Data pushed before digests were recorded has no digest file
+
+        if !digest_path.exists() {
+            return Ok(None);
+        }
+
+        let digest = read_to_string(&digest_path)
             .await
-            .map_err(|err| Status::internal(format!("failed to send store chunk: {:?}", err)))?;
+            .map_err(|err| Status::internal(format!("failed to read digest: {:?}", err)))?;
+
+        let digest = digest
+            .trim()
+            .parse::<ArchiveDigest>()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Some(digest))
+    }
+
+    async fn pull_delta_index(
+        &self,
+        hash: &ObjectDigest,
+        name: &str,
+    ) -> Result<Option<Vec<String>>, Status> {
+        let index_path = get_delta_index_path(hash, name)?;
+
+        // Sources pushed before indexes were recorded have no index file
+
+        if !index_path.exists() {
+            return Ok(None);
         }
 
-        Ok(())
+        let data = read(&index_path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to read delta index: {:?}", err)))?;
+
+        let index = serde_json::from_slice(&data)
+            .map_err(|err| Status::internal(format!("failed to parse delta index: {:?}", err)))?;
+
+        Ok(Some(index))
+    }
+
+    async fn push_delta_index(
+        &self,
+        hash: &ObjectDigest,
+        name: &str,
+        index: &[String],
+    ) -> Result<(), Status> {
+        let data = serde_json::to_vec(index)
+            .map_err(|err| Status::internal(format!("failed to encode delta index: {:?}", err)))?;
+
+        write(get_delta_index_path(hash, name)?, data)
+            .await
+            .map_err(|err| Status::internal(format!("failed to write delta index: {:?}", err)))
     }
 
     async fn push(&self, metadata: PushMetadata) -> Result<(), Status> {
         let PushMetadata {
+            data_digest,
             data_kind,
+            data_path,
             hash,
             name,
-            data,
+            ..
         } = metadata;
 
         let path = get_registry_path(data_kind, &hash, &name)?;
@@ -81,17 +588,65 @@ impl RegistryBackend for LocalRegistryBackend {
             return Ok(());
         }
 
-        write(&path, &data)
-            .await
-            .map_err(|err| Status::internal(format!("failed to write store path: {:?}", err)))?;
+        // The spooled push is moved into place, copied when the sandbox is on another filesystem
+
+        if rename(&data_path, &path).await.is_err() {
+            copy_file(&data_path, &path).await.map_err(|err| {
+                Status::internal(format!("failed to write store path: {:?}", err))
+            })?;
+        }
 
         set_timestamps(&path)
             .await
             .map_err(|err| Status::internal(format!("failed to sanitize path: {:?}", err)))?;
 
+        write(get_digest_path(&path), data_digest.as_str())
+            .await
+            .map_err(|err| Status::internal(format!("failed to write digest: {:?}", err)))?;
+
+        if data_kind == RegistryKind::ArtifactManifest {
+            let data = read_manifest(&path).await?;
+
+            let artifact = get_index_artifact(&data, &name, SystemTime::now());
+
+            self.update_index(|index| {
+                index.insert(hash.to_string(), artifact);
+            })
+            .await;
+        }
+
         Ok(())
     }
 
+    async fn search(&self, term: &str) -> Result<Vec<RegistrySearchArtifact>, Status> {
+        let index = {
+            let _index_guard = self.index_lock.lock().await;
+
+            read_index().await?
+        };
+
+        let mut artifacts = index
+            .into_iter()
+            .filter(|(_, artifact)| artifact.name.contains(term))
+            .map(|(hash, artifact)| RegistrySearchArtifact {
+                artifact: Some(ArtifactId {
+                    hash,
+                    name: artifact.name,
+                }),
+                created_at: artifact.created_at,
+                systems: artifact.systems,
+            })
+            .collect::<Vec<_>>();
+
+        artifacts.sort_by(|a, b| a.artifact.cmp(&b.artifact));
+
+        Ok(artifacts)
+    }
+
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
     fn box_clone(&self) -> Box<dyn RegistryBackend> {
         Box::new(self.clone())
     }
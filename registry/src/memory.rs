@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::{fs::read, sync::mpsc};
+use tonic::{async_trait, Status};
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactId,
+    registry::v0::{
+        RegistryKind, RegistryListRequest, RegistryListResponse, RegistryPullResponse,
+        RegistryRequest,
+    },
+};
+use vorpal_store::{chunks::ChunkBounds, digests::ArchiveDigest};
+
+use crate::{
+    get_request_digest, send_chunks, ObjectDigest, PushMetadata, RegistryBackend, RegistryObject,
+};
+
+type MemoryKey = (RegistryKind, String, ObjectDigest);
+type MemoryObjects = BTreeMap<MemoryKey, (Vec<u8>, SystemTime)>;
+type MemoryDeltaIndexes = BTreeMap<(String, ObjectDigest), Vec<String>>;
+
+/// Keeps objects in memory, for tests of code running against a `RegistryBackend`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryRegistryBackend {
+    delta_indexes: Arc<Mutex<MemoryDeltaIndexes>>,
+    objects: Arc<Mutex<MemoryObjects>>,
+}
+
+impl MemoryRegistryBackend {
+    /// Stores `data` as if it was pushed at `modified`.
+    pub fn insert(
+        &self,
+        kind: RegistryKind,
+        name: &str,
+        hash: &str,
+        data: Vec<u8>,
+        modified: SystemTime,
+    ) {
+        let digest = ObjectDigest::parse(kind, hash).unwrap();
+
+        self.objects
+            .lock()
+            .unwrap()
+            .insert((kind, name.to_string(), digest), (data, modified));
+    }
+
+    pub fn contains(&self, kind: RegistryKind, name: &str, hash: &str) -> bool {
+        let digest = ObjectDigest::parse(kind, hash).unwrap();
+
+        self.objects
+            .lock()
+            .unwrap()
+            .contains_key(&(kind, name.to_string(), digest))
+    }
+
+    pub fn delta_index(&self, name: &str, hash: &str) -> Option<Vec<String>> {
+        let digest = ObjectDigest::parse(RegistryKind::ArtifactSource, hash).unwrap();
+
+        self.delta_indexes
+            .lock()
+            .unwrap()
+            .get(&(name.to_string(), digest))
+            .cloned()
+    }
+
+    fn get(&self, kind: RegistryKind, name: &str, hash: &ObjectDigest) -> Option<Vec<u8>> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&(kind, name.to_string(), hash.clone()))
+            .map(|(data, _)| data.clone())
+    }
+}
+
+#[async_trait]
+impl RegistryBackend for MemoryRegistryBackend {
+    async fn check(&self) -> Result<(), Status> {
+        Ok(())
+    }
+
+    async fn delete(&self, object: &RegistryObject) -> Result<(), Status> {
+        self.delta_indexes
+            .lock()
+            .unwrap()
+            .remove(&(object.name.clone(), object.hash.clone()));
+
+        self.objects.lock().unwrap().remove(&(
+            object.kind,
+            object.name.clone(),
+            object.hash.clone(),
+        ));
+
+        Ok(())
+    }
+
+    async fn exists(&self, request: &RegistryRequest) -> Result<(), Status> {
+        match self.get(request.kind(), &request.name, &get_request_digest(request)?) {
+            Some(_) => Ok(()),
+            None => Err(Status::not_found("store path not found")),
+        }
+    }
+
+    async fn list(&self, _request: &RegistryListRequest) -> Result<RegistryListResponse, Status> {
+        Ok(RegistryListResponse::default())
+    }
+
+    async fn list_objects(&self) -> Result<Vec<RegistryObject>, Status> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((kind, name, hash), (data, modified))| RegistryObject {
+                hash: hash.clone(),
+                kind: *kind,
+                modified: *modified,
+                name: name.clone(),
+                size: data.len() as u64,
+            })
+            .collect())
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn pull(
+        &self,
+        request: &RegistryRequest,
+        tx: mpsc::Sender<Result<RegistryPullResponse, Status>>,
+        chunk_bounds: ChunkBounds,
+    ) -> Result<(), Status> {
+        let Some(data) = self.get(request.kind(), &request.name, &get_request_digest(request)?)
+        else {
+            return Err(Status::not_found("store path not found"));
+        };
+
+        send_chunks(&data, chunk_bounds, &tx).await?;
+
+        Ok(())
+    }
+
+    async fn pull_manifest(&self, artifact_id: &ArtifactId) -> Result<Option<Vec<u8>>, Status> {
+        let digest = ObjectDigest::parse(RegistryKind::ArtifactManifest, &artifact_id.hash)?;
+
+        Ok(self.get(RegistryKind::ArtifactManifest, &artifact_id.name, &digest))
+    }
+
+    async fn pull_digest(
+        &self,
+        _request: &RegistryRequest,
+    ) -> Result<Option<ArchiveDigest>, Status> {
+        Ok(None)
+    }
+
+    async fn pull_delta_index(
+        &self,
+        hash: &ObjectDigest,
+        name: &str,
+    ) -> Result<Option<Vec<String>>, Status> {
+        Ok(self
+            .delta_indexes
+            .lock()
+            .unwrap()
+            .get(&(name.to_string(), hash.clone()))
+            .cloned())
+    }
+
+    async fn push_delta_index(
+        &self,
+        hash: &ObjectDigest,
+        name: &str,
+        index: &[String],
+    ) -> Result<(), Status> {
+        self.delta_indexes
+            .lock()
+            .unwrap()
+            .insert((name.to_string(), hash.clone()), index.to_vec());
+
+        Ok(())
+    }
+
+    async fn push(&self, metadata: PushMetadata) -> Result<(), Status> {
+        let data = read(&metadata.data_path)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        self.objects.lock().unwrap().insert(
+            (metadata.data_kind, metadata.name, metadata.hash),
+            (data, SystemTime::now()),
+        );
+
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn RegistryBackend> {
+        Box::new(self.clone())
+    }
+}
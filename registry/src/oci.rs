@@ -0,0 +1,1180 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{
+    header::{
+        HeaderMap, ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LINK, LOCATION,
+        WWW_AUTHENTICATE,
+    },
+    Body, Client, RequestBuilder, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, sync::mpsc};
+use tonic::{async_trait, Status};
+use tracing::debug;
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactId,
+    registry::v0::{
+        RegistryKind, RegistryListRequest, RegistryListResponse, RegistryPullResponse,
+        RegistryRequest,
+    },
+};
+use vorpal_store::{
+    chunks::{ChunkBounds, ChunkSizer},
+    digests::ArchiveDigest,
+    http::get_http_client,
+    oci::{get_challenge_params, get_credentials, OciCredentials},
+    paths::get_store_dir_name,
+};
+
+use crate::{
+    exists_concurrent, get_request_digest, send_chunk, ObjectDigest, PushMetadata, RegistryBackend,
+    RegistryError, RegistryObject,
+};
+
+// Media types of the pushed OCI artifacts, the config is the OCI empty descriptor
+const ARTIFACT_TYPE: &str = "application/vnd.vorpal.object.v1";
+const CONFIG_DATA: &[u8] = b"{}";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.vorpal.object.layer.v1";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+// Manifest annotations carrying the vorpal object metadata
+const COMPRESSION_ANNOTATION: &str = "dev.vorpal.compression";
+const CREATED_ANNOTATION: &str = "dev.vorpal.created";
+const DIGEST_ANNOTATION: &str = "dev.vorpal.digest";
+const HASH_ANNOTATION: &str = "dev.vorpal.hash";
+const KIND_ANNOTATION: &str = "dev.vorpal.kind";
+const NAME_ANNOTATION: &str = "dev.vorpal.name";
+const SIGNATURE_ANNOTATION: &str = "dev.vorpal.signature";
+
+// Tags returned per tag list page
+const TAGS_PAGE_SIZE: u32 = 1000;
+
+// Tag prefixes of prunable objects, tags are `<prefix>-<hash>`
+const OBJECT_TAG_PREFIXES: [(&str, RegistryKind); 5] = [
+    ("artifact", RegistryKind::Artifact),
+    ("manifest", RegistryKind::ArtifactManifest),
+    ("provenance", RegistryKind::ArtifactProvenance),
+    ("source", RegistryKind::ArtifactSource),
+    ("step", RegistryKind::ArtifactStep),
+];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    digest: String,
+    media_type: String,
+    size: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OciManifest {
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    artifact_type: String,
+    config: OciDescriptor,
+    layers: Vec<OciDescriptor>,
+    media_type: String,
+    schema_version: u32,
+}
+
+#[derive(Deserialize)]
+struct OciTagList {
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct OciToken {
+    access_token: Option<String>,
+    token: Option<String>,
+}
+
+/// Stores objects as OCI artifacts in a container registry repository.
+///
+/// Each object is a manifest tagged `<kind>-<hash>` with a single layer holding the data and
+/// annotations holding its name, digest and signature. Credentials are resolved from the docker
+/// config the same way `docker` does.
+#[derive(Clone, Debug)]
+pub struct OciRegistryBackend {
+    authorization: Arc<RwLock<Option<String>>>,
+    client: Client,
+    credentials: Option<OciCredentials>,
+    repository: String,
+    url: String,
+}
+
+/// Returns the `rel="next"` target of a `Link` header.
+fn get_next_link(headers: &HeaderMap) -> Option<String> {
+    let link = headers.get(LINK)?.to_str().ok()?;
+
+    if !link.contains("rel=\"next\"") {
+        return None;
+    }
+
+    let start = link.find('<')? + 1;
+    let end = link.find('>')?;
+
+    Some(link.get(start..end)?.to_string())
+}
+
+fn get_tag_prefix(kind: RegistryKind) -> Result<&'static str, Status> {
+    match kind {
+        RegistryKind::Dictionary => Ok("dictionary"),
+        _ => OBJECT_TAG_PREFIXES
+            .iter()
+            .find(|(_, object_kind)| *object_kind == kind)
+            .map(|(prefix, _)| *prefix)
+            .ok_or_else(|| Status::invalid_argument("unsupported store kind")),
+    }
+}
+
+fn get_tag(kind: RegistryKind, hash: &ObjectDigest) -> Result<String, Status> {
+    Ok(format!("{}-{}", get_tag_prefix(kind)?, hash))
+}
+
+fn get_object_kind(tag: &str) -> Option<(RegistryKind, &str)> {
+    let (prefix, hash) = tag.split_once('-')?;
+
+    OBJECT_TAG_PREFIXES
+        .iter()
+        .find(|(object_prefix, _)| *object_prefix == prefix)
+        .map(|(_, kind)| (*kind, hash))
+}
+
+fn get_blob_digest(digest: &ArchiveDigest) -> String {
+    format!("sha256:{}", digest)
+}
+
+impl OciRegistryBackend {
+    /// Creates a backend for `repo`, a repository reference like `ghcr.io/org/vorpal-cache`.
+    ///
+    /// Registries are reached over HTTPS unless `repo` starts with `http://`.
+    pub async fn new(repo: Option<String>) -> Result<Self, RegistryError> {
+        let Some(repo) = repo else {
+            return Err(RegistryError::MissingOciRepo);
+        };
+
+        let (scheme, reference) = match repo.split_once("://") {
+            Some((scheme, reference)) => (scheme, reference),
+            None => ("https", repo.as_str()),
+        };
+
+        let Some((host, repository)) = reference.trim_end_matches('/').split_once('/') else {
+            return Err(RegistryError::InvalidOciRepo(repo.clone()));
+        };
+
+        if host.is_empty() || repository.is_empty() || !["http", "https"].contains(&scheme) {
+            return Err(RegistryError::InvalidOciRepo(repo.clone()));
+        }
+
+        let client = get_http_client()
+            .map_err(|err| RegistryError::FailedToCreateOciClient(err.to_string()))?;
+
+        let credentials = get_credentials(host)
+            .await
+            .map_err(|err| RegistryError::FailedToCreateOciClient(err.to_string()))?;
+
+        Ok(Self {
+            authorization: Arc::new(RwLock::new(None)),
+            client,
+            credentials,
+            repository: repository.to_string(),
+            url: format!("{}://{}", scheme, host),
+        })
+    }
+
+    fn get_repository_url(&self) -> String {
+        format!("{}/v2/{}", self.url, self.repository)
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let authorization = self
+            .authorization
+            .read()
+            .ok()
+            .and_then(|authorization| authorization.clone());
+
+        match authorization {
+            Some(authorization) => request.header(AUTHORIZATION, authorization),
+            None => request,
+        }
+    }
+
+    /// Answers an authentication challenge, exchanging credentials for a bearer token scoped
+    /// to the repository when the registry asks for one.
+    async fn authenticate(&self, challenge: &str) -> Result<(), Status> {
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+
+        let authorization = if scheme.eq_ignore_ascii_case("basic") {
+            let Some(credentials) = &self.credentials else {
+                return Err(Status::unauthenticated("registry requires credentials"));
+            };
+
+            credentials.get_basic_authorization()
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            let params = get_challenge_params(params);
+
+            let Some(realm) = params.get("realm") else {
+                return Err(Status::unauthenticated(
+                    "registry challenge is missing realm",
+                ));
+            };
+
+            let mut query = vec![("scope", format!("repository:{}:pull,push", self.repository))];
+
+            if let Some(service) = params.get("service") {
+                query.push(("service", service.clone()));
+            }
+
+            let mut request = self.client.get(realm).query(&query);
+
+            if let Some(credentials) = &self.credentials {
+                request = request.basic_auth(&credentials.username, Some(&credentials.password));
+            }
+
+            let response = request.send().await.map_err(|err| {
+                Status::unavailable(format!("failed to get registry token: {:?}", err))
+            })?;
+
+            if !response.status().is_success() {
+                return Err(Status::unauthenticated(format!(
+                    "failed to get registry token: {}",
+                    response.status()
+                )));
+            }
+
+            let token: OciToken = response.json().await.map_err(|err| {
+                Status::internal(format!("failed to parse registry token: {:?}", err))
+            })?;
+
+            let Some(token) = token.token.or(token.access_token) else {
+                return Err(Status::unauthenticated("registry token is missing"));
+            };
+
+            format!("Bearer {}", token)
+        } else {
+            return Err(Status::unauthenticated(format!(
+                "unsupported registry authentication: {}",
+                scheme
+            )));
+        };
+
+        if let Ok(mut current) = self.authorization.write() {
+            *current = Some(authorization);
+        }
+
+        Ok(())
+    }
+
+    /// Sends `request`, authenticating and retrying once when the registry responds with a
+    /// challenge.
+    async fn send(&self, request: RequestBuilder) -> Result<Response, Status> {
+        let retry = request.try_clone();
+
+        let response =
+            self.authorize(request).send().await.map_err(|err| {
+                Status::unavailable(format!("failed to reach registry: {:?}", err))
+            })?;
+
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let Some(retry) = retry else {
+            return Ok(response);
+        };
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        self.authenticate(&challenge).await?;
+
+        self.authorize(retry)
+            .send()
+            .await
+            .map_err(|err| Status::unavailable(format!("failed to reach registry: {:?}", err)))
+    }
+
+    async fn get_manifest(&self, tag: &str) -> Result<Option<OciManifest>, Status> {
+        let request = self
+            .client
+            .get(format!("{}/manifests/{}", self.get_repository_url(), tag))
+            .header(ACCEPT, MANIFEST_MEDIA_TYPE);
+
+        let response = self.send(request).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(Status::internal(format!(
+                "failed to get manifest {}: {}",
+                tag,
+                response.status()
+            )));
+        }
+
+        let manifest = response
+            .json()
+            .await
+            .map_err(|err| Status::internal(format!("failed to parse manifest: {:?}", err)))?;
+
+        Ok(Some(manifest))
+    }
+
+    async fn get_tags(&self) -> Result<Vec<String>, Status> {
+        let mut tags = vec![];
+
+        let mut page_url = Some(format!(
+            "{}/tags/list?n={}",
+            self.get_repository_url(),
+            TAGS_PAGE_SIZE
+        ));
+
+        while let Some(url) = page_url.take() {
+            let response = self.send(self.client.get(&url)).await?;
+
+            // Repositories are created by the first push
+
+            if response.status() == StatusCode::NOT_FOUND {
+                break;
+            }
+
+            if !response.status().is_success() {
+                return Err(Status::internal(format!(
+                    "failed to list tags: {}",
+                    response.status()
+                )));
+            }
+
+            page_url = get_next_link(response.headers()).map(|link| match link.starts_with('/') {
+                true => format!("{}{}", self.url, link),
+                false => link,
+            });
+
+            let page: OciTagList = response
+                .json()
+                .await
+                .map_err(|err| Status::internal(format!("failed to parse tags: {:?}", err)))?;
+
+            tags.extend(page.tags.unwrap_or_default());
+        }
+
+        Ok(tags)
+    }
+
+    async fn push_blob(&self, digest: &str, data: Body, size: u64) -> Result<(), Status> {
+        let repository_url = self.get_repository_url();
+
+        let response = self
+            .send(
+                self.client
+                    .head(format!("{}/blobs/{}", repository_url, digest)),
+            )
+            .await?;
+
+        if response.status().is_success() {
+            return Ok(());
+        }
+
+        let response = self
+            .send(
+                self.client
+                    .post(format!("{}/blobs/uploads/", repository_url)),
+            )
+            .await?;
+
+        if response.status() != StatusCode::ACCEPTED {
+            return Err(Status::internal(format!(
+                "failed to start blob upload: {}",
+                response.status()
+            )));
+        }
+
+        let Some(location) = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Err(Status::internal("blob upload is missing location"));
+        };
+
+        let location = match location.starts_with('/') {
+            true => format!("{}{}", self.url, location),
+            false => location.to_string(),
+        };
+
+        let request = self
+            .client
+            .put(location)
+            .query(&[("digest", digest)])
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, size)
+            .body(data);
+
+        let response = self.send(request).await?;
+
+        if response.status() != StatusCode::CREATED {
+            return Err(Status::internal(format!(
+                "failed to upload blob {}: {}",
+                digest,
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RegistryBackend for OciRegistryBackend {
+    async fn check(&self) -> Result<(), Status> {
+        let response = self
+            .send(self.client.get(format!("{}/v2/", self.url)))
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Status::unavailable(format!(
+                "failed to reach registry: {}",
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, object: &RegistryObject) -> Result<(), Status> {
+        let tag = get_tag(object.kind, &object.hash)?;
+
+        let manifest_url = format!("{}/manifests/{}", self.get_repository_url(), tag);
+
+        let response = self
+            .send(
+                self.client
+                    .head(&manifest_url)
+                    .header(ACCEPT, MANIFEST_MEDIA_TYPE),
+            )
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+
+        // Manifests are deleted by digest, the registry collects the unreferenced blobs
+
+        let Some(digest) = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Err(Status::internal("manifest is missing digest"));
+        };
+
+        let response = self
+            .send(self.client.delete(format!(
+                "{}/manifests/{}",
+                self.get_repository_url(),
+                digest
+            )))
+            .await?;
+
+        if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+            return Err(Status::internal(format!(
+                "failed to delete store path: {}",
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn exists(&self, request: &RegistryRequest) -> Result<(), Status> {
+        let tag = get_tag(request.kind(), &get_request_digest(request)?)?;
+
+        let response = self
+            .send(
+                self.client
+                    .head(format!("{}/manifests/{}", self.get_repository_url(), tag))
+                    .header(ACCEPT, MANIFEST_MEDIA_TYPE),
+            )
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Status::not_found("store path not found"));
+        }
+
+        Ok(())
+    }
+
+    async fn exists_batch(&self, requests: &[RegistryRequest]) -> Result<Vec<bool>, Status> {
+        exists_concurrent(self, requests).await
+    }
+
+    /// Artifact names are only held by manifests, so every artifact manifest is fetched before
+    /// the page is selected.
+    async fn list(&self, request: &RegistryListRequest) -> Result<RegistryListResponse, Status> {
+        let page_size = request.page_size as usize;
+
+        let mut page = BTreeMap::new();
+
+        for tag in self.get_tags().await? {
+            let Some((RegistryKind::Artifact, hash)) = get_object_kind(&tag) else {
+                continue;
+            };
+
+            let Some(manifest) = self.get_manifest(&tag).await? else {
+                continue;
+            };
+
+            let Some(name) = manifest.annotations.get(NAME_ANNOTATION) else {
+                continue;
+            };
+
+            let store_name = get_store_dir_name(hash, name);
+
+            if !store_name.starts_with(&request.name_prefix) || store_name <= request.page_token {
+                continue;
+            }
+
+            page.insert(
+                store_name,
+                ArtifactId {
+                    hash: hash.to_string(),
+                    name: name.clone(),
+                },
+            );
+        }
+
+        let mut artifacts: Vec<ArtifactId> = page.into_values().collect();
+        let mut next_page_token = String::new();
+
+        if artifacts.len() > page_size {
+            artifacts.truncate(page_size);
+
+            if let Some(last) = artifacts.last() {
+                next_page_token = get_store_dir_name(&last.hash, &last.name);
+            }
+        }
+
+        Ok(RegistryListResponse {
+            artifacts,
+            next_page_token,
+        })
+    }
+
+    async fn list_objects(&self) -> Result<Vec<RegistryObject>, Status> {
+        let mut objects = vec![];
+        let mut tags = BTreeSet::new();
+
+        tags.extend(self.get_tags().await?);
+
+        for tag in tags {
+            let Some((kind, hash)) = get_object_kind(&tag) else {
+                continue;
+            };
+
+            let Ok(hash) = ObjectDigest::parse(kind, hash) else {
+                continue;
+            };
+
+            let Some(manifest) = self.get_manifest(&tag).await? else {
+                continue;
+            };
+
+            let Some(name) = manifest.annotations.get(NAME_ANNOTATION) else {
+                continue;
+            };
+
+            let modified = manifest
+                .annotations
+                .get(CREATED_ANNOTATION)
+                .and_then(|created| created.parse::<u64>().ok())
+                .map(|created| UNIX_EPOCH + Duration::from_secs(created))
+                .unwrap_or(UNIX_EPOCH);
+
+            objects.push(RegistryObject {
+                hash,
+                kind,
+                modified,
+                name: name.clone(),
+                size: manifest.layers.iter().map(|layer| layer.size).sum(),
+            });
+        }
+
+        Ok(objects)
+    }
+
+    async fn pull(
+        &self,
+        request: &RegistryRequest,
+        tx: mpsc::Sender<Result<RegistryPullResponse, Status>>,
+        chunk_bounds: ChunkBounds,
+    ) -> Result<(), Status> {
+        let tag = get_tag(request.kind(), &get_request_digest(request)?)?;
+
+        let Some(manifest) = self.get_manifest(&tag).await? else {
+            return Err(Status::not_found("store path not found"));
+        };
+
+        let Some(layer) = manifest.layers.first() else {
+            return Err(Status::internal(format!("manifest has no layers: {}", tag)));
+        };
+
+        let mut response = self
+            .send(self.client.get(format!(
+                "{}/blobs/{}",
+                self.get_repository_url(),
+                layer.digest
+            )))
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Status::internal(format!(
+                "failed to get blob {}: {}",
+                layer.digest,
+                response.status()
+            )));
+        }
+
+        // Re-chunk the blob body to the adaptive chunk size
+
+        let mut sizer = ChunkSizer::new(chunk_bounds);
+        let mut buffer = vec![];
+
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+        {
+            buffer.extend_from_slice(&chunk);
+
+            while buffer.len() >= sizer.size() {
+                let data = buffer.drain(..sizer.size()).collect();
+
+                send_chunk(data, &mut sizer, &tx).await?;
+            }
+        }
+
+        if !buffer.is_empty() {
+            send_chunk(buffer, &mut sizer, &tx).await?;
+        }
+
+        debug!("pull transfer: {}", sizer.summary());
+
+        Ok(())
+    }
+
+    async fn pull_digest(
+        &self,
+        request: &RegistryRequest,
+    ) -> Result<Option<ArchiveDigest>, Status> {
+        let tag = get_tag(request.kind(), &get_request_digest(request)?)?;
+
+        let Some(manifest) = self.get_manifest(&tag).await? else {
+            return Err(Status::not_found("store path not found"));
+        };
+
+        let Some(digest) = manifest.annotations.get(DIGEST_ANNOTATION) else {
+            return Ok(None);
+        };
+
+        let digest = digest
+            .parse::<ArchiveDigest>()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Some(digest))
+    }
+
+    async fn push(&self, metadata: PushMetadata) -> Result<(), Status> {
+        let PushMetadata {
+            compression,
+            data_digest,
+            data_kind,
+            data_path,
+            data_signature,
+            data_size,
+            hash,
+            name,
+        } = metadata;
+
+        let tag = get_tag(data_kind, &hash)?;
+
+        if self.get_manifest(&tag).await?.is_some() {
+            return Ok(());
+        }
+
+        let config = OciDescriptor {
+            digest: get_blob_digest(&ArchiveDigest::from_data(CONFIG_DATA)),
+            media_type: CONFIG_MEDIA_TYPE.to_string(),
+            size: CONFIG_DATA.len() as u64,
+        };
+
+        let layer = OciDescriptor {
+            digest: get_blob_digest(&data_digest),
+            media_type: LAYER_MEDIA_TYPE.to_string(),
+            size: data_size,
+        };
+
+        self.push_blob(&config.digest, CONFIG_DATA.into(), config.size)
+            .await?;
+
+        // Streamed from the spooled push rather than read into memory
+
+        let layer_file = File::open(&data_path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to read push file: {:?}", err)))?;
+
+        self.push_blob(&layer.digest, layer_file.into(), layer.size)
+            .await?;
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut annotations = BTreeMap::from([
+            (
+                COMPRESSION_ANNOTATION.to_string(),
+                compression.as_str_name().to_string(),
+            ),
+            (CREATED_ANNOTATION.to_string(), created.to_string()),
+            (DIGEST_ANNOTATION.to_string(), data_digest.to_string()),
+            (HASH_ANNOTATION.to_string(), hash.to_string()),
+            (
+                KIND_ANNOTATION.to_string(),
+                data_kind.as_str_name().to_string(),
+            ),
+            (NAME_ANNOTATION.to_string(), name),
+        ]);
+
+        if !data_signature.is_empty() {
+            annotations.insert(
+                SIGNATURE_ANNOTATION.to_string(),
+                vorpal_notary::encode_signature(&data_signature),
+            );
+        }
+
+        let manifest = OciManifest {
+            annotations,
+            artifact_type: ARTIFACT_TYPE.to_string(),
+            config,
+            layers: vec![layer],
+            media_type: MANIFEST_MEDIA_TYPE.to_string(),
+            schema_version: 2,
+        };
+
+        let manifest_data = serde_json::to_vec(&manifest)
+            .map_err(|err| Status::internal(format!("failed to serialize manifest: {:?}", err)))?;
+
+        let request = self
+            .client
+            .put(format!("{}/manifests/{}", self.get_repository_url(), tag))
+            .header(CONTENT_TYPE, MANIFEST_MEDIA_TYPE)
+            .body(manifest_data);
+
+        let response = self.send(request).await?;
+
+        if response.status() != StatusCode::CREATED {
+            return Err(Status::internal(format!(
+                "failed to write store path: {}",
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "oci"
+    }
+
+    fn box_clone(&self) -> Box<dyn RegistryBackend> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, path::Path, sync::Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+    use vorpal_schema::vorpal::registry::v0::RegistryCompression;
+
+    const STUB_TOKEN: &str = "stub-token";
+
+    // Request received by the registry stub: method, path with query, headers and body
+    #[derive(Debug)]
+    struct StubRequest {
+        method: String,
+        path: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl StubRequest {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    // Blobs by digest and manifests by tag held by the registry stub
+    #[derive(Default)]
+    struct StubRegistry {
+        blobs: HashMap<String, Vec<u8>>,
+        manifests: HashMap<String, Vec<u8>>,
+        requests: Vec<StubRequest>,
+    }
+
+    async fn read_request(reader: &mut BufReader<TcpStream>) -> Option<StubRequest> {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let path = parts.next()?.to_string();
+
+        let mut headers = vec![];
+
+        loop {
+            let mut line = String::new();
+
+            reader.read_line(&mut line).await.ok()?;
+
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                break;
+            }
+
+            let (key, value) = line.split_once(':')?;
+
+            headers.push((key.to_lowercase(), value.trim().to_string()));
+        }
+
+        let mut request = StubRequest {
+            method,
+            path,
+            headers,
+            body: vec![],
+        };
+
+        let length = request
+            .header("content-length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        request.body = vec![0; length];
+
+        reader.read_exact(&mut request.body).await.ok()?;
+
+        Some(request)
+    }
+
+    // Answers `request` like a registry requiring a bearer token for every `/v2/` request
+    fn get_response(
+        registry: &mut StubRegistry,
+        request: &StubRequest,
+        url: &str,
+    ) -> (&'static str, String, Vec<u8>) {
+        if request.path.starts_with("/token?") {
+            let body = format!("{{\"token\":\"{}\"}}", STUB_TOKEN);
+
+            return ("200 OK", String::new(), body.into_bytes());
+        }
+
+        if request.header("authorization") != Some(format!("Bearer {}", STUB_TOKEN).as_str()) {
+            let challenge = format!(
+                "WWW-Authenticate: Bearer realm=\"{}/token\",service=\"stub\"\r\n",
+                url
+            );
+
+            return ("401 Unauthorized", challenge, vec![]);
+        }
+
+        let path = request.path.trim_start_matches("/v2/vorpal");
+
+        match (request.method.as_str(), path) {
+            ("GET", "/v2/") => ("200 OK", String::new(), vec![]),
+
+            ("POST", "/blobs/uploads/") => (
+                "202 Accepted",
+                "Location: /v2/vorpal/blobs/uploads/upload\r\n".to_string(),
+                vec![],
+            ),
+
+            ("PUT", upload) if upload.starts_with("/blobs/uploads/upload?digest=") => {
+                let digest = upload
+                    .trim_start_matches("/blobs/uploads/upload?digest=")
+                    .replace("%3A", ":");
+
+                // Blobs are only accepted when the body matches the digest
+
+                if digest != get_blob_digest(&ArchiveDigest::from_data(&request.body)) {
+                    return ("400 Bad Request", String::new(), vec![]);
+                }
+
+                registry.blobs.insert(digest, request.body.clone());
+
+                ("201 Created", String::new(), vec![])
+            }
+
+            ("PUT", manifest) if manifest.starts_with("/manifests/") => {
+                let tag = manifest.trim_start_matches("/manifests/").to_string();
+
+                registry.manifests.insert(tag, request.body.clone());
+
+                ("201 Created", String::new(), vec![])
+            }
+
+            (method, path) => {
+                let object = match path.split_once('/').map(|(_, path)| path) {
+                    Some(path) if path.starts_with("blobs/") => {
+                        registry.blobs.get(path.trim_start_matches("blobs/"))
+                    }
+                    Some(path) if path.starts_with("manifests/") => registry
+                        .manifests
+                        .get(path.trim_start_matches("manifests/")),
+                    _ => None,
+                };
+
+                match (method, object) {
+                    ("GET", Some(object)) => ("200 OK", String::new(), object.clone()),
+                    ("HEAD", Some(_)) => ("200 OK", String::new(), vec![]),
+                    _ => ("404 Not Found", String::new(), vec![]),
+                }
+            }
+        }
+    }
+
+    /// Starts a registry stub, returning the backend using it and the stub state.
+    async fn start_stub() -> (OciRegistryBackend, Arc<Mutex<StubRegistry>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let registry = Arc::new(Mutex::new(StubRegistry::default()));
+        let stub_registry = registry.clone();
+        let stub_url = url.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let registry = stub_registry.clone();
+                let url = stub_url.clone();
+
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+
+                    while let Some(request) = read_request(&mut reader).await {
+                        let (status, headers, body) = {
+                            let mut registry = registry.lock().unwrap();
+
+                            let response = get_response(&mut registry, &request, &url);
+
+                            registry.requests.push(request);
+
+                            response
+                        };
+
+                        let mut response = format!(
+                            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n",
+                            status,
+                            headers,
+                            body.len()
+                        )
+                        .into_bytes();
+
+                        response.extend(body);
+
+                        if reader.get_mut().write_all(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let backend = OciRegistryBackend {
+            authorization: Arc::new(RwLock::new(None)),
+            client: get_http_client().unwrap(),
+            credentials: None,
+            repository: "vorpal".to_string(),
+            url,
+        };
+
+        (backend, registry)
+    }
+
+    fn get_push_metadata(data_path: &Path, data: &[u8]) -> PushMetadata {
+        PushMetadata {
+            compression: RegistryCompression::Zstd,
+            data_digest: ArchiveDigest::from_data(data),
+            data_kind: RegistryKind::Artifact,
+            data_path: data_path.to_path_buf(),
+            data_signature: vec![],
+            data_size: data.len() as u64,
+            hash: ObjectDigest::parse(RegistryKind::Artifact, &"a".repeat(64)).unwrap(),
+            name: "example".to_string(),
+        }
+    }
+
+    fn get_pull_request() -> RegistryRequest {
+        RegistryRequest {
+            hash: "a".repeat(64),
+            kind: RegistryKind::Artifact as i32,
+            name: "example".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_with_token() {
+        let (backend, registry) = start_stub().await;
+
+        backend.check().await.unwrap();
+
+        let registry = registry.lock().unwrap();
+
+        let requests = registry
+            .requests
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.path))
+            .collect::<Vec<_>>();
+
+        // The challenge is answered with a token scoped to the repository, then retried
+
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], "GET /v2/");
+        assert!(requests[1].starts_with("GET /token?"), "{}", requests[1]);
+        assert!(requests[1].contains("scope=repository%3Avorpal%3Apull%2Cpush"));
+        assert!(requests[1].contains("service=stub"));
+        assert_eq!(requests[2], "GET /v2/");
+
+        assert_eq!(registry.requests[0].header("authorization"), None);
+        assert_eq!(
+            registry.requests[2].header("authorization"),
+            Some("Bearer stub-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_pull() {
+        let (backend, registry) = start_stub().await;
+
+        let data = (0..256 * 1024)
+            .map(|index| (index % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("push");
+
+        tokio::fs::write(&data_path, &data).await.unwrap();
+
+        backend
+            .push(get_push_metadata(&data_path, &data))
+            .await
+            .unwrap();
+
+        {
+            let registry = registry.lock().unwrap();
+
+            let manifest: OciManifest = serde_json::from_slice(
+                &registry.manifests[&format!("artifact-{}", "a".repeat(64))],
+            )
+            .unwrap();
+
+            assert_eq!(manifest.layers.len(), 1);
+            assert_eq!(manifest.layers[0].size, data.len() as u64);
+            assert_eq!(manifest.annotations[NAME_ANNOTATION], "example");
+            assert_eq!(registry.blobs[&manifest.layers[0].digest], data);
+
+            // Only the first request is challenged, the token is reused after
+
+            let challenged = registry
+                .requests
+                .iter()
+                .filter(|request| request.header("authorization").is_none())
+                .filter(|request| !request.path.starts_with("/token?"))
+                .count();
+
+            assert_eq!(challenged, 1);
+        }
+
+        let request = get_pull_request();
+
+        assert_eq!(
+            backend.pull_digest(&request).await.unwrap(),
+            Some(ArchiveDigest::from_data(&data))
+        );
+
+        let (tx, mut rx) = mpsc::channel(64);
+
+        let pulling = tokio::spawn({
+            let backend = backend.clone();
+
+            async move {
+                backend
+                    .pull(&request, tx, ChunkBounds::new(1024, 64 * 1024))
+                    .await
+            }
+        });
+
+        let mut pulled = vec![];
+
+        while let Some(response) = rx.recv().await {
+            pulled.extend(response.unwrap().data);
+        }
+
+        pulling.await.unwrap().unwrap();
+
+        assert_eq!(pulled, data);
+
+        // Pushing an existing object does not upload it again
+
+        let requests = registry.lock().unwrap().requests.len();
+
+        backend
+            .push(get_push_metadata(&data_path, &data))
+            .await
+            .unwrap();
+
+        let registry = registry.lock().unwrap();
+
+        assert_eq!(registry.requests.len(), requests + 1);
+        assert_eq!(registry.requests[requests].method, "GET");
+    }
+
+    #[tokio::test]
+    async fn test_pull_missing() {
+        let (backend, _) = start_stub().await;
+
+        let (tx, _rx) = mpsc::channel(1);
+
+        let error = backend
+            .pull(&get_pull_request(), tx, ChunkBounds::default())
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.code(), tonic::Code::NotFound);
+
+        backend.exists(&get_pull_request()).await.unwrap_err();
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, SystemTime},
+};
+use tonic::Status;
+use tracing::info;
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactBuildRequest,
+    registry::v0::{
+        RegistryKind, RegistryPruneObject, RegistryPruneRequest, RegistryPruneResponse,
+        RegistryRequest,
+    },
+};
+use vorpal_store::chunks::ChunkBounds;
+
+use crate::{decode_manifest, pull_data, RegistryBackend, RegistryObject};
+
+fn get_object_age(object: &RegistryObject, now: SystemTime) -> Duration {
+    now.duration_since(object.modified).unwrap_or_default()
+}
+
+async fn get_manifest(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    name: &str,
+    hash: &str,
+    chunk_bounds: ChunkBounds,
+) -> Result<ArtifactBuildRequest, Status> {
+    let request = RegistryRequest {
+        accept_dictionary: false,
+        hash: hash.to_string(),
+        kind: RegistryKind::ArtifactManifest as i32,
+        name: name.to_string(),
+    };
+
+    let data = pull_data(backend, &request, chunk_bounds).await?;
+
+    let data = decode_manifest(backend, dictionary, data, chunk_bounds).await?;
+
+    serde_json::from_slice(&data).map_err(|err| {
+        Status::internal(format!(
+            "failed to parse manifest {}-{}: {:?}",
+            name, hash, err
+        ))
+    })
+}
+
+/// Deletes stored objects that are older than the minimum age and no longer referenced.
+///
+/// Every stored artifact manifest is a root, however long ago it was pushed, so artifacts still
+/// used as cache hits are kept. Each manifest references its sources and artifact dependencies,
+/// which are retained in turn. Artifacts and provenance without a manifest and sources no
+/// manifest references are pruned once old enough, step snapshots are pruned on age alone and
+/// dictionaries are always kept. Artifacts are removed from the registry by deleting their
+/// manifest.
+pub async fn prune(
+    backend: &dyn RegistryBackend,
+    dictionary: &Option<Vec<u8>>,
+    request: &RegistryPruneRequest,
+    chunk_bounds: ChunkBounds,
+) -> Result<RegistryPruneResponse, Status> {
+    if request.min_age_seconds == 0 {
+        return Err(Status::invalid_argument("missing `min_age_seconds` field"));
+    }
+
+    let min_age = Duration::from_secs(request.min_age_seconds);
+    let now = SystemTime::now();
+
+    let objects = backend.list_objects().await?;
+
+    let manifests = objects
+        .iter()
+        .filter(|o| o.kind == RegistryKind::ArtifactManifest)
+        .map(|o| (o.name.clone(), o.hash.to_string()))
+        .collect::<BTreeSet<_>>();
+
+    // 1. Mark artifacts and sources referenced from stored manifests
+
+    let mut pending = manifests.iter().cloned().collect::<Vec<_>>();
+
+    let mut retained_artifacts = BTreeSet::new();
+    let mut retained_sources = BTreeSet::new();
+
+    while let Some((name, hash)) = pending.pop() {
+        if !retained_artifacts.insert((name.clone(), hash.clone())) {
+            continue;
+        }
+
+        if !manifests.contains(&(name.clone(), hash.clone())) {
+            continue;
+        }
+
+        let manifest = get_manifest(backend, dictionary, &name, &hash, chunk_bounds).await?;
+
+        let Some(artifact) = manifest.artifact else {
+            continue;
+        };
+
+        for source in artifact.sources.iter() {
+            retained_sources.insert((source.name.clone(), source.hash.clone()));
+        }
+
+        for dependency in artifact.artifacts.iter() {
+            pending.push((dependency.name.clone(), dependency.hash.clone()));
+        }
+    }
+
+    // 2. Collect old objects without references
+
+    let mut pruned = vec![];
+    let mut retained = 0;
+
+    for object in objects.iter() {
+        let age = get_object_age(object, now);
+
+        let id = (object.name.clone(), object.hash.to_string());
+
+        let referenced = match object.kind {
+            RegistryKind::Artifact
+            | RegistryKind::ArtifactManifest
+            | RegistryKind::ArtifactProvenance => retained_artifacts.contains(&id),
+            RegistryKind::ArtifactSource => retained_sources.contains(&id),
+            RegistryKind::ArtifactStep => false,
+            _ => true,
+        };
+
+        if referenced || age < min_age {
+            retained += 1;
+
+            continue;
+        }
+
+        pruned.push((object, age));
+    }
+
+    pruned.sort_by(|(a, _), (b, _)| (a.kind, &a.name, &a.hash).cmp(&(b.kind, &b.name, &b.hash)));
+
+    // 3. Delete unless this is a dry run
+
+    let mut response = RegistryPruneResponse {
+        objects: vec![],
+        reclaimable_bytes: 0,
+        retained,
+    };
+
+    for (object, age) in pruned {
+        if !request.dry_run {
+            backend.delete(object).await?;
+
+            info!(
+                "pruned {}: {}-{} ({} bytes)",
+                object.kind.as_str_name(),
+                object.name,
+                object.hash,
+                object.size
+            );
+        }
+
+        response.reclaimable_bytes += object.size;
+
+        response.objects.push(RegistryPruneObject {
+            age_seconds: age.as_secs(),
+            hash: object.hash.to_string(),
+            kind: object.kind as i32,
+            name: object.name.clone(),
+            size: object.size,
+        });
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryRegistryBackend;
+    use vorpal_schema::vorpal::artifact::v0::{Artifact, ArtifactId, ArtifactSourceId};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn get_manifest_data(artifacts: &[(&str, &str)], sources: &[(&str, &str)]) -> Vec<u8> {
+        let request = ArtifactBuildRequest {
+            artifact: Some(Artifact {
+                artifacts: artifacts
+                    .iter()
+                    .map(|(name, hash)| ArtifactId {
+                        hash: hash.to_string(),
+                        name: name.to_string(),
+                    })
+                    .collect(),
+                sources: sources
+                    .iter()
+                    .map(|(name, hash)| ArtifactSourceId {
+                        hash: hash.to_string(),
+                        name: name.to_string(),
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        serde_json::to_vec(&request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_prune_keeps_everything_referenced_by_stored_manifests() {
+        let backend = MemoryRegistryBackend::default();
+        let old = SystemTime::now() - 60 * DAY;
+
+        let app = "a".repeat(64);
+        let lib = "b".repeat(64);
+        let orphan = "c".repeat(64);
+        let app_source = "1".repeat(64);
+        let lib_source = "2".repeat(64);
+        let unused_source = "3".repeat(64);
+        let recent_source = "4".repeat(64);
+        let step = "5".repeat(64);
+
+        // Artifacts pushed long ago but still stored with their manifest, as cache hits leave them
+
+        backend.insert(
+            RegistryKind::ArtifactManifest,
+            "app",
+            &app,
+            get_manifest_data(&[("lib", &lib)], &[("app-src", &app_source)]),
+            old,
+        );
+        backend.insert(RegistryKind::Artifact, "app", &app, vec![0; 10], old);
+        backend.insert(
+            RegistryKind::ArtifactProvenance,
+            "app",
+            &app,
+            vec![0; 1],
+            old,
+        );
+        backend.insert(
+            RegistryKind::ArtifactManifest,
+            "lib",
+            &lib,
+            get_manifest_data(&[], &[("lib-src", &lib_source)]),
+            old,
+        );
+        backend.insert(RegistryKind::Artifact, "lib", &lib, vec![0; 10], old);
+        backend.insert(
+            RegistryKind::ArtifactSource,
+            "app-src",
+            &app_source,
+            vec![0; 5],
+            old,
+        );
+        backend.insert(
+            RegistryKind::ArtifactSource,
+            "lib-src",
+            &lib_source,
+            vec![0; 5],
+            old,
+        );
+
+        // Objects no manifest references
+
+        backend.insert(RegistryKind::Artifact, "orphan", &orphan, vec![0; 7], old);
+        backend.insert(
+            RegistryKind::ArtifactSource,
+            "unused",
+            &unused_source,
+            vec![0; 3],
+            old,
+        );
+        backend.insert(
+            RegistryKind::ArtifactSource,
+            "recent",
+            &recent_source,
+            vec![0; 3],
+            SystemTime::now(),
+        );
+        backend.insert(RegistryKind::ArtifactStep, "step", &step, vec![0; 2], old);
+
+        let request = RegistryPruneRequest {
+            dry_run: false,
+            min_age_seconds: (30 * DAY).as_secs(),
+        };
+
+        let response = prune(&backend, &None, &request, ChunkBounds::default())
+            .await
+            .unwrap();
+
+        let mut pruned = response
+            .objects
+            .iter()
+            .map(|object| object.name.as_str())
+            .collect::<Vec<_>>();
+
+        pruned.sort();
+
+        assert_eq!(pruned, ["orphan", "step", "unused"]);
+        assert_eq!(response.reclaimable_bytes, 12);
+        assert_eq!(response.retained, 8);
+
+        assert!(backend.contains(RegistryKind::Artifact, "app", &app));
+        assert!(backend.contains(RegistryKind::Artifact, "lib", &lib));
+        assert!(backend.contains(RegistryKind::ArtifactSource, "lib-src", &lib_source));
+        assert!(backend.contains(RegistryKind::ArtifactSource, "recent", &recent_source));
+        assert!(!backend.contains(RegistryKind::ArtifactSource, "unused", &unused_source));
+    }
+
+    #[tokio::test]
+    async fn test_prune_dry_run_deletes_nothing() {
+        let backend = MemoryRegistryBackend::default();
+        let source = "3".repeat(64);
+
+        backend.insert(
+            RegistryKind::ArtifactSource,
+            "unused",
+            &source,
+            vec![0; 3],
+            SystemTime::now() - 60 * DAY,
+        );
+
+        let request = RegistryPruneRequest {
+            dry_run: true,
+            min_age_seconds: (30 * DAY).as_secs(),
+        };
+
+        let response = prune(&backend, &None, &request, ChunkBounds::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.objects.len(), 1);
+        assert!(backend.contains(RegistryKind::ArtifactSource, "unused", &source));
+    }
+}
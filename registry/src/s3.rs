@@ -1,15 +1,48 @@
-use aws_sdk_s3::Client;
+use aws_sdk_s3::{
+    primitives::{ByteStream, Length},
+    types::{CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use std::{path::Path, time::SystemTime};
 use tokio::sync::mpsc;
 use tonic::{async_trait, Status};
-use vorpal_schema::vorpal::registry::v0::{RegistryKind, RegistryPullResponse, RegistryRequest};
-use vorpal_store::paths::get_store_dir_name;
+use tracing::debug;
+use vorpal_schema::vorpal::{
+    artifact::v0::ArtifactId,
+    registry::v0::{
+        RegistryKind, RegistryListRequest, RegistryListResponse, RegistryPullResponse,
+        RegistryRequest,
+    },
+};
+use vorpal_store::{
+    chunks::{ChunkBounds, ChunkSizer},
+    digests::{ArchiveDigest, ArtifactDigest},
+    paths::get_store_dir_name,
+};
 
-use crate::{PushMetadata, RegistryBackend, RegistryError};
+use crate::{
+    exists_concurrent, get_latest_object, get_request_digest, send_chunk, ObjectDigest,
+    PushMetadata, RegistryBackend, RegistryError, RegistryObject,
+};
+
+// Object metadata holding the digest of the stored data
+const DIGEST_METADATA_KEY: &str = "vorpal-digest";
+
+// Chunk indexes of stored sources are kept next to them with this suffix
+const DELTA_INDEX_SUFFIX: &str = ".chunks";
+
+// Pushes larger than one part are uploaded in parts of this size
+const PART_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+
+// Limits of S3 multipart uploads
+const PART_SIZE_MIN: u64 = 5 * 1024 * 1024; // 5MB
+const PART_COUNT_MAX: u64 = 10_000;
 
 #[derive(Clone, Debug)]
 pub struct S3RegistryBackend {
     bucket: String,
     client: Client,
+    part_size: u64,
 }
 
 impl S3RegistryBackend {
@@ -21,24 +54,290 @@ impl S3RegistryBackend {
         let client_config = aws_config::load_from_env().await;
         let client = Client::new(&client_config);
 
-        Ok(Self { bucket, client })
+        Ok(Self {
+            bucket,
+            client,
+            part_size: PART_SIZE,
+        })
+    }
+
+    /// Lists the stored objects with keys starting with `prefix`.
+    async fn list_prefix(&self, prefix: &str) -> Result<Vec<RegistryObject>, Status> {
+        let mut objects = vec![];
+        let mut continuation_token = None;
+
+        // Buckets hold more keys than a single response returns, list every page
+
+        loop {
+            let response = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(|err| Status::internal(format!("failed to list store: {:?}", err)))?;
+
+            for object in response.contents() {
+                let Some((kind, name, hash)) = object.key().and_then(get_object_kind) else {
+                    continue;
+                };
+
+                let Ok(hash) = ObjectDigest::parse(kind, hash) else {
+                    continue;
+                };
+
+                let modified = object
+                    .last_modified()
+                    .and_then(|time| SystemTime::try_from(*time).ok())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+
+                objects.push(RegistryObject {
+                    hash,
+                    kind,
+                    modified,
+                    name: name.to_string(),
+                    size: object.size().unwrap_or_default() as u64,
+                });
+            }
+
+            continuation_token = response.next_continuation_token().map(str::to_string);
+
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Overrides the size of multipart upload parts, at least the S3 minimum of 5MB.
+    pub fn with_part_size(mut self, part_size: u64) -> Self {
+        self.part_size = part_size.max(PART_SIZE_MIN);
+        self
+    }
+
+    /// Uploads `data_path` in parts read from the file, aborting the upload when a part fails.
+    async fn push_multipart(
+        &self,
+        key: &str,
+        compression: &str,
+        data_digest: &str,
+        data_path: &Path,
+        data_size: u64,
+    ) -> Result<(), Status> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .metadata("vorpal-compression", compression)
+            .metadata(DIGEST_METADATA_KEY, data_digest)
+            .send()
+            .await
+            .map_err(|err| Status::internal(format!("failed to start upload: {:?}", err)))?;
+
+        let Some(upload_id) = upload.upload_id() else {
+            return Err(Status::internal(
+                "failed to start upload: missing upload id",
+            ));
+        };
+
+        let result = self.push_parts(key, upload_id, data_path, data_size).await;
+
+        let parts = match result {
+            Ok(parts) => parts,
+            Err(err) => {
+                let _ = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await;
+
+                return Err(err);
+            }
+        };
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|err| Status::internal(format!("failed to complete upload: {:?}", err)))?;
+
+        Ok(())
+    }
+
+    async fn push_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        data_path: &Path,
+        data_size: u64,
+    ) -> Result<Vec<CompletedPart>, Status> {
+        let mut parts = vec![];
+
+        for (index, (offset, length)) in
+            get_parts(data_size, self.part_size).into_iter().enumerate()
+        {
+            let part_number = index as i32 + 1;
+
+            let body = ByteStream::read_from()
+                .path(data_path)
+                .offset(offset)
+                .length(Length::Exact(length))
+                .build()
+                .await
+                .map_err(|err| Status::internal(format!("failed to read push file: {:?}", err)))?;
+
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .content_length(length as i64)
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| {
+                    Status::internal(format!("failed to upload part {}: {:?}", part_number, err))
+                })?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+
+        Ok(parts)
     }
 }
 
-fn artifact_key(kind: RegistryKind, hash: &str, name: &str) -> Result<String, Status> {
+/// Returns the offset and length of each part of a multipart upload of `size` bytes.
+///
+/// Parts grow past `part_size` when the upload would have more than S3 allows.
+fn get_parts(size: u64, part_size: u64) -> Vec<(u64, u64)> {
+    let part_size = part_size.max(size.div_ceil(PART_COUNT_MAX));
+
+    (0..size)
+        .step_by(part_size as usize)
+        .map(|offset| (offset, part_size.min(size - offset)))
+        .collect()
+}
+
+fn artifact_key(kind: RegistryKind, hash: &ObjectDigest, name: &str) -> Result<String, Status> {
+    let hash = &hash.to_string();
+
     match kind {
         RegistryKind::Artifact => Ok(format!("store/{}.artifact", get_store_dir_name(hash, name))),
         RegistryKind::ArtifactSource => {
             Ok(format!("store/{}.source", get_store_dir_name(hash, name)))
         }
+        RegistryKind::ArtifactStep => Ok(format!("store/{}.step", get_store_dir_name(hash, name))),
+        RegistryKind::ArtifactManifest => {
+            Ok(format!("store/{}.manifest", get_store_dir_name(hash, name)))
+        }
+        RegistryKind::ArtifactProvenance => Ok(format!(
+            "store/{}.provenance",
+            get_store_dir_name(hash, name)
+        )),
+        RegistryKind::Dictionary => Ok(format!("store/{}.dictionary", hash)),
         _ => Err(Status::invalid_argument("unsupported store kind")),
     }
 }
 
+// Key suffixes of prunable objects
+const OBJECT_SUFFIXES: [(&str, RegistryKind); 5] = [
+    (".artifact", RegistryKind::Artifact),
+    (".manifest", RegistryKind::ArtifactManifest),
+    (".provenance", RegistryKind::ArtifactProvenance),
+    (".source", RegistryKind::ArtifactSource),
+    (".step", RegistryKind::ArtifactStep),
+];
+
+fn get_object_kind(key: &str) -> Option<(RegistryKind, &str, &str)> {
+    let key = key.strip_prefix("store/")?;
+
+    for (suffix, kind) in OBJECT_SUFFIXES {
+        if let Some(store_name) = key.strip_suffix(suffix) {
+            let (name, hash) = store_name.rsplit_once('-')?;
+
+            return Some((kind, name, hash));
+        }
+    }
+
+    None
+}
+
+fn get_artifact_id(key: &str) -> Option<ArtifactId> {
+    let store_name = key.strip_prefix("store/")?.strip_suffix(".artifact")?;
+    let (name, hash) = store_name.rsplit_once('-')?;
+
+    hash.parse::<ArtifactDigest>().ok()?;
+
+    Some(ArtifactId {
+        hash: hash.to_string(),
+        name: name.to_string(),
+    })
+}
+
 #[async_trait]
 impl RegistryBackend for S3RegistryBackend {
+    async fn check(&self) -> Result<(), Status> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(|err| Status::unavailable(format!("failed to reach bucket: {:?}", err)))?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, object: &RegistryObject) -> Result<(), Status> {
+        let artifact_key = artifact_key(object.kind, &object.hash, &object.name)?;
+
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&artifact_key)
+            .send()
+            .await
+            .map_err(|err| Status::internal(format!("failed to delete store path: {:?}", err)))?;
+
+        if object.kind == RegistryKind::ArtifactSource {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(format!("{}{}", artifact_key, DELTA_INDEX_SUFFIX))
+                .send()
+                .await
+                .map_err(|err| {
+                    Status::internal(format!("failed to delete delta index: {:?}", err))
+                })?;
+        }
+
+        Ok(())
+    }
+
     async fn exists(&self, request: &RegistryRequest) -> Result<(), Status> {
-        let artifact_key = artifact_key(request.kind(), &request.hash, &request.name)?;
+        let artifact_key =
+            artifact_key(request.kind(), &get_request_digest(request)?, &request.name)?;
 
         let head_result = &self
             .client
@@ -55,12 +354,92 @@ impl RegistryBackend for S3RegistryBackend {
         Ok(())
     }
 
+    async fn exists_batch(&self, requests: &[RegistryRequest]) -> Result<Vec<bool>, Status> {
+        exists_concurrent(self, requests).await
+    }
+
+    async fn list(&self, request: &RegistryListRequest) -> Result<RegistryListResponse, Status> {
+        let page_size = request.page_size as usize;
+
+        let mut artifacts = vec![];
+        let mut continuation_token = None;
+
+        let start_after = match request.page_token.is_empty() {
+            true => None,
+            false => Some(format!("store/{}.artifact", request.page_token)),
+        };
+
+        // Objects are listed in key order, one backend page at a time
+
+        loop {
+            let response = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(format!("store/{}", request.name_prefix))
+                .set_continuation_token(continuation_token)
+                .set_start_after(start_after.clone())
+                .send()
+                .await
+                .map_err(|err| Status::internal(format!("failed to list store: {:?}", err)))?;
+
+            for object in response.contents() {
+                if let Some(artifact) = object.key().and_then(get_artifact_id) {
+                    artifacts.push(artifact);
+                }
+
+                if artifacts.len() > page_size {
+                    break;
+                }
+            }
+
+            continuation_token = response.next_continuation_token().map(str::to_string);
+
+            if artifacts.len() > page_size || continuation_token.is_none() {
+                break;
+            }
+        }
+
+        let mut next_page_token = String::new();
+
+        if artifacts.len() > page_size {
+            artifacts.truncate(page_size);
+
+            if let Some(last) = artifacts.last() {
+                next_page_token = get_store_dir_name(&last.hash, &last.name);
+            }
+        }
+
+        Ok(RegistryListResponse {
+            artifacts,
+            next_page_token,
+        })
+    }
+
+    async fn list_objects(&self) -> Result<Vec<RegistryObject>, Status> {
+        self.list_prefix("store/").await
+    }
+
+    async fn latest(
+        &self,
+        kind: RegistryKind,
+        name: &str,
+    ) -> Result<Option<RegistryObject>, Status> {
+        // Keys of other names starting with `name-` are listed too, and filtered out by name
+
+        let objects = self.list_prefix(&format!("store/{}-", name)).await?;
+
+        Ok(get_latest_object(objects, kind, name))
+    }
+
     async fn pull(
         &self,
         request: &RegistryRequest,
         tx: mpsc::Sender<Result<RegistryPullResponse, Status>>,
+        chunk_bounds: ChunkBounds,
     ) -> Result<(), Status> {
-        let artifact_key = artifact_key(request.kind(), &request.hash, &request.name)?;
+        let artifact_key =
+            artifact_key(request.kind(), &get_request_digest(request)?, &request.name)?;
 
         let client = &self.client;
         let client_bucket_name = &self.bucket;
@@ -82,25 +461,170 @@ impl RegistryBackend for S3RegistryBackend {
             .map_err(|err| Status::internal(err.to_string()))?
             .body;
 
+        // Re-chunk the object body to the adaptive chunk size
+
+        let mut sizer = ChunkSizer::new(chunk_bounds);
+        let mut buffer = vec![];
+
         while let Some(chunk_result) = stream.next().await {
             let chunk = chunk_result.map_err(|err| Status::internal(err.to_string()))?;
 
-            tx.send(Ok(RegistryPullResponse {
-                data: chunk.to_vec(),
-            }))
-            .await
-            .map_err(|err| Status::internal(format!("failed to send store chunk: {:?}", err)))?;
+            buffer.extend_from_slice(&chunk);
+
+            while buffer.len() >= sizer.size() {
+                let data = buffer.drain(..sizer.size()).collect();
+
+                send_chunk(data, &mut sizer, &tx).await?;
+            }
         }
 
+        if !buffer.is_empty() {
+            send_chunk(buffer, &mut sizer, &tx).await?;
+        }
+
+        debug!("pull transfer: {}", sizer.summary());
+
+        Ok(())
+    }
+
+    async fn pull_manifest(&self, artifact_id: &ArtifactId) -> Result<Option<Vec<u8>>, Status> {
+        let artifact_key = artifact_key(
+            RegistryKind::ArtifactManifest,
+            &ObjectDigest::parse(RegistryKind::ArtifactManifest, &artifact_id.hash)?,
+            &artifact_id.name,
+        )?;
+
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&artifact_key)
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_no_such_key()) =>
+            {
+                return Ok(None);
+            }
+            Err(err) => return Err(Status::internal(err.to_string())),
+        };
+
+        let data = object
+            .body
+            .collect()
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .into_bytes()
+            .to_vec();
+
+        Ok(Some(data))
+    }
+
+    async fn pull_digest(
+        &self,
+        request: &RegistryRequest,
+    ) -> Result<Option<ArchiveDigest>, Status> {
+        let artifact_key =
+            artifact_key(request.kind(), &get_request_digest(request)?, &request.name)?;
+
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&artifact_key)
+            .send()
+            .await
+            .map_err(|err| Status::not_found(err.to_string()))?;
+
+        // Objects pushed before digests were recorded have no digest metadata
+
+        let Some(digest) = head.metadata().and_then(|m| m.get(DIGEST_METADATA_KEY)) else {
+            return Ok(None);
+        };
+
+        let digest = digest
+            .parse::<ArchiveDigest>()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Some(digest))
+    }
+
+    async fn pull_delta_index(
+        &self,
+        hash: &ObjectDigest,
+        name: &str,
+    ) -> Result<Option<Vec<String>>, Status> {
+        let index_key = format!(
+            "{}{}",
+            artifact_key(RegistryKind::ArtifactSource, hash, name)?,
+            DELTA_INDEX_SUFFIX
+        );
+
+        // Sources pushed before indexes were recorded have no index object
+
+        let Ok(object) = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&index_key)
+            .send()
+            .await
+        else {
+            return Ok(None);
+        };
+
+        let data =
+            object.body.collect().await.map_err(|err| {
+                Status::internal(format!("failed to read delta index: {:?}", err))
+            })?;
+
+        let index = serde_json::from_slice(&data.into_bytes())
+            .map_err(|err| Status::internal(format!("failed to parse delta index: {:?}", err)))?;
+
+        Ok(Some(index))
+    }
+
+    async fn push_delta_index(
+        &self,
+        hash: &ObjectDigest,
+        name: &str,
+        index: &[String],
+    ) -> Result<(), Status> {
+        let index_key = format!(
+            "{}{}",
+            artifact_key(RegistryKind::ArtifactSource, hash, name)?,
+            DELTA_INDEX_SUFFIX
+        );
+
+        let data = serde_json::to_vec(index)
+            .map_err(|err| Status::internal(format!("failed to encode delta index: {:?}", err)))?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(index_key)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(|err| Status::internal(format!("failed to write delta index: {:?}", err)))?;
+
         Ok(())
     }
 
     async fn push(&self, metadata: PushMetadata) -> Result<(), Status> {
         let PushMetadata {
+            compression,
+            data_digest,
             data_kind,
+            data_path,
+            data_size,
             hash,
             name,
-            data,
+            ..
         } = metadata;
 
         let artifact_key = artifact_key(data_kind, &hash, &name)?;
@@ -119,11 +643,31 @@ impl RegistryBackend for S3RegistryBackend {
             return Ok(());
         }
 
+        // Streamed from the spooled push rather than read into memory, in parts when large
+
+        if data_size > self.part_size {
+            return self
+                .push_multipart(
+                    &artifact_key,
+                    compression.as_str_name(),
+                    data_digest.as_str(),
+                    &data_path,
+                    data_size,
+                )
+                .await;
+        }
+
+        let body = ByteStream::from_path(&data_path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to read push file: {:?}", err)))?;
+
         let _ = client
             .put_object()
             .bucket(bucket)
             .key(artifact_key)
-            .body(data.into())
+            .metadata("vorpal-compression", compression.as_str_name())
+            .metadata(DIGEST_METADATA_KEY, data_digest.as_str())
+            .body(body)
             .send()
             .await
             .map_err(|err| Status::internal(format!("failed to write store path: {:?}", err)))?;
@@ -131,7 +675,365 @@ impl RegistryBackend for S3RegistryBackend {
         Ok(())
     }
 
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
     fn box_clone(&self) -> Box<dyn RegistryBackend> {
         Box::new(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region, RequestChecksumCalculation};
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+    use vorpal_schema::vorpal::registry::v0::RegistryCompression;
+
+    // Request received by the S3 stub: method, path with query, headers and body
+    #[derive(Debug)]
+    struct StubRequest {
+        method: String,
+        path: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl StubRequest {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    async fn read_request(reader: &mut BufReader<TcpStream>) -> Option<StubRequest> {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let path = parts.next()?.to_string();
+
+        let mut headers = vec![];
+
+        loop {
+            let mut line = String::new();
+
+            reader.read_line(&mut line).await.ok()?;
+
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                break;
+            }
+
+            let (key, value) = line.split_once(':')?;
+
+            headers.push((key.to_lowercase(), value.trim().to_string()));
+        }
+
+        let mut request = StubRequest {
+            method,
+            path,
+            headers,
+            body: vec![],
+        };
+
+        if request.header("expect") == Some("100-continue") {
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .ok()?;
+        }
+
+        let length = request
+            .header("content-length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        request.body = vec![0; length];
+
+        reader.read_exact(&mut request.body).await.ok()?;
+
+        Some(request)
+    }
+
+    fn get_response(request: &StubRequest) -> String {
+        let (status, headers, body) = match request.method.as_str() {
+            "HEAD" => ("404 Not Found", String::new(), String::new()),
+            "POST" if request.path.ends_with("?uploads") => (
+                "200 OK",
+                String::new(),
+                "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                 <UploadId>upload</UploadId></InitiateMultipartUploadResult>"
+                    .to_string(),
+            ),
+            "POST" => (
+                "200 OK",
+                String::new(),
+                "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+                 <ETag>\"complete\"</ETag></CompleteMultipartUploadResult>"
+                    .to_string(),
+            ),
+            "PUT" => (
+                "200 OK",
+                format!("ETag: \"{}\"\r\n", request.body.len()),
+                String::new(),
+            ),
+            "GET" if request.path.contains("list-type=2") => {
+                ("200 OK", String::new(), get_list_response())
+            }
+            _ => ("204 No Content", String::new(), String::new()),
+        };
+
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    // Sources listed by the stub, including another name sharing the `example-` prefix
+    fn get_list_response() -> String {
+        let mut contents = String::new();
+
+        for (name, hash, date) in [
+            ("example", "a", "2024-01-01"),
+            ("example", "b", "2025-01-01"),
+            ("example-other", "c", "2026-01-01"),
+        ] {
+            contents.push_str(&format!(
+                "<Contents><Key>store/{}-{}.source</Key>\
+                 <LastModified>{}T00:00:00.000Z</LastModified><Size>1</Size></Contents>",
+                name,
+                hash.repeat(64),
+                date
+            ));
+        }
+
+        format!(
+            "<ListBucketResult><Name>bucket</Name><IsTruncated>false</IsTruncated>{}\
+             </ListBucketResult>",
+            contents
+        )
+    }
+
+    /// Starts an S3 stub answering uploads, returning the backend using it and its requests.
+    async fn start_stub() -> (S3RegistryBackend, Arc<Mutex<Vec<StubRequest>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let requests = Arc::new(Mutex::new(vec![]));
+        let stub_requests = requests.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let requests = stub_requests.clone();
+
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+
+                    while let Some(request) = read_request(&mut reader).await {
+                        let response = get_response(&request);
+
+                        requests.lock().unwrap().push(request);
+
+                        if reader
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let sdk_config = aws_config::defaults(BehaviorVersion::latest())
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .endpoint_url(format!("http://{}", address))
+            .region(Region::new("us-east-1"))
+            .load()
+            .await;
+
+        let config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(true)
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .build();
+
+        let backend = S3RegistryBackend {
+            bucket: "bucket".to_string(),
+            client: Client::from_conf(config),
+            part_size: PART_SIZE,
+        };
+
+        (backend, requests)
+    }
+
+    fn get_push_metadata(data_path: &Path, data: &[u8]) -> PushMetadata {
+        PushMetadata {
+            compression: RegistryCompression::Zstd,
+            data_digest: ArchiveDigest::from_data(data),
+            data_kind: RegistryKind::Artifact,
+            data_path: data_path.to_path_buf(),
+            data_signature: vec![],
+            data_size: data.len() as u64,
+            hash: ObjectDigest::parse(RegistryKind::Artifact, &"a".repeat(64)).unwrap(),
+            name: "example".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_get_parts() {
+        let mb = 1024 * 1024;
+
+        assert_eq!(get_parts(0, PART_SIZE), vec![]);
+        assert_eq!(get_parts(10, PART_SIZE), vec![(0, 10)]);
+        assert_eq!(
+            get_parts(12 * mb, 5 * mb),
+            vec![(0, 5 * mb), (5 * mb, 5 * mb), (10 * mb, 2 * mb)]
+        );
+
+        // Pushes too large for 10000 parts of the configured size use larger parts
+
+        let size = 1024 * 1024 * mb;
+        let parts = get_parts(size, PART_SIZE);
+
+        assert!(parts.len() as u64 <= PART_COUNT_MAX);
+        assert_eq!(parts.iter().map(|(_, length)| length).sum::<u64>(), size);
+        assert!(parts
+            .windows(2)
+            .all(|parts| parts[0].0 + parts[0].1 == parts[1].0));
+    }
+
+    #[tokio::test]
+    async fn test_push_large_uses_multipart_upload() {
+        let (backend, requests) = start_stub().await;
+
+        let backend = backend.with_part_size(5 * 1024 * 1024);
+
+        // Synthetic push of two and a half parts
+
+        let data = (0..12 * 1024 * 1024 + 512)
+            .map(|index| (index % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("push");
+
+        tokio::fs::write(&data_path, &data).await.unwrap();
+
+        backend
+            .push(get_push_metadata(&data_path, &data))
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+
+        let create = requests
+            .iter()
+            .find(|request| request.method == "POST" && request.path.ends_with("?uploads"))
+            .expect("multipart upload not started");
+
+        assert_eq!(
+            create.header("x-amz-meta-vorpal-digest"),
+            Some(ArchiveDigest::from_data(&data).as_str())
+        );
+
+        let parts = requests
+            .iter()
+            .filter(|request| request.method == "PUT")
+            .collect::<Vec<_>>();
+
+        assert_eq!(parts.len(), 3);
+        assert!(parts
+            .iter()
+            .all(|request| request.path.contains("uploadId=upload")));
+
+        assert_eq!(
+            parts
+                .iter()
+                .flat_map(|request| request.body.clone())
+                .collect::<Vec<_>>(),
+            data
+        );
+
+        let complete = requests.last().unwrap();
+
+        assert_eq!(complete.method, "POST");
+        assert!(complete.path.contains("uploadId=upload"));
+
+        let complete_body = String::from_utf8_lossy(&complete.body);
+
+        for part_number in 1..=3 {
+            assert!(complete_body.contains(&format!("<PartNumber>{}</PartNumber>", part_number)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_small_uses_single_request() {
+        let (backend, requests) = start_stub().await;
+
+        let data = b"small".to_vec();
+
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("push");
+
+        tokio::fs::write(&data_path, &data).await.unwrap();
+
+        backend
+            .push(get_push_metadata(&data_path, &data))
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+
+        let methods = requests
+            .iter()
+            .map(|request| request.method.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(methods, vec!["HEAD", "PUT"]);
+        assert_eq!(requests[1].body, data);
+    }
+
+    #[tokio::test]
+    async fn test_latest_lists_name_prefix() {
+        let (backend, requests) = start_stub().await;
+
+        let latest = backend
+            .latest(RegistryKind::ArtifactSource, "example")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(latest.hash.to_string(), "b".repeat(64));
+        assert_eq!(latest.name, "example");
+
+        // Only keys of the name are listed, not the whole store
+
+        let requests = requests.lock().unwrap();
+
+        assert_eq!(requests.len(), 1);
+        assert!(
+            requests[0].path.contains("prefix=store%2Fexample-"),
+            "{}",
+            requests[0].path
+        );
+    }
+}
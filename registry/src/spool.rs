@@ -0,0 +1,115 @@
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{read, File},
+    io::{AsyncWriteExt, BufWriter},
+};
+use tonic::Status;
+use vorpal_store::temps::create_sandbox_file;
+
+// Leading bytes kept to detect the archive format without reading the file back
+const SPOOL_PREFIX_SIZE: usize = 8;
+
+/// Sandbox file holding pushed data, removed when dropped unless a backend moved it away.
+pub struct SpoolFile {
+    path: PathBuf,
+}
+
+impl SpoolFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Pushed data written to a spool file as it arrives, hashed on the way so the signature and
+/// digest never need the whole push in memory.
+pub struct Spool {
+    file: SpoolFile,
+    hasher: Sha256,
+    prefix: Vec<u8>,
+    size: u64,
+    writer: BufWriter<File>,
+}
+
+/// Spooled push, its file with the hash, leading bytes and size of the data.
+pub struct SpoolData {
+    pub file: SpoolFile,
+    pub hasher: Sha256,
+    pub prefix: Vec<u8>,
+    pub size: u64,
+}
+
+impl Spool {
+    pub async fn create() -> Result<Self, Status> {
+        let path = create_sandbox_file(Some("push"))
+            .await
+            .map_err(|err| Status::internal(format!("failed to create push file: {:?}", err)))?;
+
+        let file = File::create(&path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to open push file: {:?}", err)))?;
+
+        Ok(Self {
+            file: SpoolFile { path },
+            hasher: Sha256::new(),
+            prefix: vec![],
+            size: 0,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+        if self.prefix.len() < SPOOL_PREFIX_SIZE {
+            let missing = SPOOL_PREFIX_SIZE - self.prefix.len();
+
+            self.prefix
+                .extend_from_slice(&data[..missing.min(data.len())]);
+        }
+
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+
+        self.writer
+            .write_all(data)
+            .await
+            .map_err(|err| Status::internal(format!("failed to write push file: {:?}", err)))
+    }
+
+    pub async fn finish(mut self) -> Result<SpoolData, Status> {
+        self.writer
+            .flush()
+            .await
+            .map_err(|err| Status::internal(format!("failed to write push file: {:?}", err)))?;
+
+        Ok(SpoolData {
+            file: self.file,
+            hasher: self.hasher,
+            prefix: self.prefix,
+            size: self.size,
+        })
+    }
+
+    /// Spools data already in memory, such as an archive re-created from a delta.
+    pub async fn from_data(data: &[u8]) -> Result<SpoolData, Status> {
+        let mut spool = Self::create().await?;
+
+        spool.write(data).await?;
+
+        spool.finish().await
+    }
+}
+
+impl SpoolData {
+    /// Reads the spooled data, only used for data known to be small.
+    pub async fn read(&self) -> Result<Vec<u8>, Status> {
+        read(self.file.path())
+            .await
+            .map_err(|err| Status::internal(format!("failed to read push file: {:?}", err)))
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tonic::Status;
+use vorpal_schema::vorpal::registry::v0::RegistryKind;
+use vorpal_store::metrics::Metric;
+
+pub static REGISTRY_EXISTS_TOTAL: Metric = Metric::counter(
+    "vorpal_registry_exists_total",
+    "Existence checks of the registry, by result (hit, miss or error).",
+);
+
+pub static REGISTRY_PULL_BYTES_TOTAL: Metric = Metric::counter(
+    "vorpal_registry_pull_bytes_total",
+    "Bytes sent by registry pulls.",
+);
+
+pub static REGISTRY_PULL_DURATION_SECONDS: Metric = Metric::histogram(
+    "vorpal_registry_pull_duration_seconds",
+    "Duration of registry pulls, until the client received the last chunk.",
+);
+
+pub static REGISTRY_PULL_TOTAL: Metric = Metric::counter(
+    "vorpal_registry_pull_total",
+    "Pulls served by the registry.",
+);
+
+pub static REGISTRY_PUSH_BYTES_TOTAL: Metric = Metric::counter(
+    "vorpal_registry_push_bytes_total",
+    "Bytes stored by registry pushes.",
+);
+
+pub static REGISTRY_PUSH_DURATION_SECONDS: Metric = Metric::histogram(
+    "vorpal_registry_push_duration_seconds",
+    "Duration of registry pushes, from the first chunk until stored.",
+);
+
+pub static REGISTRY_PUSH_TOTAL: Metric = Metric::counter(
+    "vorpal_registry_push_total",
+    "Pushes stored by the registry.",
+);
+
+pub static REGISTRY_SIGNATURE_FAILURES_TOTAL: Metric = Metric::counter(
+    "vorpal_registry_signature_failures_total",
+    "Pushes rejected for an invalid data signature.",
+);
+
+fn get_kind_label(kind: RegistryKind) -> String {
+    kind.as_str_name().to_lowercase()
+}
+
+/// Records requests served by a registry as metrics labelled by backend.
+#[derive(Debug)]
+pub struct RegistryStats {
+    backend: &'static str,
+}
+
+impl RegistryStats {
+    pub fn new(backend: &'static str) -> Self {
+        Self { backend }
+    }
+
+    /// Records an existence check, failed lookups are errors rather than misses.
+    pub fn record_exists(&self, exists: &Result<bool, Status>) {
+        let result = match exists {
+            Ok(true) => "hit",
+            Ok(false) => "miss",
+            Err(_) => "error",
+        };
+
+        REGISTRY_EXISTS_TOTAL.increment(
+            &[
+                ("backend", self.backend),
+                ("result", result),
+                ("service", "registry"),
+            ],
+            1,
+        );
+    }
+
+    pub fn record_push(&self, kind: RegistryKind, bytes: usize, duration: Duration) {
+        let kind = get_kind_label(kind);
+
+        let labels = [
+            ("backend", self.backend),
+            ("kind", kind.as_str()),
+            ("service", "registry"),
+        ];
+
+        REGISTRY_PUSH_BYTES_TOTAL.increment(&labels, bytes as u64);
+        REGISTRY_PUSH_DURATION_SECONDS.observe(&labels, duration.as_secs_f64());
+        REGISTRY_PUSH_TOTAL.increment(&labels, 1);
+    }
+
+    pub fn record_signature_failure(&self) {
+        REGISTRY_SIGNATURE_FAILURES_TOTAL
+            .increment(&[("backend", self.backend), ("service", "registry")], 1);
+    }
+
+    /// Counts a pull, recording its duration when the returned guard is dropped.
+    pub fn start_pull(self: &Arc<Self>, kind: RegistryKind) -> RegistryPullGuard {
+        let kind = get_kind_label(kind);
+
+        REGISTRY_PULL_TOTAL.increment(
+            &[
+                ("backend", self.backend),
+                ("kind", kind.as_str()),
+                ("service", "registry"),
+            ],
+            1,
+        );
+
+        RegistryPullGuard {
+            kind,
+            started: Instant::now(),
+            stats: self.clone(),
+        }
+    }
+}
+
+/// Counts the bytes of an active pull, recording its duration when dropped.
+pub struct RegistryPullGuard {
+    kind: String,
+    started: Instant,
+    stats: Arc<RegistryStats>,
+}
+
+impl RegistryPullGuard {
+    pub fn record_bytes(&self, bytes: usize) {
+        REGISTRY_PULL_BYTES_TOTAL.increment(
+            &[
+                ("backend", self.stats.backend),
+                ("kind", self.kind.as_str()),
+                ("service", "registry"),
+            ],
+            bytes as u64,
+        );
+    }
+}
+
+impl Drop for RegistryPullGuard {
+    fn drop(&mut self) {
+        REGISTRY_PULL_DURATION_SECONDS.observe(
+            &[
+                ("backend", self.stats.backend),
+                ("kind", self.kind.as_str()),
+                ("service", "registry"),
+            ],
+            self.started.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vorpal_store::metrics::get_metrics_text;
+
+    #[test]
+    fn test_record_exists_counts_errors_apart_from_misses() {
+        let stats = RegistryStats::new("test_exists");
+
+        stats.record_exists(&Ok(true));
+        stats.record_exists(&Ok(false));
+        stats.record_exists(&Ok(false));
+        stats.record_exists(&Err(Status::unavailable("backend unavailable")));
+
+        let text = get_metrics_text();
+
+        for (result, count) in [("hit", 1), ("miss", 2), ("error", 1)] {
+            let line = format!(
+                "vorpal_registry_exists_total{{backend=\"test_exists\",result=\"{}\",service=\"registry\"}} {}",
+                result, count
+            );
+
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+}
@@ -4,6 +4,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "vorpal.artifact.v0.ArtifactSystem",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
+        .message_attribute(
+            "vorpal.config.v0.ConfigArtifactSource",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .message_attribute(
+            "vorpal.config.v0.ConfigSourceLock",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .message_attribute(
+            "vorpal.config.v0.ConfigSourceRevision",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
         .message_attribute(
             "vorpal.config.v0.Config",
             "#[derive(serde::Serialize, serde::Deserialize)]",
@@ -24,6 +36,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "vorpal.artifact.v0.ArtifactStepEnvironment",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
+        .message_attribute(
+            "vorpal.artifact.v0.ArtifactStepIncremental",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
         .message_attribute(
             "vorpal.artifact.v0.ArtifactStep",
             "#[derive(serde::Serialize, serde::Deserialize)]",
@@ -32,12 +48,58 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "vorpal.artifact.v0.Artifact",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
+        .message_attribute(
+            "vorpal.artifact.v0.ArtifactProvenanceStep",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .message_attribute(
+            "vorpal.artifact.v0.ArtifactSourceRevision",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .message_attribute(
+            "vorpal.artifact.v0.ArtifactProvenance",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .message_attribute(
+            "vorpal.artifact.v0.ArtifactProvenanceEnvelope",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
         .message_attribute(
             "vorpal.artifact.v0.ArtifactBuildRequest",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
+        .field_attribute(
+            "vorpal.artifact.v0.Artifact.output_excludes",
+            "#[serde(default, skip_serializing_if = \"Vec::is_empty\")]",
+        )
+        .field_attribute(
+            "vorpal.artifact.v0.ArtifactStep.incremental",
+            "#[serde(default, skip_serializing_if = \"Option::is_none\")]",
+        )
+        .field_attribute(
+            "vorpal.artifact.v0.ArtifactProvenance.source_revisions",
+            "#[serde(default, skip_serializing_if = \"Vec::is_empty\")]",
+        )
+        .field_attribute("vorpal.config.v0.Config.revisions", "#[serde(default)]")
+        .field_attribute(
+            "vorpal.artifact.v0.ArtifactBuildRequest.force",
+            "#[serde(skip)]",
+        )
+        .field_attribute(
+            "vorpal.artifact.v0.ArtifactBuildRequest.secrets",
+            "#[serde(skip)]",
+        )
+        .field_attribute(
+            "vorpal.artifact.v0.ArtifactBuildRequest.push_policy",
+            "#[serde(skip)]",
+        )
+        .field_attribute(
+            "vorpal.artifact.v0.ArtifactBuildRequest.source_revisions",
+            "#[serde(skip)]",
+        )
         .compile_protos(
             &[
+                "v0/agent/agent.proto",
                 "v0/artifact/artifact.proto",
                 "v0/config/config.proto",
                 "v0/registry/registry.proto",
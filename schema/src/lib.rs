@@ -1,9 +1,24 @@
 use crate::vorpal::artifact::v0::{
-    ArtifactSystem,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, X8664Linux, X8664Macos},
+    Artifact, ArtifactId, ArtifactStepEnvironment, ArtifactSystem,
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
+use indoc::formatdoc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Component, Path},
+};
+
+pub mod sources;
 
 pub mod vorpal {
+    pub mod agent {
+        pub mod v0 {
+            tonic::include_proto!("vorpal.agent.v0");
+        }
+    }
+
     pub mod artifact {
         pub mod v0 {
             tonic::include_proto!("vorpal.artifact.v0");
@@ -23,6 +38,10 @@ pub mod vorpal {
     }
 }
 
+// Version of the config protocol, raised on changes config programs built against an older SDK
+// can not decode
+pub const CONFIG_PROTOCOL_VERSION: u32 = 1;
+
 pub trait ArtifactTarget {
     fn from_str(system: &str) -> Self;
 }
@@ -34,6 +53,7 @@ impl ArtifactTarget for ArtifactSystem {
             "aarch64-macos" => Aarch64Macos,
             "x86_64-linux" => X8664Linux,
             "x86_64-macos" => X8664Macos,
+            "x86_64-windows" => X8664Windows,
             _ => ArtifactSystem::default(),
         }
     }
@@ -42,3 +62,943 @@ impl ArtifactTarget for ArtifactSystem {
 pub fn get_artifact_system<T: ArtifactTarget>(target: &str) -> T {
     T::from_str(target)
 }
+
+/// Returns the name `get_artifact_system` parses into `system`, as given to `--system`.
+pub fn get_artifact_system_name(system: ArtifactSystem) -> &'static str {
+    match system {
+        Aarch64Linux => "aarch64-linux",
+        Aarch64Macos => "aarch64-macos",
+        X8664Linux => "x86_64-linux",
+        X8664Macos => "x86_64-macos",
+        X8664Windows => "x86_64-windows",
+        UnknownSystem => "unknown",
+    }
+}
+
+// Prefix of the variables holding artifact paths in steps
+const ARTIFACT_ENVKEY_PREFIX: &str = "VORPAL_ARTIFACT_";
+
+/// Returns the variable holding the path of artifact `name` in steps, without the `$`.
+pub fn get_artifact_envkey_name(name: &str) -> String {
+    format!(
+        "{}{}",
+        ARTIFACT_ENVKEY_PREFIX,
+        name.to_lowercase().replace('-', "_")
+    )
+}
+
+fn get_artifact_envkey_references(value: &str) -> Vec<&str> {
+    let mut references = vec![];
+
+    for (index, _) in value.match_indices(ARTIFACT_ENVKEY_PREFIX) {
+        // Only count expansions, `$NAME` or `${NAME}`, not escaped `\$NAME`
+
+        let prefix = &value[..index];
+
+        let Some(prefix) = prefix
+            .strip_suffix("${")
+            .or_else(|| prefix.strip_suffix('$'))
+        else {
+            continue;
+        };
+
+        if prefix.ends_with('\\') {
+            continue;
+        }
+
+        let rest = &value[index..];
+
+        // Versioned names keep their dots (`cargo_1.83.0`), the worker replaces references as
+        // text rather than through the shell
+
+        let end = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+            .unwrap_or(rest.len());
+
+        references.push(rest[..end].trim_end_matches('.'));
+    }
+
+    references
+}
+
+/// Checks every `$VORPAL_ARTIFACT_<name>` reference in an artifact's steps is defined.
+///
+/// Only the artifact itself and its declared artifacts are exported to steps, so any other
+/// reference would expand to an empty path at build time.
+pub fn validate_artifact_references(artifact: &Artifact) -> Result<(), String> {
+    let defined = artifact
+        .artifacts
+        .iter()
+        .map(|a| get_artifact_envkey_name(&a.name))
+        .chain([get_artifact_envkey_name(&artifact.name)])
+        .collect::<BTreeSet<_>>();
+
+    for (index, step) in artifact.steps.iter().enumerate() {
+        let values = step
+            .script
+            .iter()
+            .chain(step.arguments.iter())
+            .chain(step.environments.iter().map(|e| &e.value))
+            .chain(artifact.environments.iter().map(|e| &e.value));
+
+        for value in values {
+            for reference in get_artifact_envkey_references(value) {
+                if !defined.contains(reference) {
+                    return Err(format!(
+                        "artifact `{}` step {} references `${}` but no such artifact is declared, declared: {}",
+                        artifact.name,
+                        index,
+                        reference,
+                        defined.iter().cloned().collect::<Vec<_>>().join(", ")
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Placeholders in step entrypoints and arguments, `{{artifact:<digest>}}` takes a digest
+pub const STEP_PLACEHOLDER_ARTIFACT: &str = "artifact:";
+pub const STEP_PLACEHOLDER_OUTPUT: &str = "output";
+pub const STEP_PLACEHOLDER_WORKSPACE: &str = "workspace";
+
+/// Replaces each `{{<placeholder>}}` in `value` with `resolve(placeholder)`, failing on the
+/// first placeholder it does not resolve.
+pub fn expand_step_placeholders<F>(value: &str, resolve: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+
+        let placeholder = &rest[start + 2..start + end];
+
+        let Some(resolved) = resolve(placeholder) else {
+            return Err(format!("unknown placeholder `{{{{{}}}}}`", placeholder));
+        };
+
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&resolved);
+
+        rest = &rest[start + end + 2..];
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Checks placeholders in step entrypoints and arguments, artifact placeholders must name the
+/// digest of a declared artifact.
+pub fn validate_artifact_placeholders(artifact: &Artifact) -> Result<(), String> {
+    let resolve = |placeholder: &str| match placeholder {
+        STEP_PLACEHOLDER_OUTPUT | STEP_PLACEHOLDER_WORKSPACE => Some(String::new()),
+        _ => placeholder
+            .strip_prefix(STEP_PLACEHOLDER_ARTIFACT)
+            .filter(|digest| artifact.artifacts.iter().any(|a| a.hash == *digest))
+            .map(|_| String::new()),
+    };
+
+    for (index, step) in artifact.steps.iter().enumerate() {
+        for value in step.entrypoint.iter().chain(step.arguments.iter()) {
+            expand_step_placeholders(value, resolve).map_err(|err| {
+                format!("artifact `{}` step {} has an {}", artifact.name, index, err)
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats a dependency cycle by artifact name, e.g. `a -> b -> a`.
+pub fn get_artifact_cycle_error(cycle: &[ArtifactId]) -> String {
+    let names = cycle
+        .iter()
+        .map(|artifact_id| artifact_id.name.as_str())
+        .collect::<Vec<_>>();
+
+    format!("artifact dependency cycle: {}", names.join(" -> "))
+}
+
+fn get_artifact_cycle(
+    artifact_id: &ArtifactId,
+    artifacts: &HashMap<ArtifactId, Artifact>,
+    path: &mut Vec<ArtifactId>,
+    visited: &mut BTreeSet<ArtifactId>,
+) -> Option<Vec<ArtifactId>> {
+    if let Some(index) = path.iter().position(|id| id == artifact_id) {
+        let mut cycle = path[index..].to_vec();
+
+        cycle.push(artifact_id.clone());
+
+        return Some(cycle);
+    }
+
+    if visited.contains(artifact_id) {
+        return None;
+    }
+
+    path.push(artifact_id.clone());
+
+    if let Some(artifact) = artifacts.get(artifact_id) {
+        for dependency in artifact.artifacts.iter() {
+            if let Some(cycle) = get_artifact_cycle(dependency, artifacts, path, visited) {
+                return Some(cycle);
+            }
+        }
+    }
+
+    path.pop();
+
+    visited.insert(artifact_id.clone());
+
+    None
+}
+
+/// Checks the dependencies of `artifact_id` never lead back to an artifact on the same path.
+pub fn validate_artifact_dependencies(
+    artifact_id: &ArtifactId,
+    artifacts: &HashMap<ArtifactId, Artifact>,
+) -> Result<(), String> {
+    let mut path = vec![];
+    let mut visited = BTreeSet::new();
+
+    match get_artifact_cycle(artifact_id, artifacts, &mut path, &mut visited) {
+        Some(cycle) => Err(get_artifact_cycle_error(&cycle)),
+        None => Ok(()),
+    }
+}
+
+/// Returns whether a worker running on `host` can build artifacts for `target`.
+///
+/// Windows artifacts are cross-built on Linux and macOS hosts, all other targets build natively.
+pub fn is_artifact_system_buildable(host: ArtifactSystem, target: ArtifactSystem) -> bool {
+    match target {
+        X8664Windows => ![UnknownSystem, X8664Windows].contains(&host),
+        _ => host == target,
+    }
+}
+
+/// Checks `system` is one of the systems the artifact declares.
+pub fn validate_artifact_system(artifact: &Artifact, system: ArtifactSystem) -> Result<(), String> {
+    if artifact.systems.contains(&(system as i32)) {
+        return Ok(());
+    }
+
+    let systems = artifact
+        .systems()
+        .map(|system| system.as_str_name())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Err(format!(
+        "artifact `{}` does not support `{}` (systems: {})",
+        artifact.name,
+        system.as_str_name(),
+        systems
+    ))
+}
+
+// Entrypoints that run POSIX shell scripts, by name or by path (`/bin/bash`)
+const POSIX_SHELL_ENTRYPOINTS: [&str; 4] = ["bash", "bwrap", "sh", "zsh"];
+
+/// Checks the steps of an artifact can run for `system`.
+///
+/// Windows steps must use a PowerShell entrypoint, bash scripts and bare scripts run through
+/// their shebang are rejected.
+pub fn validate_artifact_steps(artifact: &Artifact, system: ArtifactSystem) -> Result<(), String> {
+    if system != X8664Windows {
+        return Ok(());
+    }
+
+    for (index, step) in artifact.steps.iter().enumerate() {
+        let entrypoint = step.entrypoint.as_deref().unwrap_or_default();
+
+        let entrypoint_name = Path::new(entrypoint)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        if POSIX_SHELL_ENTRYPOINTS.contains(&entrypoint_name)
+            || (entrypoint.is_empty() && step.script.is_some())
+        {
+            return Err(format!(
+                "artifact `{}` step {} has a bash script but targets `{}`, use a `pwsh` step",
+                artifact.name,
+                index,
+                system.as_str_name()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the first key declared more than once in `environments` with different values
+fn get_environment_conflict(
+    environments: &[ArtifactStepEnvironment],
+) -> Option<(&str, &str, &str)> {
+    let mut values = BTreeMap::<&str, &str>::new();
+
+    for environment in environments {
+        match values.insert(environment.key.as_str(), environment.value.as_str()) {
+            Some(value) if value != environment.value => {
+                return Some((environment.key.as_str(), value, environment.value.as_str()))
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Returns whether `path` is a non-empty relative path that stays within its root.
+fn is_step_subpath(path: &str) -> bool {
+    let mut components = Path::new(path).components().peekable();
+
+    components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Checks step working directories, output subdirectories and incremental paths are relative
+/// paths that stay within the workspace and output, and incremental keys are valid names.
+pub fn validate_artifact_step_paths(artifact: &Artifact) -> Result<(), String> {
+    for (index, step) in artifact.steps.iter().enumerate() {
+        let paths = [
+            ("working directory", &step.working_directory),
+            ("output subdirectory", &step.output_subdir),
+        ];
+
+        for (kind, path) in paths {
+            if let Some(path) = path {
+                if !is_step_subpath(path) {
+                    return Err(format!(
+                        "artifact `{}` step {} {} `{}` must be a relative path without `..`",
+                        artifact.name, index, kind, path
+                    ));
+                }
+            }
+        }
+
+        if let Some(incremental) = &step.incremental {
+            // Moved in and out of the workspace, so it can not be the workspace itself
+
+            let path_valid = is_step_subpath(&incremental.path)
+                && Path::new(&incremental.path)
+                    .components()
+                    .any(|component| matches!(component, Component::Normal(_)));
+
+            if !path_valid {
+                return Err(format!(
+                    "artifact `{}` step {} incremental path `{}` must be a relative path without `..`",
+                    artifact.name, index, incremental.path
+                ));
+            }
+
+            // Keys name the directory kept by the worker
+
+            let key_valid = !incremental.key.is_empty()
+                && !incremental.key.starts_with('.')
+                && incremental
+                    .key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+            if !key_valid {
+                return Err(format!(
+                    "artifact `{}` step {} incremental key `{}` must only contain letters, digits, `-`, `_` and `.`",
+                    artifact.name, index, incremental.key
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks environment variables declared across the steps of an artifact.
+///
+/// Returns warnings for variables set to different values by different steps, and an error
+/// when a step overrides an artifact-wide variable without listing it in
+/// `environment_overrides`, or when a key is declared twice with different values in the
+/// artifact-wide environment or a single step.
+pub fn validate_artifact_environments(artifact: &Artifact) -> Result<Vec<String>, String> {
+    if let Some((key, first, second)) = get_environment_conflict(&artifact.environments) {
+        return Err(format!(
+            "artifact `{}` environment `{}` is declared twice (`{}` and `{}`)",
+            artifact.name, key, first, second
+        ));
+    }
+
+    for (index, step) in artifact.steps.iter().enumerate() {
+        if let Some((key, first, second)) = get_environment_conflict(&step.environments) {
+            return Err(format!(
+                "artifact `{}` step {} environment `{}` is declared twice (`{}` and `{}`)",
+                artifact.name, index, key, first, second
+            ));
+        }
+    }
+
+    let artifact_environments = artifact
+        .environments
+        .iter()
+        .map(|e| (e.key.as_str(), e.value.as_str()))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut step_environments = BTreeMap::<&str, Vec<(usize, &str)>>::new();
+
+    for (index, step) in artifact.steps.iter().enumerate() {
+        for environment in step.environments.iter() {
+            let key = environment.key.as_str();
+            let value = environment.value.as_str();
+
+            if let Some(artifact_value) = artifact_environments.get(key) {
+                if *artifact_value != value && !step.environment_overrides.iter().any(|o| o == key)
+                {
+                    return Err(format!(
+                        "artifact `{}` environment `{}` is artifact-wide (`{}`) but step {} sets `{}` without an override",
+                        artifact.name, key, artifact_value, index, value
+                    ));
+                }
+
+                continue;
+            }
+
+            step_environments
+                .entry(key)
+                .or_default()
+                .push((index, value));
+        }
+    }
+
+    let mut warnings = vec![];
+
+    for (key, values) in step_environments {
+        let (first_index, first_value) = values[0];
+
+        for (index, value) in values.iter().skip(1) {
+            if *value != first_value {
+                warnings.push(format!(
+                    "artifact `{}` environment `{}` differs between step {} (`{}`) and step {} (`{}`)",
+                    artifact.name, key, first_index, first_value, index, value
+                ));
+            }
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// Returns the canonical script of a process artifact, which writes `bin/<name>-start`,
+/// `bin/<name>-stop` and `bin/<name>-logs` keeping the pid, log and state in `process_path`.
+///
+/// SDKs build process artifacts with this script, served by the `GetTemplate` RPC of the config
+/// service, so identical inputs produce identical digests in every SDK.
+pub fn get_process_template_script(
+    name: &str,
+    entrypoint: &str,
+    arguments: &[String],
+    process_path: &str,
+) -> String {
+    let arguments = arguments
+        .iter()
+        .map(|argument| format!("\"{}\"", argument))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let log_path = format!("{}/process.log", process_path);
+    let pid_path = format!("{}/process.pid", process_path);
+    let state_path = format!("{}/process.json", process_path);
+
+    // Entrypoint and arguments expand when the artifact is built, the rest when scripts run
+
+    formatdoc! {"
+        mkdir -pv $VORPAL_OUTPUT/bin
+
+        cat > $VORPAL_OUTPUT/bin/{name}-start << EOF
+        #!/bin/bash
+        set -eo pipefail
+
+        ENTRYPOINT=\"{entrypoint}\"
+        ARGUMENTS=({arguments})
+        EOF
+
+        cat >> $VORPAL_OUTPUT/bin/{name}-start << \"EOF\"
+
+        json_string() {{
+            local value=\"$1\"
+            value=\"${{value//\\\\/\\\\\\\\}}\"
+            value=\"${{value//\\\"/\\\\\\\"}}\"
+            value=\"${{value//$'\\n'/\\\\n}}\"
+            value=\"${{value//$'\\t'/\\\\t}}\"
+            printf '\"%s\"' \"$value\"
+        }}
+
+        if [ -f \"{pid_path}\" ] && kill -0 \"$(cat \"{pid_path}\")\" 2>/dev/null; then
+            echo \"{name} already running: $(cat \"{pid_path}\")\"
+            exit 0
+        fi
+
+        mkdir -p \"$(dirname \"{pid_path}\")\"
+
+        nohup \"$ENTRYPOINT\" \"${{ARGUMENTS[@]}}\" >> \"{log_path}\" 2>&1 &
+
+        PID=$!
+
+        ARGUMENTS_JSON=\"\"
+
+        for argument in \"${{ARGUMENTS[@]}}\"; do
+            ARGUMENTS_JSON=\"${{ARGUMENTS_JSON:+$ARGUMENTS_JSON,}}$(json_string \"$argument\")\"
+        done
+
+        ARTIFACT_PATH=\"$(cd \"$(dirname \"${{BASH_SOURCE[0]}}\")/..\" && pwd)\"
+
+        printf '{{\"arguments\":[%s],\"artifact\":%s,\"entrypoint\":%s,\"pid\":%d,\"started_at\":%d}}\\n' \\
+            \"$ARGUMENTS_JSON\" \\
+            \"$(json_string \"$ARTIFACT_PATH\")\" \\
+            \"$(json_string \"$ENTRYPOINT\")\" \\
+            \"$PID\" \\
+            \"$(date +%s)\" > \"{state_path}.tmp\"
+
+        mv \"{state_path}.tmp\" \"{state_path}\"
+
+        echo \"$PID\" > \"{pid_path}\"
+
+        echo \"{name} started: $PID\"
+        EOF
+
+        cat > $VORPAL_OUTPUT/bin/{name}-stop << \"EOF\"
+        #!/bin/bash
+        set -eo pipefail
+
+        if [ ! -f \"{pid_path}\" ]; then
+            echo \"{name} not running\"
+            exit 0
+        fi
+
+        PID=\"$(cat \"{pid_path}\")\"
+
+        if kill -0 \"$PID\" 2>/dev/null; then
+            kill \"$PID\"
+        fi
+
+        rm -f \"{pid_path}\"
+
+        echo \"{name} stopped: $PID\"
+        EOF
+
+        cat > $VORPAL_OUTPUT/bin/{name}-logs << \"EOF\"
+        #!/bin/bash
+        set -eo pipefail
+
+        tail -F \"{log_path}\"
+        EOF
+
+        chmod +x $VORPAL_OUTPUT/bin/{name}-start
+        chmod +x $VORPAL_OUTPUT/bin/{name}-stop
+        chmod +x $VORPAL_OUTPUT/bin/{name}-logs",
+    }
+}
+
+/// Returns the canonical script of a shell environment artifact, which writes `bin/activate`
+/// exporting `environments` ("KEY=VALUE") and restoring them on `exit-shell`.
+///
+/// SDKs build shell artifacts with this script, served by the `GetTemplate` RPC of the config
+/// service, so identical inputs produce identical digests in every SDK.
+pub fn get_shell_template_script(name: &str, environments: &[String]) -> String {
+    let mut backups = vec![
+        "export VORPAL_SHELL_BACKUP_PATH=\"$PATH\"".to_string(),
+        "export VORPAL_SHELL_BACKUP_PS1=\"$PS1\"".to_string(),
+        "export VORPAL_SHELL_BACKUP_VORPAL_SHELL=\"$VORPAL_SHELL\"".to_string(),
+    ];
+
+    let mut exports = vec![
+        format!("export PS1=\"({}) $PS1\"", name),
+        "export VORPAL_SHELL=\"1\"".to_string(),
+    ];
+
+    let mut restores = vec![
+        "export PATH=\"$VORPAL_SHELL_BACKUP_PATH\"".to_string(),
+        "export PS1=\"$VORPAL_SHELL_BACKUP_PS1\"".to_string(),
+        "export VORPAL_SHELL=\"$VORPAL_SHELL_BACKUP_VORPAL_SHELL\"".to_string(),
+    ];
+
+    let mut unsets = vec![
+        "unset VORPAL_SHELL_BACKUP_PATH".to_string(),
+        "unset VORPAL_SHELL_BACKUP_PS1".to_string(),
+        "unset VORPAL_SHELL_BACKUP_VORPAL_SHELL".to_string(),
+    ];
+
+    for env in environments {
+        let key = env.split('=').next().unwrap_or_default();
+        backups.push(format!("export VORPAL_SHELL_BACKUP_{}=\"${}\"", key, key));
+        exports.push(format!("export {}", env));
+        restores.push(format!("export {}=\"$VORPAL_SHELL_BACKUP_{}\"", key, key));
+        unsets.push(format!("unset VORPAL_SHELL_BACKUP_{}", key));
+    }
+
+    formatdoc! {"
+        mkdir -pv $VORPAL_WORKSPACE/bin
+
+        cat > bin/activate << \"EOF\"
+        #!/bin/bash
+
+        # Set backup variables
+        {backups}
+
+        # Set new variables
+        {exports}
+
+        # Restore old variables
+        exit-shell(){{
+        # Set restore variables
+        {restores}
+
+        # Set unset variables
+        {unsets}
+        }}
+
+        # Run the command
+        exec \"$@\"
+        EOF
+
+        chmod +x $VORPAL_WORKSPACE/bin/activate
+
+        mkdir -pv $VORPAL_OUTPUT/bin
+
+        cp -prv bin \"$VORPAL_OUTPUT\"",
+        backups = backups.join("\n"),
+        exports = exports.join("\n"),
+        restores = restores.join("\n"),
+        unsets = unsets.join("\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vorpal::artifact::v0::ArtifactStep;
+
+    fn get_test_environments(environments: &[(&str, &str)]) -> Vec<ArtifactStepEnvironment> {
+        environments
+            .iter()
+            .map(|(key, value)| ArtifactStepEnvironment {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn get_test_step(environments: &[(&str, &str)], overrides: &[&str]) -> ArtifactStep {
+        ArtifactStep {
+            environment_overrides: overrides.iter().map(|o| o.to_string()).collect(),
+            environments: get_test_environments(environments),
+            ..Default::default()
+        }
+    }
+
+    fn get_test_artifact(environments: &[(&str, &str)], steps: Vec<ArtifactStep>) -> Artifact {
+        Artifact {
+            environments: get_test_environments(environments),
+            name: "example".to_string(),
+            steps,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_artifact_environments_consistent() {
+        let artifact = get_test_artifact(
+            &[("LANG", "C")],
+            vec![
+                get_test_step(&[("LANG", "C"), ("PATH", "/bin")], &[]),
+                get_test_step(&[("PATH", "/bin")], &[]),
+            ],
+        );
+
+        assert_eq!(validate_artifact_environments(&artifact), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_validate_artifact_environments_step_warnings() {
+        let artifact = get_test_artifact(
+            &[],
+            vec![
+                get_test_step(&[("PATH", "/bin")], &[]),
+                get_test_step(&[("PATH", "/bin")], &[]),
+                get_test_step(&[("PATH", "/usr/bin")], &[]),
+            ],
+        );
+
+        assert_eq!(
+            validate_artifact_environments(&artifact),
+            Ok(vec![
+                "artifact `example` environment `PATH` differs between step 0 (`/bin`) and step 2 (`/usr/bin`)"
+                    .to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_artifact_environments_overrides() {
+        let step = get_test_step(&[("LANG", "en_US.UTF-8")], &[]);
+
+        let artifact = get_test_artifact(&[("LANG", "C")], vec![step]);
+
+        assert_eq!(
+            validate_artifact_environments(&artifact),
+            Err(
+                "artifact `example` environment `LANG` is artifact-wide (`C`) but step 0 sets `en_US.UTF-8` without an override"
+                    .to_string()
+            )
+        );
+
+        // Listed overrides are allowed and never reported as step differences
+
+        let artifact = get_test_artifact(
+            &[("LANG", "C")],
+            vec![
+                get_test_step(&[("LANG", "en_US.UTF-8")], &["LANG"]),
+                get_test_step(&[], &[]),
+            ],
+        );
+
+        assert_eq!(validate_artifact_environments(&artifact), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_validate_artifact_environments_duplicate_keys() {
+        let artifact = get_test_artifact(&[("LANG", "C"), ("LANG", "C.UTF-8")], vec![]);
+
+        assert_eq!(
+            validate_artifact_environments(&artifact),
+            Err(
+                "artifact `example` environment `LANG` is declared twice (`C` and `C.UTF-8`)"
+                    .to_string()
+            )
+        );
+
+        let artifact = get_test_artifact(
+            &[],
+            vec![
+                get_test_step(&[], &[]),
+                get_test_step(&[("PATH", "/bin"), ("PATH", "/usr/bin")], &[]),
+            ],
+        );
+
+        assert_eq!(
+            validate_artifact_environments(&artifact),
+            Err(
+                "artifact `example` step 1 environment `PATH` is declared twice (`/bin` and `/usr/bin`)"
+                    .to_string()
+            )
+        );
+
+        // Repeating a key with the same value is harmless
+
+        let artifact = get_test_artifact(
+            &[("LANG", "C"), ("LANG", "C")],
+            vec![get_test_step(&[("PATH", "/bin"), ("PATH", "/bin")], &[])],
+        );
+
+        assert_eq!(validate_artifact_environments(&artifact), Ok(vec![]));
+    }
+
+    fn get_test_reference_artifact(script: &str, artifacts: &[&str]) -> Artifact {
+        Artifact {
+            artifacts: artifacts
+                .iter()
+                .map(|name| ArtifactId {
+                    hash: format!("{}-hash", name),
+                    name: name.to_string(),
+                })
+                .collect(),
+            name: "example".to_string(),
+            steps: vec![ArtifactStep {
+                script: Some(script.to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_artifact_references_valid() {
+        let scripts = [
+            "cp -r $VORPAL_ARTIFACT_rust_toolchain/bin $VORPAL_OUTPUT",
+            "export PATH=${VORPAL_ARTIFACT_rust_toolchain}/bin:$PATH",
+            "touch $VORPAL_ARTIFACT_example/done",
+            "echo $VORPAL_ARTIFACTS",
+            "cp $VORPAL_ARTIFACT_cargo_1.83.0/bin/cargo $VORPAL_OUTPUT",
+            "echo built $VORPAL_ARTIFACT_cargo_1.83.0.",
+        ];
+
+        for script in scripts {
+            let artifact = get_test_reference_artifact(script, &["cargo-1.83.0", "rust-toolchain"]);
+
+            assert_eq!(
+                validate_artifact_references(&artifact),
+                Ok(()),
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_artifact_references_unknown() {
+        let artifact = get_test_reference_artifact(
+            "cp -r $VORPAL_ARTIFACT_rust_toolchan/bin $VORPAL_OUTPUT",
+            &["rust-toolchain"],
+        );
+
+        assert_eq!(
+            validate_artifact_references(&artifact),
+            Err(
+                "artifact `example` step 0 references `$VORPAL_ARTIFACT_rust_toolchan` but no such artifact is declared, declared: VORPAL_ARTIFACT_example, VORPAL_ARTIFACT_rust_toolchain"
+                    .to_string()
+            )
+        );
+
+        // Arguments and environment values are expanded too
+
+        let mut artifact = get_test_reference_artifact("true", &[]);
+
+        artifact.steps[0].arguments = vec!["${VORPAL_ARTIFACT_missing}".to_string()];
+
+        assert!(validate_artifact_references(&artifact)
+            .unwrap_err()
+            .contains("`$VORPAL_ARTIFACT_missing`"));
+
+        let mut artifact = get_test_reference_artifact("true", &[]);
+
+        artifact.environments = get_test_environments(&[("PATH", "$VORPAL_ARTIFACT_missing/bin")]);
+
+        assert!(validate_artifact_references(&artifact)
+            .unwrap_err()
+            .contains("`$VORPAL_ARTIFACT_missing`"));
+    }
+
+    #[test]
+    fn test_validate_artifact_references_escaped() {
+        // Escaped and unexpanded names are left to the step, the worker never exports them
+
+        let scripts = [
+            "echo \\$VORPAL_ARTIFACT_missing",
+            "echo \\${VORPAL_ARTIFACT_missing}",
+            "echo VORPAL_ARTIFACT_missing",
+        ];
+
+        for script in scripts {
+            let artifact = get_test_reference_artifact(script, &[]);
+
+            assert_eq!(
+                validate_artifact_references(&artifact),
+                Ok(()),
+                "{}",
+                script
+            );
+        }
+    }
+
+    fn get_test_dependencies(edges: &[(&str, &[&str])]) -> HashMap<ArtifactId, Artifact> {
+        let get_id = |name: &str| ArtifactId {
+            hash: format!("{}-hash", name),
+            name: name.to_string(),
+        };
+
+        edges
+            .iter()
+            .map(|(name, dependencies)| {
+                let artifact = Artifact {
+                    artifacts: dependencies.iter().map(|name| get_id(name)).collect(),
+                    name: name.to_string(),
+                    ..Default::default()
+                };
+
+                (get_id(name), artifact)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_artifact_dependencies_cycle() {
+        // `d` only leads into the cycle, so it is not part of the reported path
+
+        let artifacts =
+            get_test_dependencies(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &["a"])]);
+
+        let (artifact_id, _) = artifacts.iter().find(|(id, _)| id.name == "d").unwrap();
+
+        assert_eq!(
+            validate_artifact_dependencies(artifact_id, &artifacts),
+            Err("artifact dependency cycle: a -> b -> c -> a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_artifact_dependencies_shared() {
+        // Dependencies reached twice through different paths are not cycles
+
+        let artifacts =
+            get_test_dependencies(&[("a", &["b", "c"]), ("b", &["d"]), ("c", &["d"]), ("d", &[])]);
+
+        let (artifact_id, _) = artifacts.iter().find(|(id, _)| id.name == "a").unwrap();
+
+        assert_eq!(
+            validate_artifact_dependencies(artifact_id, &artifacts),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_validate_artifact_system() {
+        let artifact = Artifact {
+            name: "example".to_string(),
+            systems: vec![Aarch64Linux as i32, X8664Linux as i32],
+            ..Default::default()
+        };
+
+        assert_eq!(validate_artifact_system(&artifact, X8664Linux), Ok(()));
+
+        assert_eq!(
+            validate_artifact_system(&artifact, Aarch64Macos),
+            Err(
+                "artifact `example` does not support `AARCH64_MACOS` (systems: AARCH64_LINUX, X86_64_LINUX)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_artifact_system_buildable() {
+        assert!(is_artifact_system_buildable(X8664Linux, X8664Linux));
+        assert!(is_artifact_system_buildable(Aarch64Macos, X8664Windows));
+        assert!(!is_artifact_system_buildable(X8664Linux, Aarch64Linux));
+        assert!(!is_artifact_system_buildable(X8664Windows, X8664Linux));
+        assert!(!is_artifact_system_buildable(UnknownSystem, X8664Windows));
+    }
+
+    #[test]
+    fn test_get_process_template_script() {
+        let script = get_process_template_script(
+            "server",
+            "$VORPAL_ARTIFACT_server/bin/server",
+            &["--port".to_string(), "8080".to_string()],
+            "/var/lib/vorpal/process/server",
+        );
+
+        assert!(script.contains("ENTRYPOINT=\"$VORPAL_ARTIFACT_server/bin/server\"\n"));
+        assert!(script.contains("ARGUMENTS=(\"--port\" \"8080\")\n"));
+        assert!(script.contains(">> \"/var/lib/vorpal/process/server/process.log\""));
+        assert!(script.contains("> \"/var/lib/vorpal/process/server/process.json.tmp\""));
+        assert!(script.ends_with("chmod +x $VORPAL_OUTPUT/bin/server-logs"));
+    }
+}
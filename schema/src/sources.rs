@@ -0,0 +1,410 @@
+use std::fmt;
+use tonic::{metadata::MetadataValue, Code, Status};
+
+// Metadata keys of source errors sent by workers, read back by `SourceError::from_status`
+const SOURCE_ERROR_KIND_KEY: &str = "vorpal-source-error";
+const SOURCE_ERROR_ACTUAL_KEY: &str = "vorpal-source-actual";
+const SOURCE_ERROR_DETAIL_KEY: &str = "vorpal-source-detail";
+const SOURCE_ERROR_EXPECTED_KEY: &str = "vorpal-source-expected";
+const SOURCE_ERROR_NAME_KEY: &str = "vorpal-source-name";
+
+/// Failure to prepare an artifact source, by the config when it resolves sources or by a worker
+/// when it pulls them from the registry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceError {
+    ContentMismatch {
+        actual: String,
+        expected: String,
+        name: String,
+    },
+    Download {
+        error: String,
+        name: String,
+    },
+    EmptySource {
+        name: String,
+    },
+    HashMismatch {
+        actual: String,
+        expected: String,
+        locked: bool,
+        name: String,
+    },
+    RegistryUnavailable {
+        error: String,
+        name: String,
+    },
+    Unpack {
+        error: String,
+        name: String,
+    },
+    UnsupportedType {
+        kind: String,
+        name: String,
+    },
+}
+
+impl SourceError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SourceError::ContentMismatch { .. } => "content_mismatch",
+            SourceError::Download { .. } => "download",
+            SourceError::EmptySource { .. } => "empty_source",
+            SourceError::HashMismatch { .. } => "hash_mismatch",
+            SourceError::RegistryUnavailable { .. } => "registry_unavailable",
+            SourceError::Unpack { .. } => "unpack",
+            SourceError::UnsupportedType { .. } => "unsupported_type",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            SourceError::ContentMismatch { name, .. }
+            | SourceError::Download { name, .. }
+            | SourceError::EmptySource { name }
+            | SourceError::HashMismatch { name, .. }
+            | SourceError::RegistryUnavailable { name, .. }
+            | SourceError::Unpack { name, .. }
+            | SourceError::UnsupportedType { name, .. } => name,
+        }
+    }
+
+    /// Returns the gRPC code of the error, `Unavailable` is retried by clients.
+    pub fn code(&self) -> Code {
+        match self {
+            SourceError::ContentMismatch { .. } => Code::FailedPrecondition,
+            SourceError::Download { .. } => Code::Aborted,
+            SourceError::EmptySource { .. } => Code::InvalidArgument,
+            SourceError::HashMismatch { .. } => Code::FailedPrecondition,
+            SourceError::RegistryUnavailable { .. } => Code::Unavailable,
+            SourceError::Unpack { .. } => Code::DataLoss,
+            SourceError::UnsupportedType { .. } => Code::Unimplemented,
+        }
+    }
+
+    /// Returns what the user can do about the error.
+    pub fn hint(&self) -> String {
+        match self {
+            SourceError::ContentMismatch { actual, name, .. } => format!(
+                "if the upstream file changed intentionally, update `source.{}.content_digest` to {}",
+                name, actual
+            ),
+
+            SourceError::Download { .. } => {
+                "check the source URL is reachable, or add a `--source-mirror`".to_string()
+            }
+
+            SourceError::EmptySource { name } => format!(
+                "check the path, `includes` and `excludes` of `source.{}` match files",
+                name
+            ),
+
+            SourceError::HashMismatch {
+                actual,
+                locked: true,
+                ..
+            } => format!(
+                "if the upstream file changed intentionally, refresh the lock with `--update-locks` (now {})",
+                actual
+            ),
+
+            SourceError::HashMismatch { actual, name, .. } => format!(
+                "if the upstream file changed intentionally, update `source.{}.hash` to {}",
+                name, actual
+            ),
+
+            SourceError::RegistryUnavailable { .. } => {
+                "check the registry is running and reachable from the worker".to_string()
+            }
+
+            SourceError::Unpack { .. } => {
+                "the source archive is corrupt or truncated, retry or check the upstream file"
+                    .to_string()
+            }
+
+            SourceError::UnsupportedType { .. } => {
+                "use a tar (gzip, bzip2, xz, zstd) or zip archive, or a single file".to_string()
+            }
+        }
+    }
+
+    /// Returns the error as a status with its fields as `vorpal-source-*` metadata.
+    pub fn to_status(&self) -> Status {
+        let mut status = Status::new(self.code(), self.to_string());
+
+        let mut fields = vec![
+            (SOURCE_ERROR_KIND_KEY, self.kind()),
+            (SOURCE_ERROR_NAME_KEY, self.name()),
+        ];
+
+        match self {
+            SourceError::Download { error, .. }
+            | SourceError::RegistryUnavailable { error, .. }
+            | SourceError::Unpack { error, .. } => fields.push((SOURCE_ERROR_DETAIL_KEY, error)),
+
+            SourceError::ContentMismatch {
+                actual, expected, ..
+            }
+            | SourceError::HashMismatch {
+                actual, expected, ..
+            } => {
+                fields.push((SOURCE_ERROR_ACTUAL_KEY, actual));
+                fields.push((SOURCE_ERROR_EXPECTED_KEY, expected));
+            }
+
+            SourceError::UnsupportedType { kind, .. } => {
+                fields.push((SOURCE_ERROR_DETAIL_KEY, kind))
+            }
+
+            SourceError::EmptySource { .. } => {}
+        }
+
+        for (key, value) in fields {
+            if let Ok(value) = MetadataValue::try_from(value) {
+                status.metadata_mut().insert(key, value);
+            }
+        }
+
+        status
+    }
+
+    /// Returns the source error sent as `status` by `to_status`, if any.
+    pub fn from_status(status: &Status) -> Option<Self> {
+        let metadata = status.metadata();
+
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let error = get(SOURCE_ERROR_DETAIL_KEY);
+        let name = get(SOURCE_ERROR_NAME_KEY);
+
+        let source_error = match get(SOURCE_ERROR_KIND_KEY).as_str() {
+            "content_mismatch" => SourceError::ContentMismatch {
+                actual: get(SOURCE_ERROR_ACTUAL_KEY),
+                expected: get(SOURCE_ERROR_EXPECTED_KEY),
+                name,
+            },
+            "download" => SourceError::Download { error, name },
+            "empty_source" => SourceError::EmptySource { name },
+            "hash_mismatch" => SourceError::HashMismatch {
+                actual: get(SOURCE_ERROR_ACTUAL_KEY),
+                expected: get(SOURCE_ERROR_EXPECTED_KEY),
+                locked: false,
+                name,
+            },
+            "registry_unavailable" => SourceError::RegistryUnavailable { error, name },
+            "unpack" => SourceError::Unpack { error, name },
+            "unsupported_type" => SourceError::UnsupportedType { kind: error, name },
+            _ => return None,
+        };
+
+        Some(source_error)
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::ContentMismatch {
+                actual,
+                expected,
+                name,
+            } => write!(
+                f,
+                "`source.{}.content_digest` mismatch: expected {}, got {}",
+                name, expected, actual
+            ),
+
+            SourceError::Download { error, name } => {
+                write!(f, "`source.{}` failed to download: {}", name, error)
+            }
+
+            SourceError::EmptySource { name } => {
+                write!(f, "`source.{}` no files found", name)
+            }
+
+            SourceError::HashMismatch {
+                actual,
+                expected,
+                name,
+                ..
+            } => write!(
+                f,
+                "`source.{}.hash` mismatch: expected {}, got {}",
+                name, expected, actual
+            ),
+
+            SourceError::RegistryUnavailable { error, name } => {
+                write!(f, "`source.{}` registry unavailable: {}", name, error)
+            }
+
+            SourceError::Unpack { error, name } => {
+                write!(f, "`source.{}` failed to unpack: {}", name, error)
+            }
+
+            SourceError::UnsupportedType { kind, name } => {
+                write!(f, "`source.{}` unsupported type: {}", name, kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_source_errors() -> Vec<SourceError> {
+        let name = "example".to_string();
+
+        vec![
+            SourceError::ContentMismatch {
+                actual: "b".repeat(64),
+                expected: "a".repeat(64),
+                name: name.clone(),
+            },
+            SourceError::Download {
+                error: "connection refused".to_string(),
+                name: name.clone(),
+            },
+            SourceError::EmptySource { name: name.clone() },
+            SourceError::HashMismatch {
+                actual: "d".repeat(64),
+                expected: "c".repeat(64),
+                locked: false,
+                name: name.clone(),
+            },
+            SourceError::RegistryUnavailable {
+                error: "tcp connect error".to_string(),
+                name: name.clone(),
+            },
+            SourceError::Unpack {
+                error: "unexpected end of file".to_string(),
+                name: name.clone(),
+            },
+            SourceError::UnsupportedType {
+                kind: "application/x-rar".to_string(),
+                name,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_source_error_status() {
+        let codes = [
+            Code::FailedPrecondition,
+            Code::Aborted,
+            Code::InvalidArgument,
+            Code::FailedPrecondition,
+            Code::Unavailable,
+            Code::DataLoss,
+            Code::Unimplemented,
+        ];
+
+        for (source_error, code) in get_source_errors().into_iter().zip(codes) {
+            let status = source_error.to_status();
+
+            assert_eq!(status.code(), code, "{}", source_error.kind());
+            assert_eq!(status.message(), source_error.to_string());
+            assert_eq!(SourceError::from_status(&status), Some(source_error));
+        }
+
+        // Other errors carry no source error
+
+        assert_eq!(SourceError::from_status(&Status::internal("failed")), None);
+
+        // Details that can not be sent as metadata are dropped, the message keeps them
+
+        let source_error = SourceError::Download {
+            error: "failed\nretrying".to_string(),
+            name: "example".to_string(),
+        };
+
+        let status = source_error.to_status();
+
+        assert_eq!(
+            status.message(),
+            "`source.example` failed to download: failed\nretrying"
+        );
+        assert_eq!(
+            SourceError::from_status(&status),
+            Some(SourceError::Download {
+                error: String::new(),
+                name: "example".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_source_error_display() {
+        let messages = [
+            format!(
+                "`source.example.content_digest` mismatch: expected {}, got {}",
+                "a".repeat(64),
+                "b".repeat(64)
+            ),
+            "`source.example` failed to download: connection refused".to_string(),
+            "`source.example` no files found".to_string(),
+            format!(
+                "`source.example.hash` mismatch: expected {}, got {}",
+                "c".repeat(64),
+                "d".repeat(64)
+            ),
+            "`source.example` registry unavailable: tcp connect error".to_string(),
+            "`source.example` failed to unpack: unexpected end of file".to_string(),
+            "`source.example` unsupported type: application/x-rar".to_string(),
+        ];
+
+        for (source_error, message) in get_source_errors().into_iter().zip(messages) {
+            assert_eq!(source_error.to_string(), message);
+            assert_eq!(source_error.name(), "example");
+        }
+    }
+
+    #[test]
+    fn test_source_error_hint() {
+        let source_errors = get_source_errors();
+
+        assert_eq!(
+            source_errors[0].hint(),
+            format!(
+                "if the upstream file changed intentionally, update `source.example.content_digest` to {}",
+                "b".repeat(64)
+            )
+        );
+
+        assert_eq!(
+            source_errors[3].hint(),
+            format!(
+                "if the upstream file changed intentionally, update `source.example.hash` to {}",
+                "d".repeat(64)
+            )
+        );
+
+        // Locked hashes are refreshed instead of edited
+
+        let source_error = SourceError::HashMismatch {
+            actual: "d".repeat(64),
+            expected: "c".repeat(64),
+            locked: true,
+            name: "example".to_string(),
+        };
+
+        assert_eq!(
+            source_error.hint(),
+            format!(
+                "if the upstream file changed intentionally, refresh the lock with `--update-locks` (now {})",
+                "d".repeat(64)
+            )
+        );
+
+        assert!(source_errors[2].hint().contains("`source.example`"));
+        assert!(source_errors[1].hint().contains("--source-mirror"));
+    }
+}
@@ -1 +1,3 @@
+pub mod nodejs;
+pub mod python;
 pub mod rust;
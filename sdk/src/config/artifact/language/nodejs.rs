@@ -0,0 +1,157 @@
+use crate::config::{
+    artifact::{
+        add_artifact, get_artifact_envkey,
+        toolchain::nodejs::{self, get_nodejs_version},
+        ArtifactSource,
+    },
+    ConfigContext,
+};
+use anyhow::{bail, Result};
+use indoc::formatdoc;
+use std::collections::BTreeMap;
+use vorpal_schema::vorpal::artifact::v0::ArtifactId;
+
+pub struct NodejsBuilder<'a> {
+    build_script: String,
+    excludes: Vec<String>,
+    includes: Vec<String>,
+    name: &'a str,
+    node_version: String,
+    packages: Vec<String>,
+    source_path: String,
+}
+
+impl<'a> NodejsBuilder<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self {
+            build_script: "npm ci && npm run build".to_string(),
+            excludes: vec![],
+            includes: vec![],
+            name,
+            node_version: get_nodejs_version(),
+            packages: vec![],
+            source_path: ".".to_string(),
+        }
+    }
+
+    pub fn with_build_script(mut self, script: &str) -> Self {
+        self.build_script = script.to_string();
+        self
+    }
+
+    pub fn with_excludes(mut self, excludes: Vec<&str>) -> Self {
+        self.excludes = excludes.into_iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    pub fn with_includes(mut self, includes: Vec<&str>) -> Self {
+        self.includes = includes.into_iter().map(|i| i.to_string()).collect();
+        self
+    }
+
+    pub fn with_node_version(mut self, version: &str) -> Self {
+        self.node_version = version.to_string();
+        self
+    }
+
+    /// Workspace packages to output, the whole project is output when empty.
+    pub fn with_packages(mut self, packages: Vec<&str>) -> Self {
+        self.packages = packages.into_iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn with_source_path(mut self, path: &str) -> Self {
+        self.source_path = path.to_string();
+        self
+    }
+
+    pub async fn build(self, context: &mut ConfigContext) -> Result<ArtifactId> {
+        let name = self.name;
+
+        let source_path = context.get_context_path().join(&self.source_path);
+
+        if !source_path.join("package.json").exists() {
+            bail!("package.json not found: {:?}", source_path);
+        }
+
+        for package in self.packages.iter() {
+            if !source_path.join(package).join("package.json").exists() {
+                bail!("package.json not found: {:?}", source_path.join(package));
+            }
+        }
+
+        let nodejs = nodejs::artifact(context, &self.node_version).await?;
+
+        // Installed dependencies never belong in the source hash
+
+        let mut excludes = self.excludes.clone();
+
+        if !excludes.contains(&"node_modules".to_string()) {
+            excludes.push("node_modules".to_string());
+        }
+
+        for package in self.packages.iter() {
+            let package_modules = format!("{}/node_modules", package);
+
+            if !excludes.contains(&package_modules) {
+                excludes.push(package_modules);
+            }
+        }
+
+        let output_script = match self.packages.is_empty() {
+            true => "cp -prv \"./.\" \"$VORPAL_OUTPUT\"".to_string(),
+            false => formatdoc! {"
+                packages=({packages})
+
+                for package in \"${{packages[@]}}\"; do
+                    mkdir -pv \"$VORPAL_OUTPUT/$package\"
+                    cp -prv \"./$package/.\" \"$VORPAL_OUTPUT/$package\"
+                done
+
+                cp -prv \"./node_modules\" \"$VORPAL_OUTPUT/node_modules\"",
+                packages = self.packages.join(" "),
+            },
+        };
+
+        add_artifact(
+            context,
+            vec![nodejs.clone()],
+            BTreeMap::from([
+                ("HOME", "$VORPAL_WORKSPACE/home".to_string()),
+                ("PATH", format!("{}/bin", get_artifact_envkey(&nodejs))),
+            ]),
+            name,
+            formatdoc! {"
+                mkdir -pv $HOME
+
+                pushd ./source/{name}
+
+                {build_script}
+
+                {output_script}",
+                build_script = self.build_script,
+            },
+            BTreeMap::from([(
+                name,
+                ArtifactSource {
+                    allow_outside_context: false,
+                    content_digest: None,
+                    excludes,
+                    executable: false,
+                    hash: None,
+                    includes: self.includes.clone(),
+                    path: source_path.display().to_string(),
+                    rename: None,
+                    strip_prefix: false,
+                },
+            )]),
+            vec![
+                "aarch64-linux",
+                "aarch64-macos",
+                "x86_64-linux",
+                "x86_64-macos",
+            ],
+        )
+        .await
+    }
+}
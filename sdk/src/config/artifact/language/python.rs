@@ -0,0 +1,208 @@
+use crate::config::{
+    artifact::{
+        add_artifact, get_artifact_envkey,
+        toolchain::python::{self, get_python_version},
+        ArtifactSource,
+    },
+    ConfigContext,
+};
+use anyhow::{bail, Result};
+use indoc::formatdoc;
+use std::collections::BTreeMap;
+use vorpal_schema::vorpal::artifact::v0::ArtifactId;
+
+pub struct PythonBuilder<'a> {
+    entry_points: Vec<String>,
+    excludes: Vec<String>,
+    includes: Vec<String>,
+    name: &'a str,
+    python_version: String,
+    requirements: Option<String>,
+    source_path: String,
+    wheels_path: Option<String>,
+}
+
+impl<'a> PythonBuilder<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self {
+            entry_points: vec![],
+            excludes: vec![],
+            includes: vec![],
+            name,
+            python_version: get_python_version(),
+            requirements: None,
+            source_path: ".".to_string(),
+            wheels_path: None,
+        }
+    }
+
+    /// Scripts installed by the project to expose under `$VORPAL_OUTPUT/bin`.
+    pub fn with_entry_points(mut self, entry_points: Vec<&str>) -> Self {
+        self.entry_points = entry_points.into_iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    pub fn with_excludes(mut self, excludes: Vec<&str>) -> Self {
+        self.excludes = excludes.into_iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    pub fn with_includes(mut self, includes: Vec<&str>) -> Self {
+        self.includes = includes.into_iter().map(|i| i.to_string()).collect();
+        self
+    }
+
+    pub fn with_python_version(mut self, version: &str) -> Self {
+        self.python_version = version.to_string();
+        self
+    }
+
+    /// Requirements file, relative to the source path, installed before the project.
+    pub fn with_requirements(mut self, path: &str) -> Self {
+        self.requirements = Some(path.to_string());
+        self
+    }
+
+    pub fn with_source_path(mut self, path: &str) -> Self {
+        self.source_path = path.to_string();
+        self
+    }
+
+    /// Directory of pre-vendored wheels, installs run without a package index when set.
+    pub fn with_wheels(mut self, path: &str) -> Self {
+        self.wheels_path = Some(path.to_string());
+        self
+    }
+
+    pub async fn build(self, context: &mut ConfigContext) -> Result<ArtifactId> {
+        let name = self.name;
+
+        let source_path = context.get_context_path().join(&self.source_path);
+
+        if !source_path.join("pyproject.toml").exists() {
+            bail!("pyproject.toml not found: {:?}", source_path);
+        }
+
+        if let Some(requirements) = &self.requirements {
+            if !source_path.join(requirements).exists() {
+                bail!(
+                    "requirements not found: {:?}",
+                    source_path.join(requirements)
+                );
+            }
+        }
+
+        let python = python::artifact(context, &self.python_version).await?;
+
+        // Local environments and caches never belong in the source hash
+
+        let mut excludes = self.excludes.clone();
+
+        for exclude in [".venv", "__pycache__", "build", "dist"] {
+            if !excludes.contains(&exclude.to_string()) {
+                excludes.push(exclude.to_string());
+            }
+        }
+
+        let mut sources = BTreeMap::from([(
+            name,
+            ArtifactSource {
+                allow_outside_context: false,
+                content_digest: None,
+                excludes,
+                executable: false,
+                hash: None,
+                includes: self.includes.clone(),
+                path: source_path.display().to_string(),
+                rename: None,
+                strip_prefix: false,
+            },
+        )]);
+
+        let wheels_name = format!("{}-wheels", name);
+
+        let mut pip_args = vec!["--no-cache-dir".to_string()];
+
+        if let Some(wheels_path) = &self.wheels_path {
+            sources.insert(
+                wheels_name.as_str(),
+                ArtifactSource {
+                    allow_outside_context: false,
+                    content_digest: None,
+                    excludes: vec![],
+                    executable: false,
+                    hash: None,
+                    includes: vec![],
+                    path: wheels_path.clone(),
+                    rename: None,
+                    strip_prefix: false,
+                },
+            );
+
+            pip_args.push("--no-index".to_string());
+            pip_args.push(format!(
+                "--find-links \"$VORPAL_WORKSPACE/source/{}\"",
+                wheels_name
+            ));
+        }
+
+        let pip_args = pip_args.join(" ");
+
+        let requirements_script = match &self.requirements {
+            Some(requirements) => format!(
+                "\"$VORPAL_OUTPUT/venv/bin/pip\" install {} -r \"{}\"",
+                pip_args, requirements
+            ),
+            None => String::new(),
+        };
+
+        add_artifact(
+            context,
+            vec![python.clone()],
+            BTreeMap::from([
+                ("HOME", "$VORPAL_WORKSPACE/home".to_string()),
+                ("PATH", format!("{}/bin", get_artifact_envkey(&python))),
+            ]),
+            name,
+            formatdoc! {"
+                mkdir -pv $HOME
+
+                pushd ./source/{name}
+
+                python3 -m venv \"$VORPAL_OUTPUT/venv\"
+
+                {requirements_script}
+
+                \"$VORPAL_OUTPUT/venv/bin/pip\" install {pip_args} .
+
+                mkdir -pv \"$VORPAL_OUTPUT/bin\"
+
+                entry_points=({entry_points})
+
+                for entry_point in \"${{entry_points[@]}}\"; do
+                    if [ ! -f \"$VORPAL_OUTPUT/venv/bin/$entry_point\" ]; then
+                        echo \"entry point not found: $entry_point\"
+                        exit 1
+                    fi
+
+                    cat > \"$VORPAL_OUTPUT/bin/$entry_point\" << EOF
+                #!/bin/sh
+                bin_dir=\"\\$(dirname -- \"\\$0\")\"
+                exec \"\\$bin_dir/../venv/bin/python\" \"\\$bin_dir/../venv/bin/$entry_point\" \"\\$@\"
+                EOF
+
+                    chmod +x \"$VORPAL_OUTPUT/bin/$entry_point\"
+                done",
+                entry_points = self.entry_points.join(" "),
+            },
+            sources,
+            vec![
+                "aarch64-linux",
+                "aarch64-macos",
+                "x86_64-linux",
+                "x86_64-macos",
+            ],
+        )
+        .await
+    }
+}
@@ -1,6 +1,6 @@
 use crate::config::{
     artifact::{
-        add_artifact, get_artifact_envkey,
+        add_artifact, add_artifact_with_incremental, get_artifact_envkey,
         shell::shell_artifact,
         toolchain::{cargo, clippy, protoc, rust_analyzer, rust_src, rust_std, rustc, rustfmt},
         ArtifactSource,
@@ -8,15 +8,19 @@ use crate::config::{
     ConfigContext,
 };
 use anyhow::{bail, Result};
+use globset::Glob;
 use indoc::formatdoc;
 use serde::Deserialize;
-use std::collections::BTreeMap;
+use sha256::digest;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::path::Path;
 use toml::from_str;
 use vorpal_schema::vorpal::artifact::v0::{
-    ArtifactId, ArtifactSystem,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
+    ArtifactId, ArtifactStepIncremental, ArtifactSystem,
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
 
 #[derive(Debug, Deserialize)]
@@ -33,23 +37,147 @@ struct RustArtifactCargoTomlBinary {
 
 #[derive(Debug, Deserialize)]
 struct RustArtifactCargoTomlWorkspace {
+    exclude: Option<Vec<String>>,
     members: Option<Vec<String>>,
 }
 
+fn join_member_path(path: &str, component: &str) -> String {
+    match path.is_empty() {
+        true => component.to_string(),
+        false => format!("{}/{}", path, component),
+    }
+}
+
+/// Expands a workspace member into the directories it matches, each path component may be a
+/// glob like cargo allows (`crates/*`).
+fn get_member_paths(source_path: &Path, member: &str) -> Result<Vec<String>> {
+    let mut paths = vec![String::new()];
+
+    for component in member.split('/') {
+        if component.is_empty() || component == "." {
+            continue;
+        }
+
+        if !component.contains(['*', '?', '[']) {
+            paths = paths
+                .iter()
+                .map(|path| join_member_path(path, component))
+                .collect();
+
+            continue;
+        }
+
+        let matcher = Glob::new(component)?.compile_matcher();
+
+        let mut matched_paths = vec![];
+
+        for path in paths.iter() {
+            let Ok(entries) = fs::read_dir(source_path.join(path)) else {
+                continue;
+            };
+
+            for entry in entries {
+                let entry = entry?;
+
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                if entry.path().is_dir() && matcher.is_match(&name) {
+                    matched_paths.push(join_member_path(path, &name));
+                }
+            }
+        }
+
+        paths = matched_paths;
+    }
+
+    Ok(paths)
+}
+
+fn is_member_excluded(member: &str, excludes: &[&str]) -> bool {
+    excludes.iter().any(|exclude| {
+        let exclude = exclude.trim_start_matches("./").trim_end_matches('/');
+
+        member == exclude || member.starts_with(&format!("{}/", exclude))
+    })
+}
+
+/// Resolves the workspace members to build: expanded `members` globs and `packages`, minus
+/// `exclude` and `excludes` (and anything below them), sorted so the artifact only changes when
+/// membership does.
+fn get_workspace_members(
+    source_path: &Path,
+    workspace: &RustArtifactCargoTomlWorkspace,
+    packages: &[String],
+    excludes: &[String],
+) -> Result<Vec<String>> {
+    let mut members = BTreeSet::new();
+
+    for member in workspace.members.iter().flatten().chain(packages.iter()) {
+        members.extend(get_member_paths(source_path, member)?);
+    }
+
+    let excludes = workspace
+        .exclude
+        .iter()
+        .flatten()
+        .chain(excludes.iter())
+        .map(|exclude| exclude.as_str())
+        .collect::<Vec<_>>();
+
+    Ok(members
+        .into_iter()
+        .filter(|member| !is_member_excluded(member, &excludes))
+        .collect())
+}
+
 pub fn get_toolchain_target(target: ArtifactSystem) -> Result<String> {
     let target = match target {
         Aarch64Linux => "aarch64-unknown-linux-gnu",
         Aarch64Macos => "aarch64-apple-darwin",
         X8664Linux => "x86_64-unknown-linux-gnu",
         X8664Macos => "x86_64-apple-darwin",
-        UnknownSystem => bail!("Unsupported rustc target: {:?}", target),
+        UnknownSystem | X8664Windows => bail!("Unsupported rustc target: {:?}", target),
     };
 
     Ok(target.to_string())
 }
 
+// Toolchain versions with digests for their archives, the first being the default
+pub const RUST_TOOLCHAIN_VERSIONS: [&str; 5] = ["1.83.0", "1.82.0", "1.81.0", "1.80.1", "1.79.0"];
+
 pub fn get_rust_toolchain_version() -> String {
-    "1.83.0".to_string()
+    RUST_TOOLCHAIN_VERSIONS[0].to_string()
+}
+
+/// Fails for toolchain versions without digests, listing the known ones.
+pub fn check_rust_toolchain_version(version: &str) -> Result<()> {
+    if !RUST_TOOLCHAIN_VERSIONS.contains(&version) {
+        bail!(
+            "unknown rust toolchain version: {} (known: {})",
+            version,
+            RUST_TOOLCHAIN_VERSIONS.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the content digest and hash of toolchain `archive`: its store hash for the default
+/// version, its upstream `content_digest` for other versions.
+pub fn get_toolchain_digests(
+    archive: &str,
+    content_digest: Option<&str>,
+    hash: &str,
+    version: &str,
+) -> Result<(Option<String>, Option<String>)> {
+    if version == get_rust_toolchain_version() {
+        return Ok((None, Some(hash.to_string())));
+    }
+
+    match content_digest {
+        Some(digest) => Ok((Some(format!("sha256:{}", digest)), None)),
+        None => bail!("no digest for rust toolchain archive: {}", archive),
+    }
 }
 
 fn read_cargo_toml(path: &str) -> Result<RustArtifactCargoToml> {
@@ -58,20 +186,27 @@ fn read_cargo_toml(path: &str) -> Result<RustArtifactCargoToml> {
     Ok(from_str(&contents).expect("Failed to parse Cargo.toml"))
 }
 
+/// Toolchain for the host, with the standard library of `target_triple` added to cross compile.
 #[allow(clippy::too_many_arguments)]
-pub async fn toolchain_artifact(context: &mut ConfigContext, name: &str) -> Result<ArtifactId> {
-    let version = get_rust_toolchain_version();
+pub async fn toolchain_artifact(
+    context: &mut ConfigContext,
+    name: &str,
+    target_triple: Option<&str>,
+    version: &str,
+) -> Result<ArtifactId> {
+    check_rust_toolchain_version(version)?;
+
     let target = get_toolchain_target(context.get_target())?;
 
-    let cargo = cargo::artifact(context, &version).await?;
-    let clippy = clippy::artifact(context, &version).await?;
-    let rust_analyzer = rust_analyzer::artifact(context, &version).await?;
-    let rust_src = rust_src::artifact(context, &version).await?;
-    let rust_std = rust_std::artifact(context, &version).await?;
-    let rustc = rustc::artifact(context, &version).await?;
-    let rustfmt = rustfmt::artifact(context, &version).await?;
+    let cargo = cargo::artifact(context, version).await?;
+    let clippy = clippy::artifact(context, version).await?;
+    let rust_analyzer = rust_analyzer::artifact(context, version).await?;
+    let rust_src = rust_src::artifact(context, version).await?;
+    let rust_std = rust_std::artifact(context, &target, version).await?;
+    let rustc = rustc::artifact(context, version).await?;
+    let rustfmt = rustfmt::artifact(context, version).await?;
 
-    let artifacts = vec![
+    let mut artifacts = vec![
         cargo.clone(),
         clippy.clone(),
         rust_analyzer.clone(),
@@ -81,6 +216,10 @@ pub async fn toolchain_artifact(context: &mut ConfigContext, name: &str) -> Resu
         rustfmt.clone(),
     ];
 
+    if let Some(target_triple) = target_triple.filter(|triple| *triple != target) {
+        artifacts.push(rust_std::artifact(context, target_triple, version).await?);
+    }
+
     let mut component_paths = vec![];
 
     for component in &artifacts {
@@ -91,7 +230,7 @@ pub async fn toolchain_artifact(context: &mut ConfigContext, name: &str) -> Resu
         context,
         artifacts,
         BTreeMap::new(),
-        format!("{}-rust-toolchain", name).as_str(),
+        format!("{}-rust-toolchain-{}", name, version).as_str(),
         formatdoc! {"
             toolchain_dir=\"$VORPAL_OUTPUT/toolchains/{version}-{target}\"
 
@@ -138,7 +277,7 @@ pub async fn toolchain_artifact(context: &mut ConfigContext, name: &str) -> Resu
 }
 
 pub async fn rust_shell(context: &mut ConfigContext, name: &str) -> Result<ArtifactId> {
-    let toolchain = toolchain_artifact(context, name).await?;
+    let toolchain = toolchain_artifact(context, name, None, &get_rust_toolchain_version()).await?;
 
     let protoc = protoc::artifact(context).await?;
 
@@ -166,13 +305,95 @@ pub async fn rust_shell(context: &mut ConfigContext, name: &str) -> Result<Artif
     shell_artifact(context, artifacts, envs, name).await
 }
 
-pub async fn rust_package<'a>(context: &mut ConfigContext, name: &'a str) -> Result<ArtifactId> {
-    let toolchain = toolchain_artifact(context, name).await?;
+pub struct RustBuilder<'a> {
+    excludes: Vec<String>,
+    incremental: bool,
+    name: &'a str,
+    packages: Vec<String>,
+    target_triple: Option<String>,
+    toolchain_version: Option<String>,
+}
+
+impl<'a> RustBuilder<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self {
+            excludes: vec![],
+            incremental: false,
+            name,
+            packages: vec![],
+            target_triple: None,
+            toolchain_version: None,
+        }
+    }
+
+    /// Builds the package at `path` besides the workspace members, which are always discovered
+    /// from the workspace `Cargo.toml`.
+    pub fn with_package(mut self, path: &str) -> Self {
+        self.packages.push(path.to_string());
+        self
+    }
+
+    /// Keeps `target/` on the worker between builds with the same toolchain and `Cargo.lock`, so
+    /// cargo only rebuilds what changed. The artifact digest still depends on sources only.
+    pub fn with_incremental(mut self) -> Self {
+        self.incremental = true;
+        self
+    }
+
+    /// Skips the workspace member at `path`, and any member below it.
+    pub fn without_package(mut self, path: &str) -> Self {
+        self.excludes.push(path.to_string());
+        self
+    }
+
+    /// Cargo target to build for instead of the host, binaries are read from
+    /// `target/<triple>/release` and tests are skipped as they may not run on the host.
+    pub fn with_target_triple(mut self, target_triple: &str) -> Self {
+        self.target_triple = Some(target_triple.to_string());
+        self
+    }
+
+    /// Rust toolchain to build with instead of the SDK default, one of `RUST_TOOLCHAIN_VERSIONS`.
+    pub fn with_toolchain_version(mut self, version: &str) -> Self {
+        self.toolchain_version = Some(version.to_string());
+        self
+    }
+
+    pub async fn build(self, context: &mut ConfigContext) -> Result<ArtifactId> {
+        build_package(
+            context,
+            self.name,
+            &self.packages,
+            &self.excludes,
+            self.incremental,
+            self.target_triple.as_deref(),
+            &self
+                .toolchain_version
+                .unwrap_or_else(get_rust_toolchain_version),
+        )
+        .await
+    }
+}
+
+pub async fn rust_package(context: &mut ConfigContext, name: &str) -> Result<ArtifactId> {
+    RustBuilder::new(name).build(context).await
+}
+
+async fn build_package(
+    context: &mut ConfigContext,
+    name: &str,
+    packages: &[String],
+    excludes: &[String],
+    incremental: bool,
+    target_triple: Option<&str>,
+    toolchain_version: &str,
+) -> Result<ArtifactId> {
+    let toolchain = toolchain_artifact(context, name, target_triple, toolchain_version).await?;
 
     // 1. READ CARGO.TOML FILES
 
     // Get the source path
-    let source_path = Path::new(".").to_path_buf();
+    let source_path = context.get_context_path().to_path_buf();
 
     if !source_path.exists() {
         bail!(
@@ -199,36 +420,34 @@ pub async fn rust_package<'a>(context: &mut ConfigContext, name: &'a str) -> Res
     let mut workspaces_targets = vec![];
 
     if let Some(workspace) = cargo_toml.workspace {
-        if let Some(members) = workspace.members {
-            for member in members {
-                let member_path = source_path.join(member.clone());
-                let member_cargo_toml_path = member_path.join("Cargo.toml");
+        for member in get_workspace_members(&source_path, &workspace, packages, excludes)? {
+            let member_path = source_path.join(member.clone());
+            let member_cargo_toml_path = member_path.join("Cargo.toml");
 
-                if !member_cargo_toml_path.exists() {
-                    bail!("Cargo.toml not found: {:?}", member_cargo_toml_path);
-                }
-
-                let member_cargo = read_cargo_toml(member_cargo_toml_path.to_str().unwrap())?;
+            if !member_cargo_toml_path.exists() {
+                bail!("Cargo.toml not found: {:?}", member_cargo_toml_path);
+            }
 
-                let mut member_target_paths = vec![];
+            let member_cargo = read_cargo_toml(member_cargo_toml_path.to_str().unwrap())?;
 
-                if let Some(bins) = member_cargo.bin {
-                    for bin in bins {
-                        member_target_paths.push(format!("{}/{}", member, bin.path));
-                        workspaces_bin_names.push(bin.name);
-                    }
-                }
+            let mut member_target_paths = vec![];
 
-                if member_target_paths.is_empty() {
-                    member_target_paths.push(format!("{}/src/lib.rs", member));
+            if let Some(bins) = member_cargo.bin {
+                for bin in bins {
+                    member_target_paths.push(format!("{}/{}", member, bin.path));
+                    workspaces_bin_names.push(bin.name);
                 }
+            }
 
-                for member_path in member_target_paths {
-                    workspaces_targets.push(member_path);
-                }
+            if member_target_paths.is_empty() {
+                member_target_paths.push(format!("{}/src/lib.rs", member));
+            }
 
-                workspaces.push(member);
+            for member_path in member_target_paths {
+                workspaces_targets.push(member_path);
             }
+
+            workspaces.push(member);
         }
     }
 
@@ -246,14 +465,13 @@ pub async fn rust_package<'a>(context: &mut ConfigContext, name: &'a str) -> Res
     let protoc = protoc::artifact(context).await?;
 
     let toolchain_target = get_toolchain_target(context.get_target())?;
-    let toolchain_version = get_rust_toolchain_version();
 
     // Set environment variables
 
     let mut env_paths = vec![format!(
         "{}/toolchains/{}-{}/bin",
         get_artifact_envkey(&toolchain),
-        get_rust_toolchain_version(),
+        toolchain_version,
         toolchain_target
     )];
 
@@ -299,10 +517,15 @@ pub async fn rust_package<'a>(context: &mut ConfigContext, name: &'a str) -> Res
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest: None,
                 excludes: vec![],
+                executable: false,
                 hash: None,
                 includes: vendor_cargo_tomls.clone(),
                 path: source_path.display().to_string(),
+                rename: None,
+                strip_prefix: false,
             },
         )]),
         systems.clone(),
@@ -311,73 +534,129 @@ pub async fn rust_package<'a>(context: &mut ConfigContext, name: &'a str) -> Res
 
     // TODO: implement artifact for 'check` to pre-bake the vendor cache
 
+    let (cargo_build, cargo_test, target_dir) = match target_triple {
+        Some(triple) => (
+            format!("cargo build --offline --release --target {}", triple),
+            String::new(),
+            format!("target/{}/release", triple),
+        ),
+        None => (
+            "cargo build --offline --release".to_string(),
+            "cargo test --offline --release".to_string(),
+            "target/release".to_string(),
+        ),
+    };
+
     let artifacts = vec![protoc.clone(), toolchain.clone(), vendor.clone()];
 
     // Create artifact
 
     env_paths.push(format!("{}/bin", get_artifact_envkey(&protoc)));
 
-    add_artifact(
-        context,
-        artifacts,
-        BTreeMap::from([
-            ("HOME", "$VORPAL_WORKSPACE/home".to_string()),
-            ("PATH", env_paths.join(":")),
-            ("RUSTUP_HOME", get_artifact_envkey(&toolchain)),
-            (
-                "RUSTUP_TOOLCHAIN",
-                format!("{}-{}", get_rust_toolchain_version(), toolchain_target),
-            ),
-        ]),
-        name,
-        formatdoc! {"
-            mkdir -pv $HOME
+    let environment = BTreeMap::from([
+        ("HOME", "$VORPAL_WORKSPACE/home".to_string()),
+        ("PATH", env_paths.join(":")),
+        ("RUSTUP_HOME", get_artifact_envkey(&toolchain)),
+        (
+            "RUSTUP_TOOLCHAIN",
+            format!("{}-{}", toolchain_version, toolchain_target),
+        ),
+    ]);
 
-            pushd ./source/{name}
+    let script = formatdoc! {"
+        mkdir -pv $HOME
 
-            mkdir -pv .cargo
+        pushd ./source/{name}
 
-            ln -sv \"{vendor}/config.toml\" .cargo/config.toml
+        mkdir -pv .cargo
 
-            cargo build --offline --release
+        ln -sv \"{vendor}/config.toml\" .cargo/config.toml
 
-            cargo test --offline --release
+        {cargo_build}
 
-            mkdir -pv \"$VORPAL_OUTPUT/bin\"
+        {cargo_test}
 
-            bin_names=({bin_names})
+        mkdir -pv \"$VORPAL_OUTPUT/bin\"
+
+        bin_names=({bin_names})
+
+        for bin_name in ${{bin_names[@]}}; do
+            cp -pv \"{target_dir}/${{bin_name}}\" \"$VORPAL_OUTPUT/bin/\"
+        done",
+        bin_names = workspaces_bin_names.join(" "),
+        vendor = get_artifact_envkey(&vendor),
+    };
 
-            for bin_name in ${{bin_names[@]}}; do
-                cp -pv \"target/release/${{bin_name}}\" \"$VORPAL_OUTPUT/bin/\"
-            done",
-            bin_names = workspaces_bin_names.join(" "),
-            vendor = get_artifact_envkey(&vendor),
+    let source = BTreeMap::from([(
+        name,
+        ArtifactSource {
+            allow_outside_context: false,
+            content_digest: None,
+            excludes: vec![
+                ".env".to_string(),
+                ".envrc".to_string(),
+                ".github".to_string(),
+                ".gitignore".to_string(),
+                ".packer".to_string(),
+                ".vagrant".to_string(),
+                "Dockerfile".to_string(),
+                "Vagrantfile".to_string(),
+                "dist".to_string(),
+                "makefile".to_string(),
+                "script".to_string(),
+                "shell.nix".to_string(),
+                "target".to_string(),
+                "vorpal-domains.svg".to_string(),
+                "vorpal-purpose.jpg".to_string(),
+            ],
+            executable: false,
+            hash: None,
+            includes: vec![],
+            path: source_path.display().to_string(),
+            rename: None,
+            strip_prefix: false,
         },
-        BTreeMap::from([(
+    )]);
+
+    if !incremental {
+        return add_artifact(
+            context,
+            artifacts,
+            environment,
             name,
-            ArtifactSource {
-                excludes: vec![
-                    ".env".to_string(),
-                    ".envrc".to_string(),
-                    ".github".to_string(),
-                    ".gitignore".to_string(),
-                    ".packer".to_string(),
-                    ".vagrant".to_string(),
-                    "Dockerfile".to_string(),
-                    "Vagrantfile".to_string(),
-                    "dist".to_string(),
-                    "makefile".to_string(),
-                    "script".to_string(),
-                    "shell.nix".to_string(),
-                    "target".to_string(),
-                    "vorpal-domains.svg".to_string(),
-                    "vorpal-purpose.jpg".to_string(),
-                ],
-                hash: None,
-                includes: vec![],
-                path: source_path.display().to_string(),
-            },
-        )]),
+            script,
+            source,
+            systems,
+        )
+        .await;
+    }
+
+    // Keep `target/` between builds with the same toolchain and dependencies
+
+    let cargo_lock_path = source_path.join("Cargo.lock");
+
+    let cargo_lock_digest = match cargo_lock_path.exists() {
+        true => digest(fs::read(&cargo_lock_path)?.as_slice()),
+        false => String::new(),
+    };
+
+    let incremental = ArtifactStepIncremental {
+        key: format!(
+            "{}-{}",
+            name,
+            digest(format!("{}:{}", toolchain.hash, cargo_lock_digest))
+        ),
+        path: format!("source/{}/target", name),
+    };
+
+    add_artifact_with_incremental(
+        context,
+        artifacts,
+        environment,
+        incremental,
+        name,
+        script,
+        source,
         systems,
     )
     .await
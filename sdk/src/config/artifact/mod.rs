@@ -1,28 +1,28 @@
 use crate::config::{
     artifact::{
-        steps::{bash, bwrap},
+        steps::{is_shell_system, shell, ArtifactStepOptions},
         toolchain::linux::{debian, vorpal},
     },
     ArtifactSource, ConfigContext,
 };
 use anyhow::{bail, Result};
 use std::collections::BTreeMap;
-use vorpal_schema::vorpal::artifact::v0::{
-    ArtifactId, ArtifactStepEnvironment, ArtifactSystem,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, X8664Linux, X8664Macos},
+use vorpal_schema::{
+    get_artifact_envkey_name,
+    vorpal::artifact::v0::{
+        ArtifactId, ArtifactStepEnvironment, ArtifactStepIncremental, ArtifactSystem,
+        ArtifactSystem::{Aarch64Linux, Aarch64Macos, X8664Linux, X8664Macos, X8664Windows},
+    },
 };
 
 pub mod language;
+pub mod process;
 pub mod shell;
 pub mod steps;
 pub mod toolchain;
 
 pub fn get_artifact_envkey(artifact: &ArtifactId) -> String {
-    format!(
-        "$VORPAL_ARTIFACT_{}",
-        artifact.name.to_lowercase().replace("-", "_")
-    )
-    .to_string()
+    format!("${}", get_artifact_envkey_name(&artifact.name))
 }
 
 pub fn add_artifact_systems(systems: Vec<&str>) -> Result<Vec<ArtifactSystem>> {
@@ -34,6 +34,7 @@ pub fn add_artifact_systems(systems: Vec<&str>) -> Result<Vec<ArtifactSystem>> {
             "aarch64-macos" => build_systems.push(Aarch64Macos),
             "x86_64-linux" => build_systems.push(X8664Linux),
             "x86_64-macos" => build_systems.push(X8664Macos),
+            "x86_64-windows" => build_systems.push(X8664Windows),
             _ => bail!("Unsupported system: {}", system),
         }
     }
@@ -52,10 +53,98 @@ pub async fn add_artifact(
     source: BTreeMap<&str, ArtifactSource>,
     systems: Vec<&str>,
 ) -> Result<ArtifactId> {
-    // Setup target
+    add_artifact_script(
+        context,
+        artifacts,
+        environment,
+        None,
+        name,
+        script,
+        None,
+        source,
+        systems,
+    )
+    .await
+}
+
+/// Adds an artifact whose script step keeps `incremental.path` between builds on a worker, see
+/// `ArtifactStepOptions::with_incremental`.
+#[allow(clippy::too_many_arguments)]
+pub async fn add_artifact_with_incremental(
+    context: &mut ConfigContext,
+    artifacts: Vec<ArtifactId>,
+    environment: BTreeMap<&str, String>,
+    incremental: ArtifactStepIncremental,
+    name: &str,
+    script: String,
+    source: BTreeMap<&str, ArtifactSource>,
+    systems: Vec<&str>,
+) -> Result<ArtifactId> {
+    add_artifact_script(
+        context,
+        artifacts,
+        environment,
+        Some(incremental),
+        name,
+        script,
+        None,
+        source,
+        systems,
+    )
+    .await
+}
+
+/// Adds an artifact whose script runs with `shell` (`/bin/zsh`) instead of the default
+/// interpreter of the target.
+#[allow(clippy::too_many_arguments)]
+pub async fn add_artifact_with_shell(
+    context: &mut ConfigContext,
+    artifacts: Vec<ArtifactId>,
+    environment: BTreeMap<&str, String>,
+    name: &str,
+    script: String,
+    shell: &str,
+    source: BTreeMap<&str, ArtifactSource>,
+    systems: Vec<&str>,
+) -> Result<ArtifactId> {
+    add_artifact_script(
+        context,
+        artifacts,
+        environment,
+        None,
+        name,
+        script,
+        Some(shell),
+        source,
+        systems,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn add_artifact_script(
+    context: &mut ConfigContext,
+    artifacts: Vec<ArtifactId>,
+    environment: BTreeMap<&str, String>,
+    incremental: Option<ArtifactStepIncremental>,
+    name: &str,
+    script: String,
+    script_shell: Option<&str>,
+    source: BTreeMap<&str, ArtifactSource>,
+    systems: Vec<&str>,
+) -> Result<ArtifactId> {
+    // Setup target, failing before toolchains are added for targets without shell steps
 
     let target = context.get_target();
 
+    if !is_shell_system(target) {
+        bail!(
+            "`{}` uses a shell script, which can not target `{}`",
+            name,
+            target.as_str_name()
+        );
+    }
+
     // Setup artifacts
 
     let mut artifacts = artifacts.clone();
@@ -117,27 +206,24 @@ pub async fn add_artifact(
 
     // Setup steps
 
-    let mut steps = vec![];
+    let rootfs = artifacts.iter().find(|a| a.name == "linux-vorpal").cloned();
 
-    if target == Aarch64Linux || target == X8664Linux {
-        let linux_vorpal = artifacts
-            .iter()
-            .find(|a| a.name == "linux-vorpal")
-            .expect("linux-vorpal artifact not found");
-
-        steps.push(bwrap(
-            vec![],
-            artifacts.clone(),
-            env.clone(),
-            Some(linux_vorpal.clone()),
-            script.to_string(),
-        ));
-    }
+    let mut step = shell(
+        artifacts.clone(),
+        env.clone(),
+        name,
+        rootfs,
+        script,
+        script_shell,
+        target,
+    )?;
 
-    if target == Aarch64Macos || target == X8664Macos {
-        steps.push(bash(env.clone(), script.to_string()));
+    if let Some(incremental) = incremental {
+        step = step.with_incremental(&incremental.key, &incremental.path);
     }
 
+    let steps = vec![step];
+
     // Add artifact to context
 
     context
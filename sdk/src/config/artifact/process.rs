@@ -0,0 +1,65 @@
+use crate::config::{artifact::add_artifact, ConfigContext};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use vorpal_schema::{get_process_template_script, vorpal::artifact::v0::ArtifactId};
+use vorpal_store::paths::get_process_path;
+
+/// Long-running process with `bin/<name>-start`, `bin/<name>-stop` and `bin/<name>-logs` scripts.
+///
+/// The pid, log and state of a started process are kept in `/var/lib/vorpal/process/<name>`,
+/// outside the store, and read by `vorpal process status` and `vorpal process logs`.
+pub struct ArtifactProcessBuilder<'a> {
+    arguments: Vec<String>,
+    artifacts: Vec<ArtifactId>,
+    entrypoint: &'a str,
+    name: &'a str,
+}
+
+impl<'a> ArtifactProcessBuilder<'a> {
+    /// Runs `entrypoint`, usually a path in an artifact (`$VORPAL_ARTIFACT_<name>/bin/server`).
+    pub fn new(name: &'a str, entrypoint: &'a str) -> Self {
+        Self {
+            arguments: vec![],
+            artifacts: vec![],
+            entrypoint,
+            name,
+        }
+    }
+
+    pub fn with_arguments(mut self, arguments: Vec<&str>) -> Self {
+        self.arguments = arguments.into_iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    pub fn with_artifacts(mut self, artifacts: Vec<ArtifactId>) -> Self {
+        self.artifacts = artifacts;
+        self
+    }
+
+    pub async fn build(self, context: &mut ConfigContext) -> Result<ArtifactId> {
+        let name = self.name;
+
+        let script = get_process_template_script(
+            name,
+            self.entrypoint,
+            &self.arguments,
+            &get_process_path(name).display().to_string(),
+        );
+
+        add_artifact(
+            context,
+            self.artifacts,
+            BTreeMap::new(),
+            name,
+            script,
+            BTreeMap::new(),
+            vec![
+                "aarch64-linux",
+                "aarch64-macos",
+                "x86_64-linux",
+                "x86_64-macos",
+            ],
+        )
+        .await
+    }
+}
@@ -1,10 +1,100 @@
 use crate::config::artifact::get_artifact_envkey;
+use anyhow::{bail, Result};
 use indoc::formatdoc;
 use std::collections::BTreeMap;
-use vorpal_schema::vorpal::artifact::v0::{ArtifactId, ArtifactStep, ArtifactStepEnvironment};
+use vorpal_schema::{
+    vorpal::artifact::v0::{
+        ArtifactId, ArtifactStep, ArtifactStepEnvironment, ArtifactStepIncremental, ArtifactSystem,
+        ArtifactSystem::{Aarch64Linux, Aarch64Macos, X8664Linux, X8664Macos},
+    },
+    STEP_PLACEHOLDER_ARTIFACT, STEP_PLACEHOLDER_OUTPUT, STEP_PLACEHOLDER_WORKSPACE,
+};
 
 // TODO: implement amber step
 
+// Interpreter of shell steps on macOS, which run on the host without a sandbox
+const SHELL_MACOS: &str = "/bin/bash";
+
+/// Placeholder the worker resolves to the store path of `artifact` in step entrypoints and
+/// arguments, which unlike `$VORPAL_ARTIFACT_<name>` does not need a shell to expand.
+pub fn get_artifact_placeholder(artifact: &ArtifactId) -> String {
+    format!("{{{{{}{}}}}}", STEP_PLACEHOLDER_ARTIFACT, artifact.hash)
+}
+
+/// Placeholder the worker resolves to the output path in step entrypoints and arguments.
+pub fn get_output_placeholder() -> String {
+    format!("{{{{{}}}}}", STEP_PLACEHOLDER_OUTPUT)
+}
+
+/// Placeholder the worker resolves to the workspace path in step entrypoints and arguments.
+pub fn get_workspace_placeholder() -> String {
+    format!("{{{{{}}}}}", STEP_PLACEHOLDER_WORKSPACE)
+}
+
+pub trait ArtifactStepOptions {
+    /// Limits the step to `cpus` cores.
+    fn with_cpu_limit(self, cpus: u32) -> Self;
+
+    /// Limits the step memory to `bytes`, the worker reports steps killed by this limit.
+    fn with_memory_limit(self, bytes: u64) -> Self;
+
+    /// Allows the step to override the artifact-wide environment variable `key`.
+    fn with_environment_override(self, key: &str) -> Self;
+
+    /// Snapshots the step output so workers with step caching can skip it on rebuilds.
+    fn with_cache(self) -> Self;
+
+    /// Runs the step in `path` relative to the workspace, created if missing.
+    fn with_working_directory(self, path: &str) -> Self;
+
+    /// Points `VORPAL_OUTPUT` at `path` relative to the output, which stays part of the artifact.
+    fn with_output_subdir(self, path: &str) -> Self;
+
+    /// Keeps `path` relative to the workspace between builds of steps with the same `key` on a
+    /// worker, such as a cargo `target/` directory. The step must produce the same output
+    /// without it.
+    fn with_incremental(self, key: &str, path: &str) -> Self;
+}
+
+impl ArtifactStepOptions for ArtifactStep {
+    fn with_cpu_limit(mut self, cpus: u32) -> Self {
+        self.cpu_limit = Some(cpus);
+        self
+    }
+
+    fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit_bytes = Some(bytes);
+        self
+    }
+
+    fn with_environment_override(mut self, key: &str) -> Self {
+        self.environment_overrides.push(key.to_string());
+        self
+    }
+
+    fn with_cache(mut self) -> Self {
+        self.cache = true;
+        self
+    }
+
+    fn with_working_directory(mut self, path: &str) -> Self {
+        self.working_directory = Some(path.to_string());
+        self
+    }
+
+    fn with_output_subdir(mut self, path: &str) -> Self {
+        self.output_subdir = Some(path.to_string());
+        self
+    }
+    fn with_incremental(mut self, key: &str, path: &str) -> Self {
+        self.incremental = Some(ArtifactStepIncremental {
+            key: key.to_string(),
+            path: path.to_string(),
+        });
+        self
+    }
+}
+
 pub fn bash(environment: BTreeMap<&str, String>, script: String) -> ArtifactStep {
     let mut environment = environment.clone();
 
@@ -30,8 +120,14 @@ pub fn bash(environment: BTreeMap<&str, String>, script: String) -> ArtifactStep
 
     ArtifactStep {
         arguments: vec![],
+        cache: false,
+        cpu_limit: None,
         entrypoint: Some("bash".to_string()),
+        environment_overrides: vec![],
         environments,
+        incremental: None,
+        memory_limit_bytes: None,
+        output_subdir: None,
         script: Some(formatdoc! {"
             #!/bin/bash
             set -euo pipefail
@@ -39,6 +135,57 @@ pub fn bash(environment: BTreeMap<&str, String>, script: String) -> ArtifactStep
             {script}",
             script = script,
         }),
+        working_directory: None,
+    }
+}
+
+/// PowerShell step for Windows targets, run with `pwsh` on the cross-building host.
+pub fn pwsh(environment: BTreeMap<&str, String>, script: String) -> ArtifactStep {
+    let mut environment = environment.clone();
+
+    let path_defined_default = "".to_string();
+    let path_defined = environment.get("PATH").unwrap_or(&path_defined_default);
+
+    let mut path = "/usr/local/bin:/usr/bin:/usr/sbin:/bin:/sbin".to_string();
+
+    if !path_defined.is_empty() {
+        path = format!("{}:{}", path_defined, path);
+    }
+
+    environment.insert("PATH", path);
+
+    let mut environments = vec![];
+
+    for (key, value) in environment {
+        environments.push(ArtifactStepEnvironment {
+            key: key.to_string(),
+            value,
+        });
+    }
+
+    ArtifactStep {
+        arguments: vec![
+            "-NoLogo".to_string(),
+            "-NoProfile".to_string(),
+            "-NonInteractive".to_string(),
+            "-File".to_string(),
+        ],
+        cache: false,
+        cpu_limit: None,
+        entrypoint: Some("pwsh".to_string()),
+        environment_overrides: vec![],
+        environments,
+        incremental: None,
+        memory_limit_bytes: None,
+        output_subdir: None,
+        script: Some(formatdoc! {"
+            $ErrorActionPreference = 'Stop'
+            Set-StrictMode -Version Latest
+
+            {script}",
+            script = script,
+        }),
+        working_directory: None,
     }
 }
 
@@ -140,12 +287,67 @@ pub fn bwrap(
 
     ArtifactStep {
         arguments: args,
+        cache: false,
+        cpu_limit: None,
         entrypoint: Some("bwrap".to_string()),
+        environment_overrides: vec![],
         environments: vec![ArtifactStepEnvironment {
             key: "PATH".to_string(),
             value: path,
         }],
+        incremental: None,
+        memory_limit_bytes: None,
+        output_subdir: None,
         script: Some(script),
+        working_directory: None,
+    }
+}
+
+/// Returns whether shell steps can run for `target`.
+pub fn is_shell_system(target: ArtifactSystem) -> bool {
+    matches!(
+        target,
+        Aarch64Linux | Aarch64Macos | X8664Linux | X8664Macos
+    )
+}
+
+/// Shell step of artifact `name` running `script` for `target`.
+///
+/// Linux steps run in the bwrap sandbox with `rootfs`, macOS steps with `/bin/bash`. A `shell`
+/// (`/bin/zsh`) replaces the interpreter on either, steps without one are unchanged.
+pub fn shell(
+    artifacts: Vec<ArtifactId>,
+    environment: BTreeMap<&str, String>,
+    name: &str,
+    rootfs: Option<ArtifactId>,
+    script: String,
+    shell: Option<&str>,
+    target: ArtifactSystem,
+) -> Result<ArtifactStep> {
+    match target {
+        // The worker runs the script file in the sandbox, which honors its shebang
+        Aarch64Linux | X8664Linux => {
+            let script = match shell {
+                Some(shell) => format!("#!{}\n{}", shell, script),
+                None => script,
+            };
+
+            Ok(bwrap(vec![], artifacts, environment, rootfs, script))
+        }
+
+        Aarch64Macos | X8664Macos => {
+            let mut step = bash(environment, script);
+
+            step.entrypoint = Some(shell.unwrap_or(SHELL_MACOS).to_string());
+
+            Ok(step)
+        }
+
+        _ => bail!(
+            "`{}` uses a shell script, which can not target `{}`",
+            name,
+            target.as_str_name()
+        ),
     }
 }
 
@@ -154,11 +356,18 @@ pub fn docker(arguments: Vec<String>) -> ArtifactStep {
 
     ArtifactStep {
         arguments,
+        cache: false,
+        cpu_limit: None,
         entrypoint: Some("docker".to_string()),
+        environment_overrides: vec![],
         environments: vec![ArtifactStepEnvironment {
             key: "PATH".to_string(),
             value: path,
         }],
+        incremental: None,
+        memory_limit_bytes: None,
+        output_subdir: None,
         script: None,
+        working_directory: None,
     }
 }
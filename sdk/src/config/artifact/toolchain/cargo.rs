@@ -1,42 +1,89 @@
 use crate::config::{
-    artifact::{add_artifact, language::rust::get_toolchain_target, ConfigContext},
+    artifact::{
+        add_artifact,
+        language::rust::{get_toolchain_digests, get_toolchain_target},
+        ConfigContext,
+    },
     ArtifactSource,
 };
 use anyhow::{bail, Result};
 use std::collections::BTreeMap;
 use vorpal_schema::vorpal::artifact::v0::{
     ArtifactId,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
 
+// Upstream sha256 of the archive for other versions than the default, by version and target
+const CONTENT_DIGESTS: [(&str, &str, &str); 4] = [
+    (
+        "1.79.0",
+        "x86_64-unknown-linux-gnu",
+        "d394298cfd4a51eaf85607cceb33a1d83cbe723365687d7055f4b68e065a72fe",
+    ),
+    (
+        "1.80.1",
+        "x86_64-unknown-linux-gnu",
+        "67c9c24442879316f42cd77ef8f680979a0a100557c563e2ff5a58588f67b36f",
+    ),
+    (
+        "1.81.0",
+        "x86_64-unknown-linux-gnu",
+        "e735432b85349aa78ed164ff03a31c43298f46a085fef047a33607adee80adc3",
+    ),
+    (
+        "1.82.0",
+        "x86_64-unknown-linux-gnu",
+        "45594e7c01a27c4d9c128852735b84c80439fe1a07cffb664944e8a1036fd9f0",
+    ),
+];
+
 pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
     let hash = match context.get_target() {
         Aarch64Linux => "42781c7ae909a5cd01c955cb4343754ce33d75783b2599a3f1a3b3752a0947af",
         Aarch64Macos => "e88e4babfc20e0546fe28bc2ba3f71a467f83e9fb1be76c9a078d327379ee4d0",
         X8664Linux => "62091f43974e3e24583cceae24db710e9bd6863f366b9a5891bd7a5aa3d8c0fd",
         X8664Macos => "1234567890",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+        UnknownSystem | X8664Windows => bail!("Invalid protoc system: {:?}", context.get_target()),
     };
 
     let name = "cargo";
 
     let target = get_toolchain_target(context.get_target())?;
 
+    let content_digest = CONTENT_DIGESTS
+        .iter()
+        .find(|(v, t, _)| *v == version && *t == target)
+        .map(|(_, _, digest)| *digest);
+
+    let (content_digest, hash) = get_toolchain_digests(
+        &format!("{name}-{version}-{target}"),
+        content_digest,
+        hash,
+        version,
+    )?;
+
     add_artifact(
         context,
         vec![],
         BTreeMap::new(),
-        name,
+        &format!("{name}-{version}"),
         format!(
             "cp -prv \"./source/{name}/{name}-{version}-{target}/{name}/.\" \"$VORPAL_OUTPUT\""
         ),
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest,
                 excludes: vec![],
-                hash: Some(hash.to_string()),
+                executable: false,
+                hash,
                 includes: vec![],
                 path: format!("https://static.rust-lang.org/dist/{name}-{version}-{target}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
             },
         )]),
         vec![
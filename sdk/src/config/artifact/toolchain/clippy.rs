@@ -1,40 +1,87 @@
 use crate::config::{
-    artifact::{add_artifact, language::rust::get_toolchain_target, ConfigContext},
+    artifact::{
+        add_artifact,
+        language::rust::{get_toolchain_digests, get_toolchain_target},
+        ConfigContext,
+    },
     ArtifactSource,
 };
 use anyhow::{bail, Result};
 use std::collections::BTreeMap;
 use vorpal_schema::vorpal::artifact::v0::{
     ArtifactId,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
 
+// Upstream sha256 of the archive for other versions than the default, by version and target
+const CONTENT_DIGESTS: [(&str, &str, &str); 4] = [
+    (
+        "1.79.0",
+        "x86_64-unknown-linux-gnu",
+        "a38d8ca1beb010a098b380af0651aa2d1b1bfb02a05f319257a06b9c98eebe45",
+    ),
+    (
+        "1.80.1",
+        "x86_64-unknown-linux-gnu",
+        "d79814945a59445932c6ecd29649c0d11efc0685a227e6d50622cea331715f3d",
+    ),
+    (
+        "1.81.0",
+        "x86_64-unknown-linux-gnu",
+        "8cb8147f868b049024cb663e77f62fac7d579d5b61d2ba9649c3e7e6e8e5773f",
+    ),
+    (
+        "1.82.0",
+        "x86_64-unknown-linux-gnu",
+        "e81e9e8dd3c514626a1a0c705ef5e037bcafcf9d79fa34f718d3043e8fd2987e",
+    ),
+];
+
 pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
     let hash = match context.get_target() {
         Aarch64Linux => "5e0b5cb7e8655501369a6f42cb10b1c5d4711a0edfcbe44483c5234da485819d",
         Aarch64Macos => "fe82bf19b064f6fca648b9be6a53ae210a9934023df364d669fc7c4ee5ccd485",
         X8664Linux => "84168586980d4dfa8f385c83d66af0dcc3256668f0a3109b57712340251660f1",
         X8664Macos => "1234567890",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+        UnknownSystem | X8664Windows => bail!("Invalid protoc system: {:?}", context.get_target()),
     };
 
     let name = "clippy";
 
     let target = get_toolchain_target(context.get_target())?;
 
+    let content_digest = CONTENT_DIGESTS
+        .iter()
+        .find(|(v, t, _)| *v == version && *t == target)
+        .map(|(_, _, digest)| *digest);
+
+    let (content_digest, hash) = get_toolchain_digests(
+        &format!("{name}-{version}-{target}"),
+        content_digest,
+        hash,
+        version,
+    )?;
+
     add_artifact(
         context,
         vec![],
         BTreeMap::new(),
-        name,
+        &format!("{name}-{version}"),
         format!("cp -prv \"./source/{name}/{name}-{version}-{target}/{name}-preview/.\" \"$VORPAL_OUTPUT\""),
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest,
                 excludes: vec![],
-                hash: Some(hash.to_string()),
+                executable: false,
+                hash,
                 includes: vec![],
                 path: format!("https://static.rust-lang.org/dist/{name}-{version}-{target}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
             }
         )]),
         vec![
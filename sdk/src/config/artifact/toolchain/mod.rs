@@ -1,7 +1,9 @@
 pub mod cargo;
 pub mod clippy;
 pub mod linux;
+pub mod nodejs;
 pub mod protoc;
+pub mod python;
 pub mod rust_analyzer;
 pub mod rust_src;
 pub mod rust_std;
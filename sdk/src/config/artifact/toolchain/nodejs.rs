@@ -0,0 +1,92 @@
+use crate::config::{artifact::add_artifact, ArtifactSource, ConfigContext};
+use anyhow::{bail, Result};
+use indoc::formatdoc;
+use std::collections::BTreeMap;
+use vorpal_schema::vorpal::artifact::v0::{
+    ArtifactId,
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
+};
+
+// Versions with upstream builds for every system below, the first being the default
+pub const NODEJS_VERSIONS: [&str; 3] = ["22.12.0", "20.18.1", "18.20.5"];
+
+pub fn get_nodejs_version() -> String {
+    NODEJS_VERSIONS[0].to_string()
+}
+
+/// Fails for versions without upstream builds for every system, listing the known ones.
+pub fn check_nodejs_version(version: &str) -> Result<()> {
+    if !NODEJS_VERSIONS.contains(&version) {
+        bail!(
+            "unknown nodejs version: {} (known: {})",
+            version,
+            NODEJS_VERSIONS.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
+    check_nodejs_version(version)?;
+
+    let name = "nodejs";
+
+    let target = match context.get_target() {
+        Aarch64Linux => "linux-arm64",
+        Aarch64Macos => "darwin-arm64",
+        X8664Linux => "linux-x64",
+        X8664Macos => "darwin-x64",
+        UnknownSystem | X8664Windows => bail!("Invalid nodejs system: {:?}", context.get_target()),
+    };
+
+    add_artifact(
+        context,
+        vec![],
+        BTreeMap::new(),
+        &format!("{name}-{version}"),
+        formatdoc! {"
+            cp -prv \"./source/{name}/node-v{version}-{target}/.\" \"$VORPAL_OUTPUT\"",
+        },
+        // Upstream builds differ per version and system, their hashes are locked in
+        // `Vorpal.lock` by the first build instead of pinned here
+        BTreeMap::from([(
+            name,
+            ArtifactSource {
+                allow_outside_context: false,
+                content_digest: None,
+                excludes: vec![],
+                executable: false,
+                hash: None,
+                includes: vec![],
+                path: format!("https://nodejs.org/dist/v{version}/node-v{version}-{target}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
+            },
+        )]),
+        vec![
+            "aarch64-linux",
+            "aarch64-macos",
+            "x86_64-linux",
+            "x86_64-macos",
+        ],
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_nodejs_version() {
+        assert!(check_nodejs_version(&get_nodejs_version()).is_ok());
+
+        let error = check_nodejs_version("0.0.1").unwrap_err().to_string();
+
+        assert!(error.contains("unknown nodejs version: 0.0.1"));
+        assert!(error.contains(&get_nodejs_version()));
+    }
+}
@@ -4,7 +4,9 @@ use indoc::formatdoc;
 use std::collections::BTreeMap;
 use vorpal_schema::vorpal::artifact::v0::{
     ArtifactId,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
 
 pub async fn artifact(context: &mut ConfigContext) -> Result<ArtifactId> {
@@ -13,7 +15,7 @@ pub async fn artifact(context: &mut ConfigContext) -> Result<ArtifactId> {
         Aarch64Macos => "d105abb1c1d2c024f29df884f0592f1307984d63aeb10f0e61ccb94aee2c2feb",
         X8664Linux => "d5e8fb327ea9568fd1ce2de3557740948a2168faff79c0e02e64bd9f040964d9",
         X8664Macos => "1234567890",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+        UnknownSystem | X8664Windows => bail!("Invalid protoc system: {:?}", context.get_target()),
     };
 
     let name = "protoc";
@@ -23,7 +25,7 @@ pub async fn artifact(context: &mut ConfigContext) -> Result<ArtifactId> {
         Aarch64Macos => "osx-aarch_64",
         X8664Linux => "linux-x86_64",
         X8664Macos => "osx-x86_64",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+        UnknownSystem | X8664Windows => bail!("Invalid protoc system: {:?}", context.get_target()),
     };
 
     let version = "25.4";
@@ -43,10 +45,15 @@ pub async fn artifact(context: &mut ConfigContext) -> Result<ArtifactId> {
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest: None,
                 excludes: vec![],
+                executable: false,
                 hash: Some(hash.to_string()),
                 includes: vec![],
                 path: format!("https://github.com/protocolbuffers/protobuf/releases/download/v{version}/{name}-{version}-{target}.zip"),
+                rename: None,
+                strip_prefix: false,
             }
         )]),
         vec![
@@ -0,0 +1,98 @@
+use crate::config::{artifact::add_artifact, ArtifactSource, ConfigContext};
+use anyhow::{bail, Result};
+use indoc::formatdoc;
+use std::collections::BTreeMap;
+use vorpal_schema::vorpal::artifact::v0::{
+    ArtifactId,
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
+};
+
+// Release of the standalone python builds providing the interpreter
+const PYTHON_STANDALONE_RELEASE: &str = "20241206";
+
+// Versions built by `PYTHON_STANDALONE_RELEASE` for every system below, the first being the
+// default
+pub const PYTHON_VERSIONS: [&str; 3] = ["3.12.8", "3.13.1", "3.11.11"];
+
+pub fn get_python_version() -> String {
+    PYTHON_VERSIONS[0].to_string()
+}
+
+/// Fails for versions without standalone builds for every system, listing the known ones.
+pub fn check_python_version(version: &str) -> Result<()> {
+    if !PYTHON_VERSIONS.contains(&version) {
+        bail!(
+            "unknown python version: {} (known: {})",
+            version,
+            PYTHON_VERSIONS.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
+    check_python_version(version)?;
+
+    let name = "python";
+
+    let target = match context.get_target() {
+        Aarch64Linux => "aarch64-unknown-linux-gnu",
+        Aarch64Macos => "aarch64-apple-darwin",
+        X8664Linux => "x86_64-unknown-linux-gnu",
+        X8664Macos => "x86_64-apple-darwin",
+        UnknownSystem | X8664Windows => bail!("Invalid python system: {:?}", context.get_target()),
+    };
+
+    let release = PYTHON_STANDALONE_RELEASE;
+
+    add_artifact(
+        context,
+        vec![],
+        BTreeMap::new(),
+        &format!("{name}-{version}"),
+        formatdoc! {"
+            cp -prv \"./source/{name}/python/.\" \"$VORPAL_OUTPUT\"",
+        },
+        // Upstream builds differ per version and system, their hashes are locked in
+        // `Vorpal.lock` by the first build instead of pinned here
+        BTreeMap::from([(
+            name,
+            ArtifactSource {
+                allow_outside_context: false,
+                content_digest: None,
+                excludes: vec![],
+                executable: false,
+                hash: None,
+                includes: vec![],
+                path: format!("https://github.com/astral-sh/python-build-standalone/releases/download/{release}/cpython-{version}+{release}-{target}-install_only.tar.gz"),
+                rename: None,
+                strip_prefix: false,
+            },
+        )]),
+        vec![
+            "aarch64-linux",
+            "aarch64-macos",
+            "x86_64-linux",
+            "x86_64-macos",
+        ],
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_python_version() {
+        assert!(check_python_version(&get_python_version()).is_ok());
+
+        let error = check_python_version("0.0.1").unwrap_err().to_string();
+
+        assert!(error.contains("unknown python version: 0.0.1"));
+        assert!(error.contains(&get_python_version()));
+    }
+}
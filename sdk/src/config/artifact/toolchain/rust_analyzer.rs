@@ -1,40 +1,87 @@
 use crate::config::{
-    artifact::{add_artifact, language::rust::get_toolchain_target, ConfigContext},
+    artifact::{
+        add_artifact,
+        language::rust::{get_toolchain_digests, get_toolchain_target},
+        ConfigContext,
+    },
     ArtifactSource,
 };
 use anyhow::{bail, Result};
 use std::collections::BTreeMap;
 use vorpal_schema::vorpal::artifact::v0::{
     ArtifactId,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
 
+// Upstream sha256 of the archive for other versions than the default, by version and target
+const CONTENT_DIGESTS: [(&str, &str, &str); 4] = [
+    (
+        "1.79.0",
+        "x86_64-unknown-linux-gnu",
+        "a6c2c1f94377e27c6d60bd901a00e8d24c1c4d713a1e43486ee42d400af2f824",
+    ),
+    (
+        "1.80.1",
+        "x86_64-unknown-linux-gnu",
+        "321164f2a724b9baa04fe10e45878e3acb44a297d94977d62ca78b5b53207cb1",
+    ),
+    (
+        "1.81.0",
+        "x86_64-unknown-linux-gnu",
+        "70d06d64420ca8355d270686e28a6e46d57fadd9c171b69dce8e372f82cc761e",
+    ),
+    (
+        "1.82.0",
+        "x86_64-unknown-linux-gnu",
+        "14ac9ed72ea79b7df7f7363c7590851fdb52f7cf30a5e2d97d2c012d1e3d3140",
+    ),
+];
+
 pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
     let hash = match context.get_target() {
         Aarch64Linux => "79fbf7077b846a4b28935fa6a22259d589baed2197c08bfc5c362f1e3f54db44",
         Aarch64Macos => "ba92aa08cdada8fad8d772623b0522cb3d6e659a8edb9e037453fab998772a19",
         X8664Linux => "b3d88f0ed6f77562f8376756d1b09fc7f5604aedcfac0ded2dd424c069e34ebe",
         X8664Macos => "1234567890",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+        UnknownSystem | X8664Windows => bail!("Invalid protoc system: {:?}", context.get_target()),
     };
 
     let name = "rust-analyzer";
 
     let target = get_toolchain_target(context.get_target())?;
 
+    let content_digest = CONTENT_DIGESTS
+        .iter()
+        .find(|(v, t, _)| *v == version && *t == target)
+        .map(|(_, _, digest)| *digest);
+
+    let (content_digest, hash) = get_toolchain_digests(
+        &format!("{name}-{version}-{target}"),
+        content_digest,
+        hash,
+        version,
+    )?;
+
     add_artifact(
         context,
         vec![],
         BTreeMap::new(),
-        name,
+        &format!("{name}-{version}"),
         format!("cp -prv \"./source/{name}/{name}-{version}-{target}/{name}-preview/.\" \"$VORPAL_OUTPUT\""),
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest,
                 excludes: vec![],
-                hash: Some(hash.to_string()),
+                executable: false,
+                hash,
                 includes: vec![],
                 path: format!("https://static.rust-lang.org/dist/{name}-{version}-{target}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
             }
         )]),
         vec![
@@ -1,29 +1,62 @@
 use crate::config::{
-    artifact::{add_artifact, ConfigContext},
+    artifact::{add_artifact, language::rust::get_toolchain_digests, ConfigContext},
     ArtifactSource,
 };
 use anyhow::Result;
 use std::collections::BTreeMap;
 use vorpal_schema::vorpal::artifact::v0::ArtifactId;
 
+// Upstream sha256 of the archive for other versions than the default, by version
+const CONTENT_DIGESTS: [(&str, &str); 4] = [
+    (
+        "1.79.0",
+        "43c640ca0a4efa0639cf529ce06a884e32b9f31614759d7e025af5d5880afd39",
+    ),
+    (
+        "1.80.1",
+        "c33eae6921b22be6520bbba9dc0b1ea21250ea3054bf15006dba30bab97eba9f",
+    ),
+    (
+        "1.81.0",
+        "c0b2f10a6923e4065ff1644307cf838542d177ab108a7350fc8638820e8d2650",
+    ),
+    (
+        "1.82.0",
+        "3ee4c9656ee1e7c7f83698116ccfaf5b1cc007c5a6cc96363cf6c0bf97a9cfa8",
+    ),
+];
+
 pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
     let hash = "5f0adbae49a5442bf3389f7798cbacba92a94b7fefe7810ce00d1356a861d305";
 
     let name = "rust-src";
 
+    let content_digest = CONTENT_DIGESTS
+        .iter()
+        .find(|(v, _)| *v == version)
+        .map(|(_, digest)| *digest);
+
+    let (content_digest, hash) =
+        get_toolchain_digests(&format!("{name}-{version}"), content_digest, hash, version)?;
+
     add_artifact(
         context,
         vec![],
         BTreeMap::new(),
-        name,
+        &format!("{name}-{version}"),
         format!("cp -prv \"./source/{name}/{name}-{version}/{name}/.\" \"$VORPAL_OUTPUT\""),
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest,
                 excludes: vec![],
-                hash: Some(hash.to_string()),
+                executable: false,
+                hash,
                 includes: vec![],
                 path: format!("https://static.rust-lang.org/dist/{name}-{version}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
             },
         )]),
         vec![
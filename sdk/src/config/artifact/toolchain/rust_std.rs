@@ -1,40 +1,232 @@
 use crate::config::{
-    artifact::{add_artifact, language::rust::get_toolchain_target, ConfigContext},
+    artifact::{
+        add_artifact,
+        language::rust::{get_toolchain_digests, get_toolchain_target},
+        ConfigContext,
+    },
     ArtifactSource,
 };
 use anyhow::{bail, Result};
 use std::collections::BTreeMap;
-use vorpal_schema::vorpal::artifact::v0::{
-    ArtifactId,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
-};
+use vorpal_schema::vorpal::artifact::v0::ArtifactId;
+
+// Upstream sha256 of the archive for other versions than the default, by version and target
+const CONTENT_DIGESTS: [(&str, &str, &str); 28] = [
+    (
+        "1.79.0",
+        "aarch64-apple-darwin",
+        "80efc6db54cfc74484eaf51022eb60caaa3e1da9c3f3610ac94c5013386f8271",
+    ),
+    (
+        "1.79.0",
+        "aarch64-linux-android",
+        "f6cd91b302933d62acd879cba7eab0ad40a03a551f7e612d858091553295b84f",
+    ),
+    (
+        "1.79.0",
+        "aarch64-unknown-linux-gnu",
+        "e4597274ffbad0b3196dbab9f8badbdf33d4407a07e3f577c074cc90f95c0ca5",
+    ),
+    (
+        "1.79.0",
+        "aarch64-unknown-linux-musl",
+        "8c65e465f3f82ae55fc2f43ac58de7d972497bc839829e0119784be98faa8431",
+    ),
+    (
+        "1.79.0",
+        "x86_64-apple-darwin",
+        "52575ebb436bd67016a5fdf8a6e85ffb258c7f1f70c6b2d4a4e0b98204be327d",
+    ),
+    (
+        "1.79.0",
+        "x86_64-unknown-linux-gnu",
+        "037906a372ec87f8fd7ab45efa645bcc4fbf981f534e31534c6f16ce628fddb6",
+    ),
+    (
+        "1.79.0",
+        "x86_64-unknown-linux-musl",
+        "8ee9728f1f615ca07aeec963f85cd4ad6941b932fffce6c434dd012b9e094eeb",
+    ),
+    (
+        "1.80.1",
+        "aarch64-apple-darwin",
+        "7da7be82dd9e6697829e271feaa5898a089721e5b52bac764e3672472dd69081",
+    ),
+    (
+        "1.80.1",
+        "aarch64-linux-android",
+        "4bed3d4db02bb67e1c420df3364ef9c39951f252d4f3a31559711bf8bba9176e",
+    ),
+    (
+        "1.80.1",
+        "aarch64-unknown-linux-gnu",
+        "2465f0df2ee35d32bd9690e05dd84d9c38bf81e8a5e9fd940d7347b66447c97f",
+    ),
+    (
+        "1.80.1",
+        "aarch64-unknown-linux-musl",
+        "9ff88076cc699c5e5965f7ea99e2eae614a597c9002cdac0e24b4615f0e6b7e9",
+    ),
+    (
+        "1.80.1",
+        "x86_64-apple-darwin",
+        "8fe1bd5ac9fb8741d3049b753a6eabec0e88d9c2c0276fdff34f868b35edda41",
+    ),
+    (
+        "1.80.1",
+        "x86_64-unknown-linux-gnu",
+        "c58f796e884d5e8882e13775d726f514158aa5e26aef6c4f756bdacdbc1512bf",
+    ),
+    (
+        "1.80.1",
+        "x86_64-unknown-linux-musl",
+        "097f6610f9000f29af4b261b49c82251eefd2991ba52249e67922cc63854a63e",
+    ),
+    (
+        "1.81.0",
+        "aarch64-apple-darwin",
+        "44809c3b92c7500c64517151f1e3389b32913a35414553395104bc4a0ee35f69",
+    ),
+    (
+        "1.81.0",
+        "aarch64-linux-android",
+        "edbef1d1dd515035d24c309c6ef52dfda98ec7ef7ab30bf415dce4a65d59d24a",
+    ),
+    (
+        "1.81.0",
+        "aarch64-unknown-linux-gnu",
+        "234673e33b7a523818a81dc233ba636ffc5e4c94b9766f12e19a63c985ed7d21",
+    ),
+    (
+        "1.81.0",
+        "aarch64-unknown-linux-musl",
+        "f0dbb9ed466a56f389956c51e7dc28f6bf15cb7d1c933450252503e8ac4ce482",
+    ),
+    (
+        "1.81.0",
+        "x86_64-apple-darwin",
+        "ce8ad1cf2c5a7948a8f468025a5985a5249ba2fdf3303ef753170904451b4fa4",
+    ),
+    (
+        "1.81.0",
+        "x86_64-unknown-linux-gnu",
+        "7c6918beb76e62dcf43294b18fabe058239e2fb9c8c04ebda3854f9f2b22df3c",
+    ),
+    (
+        "1.81.0",
+        "x86_64-unknown-linux-musl",
+        "4f0e181830b18c65e5ccc3281d93547fd4c061dfeb6576ca743741409c5ab225",
+    ),
+    (
+        "1.82.0",
+        "aarch64-apple-darwin",
+        "5ec28e75ed8715efaa2490d76ae026a34b13df6899d98b14d0a6995556f4e6b4",
+    ),
+    (
+        "1.82.0",
+        "aarch64-linux-android",
+        "2ae7787121d3b51fbbf96f0191147c1ea1698f9b92bee1096adad3ea74860eab",
+    ),
+    (
+        "1.82.0",
+        "aarch64-unknown-linux-gnu",
+        "82b2308ee531775bf4d1faa57bddfae85f363bec43ca36ba6db4ebad7c1450d4",
+    ),
+    (
+        "1.82.0",
+        "aarch64-unknown-linux-musl",
+        "1ea6dcf3a0c0d3a7133fa75f157bd17a3e1363be135f39325ab89ee1775de96b",
+    ),
+    (
+        "1.82.0",
+        "x86_64-apple-darwin",
+        "52084c8cdb34ca139a00f9f03f1a582d96b677e9f223a8d1aa31ae575a06cc16",
+    ),
+    (
+        "1.82.0",
+        "x86_64-unknown-linux-gnu",
+        "e7e808b8745298369fa3bbc3c0b7af9ca0fb995661bd684a7022d14bc9ae0057",
+    ),
+    (
+        "1.82.0",
+        "x86_64-unknown-linux-musl",
+        "92170a6423a606948118d9500febc234dac83c567eccfeeb75307c77d848a529",
+    ),
+];
 
-pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
-    let hash = match context.get_target() {
-        Aarch64Linux => "d560efe018be876f2d5a9106f4b37222f0d315f52aeb12ffb0bfbfc8071fc5b1",
-        Aarch64Macos => "6d636e93ec5f9a2e8a7c5bae381dc9a89808087b2eec1f987f8ed5a797fef556",
-        X8664Linux => "4ae19ae088abd72073dbf6dfbe9c68f8c70a4c2aa77c018c63b099d8732464c3",
-        X8664Macos => "1234567890",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+fn get_hash(target: &str) -> Result<&'static str> {
+    let hash = match target {
+        "aarch64-apple-darwin" => {
+            "6d636e93ec5f9a2e8a7c5bae381dc9a89808087b2eec1f987f8ed5a797fef556"
+        }
+        "aarch64-linux-android" => {
+            "6f3db7d801509309539ed17fd3f732281ef50e7d30121a9d9bdfff85ff5f23fa"
+        }
+        "aarch64-unknown-linux-gnu" => {
+            "d560efe018be876f2d5a9106f4b37222f0d315f52aeb12ffb0bfbfc8071fc5b1"
+        }
+        "aarch64-unknown-linux-musl" => {
+            "b9802b05f83a55a6d1704107c008c9bf49d3edfae05715844bf583ae6d2cff3d"
+        }
+        "x86_64-apple-darwin" => "1234567890",
+        "x86_64-unknown-linux-gnu" => {
+            "4ae19ae088abd72073dbf6dfbe9c68f8c70a4c2aa77c018c63b099d8732464c3"
+        }
+        "x86_64-unknown-linux-musl" => {
+            "f033292e85099eb387ac878388ee289ba9523e3e3e6dcf4d4fa266051b0627ee"
+        }
+        _ => bail!("rust-std not available for target: {}", target),
     };
 
+    Ok(hash)
+}
+
+/// Standard library for `target`, which may differ from the host to cross compile.
+pub async fn artifact(
+    context: &mut ConfigContext,
+    target: &str,
+    version: &str,
+) -> Result<ArtifactId> {
+    let hash = get_hash(target)?;
+
     let name = "rust-std";
 
-    let target = get_toolchain_target(context.get_target())?;
+    let content_digest = CONTENT_DIGESTS
+        .iter()
+        .find(|(v, t, _)| *v == version && *t == target)
+        .map(|(_, _, digest)| *digest);
+
+    let (content_digest, hash) = get_toolchain_digests(
+        &format!("{name}-{version}-{target}"),
+        content_digest,
+        hash,
+        version,
+    )?;
+
+    let artifact_name = if target == get_toolchain_target(context.get_target())? {
+        format!("{name}-{version}")
+    } else {
+        format!("{name}-{version}-{target}")
+    };
 
     add_artifact(
         context,
         vec![],
         BTreeMap::new(),
-        name,
+        &artifact_name,
         format!("cp -prv \"./source/{name}/{name}-{version}-{target}/{name}-{target}/.\" \"$VORPAL_OUTPUT\""),
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest,
                 excludes: vec![],
-                hash: Some(hash.to_string()),
+                executable: false,
+                hash,
                 includes: vec![],
                 path: format!("https://static.rust-lang.org/dist/{name}-{version}-{target}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
             },
         )]),
         vec![
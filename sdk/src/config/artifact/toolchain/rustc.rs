@@ -1,42 +1,89 @@
 use crate::config::{
-    artifact::{add_artifact, language::rust::get_toolchain_target, ConfigContext},
+    artifact::{
+        add_artifact,
+        language::rust::{get_toolchain_digests, get_toolchain_target},
+        ConfigContext,
+    },
     ArtifactSource,
 };
 use anyhow::{bail, Result};
 use std::collections::BTreeMap;
 use vorpal_schema::vorpal::artifact::v0::{
     ArtifactId,
-    ArtifactSystem::{Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos},
+    ArtifactSystem::{
+        Aarch64Linux, Aarch64Macos, UnknownSystem, X8664Linux, X8664Macos, X8664Windows,
+    },
 };
 
+// Upstream sha256 of the archive for other versions than the default, by version and target
+const CONTENT_DIGESTS: [(&str, &str, &str); 4] = [
+    (
+        "1.79.0",
+        "x86_64-unknown-linux-gnu",
+        "36e59d225cc4c35f4d63c276c94a5e5cba5c8083275c3990ae7cae6842f9109f",
+    ),
+    (
+        "1.80.1",
+        "x86_64-unknown-linux-gnu",
+        "c0eb2bd3f16ef51b2efe8d4f1febd93ad723cd694f16e0827b732b3a3b4b8dd9",
+    ),
+    (
+        "1.81.0",
+        "x86_64-unknown-linux-gnu",
+        "d1e8db8c3ce0bd4b8a99e29bbd5132a3cf6a7e88ba4004bf7ce889fac7aa7e8d",
+    ),
+    (
+        "1.82.0",
+        "x86_64-unknown-linux-gnu",
+        "94d5ac7d978a2397fd112704985fc892fb0a813e384682c697c47071ab2d3807",
+    ),
+];
+
 pub async fn artifact(context: &mut ConfigContext, version: &str) -> Result<ArtifactId> {
     let hash = match context.get_target() {
         Aarch64Linux => "f5e5eac428b2a62ffc14324e3a6e171fb3032921f24973b27959834e456388b1",
         Aarch64Macos => "d022dd6d61a7039c12834f90a0a5410c884bfb9ef1e38b085ad4d3f59a5bf04a",
         X8664Linux => "fb18b7bb9dd94a5eeb445af1e4dd636836b6034f5dc731d534548bf5f9cb3d6f",
         X8664Macos => "1234567890",
-        UnknownSystem => bail!("Invalid protoc system: {:?}", context.get_target()),
+        UnknownSystem | X8664Windows => bail!("Invalid protoc system: {:?}", context.get_target()),
     };
 
     let name = "rustc";
 
     let target = get_toolchain_target(context.get_target())?;
 
+    let content_digest = CONTENT_DIGESTS
+        .iter()
+        .find(|(v, t, _)| *v == version && *t == target)
+        .map(|(_, _, digest)| *digest);
+
+    let (content_digest, hash) = get_toolchain_digests(
+        &format!("{name}-{version}-{target}"),
+        content_digest,
+        hash,
+        version,
+    )?;
+
     add_artifact(
         context,
         vec![],
         BTreeMap::new(),
-        name,
+        &format!("{name}-{version}"),
         format!(
             "cp -prv \"./source/{name}/{name}-{version}-{target}/{name}/.\" \"$VORPAL_OUTPUT\""
         ),
         BTreeMap::from([(
             name,
             ArtifactSource {
+                allow_outside_context: false,
+                content_digest,
                 excludes: vec![],
-                hash: Some(hash.to_string()),
+                executable: false,
+                hash,
                 includes: vec![],
                 path: format!("https://static.rust-lang.org/dist/{name}-{version}-{target}.tar.gz"),
+                rename: None,
+                strip_prefix: false,
             },
         )]),
         vec![
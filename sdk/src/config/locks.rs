@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, write},
+    path::Path,
+};
+use vorpal_schema::vorpal::config::v0::ConfigSourceLock;
+
+pub const SOURCE_LOCK_FILE: &str = "Vorpal.lock";
+
+const SOURCE_LOCK_HEADER: &str =
+    "# Generated by `vorpal artifact`, refresh with `--update-locks`.\n\n";
+
+/// Digests resolved for remote sources without a hash, keyed by artifact, source name and path.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SourceLock {
+    #[serde(default)]
+    sources: Vec<ConfigSourceLock>,
+}
+
+fn is_lock_key(lock: &ConfigSourceLock, artifact: &str, name: &str, path: &str) -> bool {
+    lock.artifact == artifact && lock.name == name && lock.path == path
+}
+
+impl SourceLock {
+    /// Loads the lockfile at `path`, empty when it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = read_to_string(path)?;
+
+        toml::from_str(&data).map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))
+    }
+
+    pub fn get(&self, artifact: &str, name: &str, path: &str) -> Option<&str> {
+        self.sources
+            .iter()
+            .find(|lock| is_lock_key(lock, artifact, name, path))
+            .map(|lock| lock.digest.as_str())
+    }
+
+    /// Adds `locks`, replacing entries with the same key, and returns whether anything changed.
+    pub fn update(&mut self, locks: Vec<ConfigSourceLock>) -> bool {
+        let mut changed = false;
+
+        for lock in locks {
+            if self.get(&lock.artifact, &lock.name, &lock.path) == Some(lock.digest.as_str()) {
+                continue;
+            }
+
+            self.sources
+                .retain(|l| !is_lock_key(l, &lock.artifact, &lock.name, &lock.path));
+            self.sources.push(lock);
+
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Writes entries sorted by key so the file diffs cleanly between runs.
+    pub fn save(&mut self, path: &Path) -> Result<()> {
+        self.sources
+            .sort_by(|a, b| (&a.artifact, &a.name, &a.path).cmp(&(&b.artifact, &b.name, &b.path)));
+
+        let data = toml::to_string(self).map_err(|e| anyhow!(e))?;
+
+        write(path, format!("{}{}", SOURCE_LOCK_HEADER, data))?;
+
+        Ok(())
+    }
+}
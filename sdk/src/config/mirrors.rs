@@ -0,0 +1,80 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, fs::read_to_string, path::PathBuf};
+
+#[derive(Debug, Default, Deserialize)]
+struct SourceMirrorsFile {
+    #[serde(default)]
+    mirrors: BTreeMap<String, Vec<String>>,
+}
+
+/// Rewrites of remote source URL prefixes to mirrors, tried in order before the original URL.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMirrors {
+    mirrors: BTreeMap<String, Vec<String>>,
+}
+
+fn get_mirrors_file_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".vorpal").join("mirrors.toml"))
+}
+
+impl SourceMirrors {
+    /// Loads mirrors from `~/.vorpal/mirrors.toml` and `prefix=replacement` flags, with flag
+    /// mirrors tried before file mirrors for the same prefix.
+    pub fn load(flags: &[String]) -> Result<Self> {
+        let mut mirrors = BTreeMap::<String, Vec<String>>::new();
+
+        for flag in flags {
+            let Some((prefix, replacement)) = flag.split_once('=') else {
+                bail!(
+                    "invalid source mirror (expected `prefix=replacement`): {}",
+                    flag
+                );
+            };
+
+            mirrors
+                .entry(prefix.to_string())
+                .or_default()
+                .push(replacement.to_string());
+        }
+
+        if let Some(path) = get_mirrors_file_path().filter(|path| path.exists()) {
+            let data = read_to_string(&path)?;
+
+            let file: SourceMirrorsFile = toml::from_str(&data)
+                .map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))?;
+
+            for (prefix, replacements) in file.mirrors {
+                mirrors.entry(prefix).or_default().extend(replacements);
+            }
+        }
+
+        for replacement in mirrors.values().flatten() {
+            if !replacement.starts_with("http://") && !replacement.starts_with("https://") {
+                bail!("source mirror scheme not supported: {}", replacement);
+            }
+        }
+
+        Ok(Self { mirrors })
+    }
+
+    /// Returns the URLs to try for `url`: mirrors of the longest matching prefix, then `url`.
+    pub fn get_urls(&self, url: &str) -> Vec<String> {
+        let mut urls = self
+            .mirrors
+            .iter()
+            .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, replacements)| {
+                replacements
+                    .iter()
+                    .map(|replacement| format!("{}{}", replacement, &url[prefix.len()..]))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        urls.push(url.to_string());
+
+        urls
+    }
+}
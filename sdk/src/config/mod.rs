@@ -1,39 +1,69 @@
-use crate::config::service::ConfigServer;
-use anyhow::{bail, Result};
+use crate::config::{
+    locks::SourceLock,
+    mirrors::SourceMirrors,
+    oci::{pull_image_source, OciImageSource, OCI_SOURCE_SCHEME},
+    service::{get_config_token, ConfigReady, ConfigServer},
+};
+use anyhow::{anyhow, bail, Result};
 use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder};
 use clap::{Parser, Subcommand};
 use console::style;
 use serde::{Deserialize, Serialize};
 use sha256::digest;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::consts::{ARCH, OS};
-use std::path::Path;
-use tokio::fs::{remove_dir_all, remove_file, write};
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs::{read_dir, remove_dir_all, remove_file, rename as fs_rename, set_permissions, write},
+    net::TcpListener,
+    process,
+};
 use tokio_tar::Archive;
-use tonic::{transport::Server, Code::NotFound};
-use tracing::{info, Level};
+use tonic::{
+    codec::CompressionEncoding,
+    transport::{server::TcpIncoming, Server},
+};
+use tracing::{info, warn, Level};
 use url::Url;
 use vorpal_schema::{
     get_artifact_system,
+    sources::SourceError,
+    validate_artifact_dependencies, validate_artifact_environments, validate_artifact_placeholders,
+    validate_artifact_references, validate_artifact_step_paths, validate_artifact_steps,
     vorpal::{
+        agent::v0::{agent_service_client::AgentServiceClient, AgentDownloadRequest},
         artifact::v0::{
-            Artifact, ArtifactBuildRequest, ArtifactId, ArtifactSourceId, ArtifactStep,
+            Artifact, ArtifactId, ArtifactSourceId, ArtifactStep, ArtifactStepEnvironment,
             ArtifactSystem,
         },
-        config::v0::{config_service_server::ConfigServiceServer, Config},
-        registry::v0::{
-            registry_service_client::RegistryServiceClient, RegistryKind, RegistryRequest,
+        config::v0::{
+            config_service_server::ConfigServiceServer, Config, ConfigArtifactSource,
+            ConfigSourceLock, ConfigSourceRevision,
         },
+        registry::v0::{RegistryKind, RegistryRequest},
     },
 };
 use vorpal_store::{
     archives::{compress_zstd, unpack_zip},
-    hashes::hash_files,
-    paths::{copy_files, get_cache_archive_path, get_file_paths, set_timestamps},
-    temps::{create_sandbox_dir, create_sandbox_file},
+    caches::{evict_source_cache, get_source_cache_size_max, touch_source_cache},
+    digests::{ArtifactDigest, SourceDigest},
+    grpc::{get_channel, get_max_message_size, RegistryUrls, AGENT_ENV},
+    hashes::{get_hashes_digest, hash_files},
+    http::get_http_client,
+    manifests::get_artifact_manifest,
+    paths::{
+        copy_files, get_cache_archive_path, get_file_paths, get_source_excludes, sanitize_file,
+        sanitize_symlinks,
+    },
+    temps::{create_sandbox_dir, create_sandbox_file, SandboxGuard},
 };
 
 pub mod artifact;
+pub mod locks;
+pub mod mirrors;
+pub mod oci;
 pub mod service;
 
 #[derive(Parser)]
@@ -55,17 +85,35 @@ fn get_prefix(name: &str) -> String {
 #[derive(Subcommand)]
 enum Command {
     Start {
+        #[clap(default_value = ".", long)]
+        context: String,
+
         #[clap(default_value_t = Level::INFO, global = true, long)]
         level: Level,
 
-        #[clap(long, short)]
+        #[clap(default_value_t = 0, long, short)]
         port: u16,
 
-        #[clap(default_value = "http://localhost:23151", long, short)]
-        registry: String,
+        #[clap(default_value = "http://localhost:23151", long = "registry", short)]
+        registries: Vec<String>,
+
+        #[clap(long)]
+        source_lock: Option<String>,
+
+        #[clap(long = "source-mirror")]
+        source_mirrors: Vec<String>,
+
+        #[clap(long)]
+        source_revision: Option<String>,
 
         #[arg(default_value_t = get_default_system(), long, short)]
         target: String,
+
+        #[clap(default_value_t = false, long)]
+        update_locks: bool,
+
+        #[clap(long = "variable")]
+        variables: Vec<String>,
     },
 }
 
@@ -73,9 +121,18 @@ enum Command {
 pub struct ConfigContext {
     pub artifact_id: HashMap<ArtifactId, Artifact>, // TOOD: make this private
     artifact_source_id: HashMap<String, ArtifactSourceId>,
+    artifact_source_local: Vec<ConfigArtifactSource>,
+    context_path: PathBuf,
     port: u16,
-    registry: String,
+    registries: Vec<String>,
+    source_cache_paths: HashSet<PathBuf>,
+    source_lock: Option<SourceLock>,
+    source_locks: Vec<ConfigSourceLock>,
+    source_mirrors: SourceMirrors,
+    source_revision: Option<String>,
+    source_revisions: Vec<ConfigSourceRevision>,
     system: ArtifactSystem,
+    variables: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -85,10 +142,89 @@ pub struct ArtifactMetadata {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ArtifactSource {
+    /// Allows a local path resolving outside the context directory (`../shared-protos`).
+    pub allow_outside_context: bool,
+    /// SHA-256 of the downloaded file of an http source (`sha256:<hex>`), as published upstream.
+    /// Verified before unpacking, unlike `hash` which covers the unpacked files.
+    pub content_digest: Option<String>,
     pub excludes: Vec<String>,
+    pub executable: bool,
     pub hash: Option<String>,
     pub includes: Vec<String>,
     pub path: String,
+    pub rename: Option<String>,
+    /// Removes the single top-level directory of an archive source (`zlib-1.3.1/` -> `./`).
+    /// Hashes cover the stripped layout, so digests differ from the same source unstripped.
+    pub strip_prefix: bool,
+}
+
+impl ArtifactSource {
+    /// Allows a local path outside the context directory, which fails by default.
+    pub fn with_allow_outside_context(mut self, allow: bool) -> Self {
+        self.allow_outside_context = allow;
+        self
+    }
+
+    /// Verifies the downloaded file of an http source against a published `sha256:<hex>` digest.
+    pub fn with_content_digest(mut self, digest: &str) -> Self {
+        self.content_digest = Some(digest.to_string());
+        self
+    }
+
+    /// Marks a single file source as executable (mode 0755).
+    pub fn with_executable(mut self, executable: bool) -> Self {
+        self.executable = executable;
+        self
+    }
+
+    /// Renames a single file source, instead of keeping the name from its path.
+    pub fn with_rename(mut self, name: &str) -> Self {
+        self.rename = Some(name.to_string());
+        self
+    }
+
+    /// Unpacks an archive source without its single top-level directory.
+    pub fn with_strip_prefix(mut self, strip_prefix: bool) -> Self {
+        self.strip_prefix = strip_prefix;
+        self
+    }
+
+    // Single file options mixed into the source hash, none keeps existing hashes unchanged
+    fn get_file_options(&self) -> Option<String> {
+        if self.rename.is_none() && !self.executable {
+            return None;
+        }
+
+        let mode = if self.executable { "0755" } else { "0644" };
+
+        Some(format!(
+            "rename:{};mode:{}",
+            self.rename.clone().unwrap_or_default(),
+            mode
+        ))
+    }
+}
+
+/// Output checks the worker runs after the final step, before archiving.
+#[derive(Clone, Debug, Default)]
+pub struct ArtifactChecks {
+    pub command: Option<String>,
+    pub paths: Vec<String>,
+}
+
+impl ArtifactChecks {
+    /// Runs `command` with bash in the output directory, a non-zero exit fails the build.
+    pub fn with_check_command(mut self, command: &str) -> Self {
+        self.command = Some(command.to_string());
+        self
+    }
+
+    /// Requires `paths`, relative to the output directory, to exist.
+    pub fn with_check_paths(mut self, paths: Vec<&str>) -> Self {
+        self.paths
+            .extend(paths.into_iter().map(|path| path.to_string()));
+        self
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -97,6 +233,7 @@ pub enum ArtifactSourceKind {
     Git,
     Http,
     Local,
+    Oci,
 }
 
 pub async fn get_context() -> Result<ConfigContext> {
@@ -104,9 +241,15 @@ pub async fn get_context() -> Result<ConfigContext> {
 
     match args.command {
         Command::Start {
+            context,
             port,
-            registry,
+            registries,
+            source_lock,
+            source_mirrors,
+            source_revision,
             target,
+            update_locks,
+            variables,
             ..
         } => {
             let target = get_artifact_system::<ArtifactSystem>(&target);
@@ -115,19 +258,301 @@ pub async fn get_context() -> Result<ConfigContext> {
                 return Err(anyhow::anyhow!("Invalid target system"));
             }
 
-            Ok(ConfigContext::new(port, registry, target))
+            let source_mirrors = SourceMirrors::load(&source_mirrors)?;
+
+            let mut variables_parsed = BTreeMap::new();
+
+            for variable in variables {
+                let Some((key, value)) = variable.split_once('=') else {
+                    bail!("variable must be NAME=VALUE: {}", variable);
+                };
+
+                variables_parsed.insert(key.to_string(), value.to_string());
+            }
+
+            // Updating resolves every unhashed remote source again, replacing its lock
+
+            let source_lock = match source_lock {
+                Some(_) if update_locks => Some(SourceLock::default()),
+                Some(path) => Some(SourceLock::load(Path::new(&path))?),
+                None => None,
+            };
+
+            let context_path = Path::new(&context)
+                .canonicalize()
+                .map_err(|e| anyhow!("failed to resolve context {}: {}", context, e))?;
+
+            Ok(ConfigContext::new(
+                context_path,
+                port,
+                registries,
+                source_lock,
+                source_mirrors,
+                source_revision,
+                target,
+                variables_parsed,
+            ))
         }
     }
 }
 
+/// Returns the lowercase hex of a `sha256:<hex>` content digest, `None` when malformed.
+fn get_content_digest(content_digest: &str) -> Option<String> {
+    let hex = content_digest.strip_prefix("sha256:")?;
+
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some(hex.to_ascii_lowercase())
+}
+
+fn is_source_remote(path: &str) -> bool {
+    path.contains("://") || path.starts_with("git@")
+}
+
+/// Resolves a local source path from `context_path` to an absolute, canonical path.
+///
+/// Paths outside the context must be allowed by the source, missing paths are returned joined to
+/// the context so the kind check reports them.
+fn get_source_local_path(
+    context_path: &Path,
+    source_name: &str,
+    source: &ArtifactSource,
+) -> Result<PathBuf> {
+    let path = context_path.join(&source.path);
+
+    let Ok(path) = path.canonicalize() else {
+        return Ok(path);
+    };
+
+    if !source.allow_outside_context && !path.starts_with(context_path) {
+        bail!(
+            "`source.{}.path` {} is outside the context {} (use `with_allow_outside_context`)",
+            source_name,
+            path.display(),
+            context_path.display()
+        );
+    }
+
+    Ok(path)
+}
+
+/// Returns whether `agent` runs on another machine, where its download cache is shared by
+/// everyone using it. Sources are downloaded directly otherwise.
+fn is_agent_shared(agent: &str) -> bool {
+    let Ok(agent) = Url::parse(agent) else {
+        return false;
+    };
+
+    !matches!(
+        agent.host_str(),
+        None | Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+    )
+}
+
+async fn get_agent_source_bytes(
+    agent: &str,
+    url: &str,
+    content_digest: Option<&str>,
+) -> Result<Vec<u8>> {
+    let mut client = AgentServiceClient::new(get_channel(agent).await?);
+
+    let mut response = client
+        .download(AgentDownloadRequest {
+            content_digest: content_digest.unwrap_or_default().to_string(),
+            url: url.to_string(),
+        })
+        .await?
+        .into_inner();
+
+    let mut bytes = vec![];
+
+    while let Some(message) = response.message().await? {
+        bytes.extend(message.data);
+    }
+
+    Ok(bytes)
+}
+
+async fn get_source_bytes(url: &str, content_digest: Option<&str>) -> Result<Vec<u8>> {
+    // Shared agents download each url from upstream once for everyone using them
+
+    if let Some(agent) = std::env::var(AGENT_ENV).ok().filter(|a| is_agent_shared(a)) {
+        match get_agent_source_bytes(&agent, url, content_digest).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(err) => warn!("failed to download through agent {}: {}", agent, err),
+        }
+    }
+
+    let response = get_http_client()?
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| anyhow!(e))?;
+
+    if !response.status().is_success() {
+        bail!("unexpected status: {}", response.status());
+    }
+
+    let bytes = response.bytes().await.map_err(|e| anyhow!(e))?;
+
+    Ok(bytes.to_vec())
+}
+
+/// Moves the contents of the single top-level directory of `path` into `path`.
+async fn strip_source_prefix(path: &Path) -> Result<()> {
+    let mut entries = read_dir(path).await?;
+    let mut prefix_paths = vec![];
+
+    while let Some(entry) = entries.next_entry().await? {
+        prefix_paths.push(entry.path());
+    }
+
+    let [prefix_path] = prefix_paths.as_slice() else {
+        bail!(
+            "requires exactly one top-level directory, found {} entries",
+            prefix_paths.len()
+        );
+    };
+
+    if prefix_path.is_symlink() || !prefix_path.is_dir() {
+        bail!(
+            "top-level entry is not a directory: {}",
+            prefix_path.display()
+        );
+    }
+
+    // Move into a new directory so entries named like the prefix cannot collide with it
+
+    let stripped_path = create_sandbox_dir().await?;
+
+    let mut entries = read_dir(prefix_path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        fs_rename(entry.path(), stripped_path.join(entry.file_name())).await?;
+    }
+
+    remove_dir_all(path).await?;
+
+    fs_rename(&stripped_path, path).await?;
+
+    Ok(())
+}
+
+async fn get_git_output(path: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to run git: {}", e))?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+/// Extracts `local_path` as it exists at git `revision` into `target_path`.
+///
+/// Returns `None` when `local_path` is not inside a git repository, otherwise the
+/// resolved commit sha and the extracted path matching `local_path`.
+async fn get_git_revision_path(
+    local_path: &Path,
+    revision: &str,
+    target_path: &Path,
+) -> Result<Option<(String, PathBuf)>> {
+    let local_path = local_path.canonicalize()?;
+
+    let local_dir = if local_path.is_dir() {
+        local_path.clone()
+    } else {
+        local_path
+            .parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| local_path.clone())
+    };
+
+    let Ok(root) = get_git_output(&local_dir, &["rev-parse", "--show-toplevel"]).await else {
+        return Ok(None);
+    };
+
+    let root = PathBuf::from(String::from_utf8_lossy(&root).trim()).canonicalize()?;
+
+    let commit = get_git_output(
+        &root,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", revision)],
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("unknown source revision `{}`: {}", revision, e))?;
+
+    let commit = String::from_utf8_lossy(&commit).trim().to_string();
+
+    let relative_path = local_path.strip_prefix(&root)?.to_path_buf();
+
+    let mut archive_args = vec!["archive", "--format=tar", commit.as_str()];
+
+    let relative_path_str = relative_path.display().to_string();
+
+    if !relative_path_str.is_empty() {
+        archive_args.push("--");
+        archive_args.push(relative_path_str.as_str());
+    }
+
+    let archive_data = get_git_output(&root, &archive_args).await.map_err(|e| {
+        anyhow::anyhow!(
+            "revision `{}` missing `{}`: {}",
+            revision,
+            relative_path_str,
+            e
+        )
+    })?;
+
+    let mut archive = Archive::new(archive_data.as_slice());
+
+    archive
+        .unpack(target_path)
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    Ok(Some((commit, target_path.join(relative_path))))
+}
+
 impl ConfigContext {
-    pub fn new(port: u16, registry: String, system: ArtifactSystem) -> Self {
+    /// Creates a context resolving local source paths from `context_path`, an absolute directory.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        context_path: PathBuf,
+        port: u16,
+        registries: Vec<String>,
+        source_lock: Option<SourceLock>,
+        source_mirrors: SourceMirrors,
+        source_revision: Option<String>,
+        system: ArtifactSystem,
+        variables: BTreeMap<String, String>,
+    ) -> Self {
         Self {
             artifact_id: HashMap::new(),
             artifact_source_id: HashMap::new(),
+            artifact_source_local: vec![],
+            context_path,
             port,
-            registry,
+            registries,
+            source_cache_paths: HashSet::new(),
+            source_lock,
+            source_locks: vec![],
+            source_mirrors,
+            source_revision,
+            source_revisions: vec![],
             system,
+            variables,
         }
     }
 
@@ -135,8 +560,32 @@ impl ConfigContext {
         &mut self,
         artifact_name: &str,
         source_name: &str,
-        source: ArtifactSource,
+        mut source: ArtifactSource,
     ) -> Result<ArtifactSourceId> {
+        // 0. Resolve local paths from the context, so sources match wherever the config runs
+
+        if !is_source_remote(&source.path) {
+            source.path = get_source_local_path(&self.context_path, source_name, &source)?
+                .display()
+                .to_string();
+        }
+
+        // 0a. Track local sources so clients can watch them for changes
+
+        if let Ok(local_path) = Path::new(&source.path).canonicalize() {
+            let local_source = ConfigArtifactSource {
+                artifact: artifact_name.to_string(),
+                excludes: source.excludes.clone(),
+                includes: source.includes.clone(),
+                name: source_name.to_string(),
+                path: local_path.display().to_string(),
+            };
+
+            if !self.artifact_source_local.contains(&local_source) {
+                self.artifact_source_local.push(local_source);
+            }
+        }
+
         // 1. If source is cached using '<artifact-name>-<source-name>-<digest>', return the source id
 
         let source_json = serde_json::to_string(&source).map_err(|e| anyhow::anyhow!(e))?;
@@ -146,6 +595,24 @@ impl ConfigContext {
             return Ok(source_id.clone());
         }
 
+        // 1a. Use the locked digest of remote sources without a hash
+
+        let mut source_locked = false;
+
+        let source_remote =
+            source.path.starts_with("http") || source.path.starts_with(OCI_SOURCE_SCHEME);
+
+        if source.hash.is_none() && source_remote {
+            if let Some(digest) = self
+                .source_lock
+                .as_ref()
+                .and_then(|lock| lock.get(artifact_name, source_name, &source.path))
+            {
+                source.hash = Some(digest.to_string());
+                source_locked = true;
+            }
+        }
+
         // 2. Check if source exists in registry or local cache
 
         if let Some(hash) = source.hash.clone() {
@@ -154,28 +621,25 @@ impl ConfigContext {
                 name: source_name.to_string(),
             };
 
-            // 2a. Check if source exists in the registry
-
-            let registry_host = self.registry.clone();
+            // 2a. Check if source exists in any of the registries
 
-            let mut registry = RegistryServiceClient::connect(registry_host.to_owned())
-                .await
-                .expect("failed to connect to registry");
+            let registries = RegistryUrls::new(self.registries.clone(), None)?
+                .connect()
+                .await?;
 
             let registry_request = RegistryRequest {
+                accept_dictionary: false,
                 hash: hash.clone(),
                 kind: RegistryKind::ArtifactSource as i32,
                 name: source_name.to_string(),
             };
 
-            match registry.exists(registry_request.clone()).await {
-                Err(status) => {
-                    if status.code() != NotFound {
-                        bail!("Registry pull error: {:?}", status);
-                    }
-                }
+            match registries.find(&registry_request).await {
+                Err(status) => bail!("Registry pull error: {:?}", status),
 
-                Ok(_) => {
+                Ok(None) => {}
+
+                Ok(Some(_)) => {
                     info!(
                         "{} pushed source: {}-{}",
                         get_prefix(artifact_name),
@@ -192,9 +656,13 @@ impl ConfigContext {
 
             // 2b. Check if source exists in local cache
 
-            let cache_archive_path = get_cache_archive_path(&hash, source_name);
+            // Placeholder hashes are not valid digests, the hash check below reports the actual one
+
+            let cache_archive_path = hash
+                .parse::<SourceDigest>()
+                .map(|digest| get_cache_archive_path(&digest, source_name));
 
-            if cache_archive_path.exists() {
+            if let Some(cache_archive_path) = cache_archive_path.ok().filter(|path| path.exists()) {
                 info!(
                     "{} cached source: {}-{}",
                     get_prefix(artifact_name),
@@ -202,6 +670,12 @@ impl ConfigContext {
                     hash
                 );
 
+                if let Err(err) = touch_source_cache(&cache_archive_path) {
+                    warn!("failed to touch source cache: {}", err);
+                }
+
+                self.source_cache_paths.insert(cache_archive_path);
+
                 self.artifact_source_id
                     .insert(source_key, artifact_source_id.clone());
 
@@ -215,6 +689,7 @@ impl ConfigContext {
             s if Path::new(s).exists() => ArtifactSourceKind::Local,
             s if s.starts_with("git") => ArtifactSourceKind::Git,
             s if s.starts_with("http") => ArtifactSourceKind::Http,
+            s if s.starts_with(OCI_SOURCE_SCHEME) => ArtifactSourceKind::Oci,
             _ => ArtifactSourceKind::UnknownSourceKind,
         };
 
@@ -230,25 +705,72 @@ impl ConfigContext {
             bail!("`source.{}.path` git not supported", source_name);
         }
 
-        let source_sandbox_path = create_sandbox_dir().await?;
+        if source.strip_prefix && source_path_kind != ArtifactSourceKind::Http {
+            bail!(
+                "`source.{}.strip_prefix` requires an archive source: {:?}",
+                source_name,
+                source.path
+            );
+        }
 
-        if source_path_kind == ArtifactSourceKind::Http {
-            if source.hash.is_none() {
-                bail!(
-                    "`source.{}.hash` required for remote sources: {:?}",
-                    source_name,
-                    source.path
-                );
-            }
+        let content_digest = match &source.content_digest {
+            Some(_) if source_path_kind != ArtifactSourceKind::Http => bail!(
+                "`source.{}.content_digest` requires an http source: {:?}",
+                source_name,
+                source.path
+            ),
 
-            if source.hash.is_some() && source.hash.clone().unwrap() == "" {
-                bail!(
-                    "`source.{}.hash` empty for remote sources: {:?}",
+            Some(content_digest) => Some(get_content_digest(content_digest).ok_or_else(|| {
+                anyhow!(
+                    "`source.{}.content_digest` invalid, expected `sha256:<hex>`: {:?}",
                     source_name,
-                    source.path
-                );
-            }
+                    content_digest
+                )
+            })?),
+
+            None => None,
+        };
+
+        // A content digest pins the downloaded file, the hash of its files is then optional
 
+        if source_remote
+            && source.hash.is_none()
+            && content_digest.is_none()
+            && self.source_lock.is_none()
+        {
+            bail!(
+                "`source.{}.hash` required for remote sources: {:?}",
+                source_name,
+                source.path
+            );
+        }
+
+        if source_remote && source.hash.as_ref().is_some_and(|hash| hash.is_empty()) {
+            bail!(
+                "`source.{}.hash` empty for remote sources: {:?}",
+                source_name,
+                source.path
+            );
+        }
+
+        let source_sandbox_path = create_sandbox_dir().await?;
+
+        if source_path_kind == ArtifactSourceKind::Oci {
+            let image = OciImageSource::parse(&source.path)
+                .map_err(|e| anyhow!("`source.{}.path` {}", source_name, e))?;
+
+            info!(
+                "{} pulling source: {}",
+                get_prefix(artifact_name),
+                source.path
+            );
+
+            pull_image_source(&image, self.system, &source_sandbox_path)
+                .await
+                .map_err(|e| anyhow!("`source.{}.path` {}", source_name, e))?;
+        }
+
+        if source_path_kind == ArtifactSourceKind::Http {
             let remote_path = Url::parse(&source.path).map_err(|e| anyhow::anyhow!(e))?;
 
             if remote_path.scheme() != "http" && remote_path.scheme() != "https" {
@@ -258,24 +780,82 @@ impl ConfigContext {
                 );
             }
 
-            info!(
-                "{} downloading source: {}",
-                get_prefix(artifact_name),
-                source.path
-            );
+            // Try mirrors in order, the source hash is verified against the original below
 
-            let remote_response = reqwest::get(remote_path.as_str())
-                .await
-                .map_err(|e| anyhow::anyhow!(e))?;
+            let mut remote_content_mismatch = None;
+            let mut remote_error = String::new();
+            let mut remote_response_bytes = None;
+
+            for remote_url in self.source_mirrors.get_urls(&source.path) {
+                if remote_url != source.path {
+                    info!(
+                        "{} source mirror: {} -> {}",
+                        get_prefix(artifact_name),
+                        source.path,
+                        remote_url
+                    );
+                }
+
+                info!(
+                    "{} downloading source: {}",
+                    get_prefix(artifact_name),
+                    remote_url
+                );
+
+                match get_source_bytes(&remote_url, content_digest.as_deref()).await {
+                    Ok(bytes) => {
+                        // Mirrors serving other bytes than the published digest are skipped
+
+                        if let Some(expected) = &content_digest {
+                            let actual = digest(&bytes[..]);
+
+                            if actual != *expected {
+                                warn!(
+                                    "{} source content mismatch: {} (sha256:{})",
+                                    get_prefix(artifact_name),
+                                    remote_url,
+                                    actual
+                                );
+
+                                remote_content_mismatch = Some(actual);
+
+                                continue;
+                            }
+                        }
 
-            if !remote_response.status().is_success() {
-                anyhow::bail!("source URL not failed: {:?}", remote_response.status());
+                        remote_response_bytes = Some(bytes);
+                        break;
+                    }
+
+                    Err(e) => {
+                        warn!(
+                            "{} failed to download source: {} ({})",
+                            get_prefix(artifact_name),
+                            remote_url,
+                            e
+                        );
+
+                        remote_error = format!("{}: {}", remote_url, e);
+                    }
+                }
             }
 
-            let remote_response_bytes = remote_response
-                .bytes()
-                .await
-                .map_err(|e| anyhow::anyhow!(e))?;
+            let Some(remote_response_bytes) = remote_response_bytes else {
+                if let (Some(actual), Some(expected)) = (remote_content_mismatch, content_digest) {
+                    return Err(SourceError::ContentMismatch {
+                        actual: format!("sha256:{}", actual),
+                        expected: format!("sha256:{}", expected),
+                        name: source_name.to_string(),
+                    }
+                    .into());
+                }
+
+                return Err(SourceError::Download {
+                    error: remote_error,
+                    name: source_name.to_string(),
+                }
+                .into());
+            };
 
             let remote_response_bytes = remote_response_bytes.as_ref();
 
@@ -309,10 +889,12 @@ impl ConfigContext {
                         let decoder = GzipDecoder::new(remote_response_bytes);
                         let mut archive = Archive::new(decoder);
 
-                        archive
-                            .unpack(&source_sandbox_path)
-                            .await
-                            .map_err(|e| anyhow::anyhow!(e))?;
+                        archive.unpack(&source_sandbox_path).await.map_err(|e| {
+                            SourceError::Unpack {
+                                error: e.to_string(),
+                                name: source_name.to_string(),
+                            }
+                        })?;
 
                         // let source_cache_path = source_cache_path.join("...");
                     }
@@ -321,20 +903,24 @@ impl ConfigContext {
                         let decoder = BzDecoder::new(remote_response_bytes);
                         let mut archive = Archive::new(decoder);
 
-                        archive
-                            .unpack(&source_sandbox_path)
-                            .await
-                            .map_err(|e| anyhow::anyhow!(e))?;
+                        archive.unpack(&source_sandbox_path).await.map_err(|e| {
+                            SourceError::Unpack {
+                                error: e.to_string(),
+                                name: source_name.to_string(),
+                            }
+                        })?;
                     }
 
                     "application/x-xz" => {
                         let decoder = XzDecoder::new(remote_response_bytes);
                         let mut archive = Archive::new(decoder);
 
-                        archive
-                            .unpack(&source_sandbox_path)
-                            .await
-                            .map_err(|e| anyhow::anyhow!(e))?;
+                        archive.unpack(&source_sandbox_path).await.map_err(|e| {
+                            SourceError::Unpack {
+                                error: e.to_string(),
+                                name: source_name.to_string(),
+                            }
+                        })?;
                     }
 
                     "application/zip" => {
@@ -344,34 +930,94 @@ impl ConfigContext {
                             .await
                             .map_err(|e| anyhow::anyhow!(e))?;
 
-                        unpack_zip(&archive_sandbox_path, &source_sandbox_path).await?;
+                        unpack_zip(&archive_sandbox_path, &source_sandbox_path)
+                            .await
+                            .map_err(|e| SourceError::Unpack {
+                                error: e.to_string(),
+                                name: source_name.to_string(),
+                            })?;
 
                         remove_file(&archive_sandbox_path)
                             .await
                             .map_err(|e| anyhow::anyhow!(e))?;
                     }
 
-                    _ => {
-                        bail!(
-                            "`source.{}.path` unsupported mime-type detected: {:?}",
-                            source_name,
-                            source.path
-                        );
+                    mime_type => {
+                        return Err(SourceError::UnsupportedType {
+                            kind: mime_type.to_string(),
+                            name: source_name.to_string(),
+                        }
+                        .into());
                     }
                 }
             }
+
+            if source.strip_prefix {
+                if kind.is_none() {
+                    bail!(
+                        "`source.{}.strip_prefix` requires an archive source: {:?}",
+                        source_name,
+                        source.path
+                    );
+                }
+
+                strip_source_prefix(&source_sandbox_path)
+                    .await
+                    .map_err(|e| anyhow!("`source.{}.strip_prefix` {}", source_name, e))?;
+            }
         }
 
         if source_path_kind == ArtifactSourceKind::Local {
-            let local_path = Path::new(&source.path).to_path_buf();
+            let mut local_path = Path::new(&source.path).to_path_buf();
 
             if !local_path.exists() {
                 bail!("`source.{}.path` not found: {:?}", source_name, source.path);
             }
 
+            // Materialize from the requested revision instead of the working tree
+
+            let mut local_revision_path = None;
+
+            if let Some(revision) = &self.source_revision {
+                let revision_path = SandboxGuard::new(create_sandbox_dir().await?);
+
+                if let Some((commit, path)) =
+                    get_git_revision_path(&local_path, revision, revision_path.path()).await?
+                {
+                    for include in source.includes.iter() {
+                        if !path.join(include).exists() {
+                            bail!(
+                                "`source.{}.includes` path `{}` not found in revision `{}` ({})",
+                                source_name,
+                                include,
+                                revision,
+                                commit
+                            );
+                        }
+                    }
+
+                    info!(
+                        "{} source revision: {} ({})",
+                        get_prefix(artifact_name),
+                        revision,
+                        commit
+                    );
+
+                    self.source_revisions.push(ConfigSourceRevision {
+                        artifact: artifact_name.to_string(),
+                        commit,
+                        name: source_name.to_string(),
+                        revision: revision.clone(),
+                    });
+
+                    local_path = path;
+                    local_revision_path = Some(revision_path);
+                }
+            }
+
             let local_source_files = get_file_paths(
                 &local_path,
-                source.excludes.clone(),
+                get_source_excludes(&local_path, &source.excludes)?,
                 source.includes.clone(),
             )?;
 
@@ -387,6 +1033,56 @@ impl ConfigContext {
                 &source_sandbox_path,
             )
             .await?;
+
+            drop(local_revision_path);
+        }
+
+        // 3a. Apply single file options
+
+        if source.get_file_options().is_some() {
+            let source_files = get_file_paths(
+                &source_sandbox_path,
+                source.excludes.clone(),
+                source.includes.clone(),
+            )?
+            .into_iter()
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+
+            let [source_file_path] = source_files.as_slice() else {
+                bail!(
+                    "`source.{}` rename and executable require a single file, found {}: {:?}",
+                    source_name,
+                    source_files.len(),
+                    source.path
+                );
+            };
+
+            let mut source_file_path = source_file_path.clone();
+
+            if let Some(rename) = &source.rename {
+                if rename.is_empty() || rename.contains('/') || rename == "." || rename == ".." {
+                    bail!(
+                        "`source.{}.rename` invalid file name: {:?}",
+                        source_name,
+                        rename
+                    );
+                }
+
+                let rename_path = source_sandbox_path.join(rename);
+
+                fs_rename(&source_file_path, &rename_path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e))?;
+
+                source_file_path = rename_path;
+            }
+
+            let mode = if source.executable { 0o755 } else { 0o644 };
+
+            set_permissions(&source_file_path, Permissions::from_mode(mode))
+                .await
+                .map_err(|e| anyhow::anyhow!(e))?;
         }
 
         // 4. Calculate source hash
@@ -398,17 +1094,22 @@ impl ConfigContext {
         )?;
 
         if source_sandbox_files.is_empty() {
-            bail!(
-                "Artifact `source.{}.path` no files found: {:?}",
-                source_name,
-                source.path
-            );
+            return Err(SourceError::EmptySource {
+                name: source_name.to_string(),
+            }
+            .into());
         }
 
-        // 4a. Set timestamps
+        // 4a. Sanitize symlinks
+
+        sanitize_symlinks(&source_sandbox_path, &source_sandbox_files)
+            .await
+            .map_err(|e| anyhow!("`source.{}.path` {}", source_name, e))?;
+
+        // 4b. Set timestamps
 
         for file_path in source_sandbox_files.clone().into_iter() {
-            set_timestamps(&file_path).await?;
+            sanitize_file(&file_path).await?;
         }
 
         info!(
@@ -417,19 +1118,44 @@ impl ConfigContext {
             source.path
         );
 
-        // 4b. Hash source files
+        // 4c. Hash source files
 
-        let source_hash = hash_files(source_sandbox_files.clone())?;
+        let mut source_hash = hash_files(source_sandbox_files.clone())?;
 
-        if let Some(hash) = source.hash.clone() {
-            if hash != source_hash {
-                bail!(
-                    "`source.{}.hash` mismatch: {} != {}",
+        if let Some(options) = source.get_file_options() {
+            source_hash = get_hashes_digest(vec![source_hash.to_string(), options])?.parse()?;
+        }
+
+        match source.hash.clone() {
+            Some(hash) if hash != source_hash.as_str() => {
+                return Err(SourceError::HashMismatch {
+                    actual: source_hash.to_string(),
+                    expected: hash,
+                    locked: source_locked,
+                    name: source_name.to_string(),
+                }
+                .into());
+            }
+
+            Some(_) => {}
+
+            None if source_remote => {
+                info!(
+                    "{} locked source: {}-{}",
+                    get_prefix(artifact_name),
                     source_name,
-                    source_hash,
-                    hash
+                    source_hash
                 );
+
+                self.source_locks.push(ConfigSourceLock {
+                    artifact: artifact_name.to_string(),
+                    digest: source_hash.to_string(),
+                    name: source_name.to_string(),
+                    path: source.path.clone(),
+                });
             }
+
+            None => {}
         }
 
         info!(
@@ -447,12 +1173,21 @@ impl ConfigContext {
         )
         .await?;
 
+        // Archives used by this evaluation are kept, even when they alone exceed the cap
+
+        self.source_cache_paths.insert(cache_archive_path);
+
+        if let Err(err) = evict_source_cache(get_source_cache_size_max(), &self.source_cache_paths)
+        {
+            warn!("failed to evict source cache: {}", err);
+        }
+
         remove_dir_all(&source_sandbox_path)
             .await
             .map_err(|e| anyhow::anyhow!(e))?;
 
         let id = ArtifactSourceId {
-            hash: source_hash,
+            hash: source_hash.to_string(),
             name: source_name.to_string(),
         };
 
@@ -468,13 +1203,138 @@ impl ConfigContext {
         source: BTreeMap<&str, ArtifactSource>,
         steps: Vec<ArtifactStep>,
         systems: Vec<&str>,
+    ) -> Result<ArtifactId> {
+        self.add_artifact_with_environment(name, artifacts, BTreeMap::new(), source, steps, systems)
+            .await
+    }
+
+    /// Adds an artifact with output `checks` the worker runs before archiving.
+    pub async fn add_artifact_with_checks(
+        &mut self,
+        name: &str,
+        artifacts: Vec<ArtifactId>,
+        checks: ArtifactChecks,
+        source: BTreeMap<&str, ArtifactSource>,
+        steps: Vec<ArtifactStep>,
+        systems: Vec<&str>,
+    ) -> Result<ArtifactId> {
+        self.add_artifact_manifest(
+            name,
+            artifacts,
+            checks,
+            BTreeMap::new(),
+            None,
+            vec![],
+            source,
+            steps,
+            systems,
+        )
+        .await
+    }
+
+    /// Adds an artifact that requires `min_disk_bytes` free in the worker store instead of
+    /// the worker default, failing before the build starts otherwise.
+    pub async fn add_artifact_with_min_disk_bytes(
+        &mut self,
+        name: &str,
+        artifacts: Vec<ArtifactId>,
+        min_disk_bytes: u64,
+        source: BTreeMap<&str, ArtifactSource>,
+        steps: Vec<ArtifactStep>,
+        systems: Vec<&str>,
+    ) -> Result<ArtifactId> {
+        self.add_artifact_manifest(
+            name,
+            artifacts,
+            ArtifactChecks::default(),
+            BTreeMap::new(),
+            Some(min_disk_bytes),
+            vec![],
+            source,
+            steps,
+            systems,
+        )
+        .await
+    }
+
+    /// Adds an artifact with `environment` injected into every step.
+    ///
+    /// Steps may only set an artifact-wide variable to a different value when they list it
+    /// with `ArtifactStepOptions::with_environment_override`.
+    pub async fn add_artifact_with_environment(
+        &mut self,
+        name: &str,
+        artifacts: Vec<ArtifactId>,
+        environment: BTreeMap<&str, String>,
+        source: BTreeMap<&str, ArtifactSource>,
+        steps: Vec<ArtifactStep>,
+        systems: Vec<&str>,
+    ) -> Result<ArtifactId> {
+        self.add_artifact_manifest(
+            name,
+            artifacts,
+            ArtifactChecks::default(),
+            environment,
+            None,
+            vec![],
+            source,
+            steps,
+            systems,
+        )
+        .await
+    }
+
+    /// Adds an artifact whose output paths matching `output_excludes` are removed after the final
+    /// step, like source `excludes` (`**/*.pyc`, `!keep.log`).
+    pub async fn add_artifact_with_output_excludes(
+        &mut self,
+        name: &str,
+        artifacts: Vec<ArtifactId>,
+        output_excludes: Vec<&str>,
+        source: BTreeMap<&str, ArtifactSource>,
+        steps: Vec<ArtifactStep>,
+        systems: Vec<&str>,
+    ) -> Result<ArtifactId> {
+        self.add_artifact_manifest(
+            name,
+            artifacts,
+            ArtifactChecks::default(),
+            BTreeMap::new(),
+            None,
+            output_excludes.into_iter().map(|e| e.to_string()).collect(),
+            source,
+            steps,
+            systems,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn add_artifact_manifest(
+        &mut self,
+        name: &str,
+        artifacts: Vec<ArtifactId>,
+        checks: ArtifactChecks,
+        environment: BTreeMap<&str, String>,
+        min_disk_bytes: Option<u64>,
+        output_excludes: Vec<String>,
+        source: BTreeMap<&str, ArtifactSource>,
+        steps: Vec<ArtifactStep>,
+        systems: Vec<&str>,
     ) -> Result<ArtifactId> {
         // 1. Setup sources
 
         let mut sources = vec![];
 
         for (source_name, source) in source.into_iter() {
-            let source = self.add_artifact_source(name, source_name, source).await?;
+            let source = self
+                .add_artifact_source(name, source_name, source)
+                .await
+                .inspect_err(|e| {
+                    if let Some(source_error) = e.downcast_ref::<SourceError>() {
+                        warn!("{} hint: {}", get_prefix(name), source_error.hint());
+                    }
+                })?;
 
             sources.push(source);
         }
@@ -497,24 +1357,47 @@ impl ConfigContext {
 
         let artifact = Artifact {
             artifacts,
+            check_command: checks.command,
+            check_paths: checks.paths,
+            environments: environment
+                .into_iter()
+                .map(|(key, value)| ArtifactStepEnvironment {
+                    key: key.to_string(),
+                    value,
+                })
+                .collect(),
+            min_disk_bytes,
             name: name.to_string(),
+            output_excludes,
             sources,
             steps,
             systems: systems_int,
         };
 
-        let artifact_manifest = ArtifactBuildRequest {
-            artifact: Some(artifact.clone()),
-            system: self.system.into(),
-        };
+        // 3a. Validate environments across steps
+
+        let warnings = validate_artifact_environments(&artifact).map_err(|e| anyhow!(e))?;
+
+        for warning in warnings {
+            warn!("{} {}", get_prefix(name), warning);
+        }
 
-        let artifact_manifest_json =
-            serde_json::to_string(&artifact_manifest).map_err(|e| anyhow::anyhow!(e))?;
+        // 3b. Validate steps can run for the target
 
-        let artifact_manifest_hash = digest(artifact_manifest_json.as_bytes());
+        validate_artifact_steps(&artifact, self.system).map_err(|e| anyhow!(e))?;
+
+        validate_artifact_step_paths(&artifact).map_err(|e| anyhow!(e))?;
+
+        // 3c. Validate artifact references in steps
+
+        validate_artifact_references(&artifact).map_err(|e| anyhow!(e))?;
+
+        validate_artifact_placeholders(&artifact).map_err(|e| anyhow!(e))?;
+
+        let (artifact_manifest_hash, _) = get_artifact_manifest(&artifact, self.system)?;
 
         let artifact_id = ArtifactId {
-            hash: artifact_manifest_hash,
+            hash: artifact_manifest_hash.to_string(),
             name: artifact.name.clone(),
         };
 
@@ -523,39 +1406,229 @@ impl ConfigContext {
                 .insert(artifact_id.clone(), artifact.clone());
         }
 
+        // 4. Validate dependencies do not lead back to the artifact
+
+        validate_artifact_dependencies(&artifact_id, &self.artifact_id).map_err(|e| anyhow!(e))?;
+
         Ok(artifact_id)
     }
 
-    pub fn get_artifact(&self, hash: &str, name: &str) -> Option<&Artifact> {
+    pub fn get_artifact(&self, digest: &ArtifactDigest, name: &str) -> Option<&Artifact> {
         let artifact_id = ArtifactId {
-            hash: hash.to_string(),
+            hash: digest.to_string(),
             name: name.to_string(),
         };
 
         self.artifact_id.get(&artifact_id)
     }
 
+    /// Directory local source paths are resolved from.
+    pub fn get_context_path(&self) -> &Path {
+        &self.context_path
+    }
+
     pub fn get_target(&self) -> ArtifactSystem {
         self.system
     }
 
+    /// Returns variable `name`, set with `vorpal artifact --variable NAME=VALUE` or the
+    /// `[variables]` table of `Vorpal.toml`.
+    pub fn get_variable(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(String::as_str)
+    }
+
+    /// Returns variable `name` scoped to `namespace`, set with `--variable NAMESPACE.NAME=VALUE`,
+    /// or the unscoped variable `name` when the namespace does not set it.
+    pub fn get_namespace_variable(&self, namespace: &str, name: &str) -> Option<&str> {
+        self.variables
+            .get(&format!("{}.{}", namespace, name))
+            .or_else(|| self.variables.get(name))
+            .map(String::as_str)
+    }
+
+    /// Serves the config on `--port` (`0` for a free port), printing a `ConfigReady` JSON line to
+    /// stdout once listening.
     pub async fn run(&self, artifacts: Vec<ArtifactId>) -> Result<()> {
-        let addr = format!("[::]:{}", self.port)
-            .parse()
-            .expect("failed to parse address");
+        let listener = TcpListener::bind(("127.0.0.1", self.port))
+            .await
+            .map_err(|e| anyhow!("failed to bind port {}: {}", self.port, e))?;
+
+        let port = listener.local_addr()?.port();
+
+        let token = get_config_token();
 
-        let config = Config { artifacts };
+        let config = Config {
+            artifacts,
+            locks: self.source_locks.clone(),
+            revisions: self.source_revisions.clone(),
+            sources: self.artifact_source_local.clone(),
+        };
 
         let context = self.clone();
 
-        let config_service = ConfigServiceServer::new(ConfigServer::new(context, config));
+        // Artifacts are sent whole, compressed so large manifests fit
+
+        let config_service =
+            ConfigServiceServer::new(ConfigServer::new(context, config, token.clone()))
+                .send_compressed(CompressionEncoding::Gzip)
+                .max_encoding_message_size(get_max_message_size());
 
-        println!("Config listening: {}", addr);
+        let incoming = TcpIncoming::from_listener(listener, true, None)
+            .map_err(|e| anyhow!("failed to listen: {}", e))?;
+
+        println!("{}", serde_json::to_string(&ConfigReady { port, token })?);
 
         Server::builder()
             .add_service(config_service)
-            .serve(addr)
+            .serve_with_incoming(incoming)
             .await
             .map_err(|e| anyhow::anyhow!("failed to serve: {}", e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process::Command};
+
+    #[test]
+    fn test_is_agent_shared() {
+        assert!(is_agent_shared("http://vorpal.office.lan:23151"));
+        assert!(is_agent_shared("https://10.0.0.5:23151"));
+
+        assert!(!is_agent_shared("http://localhost:23151"));
+        assert!(!is_agent_shared("http://127.0.0.1:23151"));
+        assert!(!is_agent_shared("http://[::1]:23151"));
+        assert!(!is_agent_shared("not a url"));
+    }
+
+    #[test]
+    fn test_get_namespace_variable() {
+        let context = ConfigContext::new(
+            PathBuf::from("."),
+            0,
+            vec![],
+            None,
+            SourceMirrors::default(),
+            None,
+            ArtifactSystem::X8664Linux,
+            BTreeMap::from([
+                ("branch".to_string(), "main".to_string()),
+                ("release.branch".to_string(), "release".to_string()),
+                ("tag".to_string(), "latest".to_string()),
+            ]),
+        );
+
+        assert_eq!(
+            context.get_namespace_variable("release", "branch"),
+            Some("release")
+        );
+
+        // Names the namespace does not set fall back to the unscoped variable
+
+        assert_eq!(
+            context.get_namespace_variable("release", "tag"),
+            Some("latest")
+        );
+        assert_eq!(
+            context.get_namespace_variable("other", "branch"),
+            Some("main")
+        );
+        assert_eq!(context.get_namespace_variable("release", "missing"), None);
+
+        assert_eq!(context.get_variable("release.branch"), Some("release"));
+    }
+
+    fn git(path: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-c", "user.email=test@vorpal", "-c", "user.name=test"])
+            .args(args)
+            .current_dir(path)
+            .output()
+            .expect("failed to run git");
+
+        assert!(output.status.success(), "git {:?} failed", args);
+
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn get_digest(path: &Path) -> String {
+        let files = get_file_paths(&path.to_path_buf(), vec![], vec![]).unwrap();
+
+        hash_files(files).unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_git_revision_path_uses_older_commit() {
+        let repo = tempfile::tempdir().unwrap();
+        let source = repo.path().join("source");
+
+        git(repo.path(), &["init", "--quiet"]);
+
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("main.rs"), "fn main() {}").unwrap();
+
+        git(repo.path(), &["add", "."]);
+        git(repo.path(), &["commit", "--quiet", "-m", "first"]);
+
+        fs::write(source.join("main.rs"), "fn main() { todo!() }").unwrap();
+        fs::write(source.join("lib.rs"), "").unwrap();
+
+        git(repo.path(), &["add", "."]);
+        git(repo.path(), &["commit", "--quiet", "-m", "second"]);
+
+        // Dirty the working tree, which the revision must ignore
+
+        fs::write(source.join("dirty.rs"), "").unwrap();
+
+        let expected = tempfile::tempdir().unwrap();
+
+        fs::write(expected.path().join("main.rs"), "fn main() {}").unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+
+        let (commit, path) = get_git_revision_path(&source, "HEAD~1", target.path())
+            .await
+            .unwrap()
+            .expect("source is inside a git repository");
+
+        assert_eq!(commit, git(repo.path(), &["rev-parse", "HEAD~1"]));
+        assert_eq!(path, target.path().join("source"));
+        assert_eq!(get_digest(&path), get_digest(expected.path()));
+        assert_ne!(get_digest(&path), get_digest(&source));
+    }
+
+    #[tokio::test]
+    async fn test_git_revision_path_unknown_revision() {
+        let repo = tempfile::tempdir().unwrap();
+
+        git(repo.path(), &["init", "--quiet"]);
+
+        fs::write(repo.path().join("main.rs"), "").unwrap();
+
+        git(repo.path(), &["add", "."]);
+        git(repo.path(), &["commit", "--quiet", "-m", "first"]);
+
+        let target = tempfile::tempdir().unwrap();
+
+        let error = get_git_revision_path(repo.path(), "v9.9.9", target.path())
+            .await
+            .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("unknown source revision `v9.9.9`"));
+    }
+
+    #[tokio::test]
+    async fn test_git_revision_path_outside_repository() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let revision_path = get_git_revision_path(source.path(), "HEAD", target.path())
+            .await
+            .unwrap();
+
+        assert!(revision_path.is_none());
+    }
+}
@@ -0,0 +1,738 @@
+use anyhow::{anyhow, bail, Result};
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use reqwest::{
+    header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE},
+    Client, RequestBuilder, Response, StatusCode,
+};
+use serde::Deserialize;
+use sha256::digest;
+use std::{
+    collections::HashSet,
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+use tokio::{
+    fs::{
+        create_dir_all, read_dir, remove_dir_all, remove_file, rename, set_permissions, symlink,
+        File,
+    },
+    io::{AsyncRead, BufReader},
+};
+use tokio_stream::StreamExt;
+use tokio_tar::Archive;
+use vorpal_schema::vorpal::artifact::v0::ArtifactSystem;
+use vorpal_store::{
+    archives::{check_entry_link, check_entry_parents, ArchiveWriter},
+    digests::ArchiveDigest,
+    http::get_http_client,
+    oci::{get_challenge_params, get_credentials, OciCredentials},
+    temps::{create_sandbox_dir, create_sandbox_file, SandboxGuard},
+};
+
+pub const OCI_SOURCE_SCHEME: &str = "oci://";
+
+// Manifests accepted when resolving references, indexes list a manifest per platform
+const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+// Layer entries hiding all lower layer contents of their directory, or a single lower path
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+#[derive(Deserialize)]
+struct OciPlatform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    digest: String,
+    media_type: String,
+    platform: Option<OciPlatform>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    #[serde(default)]
+    layers: Vec<OciDescriptor>,
+    #[serde(default)]
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciToken {
+    access_token: Option<String>,
+    token: Option<String>,
+}
+
+/// Image of an `oci://<registry>/<repository>[:<tag>|@<digest>][#<path>]` source.
+#[derive(Debug, PartialEq)]
+pub struct OciImageSource {
+    pub path: Option<PathBuf>,
+    pub reference: String,
+    pub registry: String,
+    pub repository: String,
+}
+
+impl OciImageSource {
+    pub fn parse(source: &str) -> Result<Self> {
+        let Some(image) = source.strip_prefix(OCI_SOURCE_SCHEME) else {
+            bail!(
+                "image source must start with {}: {}",
+                OCI_SOURCE_SCHEME,
+                source
+            );
+        };
+
+        let (image, path) = match image.split_once('#') {
+            Some((image, path)) => (image, Some(path.trim_start_matches('/'))),
+            None => (image, None),
+        };
+
+        let path = path.filter(|path| !path.is_empty()).map(PathBuf::from);
+
+        if let Some(path) = &path {
+            if path
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                bail!("image source path must be relative: {}", source);
+            }
+        }
+
+        let Some((registry, repository)) = image.split_once('/') else {
+            bail!("image source is missing registry: {}", source);
+        };
+
+        let (repository, reference) = match repository.split_once('@') {
+            Some((repository, digest)) => (repository, digest),
+            None => match repository.rsplit_once(':') {
+                Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+                _ => (repository, "latest"),
+            },
+        };
+
+        if registry.is_empty() || repository.is_empty() || reference.is_empty() {
+            bail!("invalid image source: {}", source);
+        }
+
+        Ok(Self {
+            path,
+            reference: reference.to_string(),
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+        })
+    }
+}
+
+/// Returns the platform architecture of images for `system`.
+fn get_platform_architecture(system: ArtifactSystem) -> Result<&'static str> {
+    match system {
+        ArtifactSystem::Aarch64Linux | ArtifactSystem::Aarch64Macos => Ok("arm64"),
+        ArtifactSystem::X8664Linux | ArtifactSystem::X8664Macos => Ok("amd64"),
+        _ => bail!("unsupported image system: {}", system.as_str_name()),
+    }
+}
+
+fn verify_digest(expected: &str, data: &[u8]) -> Result<()> {
+    let Some(expected_hash) = expected.strip_prefix("sha256:") else {
+        bail!("unsupported image digest: {}", expected);
+    };
+
+    let hash = digest(data);
+
+    if hash != expected_hash {
+        bail!("image digest mismatch: sha256:{} != {}", hash, expected);
+    }
+
+    Ok(())
+}
+
+/// Pulls images anonymously, or with credentials from the docker config.
+struct OciImageClient {
+    authorization: Option<String>,
+    client: Client,
+    credentials: Option<OciCredentials>,
+    repository: String,
+    url: String,
+}
+
+impl OciImageClient {
+    async fn new(source: &OciImageSource) -> Result<Self> {
+        // Docker Hub serves its API and keeps credentials on other hosts than image names use
+
+        let (host, credentials_host) = match source.registry.as_str() {
+            "docker.io" => ("registry-1.docker.io", "index.docker.io"),
+            host => (host, host),
+        };
+
+        let repository = match (source.registry.as_str(), source.repository.contains('/')) {
+            ("docker.io", false) => format!("library/{}", source.repository),
+            _ => source.repository.clone(),
+        };
+
+        Ok(Self {
+            authorization: None,
+            client: get_http_client()?,
+            credentials: get_credentials(credentials_host).await?,
+            repository,
+            url: format!("https://{}", host),
+        })
+    }
+
+    /// Answers an authentication challenge, exchanging credentials for a bearer token scoped
+    /// to pulling the repository when the registry asks for one.
+    async fn authenticate(&mut self, challenge: &str) -> Result<()> {
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let Some(credentials) = &self.credentials else {
+                bail!("image registry requires credentials: {}", self.url);
+            };
+
+            self.authorization = Some(credentials.get_basic_authorization());
+
+            return Ok(());
+        }
+
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            bail!("unsupported image registry authentication: {}", scheme);
+        }
+
+        let params = get_challenge_params(params);
+
+        let Some(realm) = params.get("realm") else {
+            bail!("image registry challenge is missing realm");
+        };
+
+        let mut query = vec![("scope", format!("repository:{}:pull", self.repository))];
+
+        if let Some(service) = params.get("service") {
+            query.push(("service", service.clone()));
+        }
+
+        let mut request = self.client.get(realm).query(&query);
+
+        if let Some(credentials) = &self.credentials {
+            request = request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            bail!("failed to get image registry token: {}", response.status());
+        }
+
+        let token: OciToken = response.json().await?;
+
+        let Some(token) = token.token.or(token.access_token) else {
+            bail!("image registry token is missing");
+        };
+
+        self.authorization = Some(format!("Bearer {}", token));
+
+        Ok(())
+    }
+
+    /// Sends `request`, authenticating and retrying once when the registry responds with a
+    /// challenge.
+    async fn send(&mut self, request: RequestBuilder) -> Result<Response> {
+        let retry = request.try_clone();
+
+        let authorize =
+            |request: RequestBuilder, authorization: &Option<String>| match authorization {
+                Some(authorization) => request.header(AUTHORIZATION, authorization),
+                None => request,
+            };
+
+        let response = authorize(request, &self.authorization).send().await?;
+
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let Some(retry) = retry else {
+            return Ok(response);
+        };
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        self.authenticate(&challenge).await?;
+
+        Ok(authorize(retry, &self.authorization).send().await?)
+    }
+
+    async fn get_manifest(&mut self, reference: &str) -> Result<Vec<u8>> {
+        let request = self
+            .client
+            .get(format!(
+                "{}/v2/{}/manifests/{}",
+                self.url, self.repository, reference
+            ))
+            .header(ACCEPT, MANIFEST_MEDIA_TYPES.join(", "));
+
+        let response = self.send(request).await?;
+
+        if !response.status().is_success() {
+            bail!(
+                "failed to get image manifest {}: {}",
+                reference,
+                response.status()
+            );
+        }
+
+        let data = response.bytes().await?.to_vec();
+
+        if reference.contains(':') {
+            verify_digest(reference, &data)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Streams blob `digest` to `path`, verifying it against its digest as it is written.
+    async fn get_blob(&mut self, digest: &str, path: &Path) -> Result<()> {
+        let Some(hash) = digest.strip_prefix("sha256:") else {
+            bail!("unsupported image digest: {}", digest);
+        };
+
+        let hash = hash.parse::<ArchiveDigest>()?;
+
+        let request = self.client.get(format!(
+            "{}/v2/{}/blobs/{}",
+            self.url, self.repository, digest
+        ));
+
+        let mut response = self.send(request).await?;
+
+        if !response.status().is_success() {
+            bail!("failed to get image blob {}: {}", digest, response.status());
+        }
+
+        let mut writer = ArchiveWriter::create(path).await?;
+
+        while let Some(chunk) = response.chunk().await? {
+            writer.write(&chunk).await?;
+        }
+
+        writer.finish(Some(&hash), digest).await?;
+
+        Ok(())
+    }
+
+    /// Returns the layers of the image, resolving indexes to the manifest of `architecture`.
+    async fn get_layers(
+        &mut self,
+        reference: &str,
+        architecture: &str,
+    ) -> Result<Vec<OciDescriptor>> {
+        let manifest: OciManifest = serde_json::from_slice(&self.get_manifest(reference).await?)?;
+
+        if manifest.manifests.is_empty() {
+            return Ok(manifest.layers);
+        }
+
+        let Some(platform_manifest) = manifest.manifests.iter().find(|descriptor| {
+            descriptor.platform.as_ref().is_some_and(|platform| {
+                platform.os == "linux" && platform.architecture == architecture
+            })
+        }) else {
+            bail!(
+                "image has no linux/{} manifest: {}",
+                architecture,
+                reference
+            );
+        };
+
+        let manifest: OciManifest =
+            serde_json::from_slice(&self.get_manifest(&platform_manifest.digest).await?)?;
+
+        Ok(manifest.layers)
+    }
+}
+
+/// Removes `path` whatever its kind, ignoring missing paths.
+async fn remove_path(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => remove_dir_all(path).await?,
+        Ok(_) => remove_file(path).await?,
+        Err(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Returns `target` of a symlink at `path` relative to its directory, as absolute targets
+/// refer to the root of the image.
+fn get_symlink_target(path: &Path, target: &Path) -> PathBuf {
+    if !target.is_absolute() {
+        return target.to_path_buf();
+    }
+
+    let depth = path.components().count().saturating_sub(1);
+
+    let mut relative = PathBuf::new();
+
+    for _ in 0..depth {
+        relative.push("..");
+    }
+
+    relative.join(target.strip_prefix("/").unwrap_or(target))
+}
+
+/// Applies layer `layer` read from `reader` to `root_path`, handling whiteouts of lower layer
+/// paths.
+///
+/// Layers are untrusted: entries written or removed through a symlinked parent, and symlinks
+/// leaving the root, fail before anything is touched.
+async fn apply_layer<R: AsyncRead + Unpin + Send>(
+    layer: &str,
+    reader: R,
+    root_path: &Path,
+) -> Result<()> {
+    let layer_path = Path::new(layer);
+
+    let mut archive = Archive::new(reader);
+    let mut entries = archive.entries()?;
+
+    // Paths of this layer, which opaque whiteouts keep
+
+    let mut layer_paths = HashSet::new();
+
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+
+        let path: PathBuf = entry
+            .path()?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        check_entry_parents(layer_path, &path, root_path).await?;
+
+        let parent_path = root_path.join(path.parent().unwrap_or(Path::new("")));
+
+        if file_name == WHITEOUT_OPAQUE {
+            if let Ok(mut children) = read_dir(&parent_path).await {
+                while let Some(child) = children.next_entry().await? {
+                    let child_path = child.path();
+
+                    if !layer_paths.contains(&child_path) {
+                        remove_path(&child_path).await?;
+                    }
+                }
+            }
+
+            continue;
+        }
+
+        if let Some(name) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+            remove_path(&parent_path.join(name)).await?;
+
+            continue;
+        }
+
+        let entry_type = entry.header().entry_type();
+
+        let link_target = match entry_type.is_symlink() {
+            true => {
+                let Some(target) = entry.link_name()? else {
+                    bail!("image symlink is missing target: {}", path.display());
+                };
+
+                let target = get_symlink_target(&path, &target);
+
+                check_entry_link(layer_path, &path, &target)?;
+
+                Some(target)
+            }
+            false => None,
+        };
+
+        // Replace lower layer paths, except directories which are merged
+
+        let entry_path = root_path.join(&path);
+
+        if let Ok(metadata) = entry_path.symlink_metadata() {
+            if !metadata.is_dir() || !entry_type.is_dir() {
+                remove_path(&entry_path).await?;
+            }
+        }
+
+        if let Some(target) = link_target {
+            create_dir_all(&parent_path).await?;
+
+            symlink(target, &entry_path).await?;
+        } else if !entry.unpack_in(root_path).await? {
+            continue;
+        }
+
+        // Later layers must be able to write into read-only directories
+
+        if entry_type.is_dir() {
+            let mode = entry_path.symlink_metadata()?.permissions().mode();
+
+            set_permissions(&entry_path, Permissions::from_mode(mode | 0o700)).await?;
+        }
+
+        for ancestor in entry_path.ancestors() {
+            if ancestor == root_path || !layer_paths.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Pulls the image of `source` for `system` and flattens its layers into `target_path`,
+/// keeping only the contents of the source path when set.
+pub async fn pull_image_source(
+    source: &OciImageSource,
+    system: ArtifactSystem,
+    target_path: &Path,
+) -> Result<()> {
+    let architecture = get_platform_architecture(system)?;
+
+    let mut client = OciImageClient::new(source).await?;
+
+    let layers = client.get_layers(&source.reference, architecture).await?;
+
+    let root_path = match source.path {
+        Some(_) => create_sandbox_dir().await?,
+        None => target_path.to_path_buf(),
+    };
+
+    for layer in layers {
+        let media_type = layer.media_type.as_str();
+
+        if !["gzip", "zstd", "tar"]
+            .iter()
+            .any(|suffix| media_type.ends_with(suffix))
+        {
+            bail!("unsupported image layer media type: {}", media_type);
+        }
+
+        // Layers are spooled to disk, they can be far larger than memory
+
+        let layer_file = SandboxGuard::new(create_sandbox_file(Some("tar")).await?);
+
+        client.get_blob(&layer.digest, layer_file.path()).await?;
+
+        let reader = BufReader::new(File::open(layer_file.path()).await?);
+
+        if media_type.ends_with("gzip") {
+            apply_layer(&layer.digest, GzipDecoder::new(reader), &root_path).await?;
+        } else if media_type.ends_with("zstd") {
+            apply_layer(&layer.digest, ZstdDecoder::new(reader), &root_path).await?;
+        } else {
+            apply_layer(&layer.digest, reader, &root_path).await?;
+        }
+    }
+
+    let Some(path) = &source.path else {
+        return Ok(());
+    };
+
+    // Symlinks are relative after flattening, so the path resolves inside the image
+
+    let source_path = root_path
+        .join(path)
+        .canonicalize()
+        .map_err(|_| anyhow!("image path not found: {}", path.display()))?;
+
+    if !source_path.starts_with(root_path.canonicalize()?) {
+        bail!(
+            "image path resolves outside of the image: {}",
+            path.display()
+        );
+    }
+
+    if source_path.is_dir() {
+        let mut children = read_dir(&source_path).await?;
+
+        while let Some(child) = children.next_entry().await? {
+            rename(child.path(), target_path.join(child.file_name())).await?;
+        }
+    } else {
+        let Some(file_name) = source_path.file_name() else {
+            bail!("image path not found: {}", path.display());
+        };
+
+        rename(&source_path, target_path.join(file_name)).await?;
+    }
+
+    remove_dir_all(&root_path).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tokio_tar::{Builder, EntryType, Header};
+
+    // Digest naming the layer in errors
+    const TEST_LAYER: &str = "sha256:test";
+
+    // Header names are written directly, `Header::set_path` refuses the paths under test
+    async fn get_layer(entries: &[(EntryType, &str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+
+        for (entry_type, path, link) in entries {
+            let data: &[u8] = match entry_type.is_file() {
+                true => b"data",
+                false => b"",
+            };
+
+            let mut header = Header::new_gnu();
+
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o755);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+
+            builder.append(&header, data).await.unwrap();
+        }
+
+        builder.into_inner().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_layer_whiteouts() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let root_path = dir.path().join("root");
+
+        fs::create_dir_all(&root_path).unwrap();
+
+        let lower = get_layer(&[
+            (EntryType::Directory, "a", ""),
+            (EntryType::Regular, "a/lower", ""),
+            (EntryType::Regular, "b", ""),
+            (EntryType::Regular, "c", ""),
+        ])
+        .await;
+
+        let upper = get_layer(&[
+            (EntryType::Regular, "a/upper", ""),
+            (EntryType::Regular, "a/.wh..wh..opq", ""),
+            (EntryType::Regular, ".wh.b", ""),
+            (EntryType::Symlink, "c", "/a/upper"),
+        ])
+        .await;
+
+        apply_layer(TEST_LAYER, lower.as_slice(), &root_path)
+            .await
+            .unwrap();
+        apply_layer(TEST_LAYER, upper.as_slice(), &root_path)
+            .await
+            .unwrap();
+
+        assert!(!root_path.join("a/lower").exists());
+        assert!(!root_path.join("b").exists());
+        assert_eq!(fs::read(root_path.join("a/upper")).unwrap(), b"data");
+        assert_eq!(
+            fs::read_link(root_path.join("c")).unwrap(),
+            Path::new("a/upper")
+        );
+        assert_eq!(fs::read(root_path.join("c")).unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn test_apply_layer_rejects_crafted_entries() {
+        // Cases pointing `lib` outside of the root first, entries of the layer and the error
+
+        let cases = [
+            (
+                true,
+                vec![(EntryType::Regular, "lib/.wh..wh..opq", "")],
+                "written through symlink lib",
+            ),
+            (
+                true,
+                vec![(EntryType::Regular, "lib/.wh.victim", "")],
+                "written through symlink lib",
+            ),
+            (
+                true,
+                vec![(EntryType::Regular, "lib/evil", "")],
+                "written through symlink lib",
+            ),
+            (
+                true,
+                vec![(EntryType::Directory, "lib/evil", "")],
+                "written through symlink lib",
+            ),
+            (
+                true,
+                vec![(EntryType::Symlink, "lib/evil", "victim")],
+                "written through symlink lib",
+            ),
+            (
+                false,
+                vec![
+                    (EntryType::Symlink, "lib", "../../.."),
+                    (EntryType::Regular, "lib/.wh..wh..opq", ""),
+                ],
+                "symlink target ../../.. is outside of the archive",
+            ),
+            (
+                false,
+                vec![(EntryType::Symlink, "a/evil", "../../evil")],
+                "symlink target ../../evil is outside of the archive",
+            ),
+            (
+                false,
+                vec![(EntryType::Symlink, "evil", "/../evil")],
+                "symlink target ../evil is outside of the archive",
+            ),
+        ];
+
+        for (index, (outside_link, entries, expected)) in cases.into_iter().enumerate() {
+            let dir = tempfile::tempdir().unwrap();
+
+            let outside_path = dir.path().join("outside");
+            let root_path = dir.path().join("root");
+
+            fs::create_dir_all(&outside_path).unwrap();
+            fs::create_dir_all(&root_path).unwrap();
+            fs::write(outside_path.join("victim"), b"victim").unwrap();
+
+            if outside_link {
+                std::os::unix::fs::symlink(&outside_path, root_path.join("lib")).unwrap();
+            }
+
+            let layer = get_layer(&entries).await;
+
+            let err = apply_layer(TEST_LAYER, layer.as_slice(), &root_path)
+                .await
+                .expect_err(expected);
+
+            assert!(err.to_string().contains(expected), "{}: {}", index, err);
+
+            assert!(outside_path.join("victim").exists(), "{}", index);
+            assert!(!outside_path.join("evil").exists(), "{}", index);
+            assert!(!dir.path().join("evil").exists(), "{}", index);
+        }
+    }
+}
@@ -1,19 +1,106 @@
 use crate::config::ConfigContext;
-use anyhow::Result;
-use vorpal_schema::vorpal::{
-    artifact::v0::{Artifact, ArtifactId},
-    config::v0::{config_service_server::ConfigService, Config, ConfigRequest},
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::Channel,
+    Request, Status,
 };
+use vorpal_schema::{
+    get_process_template_script, get_shell_template_script,
+    vorpal::{
+        artifact::v0::{
+            artifact_template_request::Template, Artifact, ArtifactId, ArtifactTemplateRequest,
+            ArtifactTemplateResponse,
+        },
+        config::v0::{
+            config_service_server::ConfigService, Config, ConfigRequest, ConfigVersionRequest,
+            ConfigVersionResponse,
+        },
+    },
+    CONFIG_PROTOCOL_VERSION,
+};
+use vorpal_store::{digests::ArtifactDigest, paths::get_process_path};
+
+// Metadata carrying the session token of the config process on every request
+pub const CONFIG_TOKEN_HEADER: &str = "x-vorpal-config-token";
+
+pub type ConfigChannel = InterceptedService<Channel, ConfigTokenInterceptor>;
+
+/// Line printed to stdout by the config process once it is listening.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigReady {
+    pub port: u16,
+    pub token: String,
+}
+
+/// Returns a random session token for the config process.
+pub fn get_config_token() -> String {
+    let mut token = String::with_capacity(32);
+
+    for byte in rand::random::<[u8; 16]>() {
+        token.push_str(&format!("{:02x}", byte));
+    }
+
+    token
+}
+
+/// Adds the session token of the config process to requests.
+#[derive(Clone, Debug)]
+pub struct ConfigTokenInterceptor {
+    token: MetadataValue<Ascii>,
+}
+
+impl ConfigTokenInterceptor {
+    pub fn new(token: &str) -> Result<Self> {
+        let token = token
+            .parse()
+            .map_err(|_| anyhow!("config token contains invalid characters"))?;
+
+        Ok(Self { token })
+    }
+}
+
+impl Interceptor for ConfigTokenInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request
+            .metadata_mut()
+            .insert(CONFIG_TOKEN_HEADER, self.token.clone());
+
+        Ok(request)
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct ConfigServer {
     pub context: ConfigContext,
     pub config: Config,
+    token: String,
 }
 
 impl ConfigServer {
-    pub fn new(context: ConfigContext, config: Config) -> Self {
-        Self { context, config }
+    pub fn new(context: ConfigContext, config: Config, token: String) -> Self {
+        Self {
+            context,
+            config,
+            token,
+        }
+    }
+
+    /// Rejects requests without the session token, such as clients of a previous run.
+    fn check_token<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let token = request
+            .metadata()
+            .get(CONFIG_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        if token != self.token {
+            return Err(Status::unauthenticated("invalid config token"));
+        }
+
+        Ok(())
     }
 }
 
@@ -21,8 +108,10 @@ impl ConfigServer {
 impl ConfigService for ConfigServer {
     async fn get_config(
         &self,
-        _request: tonic::Request<ConfigRequest>,
+        request: tonic::Request<ConfigRequest>,
     ) -> Result<tonic::Response<Config>, tonic::Status> {
+        self.check_token(&request)?;
+
         Ok(tonic::Response::new(self.config.clone()))
     }
 
@@ -30,11 +119,16 @@ impl ConfigService for ConfigServer {
         &self,
         request: tonic::Request<ArtifactId>,
     ) -> Result<tonic::Response<Artifact>, tonic::Status> {
+        self.check_token(&request)?;
+
         let request = request.into_inner();
 
-        let artifact = self
-            .context
-            .get_artifact(request.hash.as_str(), request.name.as_str());
+        let digest = request
+            .hash
+            .parse::<ArtifactDigest>()
+            .map_err(|err| tonic::Status::invalid_argument(err.to_string()))?;
+
+        let artifact = self.context.get_artifact(&digest, request.name.as_str());
 
         if artifact.is_none() {
             return Err(tonic::Status::not_found("Artifact input not found"));
@@ -42,4 +136,62 @@ impl ConfigService for ConfigServer {
 
         Ok(tonic::Response::new(artifact.unwrap().clone()))
     }
+
+    async fn get_version(
+        &self,
+        request: tonic::Request<ConfigVersionRequest>,
+    ) -> Result<tonic::Response<ConfigVersionResponse>, tonic::Status> {
+        self.check_token(&request)?;
+
+        Ok(tonic::Response::new(ConfigVersionResponse {
+            protocol_version: CONFIG_PROTOCOL_VERSION,
+        }))
+    }
+
+    async fn get_template(
+        &self,
+        request: tonic::Request<ArtifactTemplateRequest>,
+    ) -> Result<tonic::Response<ArtifactTemplateResponse>, tonic::Status> {
+        self.check_token(&request)?;
+
+        let script = match request.into_inner().template {
+            Some(Template::Process(process)) => {
+                if process.name.is_empty() {
+                    return Err(tonic::Status::invalid_argument("missing `name` field"));
+                }
+
+                if process.entrypoint.is_empty() {
+                    return Err(tonic::Status::invalid_argument(
+                        "missing `entrypoint` field",
+                    ));
+                }
+
+                get_process_template_script(
+                    &process.name,
+                    &process.entrypoint,
+                    &process.arguments,
+                    &get_process_path(&process.name).display().to_string(),
+                )
+            }
+
+            Some(Template::Shell(shell)) => {
+                if shell.name.is_empty() {
+                    return Err(tonic::Status::invalid_argument("missing `name` field"));
+                }
+
+                if let Some(env) = shell.environments.iter().find(|env| !env.contains('=')) {
+                    return Err(tonic::Status::invalid_argument(format!(
+                        "invalid environment (expected KEY=VALUE): {}",
+                        env
+                    )));
+                }
+
+                get_shell_template_script(&shell.name, &shell.environments)
+            }
+
+            None => return Err(tonic::Status::invalid_argument("missing `template` field")),
+        };
+
+        Ok(tonic::Response::new(ArtifactTemplateResponse { script }))
+    }
 }
@@ -1,39 +1,132 @@
-use crate::temps::create_sandbox_file;
-use anyhow::{Error, Result};
-use async_compression::tokio::{
-    bufread::{GzipDecoder, ZstdDecoder},
-    write::GzipEncoder,
-    write::ZstdEncoder,
+use crate::{
+    dictionaries::get_archive_dictionary_id,
+    digests::{verify_pulled_digest, ArchiveDigest},
+    paths::copy_file,
+    temps::create_sandbox_file,
+};
+use anyhow::{anyhow, bail, Error, Result};
+use async_compression::{
+    tokio::{
+        bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder},
+        write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder},
+    },
+    Level,
 };
 use async_zip::tokio::read::seek::ZipFileReader;
-use std::path::{Path, PathBuf};
-use tokio::io::AsyncWriteExt;
+use futures_lite::StreamExt;
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::{
-    fs::{copy, create_dir_all, remove_file, File, OpenOptions},
-    io::BufReader,
+    fs::{create_dir_all, remove_file, symlink_metadata, File, OpenOptions},
+    io::{BufReader, BufWriter},
 };
-use tokio_tar::{Archive, ArchiveBuilder, Builder};
+use tokio_tar::{Archive, Builder, HeaderMode};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 use tokio_util::compat::TokioAsyncWriteCompatExt;
 
-pub async fn compress_zstd(
-    source_path: &PathBuf,
-    source_files: &[PathBuf],
-    output_path: &PathBuf,
-) -> Result<File, Error> {
-    let temp_file = create_sandbox_file(Some("tar.zst"))
-        .await
-        .expect("Failed to create temp file");
+// Default zstd compression level for archives
+pub const ARCHIVE_ZSTD_LEVEL_DEFAULT: i32 = 3;
 
-    let file = File::create(temp_file.clone())
-        .await
-        .expect("Failed to create file");
+// Leading bytes kept from a pulled archive, enough for the largest zstd frame header
+const ARCHIVE_HEADER_SIZE: usize = 18;
+
+// Buffer between pulled chunks and the archive file
+const ARCHIVE_WRITE_BUFFER_SIZE: usize = 1024 * 1024; // 1MB
+
+/// Compression format (and zstd level) of archives pushed to the registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveCompression {
+    Bzip2,
+    Gzip,
+    Xz,
+    Zstd(i32),
+}
+
+impl Default for ArchiveCompression {
+    fn default() -> Self {
+        Self::Zstd(ARCHIVE_ZSTD_LEVEL_DEFAULT)
+    }
+}
+
+impl std::fmt::Display for ArchiveCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bzip2 => write!(f, "bzip2"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Xz => write!(f, "xz"),
+            Self::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+impl FromStr for ArchiveCompression {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None => match value {
+                "bzip2" => Ok(Self::Bzip2),
+                "gzip" => Ok(Self::Gzip),
+                "xz" => Ok(Self::Xz),
+                "zstd" => Ok(Self::default()),
+                _ => bail!("unsupported archive compression: {}", value),
+            },
+            Some(("zstd", level)) => match level.parse::<i32>() {
+                Ok(level) if (1..=22).contains(&level) => Ok(Self::Zstd(level)),
+                _ => bail!("invalid zstd level (1-22): {}", level),
+            },
+            Some(_) => bail!("unsupported archive compression: {}", value),
+        }
+    }
+}
+
+impl ArchiveCompression {
+    /// Detects the format of compressed archive `data` from its magic bytes.
+    ///
+    /// The zstd level is not recorded in the data, so zstd archives report the default level.
+    pub fn from_data(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            return Some(Self::default());
+        }
+
+        if data.starts_with(&[0x1f, 0x8b]) {
+            return Some(Self::Gzip);
+        }
+
+        if data.starts_with(b"BZh") {
+            return Some(Self::Bzip2);
+        }
+
+        if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            return Some(Self::Xz);
+        }
+
+        None
+    }
 
-    let encoder = ZstdEncoder::new(file);
+    /// Returns true when both use the same format, regardless of zstd level.
+    pub fn is_format(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
 
-    let mut builder = Builder::new(encoder);
+async fn write_tar<W: AsyncWrite + Unpin + Send + Sync + 'static>(
+    writer: W,
+    source_path: &PathBuf,
+    source_files: &[PathBuf],
+) -> Result<W, Error> {
+    let mut builder = Builder::new(writer);
 
     builder.follow_symlinks(false);
+    builder.mode(HeaderMode::Deterministic);
+
+    let mut source_files = source_files.iter().collect::<Vec<_>>();
+
+    source_files.sort();
 
     for path in source_files {
         let relative_path = path
@@ -52,35 +145,386 @@ pub async fn compress_zstd(
 
     builder.finish().await.expect("Failed to finish builder");
 
-    let mut output = builder.into_inner().await.expect("Failed to get inner");
+    let mut writer = builder.into_inner().await.expect("Failed to get inner");
 
-    output.shutdown().await.expect("Failed to shutdown");
+    writer.shutdown().await.expect("Failed to shutdown");
 
-    let mut file = output.into_inner();
+    Ok(writer)
+}
+
+/// Creates a tar archive of `source_files` with `compression` that is byte-identical for
+/// identical trees.
+///
+/// Entries are sorted by path and headers are written with zero uid, gid and mtime, 0644 or
+/// 0755 permissions and no extended attributes.
+pub async fn compress_archive(
+    compression: ArchiveCompression,
+    source_path: &PathBuf,
+    source_files: &[PathBuf],
+    output_path: &Path,
+) -> Result<File, Error> {
+    let temp_file = create_sandbox_file(Some("tar"))
+        .await
+        .expect("Failed to create temp file");
+
+    let file = File::create(temp_file.clone())
+        .await
+        .expect("Failed to create file");
+
+    let mut file = match compression {
+        ArchiveCompression::Bzip2 => write_tar(BzEncoder::new(file), source_path, source_files)
+            .await?
+            .into_inner(),
+        ArchiveCompression::Gzip => write_tar(GzipEncoder::new(file), source_path, source_files)
+            .await?
+            .into_inner(),
+        ArchiveCompression::Xz => write_tar(XzEncoder::new(file), source_path, source_files)
+            .await?
+            .into_inner(),
+        ArchiveCompression::Zstd(level) => write_tar(
+            ZstdEncoder::with_quality(file, Level::Precise(level)),
+            source_path,
+            source_files,
+        )
+        .await?
+        .into_inner(),
+    };
 
     file.flush().await.expect("Failed to flush");
 
-    copy(&temp_file, output_path).await.expect("Failed to copy");
+    copy_file(&temp_file, output_path)
+        .await
+        .expect("Failed to copy");
 
     remove_file(temp_file).await.expect("Failed to remove file");
 
     Ok(file)
 }
 
-pub async fn unpack_zstd(target_dir: &PathBuf, source_zstd: &Path) -> Result<(), Error> {
-    let zstd = File::open(source_zstd).await.expect("Failed to open file");
+/// Creates an uncompressed tar archive of `source_files`, for bundling data that is already
+/// compressed.
+pub async fn create_tar(
+    source_path: &PathBuf,
+    source_files: &[PathBuf],
+    output_path: &PathBuf,
+) -> Result<(), Error> {
+    let file = File::create(output_path).await?;
 
-    let buf_reader = BufReader::new(zstd);
+    write_tar(file, source_path, source_files).await?;
 
-    let zstd_decoder = ZstdDecoder::new(buf_reader);
+    Ok(())
+}
+
+/// Returns the path of an archive entry relative to the unpack directory, `None` for the
+/// directory itself ("./").
+///
+/// Entries are untrusted, so absolute paths and `..` components fail instead of being dropped.
+fn get_entry_path(archive_path: &Path, entry_path: &Path) -> Result<Option<PathBuf>> {
+    let mut path = PathBuf::new();
+
+    for component in entry_path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => bail!(
+                "archive {} entry {}: path contains `..`",
+                archive_path.display(),
+                entry_path.display()
+            ),
+            Component::Prefix(_) | Component::RootDir => bail!(
+                "archive {} entry {}: absolute path",
+                archive_path.display(),
+                entry_path.display()
+            ),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(path))
+}
+
+/// Fails when a parent of `path` under `target_dir` is a symlink, which an earlier entry could
+/// have pointed anywhere.
+pub async fn check_entry_parents(
+    archive_path: &Path,
+    path: &Path,
+    target_dir: &Path,
+) -> Result<()> {
+    for parent in path.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() {
+            break;
+        }
 
-    let mut archive = Archive::new(zstd_decoder);
+        let parent_path = target_dir.join(parent);
 
-    archive.unpack(target_dir).await.expect("Failed to unpack");
+        if let Ok(metadata) = symlink_metadata(&parent_path).await {
+            if metadata.is_symlink() {
+                bail!(
+                    "archive {} entry {}: written through symlink {}",
+                    archive_path.display(),
+                    path.display(),
+                    parent.display()
+                );
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Fails when a relative symlink target leaves `target_dir` from the directory of the link.
+///
+/// Absolute targets are kept, rootfs artifacts rely on them, and nothing is written through a
+/// symlink (see `check_entry_parents`).
+pub fn check_entry_link(archive_path: &Path, path: &Path, link_path: &Path) -> Result<()> {
+    if link_path.is_absolute() {
+        return Ok(());
+    }
+
+    let mut depth = path.components().count() - 1;
+
+    for component in link_path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(_) => depth += 1,
+            _ => {
+                if depth == 0 {
+                    bail!(
+                        "archive {} entry {}: symlink target {} is outside of the archive",
+                        archive_path.display(),
+                        path.display(),
+                        link_path.display()
+                    );
+                }
+
+                depth -= 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpacks the tar archive read from `reader` into `target_dir`, validating each entry first.
+///
+/// Entries with absolute or `..` paths, symlinks leaving `target_dir`, entries written through
+/// symlinks, and device or fifo files fail the unpack naming the archive and entry.
+async fn unpack_tar_reader<R: AsyncRead + Unpin + Send + Sync>(
+    archive_path: &Path,
+    reader: R,
+    target_dir: &Path,
+) -> Result<()> {
+    create_dir_all(target_dir).await?;
+
+    let mut archive = Archive::new(reader);
+
+    let mut entries = archive.entries()?;
+
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.map_err(|err| {
+            anyhow!(
+                "archive {}: failed to read entry: {}",
+                archive_path.display(),
+                err
+            )
+        })?;
+
+        let entry_path = entry.path()?.to_path_buf();
+
+        let Some(path) = get_entry_path(archive_path, &entry_path)? else {
+            continue;
+        };
+
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_block_special()
+            || entry_type.is_character_special()
+            || entry_type.is_fifo()
+        {
+            bail!(
+                "archive {} entry {}: special files are not supported",
+                archive_path.display(),
+                entry_path.display()
+            );
+        }
+
+        check_entry_parents(archive_path, &path, target_dir).await?;
+
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let Some(link_path) = entry.link_name()? else {
+                bail!(
+                    "archive {} entry {}: link without target",
+                    archive_path.display(),
+                    entry_path.display()
+                );
+            };
+
+            let link_path = link_path.to_path_buf();
+
+            // Hard link targets are other entries of the archive
+
+            if entry_type.is_hard_link() {
+                let link_path = get_entry_path(archive_path, &link_path).map_err(|_| {
+                    anyhow!(
+                        "archive {} entry {}: hard link target {} is outside of the archive",
+                        archive_path.display(),
+                        entry_path.display(),
+                        link_path.display()
+                    )
+                })?;
+
+                if let Some(link_path) = link_path {
+                    check_entry_parents(archive_path, &link_path, target_dir).await?;
+                }
+            } else {
+                check_entry_link(archive_path, &path, &link_path)?;
+            }
+        }
+
+        entry.unpack_in(target_dir).await.map_err(|err| {
+            anyhow!(
+                "archive {} entry {}: failed to unpack: {}",
+                archive_path.display(),
+                entry_path.display(),
+                err
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+pub async fn unpack_tar(target_dir: &Path, source_path: &Path) -> Result<(), Error> {
+    let file = File::open(source_path).await?;
+
+    unpack_tar_reader(source_path, BufReader::new(file), target_dir).await
+}
+
+pub async fn compress_zstd(
+    source_path: &PathBuf,
+    source_files: &[PathBuf],
+    output_path: &Path,
+) -> Result<File, Error> {
+    compress_archive(
+        ArchiveCompression::default(),
+        source_path,
+        source_files,
+        output_path,
+    )
+    .await
+}
+
+/// Writes a pulled archive to a file as its chunks arrive, hashing them on the way so the
+/// archive is verified without ever being held in memory.
+pub struct ArchiveWriter {
+    file: BufWriter<File>,
+    hasher: Sha256,
+    header: Vec<u8>,
+    path: PathBuf,
+    size: u64,
+}
+
+impl ArchiveWriter {
+    pub async fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .await
+            .map_err(|err| anyhow!("failed to create archive {}: {}", path.display(), err))?;
+
+        Ok(Self {
+            file: BufWriter::with_capacity(ARCHIVE_WRITE_BUFFER_SIZE, file),
+            hasher: Sha256::new(),
+            header: Vec::with_capacity(ARCHIVE_HEADER_SIZE),
+            path: path.to_path_buf(),
+            size: 0,
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.header.len() < ARCHIVE_HEADER_SIZE {
+            let end = (ARCHIVE_HEADER_SIZE - self.header.len()).min(data.len());
+
+            self.header.extend_from_slice(&data[..end]);
+        }
+
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+
+        self.file
+            .write_all(data)
+            .await
+            .map_err(|err| anyhow!("failed to write archive {}: {}", self.path.display(), err))
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Flushes the archive and verifies it against `data_digest`, returning the id of the
+    /// dictionary it was compressed with, if any. The archive is removed when it is rejected.
+    pub async fn finish(
+        mut self,
+        data_digest: Option<&ArchiveDigest>,
+        name: &str,
+    ) -> Result<Option<u32>> {
+        self.file
+            .flush()
+            .await
+            .map_err(|err| anyhow!("failed to write archive {}: {}", self.path.display(), err))?;
+
+        let digest = ArchiveDigest::from_hasher(self.hasher);
+
+        if let Err(err) = verify_pulled_digest(data_digest, &digest, name) {
+            let _ = remove_file(&self.path).await;
+
+            return Err(err);
+        }
+
+        Ok(get_archive_dictionary_id(&self.header))
+    }
+}
+
+/// Unpacks a tar archive in any supported compression, detected from its magic bytes.
+pub async fn unpack_archive(target_dir: &Path, source_path: &Path) -> Result<(), Error> {
+    let mut magic = [0; 6];
+
+    let mut file = File::open(source_path).await?;
+
+    let magic_size = file.read(&mut magic).await?;
+
+    let Some(compression) = ArchiveCompression::from_data(&magic[..magic_size]) else {
+        bail!("unsupported archive format: {}", source_path.display());
+    };
+
+    match compression {
+        ArchiveCompression::Bzip2 => {
+            let decoder = BzDecoder::new(BufReader::new(File::open(source_path).await?));
+
+            unpack_tar_reader(source_path, decoder, target_dir).await
+        }
+        ArchiveCompression::Gzip => unpack_gzip(target_dir, source_path).await,
+        ArchiveCompression::Xz => {
+            let decoder = XzDecoder::new(BufReader::new(File::open(source_path).await?));
+
+            unpack_tar_reader(source_path, decoder, target_dir).await
+        }
+        ArchiveCompression::Zstd(_) => unpack_zstd(target_dir, source_path).await,
+    }
+}
+
+pub async fn unpack_zstd(target_dir: &Path, source_zstd: &Path) -> Result<(), Error> {
+    let zstd = File::open(source_zstd).await.expect("Failed to open file");
+
+    let buf_reader = BufReader::new(zstd);
+
+    let zstd_decoder = ZstdDecoder::new(buf_reader);
+
+    unpack_tar_reader(source_zstd, zstd_decoder, target_dir).await
+}
+
 pub async fn compress_gzip(
     source_path: &PathBuf,
     source_files: &[PathBuf],
@@ -123,20 +567,14 @@ pub async fn compress_gzip(
     Ok(output.into_inner())
 }
 
-pub async fn unpack_gzip(target_dir: &PathBuf, source_tar: &Path) -> Result<(), Error> {
+pub async fn unpack_gzip(target_dir: &Path, source_tar: &Path) -> Result<(), Error> {
     let tar_gz = File::open(source_tar).await.expect("Failed to open file");
 
     let buf_reader = BufReader::new(tar_gz);
 
     let gz_decoder = GzipDecoder::new(buf_reader);
 
-    let archive_builder = ArchiveBuilder::new(gz_decoder);
-
-    let mut archive = archive_builder.build();
-
-    archive.unpack(target_dir).await.expect("Failed to unpack");
-
-    Ok(())
+    unpack_tar_reader(source_tar, gz_decoder, target_dir).await
 }
 
 /// Returns a relative path without reserved names, redundant separators, ".", or "..".
@@ -149,61 +587,101 @@ fn sanitize_file_path(path: &str) -> PathBuf {
         .collect()
 }
 
+/// Unpacks the zip archive at `source_path` into `target_dir`, validating each entry first.
+///
+/// Crafted entries, such as non UTF-8 names or duplicate paths, fail the unpack naming the
+/// archive and entry.
 pub async fn unpack_zip(source_path: &PathBuf, target_dir: &Path) -> Result<(), Error> {
-    let archive_file = File::open(source_path).await.expect("Failed to open file");
+    let archive_file = File::open(source_path)
+        .await
+        .map_err(|err| anyhow!("archive {}: failed to open: {}", source_path.display(), err))?;
 
     let archive = BufReader::new(archive_file).compat();
 
     let mut reader = ZipFileReader::new(archive)
         .await
-        .expect("Failed to read zip file");
+        .map_err(|err| anyhow!("archive {}: failed to read: {}", source_path.display(), err))?;
 
     for index in 0..reader.file().entries().len() {
-        let entry = reader.file().entries().get(index).unwrap();
+        let entry = &reader.file().entries()[index];
+
+        let entry_filename = entry
+            .filename()
+            .as_str()
+            .map_err(|_| {
+                anyhow!(
+                    "archive {} entry {}: name is not valid UTF-8",
+                    source_path.display(),
+                    String::from_utf8_lossy(entry.filename().as_bytes())
+                )
+            })?
+            .replace('\\', "/");
+
+        let Some(entry_path) = get_entry_path(source_path, Path::new(&entry_filename))? else {
+            continue;
+        };
+
+        check_entry_parents(source_path, &entry_path, target_dir).await?;
 
-        let path = target_dir.join(sanitize_file_path(entry.filename().as_str().unwrap()));
+        let path = target_dir.join(sanitize_file_path(&entry_path.to_string_lossy()));
 
         // If the filename of the entry ends with '/', it is treated as a directory.
         // This is implemented by previous versions of this crate and the Python Standard Library.
         // https://docs.rs/async_zip/0.0.8/src/async_zip/read/mod.rs.html#63-65
         // https://github.com/python/cpython/blob/820ef62833bd2d84a141adedd9a05998595d6b6d/Lib/zipfile.py#L528
-        let entry_is_dir = entry.dir().unwrap();
+        let entry_is_dir = entry.dir().map_err(|err| {
+            anyhow!(
+                "archive {} entry {}: {}",
+                source_path.display(),
+                entry_filename,
+                err
+            )
+        })?;
+
+        let entry_error = |action: &str, err: &dyn std::fmt::Display| {
+            anyhow!(
+                "archive {} entry {}: failed to {}: {}",
+                source_path.display(),
+                entry_filename,
+                action,
+                err
+            )
+        };
 
         let mut entry_reader = reader
             .reader_without_entry(index)
             .await
-            .expect("Failed to read ZipEntry");
+            .map_err(|err| entry_error("read", &err))?;
 
         if entry_is_dir {
             // The directory may have been created if iteration is out of order.
             if !path.exists() {
                 create_dir_all(&path)
                     .await
-                    .expect("Failed to create extracted directory");
+                    .map_err(|err| entry_error("create directory", &err))?;
             }
         } else {
             // Creates parent directories. They may not exist if iteration is out of order
             // or the archive does not contain directory entries.
-            let parent = path
-                .parent()
-                .expect("A file entry should have parent directories");
-
-            if !parent.is_dir() {
-                create_dir_all(parent)
-                    .await
-                    .expect("Failed to create parent directories");
+            if let Some(parent) = path.parent() {
+                if !parent.is_dir() {
+                    create_dir_all(parent)
+                        .await
+                        .map_err(|err| entry_error("create parent directories", &err))?;
+                }
             }
 
+            // Duplicate entries fail here instead of overwriting the first one
             let writer = OpenOptions::new()
                 .write(true)
                 .create_new(true)
                 .open(&path)
                 .await
-                .expect("Failed to create extracted file");
+                .map_err(|err| entry_error("create file", &err))?;
 
             futures_lite::io::copy(&mut entry_reader, &mut writer.compat_write())
                 .await
-                .expect("Failed to copy to extracted file");
+                .map_err(|err| entry_error("extract file", &err))?;
 
             // Closes the file and manipulates its metadata here if you wish to preserve its metadata from the archive.
         }
@@ -211,3 +689,324 @@ pub async fn unpack_zip(source_path: &PathBuf, target_dir: &Path) -> Result<(),
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::get_sandbox_dir_path;
+    use async_zip::{
+        base::write::ZipFileWriter, Compression, StringEncoding, ZipEntryBuilder, ZipString,
+    };
+    use filetime::{set_file_mtime, FileTime};
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tokio_tar::{EntryType, Header};
+
+    // Entry of a crafted archive: type, path, link target and contents, written as given
+    struct TestEntry {
+        entry_type: EntryType,
+        path: &'static str,
+        link: String,
+        data: &'static [u8],
+    }
+
+    fn file(path: &'static str) -> TestEntry {
+        TestEntry {
+            entry_type: EntryType::Regular,
+            path,
+            link: String::new(),
+            data: b"data",
+        }
+    }
+
+    fn link(entry_type: EntryType, path: &'static str, link: &str) -> TestEntry {
+        TestEntry {
+            entry_type,
+            path,
+            link: link.to_string(),
+            data: b"",
+        }
+    }
+
+    // Header names are written directly, `Header::set_path` refuses the paths under test
+    fn get_header(entry: &TestEntry) -> Header {
+        let mut header = Header::new_gnu();
+
+        header.as_old_mut().name[..entry.path.len()].copy_from_slice(entry.path.as_bytes());
+        header.as_old_mut().linkname[..entry.link.len()].copy_from_slice(entry.link.as_bytes());
+        header.set_entry_type(entry.entry_type);
+        header.set_mode(0o644);
+        header.set_size(entry.data.len() as u64);
+        header.set_cksum();
+
+        header
+    }
+
+    async fn write_archive(path: &Path, compression: ArchiveCompression, entries: &[TestEntry]) {
+        let file = File::create(path).await.unwrap();
+
+        let writer: Box<dyn AsyncWrite + Unpin + Send + Sync> = match compression {
+            ArchiveCompression::Gzip => Box::new(GzipEncoder::new(file)),
+            _ => Box::new(ZstdEncoder::new(file)),
+        };
+
+        let mut builder = Builder::new(writer);
+
+        for entry in entries {
+            builder
+                .append(&get_header(entry), entry.data)
+                .await
+                .unwrap();
+        }
+
+        let mut writer = builder.into_inner().await.unwrap();
+
+        writer.shutdown().await.unwrap();
+    }
+
+    async fn write_zip(path: &Path, entries: &[&str]) {
+        let entries = entries
+            .iter()
+            .map(|name| ZipString::new(name.as_bytes().to_vec(), StringEncoding::Utf8))
+            .collect::<Vec<_>>();
+
+        write_zip_names(path, entries).await;
+    }
+
+    // Entry names are written as given, including names that are not UTF-8
+    async fn write_zip_names(path: &Path, entries: Vec<ZipString>) {
+        let mut writer = ZipFileWriter::new(vec![]);
+
+        for name in entries {
+            let entry = ZipEntryBuilder::new(name, Compression::Stored);
+
+            writer.write_entry_whole(entry, b"data").await.unwrap();
+        }
+
+        fs::write(path, writer.close().await.unwrap()).unwrap();
+    }
+
+    fn write_tree(path: &Path, order: &[&str], mtime: i64) -> Vec<PathBuf> {
+        let mut files = vec![];
+
+        for name in order {
+            let file_path = path.join(name);
+
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(&file_path, format!("{}\n", name)).unwrap();
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(0o600)).unwrap();
+            set_file_mtime(&file_path, FileTime::from_unix_time(mtime, 0)).unwrap();
+
+            files.push(file_path);
+        }
+
+        files
+    }
+
+    #[tokio::test]
+    async fn test_compress_zstd_is_deterministic() {
+        create_dir_all(get_sandbox_dir_path()).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let first_path = dir.path().join("first");
+        let second_path = dir.path().join("second");
+
+        let first_files = write_tree(&first_path, &["a.txt", "lib/b.txt", "lib/c.txt"], 1);
+        let second_files = write_tree(&second_path, &["lib/c.txt", "a.txt", "lib/b.txt"], 2);
+
+        fs::set_permissions(&second_files[1], fs::Permissions::from_mode(0o644)).unwrap();
+
+        let first_archive = dir.path().join("first.tar.zst");
+        let second_archive = dir.path().join("second.tar.zst");
+
+        compress_zstd(&first_path, &first_files, &first_archive)
+            .await
+            .unwrap();
+        compress_zstd(&second_path, &second_files, &second_archive)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::read(&first_archive).unwrap(),
+            fs::read(&second_archive).unwrap()
+        );
+    }
+
+    // Crafted entries and the error unpacking them, `outside` is a directory next to the target
+    fn get_crafted_entries(outside: &str) -> Vec<(&'static str, Vec<TestEntry>, &'static str)> {
+        vec![
+            ("absolute path", vec![file("/evil")], "absolute path"),
+            (
+                "parent path",
+                vec![file("a/../../evil")],
+                "path contains `..`",
+            ),
+            (
+                "symlink outside",
+                vec![link(EntryType::Symlink, "a/link", "../../evil")],
+                "symlink target ../../evil is outside of the archive",
+            ),
+            (
+                "hard link outside",
+                vec![link(EntryType::Link, "hard", "../evil")],
+                "hard link target ../evil is outside of the archive",
+            ),
+            // Absolute symlinks are allowed, but nothing is written through them
+            (
+                "symlink then write",
+                vec![link(EntryType::Symlink, "link", outside), file("link/evil")],
+                "written through symlink link",
+            ),
+            (
+                "fifo",
+                vec![link(EntryType::Fifo, "fifo", "")],
+                "special files are not supported",
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_unpack_archive_rejects_crafted_entries() {
+        // Worker sources and CLI pulls both unpack through `unpack_archive`
+
+        for compression in [ArchiveCompression::Gzip, ArchiveCompression::default()] {
+            let dir = tempfile::tempdir().unwrap();
+
+            let outside_path = dir.path().join("outside");
+
+            fs::create_dir_all(&outside_path).unwrap();
+
+            for (name, entries, expected) in get_crafted_entries(outside_path.to_str().unwrap()) {
+                let archive_path = dir.path().join(format!("{}.tar", name));
+                let target_path = dir.path().join(name);
+
+                write_archive(&archive_path, compression, &entries).await;
+
+                let err = unpack_archive(&target_path, &archive_path)
+                    .await
+                    .expect_err(name);
+
+                assert!(
+                    err.to_string().contains(expected),
+                    "{} ({}): {}",
+                    name,
+                    compression,
+                    err
+                );
+
+                assert!(!dir.path().join("evil").exists(), "{}", name);
+                assert!(!outside_path.join("evil").exists(), "{}", name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpack_archive_keeps_links_inside() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let archive_path = dir.path().join("archive");
+        let target_path = dir.path().join("target");
+
+        let entries = [
+            file("bin/tool"),
+            link(EntryType::Symlink, "bin/alias", "tool"),
+            link(EntryType::Symlink, "lib/tool", "../bin/tool"),
+            link(EntryType::Symlink, "etc/resolv.conf", "/run/resolv.conf"),
+            link(EntryType::Link, "bin/copy", "bin/tool"),
+        ];
+
+        write_archive(&archive_path, ArchiveCompression::default(), &entries).await;
+
+        unpack_archive(&target_path, &archive_path).await.unwrap();
+
+        assert_eq!(fs::read(target_path.join("bin/alias")).unwrap(), b"data");
+        assert_eq!(fs::read(target_path.join("lib/tool")).unwrap(), b"data");
+        assert_eq!(fs::read(target_path.join("bin/copy")).unwrap(), b"data");
+        assert_eq!(
+            fs::read_link(target_path.join("etc/resolv.conf")).unwrap(),
+            Path::new("/run/resolv.conf")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unpack_zip_rejects_crafted_entries() {
+        for (entry, expected) in [
+            ("/evil", "absolute path"),
+            ("a/../../evil", "path contains `..`"),
+            ("a\\..\\..\\evil", "path contains `..`"),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+
+            let archive_path = dir.path().join("archive.zip");
+            let target_path = dir.path().join("target");
+
+            write_zip(&archive_path, &[entry]).await;
+
+            let err = unpack_zip(&archive_path, &target_path)
+                .await
+                .expect_err(entry);
+
+            assert!(err.to_string().contains(expected), "{}: {}", entry, err);
+            assert!(!dir.path().join("evil").exists(), "{}", entry);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpack_zip_rejects_write_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let archive_path = dir.path().join("archive.zip");
+        let outside_path = dir.path().join("outside");
+        let target_path = dir.path().join("target");
+
+        fs::create_dir_all(&outside_path).unwrap();
+        fs::create_dir_all(&target_path).unwrap();
+
+        std::os::unix::fs::symlink(&outside_path, target_path.join("link")).unwrap();
+
+        write_zip(&archive_path, &["link/evil"]).await;
+
+        let err = unpack_zip(&archive_path, &target_path).await.unwrap_err();
+
+        assert!(err.to_string().contains("written through symlink link"));
+        assert!(!outside_path.join("evil").exists());
+    }
+
+    #[tokio::test]
+    async fn test_unpack_zip_rejects_invalid_entries() {
+        for (names, expected) in [
+            (
+                vec![b"file".to_vec(), b"file".to_vec()],
+                "entry file: failed to create file",
+            ),
+            (
+                vec![b"name\xff".to_vec()],
+                "entry name\u{fffd}: name is not valid UTF-8",
+            ),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+
+            let archive_path = dir.path().join("archive.zip");
+            let target_path = dir.path().join("target");
+
+            let names = names
+                .into_iter()
+                .map(|name| ZipString::new(name, StringEncoding::Raw))
+                .collect();
+
+            write_zip_names(&archive_path, names).await;
+
+            let err = unpack_zip(&archive_path, &target_path)
+                .await
+                .expect_err(expected);
+
+            assert!(err.to_string().contains(expected), "{}: {}", expected, err);
+            assert!(
+                err.to_string()
+                    .contains(&archive_path.display().to_string()),
+                "{}",
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{hashes::get_file_hashes, paths::get_blob_dir_path};
+use anyhow::{anyhow, Result};
+use std::{
+    env,
+    fs::{create_dir_all, hard_link, read_dir, remove_file, rename, symlink_metadata},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+use walkdir::WalkDir;
+
+// Deduplicates files unpacked into the store when set to "1" or "true"
+pub const STORE_DEDUP_ENV: &str = "VORPAL_STORE_DEDUP";
+
+pub fn is_store_dedup_enabled() -> bool {
+    env::var(STORE_DEDUP_ENV)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Files of a deduplication pass, and the bytes no longer stored twice.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlobSummary {
+    pub files: u64,
+    pub linked: u64,
+    pub saved_bytes: u64,
+}
+
+impl BlobSummary {
+    pub fn add(&mut self, other: BlobSummary) {
+        self.files += other.files;
+        self.linked += other.linked;
+        self.saved_bytes += other.saved_bytes;
+    }
+}
+
+// Hard links share permissions, so files with the same contents and different modes get
+// separate blobs
+fn get_blob_path(blob_dir_path: &Path, hash: &str, mode: u32) -> PathBuf {
+    blob_dir_path.join(format!("{}-{:o}", hash, mode & 0o7777))
+}
+
+/// Replaces the files under `path` with hard links to content-addressed blobs, so identical
+/// files across artifacts are stored once.
+///
+/// Files on filesystems without hard links to the blob directory are kept as copies.
+pub fn dedup_path(path: &Path) -> Result<BlobSummary> {
+    dedup_path_blobs(&get_blob_dir_path(), path)
+}
+
+fn dedup_path_blobs(blob_dir_path: &Path, path: &Path) -> Result<BlobSummary> {
+    create_dir_all(blob_dir_path).map_err(|e| anyhow!("failed to create blob dir: {}", e))?;
+
+    // Symlinks are left as they are, only regular file contents are shared
+
+    let files = WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+
+    let hashes = get_file_hashes(&files)?;
+
+    let mut summary = BlobSummary::default();
+
+    for (file, hash) in files.iter().zip(hashes) {
+        let file_metadata = symlink_metadata(file)?;
+
+        let blob_path = get_blob_path(blob_dir_path, &hash, file_metadata.mode());
+
+        summary.files += 1;
+
+        let blob_metadata = match symlink_metadata(&blob_path) {
+            Ok(blob_metadata) => blob_metadata,
+
+            // First copy of the contents becomes the blob
+            Err(_) => {
+                match hard_link(file, &blob_path) {
+                    Ok(_) => summary.linked += 1,
+                    Err(err) => debug!("keeping copy of {}: {}", file.display(), err),
+                }
+
+                continue;
+            }
+        };
+
+        if blob_metadata.ino() == file_metadata.ino() && blob_metadata.dev() == file_metadata.dev()
+        {
+            summary.linked += 1;
+
+            continue;
+        }
+
+        // Linked next to the file and renamed over it, so the file is never missing
+
+        let mut link_path = file.as_os_str().to_owned();
+
+        link_path.push(".vorpal-blob");
+
+        let link_path = PathBuf::from(link_path);
+
+        if let Err(err) = hard_link(&blob_path, &link_path) {
+            debug!("keeping copy of {}: {}", file.display(), err);
+
+            continue;
+        }
+
+        if let Err(err) = rename(&link_path, file) {
+            let _ = remove_file(&link_path);
+
+            return Err(anyhow!("failed to link {}: {}", file.display(), err));
+        }
+
+        summary.linked += 1;
+        summary.saved_bytes += file_metadata.len();
+    }
+
+    Ok(summary)
+}
+
+/// Removes blobs no longer linked from the store, returning their count and size.
+///
+/// A blob is only referenced by its hard links, so blobs with a single link are unused.
+pub fn prune_blobs() -> Result<(u64, u64)> {
+    prune_blob_dir(&get_blob_dir_path())
+}
+
+fn prune_blob_dir(blob_dir_path: &Path) -> Result<(u64, u64)> {
+    if !blob_dir_path.exists() {
+        return Ok((0, 0));
+    }
+
+    let mut pruned = 0;
+    let mut pruned_bytes = 0;
+
+    for entry in read_dir(blob_dir_path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if !metadata.is_file() || metadata.nlink() > 1 {
+            continue;
+        }
+
+        remove_file(entry.path())?;
+
+        pruned += 1;
+        pruned_bytes += metadata.len();
+    }
+
+    Ok((pruned, pruned_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{metadata, read_to_string, remove_dir_all, set_permissions, write, Permissions},
+        os::unix::fs::PermissionsExt,
+    };
+
+    fn write_file(path: &Path, contents: &str, mode: u32) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, contents).unwrap();
+        set_permissions(path, Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn get_blob_names(blob_dir_path: &Path) -> Vec<String> {
+        let mut names = read_dir(blob_dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_dedup_path_links_identical_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let blob_dir_path = temp.path().join("blob");
+        let store_path = temp.path().join("store");
+
+        write_file(&store_path.join("a/bin/tool"), "tool", 0o755);
+        write_file(&store_path.join("a/README"), "shared", 0o644);
+        write_file(&store_path.join("b/README"), "shared", 0o644);
+        write_file(&store_path.join("b/script"), "shared", 0o755);
+
+        let first = dedup_path_blobs(&blob_dir_path, &store_path.join("a")).unwrap();
+
+        assert_eq!((first.files, first.linked, first.saved_bytes), (2, 2, 0));
+
+        let second = dedup_path_blobs(&blob_dir_path, &store_path.join("b")).unwrap();
+
+        // The README is a dedup hit, the script has other permissions and gets its own blob
+
+        assert_eq!((second.files, second.linked, second.saved_bytes), (2, 2, 6));
+
+        let readme_a = metadata(store_path.join("a/README")).unwrap();
+        let readme_b = metadata(store_path.join("b/README")).unwrap();
+        let script = metadata(store_path.join("b/script")).unwrap();
+
+        assert_eq!(readme_a.ino(), readme_b.ino());
+        assert_eq!(readme_a.nlink(), 3);
+        assert_ne!(readme_a.ino(), script.ino());
+        assert_eq!(script.mode() & 0o7777, 0o755);
+        assert_eq!(
+            read_to_string(store_path.join("b/README")).unwrap(),
+            "shared"
+        );
+        assert_eq!(get_blob_names(&blob_dir_path).len(), 3);
+
+        // Files already linked to their blob are not linked again
+
+        let again = dedup_path_blobs(&blob_dir_path, &store_path).unwrap();
+
+        assert_eq!((again.files, again.linked, again.saved_bytes), (4, 4, 0));
+        assert_eq!(metadata(store_path.join("a/README")).unwrap().nlink(), 3);
+    }
+
+    #[test]
+    fn test_dedup_path_keeps_copies_across_devices() {
+        let Ok(shm) = tempfile::tempdir_in("/dev/shm") else {
+            return;
+        };
+
+        let temp = tempfile::tempdir().unwrap();
+        let store_path = temp.path().join("store");
+
+        write_file(&store_path.join("a/README"), "shared", 0o644);
+        write_file(&store_path.join("b/README"), "shared", 0o644);
+
+        // Only meaningful when the blob directory is on another filesystem than the store
+
+        if metadata(shm.path()).unwrap().dev() == metadata(temp.path()).unwrap().dev() {
+            return;
+        }
+
+        let blob_dir_path = shm.path().join("blob");
+
+        let summary = dedup_path_blobs(&blob_dir_path, &store_path).unwrap();
+
+        assert_eq!(
+            (summary.files, summary.linked, summary.saved_bytes),
+            (2, 0, 0)
+        );
+        assert!(get_blob_names(&blob_dir_path).is_empty());
+
+        for name in ["a/README", "b/README"] {
+            let file = metadata(store_path.join(name)).unwrap();
+
+            assert_eq!(file.nlink(), 1);
+            assert_eq!(read_to_string(store_path.join(name)).unwrap(), "shared");
+        }
+    }
+
+    #[test]
+    fn test_prune_blob_dir_removes_only_unreferenced_blobs() {
+        let temp = tempfile::tempdir().unwrap();
+        let blob_dir_path = temp.path().join("blob");
+        let store_path = temp.path().join("store");
+
+        assert_eq!(prune_blob_dir(&blob_dir_path).unwrap(), (0, 0));
+
+        write_file(&store_path.join("a/README"), "shared", 0o644);
+        write_file(&store_path.join("a/only-a"), "removed", 0o644);
+        write_file(&store_path.join("b/README"), "shared", 0o644);
+
+        dedup_path_blobs(&blob_dir_path, &store_path).unwrap();
+
+        assert_eq!(get_blob_names(&blob_dir_path).len(), 2);
+
+        // Nothing is pruned while every blob is linked from the store
+
+        assert_eq!(prune_blob_dir(&blob_dir_path).unwrap(), (0, 0));
+
+        remove_dir_all(store_path.join("a")).unwrap();
+
+        // The blob of the removed file is unused, the shared one is still linked from b
+
+        assert_eq!(prune_blob_dir(&blob_dir_path).unwrap(), (1, 7));
+
+        let blob_names = get_blob_names(&blob_dir_path);
+
+        assert_eq!(blob_names.len(), 1);
+        assert_eq!(
+            read_to_string(blob_dir_path.join(&blob_names[0])).unwrap(),
+            "shared"
+        );
+        assert_eq!(
+            read_to_string(store_path.join("b/README")).unwrap(),
+            "shared"
+        );
+
+        remove_dir_all(store_path.join("b")).unwrap();
+
+        assert_eq!(prune_blob_dir(&blob_dir_path).unwrap(), (1, 6));
+        assert!(get_blob_names(&blob_dir_path).is_empty());
+    }
+}
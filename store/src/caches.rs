@@ -0,0 +1,108 @@
+use crate::paths::get_cache_dir_path;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{read_dir, remove_file, File},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::debug;
+
+pub const DEFAULT_SOURCE_CACHE_SIZE_MAX: u64 = 20 * 1024 * 1024 * 1024; // 20GB
+
+// Overrides `DEFAULT_SOURCE_CACHE_SIZE_MAX`, exported so config processes use the same cap
+pub const SOURCE_CACHE_SIZE_MAX_ENV: &str = "VORPAL_SOURCE_CACHE_MAX_BYTES";
+
+pub fn get_source_cache_size_max() -> u64 {
+    env::var(SOURCE_CACHE_SIZE_MAX_ENV)
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_SOURCE_CACHE_SIZE_MAX)
+}
+
+/// Source cache archive (`get_cache_archive_path`), with the time it was last used.
+#[derive(Clone, Debug)]
+pub struct SourceCacheEntry {
+    pub modified: SystemTime,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Returns the source cache archives, most recently used first.
+pub fn get_source_cache_entries() -> Result<Vec<SourceCacheEntry>> {
+    let dir_path = get_cache_dir_path();
+
+    if !dir_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+
+    // Unpacked sources of workers, config and incremental caches are directories
+
+    for entry in read_dir(&dir_path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+
+        if !metadata.is_file() || !path.to_string_lossy().ends_with(".tar.zst") {
+            continue;
+        }
+
+        entries.push(SourceCacheEntry {
+            modified: metadata.modified()?,
+            path,
+            size: metadata.len(),
+        });
+    }
+
+    entries.sort_by(|a, b| b.modified.cmp(&a.modified));
+
+    Ok(entries)
+}
+
+/// Marks a source cache archive as used, so it is evicted after archives used before it.
+pub fn touch_source_cache(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .map_err(|e| anyhow!("failed to touch {}: {}", path.display(), e))
+}
+
+/// Removes the least recently used source cache archives until the rest fit in `size_max` bytes,
+/// returning their paths. Archives in `keep` are never removed, even above `size_max`.
+pub fn evict_source_cache(size_max: u64, keep: &HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+    let entries = get_source_cache_entries()?;
+
+    let mut size = entries.iter().map(|entry| entry.size).sum::<u64>();
+    let mut evicted = vec![];
+
+    for entry in entries.into_iter().rev() {
+        if size <= size_max {
+            break;
+        }
+
+        if keep.contains(&entry.path) {
+            debug!("keeping source cache in use: {}", entry.path.display());
+
+            continue;
+        }
+
+        debug!(
+            "evicting source cache: {} ({} bytes, {} of {} bytes)",
+            entry.path.display(),
+            entry.size,
+            size,
+            size_max
+        );
+
+        remove_file(&entry.path)
+            .map_err(|e| anyhow!("failed to remove {}: {}", entry.path.display(), e))?;
+
+        size -= entry.size;
+
+        evicted.push(entry.path);
+    }
+
+    Ok(evicted)
+}
@@ -0,0 +1,568 @@
+use anyhow::{bail, Result};
+use std::{
+    env, fmt,
+    sync::{Mutex, Once, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{channel, Receiver},
+    task::JoinHandle,
+    time::sleep,
+};
+use tracing::{debug, info, warn};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 2 * 1024 * 1024; // 2MB
+pub const DEFAULT_CHUNK_SIZE_MAX: usize = 16 * 1024 * 1024; // 16MB
+pub const DEFAULT_CHUNK_SIZE_MIN: usize = 256 * 1024; // 256KB
+
+// Upper limit for grpc messages carrying chunks (largest chunk plus message overhead)
+pub const CHUNK_MESSAGE_SIZE_LIMIT: usize = DEFAULT_CHUNK_SIZE_MAX * 4;
+
+// Time a single chunk should take to transfer, used to derive the ideal chunk size
+const CHUNK_TARGET_DURATION: Duration = Duration::from_millis(250);
+
+// Number of consecutive samples agreeing on a direction before the size changes
+const CHUNK_HYSTERESIS: u8 = 3;
+
+// Messages buffered per transfer stream. Sends into a buffer with room return at once, so only
+// sends into a full buffer measure throughput
+pub const CHUNK_CHANNEL_SIZE: usize = 16;
+
+// Limit of registry transfers by this process, such as `10MB/s`, shared by every stream. Only
+// read once, on the first transfer, when no limit was set with `set_network_limit`
+pub const NETWORK_LIMIT_ENV: &str = "VORPAL_NETWORK_LIMIT";
+
+static NETWORK_LIMITER: OnceLock<Option<NetworkLimiter>> = OnceLock::new();
+
+fn get_network_limiter() -> Option<&'static NetworkLimiter> {
+    let limiter = NETWORK_LIMITER
+        .get_or_init(|| {
+            let limit = env::var(NETWORK_LIMIT_ENV).ok()?;
+
+            match NetworkLimiter::parse(&limit) {
+                Ok(limiter) => Some(limiter),
+                Err(err) => {
+                    warn!("ignoring {}: {}", NETWORK_LIMIT_ENV, err);
+
+                    None
+                }
+            }
+        })
+        .as_ref()?;
+
+    limiter.logged.call_once(|| {
+        info!("network limited to {}/s", limiter.limit);
+    });
+
+    Some(limiter)
+}
+
+/// Limits the registry transfers of this process to `limit`, such as `10MB/s`, shared by every
+/// stream. Must be called before the first transfer, which otherwise reads `NETWORK_LIMIT_ENV`.
+pub fn set_network_limit(limit: &str) -> Result<()> {
+    if NETWORK_LIMITER
+        .set(Some(NetworkLimiter::parse(limit)?))
+        .is_err()
+    {
+        bail!("network limit is already set");
+    }
+
+    Ok(())
+}
+
+/// Parses a transfer rate such as `10MB/s`, `512KB` or `1048576` into bytes per second.
+pub fn parse_network_limit(limit: &str) -> Result<u64> {
+    let limit = limit.trim();
+    let rate = limit.strip_suffix("/s").unwrap_or(limit).to_uppercase();
+
+    let unit_start = rate
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rate.len());
+
+    let (number, unit) = rate.split_at(unit_start);
+
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => bail!("invalid network limit unit: {}", limit),
+    };
+
+    let Ok(number) = number.parse::<f64>() else {
+        bail!("invalid network limit: {}", limit);
+    };
+
+    let bytes_per_second = (number * multiplier as f64) as u64;
+
+    if bytes_per_second == 0 {
+        bail!("network limit must be above zero: {}", limit);
+    }
+
+    Ok(bytes_per_second)
+}
+
+/// Token bucket holding up to one second of transfer, shared by the streams of the process.
+struct NetworkLimiter {
+    available: Mutex<(f64, Instant)>,
+    bytes_per_second: f64,
+    limit: String,
+    logged: Once,
+}
+
+impl NetworkLimiter {
+    fn parse(limit: &str) -> Result<Self> {
+        let bytes_per_second = parse_network_limit(limit)? as f64;
+        let limit = limit.trim();
+
+        Ok(Self {
+            available: Mutex::new((bytes_per_second, Instant::now())),
+            bytes_per_second,
+            limit: limit.strip_suffix("/s").unwrap_or(limit).to_string(),
+            logged: Once::new(),
+        })
+    }
+
+    /// Takes `bytes` from the bucket, returning how long to wait for them. Chunks larger than
+    /// the bucket leave it in debt, which delays the following transfers.
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut available = self
+            .available
+            .lock()
+            .expect("network limiter lock poisoned");
+
+        let now = Instant::now();
+        let refill = now.duration_since(available.1).as_secs_f64() * self.bytes_per_second;
+
+        available.0 = (available.0 + refill).min(self.bytes_per_second) - bytes as f64;
+        available.1 = now;
+
+        match available.0 < 0.0 {
+            true => Duration::from_secs_f64(-available.0 / self.bytes_per_second),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Waits until `bytes` can be transferred within the network limit, returning at once when no
+/// limit is set.
+pub async fn wait_network_limit(bytes: usize) {
+    let Some(limiter) = get_network_limiter() else {
+        return;
+    };
+
+    let wait = limiter.reserve(bytes);
+
+    if !wait.is_zero() {
+        sleep(wait).await;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkBounds {
+    pub default: usize,
+    pub max: usize,
+    pub min: usize,
+}
+
+impl Default for ChunkBounds {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_CHUNK_SIZE,
+            max: DEFAULT_CHUNK_SIZE_MAX,
+            min: DEFAULT_CHUNK_SIZE_MIN,
+        }
+    }
+}
+
+impl ChunkBounds {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.clamp(1, CHUNK_MESSAGE_SIZE_LIMIT / 2);
+        let max = max.clamp(min, CHUNK_MESSAGE_SIZE_LIMIT / 2);
+
+        Self {
+            default: DEFAULT_CHUNK_SIZE.clamp(min, max),
+            max,
+            min,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum ChunkVote {
+    #[default]
+    Hold,
+    Grow,
+    Shrink,
+}
+
+/// Picks chunk sizes for a single transfer stream based on measured throughput.
+///
+/// Each recorded chunk computes the size that would have taken `CHUNK_TARGET_DURATION`
+/// to send. Only after `CHUNK_HYSTERESIS` consecutive samples agree the size is doubled
+/// or halved, which keeps a single slow or fast chunk from flapping the size.
+#[derive(Clone, Debug)]
+pub struct ChunkSizer {
+    bounds: ChunkBounds,
+    bytes: usize,
+    chunks: usize,
+    errors: usize,
+    size: usize,
+    size_max: usize,
+    size_min: usize,
+    started: Instant,
+    vote: ChunkVote,
+    votes: u8,
+}
+
+impl ChunkSizer {
+    pub fn new(bounds: ChunkBounds) -> Self {
+        let size = bounds.default.clamp(bounds.min, bounds.max);
+
+        Self {
+            bounds,
+            bytes: 0,
+            chunks: 0,
+            errors: 0,
+            size,
+            size_max: size,
+            size_min: size,
+            started: Instant::now(),
+            vote: ChunkVote::Hold,
+            votes: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes += bytes;
+        self.chunks += 1;
+
+        // Partial chunks (end of stream) say nothing about the chosen size
+
+        if bytes < self.size {
+            return;
+        }
+
+        let elapsed = elapsed.as_secs_f64().max(f64::EPSILON);
+        let ideal = (bytes as f64 / elapsed) * CHUNK_TARGET_DURATION.as_secs_f64();
+
+        let vote = match ideal {
+            i if i >= (self.size * 2) as f64 => ChunkVote::Grow,
+            i if i <= (self.size / 2) as f64 => ChunkVote::Shrink,
+            _ => ChunkVote::Hold,
+        };
+
+        if vote == ChunkVote::Hold || vote != self.vote {
+            self.vote = vote;
+            self.votes = u8::from(vote != ChunkVote::Hold);
+            return;
+        }
+
+        self.votes += 1;
+
+        if self.votes < CHUNK_HYSTERESIS {
+            return;
+        }
+
+        match vote {
+            ChunkVote::Grow => self.resize(self.size.saturating_mul(2)),
+            ChunkVote::Shrink => self.resize(self.size / 2),
+            ChunkVote::Hold => {}
+        }
+    }
+
+    /// Counts a chunk whose send did not wait on the consumer, which says nothing about
+    /// throughput.
+    pub fn record_buffered(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.chunks += 1;
+    }
+
+    pub fn record_error(&mut self) {
+        self.errors += 1;
+        self.resize(self.size / 2);
+    }
+
+    pub fn summary(&self) -> ChunkSummary {
+        ChunkSummary {
+            bytes: self.bytes,
+            chunks: self.chunks,
+            elapsed: self.started.elapsed(),
+            errors: self.errors,
+            size_max: self.size_max,
+            size_min: self.size_min,
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        let size = size.clamp(self.bounds.min, self.bounds.max);
+
+        if size != self.size {
+            debug!("chunk size: {} -> {} bytes", self.size, size);
+        }
+
+        self.size = size;
+        self.size_max = self.size_max.max(self.size);
+        self.size_min = self.size_min.min(self.size);
+        self.vote = ChunkVote::Hold;
+        self.votes = 0;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChunkSummary {
+    pub bytes: usize,
+    pub chunks: usize,
+    pub elapsed: Duration,
+    pub errors: usize,
+    pub size_max: usize,
+    pub size_min: usize,
+}
+
+impl fmt::Display for ChunkSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let elapsed = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        let throughput = self.bytes as f64 / elapsed / (1024.0 * 1024.0);
+
+        write!(
+            f,
+            "{} bytes in {} chunks ({}-{} bytes/chunk, {} errors) at {:.2} MB/s",
+            self.bytes, self.chunks, self.size_min, self.size_max, self.errors, throughput
+        )
+    }
+}
+
+/// Streams `data` through a channel in adaptively sized chunks.
+///
+/// Once the channel buffer is full sends wait on the consumer, which makes the measured send
+/// time track the actual transfer rate of the stream.
+pub fn stream_chunks<T, F>(
+    data: Vec<u8>,
+    bounds: ChunkBounds,
+    message: F,
+) -> (Receiver<T>, JoinHandle<ChunkSummary>)
+where
+    T: Send + 'static,
+    F: Fn(Vec<u8>) -> T + Send + 'static,
+{
+    let (tx, rx) = channel(CHUNK_CHANNEL_SIZE);
+
+    let handle = tokio::spawn(async move {
+        let mut sizer = ChunkSizer::new(bounds);
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let end = (offset + sizer.size()).min(data.len());
+            let chunk = data[offset..end].to_vec();
+            let chunk_len = chunk.len();
+
+            wait_network_limit(chunk_len).await;
+
+            let chunk_buffered = tx.capacity() > 0;
+            let chunk_started = Instant::now();
+
+            if tx.send(message(chunk)).await.is_err() {
+                sizer.record_error();
+                break;
+            }
+
+            match chunk_buffered {
+                true => sizer.record_buffered(chunk_len),
+                false => sizer.record(chunk_len, chunk_started.elapsed()),
+            }
+
+            offset = end;
+        }
+
+        sizer.summary()
+    });
+
+    (rx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Throughput of adaptive and fixed sizes through a registry is benchmarked by the ignored
+    // `bench_push_chunk_sizes` in testing/tests/chunks.rs
+
+    const MB: usize = 1024 * 1024;
+
+    fn get_sizer() -> ChunkSizer {
+        ChunkSizer::new(ChunkBounds {
+            default: 2 * MB,
+            max: 8 * MB,
+            min: MB,
+        })
+    }
+
+    // Time a chunk of the current size takes at `ratio` times the rate that keeps it ideal
+    fn get_elapsed(ratio: f64) -> Duration {
+        CHUNK_TARGET_DURATION.div_f64(ratio)
+    }
+
+    #[test]
+    fn test_bounds_clamp_default() {
+        let bounds = ChunkBounds::new(4 * MB, 8 * MB);
+
+        assert_eq!(bounds.default, 4 * MB);
+        assert_eq!(ChunkSizer::new(bounds).size(), 4 * MB);
+
+        let bounds = ChunkBounds::new(8 * MB, MB);
+
+        assert_eq!(bounds.max, bounds.min);
+        assert!(bounds.max <= CHUNK_MESSAGE_SIZE_LIMIT / 2);
+    }
+
+    #[test]
+    fn test_grow_after_hysteresis() {
+        let mut sizer = get_sizer();
+
+        for _ in 0..CHUNK_HYSTERESIS - 1 {
+            sizer.record(2 * MB, get_elapsed(4.0));
+
+            assert_eq!(sizer.size(), 2 * MB);
+        }
+
+        sizer.record(2 * MB, get_elapsed(4.0));
+
+        assert_eq!(sizer.size(), 4 * MB);
+    }
+
+    #[test]
+    fn test_shrink_after_hysteresis() {
+        let mut sizer = get_sizer();
+
+        for _ in 0..CHUNK_HYSTERESIS {
+            sizer.record(2 * MB, get_elapsed(0.25));
+        }
+
+        assert_eq!(sizer.size(), MB);
+    }
+
+    #[test]
+    fn test_disagreeing_sample_resets_votes() {
+        let mut sizer = get_sizer();
+
+        sizer.record(2 * MB, get_elapsed(4.0));
+        sizer.record(2 * MB, get_elapsed(4.0));
+        sizer.record(2 * MB, get_elapsed(1.0));
+        sizer.record(2 * MB, get_elapsed(4.0));
+        sizer.record(2 * MB, get_elapsed(4.0));
+
+        assert_eq!(sizer.size(), 2 * MB);
+
+        sizer.record(2 * MB, get_elapsed(0.25));
+
+        assert_eq!(sizer.size(), 2 * MB);
+    }
+
+    #[test]
+    fn test_size_stays_within_bounds() {
+        let mut sizer = get_sizer();
+
+        for _ in 0..CHUNK_HYSTERESIS * 10 {
+            let size = sizer.size();
+
+            sizer.record(size, Duration::from_nanos(1));
+        }
+
+        assert_eq!(sizer.size(), 8 * MB);
+
+        for _ in 0..CHUNK_HYSTERESIS * 10 {
+            let size = sizer.size();
+
+            sizer.record(size, Duration::from_secs(60));
+        }
+
+        assert_eq!(sizer.size(), MB);
+
+        sizer.record_error();
+
+        assert_eq!(sizer.size(), MB);
+
+        let summary = sizer.summary();
+
+        assert_eq!((summary.size_min, summary.size_max), (MB, 8 * MB));
+        assert_eq!(summary.errors, 1);
+    }
+
+    #[test]
+    fn test_partial_and_buffered_chunks_do_not_vote() {
+        let mut sizer = get_sizer();
+
+        for _ in 0..CHUNK_HYSTERESIS * 2 {
+            sizer.record(MB, Duration::from_nanos(1));
+            sizer.record_buffered(2 * MB);
+        }
+
+        assert_eq!(sizer.size(), 2 * MB);
+
+        let summary = sizer.summary();
+
+        assert_eq!(summary.chunks, CHUNK_HYSTERESIS as usize * 4);
+        assert_eq!(summary.bytes, CHUNK_HYSTERESIS as usize * 6 * MB);
+    }
+
+    #[test]
+    fn test_record_error_halves_size() {
+        let mut sizer = get_sizer();
+
+        sizer.record_error();
+
+        assert_eq!(sizer.size(), MB);
+    }
+
+    #[tokio::test]
+    async fn test_stream_chunks_keeps_data() {
+        let data = (0..5 * MB + 7).map(|i| i as u8).collect::<Vec<_>>();
+
+        let (mut rx, handle) = stream_chunks(data.clone(), get_sizer().bounds, |chunk| chunk);
+
+        let mut received = vec![];
+
+        while let Some(chunk) = rx.recv().await {
+            assert!(chunk.len() <= 8 * MB);
+
+            received.extend(chunk);
+        }
+
+        let summary = handle.await.unwrap();
+
+        assert_eq!(received, data);
+        assert_eq!(summary.bytes, data.len());
+    }
+
+    #[test]
+    fn test_parse_network_limit() {
+        assert_eq!(parse_network_limit("1048576").unwrap(), MB as u64);
+        assert_eq!(parse_network_limit("512KB").unwrap(), 512 * 1024);
+        assert_eq!(parse_network_limit(" 10MB/s ").unwrap(), 10 * MB as u64);
+        assert_eq!(parse_network_limit("1.5g").unwrap(), 1536 * MB as u64);
+
+        assert!(parse_network_limit("0").is_err());
+        assert!(parse_network_limit("10XB").is_err());
+        assert!(parse_network_limit("MB").is_err());
+    }
+
+    #[test]
+    fn test_network_limiter_reserve() {
+        let limiter = NetworkLimiter::parse("1MB/s").unwrap();
+
+        assert_eq!(limiter.limit, "1MB");
+
+        // The bucket starts with one second of transfer, larger chunks leave it in debt
+
+        assert_eq!(limiter.reserve(MB / 2), Duration::ZERO);
+
+        let wait = limiter.reserve(MB);
+
+        assert!(wait > Duration::from_millis(400), "{:?}", wait);
+        assert!(wait <= Duration::from_millis(500), "{:?}", wait);
+    }
+}
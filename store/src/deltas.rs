@@ -0,0 +1,273 @@
+use crate::archives::ARCHIVE_ZSTD_LEVEL_DEFAULT;
+use anyhow::Result;
+use sha256::digest;
+use std::{collections::HashMap, io::Write, mem::take};
+use zstd::stream::Encoder;
+
+// Archives smaller than this are pushed whole, a delta would save less than its index costs
+pub const DELTA_ARCHIVE_SIZE_MIN: usize = 1024 * 1024; // 1MB
+
+// Deltas larger than this share of the whole archive are not worth the reconstruction
+pub const DELTA_SIZE_RATIO_MAX: f64 = 0.8;
+
+// Chunk boundaries follow the content, so an edit only changes the chunks around it
+const DELTA_CHUNK_SIZE_MIN: usize = 16 * 1024; // 16KB
+const DELTA_CHUNK_SIZE_MAX: usize = 256 * 1024; // 256KB
+
+// Boundary when the top 16 bits of the rolling hash are zero, 64KB chunks on average
+const DELTA_CHUNK_MASK: u64 = 0xffff << 48;
+
+const DELTA_GEAR: [u64; 256] = get_delta_gear();
+
+/// Returns the random values of the gear rolling hash (splitmix64), fixed so clients and
+/// registries agree on chunk boundaries.
+const fn get_delta_gear() -> [u64; 256] {
+    let mut gear = [0; 256];
+    let mut state: u64 = 0;
+    let mut index = 0;
+
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut value = state;
+
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        gear[index] = value ^ (value >> 31);
+
+        index += 1;
+    }
+
+    gear
+}
+
+fn get_delta_chunk_size(data: &[u8]) -> usize {
+    if data.len() <= DELTA_CHUNK_SIZE_MIN {
+        return data.len();
+    }
+
+    let size_max = data.len().min(DELTA_CHUNK_SIZE_MAX);
+
+    let mut hash: u64 = 0;
+
+    for (index, byte) in data[..size_max]
+        .iter()
+        .enumerate()
+        .skip(DELTA_CHUNK_SIZE_MIN)
+    {
+        hash = (hash << 1).wrapping_add(DELTA_GEAR[*byte as usize]);
+
+        if hash & DELTA_CHUNK_MASK == 0 {
+            return index + 1;
+        }
+    }
+
+    size_max
+}
+
+/// Splits `data` into content defined chunks.
+pub fn get_delta_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let size = get_delta_chunk_size(&data[offset..]);
+
+        chunks.push(&data[offset..offset + size]);
+
+        offset += size;
+    }
+
+    chunks
+}
+
+/// Splits data into the chunks of `get_delta_chunks` as it arrives, so large data is chunked
+/// without holding it in memory.
+#[derive(Debug, Default)]
+pub struct DeltaChunker {
+    buffer: Vec<u8>,
+}
+
+impl DeltaChunker {
+    /// Adds `data`, returning the chunks it completes.
+    pub fn update(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+
+        let mut chunks = vec![];
+        let mut offset = 0;
+
+        // A boundary is always found within the largest chunk size, later data can't move it
+
+        while self.buffer.len() - offset >= DELTA_CHUNK_SIZE_MAX {
+            let size = get_delta_chunk_size(&self.buffer[offset..]);
+
+            chunks.push(self.buffer[offset..offset + size].to_vec());
+
+            offset += size;
+        }
+
+        self.buffer.drain(..offset);
+
+        chunks
+    }
+
+    /// Returns the chunks of the remaining data.
+    pub fn finish(self) -> Vec<Vec<u8>> {
+        get_delta_chunks(&self.buffer)
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+}
+
+/// Returns the sha256 of a chunk, as listed in delta indexes.
+pub fn get_delta_chunk_digest(chunk: &[u8]) -> String {
+    digest(chunk)
+}
+
+/// Returns the sha256 of each chunk of `data`, which deltas against `data` refer to by index.
+pub fn get_delta_index(data: &[u8]) -> Vec<String> {
+    get_delta_chunks(data)
+        .into_iter()
+        .map(get_delta_chunk_digest)
+        .collect()
+}
+
+/// Part of data re-created from a base: one of its chunks, or new data.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeltaEntry<'a> {
+    Base(u32),
+    Data(&'a [u8]),
+}
+
+/// Returns the entries re-creating `data` from the chunks of a base (`get_delta_index`),
+/// with the chunks missing from the base merged into as few entries as possible.
+pub fn get_delta_entries<'a>(base_index: &[String], data: &'a [u8]) -> Vec<DeltaEntry<'a>> {
+    let base_chunks = base_index
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(index, digest)| (digest.as_str(), index as u32))
+        .collect::<HashMap<_, _>>();
+
+    let mut entries = vec![];
+    let mut offset = 0;
+
+    for chunk in get_delta_chunks(data) {
+        let chunk_offset = offset;
+
+        offset += chunk.len();
+
+        if let Some(index) = base_chunks.get(get_delta_chunk_digest(chunk).as_str()) {
+            entries.push(DeltaEntry::Base(*index));
+
+            continue;
+        }
+
+        // Chunks are consecutive slices of `data`, so new data merges by extending the slice
+
+        if let Some(DeltaEntry::Data(previous)) = entries.last_mut() {
+            *previous = &data[chunk_offset - previous.len()..offset];
+
+            continue;
+        }
+
+        entries.push(DeltaEntry::Data(chunk));
+    }
+
+    entries
+}
+
+/// Compresses `data` as a single zstd frame at the default archive level.
+///
+/// Unlike archives streamed to files, the output only depends on `data` and the zstd version,
+/// so the registry re-creates the archive of a delta push byte for byte and its signature
+/// still verifies.
+pub fn encode_delta(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeltaEncoder::new()?;
+
+    let mut encoded = encoder.write(data)?;
+
+    encoded.extend(encoder.finish()?);
+
+    Ok(encoded)
+}
+
+/// Compresses data like `encode_delta` as it arrives, for archives re-created from a delta
+/// without holding them in memory.
+pub struct DeltaEncoder {
+    encoder: Encoder<'static, Vec<u8>>,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            encoder: Encoder::new(vec![], ARCHIVE_ZSTD_LEVEL_DEFAULT)?,
+        })
+    }
+
+    /// Adds `data`, returning the compressed data produced so far.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.encoder.write_all(data)?;
+
+        Ok(take(self.encoder.get_mut()))
+    }
+
+    /// Returns the rest of the compressed data.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        Ok(self.encoder.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic data with repeats, so chunk boundaries fall at varying offsets
+    fn get_test_data(size: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+
+                (state >> 16) as u8 % 16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_delta_chunker_matches_get_delta_chunks() {
+        let data = get_test_data(3 * 1024 * 1024 + 17);
+
+        for piece_size in [1000, 64 * 1024, 1024 * 1024] {
+            let mut chunker = DeltaChunker::default();
+            let mut chunks = vec![];
+
+            for piece in data.chunks(piece_size) {
+                chunks.extend(chunker.update(piece));
+            }
+
+            chunks.extend(chunker.finish());
+
+            assert_eq!(chunks, get_delta_chunks(&data), "{}", piece_size);
+        }
+    }
+
+    #[test]
+    fn test_delta_encoder_matches_encode_delta() {
+        let data = get_test_data(2 * 1024 * 1024);
+
+        let mut encoder = DeltaEncoder::new().unwrap();
+        let mut encoded = vec![];
+
+        for piece in data.chunks(100 * 1024 + 3) {
+            encoded.extend(encoder.write(piece).unwrap());
+        }
+
+        encoded.extend(encoder.finish().unwrap());
+
+        assert_eq!(encoded, encode_delta(&data).unwrap());
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{bail, Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sha256::digest;
+use std::{fmt, str::FromStr};
+use tracing::warn;
+
+// Length of a hex encoded sha256 digest
+const DIGEST_LENGTH: usize = 64;
+
+fn validate_digest(kind: &str, value: &str) -> Result<()> {
+    if value.len() != DIGEST_LENGTH || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid {} digest: {:?}", kind, value);
+    }
+
+    Ok(())
+}
+
+/// Defines a sha256 digest newtype so digests of different kinds can not be mixed up.
+///
+/// The wrapped value is always a validated hex digest and serializes as a plain string.
+///
+/// ```
+/// use vorpal_store::{digests::ArtifactDigest, paths::get_artifact_path};
+///
+/// let digest = "0".repeat(64).parse::<ArtifactDigest>().unwrap();
+///
+/// get_artifact_path(&digest, "example");
+/// ```
+///
+/// A source digest is not accepted where an artifact digest is expected:
+///
+/// ```compile_fail
+/// use vorpal_store::{digests::SourceDigest, paths::get_artifact_path};
+///
+/// let digest = "0".repeat(64).parse::<SourceDigest>().unwrap();
+///
+/// get_artifact_path(&digest, "example");
+/// ```
+macro_rules! define_digest {
+    ($name:ident, $kind:literal) => {
+        #[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+        #[serde(try_from = "String", into = "String")]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn new(value: String) -> Result<Self> {
+                validate_digest($kind, &value)?;
+
+                Ok(Self(value))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(value: &str) -> Result<Self> {
+                Self::new(value.to_string())
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = Error;
+
+            fn try_from(value: String) -> Result<Self> {
+                Self::new(value)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+    };
+}
+
+define_digest!(ArtifactDigest, "artifact");
+define_digest!(ArchiveDigest, "archive");
+define_digest!(SourceDigest, "source");
+define_digest!(StepDigest, "step");
+
+impl ArtifactDigest {
+    /// Digest of a serialized artifact manifest.
+    pub fn from_manifest(manifest: &[u8]) -> Self {
+        Self(digest(manifest))
+    }
+}
+
+impl StepDigest {
+    /// Digest of serialized step inputs.
+    pub fn from_inputs(inputs: &[u8]) -> Self {
+        Self(digest(inputs))
+    }
+}
+
+impl ArchiveDigest {
+    /// Digest of the compressed archive bytes.
+    pub fn from_data(data: &[u8]) -> Self {
+        Self(digest(data))
+    }
+
+    /// Digest of archive bytes fed to `hasher` as they were streamed.
+    pub fn from_hasher(hasher: Sha256) -> Self {
+        Self(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Fails unless `data` hashes to this digest.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        self.verify_digest(&Self::from_data(data))
+    }
+
+    /// Fails unless `actual` matches this digest.
+    pub fn verify_digest(&self, actual: &Self) -> Result<()> {
+        if actual != self {
+            bail!("archive digest mismatch: expected {}, got {}", self, actual);
+        }
+
+        Ok(())
+    }
+}
+
+/// Verifies pulled data against the digest the registry sent with it.
+///
+/// No digest means the data was pushed before digests were recorded, so it is accepted with a
+/// warning.
+pub fn verify_pulled_data(
+    data_digest: Option<&ArchiveDigest>,
+    data: &[u8],
+    name: &str,
+) -> Result<()> {
+    verify_pulled_digest(data_digest, &ArchiveDigest::from_data(data), name)
+}
+
+/// Verifies the digest of pulled data hashed while streaming against the digest the registry
+/// sent with it, accepting a missing digest like `verify_pulled_data`.
+pub fn verify_pulled_digest(
+    data_digest: Option<&ArchiveDigest>,
+    actual: &ArchiveDigest,
+    name: &str,
+) -> Result<()> {
+    let Some(data_digest) = data_digest else {
+        warn!(
+            "no digest recorded for pulled data, skipping verification: {}",
+            name
+        );
+
+        return Ok(());
+    };
+
+    data_digest.verify_digest(actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archives::{compress_zstd, unpack_archive};
+    use std::path::Path;
+    use tokio::fs::{create_dir_all, read, write};
+
+    async fn get_test_archive(path: &Path) -> Vec<u8> {
+        let source_path = path.join("source");
+
+        create_dir_all(&source_path).await.unwrap();
+
+        for index in 0..16 {
+            write(
+                source_path.join(format!("file-{}.txt", index)),
+                format!("{}", index).repeat(4096),
+            )
+            .await
+            .unwrap();
+        }
+
+        let source_files = (0..16)
+            .map(|index| source_path.join(format!("file-{}.txt", index)))
+            .collect::<Vec<_>>();
+
+        let archive_path = path.join("archive.tar.zst");
+
+        compress_zstd(&source_path, &source_files, &archive_path)
+            .await
+            .unwrap();
+
+        read(archive_path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_pulled_data_rejects_corrupt_data() {
+        let temp = tempfile::tempdir().unwrap();
+
+        let data = get_test_archive(temp.path()).await;
+        let data_digest = ArchiveDigest::from_data(&data);
+
+        // Intact data is verified and unpacked
+
+        let archive_path = temp.path().join("pulled.tar.zst");
+        let target_path = temp.path().join("intact");
+
+        verify_pulled_data(Some(&data_digest), &data, "example").unwrap();
+
+        write(&archive_path, &data).await.unwrap();
+
+        unpack_archive(&target_path, &archive_path).await.unwrap();
+
+        assert!(target_path.join("file-0.txt").exists());
+
+        // A flipped byte, as a faulty disk or proxy would leave it, is rejected
+
+        let mut corrupt = data;
+        let middle = corrupt.len() / 2;
+
+        corrupt[middle] ^= 0xff;
+
+        let err = verify_pulled_data(Some(&data_digest), &corrupt, "example").unwrap_err();
+
+        assert!(err.to_string().contains("digest mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_pulled_data() {
+        let data = b"archive";
+        let digest = ArchiveDigest::from_data(data);
+
+        assert!(verify_pulled_data(Some(&digest), data, "example").is_ok());
+        assert!(verify_pulled_data(Some(&digest), b"tampered", "example").is_err());
+        assert!(verify_pulled_data(None, b"tampered", "example").is_ok());
+    }
+
+    #[test]
+    fn test_parse_digest() {
+        assert!("0".repeat(64).parse::<ArchiveDigest>().is_ok());
+        assert!("0".repeat(63).parse::<ArchiveDigest>().is_err());
+        assert!("z".repeat(64).parse::<ArchiveDigest>().is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::chunks::CHUNK_MESSAGE_SIZE_LIMIT;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use std::{env, fs::read, io::ErrorKind};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{
+        Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig,
+    },
+    Code, Request, Status,
+};
+use tracing::{info, warn};
+use vorpal_schema::vorpal::registry::v0::{
+    registry_service_client::RegistryServiceClient, RegistryRequest,
+};
+
+// Path to a PEM CA certificate trusted for `https://` services, besides the public roots
+pub const TLS_CA_ENV: &str = "VORPAL_TLS_CA";
+
+// Paths to a PEM certificate and key presented to services requiring client authentication
+pub const TLS_CLIENT_CERT_ENV: &str = "VORPAL_TLS_CLIENT_CERT";
+pub const TLS_CLIENT_KEY_ENV: &str = "VORPAL_TLS_CLIENT_KEY";
+
+// Shared agent config processes download remote sources through
+pub const AGENT_ENV: &str = "VORPAL_AGENT";
+
+// Token sent to registries requiring authentication
+pub const REGISTRY_TOKEN_ENV: &str = "VORPAL_REGISTRY_TOKEN";
+
+// Largest message of the artifact and config services, which carry whole artifacts
+pub const DEFAULT_MESSAGE_SIZE_MAX: usize = 16 * 1024 * 1024; // 16MB
+
+// Overrides `DEFAULT_MESSAGE_SIZE_MAX`, exported so config processes use the same limit
+pub const MESSAGE_SIZE_MAX_ENV: &str = "VORPAL_MAX_MESSAGE_SIZE";
+
+pub type RegistryChannel = InterceptedService<Channel, RegistryTokenInterceptor>;
+
+/// Adds `authorization: Bearer <token>` to registry requests when a token is set.
+#[derive(Clone, Debug, Default)]
+pub struct RegistryTokenInterceptor {
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl RegistryTokenInterceptor {
+    pub fn new(token: Option<&str>) -> Result<Self> {
+        let authorization = match token.map(str::trim).filter(|token| !token.is_empty()) {
+            Some(token) => Some(
+                format!("Bearer {}", token)
+                    .parse()
+                    .map_err(|_| anyhow!("registry token contains invalid characters"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self { authorization })
+    }
+}
+
+impl Interceptor for RegistryTokenInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+
+        Ok(request)
+    }
+}
+
+/// Returns the message size limit of the artifact and config services.
+pub fn get_max_message_size() -> usize {
+    env::var(MESSAGE_SIZE_MAX_ENV)
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MESSAGE_SIZE_MAX)
+}
+
+fn read_pem(path: &str, kind: &str) -> Result<Vec<u8>> {
+    read(path).with_context(|| format!("failed to read tls {}: {}", kind, path))
+}
+
+fn get_client_tls_config(host: &str) -> Result<ClientTlsConfig> {
+    let mut config = ClientTlsConfig::new().domain_name(host).with_webpki_roots();
+
+    if let Ok(path) = env::var(TLS_CA_ENV) {
+        config = config.ca_certificate(Certificate::from_pem(read_pem(&path, "ca")?));
+    }
+
+    match (env::var(TLS_CLIENT_CERT_ENV), env::var(TLS_CLIENT_KEY_ENV)) {
+        (Ok(cert_path), Ok(key_path)) => {
+            let cert = read_pem(&cert_path, "client certificate")?;
+            let key = read_pem(&key_path, "client key")?;
+
+            config = config.identity(Identity::from_pem(cert, key));
+        }
+
+        (Err(_), Err(_)) => {}
+
+        _ => bail!(
+            "{} and {} must be set together",
+            TLS_CLIENT_CERT_ENV,
+            TLS_CLIENT_KEY_ENV
+        ),
+    }
+
+    Ok(config)
+}
+
+/// Describes a failed connection, telling TLS handshake failures apart from network failures.
+fn get_connect_error(url: &str, error: tonic::transport::Error) -> Error {
+    let mut source = std::error::Error::source(&error);
+
+    while let Some(err) = source {
+        if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+            // rustls reports handshake and certificate failures as invalid data
+
+            if io_error.kind() == ErrorKind::InvalidData {
+                return anyhow!("tls handshake with {} failed: {}", url, io_error);
+            }
+
+            return anyhow!("failed to reach {}: {}", url, io_error);
+        }
+
+        source = err.source();
+    }
+
+    anyhow!("failed to connect to {}: {}", url, error)
+}
+
+/// Connects to a vorpal service, using TLS for `https://` URLs.
+///
+/// Servers are verified against the public roots and `VORPAL_TLS_CA`, and
+/// `VORPAL_TLS_CLIENT_CERT` with `VORPAL_TLS_CLIENT_KEY` are presented when set.
+pub async fn get_channel(url: &str) -> Result<Channel> {
+    let mut endpoint = Endpoint::from_shared(url.to_string())
+        .with_context(|| format!("invalid service url: {}", url))?;
+
+    if endpoint.uri().scheme_str() == Some("https") {
+        let host = endpoint
+            .uri()
+            .host()
+            .ok_or_else(|| anyhow!("service url is missing host: {}", url))?
+            .to_string();
+
+        endpoint = endpoint.tls_config(get_client_tls_config(&host)?)?;
+    }
+
+    endpoint
+        .connect()
+        .await
+        .map_err(|err| get_connect_error(url, err))
+}
+
+/// Returns a server builder serving TLS with `cert` and `key`, requiring clients to present a
+/// certificate signed by `client_ca` when set.
+pub fn get_server_builder(
+    cert: Option<&str>,
+    key: Option<&str>,
+    client_ca: Option<&str>,
+) -> Result<Server> {
+    let (cert, key) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if client_ca.is_none() => return Ok(Server::builder()),
+        (None, None) => bail!("'--tls-client-ca' requires '--tls-cert' and '--tls-key'"),
+        _ => bail!("'--tls-cert' and '--tls-key' must be set together"),
+    };
+
+    let mut config = ServerTlsConfig::new().identity(Identity::from_pem(
+        read_pem(cert, "certificate")?,
+        read_pem(key, "key")?,
+    ));
+
+    if let Some(client_ca) = client_ca {
+        config = config.client_ca_root(Certificate::from_pem(read_pem(client_ca, "client ca")?));
+    }
+
+    Ok(Server::builder().tls_config(config)?)
+}
+
+/// Returns `VORPAL_REGISTRY_TOKEN` when set.
+pub fn get_registry_token() -> Option<String> {
+    env::var(REGISTRY_TOKEN_ENV).ok()
+}
+
+// Tokens are only sent over TLS, or to this machine where plain `http://` does not leave it
+fn is_token_url_secure(url: &str) -> bool {
+    let Ok(endpoint) = Endpoint::from_shared(url.to_string()) else {
+        return false;
+    };
+
+    let uri = endpoint.uri();
+
+    uri.scheme_str() == Some("https")
+        || matches!(uri.host(), Some("localhost" | "127.0.0.1" | "[::1]"))
+}
+
+/// Connects to a registry with `get_channel`, sending `token` when set. Tokens are refused for
+/// plain `http://` registries on other machines, where they would be sent in the clear.
+pub async fn get_registry_channel(url: &str, token: Option<&str>) -> Result<RegistryChannel> {
+    let interceptor = RegistryTokenInterceptor::new(token)?;
+
+    if interceptor.authorization.is_some() && !is_token_url_secure(url) {
+        bail!(
+            "refusing to send the registry token over plain http to {}, use an https:// registry",
+            url
+        );
+    }
+
+    Ok(InterceptedService::new(
+        get_channel(url).await?,
+        interceptor,
+    ))
+}
+
+/// Registries in order of priority for checks and pulls, and the one of them pushes go to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegistryUrls {
+    pub push: String,
+    pub urls: Vec<String>,
+}
+
+impl RegistryUrls {
+    /// Pushes go to `push` when set, which must be one of `urls`, or the first of `urls`.
+    pub fn new(urls: Vec<String>, push: Option<String>) -> Result<Self> {
+        let Some(first) = urls.first() else {
+            bail!("no registry set");
+        };
+
+        let push = push.unwrap_or_else(|| first.clone());
+
+        if !urls.contains(&push) {
+            bail!("push registry must also be set with '--registry': {}", push);
+        }
+
+        Ok(Self { push, urls })
+    }
+
+    pub async fn connect(&self) -> Result<RegistryClients> {
+        RegistryClients::connect(self).await
+    }
+
+    /// Returns the token sent to `url`. Only the push registry receives `VORPAL_REGISTRY_TOKEN`,
+    /// other registries are often mirrors run by someone else and are read without one.
+    pub fn get_token(&self, url: &str) -> Option<String> {
+        match url == self.push {
+            true => get_registry_token(),
+            false => None,
+        }
+    }
+
+    /// Connects to registry `url` with its token (see `get_token`).
+    pub async fn get_channel(&self, url: &str) -> Result<RegistryChannel> {
+        get_registry_channel(url, self.get_token(url).as_deref()).await
+    }
+}
+
+/// Client of one registry, with its URL for logs.
+#[derive(Clone)]
+pub struct RegistryClient {
+    pub client: RegistryServiceClient<RegistryChannel>,
+    pub url: String,
+}
+
+/// Clients of the registries in `RegistryUrls`. Registries that can not be reached are skipped
+/// with a warning, and pushes fail when the push registry is one of them.
+#[derive(Clone)]
+pub struct RegistryClients {
+    clients: Vec<RegistryClient>,
+    push: Option<RegistryClient>,
+    push_url: String,
+}
+
+impl RegistryClients {
+    async fn connect(urls: &RegistryUrls) -> Result<Self> {
+        let mut clients = vec![];
+        let mut error = None;
+        let mut push = None;
+
+        for url in urls.urls.iter() {
+            let channel = match urls.get_channel(url).await {
+                Ok(channel) => channel,
+                Err(err) if urls.urls.len() > 1 => {
+                    warn!("skipping registry {}: {:#}", url, err);
+
+                    error = Some(err);
+
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            let client = RegistryClient {
+                client: RegistryServiceClient::new(channel)
+                    .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT),
+                url: url.clone(),
+            };
+
+            if *url == urls.push {
+                push = Some(client.clone());
+            }
+
+            clients.push(client);
+        }
+
+        if clients.is_empty() {
+            return Err(error.unwrap_or_else(|| anyhow!("no registry set")));
+        }
+
+        Ok(Self {
+            clients,
+            push,
+            push_url: urls.push.clone(),
+        })
+    }
+
+    pub fn clients(&self) -> &[RegistryClient] {
+        &self.clients
+    }
+
+    pub fn push(&self) -> Result<RegistryServiceClient<RegistryChannel>> {
+        match &self.push {
+            Some(push) => Ok(push.client.clone()),
+            None => bail!("push registry is unreachable: {}", self.push_url),
+        }
+    }
+
+    /// Returns the first registry with `request`, in order of priority. Errors other than
+    /// `NotFound` move on to the next registry with a warning, and are only returned when no
+    /// registry answered.
+    pub async fn find(&self, request: &RegistryRequest) -> Result<Option<RegistryClient>, Status> {
+        let mut answered = false;
+        let mut error = None;
+
+        for (index, registry) in self.clients.iter().enumerate() {
+            match registry.client.clone().exists(request.clone()).await {
+                Ok(_) => {
+                    if self.clients.len() > 1 {
+                        info!(
+                            "found {}-{} in registry {}",
+                            request.name, request.hash, registry.url
+                        );
+                    }
+
+                    return Ok(Some(registry.clone()));
+                }
+
+                Err(status) if status.code() == Code::NotFound => answered = true,
+
+                Err(status) => {
+                    if index + 1 < self.clients.len() {
+                        warn!(
+                            "registry {} failed to check {}-{}, trying the next registry: {}",
+                            registry.url,
+                            request.name,
+                            request.hash,
+                            status.message()
+                        );
+                    }
+
+                    error = Some(status);
+                }
+            }
+        }
+
+        match (answered, error) {
+            (false, Some(status)) => Err(status),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_urls_token() {
+        let registries = RegistryUrls::new(
+            vec![
+                "http://mirror.example.com:23151".to_string(),
+                "https://registry.example.com".to_string(),
+            ],
+            Some("https://registry.example.com".to_string()),
+        )
+        .unwrap();
+
+        std::env::set_var(REGISTRY_TOKEN_ENV, "secret");
+
+        let push_token = registries.get_token("https://registry.example.com");
+        let mirror_token = registries.get_token("http://mirror.example.com:23151");
+
+        std::env::remove_var(REGISTRY_TOKEN_ENV);
+
+        assert_eq!(push_token.as_deref(), Some("secret"));
+        assert_eq!(mirror_token, None);
+
+        assert_eq!(registries.get_token("https://registry.example.com"), None);
+    }
+
+    #[test]
+    fn test_is_token_url_secure() {
+        assert!(is_token_url_secure("https://registry.example.com"));
+        assert!(is_token_url_secure("http://localhost:23151"));
+        assert!(is_token_url_secure("http://127.0.0.1:23151"));
+        assert!(is_token_url_secure("http://[::1]:23151"));
+
+        assert!(!is_token_url_secure("http://registry.example.com:23151"));
+        assert!(!is_token_url_secure("http://10.0.0.2:23151"));
+        assert!(!is_token_url_secure("not a url"));
+    }
+
+    #[tokio::test]
+    async fn test_registry_channel_refuses_token_over_http() {
+        let err = get_registry_channel("http://registry.example.com:23151", Some("secret"))
+            .await
+            .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("refusing to send the registry token"));
+    }
+
+    #[test]
+    fn test_registry_token_interceptor() {
+        let mut interceptor = RegistryTokenInterceptor::new(Some(" secret\n")).unwrap();
+
+        let request = interceptor.call(Request::new(())).unwrap();
+
+        assert_eq!(
+            request.metadata().get("authorization").unwrap(),
+            "Bearer secret"
+        );
+
+        for token in [None, Some(""), Some("  ")] {
+            let mut interceptor = RegistryTokenInterceptor::new(token).unwrap();
+
+            let request = interceptor.call(Request::new(())).unwrap();
+
+            assert!(request.metadata().get("authorization").is_none());
+        }
+
+        assert!(RegistryTokenInterceptor::new(Some("sec\u{7f}ret")).is_err());
+    }
+}
@@ -1,23 +1,57 @@
+use crate::digests::SourceDigest;
 use anyhow::Result;
-use sha256::{digest, try_digest};
-use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use sha256::digest;
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
+// Bytes read from a file per hasher update, bounding memory per concurrently hashed file
+const FILE_HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Hashes a file by streaming its contents through the hasher.
 pub fn get_file_hash<P: AsRef<Path> + Send>(path: P) -> Result<String> {
     if !path.as_ref().is_file() {
         return Err(anyhow::anyhow!("Path is not a file"));
     }
 
-    Ok(try_digest(path).expect("Failed to get file hash"))
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; FILE_HASH_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
-pub fn get_file_hashes(files: &[PathBuf]) -> Result<Vec<String>> {
-    let hashes = files
-        .iter()
-        .filter(|file| file.is_file())
-        .map(|file| get_file_hash(file).unwrap())
-        .collect();
+/// Hashes the target of a symlink so changing where a link points changes the digest.
+pub fn get_symlink_hash<P: AsRef<Path>>(path: P) -> Result<String> {
+    let target = std::fs::read_link(path)?;
+
+    Ok(digest(format!("symlink:{}", target.display())))
+}
 
-    Ok(hashes)
+/// Hashes files and symlinks concurrently, returning the hashes in the order of `files`.
+pub fn get_file_hashes(files: &[PathBuf]) -> Result<Vec<String>> {
+    files
+        .par_iter()
+        .filter(|file| file.is_symlink() || file.is_file())
+        .map(|file| match file.is_symlink() {
+            true => get_symlink_hash(file),
+            false => get_file_hash(file),
+        })
+        .collect()
 }
 
 pub fn get_hashes_digest(hashes: Vec<String>) -> Result<String> {
@@ -30,7 +64,7 @@ pub fn get_hashes_digest(hashes: Vec<String>) -> Result<String> {
     Ok(digest(combined))
 }
 
-pub fn hash_files(paths: Vec<PathBuf>) -> Result<String> {
+pub fn hash_files(paths: Vec<PathBuf>) -> Result<SourceDigest> {
     if paths.is_empty() {
         anyhow::bail!("no source files found")
     }
@@ -39,9 +73,83 @@ pub fn hash_files(paths: Vec<PathBuf>) -> Result<String> {
 
     let paths_hashes_joined = get_hashes_digest(paths_hashes)?;
 
-    Ok(paths_hashes_joined)
+    SourceDigest::new(paths_hashes_joined)
 }
 
 pub fn get_hash_digest(hash: &str) -> String {
     digest(hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::write, os::unix::fs::symlink};
+
+    // Hashes `files` one at a time reading each whole, like `hash_files` before it streamed
+    fn get_file_hashes_sequential(files: &[PathBuf]) -> Vec<String> {
+        files
+            .iter()
+            .filter(|file| file.is_symlink() || file.is_file())
+            .map(|file| match file.is_symlink() {
+                true => get_symlink_hash(file).unwrap(),
+                false => digest(std::fs::read(file).unwrap()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_get_file_hashes_matches_sequential() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut files = vec![];
+
+        for index in 0..500 {
+            let path = root.path().join(format!("file-{}", index));
+
+            write(&path, format!("contents {}", index).repeat(index)).unwrap();
+
+            files.push(path);
+        }
+
+        // Files spanning several buffer reads, empty files, symlinks and directories
+
+        let large_path = root.path().join("large");
+        let large = (0..FILE_HASH_BUFFER_SIZE * 3 + 17)
+            .map(|index| (index % 251) as u8)
+            .collect::<Vec<_>>();
+
+        write(&large_path, large).unwrap();
+
+        let empty_path = root.path().join("empty");
+
+        write(&empty_path, "").unwrap();
+
+        let link_path = root.path().join("link");
+
+        symlink("file-1", &link_path).unwrap();
+
+        let dir_path = root.path().join("dir");
+
+        std::fs::create_dir(&dir_path).unwrap();
+
+        files.extend([large_path, empty_path, link_path, dir_path]);
+
+        let sequential = get_file_hashes_sequential(&files);
+
+        assert_eq!(sequential.len(), files.len() - 1);
+        assert_eq!(get_file_hashes(&files).unwrap(), sequential);
+
+        assert_eq!(
+            hash_files(files).unwrap(),
+            SourceDigest::new(get_hashes_digest(sequential).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_file_hashes_missing_file() {
+        let root = tempfile::tempdir().unwrap();
+
+        assert!(get_file_hash(root.path().join("missing")).is_err());
+        assert!(hash_files(vec![]).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use reqwest::{Certificate, Client, ClientBuilder};
+use std::{env, fs::read};
+use tracing::warn;
+
+// Path to a PEM bundle of extra trusted certificates
+pub const CA_BUNDLE_ENV: &str = "VORPAL_CA_BUNDLE";
+
+// Disables certificate verification when set to "1" or "true"
+pub const INSECURE_SKIP_TLS_VERIFY_ENV: &str = "VORPAL_INSECURE_SKIP_TLS_VERIFY";
+
+fn is_insecure_skip_tls_verify() -> bool {
+    env::var(INSECURE_SKIP_TLS_VERIFY_ENV)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Returns the builder shared by all outbound HTTP clients.
+///
+/// Proxies are taken from `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` by reqwest, extra roots
+/// from `VORPAL_CA_BUNDLE`, and `VORPAL_INSECURE_SKIP_TLS_VERIFY` disables verification.
+pub fn get_http_client_builder() -> Result<ClientBuilder> {
+    let mut builder = Client::builder();
+
+    if let Ok(path) = env::var(CA_BUNDLE_ENV) {
+        let bundle = read(&path).with_context(|| format!("failed to read ca bundle: {}", path))?;
+
+        let certificates = Certificate::from_pem_bundle(&bundle)
+            .with_context(|| format!("failed to parse ca bundle: {}", path))?;
+
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if is_insecure_skip_tls_verify() {
+        warn!("TLS certificate verification is DISABLED - only use this on isolated networks");
+
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+pub fn get_http_client() -> Result<Client> {
+    Ok(get_http_client_builder()?.build()?)
+}
@@ -0,0 +1,310 @@
+use crate::paths::{get_incremental_dir_path, get_incremental_path};
+use anyhow::{anyhow, Result};
+use std::{
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::warn;
+use walkdir::WalkDir;
+
+// Bytes of incremental directories kept by a worker, least recently used ones are evicted above
+pub const INCREMENTAL_SIZE_MAX: u64 = 20 * 1024 * 1024 * 1024;
+
+/// Moves the directory kept for `key` to `target_path`, returning whether one was restored.
+///
+/// Directories are moved rather than copied, so concurrent builds with the same key never share
+/// one. A directory that can not be restored is removed and the step starts without it.
+pub fn restore_incremental(key: &str, target_path: &Path) -> Result<bool> {
+    restore_incremental_path(&get_incremental_path(key), target_path)
+}
+
+fn restore_incremental_path(path: &Path, target_path: &Path) -> Result<bool> {
+    if !path.exists() && !path.is_symlink() {
+        return Ok(false);
+    }
+
+    // Anything but a directory was left by an interrupted save or written by hand
+
+    if !path.is_dir() || path.is_symlink() {
+        warn!(
+            "incremental directory {} is not a directory, building clean",
+            path.display()
+        );
+
+        remove_file(path).map_err(|e| anyhow!("failed to remove {}: {}", path.display(), e))?;
+
+        return Ok(false);
+    }
+
+    if let Some(parent) = target_path.parent() {
+        create_dir_all(parent)
+            .map_err(|e| anyhow!("failed to create {}: {}", parent.display(), e))?;
+    }
+
+    if target_path.exists() {
+        remove_dir_all(target_path)
+            .map_err(|e| anyhow!("failed to remove {}: {}", target_path.display(), e))?;
+    }
+
+    if let Err(err) = rename(path, target_path) {
+        warn!(
+            "incremental directory {} is unusable, building clean: {}",
+            path.display(),
+            err
+        );
+
+        let _ = remove_dir_all(path);
+
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Moves `source_path` back as the directory kept for `key`, replacing the previous one, then
+/// evicts least recently used directories above `INCREMENTAL_SIZE_MAX`.
+pub fn save_incremental(key: &str, source_path: &Path) -> Result<()> {
+    save_incremental_path(
+        &get_incremental_dir_path(),
+        key,
+        source_path,
+        INCREMENTAL_SIZE_MAX,
+    )
+}
+
+fn save_incremental_path(
+    dir_path: &Path,
+    key: &str,
+    source_path: &Path,
+    size_max: u64,
+) -> Result<()> {
+    if !source_path.is_dir() {
+        return Ok(());
+    }
+
+    create_dir_all(dir_path)
+        .map_err(|e| anyhow!("failed to create {}: {}", dir_path.display(), e))?;
+
+    let path = dir_path.join(key);
+
+    if path.exists() {
+        remove_dir_all(&path).map_err(|e| anyhow!("failed to remove {}: {}", path.display(), e))?;
+    }
+
+    rename(source_path, &path).map_err(|e| {
+        anyhow!(
+            "failed to move {} to {}: {}",
+            source_path.display(),
+            path.display(),
+            e
+        )
+    })?;
+
+    // Renames keep the modification time, which orders evictions
+
+    File::open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .map_err(|e| anyhow!("failed to touch {}: {}", path.display(), e))?;
+
+    evict_incrementals_path(dir_path, size_max)?;
+
+    Ok(())
+}
+
+fn get_dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Removes the least recently used incremental directories until the rest fit in `size_max`
+/// bytes, returning their paths.
+pub fn evict_incrementals(size_max: u64) -> Result<Vec<PathBuf>> {
+    evict_incrementals_path(&get_incremental_dir_path(), size_max)
+}
+
+fn evict_incrementals_path(dir_path: &Path, size_max: u64) -> Result<Vec<PathBuf>> {
+    if !dir_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+
+    for entry in read_dir(dir_path)? {
+        let entry = entry?;
+        let modified = entry.metadata()?.modified()?;
+        let path = entry.path();
+
+        entries.push((modified, get_dir_size(&path), path));
+    }
+
+    entries.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut size = 0;
+    let mut evicted = vec![];
+
+    for (_, entry_size, path) in entries {
+        size += entry_size;
+
+        if size <= size_max {
+            continue;
+        }
+
+        remove_dir_all(&path).map_err(|e| anyhow!("failed to remove {}: {}", path.display(), e))?;
+
+        evicted.push(path);
+    }
+
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::write,
+        os::unix::fs::symlink,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    // Writes an incremental directory of `size` bytes last used at `modified` seconds
+    fn write_incremental(dir_path: &Path, key: &str, size: usize, modified: u64) -> PathBuf {
+        let path = dir_path.join(key);
+
+        create_dir_all(path.join("debug")).unwrap();
+
+        write(path.join("debug").join("data"), vec![0; size]).unwrap();
+
+        File::open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+            .unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_save_restore_incremental() {
+        let root = tempfile::tempdir().unwrap();
+        let dir_path = root.path().join("incremental");
+        let target_path = root.path().join("build").join("target");
+
+        assert!(!restore_incremental_path(&dir_path.join("key"), &target_path).unwrap());
+
+        create_dir_all(target_path.join("debug")).unwrap();
+        write(target_path.join("debug").join("data"), "first").unwrap();
+
+        save_incremental_path(&dir_path, "key", &target_path, u64::MAX).unwrap();
+
+        assert!(!target_path.exists());
+        assert!(dir_path.join("key").join("debug").join("data").exists());
+
+        // Restoring replaces whatever the step left at the target
+
+        create_dir_all(&target_path).unwrap();
+        write(target_path.join("stale"), "stale").unwrap();
+
+        assert!(restore_incremental_path(&dir_path.join("key"), &target_path).unwrap());
+
+        assert!(!dir_path.join("key").exists());
+        assert!(!target_path.join("stale").exists());
+        assert_eq!(
+            std::fs::read_to_string(target_path.join("debug").join("data")).unwrap(),
+            "first"
+        );
+
+        // Saving again replaces the previous directory of the key
+
+        write_incremental(&dir_path, "key", 1, 0);
+
+        write(target_path.join("debug").join("data"), "second").unwrap();
+
+        save_incremental_path(&dir_path, "key", &target_path, u64::MAX).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir_path.join("key").join("debug").join("data")).unwrap(),
+            "second"
+        );
+
+        // Missing build outputs leave the kept directory alone
+
+        save_incremental_path(&dir_path, "key", &target_path, u64::MAX).unwrap();
+
+        assert!(dir_path.join("key").exists());
+    }
+
+    #[test]
+    fn test_evict_incrementals_least_recently_used() {
+        let root = tempfile::tempdir().unwrap();
+        let dir_path = root.path().join("incremental");
+
+        let oldest = write_incremental(&dir_path, "oldest", 100, 1);
+        let older = write_incremental(&dir_path, "older", 100, 2);
+        let newest = write_incremental(&dir_path, "newest", 100, 3);
+
+        assert!(evict_incrementals_path(&dir_path, 300).unwrap().is_empty());
+
+        assert_eq!(
+            evict_incrementals_path(&dir_path, 250).unwrap(),
+            vec![oldest.clone()]
+        );
+
+        assert!(!oldest.exists());
+        assert!(older.exists());
+        assert!(newest.exists());
+
+        // Saving marks the directory as most recently used
+
+        let target_path = root.path().join("target");
+
+        create_dir_all(&target_path).unwrap();
+        write(target_path.join("data"), vec![0; 100]).unwrap();
+
+        save_incremental_path(&dir_path, "saved", &target_path, 200).unwrap();
+
+        assert!(!older.exists());
+        assert!(newest.exists());
+        assert!(dir_path.join("saved").exists());
+
+        assert!(evict_incrementals_path(&root.path().join("missing"), 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_restore_incremental_unusable() {
+        let root = tempfile::tempdir().unwrap();
+        let dir_path = root.path().join("incremental");
+        let target_path = root.path().join("build").join("target");
+
+        // A file or a link in place of the directory is removed and the build starts clean
+
+        create_dir_all(&dir_path).unwrap();
+
+        write(dir_path.join("file"), "corrupt").unwrap();
+
+        assert!(!restore_incremental_path(&dir_path.join("file"), &target_path).unwrap());
+        assert!(!dir_path.join("file").exists());
+        assert!(!target_path.exists());
+
+        let linked = write_incremental(&dir_path, "linked", 1, 0);
+
+        symlink(&linked, dir_path.join("link")).unwrap();
+
+        assert!(!restore_incremental_path(&dir_path.join("link"), &target_path).unwrap());
+        assert!(!dir_path.join("link").is_symlink());
+        assert!(linked.exists());
+
+        // A directory that can not be moved to the target is removed
+
+        let path = write_incremental(&dir_path, "key", 1, 0);
+
+        assert!(!restore_incremental_path(&path, &path.join("target")).unwrap());
+        assert!(!path.exists());
+    }
+}
@@ -1,4 +1,16 @@
 pub mod archives;
+pub mod blobs;
+pub mod caches;
+pub mod chunks;
+pub mod deltas;
+pub mod dictionaries;
+pub mod digests;
+pub mod grpc;
 pub mod hashes;
+pub mod http;
+pub mod incrementals;
+pub mod manifests;
+pub mod metrics;
+pub mod oci;
 pub mod paths;
 pub mod temps;
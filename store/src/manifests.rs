@@ -0,0 +1,167 @@
+use crate::{
+    digests::ArtifactDigest,
+    paths::{get_manifest_dir_path, get_manifest_path},
+    temps::create_sandbox_file,
+};
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use tokio::fs::{create_dir_all, read, read_dir, remove_file, rename, write};
+use tracing::warn;
+use vorpal_schema::vorpal::artifact::v0::{
+    Artifact, ArtifactBuildRequest, ArtifactPushPolicy, ArtifactSystem,
+};
+
+// Suffix of manifest files in the manifest directory
+const MANIFEST_SUFFIX: &str = ".json";
+
+/// Serializes the manifest of `artifact` for `system`, returning it with the digest that
+/// identifies the artifact.
+pub fn get_artifact_manifest(
+    artifact: &Artifact,
+    system: ArtifactSystem,
+) -> Result<(ArtifactDigest, String)> {
+    let manifest = ArtifactBuildRequest {
+        artifact: Some(artifact.clone()),
+        force: false,
+        push_policy: ArtifactPushPolicy::UnknownPushPolicy as i32,
+        secrets: vec![],
+        source_revisions: vec![],
+        system: system.into(),
+    };
+
+    let manifest_json = serde_json::to_string(&manifest)?;
+
+    Ok((
+        ArtifactDigest::from_manifest(manifest_json.as_bytes()),
+        manifest_json,
+    ))
+}
+
+/// Keeps `manifest_json` in the manifest directory, so the artifact can be inspected and rebuilt
+/// without a registry. Manifests are written once, through a rename so readers never see a
+/// partial file.
+pub async fn write_manifest(
+    digest: &ArtifactDigest,
+    name: &str,
+    manifest_json: &str,
+) -> Result<()> {
+    let path = get_manifest_path(digest, name);
+
+    if path.exists() {
+        return Ok(());
+    }
+
+    let dir_path = get_manifest_dir_path();
+
+    create_dir_all(&dir_path)
+        .await
+        .map_err(|e| anyhow!("failed to create {}: {}", dir_path.display(), e))?;
+
+    let temp_path = create_sandbox_file(Some("json")).await?;
+
+    write(&temp_path, manifest_json)
+        .await
+        .map_err(|e| anyhow!("failed to write {}: {}", temp_path.display(), e))?;
+
+    if let Err(err) = rename(&temp_path, &path).await {
+        let _ = remove_file(&temp_path).await;
+
+        return Err(anyhow!(
+            "failed to move manifest to {}: {}",
+            path.display(),
+            err
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads the manifest kept for the artifact, or `None` when there is none. A manifest that no
+/// longer hashes to `digest` is removed and reported as missing.
+pub async fn read_manifest(digest: &ArtifactDigest, name: &str) -> Result<Option<String>> {
+    let path = get_manifest_path(digest, name);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = read(&path)
+        .await
+        .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+
+    if ArtifactDigest::from_manifest(&data) != *digest {
+        warn!("removing corrupt manifest: {}", path.display());
+
+        let _ = remove_file(&path).await;
+
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8(data)?))
+}
+
+/// Returns the name of the artifact `digest` from the manifests kept locally.
+pub async fn find_manifest_name(digest: &ArtifactDigest) -> Result<Option<String>> {
+    for (name, hash, _) in get_manifest_entries().await? {
+        if hash == digest.as_str() {
+            return Ok(Some(name));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Lists the manifests kept locally as `(name, hash, path)`.
+pub async fn get_manifest_entries() -> Result<Vec<(String, String, PathBuf)>> {
+    let dir_path = get_manifest_dir_path();
+
+    if !dir_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+
+    let mut dir = read_dir(&dir_path).await?;
+
+    while let Some(entry) = dir.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        let Some(store_name) = file_name.strip_suffix(MANIFEST_SUFFIX) else {
+            continue;
+        };
+
+        let Some((name, hash)) = store_name.rsplit_once('-') else {
+            continue;
+        };
+
+        entries.push((name.to_string(), hash.to_string(), entry.path()));
+    }
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+/// Checks every manifest kept locally against its digest, removing those that do not match.
+/// Returns the number of manifests checked and the paths removed.
+pub async fn verify_manifests() -> Result<(usize, Vec<PathBuf>)> {
+    let entries = get_manifest_entries().await?;
+
+    let mut removed = vec![];
+
+    for (_, hash, path) in entries.iter() {
+        let data = read(path)
+            .await
+            .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+
+        if ArtifactDigest::from_manifest(&data).as_str() != hash {
+            remove_file(path)
+                .await
+                .map_err(|e| anyhow!("failed to remove {}: {}", path.display(), e))?;
+
+            removed.push(path.clone());
+        }
+    }
+
+    Ok((entries.len(), removed))
+}
@@ -0,0 +1,279 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+// Upper bounds in seconds of duration histograms, from small pulls to long builds
+const METRIC_DURATION_BUCKETS: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+// Largest metrics request read before responding, only the request line is used
+const METRIC_REQUEST_SIZE_MAX: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// A metric family exported in the Prometheus text format, declared as a static by the service
+/// recording it.
+#[derive(Debug)]
+pub struct Metric {
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub name: &'static str,
+}
+
+type MetricGauge = Box<dyn Fn() -> f64 + Send + Sync>;
+
+enum MetricValue {
+    Gauge(MetricGauge),
+    Histogram {
+        buckets: [u64; METRIC_DURATION_BUCKETS.len()],
+        count: u64,
+        sum: f64,
+    },
+    Value(f64),
+}
+
+struct MetricFamily {
+    metric: &'static Metric,
+    series: BTreeMap<String, MetricValue>,
+}
+
+// Metrics of this process by name, recorded whether or not an exporter is running
+static METRICS: LazyLock<Mutex<BTreeMap<&'static str, MetricFamily>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+fn get_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl Metric {
+    pub const fn counter(name: &'static str, help: &'static str) -> Self {
+        Self {
+            help,
+            kind: MetricKind::Counter,
+            name,
+        }
+    }
+
+    pub const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self {
+            help,
+            kind: MetricKind::Gauge,
+            name,
+        }
+    }
+
+    pub const fn histogram(name: &'static str, help: &'static str) -> Self {
+        Self {
+            help,
+            kind: MetricKind::Histogram,
+            name,
+        }
+    }
+
+    fn update(&'static self, labels: &[(&str, &str)], update: impl FnOnce(&mut MetricValue)) {
+        let mut metrics = METRICS.lock().unwrap();
+
+        let family = metrics.entry(self.name).or_insert_with(|| MetricFamily {
+            metric: self,
+            series: BTreeMap::new(),
+        });
+
+        let value = family
+            .series
+            .entry(get_labels(labels))
+            .or_insert_with(|| match self.kind {
+                MetricKind::Histogram => MetricValue::Histogram {
+                    buckets: [0; METRIC_DURATION_BUCKETS.len()],
+                    count: 0,
+                    sum: 0.0,
+                },
+                _ => MetricValue::Value(0.0),
+            });
+
+        update(value);
+    }
+
+    /// Adds `value` to a counter.
+    pub fn increment(&'static self, labels: &[(&str, &str)], value: u64) {
+        self.update(labels, |metric| {
+            if let MetricValue::Value(total) = metric {
+                *total += value as f64;
+            }
+        });
+    }
+
+    /// Records a duration in seconds in a histogram.
+    pub fn observe(&'static self, labels: &[(&str, &str)], value: f64) {
+        self.update(labels, |metric| {
+            if let MetricValue::Histogram {
+                buckets,
+                count,
+                sum,
+            } = metric
+            {
+                for (bucket, bound) in buckets.iter_mut().zip(METRIC_DURATION_BUCKETS) {
+                    if value <= bound {
+                        *bucket += 1;
+                    }
+                }
+
+                *count += 1;
+                *sum += value;
+            }
+        });
+    }
+
+    /// Reports a gauge with the current value of `gauge` whenever metrics are rendered.
+    pub fn register(
+        &'static self,
+        labels: &[(&str, &str)],
+        gauge: impl Fn() -> f64 + Send + Sync + 'static,
+    ) {
+        self.update(labels, |metric| {
+            *metric = MetricValue::Gauge(Box::new(gauge))
+        });
+    }
+}
+
+/// Renders all recorded metrics in the Prometheus text format.
+pub fn get_metrics_text() -> String {
+    let metrics = METRICS.lock().unwrap();
+
+    let mut text = String::new();
+
+    for family in metrics.values() {
+        let name = family.metric.name;
+
+        let _ = writeln!(text, "# HELP {} {}", name, family.metric.help);
+        let _ = writeln!(text, "# TYPE {} {}", name, family.metric.kind.as_str());
+
+        for (labels, value) in family.series.iter() {
+            match value {
+                MetricValue::Gauge(gauge) => {
+                    let _ = writeln!(text, "{}{{{}}} {}", name, labels, gauge());
+                }
+
+                MetricValue::Histogram {
+                    buckets,
+                    count,
+                    sum,
+                } => {
+                    let separator = if labels.is_empty() { "" } else { "," };
+
+                    for (bucket, bound) in buckets.iter().zip(METRIC_DURATION_BUCKETS) {
+                        let _ = writeln!(
+                            text,
+                            "{}_bucket{{{}{}le=\"{}\"}} {}",
+                            name, labels, separator, bound, bucket
+                        );
+                    }
+
+                    let _ = writeln!(
+                        text,
+                        "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                        name, labels, separator, count
+                    );
+                    let _ = writeln!(text, "{}_sum{{{}}} {}", name, labels, sum);
+                    let _ = writeln!(text, "{}_count{{{}}} {}", name, labels, count);
+                }
+
+                MetricValue::Value(value) => {
+                    let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+    }
+
+    text
+}
+
+async fn handle_metrics_request(mut stream: TcpStream) -> Result<()> {
+    let mut request = vec![0; METRIC_REQUEST_SIZE_MAX];
+    let mut request_size = 0;
+
+    while request_size < request.len() {
+        let read = stream.read(&mut request[request_size..]).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        request_size += read;
+
+        if request[..request_size].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request[..request_size]);
+
+    let (status, body) = match request.lines().next() {
+        Some(line) if line.starts_with("GET /metrics ") => ("200 OK", get_metrics_text()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Serves `GET /metrics` on `port` until the process exits.
+pub async fn serve_metrics(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("::", port))
+        .await
+        .map_err(|err| anyhow!("failed to bind metrics port {}: {}", port, err))?;
+
+    info!("metrics: [::]:{}/metrics", port);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_metrics_request(stream).await {
+                warn!("failed to serve metrics: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::PathBuf, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: BTreeMap<String, DockerConfigAuth>,
+    #[serde(default)]
+    cred_helpers: BTreeMap<String, String>,
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerCredentialHelperResponse {
+    secret: String,
+    username: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct OciCredentials {
+    pub password: String,
+    pub username: String,
+}
+
+impl OciCredentials {
+    /// Returns the `Authorization` value for registries using basic authentication.
+    pub fn get_basic_authorization(&self) -> String {
+        let credentials = format!("{}:{}", self.username, self.password);
+
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+}
+
+fn get_docker_config_path() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("DOCKER_CONFIG") {
+        return Some(PathBuf::from(path).join("config.json"));
+    }
+
+    std::env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".docker").join("config.json"))
+}
+
+/// Returns the host of a docker config `auths` key, which may be a URL.
+fn get_docker_config_host(key: &str) -> &str {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+
+    key.split('/').next().unwrap_or(key)
+}
+
+async fn get_helper_credentials(helper: &str, host: &str) -> Result<Option<OciCredentials>> {
+    let helper_name = format!("docker-credential-{}", helper);
+
+    let mut child = Command::new(&helper_name)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| anyhow!("failed to run {}: {}", helper_name, err))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(host.as_bytes())
+            .await
+            .map_err(|err| anyhow!("failed to write to {}: {}", helper_name, err))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|err| anyhow!("failed to run {}: {}", helper_name, err))?;
+
+    // Helpers exit with an error when they have no credentials for the host
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let response: DockerCredentialHelperResponse = serde_json::from_slice(&output.stdout)
+        .map_err(|err| anyhow!("failed to parse {} output: {}", helper_name, err))?;
+
+    Ok(Some(OciCredentials {
+        password: response.secret,
+        username: response.username,
+    }))
+}
+
+/// Resolves credentials for `host` from the docker config: a `credHelpers` entry for the host,
+/// then the `credsStore` helper, then a static `auths` entry.
+pub async fn get_credentials(host: &str) -> Result<Option<OciCredentials>> {
+    let Some(config_path) = get_docker_config_path().filter(|path| path.exists()) else {
+        return Ok(None);
+    };
+
+    let config_data = tokio::fs::read(&config_path)
+        .await
+        .map_err(|err| anyhow!("failed to read {}: {}", config_path.display(), err))?;
+
+    let config: DockerConfig = serde_json::from_slice(&config_data)
+        .map_err(|err| anyhow!("failed to parse {}: {}", config_path.display(), err))?;
+
+    if let Some(helper) = config.cred_helpers.get(host) {
+        return get_helper_credentials(helper, host).await;
+    }
+
+    if let Some(helper) = &config.creds_store {
+        if let Some(credentials) = get_helper_credentials(helper, host).await? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    let auth = config
+        .auths
+        .iter()
+        .find(|(key, _)| get_docker_config_host(key) == host)
+        .and_then(|(_, auth)| auth.auth.clone());
+
+    let Some(auth) = auth else {
+        return Ok(None);
+    };
+
+    let auth = STANDARD
+        .decode(auth.trim())
+        .ok()
+        .and_then(|auth| String::from_utf8(auth).ok())
+        .ok_or_else(|| anyhow!("invalid auth for {}", host))?;
+
+    let Some((username, password)) = auth.split_once(':') else {
+        return Err(anyhow!("invalid auth for {}", host));
+    };
+
+    Ok(Some(OciCredentials {
+        password: password.to_string(),
+        username: username.to_string(),
+    }))
+}
+
+/// Parses the `key="value"` parameters of a `WWW-Authenticate` challenge.
+pub fn get_challenge_params(params: &str) -> BTreeMap<String, String> {
+    let mut result = BTreeMap::new();
+    let mut rest = params.trim();
+
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim();
+        let value = value.trim_start();
+
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+
+        result.insert(key.to_lowercase(), value.to_string());
+
+        rest = next;
+    }
+
+    result
+}
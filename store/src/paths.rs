@@ -1,7 +1,19 @@
-use anyhow::{bail, Error, Result};
-use filetime::{set_file_times, set_symlink_file_times, FileTime};
-use std::path::{Path, PathBuf};
-use tokio::fs::{copy, create_dir_all, metadata, symlink};
+use crate::digests::{ArtifactDigest, SourceDigest, StepDigest};
+use anyhow::{anyhow, bail, Error, Result};
+use filetime::{set_symlink_file_times, FileTime};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{metadata, Permissions},
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+use tokio::fs::{
+    create_dir_all, read_link, remove_file, set_permissions, symlink, symlink_metadata,
+};
+use tokio::task::spawn_blocking;
 use uuid::Uuid;
 use walkdir::WalkDir;
 
@@ -11,8 +23,18 @@ pub fn get_store_dir_name(hash: &str, name: &str) -> String {
     format!("{}-{}", name, hash)
 }
 
+// Overrides the root of every store path, e.g. to run services against a temporary directory
+pub const ROOT_DIR_ENV: &str = "VORPAL_ROOT_DIR";
+
 pub fn get_root_dir_path() -> PathBuf {
-    Path::new("/var/lib/vorpal").to_path_buf()
+    match std::env::var_os(ROOT_DIR_ENV).filter(|root| !root.is_empty()) {
+        Some(root) => PathBuf::from(root),
+        None => Path::new("/var/lib/vorpal").to_path_buf(),
+    }
+}
+
+pub fn get_blob_dir_path() -> PathBuf {
+    get_root_dir_path().join("blob")
 }
 
 pub fn get_cache_dir_path() -> PathBuf {
@@ -23,6 +45,18 @@ pub fn get_key_dir_path() -> PathBuf {
     get_root_dir_path().join("key")
 }
 
+pub fn get_manifest_dir_path() -> PathBuf {
+    get_root_dir_path().join("manifest")
+}
+
+pub fn get_process_dir_path() -> PathBuf {
+    get_root_dir_path().join("process")
+}
+
+pub fn get_push_dir_path() -> PathBuf {
+    get_root_dir_path().join("push")
+}
+
 pub fn get_sandbox_dir_path() -> PathBuf {
     get_root_dir_path().join("sandbox")
 }
@@ -33,16 +67,44 @@ pub fn get_store_dir_path() -> PathBuf {
 
 // Cache paths
 
-pub fn get_cache_path(hash: &str, name: &str) -> PathBuf {
-    get_cache_dir_path().join(get_store_dir_name(hash, name))
+pub fn get_config_cache_path(key: &str) -> PathBuf {
+    get_cache_dir_path()
+        .join("config")
+        .join(format!("{}.json", key))
 }
 
-pub fn get_cache_archive_path(hash: &str, name: &str) -> PathBuf {
+pub fn get_context_cache_path(key: &str) -> PathBuf {
+    get_cache_dir_path().join("context").join(key)
+}
+
+pub fn get_incremental_dir_path() -> PathBuf {
+    get_cache_dir_path().join("incremental")
+}
+
+pub fn get_incremental_path(key: &str) -> PathBuf {
+    get_incremental_dir_path().join(key)
+}
+
+pub fn get_cache_path(digest: &SourceDigest, name: &str) -> PathBuf {
+    get_cache_dir_path().join(get_store_dir_name(digest.as_str(), name))
+}
+
+pub fn get_cache_archive_path(digest: &SourceDigest, name: &str) -> PathBuf {
     get_cache_dir_path()
-        .join(get_store_dir_name(hash, name))
+        .join(get_store_dir_name(digest.as_str(), name))
         .with_extension("tar.zst")
 }
 
+pub fn get_download_dir_path() -> PathBuf {
+    get_cache_dir_path().join("download")
+}
+
+// Push paths - "/vorpal/push/{name}-{hash}", artifacts built but not pushed yet
+
+pub fn get_push_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
+    get_push_dir_path().join(get_store_dir_name(digest.as_str(), name))
+}
+
 // Key paths
 
 pub fn get_private_key_path() -> PathBuf {
@@ -55,57 +117,195 @@ pub fn get_public_key_path() -> PathBuf {
 
 // Artifact paths - "/vorpal/store/{hash}.artifact"
 
-pub fn get_artifact_path(hash: &str, name: &str) -> PathBuf {
+pub fn get_artifact_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
     get_store_dir_path()
-        .join(get_store_dir_name(hash, name))
+        .join(get_store_dir_name(digest.as_str(), name))
         .with_extension("artifact")
 }
 
-pub fn get_artifact_archive_path(hash: &str, name: &str) -> PathBuf {
+pub fn get_artifact_archive_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
     get_store_dir_path()
-        .join(get_store_dir_name(hash, name))
+        .join(get_store_dir_name(digest.as_str(), name))
         .with_extension("artifact.tar.zst")
 }
 
-pub fn get_artifact_lock_path(hash: &str, name: &str) -> PathBuf {
+pub fn get_artifact_index_path() -> PathBuf {
+    get_store_dir_path().join(".vorpal-index.json")
+}
+
+pub fn get_artifact_manifest_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
+    get_store_dir_path()
+        .join(get_store_dir_name(digest.as_str(), name))
+        .with_extension("artifact.json")
+}
+
+pub fn get_artifact_provenance_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
     get_store_dir_path()
-        .join(get_store_dir_name(hash, name))
+        .join(get_store_dir_name(digest.as_str(), name))
+        .with_extension("provenance")
+}
+
+pub fn get_artifact_lock_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
+    get_store_dir_path()
+        .join(get_store_dir_name(digest.as_str(), name))
         .with_extension("artifact.lock")
 }
 
+// Manifest paths - "/vorpal/manifest/{name}-{hash}.json"
+
+pub fn get_manifest_path(digest: &ArtifactDigest, name: &str) -> PathBuf {
+    get_manifest_dir_path()
+        .join(get_store_dir_name(digest.as_str(), name))
+        .with_extension("json")
+}
+
 // Source paths - "/vorpal/store/{hash}.source"
 
-pub fn get_source_path(hash: &str, name: &str) -> PathBuf {
+pub fn get_source_path(digest: &SourceDigest, name: &str) -> PathBuf {
     get_store_dir_path()
-        .join(get_store_dir_name(hash, name))
+        .join(get_store_dir_name(digest.as_str(), name))
         .with_extension("source")
 }
 
-pub fn get_source_archive_path(hash: &str, name: &str) -> PathBuf {
+pub fn get_source_archive_path(digest: &SourceDigest, name: &str) -> PathBuf {
     get_store_dir_path()
-        .join(get_store_dir_name(hash, name))
+        .join(get_store_dir_name(digest.as_str(), name))
         .with_extension("source.tar.zst")
 }
 
+// Step paths - "/vorpal/store/{hash}.step"
+
+pub fn get_step_archive_path(digest: &StepDigest, name: &str) -> PathBuf {
+    get_store_dir_path()
+        .join(get_store_dir_name(digest.as_str(), name))
+        .with_extension("step.tar.zst")
+}
+
+// Dictionary paths - "/vorpal/store/{id}.dictionary"
+
+pub fn get_dictionary_path(id: &str) -> PathBuf {
+    get_store_dir_path().join(id).with_extension("dictionary")
+}
+
+// Process paths - "/vorpal/process/{name}"
+
+pub fn get_process_path(name: &str) -> PathBuf {
+    get_process_dir_path().join(name)
+}
+
+pub fn get_process_log_path(name: &str) -> PathBuf {
+    get_process_path(name).join("process.log")
+}
+
+pub fn get_process_pid_path(name: &str) -> PathBuf {
+    get_process_path(name).join("process.pid")
+}
+
+pub fn get_process_state_path(name: &str) -> PathBuf {
+    get_process_path(name).join("process.json")
+}
+
 // Temp paths
 
 pub fn get_sandbox_path() -> PathBuf {
     get_sandbox_dir_path().join(Uuid::now_v7().to_string())
 }
 
+// File at the root of local sources with patterns appended to its excludes
+pub const SOURCE_IGNORE_FILE: &str = ".vorpalignore";
+
+/// Ordered glob patterns matched against paths relative to the source root.
+///
+/// Patterns are anchored at the root (`**/` matches at any depth), `*` does not match `/`, a
+/// pattern matching a directory also matches its contents, and the last matching pattern wins
+/// with `!` negating it. Plain paths therefore keep matching as prefixes.
+struct PathPatterns {
+    globs: GlobSet,
+    negated: Vec<bool>,
+}
+
+impl PathPatterns {
+    fn new(patterns: &[String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        let mut negated = vec![];
+
+        for pattern in patterns {
+            let (pattern, negate) = match pattern.strip_prefix('!') {
+                Some(pattern) => (pattern, true),
+                None => (pattern.as_str(), false),
+            };
+
+            let pattern = pattern.trim_start_matches("./").trim_matches('/');
+
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|err| anyhow!("invalid pattern {:?}: {}", pattern, err))?;
+
+            builder.add(glob);
+            negated.push(negate);
+        }
+
+        let globs = builder.build()?;
+
+        Ok(Self { globs, negated })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.globs.is_empty()
+    }
+
+    fn is_match(&self, path: &Path) -> bool {
+        let last = path
+            .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .flat_map(|ancestor| self.globs.matches(ancestor))
+            .max();
+
+        match last {
+            Some(index) => !self.negated[index],
+            None => false,
+        }
+    }
+}
+
+/// Returns `excludes` with the patterns of the `.vorpalignore` file in `source_path`, skipping
+/// blank lines and `#` comments.
+pub fn get_source_excludes(source_path: &Path, excludes: &[String]) -> Result<Vec<String>> {
+    let mut excludes = excludes.to_vec();
+
+    let ignore_path = source_path.join(SOURCE_IGNORE_FILE);
+
+    if ignore_path.is_file() {
+        let ignore = std::fs::read_to_string(&ignore_path)?;
+
+        excludes.extend(
+            ignore
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+
+    Ok(excludes)
+}
+
+/// Returns the sorted paths under `source_path`, without paths matching `excludes` and, when
+/// set, only paths matching `includes` (see `PathPatterns`).
 pub fn get_file_paths(
     source_path: &PathBuf,
     excludes: Vec<String>,
     includes: Vec<String>,
 ) -> Result<Vec<PathBuf>> {
-    let mut excludes_paths = excludes
-        .into_iter()
-        .map(|i| Path::new(&i).to_path_buf())
-        .collect::<Vec<PathBuf>>();
+    let mut excludes = excludes;
 
     // Exclude git directory
 
-    excludes_paths.push(Path::new(".git").to_path_buf());
+    excludes.push(".git".to_string());
+
+    let excludes = PathPatterns::new(&excludes)?;
+    let includes = PathPatterns::new(&includes)?;
 
     // Resolve full path
 
@@ -117,10 +317,7 @@ pub fn get_file_paths(
             let entry = entry.ok()?;
             let path = entry.path();
 
-            if excludes_paths
-                .iter()
-                .any(|i| path.strip_prefix(source_path).unwrap().starts_with(i))
-            {
+            if excludes.is_match(path.strip_prefix(source_path).unwrap()) {
                 return None;
             }
 
@@ -128,17 +325,8 @@ pub fn get_file_paths(
         })
         .collect();
 
-    let includes_paths = includes
-        .into_iter()
-        .map(|i| Path::new(&i).to_path_buf())
-        .collect::<Vec<PathBuf>>();
-
-    if !includes_paths.is_empty() {
-        files.retain(|i| {
-            includes_paths
-                .iter()
-                .any(|j| i.strip_prefix(source_path).unwrap().starts_with(j))
-        });
+    if !includes.is_empty() {
+        files.retain(|i| includes.is_match(i.strip_prefix(source_path).unwrap()));
     }
 
     files.sort();
@@ -150,20 +338,370 @@ pub fn get_file_paths(
     Ok(files)
 }
 
-pub async fn set_timestamps(path: &PathBuf) -> Result<(), Error> {
+/// Removes the paths under `root_path` matching `excludes` (see `PathPatterns`), returning the
+/// number of removed files.
+///
+/// Matching directories are only removed once empty, so files kept by a negated pattern stay.
+pub fn remove_excluded_paths(root_path: &Path, excludes: &[String]) -> Result<u64> {
+    let excludes = PathPatterns::new(excludes)?;
+
+    if excludes.is_empty() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+
+    // Contents are visited before their directory, which is empty by then unless files are kept
+
+    for entry in WalkDir::new(root_path).min_depth(1).contents_first(true) {
+        let entry = entry?;
+        let path = entry.path();
+
+        if !excludes.is_match(path.strip_prefix(root_path)?) {
+            continue;
+        }
+
+        if !entry.file_type().is_dir() {
+            std::fs::remove_file(path)?;
+
+            removed += 1;
+
+            continue;
+        }
+
+        if std::fs::read_dir(path)?.next().is_none() {
+            std::fs::remove_dir(path)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Sets the access and modification times of `path` to the epoch.
+///
+/// Symlinks are never followed, so their targets (or missing targets) are left untouched.
+pub async fn set_timestamps(path: &Path) -> Result<(), Error> {
     let epoc = FileTime::from_unix_time(0, 0);
 
-    if !path.is_symlink() {
-        set_file_times(path, epoc, epoc).expect("Failed to set file times");
+    set_symlink_file_times(path, epoc, epoc)
+        .map_err(|e| anyhow!("failed to set file times of {}: {}", path.display(), e))
+}
+
+/// Sets the mode of `path` to 0755 for directories and executables and 0644 for other files,
+/// dropping setuid, setgid and sticky bits. Symlinks are left as they are.
+pub async fn set_file_mode(path: &Path) -> Result<(), Error> {
+    let metadata = symlink_metadata(path).await?;
+
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+
+    let mode = metadata.permissions().mode();
+
+    let mode_normalized = match metadata.is_dir() || mode & 0o111 != 0 {
+        true => 0o755,
+        false => 0o644,
+    };
+
+    if mode & 0o7777 != mode_normalized {
+        set_permissions(path, Permissions::from_mode(mode_normalized))
+            .await
+            .map_err(|e| anyhow!("failed to set mode of {}: {}", path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Normalizes the mode and timestamps of `path`, so identical trees hash and archive the same
+/// on every system.
+pub async fn sanitize_file(path: &Path) -> Result<(), Error> {
+    set_file_mode(path).await?;
+
+    set_timestamps(path).await
+}
+
+fn get_normalized_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            _ => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+fn get_relative_path(from_dir: &Path, to_path: &Path) -> PathBuf {
+    let from = from_dir.components().collect::<Vec<_>>();
+    let to = to_path.components().collect::<Vec<_>>();
+
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+
+    for component in &to[common..] {
+        relative.push(component);
+    }
+
+    relative
+}
+
+// Symlinks followed while resolving a path before it is considered a loop, as in Linux
+const SYMLINK_FOLLOWS_MAX: usize = 40;
+
+/// Resolves `path` under `root_path` through the symlinks it crosses, returning `None` when it
+/// leaves `root_path` at any point. Components that do not exist are taken as they are.
+///
+/// Checking link targets lexically is not enough: with `d -> .`, the target `d/..` of another
+/// link looks inside the root but resolves to its parent.
+pub fn get_resolved_path(root_path: &Path, path: &Path) -> Result<Option<PathBuf>> {
+    let Ok(relative_path) = path.strip_prefix(root_path) else {
+        return Ok(None);
+    };
+
+    let mut pending = relative_path
+        .components()
+        .map(|component| component.as_os_str().to_os_string())
+        .collect::<VecDeque<_>>();
+
+    let mut resolved = root_path.to_path_buf();
+    let mut follows = 0;
+
+    while let Some(part) = pending.pop_front() {
+        if part == "." {
+            continue;
+        }
+
+        if part == ".." {
+            if resolved == root_path {
+                return Ok(None);
+            }
+
+            resolved.pop();
+
+            continue;
+        }
+
+        resolved.push(&part);
+
+        let is_symlink = std::fs::symlink_metadata(&resolved)
+            .map(|metadata| metadata.is_symlink())
+            .unwrap_or(false);
+
+        if !is_symlink {
+            continue;
+        }
+
+        follows += 1;
+
+        if follows > SYMLINK_FOLLOWS_MAX {
+            bail!("too many levels of symlinks: {}", path.display());
+        }
+
+        let target = std::fs::read_link(&resolved)?;
+
+        resolved.pop();
+
+        let target_parts = match target.strip_prefix(root_path) {
+            Ok(target) => {
+                resolved = root_path.to_path_buf();
+
+                target
+            }
+            Err(_) if target.is_absolute() => return Ok(None),
+            Err(_) => &target,
+        };
+
+        for component in target_parts.components().rev() {
+            pending.push_front(component.as_os_str().to_os_string());
+        }
+    }
+
+    Ok(Some(resolved))
+}
+
+/// Rejects symlinks in `files` whose target escapes `root_path` and rewrites absolute
+/// symlinks that point inside `root_path` as relative ones.
+///
+/// Targets are resolved through the other symlinks of the tree before checking they stay in
+/// `root_path`.
+pub async fn sanitize_symlinks(root_path: &Path, files: &[PathBuf]) -> Result<()> {
+    for path in files.iter().filter(|path| path.is_symlink()) {
+        let target = read_link(path).await?;
+
+        let link_dir = path.parent().unwrap_or(root_path);
+
+        if get_resolved_path(root_path, &link_dir.join(&target))?.is_none() {
+            bail!(
+                "symlink `{}` target `{}` escapes source root",
+                path.strip_prefix(root_path).unwrap_or(path).display(),
+                target.display()
+            );
+        }
+
+        if target.is_absolute() {
+            let target_relative = get_relative_path(link_dir, &get_normalized_path(&target));
+
+            remove_file(path).await?;
+
+            symlink(&target_relative, path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the filesystem of each device clones files, learned from the first copy onto it
+static CLONE_SUPPORTED: LazyLock<Mutex<HashMap<u64, bool>>> = LazyLock::new(Default::default);
+
+#[cfg(target_os = "linux")]
+fn clone_file(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::fs::{File, OpenOptions};
+    use std::os::{fd::AsRawFd, unix::fs::OpenOptionsExt};
+
+    let source = File::open(src)?;
+
+    let mode = source.metadata()?.permissions().mode();
+
+    let target = OpenOptions::new()
+        .create(true)
+        .mode(mode)
+        .truncate(true)
+        .write(true)
+        .open(dest)?;
+
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE as _, source.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The mode given to open is masked by the umask, and ignored for an existing file
+
+    target.set_permissions(Permissions::from_mode(mode))
+}
+
+#[cfg(target_os = "macos")]
+fn clone_file(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dest_path = CString::new(dest.as_os_str().as_bytes())?;
+
+    // Unlike a copy, clonefile fails when the destination exists
+
+    if let Err(err) = std::fs::remove_file(dest) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err);
+        }
     }
 
-    if path.is_symlink() {
-        set_symlink_file_times(path, epoc, epoc).expect("Failed to set symlink file times");
+    if unsafe { libc::clonefile(src.as_ptr(), dest_path.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
     }
 
     Ok(())
 }
 
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clone_file(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Whether `err` of a clone means the filesystem can not clone files at all, rather than this
+/// pair of files, e.g. `EXDEV` for files on different filesystems.
+fn is_clone_unsupported(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+
+    // ENOTSUP and EOPNOTSUPP are the same code on Linux only
+
+    err.raw_os_error().is_some_and(|code| {
+        [
+            libc::EINVAL,
+            libc::ENOSYS,
+            libc::ENOTSUP,
+            libc::ENOTTY,
+            libc::EOPNOTSUPP,
+        ]
+        .contains(&code)
+    })
+}
+
+fn copy_file_blocking(src: &Path, dest: &Path) -> io::Result<()> {
+    let device = dest
+        .parent()
+        .and_then(|parent| metadata(parent).ok())
+        .map(|metadata| metadata.dev());
+
+    let supported = device.and_then(|device| {
+        CLONE_SUPPORTED
+            .lock()
+            .expect("clone support lock poisoned")
+            .get(&device)
+            .copied()
+    });
+
+    if supported != Some(false) {
+        match clone_file(src, dest) {
+            Ok(()) => {
+                if let Some(device) = device {
+                    CLONE_SUPPORTED
+                        .lock()
+                        .expect("clone support lock poisoned")
+                        .insert(device, true);
+                }
+
+                return Ok(());
+            }
+
+            Err(err) if is_clone_unsupported(&err) => {
+                if let Some(device) = device {
+                    CLONE_SUPPORTED
+                        .lock()
+                        .expect("clone support lock poisoned")
+                        .insert(device, false);
+                }
+            }
+
+            Err(_) => {}
+        }
+    }
+
+    std::fs::copy(src, dest).map(|_| ())
+}
+
+/// Copies file `src` to `dest` with its mode. The copy shares the blocks of `src` (FICLONE on
+/// Linux, clonefile on macOS) when the filesystem supports it, and is a regular copy otherwise.
+pub async fn copy_file(src: &Path, dest: &Path) -> Result<()> {
+    let src = src.to_path_buf();
+    let dest = dest.to_path_buf();
+
+    spawn_blocking(move || {
+        copy_file_blocking(&src, &dest).map_err(|e| {
+            anyhow!(
+                "failed to copy {} to {}: {}",
+                src.display(),
+                dest.display(),
+                e
+            )
+        })
+    })
+    .await?
+}
+
 pub async fn copy_files(
     source_path: &PathBuf,
     source_path_files: Vec<PathBuf>,
@@ -178,15 +716,26 @@ pub async fn copy_files(
             bail!("source file is a tar.zst archive");
         }
 
-        if !src.exists() {
-            bail!("source file not found: {:?}", src);
-        }
+        // Symlinks are copied as links, dangling ones included, never as their targets
 
-        let metadata = metadata(src).await.expect("failed to read metadata");
+        let Ok(metadata) = symlink_metadata(src).await else {
+            bail!("source file not found: {:?}", src);
+        };
 
         let dest = target_path.join(src.strip_prefix(source_path).unwrap());
 
-        if metadata.is_dir() {
+        if metadata.is_symlink() {
+            let parent = dest.parent().expect("failed to get parent directory");
+            if !parent.exists() {
+                create_dir_all(parent)
+                    .await
+                    .expect("create parent directory fail");
+            }
+
+            symlink(read_link(src).await?, dest)
+                .await
+                .expect("symlink file fail");
+        } else if metadata.is_dir() {
             create_dir_all(dest).await.expect("create directory fail");
         } else if metadata.is_file() {
             let parent = dest.parent().expect("failed to get parent directory");
@@ -196,9 +745,7 @@ pub async fn copy_files(
                     .expect("create parent directory fail");
             }
 
-            copy(src, dest).await.expect("copy file fail");
-        } else if metadata.is_symlink() {
-            symlink(src, dest).await.expect("symlink file fail");
+            copy_file(src, &dest).await?;
         } else {
             bail!("source file is not a file or directory: {:?}", src);
         }
@@ -208,3 +755,562 @@ pub async fn copy_files(
 
     Ok(target_path_files)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashes::hash_files;
+    use std::os::unix::fs::symlink as symlink_sync;
+
+    #[test]
+    fn test_get_resolved_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path();
+
+        std::fs::create_dir(root_path.join("lib")).unwrap();
+        symlink_sync(".", root_path.join("d")).unwrap();
+        symlink_sync("lib", root_path.join("l")).unwrap();
+
+        assert_eq!(
+            get_resolved_path(root_path, &root_path.join("l/file")).unwrap(),
+            Some(root_path.join("lib/file"))
+        );
+        assert_eq!(
+            get_resolved_path(root_path, &root_path.join("d/d/lib")).unwrap(),
+            Some(root_path.join("lib"))
+        );
+        assert_eq!(
+            get_resolved_path(root_path, &root_path.join("d/..")).unwrap(),
+            None
+        );
+        assert_eq!(
+            get_resolved_path(root_path, &root_path.join("lib/../..")).unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path();
+
+        std::fs::create_dir(root_path.join("lib")).unwrap();
+        std::fs::write(root_path.join("lib/file"), "file").unwrap();
+
+        symlink_sync(".", root_path.join("d")).unwrap();
+        symlink_sync("lib/file", root_path.join("relative")).unwrap();
+        symlink_sync(root_path.join("lib/file"), root_path.join("absolute")).unwrap();
+
+        let files = [root_path.join("absolute"), root_path.join("relative")];
+
+        sanitize_symlinks(root_path, &files).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_link(root_path.join("absolute")).unwrap(),
+            Path::new("lib/file")
+        );
+
+        // Looks inside the root lexically, but `d` is the root itself
+
+        symlink_sync("d/..", root_path.join("escape")).unwrap();
+
+        let error = sanitize_symlinks(root_path, &[root_path.join("escape")])
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("escapes source root"));
+
+        // Links through other links are followed to where they point
+
+        symlink_sync("d/lib/../d/lib/file", root_path.join("nested")).unwrap();
+
+        sanitize_symlinks(root_path, &[root_path.join("nested")])
+            .await
+            .unwrap();
+
+        symlink_sync("/etc/passwd", root_path.join("outside")).unwrap();
+
+        assert!(sanitize_symlinks(root_path, &[root_path.join("outside")])
+            .await
+            .is_err());
+
+        // Sanitizing never follows links, dangling ones included
+
+        symlink_sync("missing", root_path.join("dangling")).unwrap();
+
+        let file_modified = std::fs::metadata(root_path.join("lib/file"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        for name in ["relative", "dangling"] {
+            let path = root_path.join(name);
+
+            sanitize_file(&path).await.unwrap();
+
+            let metadata = std::fs::symlink_metadata(&path).unwrap();
+
+            assert!(metadata.is_symlink());
+            assert_eq!(
+                FileTime::from_last_modification_time(&metadata).unix_seconds(),
+                0
+            );
+        }
+
+        let metadata = std::fs::metadata(root_path.join("lib/file")).unwrap();
+
+        assert_eq!(metadata.modified().unwrap(), file_modified);
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o644);
+
+        // Modes are normalized to 0644 and 0755, keeping only whether a file is executable
+
+        let modes = [
+            ("private", 0o600, 0o644),
+            ("group", 0o664, 0o644),
+            ("setuid", 0o4750, 0o755),
+            ("executable", 0o700, 0o755),
+        ];
+
+        for (name, mode, mode_normalized) in modes {
+            let path = root_path.join("lib").join(name);
+
+            std::fs::write(&path, name).unwrap();
+            std::fs::set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+
+            sanitize_file(&path).await.unwrap();
+
+            let metadata = std::fs::metadata(&path).unwrap();
+
+            assert_eq!(
+                metadata.permissions().mode() & 0o7777,
+                mode_normalized,
+                "{}",
+                name
+            );
+            assert_eq!(metadata.modified().unwrap(), std::time::UNIX_EPOCH);
+        }
+
+        std::fs::set_permissions(root_path.join("lib"), Permissions::from_mode(0o700)).unwrap();
+
+        sanitize_file(&root_path.join("lib")).await.unwrap();
+
+        assert_eq!(
+            std::fs::metadata(root_path.join("lib"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            0o755
+        );
+
+        // Copies keep links as links, so both trees hash the same
+
+        let files = ["dangling", "lib", "lib/executable", "lib/file", "relative"]
+            .iter()
+            .map(|name| root_path.join(name))
+            .collect::<Vec<_>>();
+
+        let copy_dir = tempfile::tempdir().unwrap();
+
+        let copied = copy_files(&root_path.to_path_buf(), files.clone(), copy_dir.path())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_link(copy_dir.path().join("dangling")).unwrap(),
+            Path::new("missing")
+        );
+        assert_eq!(
+            std::fs::read_link(copy_dir.path().join("relative")).unwrap(),
+            Path::new("lib/file")
+        );
+        assert_eq!(
+            std::fs::metadata(copy_dir.path().join("lib/executable"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            0o755
+        );
+
+        assert_eq!(hash_files(copied).unwrap(), hash_files(files).unwrap());
+    }
+
+    #[test]
+    fn test_path_patterns() {
+        // (patterns, path, matched)
+        let cases: [(&[&str], &str, bool); 21] = [
+            // Plain paths match themselves and their contents, not siblings sharing a prefix
+            (&["target"], "target", true),
+            (&["target"], "target/debug/vorpal", true),
+            (&["src"], "srcx/main.rs", false),
+            (&["src/main.rs"], "src/main.rs", true),
+            (&["./target/"], "target/debug", true),
+            // `*` stays within one directory
+            (&["*.log"], "build.log", true),
+            (&["*.log"], "logs/build.log", false),
+            (&["src/*.rs"], "src/lib.rs", true),
+            (&["src/*.rs"], "src/config/mod.rs", false),
+            // `**` matches at any depth
+            (&["**/*.log"], "build.log", true),
+            (&["**/*.log"], "logs/2024/build.log", true),
+            (
+                &["**/node_modules"],
+                "web/app/node_modules/react/index.js",
+                true,
+            ),
+            (&["src/**/test_*.rs"], "src/config/test_mod.rs", true),
+            (&["src/**/test_*.rs"], "tests/test_mod.rs", false),
+            // Directory patterns match everything below
+            (&["docs/**"], "docs/guide/index.md", true),
+            (&["**/target/"], "sdk/target/debug/build", true),
+            // The last matching pattern wins, `!` negates it
+            (&["*.log", "!keep.log"], "keep.log", false),
+            (&["*.log", "!keep.log"], "drop.log", true),
+            (&["!keep.log", "*.log"], "keep.log", true),
+            (
+                &["target", "!target/release"],
+                "target/release/vorpal",
+                false,
+            ),
+            (&["target", "!target/release"], "target/debug/vorpal", true),
+        ];
+
+        for (patterns, path, matched) in cases {
+            let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+            assert_eq!(
+                PathPatterns::new(&patterns)
+                    .unwrap()
+                    .is_match(Path::new(path)),
+                matched,
+                "{:?} {}",
+                patterns,
+                path
+            );
+        }
+
+        assert!(PathPatterns::new(&["src/[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_get_file_paths_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path().to_path_buf();
+
+        for path in [
+            ".git/HEAD",
+            "build.log",
+            "keep.log",
+            "src/lib.rs",
+            "src/config/mod.rs",
+            "target/debug/vorpal",
+        ] {
+            let path = root_path.join(path);
+
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let get_paths = |excludes: &[&str], includes: &[&str]| {
+            get_file_paths(
+                &root_path,
+                excludes.iter().map(|e| e.to_string()).collect(),
+                includes.iter().map(|i| i.to_string()).collect(),
+            )
+            .unwrap()
+            .iter()
+            .map(|path| path.strip_prefix(&root_path).unwrap().display().to_string())
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            get_paths(&["target", "*.log", "!keep.log"], &[]),
+            [
+                "",
+                "keep.log",
+                "src",
+                "src/config",
+                "src/config/mod.rs",
+                "src/lib.rs"
+            ]
+        );
+
+        assert_eq!(get_paths(&["src/config"], &["src/**/*.rs"]), ["src/lib.rs"]);
+
+        assert!(get_file_paths(&root_path, vec![], vec!["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_remove_excluded_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path();
+
+        for path in [
+            "a.pyc",
+            "a.pyc.txt",
+            "lib/module/b.pyc",
+            "logs/build.log",
+            "logs/keep.log",
+            "mylogs/build.log",
+            "target/.cargo-lock",
+            "target/.cargo-lock.keep",
+        ] {
+            let path = root_path.join(path);
+
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let excludes = ["**/*.pyc", "target/.cargo-lock", "logs", "!logs/keep.log"]
+            .iter()
+            .map(|exclude| exclude.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(remove_excluded_paths(root_path, &excludes).unwrap(), 4);
+
+        let paths = WalkDir::new(root_path)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+
+                entry
+                    .path()
+                    .strip_prefix(root_path)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+
+        // Matching directories are removed once empty, `logs` stays for the negated file and
+        // directories that only held excluded files are kept
+
+        assert_eq!(
+            paths,
+            [
+                "a.pyc.txt",
+                "lib",
+                "lib/module",
+                "logs",
+                "logs/keep.log",
+                "mylogs",
+                "mylogs/build.log",
+                "target",
+                "target/.cargo-lock.keep",
+            ]
+        );
+
+        assert_eq!(remove_excluded_paths(root_path, &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_is_clone_unsupported() {
+        let cases = [
+            (libc::EINVAL, true),
+            (libc::ENOSYS, true),
+            (libc::ENOTTY, true),
+            (libc::EOPNOTSUPP, true),
+            // Tied to the pair of files, other files on the filesystem may still clone
+            (libc::EACCES, false),
+            (libc::EXDEV, false),
+        ];
+
+        for (code, unsupported) in cases {
+            assert_eq!(
+                is_clone_unsupported(&io::Error::from_raw_os_error(code)),
+                unsupported,
+                "{}",
+                code
+            );
+        }
+
+        assert!(is_clone_unsupported(&io::ErrorKind::Unsupported.into()));
+    }
+
+    #[tokio::test]
+    async fn test_copy_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_path = dir.path();
+
+        let src = root_path.join("src");
+        let dest = root_path.join("dest");
+
+        std::fs::write(&src, "source").unwrap();
+        std::fs::set_permissions(&src, Permissions::from_mode(0o750)).unwrap();
+
+        // Replaces an existing file, taking the mode of the source like `std::fs::copy`
+
+        std::fs::write(&dest, "existing destination").unwrap();
+        std::fs::set_permissions(&dest, Permissions::from_mode(0o600)).unwrap();
+
+        copy_file(&src, &dest).await.unwrap();
+
+        let get_mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "source");
+        assert_eq!(get_mode(&dest), 0o750);
+
+        // The first copy learns whether the filesystem clones, either way it is remembered
+
+        let device = metadata(root_path).unwrap().dev();
+
+        let supported = CLONE_SUPPORTED.lock().unwrap().get(&device).copied();
+
+        assert!(supported.is_some());
+
+        // Filesystems known not to clone fall back to copies
+
+        CLONE_SUPPORTED.lock().unwrap().insert(device, false);
+
+        let fallback = root_path.join("fallback");
+
+        let result = copy_file(&src, &fallback).await;
+
+        CLONE_SUPPORTED
+            .lock()
+            .unwrap()
+            .insert(device, supported.unwrap());
+
+        result.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&fallback).unwrap(), "source");
+        assert_eq!(get_mode(&fallback), 0o750);
+
+        assert!(copy_file(&root_path.join("missing"), &fallback)
+            .await
+            .is_err());
+    }
+
+    // Writes a tree with an executable, a plain file, a relative and a dangling link, using
+    // modes and times that differ between the systems a source is prepared on
+    fn write_sanitize_tree(root_path: &Path, file_mode: u32, executable_mode: u32, mtime: i64) {
+        let mtime = FileTime::from_unix_time(mtime, 0);
+
+        std::fs::create_dir_all(root_path.join("bin")).unwrap();
+        std::fs::create_dir_all(root_path.join("lib")).unwrap();
+
+        for (name, mode) in [("bin/tool", executable_mode), ("lib/data", file_mode)] {
+            let path = root_path.join(name);
+
+            std::fs::write(&path, name).unwrap();
+            std::fs::set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+            filetime::set_file_times(&path, mtime, mtime).unwrap();
+        }
+
+        symlink_sync("../bin/tool", root_path.join("lib/tool")).unwrap();
+        symlink_sync("../missing", root_path.join("lib/missing")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_copied_tree_hashes_identically() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let get_mode = |path: &Path| {
+            std::fs::symlink_metadata(path)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+
+        // Setuid and group bits, and executables without read bits, as seen on other systems
+
+        let trees = [(0o600, 0o4700, 1), (0o664, 0o750, 2)];
+
+        let mut digests = vec![];
+
+        for (index, (file_mode, executable_mode, mtime)) in trees.into_iter().enumerate() {
+            let source_path = dir.path().join(format!("source-{}", index));
+            let target_path = dir.path().join(format!("target-{}", index));
+
+            write_sanitize_tree(&source_path, file_mode, executable_mode, mtime);
+
+            let source_files = get_file_paths(&source_path, vec![], vec![]).unwrap();
+
+            let target_files = copy_files(&source_path, source_files, &target_path)
+                .await
+                .unwrap();
+
+            for path in &target_files {
+                sanitize_file(path).await.unwrap();
+            }
+
+            // Links are copied as links, dangling ones included
+
+            assert_eq!(
+                std::fs::read_link(target_path.join("lib/tool")).unwrap(),
+                Path::new("../bin/tool")
+            );
+            assert_eq!(
+                std::fs::read_link(target_path.join("lib/missing")).unwrap(),
+                Path::new("../missing")
+            );
+            assert!(!target_path.join("lib/missing").exists());
+
+            assert_eq!(get_mode(&target_path.join("bin/tool")), 0o755);
+            assert_eq!(get_mode(&target_path.join("lib/data")), 0o644);
+
+            for name in ["bin/tool", "lib/data", "lib/tool", "lib/missing"] {
+                let metadata = std::fs::symlink_metadata(target_path.join(name)).unwrap();
+
+                assert_eq!(
+                    FileTime::from_last_modification_time(&metadata).seconds(),
+                    0
+                );
+            }
+
+            digests.push(hash_files(target_files).unwrap());
+        }
+
+        assert_eq!(digests[0], digests[1]);
+
+        // Pinned, so the digest of the tree is the same on every system
+
+        assert_eq!(
+            digests[0].as_str(),
+            "5c661634689f456caa2905b00c039a69294d5580a111d001c59c5af79d57985f"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_file_does_not_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let target_path = dir.path().join("target");
+        let link_path = dir.path().join("link");
+
+        let mtime = FileTime::from_unix_time(1000, 0);
+
+        std::fs::write(&target_path, "target").unwrap();
+        std::fs::set_permissions(&target_path, Permissions::from_mode(0o600)).unwrap();
+        filetime::set_file_times(&target_path, mtime, mtime).unwrap();
+
+        symlink_sync(&target_path, &link_path).unwrap();
+
+        sanitize_file(&link_path).await.unwrap();
+
+        let target_metadata = std::fs::metadata(&target_path).unwrap();
+
+        assert_eq!(target_metadata.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(
+            FileTime::from_last_modification_time(&target_metadata),
+            mtime
+        );
+
+        let link_metadata = std::fs::symlink_metadata(&link_path).unwrap();
+
+        assert_eq!(
+            FileTime::from_last_modification_time(&link_metadata).seconds(),
+            0
+        );
+
+        // Dangling links are sanitized like any other link
+
+        std::fs::remove_file(&target_path).unwrap();
+
+        sanitize_file(&link_path).await.unwrap();
+    }
+}
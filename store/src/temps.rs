@@ -1,7 +1,50 @@
 use crate::paths;
 use anyhow::{anyhow, Result};
-use std::path::PathBuf;
-use tokio::fs::{create_dir_all, File};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, File},
+    runtime::Handle,
+};
+
+/// Sandbox dir or file removed when dropped, so errors and early returns do not leave it
+/// behind. Inside a runtime the removal runs on the blocking pool.
+#[derive(Debug)]
+pub struct SandboxGuard {
+    path: PathBuf,
+}
+
+impl SandboxGuard {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SandboxGuard {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+
+        let remove = move || {
+            let _ = match path.is_dir() {
+                true => std::fs::remove_dir_all(&path),
+                false => std::fs::remove_file(&path),
+            };
+        };
+
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(remove);
+            }
+            Err(_) => remove(),
+        }
+    }
+}
 
 pub async fn create_sandbox_dir() -> Result<PathBuf> {
     let dir_path = paths::get_sandbox_path();
@@ -26,3 +69,45 @@ pub async fn create_sandbox_file(extension: Option<&str>) -> Result<PathBuf> {
 
     Ok(file_path)
 }
+
+/// Removes sandbox entries last modified more than `min_age` ago, left behind by interrupted
+/// builds, returning their paths. Nothing is removed when `dry_run` is set.
+pub async fn prune_sandboxes(dry_run: bool, min_age: Duration) -> Result<Vec<PathBuf>> {
+    let sandbox_dir_path = paths::get_sandbox_dir_path();
+
+    if !sandbox_dir_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = read_dir(&sandbox_dir_path)
+        .await
+        .map_err(|e| anyhow!("failed to read sandbox dir: {}", e))?;
+
+    let mut pruned = vec![];
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+
+        if age < min_age {
+            continue;
+        }
+
+        let path = entry.path();
+
+        if !dry_run {
+            if metadata.is_dir() {
+                remove_dir_all(&path).await?;
+            } else {
+                remove_file(&path).await?;
+            }
+        }
+
+        pruned.push(path);
+    }
+
+    pruned.sort();
+
+    Ok(pruned)
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use std::{
+    env::consts::{ARCH, OS},
+    path::Path,
+    sync::OnceLock,
+};
+use tempfile::TempDir;
+use tokio::{
+    fs::{create_dir_all, read, write},
+    net::TcpListener,
+    sync::{Mutex, MutexGuard},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Channel, Server};
+use vorpal_registry::{LocalRegistryBackend, RegistryServer};
+use vorpal_schema::{
+    get_artifact_system,
+    vorpal::{
+        agent::v0::{
+            agent_service_client::AgentServiceClient, agent_service_server::AgentServiceServer,
+        },
+        artifact::v0::{
+            artifact_service_client::ArtifactServiceClient,
+            artifact_service_server::ArtifactServiceServer, ArtifactSystem,
+        },
+        registry::v0::{
+            registry_service_client::RegistryServiceClient,
+            registry_service_server::RegistryServiceServer,
+        },
+    },
+};
+use vorpal_store::{
+    archives::ArchiveCompression,
+    chunks::{ChunkBounds, CHUNK_MESSAGE_SIZE_LIMIT},
+    grpc::RegistryUrls,
+    paths::{
+        get_cache_dir_path, get_download_dir_path, get_key_dir_path, get_manifest_dir_path,
+        get_private_key_path, get_public_key_path, get_push_dir_path, get_sandbox_dir_path,
+        get_store_dir_path, ROOT_DIR_ENV,
+    },
+};
+use vorpal_worker::{
+    agent::{AgentLimits, AgentServer, DownloadCache},
+    artifact::ArtifactServer,
+    push::PushPolicy,
+};
+
+// Store paths are read from the process environment, so environments take turns
+static ENVIRONMENT_LOCK: Mutex<()> = Mutex::const_new(());
+
+// Generating keys is slow, every environment of a test binary uses the same pair
+static KEYS: OnceLock<(Vec<u8>, Vec<u8>)> = OnceLock::new();
+
+/// Store rooted in a temporary directory with throwaway signing keys, removed when dropped.
+///
+/// Only one environment of a process exists at a time, later ones wait for it to be dropped.
+pub struct TestEnvironment {
+    _lock: MutexGuard<'static, ()>,
+    root: TempDir,
+}
+
+impl TestEnvironment {
+    pub async fn new() -> Result<Self> {
+        let lock = ENVIRONMENT_LOCK.lock().await;
+
+        let root = tempfile::tempdir()?;
+
+        std::env::set_var(ROOT_DIR_ENV, root.path());
+
+        // Directories the install script creates before services start
+
+        for path in [
+            get_cache_dir_path(),
+            get_manifest_dir_path(),
+            get_push_dir_path(),
+            get_sandbox_dir_path(),
+            get_store_dir_path(),
+        ] {
+            create_dir_all(path).await?;
+        }
+
+        write_keys().await?;
+
+        Ok(Self { _lock: lock, root })
+    }
+
+    pub fn root_path(&self) -> &Path {
+        self.root.path()
+    }
+
+    /// Starts the agent, artifact and registry services on one ephemeral port, like
+    /// `vorpal start --services agent,artifact,registry` with a local registry.
+    pub async fn start(&self) -> Result<TestServices> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = format!("http://{}", listener.local_addr()?);
+
+        let registries = RegistryUrls::new(vec![address.clone()], None)?;
+        let system = get_artifact_system::<ArtifactSystem>(&format!("{}-{}", ARCH, OS));
+
+        let agent = AgentServer::new(
+            DownloadCache::new(get_download_dir_path()),
+            AgentLimits::default(),
+        )
+        .with_hosts(&["127.0.0.1".to_string(), "localhost".to_string()]);
+
+        let artifact = ArtifactServer::new(
+            ArchiveCompression::default(),
+            ChunkBounds::default(),
+            vec![],
+            None,
+            1,
+            0,
+            PushPolicy::Always,
+            registries,
+            false,
+            system,
+        );
+
+        let registry = RegistryServer::new(
+            Box::new(LocalRegistryBackend::new()?),
+            ChunkBounds::default(),
+        );
+
+        let router = Server::builder()
+            .add_service(AgentServiceServer::new(agent))
+            .add_service(ArtifactServiceServer::new(artifact))
+            .add_service(
+                RegistryServiceServer::new(registry)
+                    .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT),
+            );
+
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+
+        Ok(TestServices { address })
+    }
+}
+
+impl Drop for TestEnvironment {
+    fn drop(&mut self) {
+        std::env::remove_var(ROOT_DIR_ENV);
+    }
+}
+
+async fn write_keys() -> Result<()> {
+    if KEYS.get().is_none() {
+        vorpal_notary::generate_keys(
+            get_key_dir_path(),
+            get_private_key_path(),
+            get_public_key_path(),
+        )
+        .await?;
+
+        let keys = (
+            read(get_private_key_path()).await?,
+            read(get_public_key_path()).await?,
+        );
+
+        let _ = KEYS.set(keys);
+
+        return Ok(());
+    }
+
+    let (private_key, public_key) = KEYS.get().ok_or_else(|| anyhow!("keys not generated"))?;
+
+    create_dir_all(get_key_dir_path()).await?;
+
+    write(get_private_key_path(), private_key).await?;
+    write(get_public_key_path(), public_key).await?;
+
+    Ok(())
+}
+
+/// Services started by `TestEnvironment::start`, stopped with the runtime of the test.
+pub struct TestServices {
+    address: String,
+}
+
+impl TestServices {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn channel(&self) -> Result<Channel> {
+        Ok(Channel::from_shared(self.address.clone())?
+            .connect()
+            .await?)
+    }
+
+    pub async fn agent_client(&self) -> Result<AgentServiceClient<Channel>> {
+        Ok(AgentServiceClient::new(self.channel().await?))
+    }
+
+    pub async fn artifact_client(&self) -> Result<ArtifactServiceClient<Channel>> {
+        Ok(ArtifactServiceClient::new(self.channel().await?))
+    }
+
+    pub async fn registry_client(&self) -> Result<RegistryServiceClient<Channel>> {
+        Ok(RegistryServiceClient::new(self.channel().await?)
+            .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT))
+    }
+}
@@ -0,0 +1,127 @@
+use std::env::consts::{ARCH, OS};
+use tokio::fs::read_to_string;
+use tonic::Code;
+use vorpal_schema::{
+    get_artifact_system,
+    vorpal::{
+        artifact::v0::{
+            Artifact, ArtifactBuildRequest, ArtifactStep, ArtifactSystem,
+            ArtifactSystem::{Aarch64Macos, X8664Linux},
+        },
+        registry::v0::{RegistryKind, RegistryRequest},
+    },
+};
+use vorpal_store::{digests::ArtifactDigest, paths::get_artifact_path};
+use vorpal_testing::TestEnvironment;
+
+#[tokio::test]
+async fn test_build_shell_step_artifact() {
+    let environment = TestEnvironment::new().await.unwrap();
+    let services = environment.start().await.unwrap();
+
+    let system = get_artifact_system::<ArtifactSystem>(&format!("{}-{}", ARCH, OS));
+
+    let request = ArtifactBuildRequest {
+        artifact: Some(Artifact {
+            name: "hello".to_string(),
+            steps: vec![ArtifactStep {
+                arguments: vec![
+                    "-c".to_string(),
+                    "echo hello > \"$VORPAL_OUTPUT/hello.txt\"".to_string(),
+                ],
+                entrypoint: Some("/bin/sh".to_string()),
+                ..Default::default()
+            }],
+            systems: vec![system as i32],
+            ..Default::default()
+        }),
+        system: system as i32,
+        ..Default::default()
+    };
+
+    let digest = ArtifactDigest::from_manifest(serde_json::to_string(&request).unwrap().as_bytes());
+
+    let mut artifact_client = services.artifact_client().await.unwrap();
+
+    let mut response = artifact_client.build(request).await.unwrap().into_inner();
+
+    while let Some(message) = response.message().await.unwrap() {
+        println!("{}", message.output);
+    }
+
+    let output = read_to_string(get_artifact_path(&digest, "hello").join("hello.txt"))
+        .await
+        .unwrap();
+
+    assert_eq!(output, "hello\n");
+
+    // The worker pushes what it built to the registry
+
+    let mut registry_client = services.registry_client().await.unwrap();
+
+    for kind in [RegistryKind::Artifact, RegistryKind::ArtifactManifest] {
+        registry_client
+            .exists(RegistryRequest {
+                accept_dictionary: false,
+                hash: digest.to_string(),
+                kind: kind as i32,
+                name: "hello".to_string(),
+            })
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_build_rejects_unsupported_system() {
+    let environment = TestEnvironment::new().await.unwrap();
+    let services = environment.start().await.unwrap();
+
+    let system = get_artifact_system::<ArtifactSystem>(&format!("{}-{}", ARCH, OS));
+
+    // Declares only a system other than the worker's
+
+    let declared = match system {
+        X8664Linux => Aarch64Macos,
+        _ => X8664Linux,
+    };
+
+    let request = ArtifactBuildRequest {
+        artifact: Some(Artifact {
+            name: "hello".to_string(),
+            steps: vec![ArtifactStep {
+                arguments: vec!["-c".to_string(), "true".to_string()],
+                entrypoint: Some("/bin/sh".to_string()),
+                ..Default::default()
+            }],
+            systems: vec![declared as i32],
+            ..Default::default()
+        }),
+        system: system as i32,
+        ..Default::default()
+    };
+
+    let mut artifact_client = services.artifact_client().await.unwrap();
+
+    let status = match artifact_client.build(request).await {
+        Err(status) => status,
+        Ok(response) => {
+            let mut response = response.into_inner();
+
+            loop {
+                match response.message().await {
+                    Err(status) => break status,
+                    Ok(Some(_)) => continue,
+                    Ok(None) => panic!("build of an unsupported system succeeded"),
+                }
+            }
+        }
+    };
+
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert!(status.message().contains(&format!(
+        "artifact `hello` does not support `{}` (systems: {})",
+        system.as_str_name(),
+        declared.as_str_name()
+    )));
+}
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use vorpal_schema::vorpal::registry::v0::{RegistryCompression, RegistryKind, RegistryPushRequest};
+use vorpal_store::{
+    chunks::{stream_chunks, ChunkBounds, ChunkSummary, DEFAULT_CHUNK_SIZE},
+    paths::get_private_key_path,
+};
+use vorpal_testing::{TestEnvironment, TestServices};
+
+const MB: usize = 1024 * 1024;
+
+// Pushes per chunk size, alternating between them
+const BENCH_RUNS: usize = 8;
+
+// Zstd frame magic, enough for the registry to accept the data as an artifact archive
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// Pushes `data` in chunks sized within `bounds`, returning the summary and the time until the
+// registry stored it
+async fn push(
+    services: &TestServices,
+    hash: &str,
+    data: Vec<u8>,
+    bounds: ChunkBounds,
+) -> (ChunkSummary, Duration) {
+    let mut client = services.registry_client().await.unwrap();
+
+    let signature = vorpal_notary::sign(get_private_key_path(), &data)
+        .await
+        .unwrap()
+        .to_vec();
+
+    let hash = hash.to_string();
+
+    let started = Instant::now();
+
+    let (stream, summary) = stream_chunks(data, bounds, move |data| RegistryPushRequest {
+        compression: RegistryCompression::Zstd as i32,
+        data,
+        data_signature: signature.clone(),
+        data_signature_fingerprint: String::new(),
+        delta_base: String::new(),
+        hash: hash.clone(),
+        kind: RegistryKind::Artifact as i32,
+        name: "example".to_string(),
+    });
+
+    client.push(ReceiverStream::new(stream)).await.unwrap();
+
+    (summary.await.unwrap(), started.elapsed())
+}
+
+// Run with `cargo test -p vorpal-testing --release --test chunks -- --ignored --nocapture`
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "benchmark, compares push throughput of fixed and adaptive chunk sizes"]
+async fn bench_push_chunk_sizes() {
+    let environment = TestEnvironment::new().await.unwrap();
+    let services = environment.start().await.unwrap();
+
+    let mut data = ZSTD_MAGIC.to_vec();
+
+    data.extend((0..256 * MB).map(|i| (i % 251) as u8));
+
+    let fixed = ChunkBounds {
+        default: DEFAULT_CHUNK_SIZE,
+        max: DEFAULT_CHUNK_SIZE,
+        min: DEFAULT_CHUNK_SIZE,
+    };
+
+    // Alternate runs after a warm-up push, so neither size gets a warmer registry
+
+    push(&services, &format!("{:064x}", 0), data.clone(), fixed).await;
+
+    let mut sent_fixed = Duration::ZERO;
+    let mut sent_adaptive = Duration::ZERO;
+    let mut stored_fixed = Duration::ZERO;
+    let mut stored_adaptive = Duration::ZERO;
+
+    for run in 0..BENCH_RUNS {
+        let (name, bounds) = match run % 2 {
+            0 => ("fixed", fixed),
+            _ => ("adaptive", ChunkBounds::default()),
+        };
+
+        let hash = format!("{:064x}", run + 1);
+
+        let (summary, stored) = push(&services, &hash, data.clone(), bounds).await;
+
+        println!("{}: {} (stored in {:?})", name, summary, stored);
+
+        match run % 2 {
+            0 => {
+                sent_fixed += summary.elapsed;
+                stored_fixed += stored;
+            }
+            _ => {
+                sent_adaptive += summary.elapsed;
+                stored_adaptive += stored;
+            }
+        }
+    }
+
+    let sent_ratio = sent_fixed.as_secs_f64() / sent_adaptive.as_secs_f64();
+    let stored_ratio = stored_fixed.as_secs_f64() / stored_adaptive.as_secs_f64();
+
+    println!("adaptive send throughput: {:.2}x fixed", sent_ratio);
+    println!("adaptive stored throughput: {:.2}x fixed", stored_ratio);
+
+    // Measured on a single core loopback: 2.43x send and 0.81x stored. Larger chunks drain the
+    // client faster, but the registry then processes fewer, larger messages without overlap, so
+    // stored time is only held within a tolerance of fixed
+
+    assert!(
+        sent_ratio >= 1.5,
+        "adaptive send throughput regressed: {:.2}x fixed",
+        sent_ratio
+    );
+
+    assert!(
+        stored_ratio >= 0.7,
+        "adaptive stored throughput regressed: {:.2}x fixed",
+        stored_ratio
+    );
+}
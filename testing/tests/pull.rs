@@ -0,0 +1,233 @@
+use sha2::{Digest, Sha256};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::net::TcpListener;
+use tokio_stream::{iter, wrappers::TcpListenerStream, Stream};
+use tonic::{async_trait, transport::Server, Request, Response, Status, Streaming};
+use vorpal_schema::vorpal::registry::v0::{
+    registry_service_client::RegistryServiceClient,
+    registry_service_server::{RegistryService, RegistryServiceServer},
+    RegistryClosureRequest, RegistryClosureResponse, RegistryDeltaIndexRequest,
+    RegistryDeltaIndexResponse, RegistryExistsBatchRequest, RegistryExistsBatchResponse,
+    RegistryInfoRequest, RegistryInfoResponse, RegistryListRequest, RegistryListResponse,
+    RegistryPruneRequest, RegistryPruneResponse, RegistryPullResponse, RegistryPushRequest,
+    RegistryRequest, RegistryResponse, RegistrySearchRequest, RegistrySearchResponse,
+};
+use vorpal_store::{
+    archives::ArchiveWriter, chunks::CHUNK_MESSAGE_SIZE_LIMIT, digests::ArchiveDigest,
+};
+
+const MB: usize = 1024 * 1024;
+
+const PULL_CHUNK_COUNT: usize = 128;
+
+// Peak allocated while pulling, far below the size of the pulled archive
+const PULL_MEMORY_LIMIT: usize = 32 * MB;
+
+// Counts heap allocations of this test binary, so pulls are measured without other tests
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+
+        ALLOCATED_PEAK.fetch_max(allocated, Ordering::SeqCst);
+
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn get_chunk(index: usize) -> Vec<u8> {
+    let mut chunk = vec![(index % 251) as u8; MB];
+
+    chunk[..8].copy_from_slice(&index.to_le_bytes());
+
+    chunk
+}
+
+// Generates each chunk as it is sent, so the registry never holds the archive either
+struct StreamRegistry {
+    data_digest: ArchiveDigest,
+}
+
+impl StreamRegistry {
+    fn new() -> Self {
+        let mut hasher = Sha256::new();
+
+        for index in 0..PULL_CHUNK_COUNT {
+            hasher.update(get_chunk(index));
+        }
+
+        Self {
+            data_digest: ArchiveDigest::from_hasher(hasher),
+        }
+    }
+}
+
+#[async_trait]
+impl RegistryService for StreamRegistry {
+    type PullStream = Pin<Box<dyn Stream<Item = Result<RegistryPullResponse, Status>> + Send>>;
+
+    async fn exists(
+        &self,
+        _request: Request<RegistryRequest>,
+    ) -> Result<Response<RegistryResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn exists_batch(
+        &self,
+        _request: Request<RegistryExistsBatchRequest>,
+    ) -> Result<Response<RegistryExistsBatchResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn push(
+        &self,
+        _request: Request<Streaming<RegistryPushRequest>>,
+    ) -> Result<Response<RegistryResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn pull(
+        &self,
+        _request: Request<RegistryRequest>,
+    ) -> Result<Response<Self::PullStream>, Status> {
+        let digest = RegistryPullResponse {
+            data: vec![],
+            data_digest: self.data_digest.to_string(),
+        };
+
+        let chunks = (0..PULL_CHUNK_COUNT).map(|index| {
+            Ok(RegistryPullResponse {
+                data: get_chunk(index),
+                data_digest: String::new(),
+            })
+        });
+
+        let responses = std::iter::once(Ok(digest)).chain(chunks);
+
+        Ok(Response::new(Box::pin(iter(responses))))
+    }
+
+    async fn info(
+        &self,
+        _request: Request<RegistryInfoRequest>,
+    ) -> Result<Response<RegistryInfoResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn list(
+        &self,
+        _request: Request<RegistryListRequest>,
+    ) -> Result<Response<RegistryListResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn search(
+        &self,
+        _request: Request<RegistrySearchRequest>,
+    ) -> Result<Response<RegistrySearchResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn prune(
+        &self,
+        _request: Request<RegistryPruneRequest>,
+    ) -> Result<Response<RegistryPruneResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn delta_index(
+        &self,
+        _request: Request<RegistryDeltaIndexRequest>,
+    ) -> Result<Response<RegistryDeltaIndexResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+
+    async fn closure(
+        &self,
+        _request: Request<RegistryClosureRequest>,
+    ) -> Result<Response<RegistryClosureResponse>, Status> {
+        Err(Status::unimplemented("stub"))
+    }
+}
+
+#[tokio::test]
+async fn test_pull_memory_bounded() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(
+        Server::builder()
+            .add_service(RegistryServiceServer::new(StreamRegistry::new()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = RegistryServiceClient::connect(address)
+        .await
+        .unwrap()
+        .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT);
+
+    let root = tempfile::tempdir().unwrap();
+    let archive_path = root.path().join("archive");
+
+    // Pulls like the CLI and worker do, each chunk goes to the archive file as it arrives
+
+    let allocated = ALLOCATED.load(Ordering::SeqCst);
+
+    ALLOCATED_PEAK.store(allocated, Ordering::SeqCst);
+
+    let mut response = client
+        .pull(RegistryRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut archive = ArchiveWriter::create(&archive_path).await.unwrap();
+    let mut data_digest = None;
+
+    while let Some(message) = response.message().await.unwrap() {
+        if !message.data_digest.is_empty() {
+            data_digest = Some(message.data_digest.parse::<ArchiveDigest>().unwrap());
+        }
+
+        archive.write(&message.data).await.unwrap();
+    }
+
+    assert_eq!(archive.size(), (PULL_CHUNK_COUNT * MB) as u64);
+
+    archive
+        .finish(data_digest.as_ref(), "example")
+        .await
+        .unwrap();
+
+    let allocated_peak = ALLOCATED_PEAK.load(Ordering::SeqCst) - allocated;
+
+    assert!(
+        allocated_peak < PULL_MEMORY_LIMIT,
+        "pulling {}MB allocated up to {}MB",
+        PULL_CHUNK_COUNT,
+        allocated_peak / MB
+    );
+
+    assert_eq!(
+        std::fs::metadata(&archive_path).unwrap().len(),
+        (PULL_CHUNK_COUNT * MB) as u64
+    );
+}
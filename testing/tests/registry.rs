@@ -0,0 +1,130 @@
+use tokio::fs::{create_dir_all, read, write};
+use tokio_stream::iter;
+use tonic::Code;
+use vorpal_schema::vorpal::registry::v0::{
+    RegistryCompression, RegistryKind, RegistryPushRequest, RegistryRequest,
+};
+use vorpal_store::{
+    archives::compress_zstd,
+    digests::{verify_pulled_data, ArchiveDigest},
+    paths::get_private_key_path,
+};
+use vorpal_testing::TestEnvironment;
+
+const ARTIFACT_NAME: &str = "example";
+
+// Archive of a single file, as a worker pushes it
+async fn get_archive(environment: &TestEnvironment) -> Vec<u8> {
+    let source_path = environment.root_path().join("source");
+    let archive_path = environment.root_path().join("source.tar.zst");
+
+    create_dir_all(&source_path).await.unwrap();
+
+    write(source_path.join("hello.txt"), "hello").await.unwrap();
+
+    compress_zstd(
+        &source_path,
+        &[source_path.join("hello.txt")],
+        &archive_path,
+    )
+    .await
+    .unwrap();
+
+    read(archive_path).await.unwrap()
+}
+
+fn get_push_request(hash: &str, data: Vec<u8>, data_signature: Vec<u8>) -> RegistryPushRequest {
+    RegistryPushRequest {
+        compression: RegistryCompression::Zstd as i32,
+        data,
+        data_signature,
+        data_signature_fingerprint: String::new(),
+        delta_base: String::new(),
+        hash: hash.to_string(),
+        kind: RegistryKind::Artifact as i32,
+        name: ARTIFACT_NAME.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_push_and_pull_signed_archive() {
+    let environment = TestEnvironment::new().await.unwrap();
+    let services = environment.start().await.unwrap();
+    let mut client = services.registry_client().await.unwrap();
+
+    let archive = get_archive(&environment).await;
+    let hash = "a".repeat(64);
+
+    let signature = vorpal_notary::sign(get_private_key_path(), &archive)
+        .await
+        .unwrap();
+
+    client
+        .push(iter([get_push_request(
+            &hash,
+            archive.clone(),
+            signature.to_vec(),
+        )]))
+        .await
+        .unwrap();
+
+    let mut response = client
+        .pull(RegistryRequest {
+            accept_dictionary: false,
+            hash: hash.clone(),
+            kind: RegistryKind::Artifact as i32,
+            name: ARTIFACT_NAME.to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut data = vec![];
+    let mut data_digest = None;
+
+    while let Some(message) = response.message().await.unwrap() {
+        if !message.data_digest.is_empty() {
+            data_digest = Some(message.data_digest.parse::<ArchiveDigest>().unwrap());
+        }
+
+        data.extend(message.data);
+    }
+
+    assert_eq!(data, archive);
+    assert!(data_digest.is_some());
+
+    verify_pulled_data(data_digest.as_ref(), &data, ARTIFACT_NAME).unwrap();
+}
+
+#[tokio::test]
+async fn test_push_rejects_invalid_signature() {
+    let environment = TestEnvironment::new().await.unwrap();
+    let services = environment.start().await.unwrap();
+    let mut client = services.registry_client().await.unwrap();
+
+    let archive = get_archive(&environment).await;
+    let hash = "b".repeat(64);
+
+    let signature = vorpal_notary::sign(get_private_key_path(), b"other data")
+        .await
+        .unwrap();
+
+    let status = client
+        .push(iter([get_push_request(&hash, archive, signature.to_vec())]))
+        .await
+        .unwrap_err();
+
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert!(status.message().contains("invalid data signature"));
+
+    let exists = client
+        .exists(RegistryRequest {
+            accept_dictionary: false,
+            hash,
+            kind: RegistryKind::Artifact as i32,
+            name: ARTIFACT_NAME.to_string(),
+        })
+        .await;
+
+    assert_eq!(exists.unwrap_err().code(), Code::NotFound);
+}
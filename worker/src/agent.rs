@@ -0,0 +1,858 @@
+use anyhow::{bail, Result};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{create_dir_all, metadata, remove_file, rename, File},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+use uuid::Uuid;
+use vorpal_schema::vorpal::agent::v0::{
+    agent_service_server::AgentService, AgentDownloadRequest, AgentDownloadResponse,
+    AgentStatsRequest, AgentStatsResponse,
+};
+use vorpal_store::{
+    chunks::CHUNK_CHANNEL_SIZE, http::get_http_client, metrics::Metric,
+    paths::get_download_dir_path,
+};
+
+// Size of the chunks downloads are sent to clients in
+const AGENT_DOWNLOAD_CHUNK_SIZE: usize = 1024 * 1024; // 1MB
+
+// Upstream downloads running at once, and how many of them a single client can hold
+pub const DEFAULT_AGENT_UPSTREAM_MAX: usize = 8;
+pub const DEFAULT_AGENT_UPSTREAM_CLIENT_MAX: usize = 2;
+
+// Downloads without a content digest are downloaded again once older than this
+pub const DEFAULT_AGENT_CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub static AGENT_DOWNLOAD_BYTES_TOTAL: Metric = Metric::counter(
+    "vorpal_agent_download_bytes_total",
+    "Bytes of downloads served by the agent, by origin (cache or upstream).",
+);
+
+pub static AGENT_DOWNLOAD_TOTAL: Metric = Metric::counter(
+    "vorpal_agent_download_total",
+    "Downloads requested from the agent, by result (hit, miss or error).",
+);
+
+/// Counters of the downloads served by an agent since it started.
+#[derive(Debug, Default)]
+pub struct AgentStats {
+    bytes_cache: AtomicU64,
+    bytes_upstream: AtomicU64,
+    cache_errors: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    preparations: Mutex<HashMap<String, u64>>,
+}
+
+impl AgentStats {
+    fn record_bytes(&self, cached: bool, bytes: usize) {
+        let origin = match cached {
+            true => {
+                self.bytes_cache.fetch_add(bytes as u64, Ordering::Relaxed);
+                "cache"
+            }
+            false => {
+                self.bytes_upstream
+                    .fetch_add(bytes as u64, Ordering::Relaxed);
+                "upstream"
+            }
+        };
+
+        AGENT_DOWNLOAD_BYTES_TOTAL
+            .increment(&[("origin", origin), ("service", "agent")], bytes as u64);
+    }
+
+    fn record_download(&self, result: Result<bool, ()>) {
+        let result = match result {
+            Ok(true) => {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                "hit"
+            }
+            Ok(false) => {
+                self.cache_misses.fetch_add(1, Ordering::Relaxed);
+                "miss"
+            }
+            Err(()) => {
+                self.cache_errors.fetch_add(1, Ordering::Relaxed);
+                "error"
+            }
+        };
+
+        AGENT_DOWNLOAD_TOTAL.increment(&[("result", result), ("service", "agent")], 1);
+    }
+
+    /// Marks a preparation of `client` as active until the returned guard is dropped.
+    fn start_preparation(self: &Arc<Self>, client: &str) -> AgentPreparation {
+        *self
+            .preparations
+            .lock()
+            .unwrap()
+            .entry(client.to_string())
+            .or_default() += 1;
+
+        AgentPreparation {
+            client: client.to_string(),
+            stats: self.clone(),
+        }
+    }
+
+    pub fn response(&self) -> AgentStatsResponse {
+        let preparations = self.preparations.lock().unwrap();
+
+        AgentStatsResponse {
+            bytes_cache: self.bytes_cache.load(Ordering::Relaxed),
+            bytes_upstream: self.bytes_upstream.load(Ordering::Relaxed),
+            cache_errors: self.cache_errors.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            clients_active: preparations.len() as u64,
+            preparations_active: preparations.values().sum(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+// Counts a download as active for its client until dropped
+struct AgentPreparation {
+    client: String,
+    stats: Arc<AgentStats>,
+}
+
+impl Drop for AgentPreparation {
+    fn drop(&mut self) {
+        let mut preparations = self.stats.preparations.lock().unwrap();
+
+        if let Some(count) = preparations.get_mut(&self.client) {
+            *count -= 1;
+
+            if *count == 0 {
+                preparations.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// Limits the upstream downloads of an agent. Each client holds at most `client_max` of the
+/// `max` downloads and queues the rest behind its own share, so a client preparing many sources
+/// can not hold every download while others wait.
+#[derive(Debug)]
+pub struct AgentLimits {
+    client_max: usize,
+    clients: Mutex<HashMap<String, Arc<Semaphore>>>,
+    upstream: Arc<Semaphore>,
+}
+
+/// Upstream download slot of a client, released when dropped.
+pub struct AgentPermit {
+    _client: OwnedSemaphorePermit,
+    _upstream: OwnedSemaphorePermit,
+}
+
+impl AgentLimits {
+    pub fn new(max: usize, client_max: usize) -> Self {
+        Self {
+            client_max: client_max.max(1),
+            clients: Mutex::new(HashMap::new()),
+            upstream: Arc::new(Semaphore::new(max.max(1))),
+        }
+    }
+
+    pub async fn acquire(&self, client: &str) -> Result<AgentPermit, Status> {
+        let semaphore = {
+            let mut clients = self.clients.lock().unwrap();
+
+            // Clients without permits or waiters hold the only reference to their semaphore
+
+            clients.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+
+            clients
+                .entry(client.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.client_max)))
+                .clone()
+        };
+
+        let client = semaphore
+            .acquire_owned()
+            .await
+            .map_err(|_| Status::unavailable("agent limits closed"))?;
+
+        let upstream = self
+            .upstream
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| Status::unavailable("agent limits closed"))?;
+
+        Ok(AgentPermit {
+            _client: client,
+            _upstream: upstream,
+        })
+    }
+}
+
+impl Default for AgentLimits {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_AGENT_UPSTREAM_MAX,
+            DEFAULT_AGENT_UPSTREAM_CLIENT_MAX,
+        )
+    }
+}
+
+/// Returns the sha256 hex of the file at `path`.
+async fn get_file_digest(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; AGENT_DOWNLOAD_CHUNK_SIZE];
+
+    loop {
+        let size = file.read(&mut buffer).await?;
+
+        if size == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..size]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn download_file(url: &str, path: &Path) -> Result<()> {
+    let mut response = get_http_client()?.get(url).send().await?;
+
+    if !response.status().is_success() {
+        bail!("unexpected status: {}", response.status());
+    }
+
+    let mut file = File::create(path).await?;
+
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+/// Downloads kept by url in a directory, shared by every client of an agent and kept across
+/// restarts. Concurrent requests for one url download it once.
+///
+/// Requests with a content digest are only served a download matching it, from the cache or
+/// upstream. Without one, downloads older than `max_age` are downloaded again.
+#[derive(Debug)]
+pub struct DownloadCache {
+    downloads: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+    max_age: Duration,
+    path: PathBuf,
+}
+
+impl DownloadCache {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            downloads: Mutex::new(HashMap::new()),
+            max_age: DEFAULT_AGENT_CACHE_MAX_AGE,
+            path,
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn get_path(&self, url: &str) -> PathBuf {
+        self.path
+            .join(format!("{:x}", Sha256::digest(url.as_bytes())))
+    }
+
+    /// Returns the cached file of `url` and whether it was cached, downloading it with a permit
+    /// of `limits` for `client` first when it was not. `content_digest` is the sha256 hex the
+    /// file must match.
+    pub async fn prepare(
+        &self,
+        url: &str,
+        content_digest: Option<&str>,
+        client: &str,
+        limits: &AgentLimits,
+    ) -> Result<(PathBuf, bool)> {
+        let download = self
+            .downloads
+            .lock()
+            .unwrap()
+            .entry(url.to_string())
+            .or_default()
+            .clone();
+
+        let result = {
+            let _download = download.lock().await;
+
+            self.prepare_locked(url, content_digest, client, limits)
+                .await
+        };
+
+        // The map and this request hold the last references when nobody else waits for the url
+
+        let mut downloads = self.downloads.lock().unwrap();
+
+        if Arc::strong_count(&download) == 2 {
+            downloads.remove(url);
+        }
+
+        result
+    }
+
+    /// Returns whether the cached file at `path` can be served, removing it when it can not.
+    async fn is_cached(&self, path: &Path, content_digest: Option<&str>) -> Result<bool> {
+        let Ok(metadata) = metadata(path).await else {
+            return Ok(false);
+        };
+
+        let is_valid = match content_digest {
+            Some(expected) => get_file_digest(path).await? == expected,
+            None => metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|age| age < self.max_age),
+        };
+
+        if !is_valid {
+            remove_file(path).await?;
+        }
+
+        Ok(is_valid)
+    }
+
+    async fn prepare_locked(
+        &self,
+        url: &str,
+        content_digest: Option<&str>,
+        client: &str,
+        limits: &AgentLimits,
+    ) -> Result<(PathBuf, bool)> {
+        let cache_path = self.get_path(url);
+
+        if self.is_cached(&cache_path, content_digest).await? {
+            return Ok((cache_path, true));
+        }
+
+        let _permit = limits.acquire(client).await?;
+
+        create_dir_all(&self.path).await?;
+
+        let temp_path = cache_path.with_extension(Uuid::now_v7().to_string());
+
+        if let Err(err) = download_file(url, &temp_path).await {
+            let _ = remove_file(&temp_path).await;
+
+            return Err(err);
+        }
+
+        if let Some(expected) = content_digest {
+            let actual = get_file_digest(&temp_path).await?;
+
+            if actual != expected {
+                let _ = remove_file(&temp_path).await;
+
+                bail!(
+                    "content mismatch: sha256:{} (expected sha256:{})",
+                    actual,
+                    expected
+                );
+            }
+        }
+
+        rename(&temp_path, &cache_path).await?;
+
+        info!("agent cached download: {}", url);
+
+        Ok((cache_path, false))
+    }
+}
+
+// Clients are told apart by address, the agent has no accounts of its own
+fn get_client_id<T>(request: &Request<T>) -> String {
+    request
+        .remote_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Serves source downloads from a persistent download cache, so a team sharing an agent
+/// downloads each source from upstream once.
+///
+/// Only urls of `hosts` are downloaded, so clients can not make the agent fetch internal
+/// services on their behalf.
+#[derive(Clone, Debug)]
+pub struct AgentServer {
+    cache: Arc<DownloadCache>,
+    hosts: Arc<HashSet<String>>,
+    limits: Arc<AgentLimits>,
+    stats: Arc<AgentStats>,
+}
+
+impl AgentServer {
+    pub fn new(cache: DownloadCache, limits: AgentLimits) -> Self {
+        Self {
+            cache: Arc::new(cache),
+            hosts: Arc::new(HashSet::new()),
+            limits: Arc::new(limits),
+            stats: Arc::new(AgentStats::default()),
+        }
+    }
+
+    pub fn with_hosts(mut self, hosts: &[String]) -> Self {
+        self.hosts = Arc::new(hosts.iter().map(|host| host.to_lowercase()).collect());
+        self
+    }
+
+    /// Checks that `url` is http or https on one of the allowed hosts.
+    fn check_download_url(&self, url: &str) -> Result<(), Status> {
+        let parsed = Url::parse(url)
+            .map_err(|err| Status::invalid_argument(format!("invalid url {}: {}", url, err)))?;
+
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(Status::invalid_argument(format!(
+                "download url must be http or https: {}",
+                url
+            )));
+        }
+
+        let host = parsed.host_str().unwrap_or_default().to_lowercase();
+
+        if !self.hosts.contains(&host) {
+            return Err(Status::permission_denied(format!(
+                "download host not allowed by the agent: {}",
+                host
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for AgentServer {
+    fn default() -> Self {
+        Self::new(
+            DownloadCache::new(get_download_dir_path()),
+            AgentLimits::default(),
+        )
+    }
+}
+
+#[tonic::async_trait]
+impl AgentService for AgentServer {
+    type DownloadStream = ReceiverStream<Result<AgentDownloadResponse, Status>>;
+
+    async fn download(
+        &self,
+        request: Request<AgentDownloadRequest>,
+    ) -> Result<Response<Self::DownloadStream>, Status> {
+        let client = get_client_id(&request);
+        let request = request.into_inner();
+
+        self.check_download_url(&request.url)?;
+
+        let url = request.url;
+
+        let content_digest = match request.content_digest.as_str() {
+            "" => None,
+            digest if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Some(digest.to_lowercase())
+            }
+            digest => {
+                return Err(Status::invalid_argument(format!(
+                    "content digest must be sha256 hex: {}",
+                    digest
+                )))
+            }
+        };
+
+        let preparation = self.stats.start_preparation(&client);
+
+        let prepared = self
+            .cache
+            .prepare(&url, content_digest.as_deref(), &client, &self.limits)
+            .await;
+
+        let (path, cached) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                self.stats.record_download(Err(()));
+
+                warn!("agent failed to download {}: {}", url, err);
+
+                return Err(Status::unavailable(format!(
+                    "failed to download {}: {}",
+                    url, err
+                )));
+            }
+        };
+
+        self.stats.record_download(Ok(cached));
+
+        let mut file = File::open(&path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to open download: {}", err)))?;
+
+        let (tx, rx) = mpsc::channel(CHUNK_CHANNEL_SIZE);
+
+        let stats = self.stats.clone();
+
+        tokio::spawn(async move {
+            let _preparation = preparation;
+
+            let mut buffer = vec![0; AGENT_DOWNLOAD_CHUNK_SIZE];
+
+            loop {
+                let size = match file.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(size) => size,
+                    Err(err) => {
+                        let status = Status::internal(format!("failed to read download: {}", err));
+
+                        let _ = tx.send(Err(status)).await;
+
+                        break;
+                    }
+                };
+
+                stats.record_bytes(cached, size);
+
+                let response = AgentDownloadResponse {
+                    data: buffer[..size].to_vec(),
+                };
+
+                if tx.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_agent_stats(
+        &self,
+        _request: Request<AgentStatsRequest>,
+    ) -> Result<Response<AgentStatsResponse>, Status> {
+        Ok(Response::new(self.stats.response()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::SocketAddr, sync::atomic::AtomicUsize, time::Duration};
+    use tokio::{
+        net::TcpListener,
+        time::{sleep, timeout},
+    };
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Channel, Server};
+    use vorpal_schema::vorpal::agent::v0::{
+        agent_service_client::AgentServiceClient, agent_service_server::AgentServiceServer,
+    };
+
+    const UPSTREAM_BODY: &[u8] = b"source archive contents";
+
+    // Serves `body` with `status` to every request, counting the requests
+    async fn start_upstream(
+        status: &'static str,
+        body: &'static [u8],
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buffer = [0; 1024];
+
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(size) => request.extend_from_slice(&buffer[..size]),
+                        }
+                    }
+
+                    // Slow enough for concurrent requests to overlap
+
+                    sleep(Duration::from_millis(100)).await;
+
+                    let head = format!(
+                        "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(body).await;
+                });
+            }
+        });
+
+        (format!("http://{}/source.tar.gz", address), requests)
+    }
+
+    async fn start_agent(server: AgentServer) -> AgentServiceClient<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(AgentServiceServer::new(server))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        AgentServiceClient::connect(format!("http://{}", address))
+            .await
+            .unwrap()
+    }
+
+    fn new_agent(cache_path: &Path) -> AgentServer {
+        AgentServer::new(
+            DownloadCache::new(cache_path.to_path_buf()),
+            AgentLimits::default(),
+        )
+        .with_hosts(&["127.0.0.1".to_string()])
+    }
+
+    async fn download(
+        client: &mut AgentServiceClient<Channel>,
+        url: &str,
+        content_digest: &str,
+    ) -> Result<Vec<u8>, Status> {
+        let mut response = client
+            .download(AgentDownloadRequest {
+                content_digest: content_digest.to_string(),
+                url: url.to_string(),
+            })
+            .await?
+            .into_inner();
+
+        let mut data = vec![];
+
+        while let Some(message) = response.message().await? {
+            data.extend(message.data);
+        }
+
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn test_agent_serves_repeated_downloads_from_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, requests) = start_upstream("200 OK", UPSTREAM_BODY).await;
+
+        let mut client = start_agent(new_agent(cache_dir.path())).await;
+
+        assert_eq!(
+            download(&mut client, &url, "").await.unwrap(),
+            UPSTREAM_BODY
+        );
+        assert_eq!(
+            download(&mut client, &url, "").await.unwrap(),
+            UPSTREAM_BODY
+        );
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let stats = client
+            .get_agent_stats(AgentStatsRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_misses, 1);
+        assert_eq!(stats.cache_errors, 0);
+        assert_eq!(stats.bytes_cache, UPSTREAM_BODY.len() as u64);
+        assert_eq!(stats.bytes_upstream, UPSTREAM_BODY.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_agent_counts_upstream_errors_apart_from_misses() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, _) = start_upstream("404 Not Found", b"").await;
+
+        let mut client = start_agent(new_agent(cache_dir.path())).await;
+
+        let status = download(&mut client, &url, "").await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        let stats = client
+            .get_agent_stats(AgentStatsRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(stats.cache_errors, 1);
+        assert_eq!(stats.cache_misses, 0);
+        assert_eq!(stats.preparations_active, 0);
+        assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_agent_rejects_non_http_urls() {
+        let cache_dir = tempfile::tempdir().unwrap();
+
+        let mut client = start_agent(new_agent(cache_dir.path())).await;
+
+        let status = download(&mut client, "file:///etc/passwd", "")
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_agent_rejects_hosts_not_allowed() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, requests) = start_upstream("200 OK", UPSTREAM_BODY).await;
+
+        let mut client =
+            start_agent(new_agent(cache_dir.path()).with_hosts(&["example.com".to_string()])).await;
+
+        let status = download(&mut client, &url, "").await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_agent_rejects_downloads_not_matching_content_digest() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, _) = start_upstream("200 OK", UPSTREAM_BODY).await;
+
+        let mut client = start_agent(new_agent(cache_dir.path())).await;
+
+        let status = download(&mut client, &url, &"0".repeat(64))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_download_cache_replaces_files_not_matching_content_digest() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, requests) = start_upstream("200 OK", UPSTREAM_BODY).await;
+        let content_digest = format!("{:x}", Sha256::digest(UPSTREAM_BODY));
+        let limits = AgentLimits::default();
+
+        let cache = DownloadCache::new(cache_dir.path().to_path_buf());
+
+        std::fs::write(cache.get_path(&url), b"poisoned").unwrap();
+
+        let (path, cached) = cache
+            .prepare(&url, Some(&content_digest), "client", &limits)
+            .await
+            .unwrap();
+
+        assert!(!cached);
+        assert_eq!(std::fs::read(path).unwrap(), UPSTREAM_BODY);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_cache_expires_files_without_content_digest() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, requests) = start_upstream("200 OK", UPSTREAM_BODY).await;
+        let limits = AgentLimits::default();
+
+        let cache = DownloadCache::new(cache_dir.path().to_path_buf()).with_max_age(Duration::ZERO);
+
+        let (_, cached) = cache.prepare(&url, None, "client", &limits).await.unwrap();
+
+        assert!(!cached);
+
+        let (_, cached) = cache.prepare(&url, None, "client", &limits).await.unwrap();
+
+        assert!(!cached);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_download_cache_persists_across_instances() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, requests) = start_upstream("200 OK", UPSTREAM_BODY).await;
+        let limits = AgentLimits::default();
+
+        let cache = DownloadCache::new(cache_dir.path().to_path_buf());
+        let (_, cached) = cache.prepare(&url, None, "client", &limits).await.unwrap();
+
+        assert!(!cached);
+
+        let cache = DownloadCache::new(cache_dir.path().to_path_buf());
+        let (path, cached) = cache.prepare(&url, None, "client", &limits).await.unwrap();
+
+        assert!(cached);
+        assert_eq!(std::fs::read(path).unwrap(), UPSTREAM_BODY);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_cache_downloads_concurrent_requests_once() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (url, requests) = start_upstream("200 OK", UPSTREAM_BODY).await;
+        let limits = AgentLimits::default();
+
+        let cache = DownloadCache::new(cache_dir.path().to_path_buf());
+
+        let (first, second) = tokio::join!(
+            cache.prepare(&url, None, "a", &limits),
+            cache.prepare(&url, None, "b", &limits)
+        );
+
+        let mut cached = [first.unwrap().1, second.unwrap().1];
+
+        cached.sort();
+
+        assert_eq!(cached, [false, true]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(cache.downloads.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_agent_limits_share_downloads_across_clients() {
+        let limits = AgentLimits::new(2, 1);
+
+        let _first = limits.acquire("a").await.unwrap();
+
+        // The second download of `a` waits for its share while `b` still gets a slot
+
+        let queued = timeout(Duration::from_millis(50), limits.acquire("a")).await;
+
+        assert!(queued.is_err());
+
+        let other = timeout(Duration::from_millis(50), limits.acquire("b")).await;
+
+        assert!(other.is_ok());
+    }
+}
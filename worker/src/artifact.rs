@@ -1,61 +1,210 @@
-use anyhow::Result;
-use sha256::digest;
+use crate::{
+    cache::{get_step_digests, pull_step_snapshot, push_step_snapshot},
+    limits::{get_available_disk_bytes, is_allocation_failure, StepLimits},
+    push::{write_pending_push, PushPolicy, PushQueue},
+    queue::BuildQueue,
+    webhook::{BuildEvents, EventWebhook},
+};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
 use std::env::consts::{ARCH, OS};
-use std::path::Path;
-use std::{fs::Permissions, os::unix::fs::PermissionsExt, process::Stdio};
-use tokio::fs::remove_dir_all;
-use tokio::fs::{create_dir_all, read, remove_file, write, File};
-use tokio::io::AsyncWriteExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs::Permissions, io::ErrorKind, os::unix::fs::PermissionsExt, process::Stdio};
+use tokio::fs::{create_dir_all, read, remove_file, rename, write};
+use tokio::fs::{hard_link, read_dir, read_to_string, remove_dir_all};
 use tokio::process::Command;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::sleep;
 use tokio::{
     fs::set_permissions,
     io::{AsyncBufReadExt, BufReader},
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{wrappers::LinesStream, StreamExt};
-use tonic::{Request, Response, Status};
-use tracing::error;
+use tonic::{Code, Request, Response, Status};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 use vorpal_schema::vorpal::artifact::v0::{
-    Artifact, ArtifactId, ArtifactSourceId, ArtifactStepEnvironment,
+    Artifact, ArtifactBuildDuration, ArtifactBuildDurationKind, ArtifactBuildEvent,
+    ArtifactBuildPhase, ArtifactBuildStream, ArtifactId, ArtifactProvenance,
+    ArtifactProvenanceEnvelope, ArtifactProvenanceStep, ArtifactSourceId, ArtifactStepEnvironment,
 };
 use vorpal_schema::vorpal::{
     artifact::v0::ArtifactSystem,
     artifact::v0::{
         artifact_service_server::ArtifactService, ArtifactBuildRequest, ArtifactBuildResponse,
+        ArtifactBuildState, ArtifactBuildStatusRequest, ArtifactBuildStatusResponse,
+        ArtifactInfoRequest, ArtifactInfoResponse, ArtifactWorkerStatusRequest,
+        ArtifactWorkerStatusResponse,
     },
 };
 use vorpal_schema::{
-    get_artifact_system,
+    expand_step_placeholders, get_artifact_envkey_name, get_artifact_system,
+    is_artifact_system_buildable,
+    sources::SourceError,
+    validate_artifact_environments, validate_artifact_placeholders, validate_artifact_references,
+    validate_artifact_step_paths, validate_artifact_steps, validate_artifact_system,
     vorpal::{
         artifact::v0::ArtifactSystem::UnknownSystem,
         registry::v0::{
-            registry_service_client::RegistryServiceClient, RegistryKind, RegistryPushRequest,
-            RegistryRequest,
+            registry_service_client::RegistryServiceClient, RegistryCompression, RegistryKind,
+            RegistryPushRequest, RegistryRequest,
         },
     },
+    STEP_PLACEHOLDER_ARTIFACT, STEP_PLACEHOLDER_OUTPUT, STEP_PLACEHOLDER_WORKSPACE,
 };
+use vorpal_store::grpc::{RegistryChannel, RegistryClients, RegistryUrls};
 use vorpal_store::temps::{create_sandbox_dir, create_sandbox_file};
 use vorpal_store::{
-    archives::{compress_zstd, unpack_zstd},
+    archives::{compress_archive, unpack_archive, ArchiveCompression, ArchiveWriter},
+    blobs::{dedup_path, is_store_dedup_enabled},
+    chunks::{stream_chunks, wait_network_limit, ChunkBounds, ChunkSummary},
+    dictionaries::decompress_archive_dictionary_file,
+    digests::{verify_pulled_data, ArchiveDigest, ArtifactDigest, SourceDigest, StepDigest},
+    incrementals::{restore_incremental, save_incremental},
+    manifests::{get_artifact_manifest, write_manifest},
+    metrics::Metric,
     paths::{
-        copy_files, get_artifact_lock_path, get_artifact_path, get_cache_path, get_file_paths,
-        get_private_key_path, get_source_archive_path, set_timestamps,
+        copy_files, get_artifact_lock_path, get_artifact_path, get_cache_path, get_dictionary_path,
+        get_file_paths, get_private_key_path, get_source_archive_path, get_store_dir_path,
+        remove_excluded_paths, sanitize_file, set_timestamps,
     },
 };
 
-const DEFAULT_CHUNKS_SIZE: usize = 8192; // default grpc limit
+// Interval of lock checks, waiters also wake as soon as a build of this worker releases a lock
+const LOCK_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+const LOCK_WAIT_MESSAGE_POLLS: u32 = 30;
+
+// Serializes breaking stale locks in this worker, so two waiters never replace each other's lock
+static LOCK_BREAK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+// Notified when a build of this worker releases its lock, locks of other processes are polled
+static LOCK_RELEASED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+// Replacement for secret values in step output
+const SECRET_REDACTED: &str = "[REDACTED]";
+
+// Free bytes required in the store before a build starts, unless the artifact sets its own
+pub const DEFAULT_MIN_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+
+// Worker variables passed to steps on macOS, where steps run without a sandbox
+const STEP_ENV_ALLOWLIST_MACOS: [&str; 2] = ["HOME", "TMPDIR"];
+
+pub static WORKER_BUILD_DURATION_SECONDS: Metric = Metric::histogram(
+    "vorpal_worker_build_duration_seconds",
+    "Duration of worker builds, including time spent queued.",
+);
+
+pub static WORKER_BUILDS_FAILED_TOTAL: Metric = Metric::counter(
+    "vorpal_worker_builds_failed_total",
+    "Builds failed by the worker.",
+);
 
-#[derive(Debug, Default)]
+pub static WORKER_BUILDS_QUEUED: Metric = Metric::gauge(
+    "vorpal_worker_builds_queued",
+    "Builds waiting for a build slot on the worker.",
+);
+
+pub static WORKER_BUILDS_RUNNING: Metric = Metric::gauge(
+    "vorpal_worker_builds_running",
+    "Builds running on the worker.",
+);
+
+pub static WORKER_BUILDS_STARTED_TOTAL: Metric = Metric::counter(
+    "vorpal_worker_builds_started_total",
+    "Builds requested from the worker.",
+);
+
+pub static WORKER_BUILDS_SUCCEEDED_TOTAL: Metric = Metric::counter(
+    "vorpal_worker_builds_succeeded_total",
+    "Builds completed by the worker.",
+);
+
+const WORKER_METRIC_LABELS: [(&str, &str); 1] = [("service", "worker")];
+
+#[derive(Debug)]
 pub struct ArtifactServer {
-    pub registry: String,
+    pub archive_compression: ArchiveCompression,
+    pub build_queue: BuildQueue,
+    pub chunk_bounds: ChunkBounds,
+    pub env_passthrough: Vec<String>,
+    pub event_webhook: Option<EventWebhook>,
+    pub min_disk_bytes: u64,
+    pub push_policy: PushPolicy,
+    pub push_queue: PushQueue,
+    pub registries: RegistryUrls,
+    pub step_cache: bool,
     pub system: ArtifactSystem,
 }
 
 impl ArtifactServer {
-    pub fn new(registry: String, system: ArtifactSystem) -> Self {
-        Self { registry, system }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        archive_compression: ArchiveCompression,
+        chunk_bounds: ChunkBounds,
+        env_passthrough: Vec<String>,
+        event_webhook: Option<EventWebhook>,
+        max_concurrent_builds: usize,
+        min_disk_bytes: u64,
+        push_policy: PushPolicy,
+        registries: RegistryUrls,
+        step_cache: bool,
+        system: ArtifactSystem,
+    ) -> Self {
+        let build_queue = BuildQueue::new(max_concurrent_builds);
+        let push_queue = PushQueue::new(chunk_bounds, registries.push.clone());
+
+        let queued = build_queue.clone();
+        let running = build_queue.clone();
+
+        WORKER_BUILDS_QUEUED.register(&WORKER_METRIC_LABELS, move || queued.queued() as f64);
+        WORKER_BUILDS_RUNNING.register(&WORKER_METRIC_LABELS, move || running.running() as f64);
+
+        Self {
+            archive_compression,
+            build_queue,
+            chunk_bounds,
+            env_passthrough,
+            event_webhook,
+            min_disk_bytes,
+            push_policy,
+            push_queue,
+            registries,
+            step_cache,
+            system,
+        }
+    }
+
+    /// Checks the sandbox prerequisites of the worker, used for the health status at startup.
+    pub fn check(&self) -> Result<()> {
+        if !get_private_key_path().exists() {
+            bail!("private key not found");
+        }
+
+        if OS == "linux" && !is_command_available("bwrap") {
+            bail!("bwrap not found in PATH");
+        }
+
+        Ok(())
+    }
+}
+
+fn is_command_available(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|path| path.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+pub(crate) fn get_registry_compression(compression: ArchiveCompression) -> RegistryCompression {
+    match compression {
+        ArchiveCompression::Bzip2 => RegistryCompression::Bzip2,
+        ArchiveCompression::Gzip => RegistryCompression::Gzip,
+        ArchiveCompression::Xz => RegistryCompression::Xz,
+        ArchiveCompression::Zstd(_) => RegistryCompression::Zstd,
     }
 }
 
@@ -65,26 +214,87 @@ fn expand_env(text: &str, envs: &[&ArtifactStepEnvironment]) -> String {
     })
 }
 
+/// Returns the worker variables passed to steps: `HOME` and `TMPDIR` on macOS and any
+/// `--env-passthrough` names that are set. Declared environments override these.
+fn get_host_environments(env_passthrough: &[String]) -> Vec<ArtifactStepEnvironment> {
+    let mut keys = vec![];
+
+    if OS == "macos" {
+        keys.extend(STEP_ENV_ALLOWLIST_MACOS.iter().map(|key| key.to_string()));
+    }
+
+    keys.extend(env_passthrough.iter().cloned());
+
+    keys.iter()
+        .filter_map(|key| {
+            std::env::var(key)
+                .ok()
+                .map(|value| ArtifactStepEnvironment {
+                    key: key.clone(),
+                    value,
+                })
+        })
+        .collect()
+}
+
+fn redact_secrets(text: String, secrets: &[ArtifactStepEnvironment]) -> String {
+    secrets
+        .iter()
+        .filter(|s| !s.value.is_empty())
+        .fold(text, |acc, s| acc.replace(&s.value, SECRET_REDACTED))
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn run_step(
     artifact_artifacts: Vec<ArtifactId>,
+    artifact_environments: Vec<ArtifactStepEnvironment>,
     artifact_name: String,
     artifact_path: &Path,
+    host_environments: &[ArtifactStepEnvironment],
+    secrets: &[ArtifactStepEnvironment],
     step_arguments: Vec<String>,
+    step_cpu_limit: Option<u32>,
     step_entrypoint: Option<String>,
     step_environments: Vec<ArtifactStepEnvironment>,
+    step_memory_limit_bytes: Option<u64>,
+    step_output_subdir: Option<String>,
     step_script: Option<String>,
+    step_working_directory: Option<String>,
     tx: &Sender<Result<ArtifactBuildResponse, Status>>,
     workspace_path: &Path,
 ) -> Result<(), Status> {
+    // Setup output and working directory, both validated to stay within their roots
+
+    let output_path = match step_output_subdir {
+        Some(subdir) => artifact_path.join(subdir),
+        None => artifact_path.to_path_buf(),
+    };
+
+    let working_path = match step_working_directory {
+        Some(directory) => workspace_path.join(directory),
+        None => workspace_path.to_path_buf(),
+    };
+
+    for path in [&output_path, &working_path] {
+        create_dir_all(path).await.map_err(|err| {
+            Status::internal(format!("failed to create {}: {:?}", path.display(), err))
+        })?;
+    }
+
     let mut environments = vec![];
 
     // Add all artifact environment variables
 
     let mut paths = vec![];
+    let mut paths_by_digest = BTreeMap::new();
 
     for artifact in artifact_artifacts.iter() {
-        let path = get_artifact_path(&artifact.hash, &artifact.name);
+        let digest = artifact
+            .hash
+            .parse::<ArtifactDigest>()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let path = get_artifact_path(&digest, &artifact.name);
 
         if !path.exists() {
             return Err(Status::internal("artifact not found"));
@@ -93,23 +303,19 @@ async fn run_step(
         let path_str = path.display().to_string();
 
         environments.push(ArtifactStepEnvironment {
-            key: format!(
-                "VORPAL_ARTIFACT_{}",
-                artifact.name.to_lowercase().replace('-', "_")
-            ),
+            key: get_artifact_envkey_name(&artifact.name),
             value: path_str.clone(),
         });
 
+        paths_by_digest.insert(artifact.hash.clone(), path_str.clone());
         paths.push(path_str);
     }
 
     // Add default environment variables
 
-    let name_envkey = artifact_name.to_lowercase().replace('-', "_");
-
     environments.extend([
         ArtifactStepEnvironment {
-            key: format!("VORPAL_ARTIFACT_{}", name_envkey.clone()),
+            key: get_artifact_envkey_name(&artifact_name),
             value: artifact_path.display().to_string(),
         },
         ArtifactStepEnvironment {
@@ -118,7 +324,7 @@ async fn run_step(
         },
         ArtifactStepEnvironment {
             key: "VORPAL_OUTPUT".to_string(),
-            value: artifact_path.display().to_string(),
+            value: output_path.display().to_string(),
         },
         ArtifactStepEnvironment {
             key: "VORPAL_WORKSPACE".to_string(),
@@ -126,7 +332,11 @@ async fn run_step(
         },
     ]);
 
-    // Add all custom environment variables
+    // Add artifact-wide environment variables
+
+    environments.extend(artifact_environments);
+
+    // Add all custom environment variables (overrides are validated per artifact)
 
     environments.extend(step_environments);
 
@@ -141,6 +351,27 @@ async fn run_step(
         .filter(|e| e.key.starts_with("VORPAL_"))
         .collect();
 
+    // Resolve placeholders in the entrypoint and arguments to absolute paths
+
+    let resolve_placeholder = |placeholder: &str| match placeholder {
+        STEP_PLACEHOLDER_OUTPUT => Some(output_path.display().to_string()),
+        STEP_PLACEHOLDER_WORKSPACE => Some(workspace_path.display().to_string()),
+        _ => placeholder
+            .strip_prefix(STEP_PLACEHOLDER_ARTIFACT)
+            .and_then(|digest| paths_by_digest.get(digest).cloned()),
+    };
+
+    let step_entrypoint = step_entrypoint
+        .map(|entrypoint| expand_step_placeholders(&entrypoint, resolve_placeholder))
+        .transpose()
+        .map_err(Status::invalid_argument)?;
+
+    let step_arguments = step_arguments
+        .iter()
+        .map(|arg| expand_step_placeholders(arg, resolve_placeholder))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Status::invalid_argument)?;
+
     // Setup script
 
     let mut script_path = None;
@@ -148,7 +379,14 @@ async fn run_step(
     if let Some(script) = step_script {
         let script = expand_env(&script, &vorpal_envs);
 
-        let path = workspace_path.join("script.sh");
+        // PowerShell only runs script files with a `.ps1` extension
+
+        let script_name = match step_entrypoint.as_deref() {
+            Some("pwsh") => "script.ps1",
+            _ => "script.sh",
+        };
+
+        let path = workspace_path.join(script_name);
 
         write(&path, script)
             .await
@@ -173,17 +411,57 @@ async fn run_step(
 
     let mut command = Command::new(&entrypoint);
 
+    // Setup resource limits
+
+    let step_limits_name = workspace_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let step_limits =
+        StepLimits::new(&step_limits_name, step_cpu_limit, step_memory_limit_bytes).await;
+
+    step_limits.apply(&mut command);
+
+    if let Some(reason) = step_limits.fallback() {
+        send_build_response(
+            tx,
+            Ok(ArtifactBuildResponse {
+                durations: vec![],
+                event: None,
+                output: format!(
+                    "cgroup delegation not available, limits use affinity and the address space limit: {}",
+                    reason
+                ),
+                stream: ArtifactBuildStream::Stderr as i32,
+            }),
+        )
+        .await?;
+    }
+
     // Setup working directory
 
-    command.current_dir(workspace_path);
+    command.current_dir(&working_path);
+
+    // Setup environment variables (steps never inherit the worker environment)
+
+    command.env_clear();
 
-    // Setup environment variables
+    for env in host_environments.iter() {
+        command.env(&env.key, &env.value);
+    }
 
     for env in environments_sorted.iter() {
         let env_value = expand_env(&env.value, &vorpal_envs);
         command.env(&env.key, env_value);
     }
 
+    // Setup secrets (never expanded into scripts or arguments)
+
+    for secret in secrets.iter() {
+        command.env(&secret.key, &secret.value);
+    }
+
     // Setup arguments
 
     if !entrypoint.is_empty() {
@@ -215,32 +493,149 @@ async fn run_step(
         .take()
         .ok_or_else(|| Status::internal("Failed to capture stderr from the spawned sandbox"))?;
 
-    let stdout = LinesStream::new(BufReader::new(stdout).lines());
-    let stderr = LinesStream::new(BufReader::new(stderr).lines());
+    // Tag lines with their stream, merging keeps the order lines are read in
+
+    let stdout = LinesStream::new(BufReader::new(stdout).lines())
+        .map(|line| (ArtifactBuildStream::Stdout, line));
+    let stderr = LinesStream::new(BufReader::new(stderr).lines())
+        .map(|line| (ArtifactBuildStream::Stderr, line));
 
     let mut stdio_merged = StreamExt::merge(stdout, stderr);
 
-    while let Some(line) = stdio_merged.next().await {
+    let mut step_allocation_failed = false;
+
+    while let Some((stream, line)) = stdio_merged.next().await {
         let output = line
             .map_err(|err| Status::internal(format!("failed to read sandbox output: {:?}", err)))?;
 
-        tx.send(Ok(ArtifactBuildResponse { output }))
-            .await
-            .map_err(|err| Status::internal(format!("failed to send sandbox output: {:?}", err)))?;
+        if stream == ArtifactBuildStream::Stderr && is_allocation_failure(&output) {
+            step_allocation_failed = true;
+        }
+
+        let output = redact_secrets(output, secrets);
+
+        tx.send(Ok(ArtifactBuildResponse {
+            durations: vec![],
+            event: None,
+            output,
+            stream: stream as i32,
+        }))
+        .await
+        .map_err(|err| Status::internal(format!("failed to send sandbox output: {:?}", err)))?;
     }
 
     let status = child
         .wait()
         .await
-        .map_err(|err| Status::internal(format!("failed to wait for sandbox: {:?}", err)))?;
+        .map_err(|err| Status::internal(format!("failed to wait for sandbox: {:?}", err)));
+
+    let step_memory_exceeded = status
+        .as_ref()
+        .is_ok_and(|status| step_limits.is_memory_exceeded(status, step_allocation_failed));
+    let step_memory_message = step_limits.get_memory_exceeded_message();
+
+    step_limits.cleanup().await;
+
+    if step_memory_exceeded {
+        send_build_response(
+            tx,
+            Ok(ArtifactBuildResponse {
+                durations: vec![],
+                event: None,
+                output: step_memory_message.clone(),
+                stream: ArtifactBuildStream::Stderr as i32,
+            }),
+        )
+        .await?;
+
+        return Err(Status::resource_exhausted(step_memory_message));
+    }
 
-    if !status.success() {
+    if !status?.success() {
         return Err(Status::internal("sandbox failed"));
     }
 
     Ok(())
 }
 
+/// Runs the output checks declared by an artifact.
+///
+/// All missing paths are reported together, the check command runs with bash in the output
+/// directory with the output `bin` directory first in `PATH`.
+async fn check_artifact_output(
+    artifact: &Artifact,
+    artifact_path: &Path,
+    host_environments: &[ArtifactStepEnvironment],
+    secrets: &[ArtifactStepEnvironment],
+    tx: &Sender<Result<ArtifactBuildResponse, Status>>,
+    workspace_path: &Path,
+) -> Result<(), Status> {
+    let mut missing_paths = vec![];
+
+    for check_path in artifact.check_paths.iter() {
+        let path = Path::new(check_path);
+
+        if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return Err(Status::invalid_argument(format!(
+                "check path must be relative to the output: {}",
+                check_path
+            )));
+        }
+
+        if !artifact_path.join(path).exists() {
+            missing_paths.push(check_path.as_str());
+        }
+    }
+
+    if !missing_paths.is_empty() {
+        return Err(Status::internal(format!(
+            "artifact checks failed, missing output paths: {}",
+            missing_paths.join(", ")
+        )));
+    }
+
+    let Some(check_command) = &artifact.check_command else {
+        return Ok(());
+    };
+
+    let check_environments = vec![ArtifactStepEnvironment {
+        key: "PATH".to_string(),
+        value: "$VORPAL_OUTPUT/bin:/usr/local/bin:/usr/bin:/usr/sbin:/bin:/sbin".to_string(),
+    }];
+
+    let check_script = format!(
+        "#!/bin/bash\nset -euo pipefail\n\ncd \"$VORPAL_OUTPUT\"\n\n{}\n",
+        check_command
+    );
+
+    run_step(
+        artifact.artifacts.clone(),
+        artifact.environments.clone(),
+        artifact.name.clone(),
+        artifact_path,
+        host_environments,
+        secrets,
+        vec![],
+        None,
+        Some("bash".to_string()),
+        check_environments,
+        None,
+        None,
+        Some(check_script),
+        None,
+        tx,
+        workspace_path,
+    )
+    .await
+    .map_err(|err| {
+        Status::internal(format!(
+            "artifact check command failed: {}: {}",
+            check_command,
+            err.message()
+        ))
+    })
+}
+
 /// Sends a response to the client and logs errors if any.
 async fn send_build_response(
     tx: &Sender<Result<ArtifactBuildResponse, Status>>,
@@ -252,12 +647,30 @@ async fn send_build_response(
     })
 }
 
-/// Writes a message to the client stream and propagates errors.
-async fn send_message(
+/// Writes a progress event to the client stream, with `message` as the legacy output.
+async fn send_event(
     tx: &Sender<Result<ArtifactBuildResponse, Status>>,
+    phase: ArtifactBuildPhase,
+    source: &str,
+    bytes_done: u64,
+    bytes_total: u64,
     message: String,
 ) -> Result<(), Status> {
-    send_build_response(tx, Ok(ArtifactBuildResponse { output: message })).await
+    send_build_response(
+        tx,
+        Ok(ArtifactBuildResponse {
+            durations: vec![],
+            event: Some(ArtifactBuildEvent {
+                bytes_done,
+                bytes_total,
+                phase: phase as i32,
+                source: source.to_string(),
+            }),
+            output: message,
+            stream: ArtifactBuildStream::UnknownStream as i32,
+        }),
+    )
+    .await
 }
 
 #[tonic::async_trait]
@@ -270,23 +683,155 @@ impl ArtifactService for ArtifactServer {
     ) -> Result<Response<Self::BuildStream>, Status> {
         let (tx, rx) = mpsc::channel(100);
 
-        let registry = self.registry.clone();
+        // Builds may override the push policy of the worker, e.g. `never` for local workflows
+
+        let push_policy =
+            PushPolicy::from_request(request.get_ref().push_policy()).unwrap_or(self.push_policy);
+
+        let archive_compression = self.archive_compression;
+        let build_queue = self.build_queue.clone();
+        let chunk_bounds = self.chunk_bounds;
+        let env_passthrough = self.env_passthrough.clone();
+        let min_disk_bytes = self.min_disk_bytes;
+        let push_queue = self.push_queue.clone();
+        let registries = self.registries.clone();
+        let step_cache = self.step_cache;
+
+        let events = self
+            .event_webhook
+            .clone()
+            .map(|webhook| BuildEvents::new(request.get_ref(), webhook));
+
+        WORKER_BUILDS_STARTED_TOTAL.increment(&WORKER_METRIC_LABELS, 1);
 
         tokio::spawn(async move {
-            if let Err(err) = handle_build(request.into_inner(), registry, tx.clone()).await {
-                if let Err(err) = send_build_response(&tx, Err(err)).await {
-                    error!("Failed to send response: {:?}", err);
+            let build_started = Instant::now();
+
+            if let Some(events) = &events {
+                events.started();
+            }
+
+            let build = handle_build(
+                archive_compression,
+                build_queue,
+                request.into_inner(),
+                chunk_bounds,
+                env_passthrough,
+                min_disk_bytes,
+                push_policy,
+                push_queue,
+                registries,
+                step_cache,
+                tx.clone(),
+            )
+            .await;
+
+            WORKER_BUILD_DURATION_SECONDS
+                .observe(&WORKER_METRIC_LABELS, build_started.elapsed().as_secs_f64());
+
+            if let Some(events) = &events {
+                events.finished(&build, build_started.elapsed());
+            }
+
+            match build {
+                Ok(_) => WORKER_BUILDS_SUCCEEDED_TOTAL.increment(&WORKER_METRIC_LABELS, 1),
+
+                Err(err) => {
+                    WORKER_BUILDS_FAILED_TOTAL.increment(&WORKER_METRIC_LABELS, 1);
+
+                    if let Err(err) = send_build_response(&tx, Err(err)).await {
+                        error!("Failed to send response: {:?}", err);
+                    }
                 }
             }
         });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn build_status(
+        &self,
+        request: Request<ArtifactBuildStatusRequest>,
+    ) -> Result<Response<ArtifactBuildStatusResponse>, Status> {
+        let request = request.into_inner();
+
+        if request.name.is_empty() {
+            return Err(Status::invalid_argument("missing `name` field"));
+        }
+
+        let digest = request
+            .hash
+            .parse::<ArtifactDigest>()
+            .map_err(|err| Status::invalid_argument(format!("invalid `hash` field: {}", err)))?;
+
+        let state = get_build_state(&digest, &request.name).await;
+
+        Ok(Response::new(ArtifactBuildStatusResponse {
+            state: state as i32,
+        }))
+    }
+
+    async fn info(
+        &self,
+        _request: Request<ArtifactInfoRequest>,
+    ) -> Result<Response<ArtifactInfoResponse>, Status> {
+        Ok(Response::new(ArtifactInfoResponse {
+            system: self.system as i32,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+    }
+
+    async fn worker_status(
+        &self,
+        _request: Request<ArtifactWorkerStatusRequest>,
+    ) -> Result<Response<ArtifactWorkerStatusResponse>, Status> {
+        Ok(Response::new(ArtifactWorkerStatusResponse {
+            max_concurrent_builds: self.build_queue.max() as u32,
+            queued_builds: self.build_queue.queued() as u32,
+            running_builds: self.build_queue.running() as u32,
+        }))
+    }
+}
+
+/// Decrypts the secret values of a build request with the worker private key.
+async fn decrypt_secrets(
+    secrets: &[ArtifactStepEnvironment],
+) -> Result<Vec<ArtifactStepEnvironment>, Status> {
+    let mut decrypted = vec![];
+
+    for secret in secrets {
+        let value = vorpal_notary::decode_encrypted(&secret.value)
+            .map_err(|err| Status::invalid_argument(format!("secret {}: {}", secret.key, err)))?;
+
+        let value = vorpal_notary::decrypt(get_private_key_path(), &value)
+            .await
+            .map_err(|err| Status::invalid_argument(format!("secret {}: {}", secret.key, err)))?;
+
+        let value = String::from_utf8(value).map_err(|_| {
+            Status::invalid_argument(format!("secret {}: value is not valid UTF-8", secret.key))
+        })?;
+
+        decrypted.push(ArtifactStepEnvironment {
+            key: secret.key.clone(),
+            value,
+        });
+    }
+
+    Ok(decrypted)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_build(
+    archive_compression: ArchiveCompression,
+    build_queue: BuildQueue,
     request: ArtifactBuildRequest,
-    registry: String,
+    chunk_bounds: ChunkBounds,
+    env_passthrough: Vec<String>,
+    min_disk_bytes: u64,
+    push_policy: PushPolicy,
+    push_queue: PushQueue,
+    registries: RegistryUrls,
+    step_cache: bool,
     tx: Sender<Result<ArtifactBuildResponse, Status>>,
 ) -> Result<(), Status> {
     let artifact = &request
@@ -302,6 +847,19 @@ async fn handle_build(
         return Err(Status::invalid_argument("steps are missing"));
     }
 
+    let environment_warnings =
+        validate_artifact_environments(artifact).map_err(Status::invalid_argument)?;
+
+    for warning in environment_warnings {
+        warn!("{}", warning);
+    }
+
+    validate_artifact_references(artifact).map_err(Status::invalid_argument)?;
+
+    validate_artifact_placeholders(artifact).map_err(Status::invalid_argument)?;
+
+    let secrets = decrypt_secrets(&request.secrets).await?;
+
     let manifest_json = serde_json::to_string(&request)
         .map_err(|err| Status::internal(format!("failed to serialize manifest: {:?}", err)))?;
 
@@ -315,195 +873,1084 @@ async fn handle_build(
 
     let worker_target = get_artifact_system::<ArtifactSystem>(&worker_system);
 
-    if request_system != worker_target {
+    if !is_artifact_system_buildable(worker_target, request_system) {
         return Err(Status::invalid_argument("target mismatch"));
     }
 
-    let manifest_hash = digest(manifest_json.as_bytes());
+    // Building for a system the artifact does not declare would fail in a step, or worse
+    // produce output for the wrong architecture
 
-    // Check if artifact is locked
+    validate_artifact_system(artifact, request_system).map_err(|err| {
+        Status::failed_precondition(format!("{} on worker `{}`", err, worker_system))
+    })?;
 
-    let lock_path = get_artifact_lock_path(&manifest_hash, &artifact.name);
+    validate_artifact_steps(artifact, request_system).map_err(Status::invalid_argument)?;
 
-    if lock_path.exists() {
-        return Err(Status::already_exists("artifact is locked"));
-    }
+    validate_artifact_step_paths(artifact).map_err(Status::invalid_argument)?;
 
-    // If artifact exists, return
+    let manifest_hash = ArtifactDigest::from_manifest(manifest_json.as_bytes());
 
-    let artifact_path = get_artifact_path(&manifest_hash, &artifact.name);
+    // Keep the manifest locally, so the artifact can be inspected and rebuilt offline
 
-    if artifact_path.exists() {
-        return Err(Status::already_exists("artifact exists"));
+    match get_artifact_manifest(artifact, request_system) {
+        Ok((digest, json)) => {
+            if let Err(err) = write_manifest(&digest, &artifact.name, &json).await {
+                warn!("failed to keep manifest of {}: {}", artifact.name, err);
+            }
+        }
+        Err(err) => warn!("failed to serialize manifest of {}: {}", artifact.name, err),
     }
 
-    // Create lock file
+    // If artifact exists, return
 
-    if let Err(err) = write(&lock_path, "").await {
-        return Err(Status::internal(format!(
-            "failed to create lock file: {:?}",
-            err
-        )));
-    }
+    let artifact_path = get_artifact_path(&manifest_hash, &artifact.name);
+    let lock_path = get_artifact_lock_path(&manifest_hash, &artifact.name);
 
-    if let Err(err) = create_dir_all(&artifact_path).await {
-        return Err(Status::internal(format!(
-            "failed to create artifact path: {:?}",
-            err
-        )));
+    if artifact_path.exists() && !lock_path.exists() && !request.force {
+        return Err(Status::already_exists("artifact exists"));
     }
 
-    // Create workspace
+    // Fail before pulling any source when the key outputs are signed with can not be read
 
-    let workspace_path = create_sandbox_dir()
+    vorpal_notary::get_private_key(get_private_key_path())
         .await
-        .map_err(|err| Status::internal(format!("failed to create workspace: {:?}", err)))?;
-
-    // let workspace_path_canonical = workspace_path
-    //     .canonicalize()
-    //     .map_err(|err| Status::internal(format!("failed to canonicalize workspace: {:?}", err)))?;
-
-    // Connect to registry
+        .map_err(|err| Status::failed_precondition(err.to_string()))?;
 
-    let mut registry_client = RegistryServiceClient::connect(registry)
-        .await
-        .map_err(|err| Status::internal(format!("failed to connect to registry: {:?}", err)))?;
+    // Fail before locking when the store can not hold the build
 
-    // Pull any source archives
+    check_disk_space(artifact.min_disk_bytes.unwrap_or(min_disk_bytes))?;
 
-    pull_source_archives(artifact, &workspace_path, &mut registry_client, &tx).await?;
+    // Acquire lock (waits for any in-progress build of the same artifact) before a build slot,
+    // so builds waiting on another build of their artifact do not hold a slot
 
-    // Run artifact steps
+    let (lock, lock_waited) = acquire_lock(&lock_path, &manifest_hash, &tx).await?;
 
-    for step in artifact.steps.iter() {
-        if let Err(err) = run_step(
-            artifact.artifacts.clone(),
-            artifact.name.clone(),
-            &artifact_path,
-            step.arguments.clone(),
-            step.entrypoint.clone(),
-            step.environments.clone(),
-            step.script.clone(),
-            &tx,
-            &workspace_path,
-        )
-        .await
-        {
-            return Err(Status::internal(format!("failed to run step: {:?}", err)));
+    if artifact_path.exists() && request.force {
+        if let Err(err) = remove_dir_all(&artifact_path).await {
+            return Err(Status::internal(format!(
+                "failed to remove artifact path: {:?}",
+                err
+            )));
         }
     }
 
-    let artifact_path_files = get_file_paths(&artifact_path, vec![], vec![])
-        .map_err(|err| Status::internal(format!("failed to get output files: {:?}", err)))?;
+    if artifact_path.exists() {
+        drop(lock);
+
+        if lock_waited {
+            return send_build_response(
+                &tx,
+                Ok(ArtifactBuildResponse {
+                    durations: vec![],
+                    event: None,
+                    output: format!("built by in-progress build: {}", manifest_hash),
+                    stream: ArtifactBuildStream::UnknownStream as i32,
+                }),
+            )
+            .await;
+        }
 
-    if artifact_path_files.is_empty() || artifact_path_files.len() == 1 {
-        return Err(Status::internal("no output files found"));
+        return Err(Status::already_exists("artifact exists"));
     }
 
-    // Create artifact tar from build output files
+    // Wait for a build slot, held until the build finishes
 
-    send_message(&tx, format!("packing: {}", manifest_hash)).await?;
+    let _build_permit = build_queue.acquire(&tx).await?;
 
-    let artifact_archive_path = create_sandbox_file(Some("tar.zst"))
-        .await
-        .map_err(|err| Status::internal(format!("failed to create artifact archive: {:?}", err)))?;
+    // Steps are only restored and snapshotted with step caching enabled, forced rebuilds skip them
 
-    if let Err(err) =
-        compress_zstd(&artifact_path, &artifact_path_files, &artifact_archive_path).await
-    {
+    let step_digests = match step_cache && !request.force {
+        true => Some(get_step_digests(artifact, request_system)?),
+        false => None,
+    };
+
+    if let Err(err) = create_dir_all(&artifact_path).await {
         return Err(Status::internal(format!(
-            "failed to compress artifact: {:?}",
+            "failed to create artifact path: {:?}",
             err
         )));
     }
 
-    // upload artifact to registry
-
-    send_message(&tx, format!("pushing: {}", manifest_hash)).await?;
-
-    let artifact_data = read(&artifact_archive_path)
-        .await
-        .map_err(|err| Status::internal(format!("failed to read artifact archive: {:?}", err)))?;
-
-    let private_key_path = get_private_key_path();
+    let provenance = ArtifactProvenance {
+        artifact: Some(ArtifactId {
+            hash: manifest_hash.to_string(),
+            name: artifact.name.clone(),
+        }),
+        artifacts: artifact.artifacts.clone(),
+        built_at: 0,
+        host: get_hostname(),
+        host_system: worker_target as i32,
+        source_revisions: request.source_revisions.clone(),
+        sources: artifact.sources.clone(),
+        steps: vec![],
+        system: request_system as i32,
+        user: std::env::var("USER").unwrap_or_default(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
 
-    if !private_key_path.exists() {
-        return Err(Status::internal("private key not found"));
-    }
+    let workspace_path = match create_sandbox_dir().await {
+        Ok(path) => path,
+        Err(err) => {
+            return Err(Status::internal(format!(
+                "failed to create workspace: {:?}",
+                err
+            )));
+        }
+    };
 
-    let source_signature = vorpal_notary::sign(private_key_path, &artifact_data)
-        .await
-        .map_err(|err| Status::internal(format!("failed to sign artifact: {:?}", err)))?;
+    let result = build_artifact(
+        archive_compression,
+        artifact,
+        &artifact_path,
+        chunk_bounds,
+        &get_host_environments(&env_passthrough),
+        &manifest_hash,
+        &manifest_json,
+        provenance,
+        push_policy,
+        &push_queue,
+        registries,
+        &secrets,
+        step_digests,
+        &tx,
+        &workspace_path,
+    )
+    .await;
 
-    let mut request_stream = vec![];
+    // Remove the workspace, and partial output so the next request can rebuild
 
-    for chunk in artifact_data.chunks(DEFAULT_CHUNKS_SIZE) {
-        request_stream.push(RegistryPushRequest {
-            data: chunk.to_vec(),
-            data_signature: source_signature.clone().to_vec(),
-            hash: manifest_hash.clone(),
-            kind: RegistryKind::Artifact as i32,
-            name: artifact.name.clone(),
-        });
+    if let Err(err) = remove_dir_all(&workspace_path).await {
+        error!("failed to remove workspace: {:?}", err);
     }
 
-    if let Err(err) = registry_client
-        .push(tokio_stream::iter(request_stream))
-        .await
-    {
-        return Err(Status::internal(format!(
-            "failed to push artifact: {:?}",
-            err
-        )));
+    if result.is_err() {
+        if let Err(err) = remove_dir_all(&artifact_path).await {
+            error!("failed to remove artifact path: {:?}", err);
+        }
     }
 
-    // sanitize output files
+    result
+}
 
-    for path in artifact_path_files.iter() {
-        if let Err(err) = set_timestamps(path).await {
-            return Err(Status::internal(format!(
-                "failed to sanitize output files: {:?}",
-                err
-            )));
-        }
+/// Fails with `resource_exhausted` when the store filesystem has less than `required` bytes
+/// free, zero disables the check.
+fn check_disk_space(required: u64) -> Result<(), Status> {
+    if required == 0 {
+        return Ok(());
     }
 
-    // Remove artifact archive
+    let store_path = get_store_dir_path();
 
-    if let Err(err) = remove_file(&artifact_archive_path).await {
-        return Err(Status::internal(format!(
-            "failed to remove artifact archive: {:?}",
+    let available = get_available_disk_bytes(&store_path).map_err(|err| {
+        Status::internal(format!(
+            "failed to get free space of {}: {:?}",
+            store_path.display(),
             err
+        ))
+    })?;
+
+    if available < required {
+        return Err(Status::resource_exhausted(format!(
+            "insufficient disk space in {}: {} bytes free, {} bytes required",
+            store_path.display(),
+            available,
+            required
         )));
     }
 
-    // Remove workspace
+    Ok(())
+}
 
-    if let Err(err) = remove_dir_all(workspace_path).await {
+// Locks hold the PID of the worker on their first line, followed by a token unique to the build
+fn get_lock_data_pid(lock_data: &str) -> Option<u32> {
+    lock_data.lines().next()?.trim().parse::<u32>().ok()
+}
+
+// Signal 0 only checks the process exists, EPERM means it does but belongs to another user
+fn is_process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+
+    // Zero and negative pids address process groups rather than one process
+
+    if pid <= 0 {
+        return false;
+    }
+
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+async fn get_lock_pid(lock_path: &Path) -> Option<u32> {
+    get_lock_data_pid(&read_to_string(lock_path).await.ok()?)
+}
+
+/// Returns the state of a build in the worker store, from its output and lock.
+///
+/// Stale locks without output are reported as missing, the next build breaks them.
+async fn get_build_state(digest: &ArtifactDigest, name: &str) -> ArtifactBuildState {
+    let artifact_path = get_artifact_path(digest, name);
+    let lock_path = get_artifact_lock_path(digest, name);
+
+    if !lock_path.exists() {
+        return match artifact_path.exists() {
+            true => ArtifactBuildState::Completed,
+            false => ArtifactBuildState::Missing,
+        };
+    }
+
+    match get_lock_pid(&lock_path).await {
+        Some(lock_pid) if is_process_alive(lock_pid) => ArtifactBuildState::Building,
+        _ if artifact_path.exists() => ArtifactBuildState::Interrupted,
+        _ => ArtifactBuildState::Missing,
+    }
+}
+
+/// Logs the fingerprint of the key pushes are signed with, to compare with the one a registry
+/// reports when it rejects a signature.
+pub async fn log_signing_key_fingerprint() {
+    let private_key_path = get_private_key_path();
+
+    if !private_key_path.exists() {
+        return;
+    }
+
+    match vorpal_notary::get_private_key_fingerprint(private_key_path).await {
+        Ok(fingerprint) => info!("signing key fingerprint: {}", fingerprint),
+        Err(err) => warn!("failed to get signing key fingerprint: {}", err),
+    }
+}
+
+/// Removes the partial output and lock of builds interrupted by a worker exit, so clients
+/// reconnecting after a restart can build them again. Locks of live processes are kept.
+pub async fn remove_interrupted_builds() -> Result<()> {
+    let store_dir_path = get_store_dir_path();
+
+    if !store_dir_path.exists() {
+        return Ok(());
+    }
+
+    let mut entries = read_dir(&store_dir_path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let lock_path = entry.path();
+
+        if !lock_path.to_string_lossy().ends_with(".artifact.lock") {
+            continue;
+        }
+
+        if matches!(get_lock_pid(&lock_path).await, Some(pid) if is_process_alive(pid)) {
+            continue;
+        }
+
+        // "{name}-{hash}.artifact.lock" locks "{name}-{hash}.artifact"
+
+        let artifact_path = lock_path.with_extension("");
+
+        if artifact_path.exists() {
+            warn!(
+                "removing output of interrupted build: {}",
+                artifact_path.display()
+            );
+
+            remove_dir_all(&artifact_path).await?;
+        }
+
+        remove_file(&lock_path).await?;
+    }
+
+    Ok(())
+}
+
+/// Artifact lock held by a build of this worker. Dropping it removes the lock file and wakes
+/// waiting builds, so errors and panics of the build release it as well.
+struct ArtifactLock {
+    path: PathBuf,
+}
+
+impl Drop for ArtifactLock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            error!(
+                "failed to remove lock file {}: {:?}",
+                self.path.display(),
+                err
+            );
+        }
+
+        LOCK_RELEASED.notify_waiters();
+    }
+}
+
+// Temporary file the lock is linked from, removed on every return of `acquire_lock`
+struct LockTempFile {
+    path: PathBuf,
+}
+
+impl Drop for LockTempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Creates the artifact lock file containing the worker PID, waiting while another
+/// live process holds it. Locks held by processes that are no longer alive are broken.
+///
+/// Returns the lock, and whether it had to be waited on.
+async fn acquire_lock(
+    lock_path: &Path,
+    manifest_hash: &ArtifactDigest,
+    tx: &Sender<Result<ArtifactBuildResponse, Status>>,
+) -> Result<(ArtifactLock, bool), Status> {
+    let lock_id = Uuid::now_v7();
+
+    // Write the PID to a temporary file and hard link it into place so the lock is
+    // created atomically with its contents. Each request has its own temporary file, builds
+    // of one worker share the PID
+
+    let lock_temp = LockTempFile {
+        path: lock_path.with_extension(format!("lock.{}", lock_id)),
+    };
+
+    write(
+        &lock_temp.path,
+        format!("{}\n{}", std::process::id(), lock_id),
+    )
+    .await
+    .map_err(|err| Status::internal(format!("failed to create lock file: {:?}", err)))?;
+
+    let mut waited = false;
+    let mut waited_polls = 0;
+
+    loop {
+        // Created before the lock is checked, so a release right after the check still wakes it
+
+        let lock_released = LOCK_RELEASED.notified();
+
+        match hard_link(&lock_temp.path, lock_path).await {
+            Ok(_) => break,
+
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                // Released between the link and the read, link again
+
+                let Ok(lock_data) = read_to_string(lock_path).await else {
+                    continue;
+                };
+
+                match get_lock_data_pid(&lock_data) {
+                    Some(lock_pid) if is_process_alive(lock_pid) => {
+                        if waited_polls % LOCK_WAIT_MESSAGE_POLLS == 0 {
+                            send_build_response(
+                                tx,
+                                Ok(ArtifactBuildResponse {
+                                    durations: vec![],
+                                    event: None,
+                                    output: format!(
+                                        "waiting for in-progress build: {}",
+                                        manifest_hash
+                                    ),
+                                    stream: ArtifactBuildStream::UnknownStream as i32,
+                                }),
+                            )
+                            .await?;
+                        }
+
+                        waited = true;
+                        waited_polls += 1;
+
+                        tokio::select! {
+                            _ = lock_released => {}
+                            _ = sleep(LOCK_WAIT_INTERVAL) => {}
+                        }
+                    }
+
+                    _ => {
+                        // Replace the stale lock in one rename, and only while it is still the
+                        // lock found stale, another waiter may have replaced it already
+
+                        let _lock_break = LOCK_BREAK.lock().await;
+
+                        if read_to_string(lock_path).await.ok().as_ref() != Some(&lock_data) {
+                            continue;
+                        }
+
+                        warn!("breaking stale lock: {}", lock_path.display());
+
+                        if let Err(err) = rename(&lock_temp.path, lock_path).await {
+                            return Err(Status::internal(format!(
+                                "failed to replace stale lock file: {:?}",
+                                err
+                            )));
+                        }
+
+                        let lock = ArtifactLock {
+                            path: lock_path.to_path_buf(),
+                        };
+
+                        return Ok((lock, waited));
+                    }
+                }
+            }
+
+            Err(err) => {
+                return Err(Status::internal(format!(
+                    "failed to create lock file: {:?}",
+                    err
+                )));
+            }
+        }
+    }
+
+    let lock = ArtifactLock {
+        path: lock_path.to_path_buf(),
+    };
+
+    Ok((lock, waited))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_artifact(
+    archive_compression: ArchiveCompression,
+    artifact: &Artifact,
+    artifact_path: &PathBuf,
+    chunk_bounds: ChunkBounds,
+    host_environments: &[ArtifactStepEnvironment],
+    manifest_hash: &ArtifactDigest,
+    manifest_json: &str,
+    mut provenance: ArtifactProvenance,
+    push_policy: PushPolicy,
+    push_queue: &PushQueue,
+    registries: RegistryUrls,
+    secrets: &[ArtifactStepEnvironment],
+    step_digests: Option<Vec<StepDigest>>,
+    tx: &Sender<Result<ArtifactBuildResponse, Status>>,
+    workspace_path: &Path,
+) -> Result<(), Status> {
+    // let workspace_path_canonical = workspace_path
+    //     .canonicalize()
+    //     .map_err(|err| Status::internal(format!("failed to canonicalize workspace: {:?}", err)))?;
+
+    // Connect to registries
+
+    let registries = registries
+        .connect()
+        .await
+        .map_err(|err| Status::internal(format!("failed to connect to registry: {}", err)))?;
+
+    // Pull any source archives
+
+    let mut durations = pull_source_archives(artifact, workspace_path, &registries, tx).await?;
+
+    // Restore latest cached step
+
+    let mut step_start = 0;
+
+    if let Some(step_digests) = &step_digests {
+        for (index, step) in artifact.steps.iter().enumerate().rev() {
+            if !step.cache {
+                continue;
+            }
+
+            let restored = pull_step_snapshot(
+                &registries,
+                &artifact.name,
+                artifact_path,
+                &step_digests[index],
+                workspace_path,
+            )
+            .await?;
+
+            if restored {
+                send_build_response(
+                    tx,
+                    Ok(ArtifactBuildResponse {
+                        durations: vec![],
+                        event: None,
+                        output: format!("step {} cached: {}", index, step_digests[index]),
+                        stream: ArtifactBuildStream::UnknownStream as i32,
+                    }),
+                )
+                .await?;
+
+                step_start = index + 1;
+
+                break;
+            }
+        }
+    }
+
+    for index in 0..step_start {
+        provenance.steps.push(ArtifactProvenanceStep {
+            cached: true,
+            duration_ms: 0,
+            index: index as u32,
+        });
+    }
+
+    // Run artifact steps
+
+    for (index, step) in artifact.steps.iter().enumerate().skip(step_start) {
+        let step_started = Instant::now();
+
+        // Restore the directory kept from previous builds of incremental steps
+
+        let mut incremental_restored = false;
+
+        if let Some(incremental) = &step.incremental {
+            incremental_restored =
+                restore_incremental(&incremental.key, &workspace_path.join(&incremental.path))
+                    .map_err(|err| {
+                        Status::internal(format!("failed to restore incremental: {:?}", err))
+                    })?;
+
+            if incremental_restored {
+                send_build_response(
+                    tx,
+                    Ok(ArtifactBuildResponse {
+                        durations: vec![],
+                        event: None,
+                        output: format!("step {} incremental: {}", index, incremental.key),
+                        stream: ArtifactBuildStream::UnknownStream as i32,
+                    }),
+                )
+                .await?;
+            }
+        }
+
+        if let Err(err) = run_step(
+            artifact.artifacts.clone(),
+            artifact.environments.clone(),
+            artifact.name.clone(),
+            artifact_path,
+            host_environments,
+            secrets,
+            step.arguments.clone(),
+            step.cpu_limit,
+            step.entrypoint.clone(),
+            step.environments.clone(),
+            step.memory_limit_bytes,
+            step.output_subdir.clone(),
+            step.script.clone(),
+            step.working_directory.clone(),
+            tx,
+            workspace_path,
+        )
+        .await
+        {
+            // The restored directory is left in the workspace, so a corrupted one is not reused
+
+            if incremental_restored {
+                warn!(
+                    "{} step {} failed with an incremental directory, discarded so the next build starts clean",
+                    artifact.name, index
+                );
+            }
+
+            return Err(Status::internal(format!("failed to run step: {:?}", err)));
+        }
+
+        // Keep the directory of incremental steps, before snapshots so they never include it
+
+        if let Some(incremental) = &step.incremental {
+            let incremental_path = workspace_path.join(&incremental.path);
+
+            if let Err(err) = save_incremental(&incremental.key, &incremental_path) {
+                warn!("failed to save incremental {}: {}", incremental.key, err);
+            }
+        }
+
+        let step_duration_ms = step_started.elapsed().as_millis() as u64;
+
+        durations.push(ArtifactBuildDuration {
+            duration_ms: step_duration_ms,
+            kind: ArtifactBuildDurationKind::Step as i32,
+            name: index.to_string(),
+        });
+
+        provenance.steps.push(ArtifactProvenanceStep {
+            cached: false,
+            duration_ms: step_duration_ms,
+            index: index as u32,
+        });
+
+        if let Some(step_digests) = step_digests.as_ref().filter(|_| step.cache) {
+            let mut registry_client = get_push_client(&registries)?;
+
+            push_step_snapshot(
+                &mut registry_client,
+                archive_compression,
+                &artifact.name,
+                artifact_path,
+                chunk_bounds,
+                &step_digests[index],
+                workspace_path,
+            )
+            .await?;
+        }
+    }
+
+    // Remove excluded output files, so they are neither archived nor sanitized
+
+    if !artifact.output_excludes.is_empty() {
+        let removed =
+            remove_excluded_paths(artifact_path, &artifact.output_excludes).map_err(|err| {
+                Status::internal(format!("failed to exclude output files: {:?}", err))
+            })?;
+
+        send_event(
+            tx,
+            ArtifactBuildPhase::Pack,
+            "",
+            0,
+            0,
+            format!("excluded output files: {}", removed),
+        )
+        .await?;
+    }
+
+    let artifact_path_files = get_file_paths(artifact_path, vec![], vec![])
+        .map_err(|err| Status::internal(format!("failed to get output files: {:?}", err)))?;
+
+    if artifact_path_files.is_empty() || artifact_path_files.len() == 1 {
+        return Err(Status::internal("no output files found"));
+    }
+
+    // Run declared output checks before archiving
+
+    check_artifact_output(
+        artifact,
+        artifact_path,
+        host_environments,
+        secrets,
+        tx,
+        workspace_path,
+    )
+    .await?;
+
+    // Create artifact tar from build output files
+
+    send_event(
+        tx,
+        ArtifactBuildPhase::Pack,
+        "",
+        0,
+        0,
+        format!("packing: {}", manifest_hash),
+    )
+    .await?;
+
+    // Created in the workspace so it is removed with it when the build fails
+
+    let artifact_archive_path = workspace_path.join("artifact.tar.zst");
+
+    if let Err(err) = compress_archive(
+        archive_compression,
+        artifact_path,
+        &artifact_path_files,
+        &artifact_archive_path,
+    )
+    .await
+    {
         return Err(Status::internal(format!(
-            "failed to remove workspace: {:?}",
+            "failed to compress artifact: {:?}",
             err
         )));
     }
 
-    // Remove lock file
+    // Output files are shared with identical files of other artifacts once archived
+
+    if is_store_dedup_enabled() {
+        match dedup_path(artifact_path) {
+            Ok(summary) => debug!("dedup: {} bytes saved", summary.saved_bytes),
+            Err(err) => warn!("failed to dedup artifact: {:?}", err),
+        }
+    }
 
-    if let Err(err) = remove_file(&lock_path).await {
+    // Set before the provenance is pushed, or kept for a later push
+
+    provenance.built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    match push_policy {
+        PushPolicy::Always => {
+            let artifact_data = read(&artifact_archive_path).await.map_err(|err| {
+                Status::internal(format!("failed to read artifact archive: {:?}", err))
+            })?;
+
+            let artifact_data_size = artifact_data.len() as u64;
+
+            send_event(
+                tx,
+                ArtifactBuildPhase::Push,
+                "",
+                0,
+                artifact_data_size,
+                format!("pushing: {}", manifest_hash),
+            )
+            .await?;
+
+            let mut registry_client = get_push_client(&registries)?;
+
+            let summary = push_artifact(
+                &mut registry_client,
+                artifact_data,
+                &artifact.name,
+                chunk_bounds,
+                manifest_hash,
+                manifest_json,
+                &provenance,
+            )
+            .await?;
+
+            if let Some(summary) = summary {
+                debug!("push transfer: {}", summary);
+
+                send_event(
+                    tx,
+                    ArtifactBuildPhase::Push,
+                    "",
+                    summary.bytes as u64,
+                    artifact_data_size,
+                    format!("pushed: {} ({})", manifest_hash, summary),
+                )
+                .await?;
+            }
+        }
+
+        PushPolicy::Async | PushPolicy::Never => {
+            write_pending_push(
+                &artifact_archive_path,
+                &artifact.name,
+                manifest_hash,
+                manifest_json,
+                &provenance,
+            )
+            .await?;
+
+            let output = match push_policy {
+                PushPolicy::Async => {
+                    push_queue.push(ArtifactId {
+                        hash: manifest_hash.to_string(),
+                        name: artifact.name.clone(),
+                    });
+
+                    format!("push queued: {}", manifest_hash)
+                }
+
+                _ => format!(
+                    "push skipped: {} (push it with `vorpal store push --digest {}`)",
+                    manifest_hash, manifest_hash
+                ),
+            };
+
+            send_build_response(
+                tx,
+                Ok(ArtifactBuildResponse {
+                    durations: vec![],
+                    event: None,
+                    output,
+                    stream: ArtifactBuildStream::UnknownStream as i32,
+                }),
+            )
+            .await?;
+        }
+    }
+
+    send_build_response(
+        tx,
+        Ok(ArtifactBuildResponse {
+            durations,
+            event: None,
+            output: String::new(),
+            stream: ArtifactBuildStream::UnknownStream as i32,
+        }),
+    )
+    .await?;
+
+    // sanitize output files
+
+    for path in artifact_path_files.iter() {
+        if let Err(err) = sanitize_file(path).await {
+            return Err(Status::internal(format!(
+                "failed to sanitize output files: {:?}",
+                err
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Pushes the archive, manifest and provenance of a built artifact, the provenance last so it
+/// only exists for pushed artifacts. Returns the transfer summary of the archive.
+pub(crate) async fn push_artifact(
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+    archive_data: Vec<u8>,
+    artifact_name: &str,
+    chunk_bounds: ChunkBounds,
+    manifest_hash: &ArtifactDigest,
+    manifest_json: &str,
+    provenance: &ArtifactProvenance,
+) -> Result<Option<ChunkSummary>, Status> {
+    let private_key_path = get_private_key_path();
+
+    if !private_key_path.exists() {
+        return Err(Status::internal("private key not found"));
+    }
+
+    let Some(archive_compression) = ArchiveCompression::from_data(&archive_data) else {
+        return Err(Status::internal("unsupported artifact archive format"));
+    };
+
+    let archive_signature = vorpal_notary::sign(private_key_path.clone(), &archive_data)
+        .await
+        .map_err(|err| Status::internal(format!("failed to sign artifact: {:?}", err)))?;
+
+    let archive_signature_fingerprint =
+        vorpal_notary::get_private_key_fingerprint(private_key_path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to get key fingerprint: {:?}", err)))?;
+
+    let request_hash = manifest_hash.to_string();
+    let request_name = artifact_name.to_string();
+
+    let request_compression = get_registry_compression(archive_compression);
+
+    let (request_stream, request_summary) =
+        stream_chunks(archive_data, chunk_bounds, move |data| {
+            RegistryPushRequest {
+                compression: request_compression as i32,
+                data,
+                data_signature: archive_signature.to_vec(),
+                data_signature_fingerprint: archive_signature_fingerprint.clone(),
+                delta_base: String::new(),
+                hash: request_hash.clone(),
+                kind: RegistryKind::Artifact as i32,
+                name: request_name.clone(),
+            }
+        });
+
+    if let Err(err) = registry_client
+        .push(ReceiverStream::new(request_stream))
+        .await
+    {
         return Err(Status::internal(format!(
-            "failed to remove lock file: {:?}",
+            "failed to push artifact: {:?}",
             err
         )));
     }
 
+    let summary = request_summary.await.ok();
+
+    // Push the manifest so the registry can track what the artifact references
+
+    push_manifest(
+        registry_client,
+        artifact_name,
+        chunk_bounds,
+        manifest_hash,
+        manifest_json,
+    )
+    .await?;
+
+    push_provenance(
+        registry_client,
+        artifact_name,
+        chunk_bounds,
+        manifest_hash,
+        provenance,
+    )
+    .await?;
+
+    Ok(summary)
+}
+
+async fn push_manifest(
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+    artifact_name: &str,
+    chunk_bounds: ChunkBounds,
+    manifest_hash: &ArtifactDigest,
+    manifest_json: &str,
+) -> Result<(), Status> {
+    let manifest_data = manifest_json.as_bytes().to_vec();
+
+    let manifest_signature = vorpal_notary::sign(get_private_key_path(), &manifest_data)
+        .await
+        .map_err(|err| Status::internal(format!("failed to sign manifest: {:?}", err)))?;
+
+    let manifest_signature_fingerprint =
+        vorpal_notary::get_private_key_fingerprint(get_private_key_path())
+            .await
+            .map_err(|err| Status::internal(format!("failed to get key fingerprint: {:?}", err)))?;
+
+    let request_hash = manifest_hash.to_string();
+    let request_name = artifact_name.to_string();
+
+    let (request_stream, _) = stream_chunks(manifest_data, chunk_bounds, move |data| {
+        RegistryPushRequest {
+            compression: RegistryCompression::Zstd as i32,
+            data,
+            data_signature: manifest_signature.to_vec(),
+            data_signature_fingerprint: manifest_signature_fingerprint.clone(),
+            delta_base: String::new(),
+            hash: request_hash.clone(),
+            kind: RegistryKind::ArtifactManifest as i32,
+            name: request_name.clone(),
+        }
+    });
+
+    registry_client
+        .push(ReceiverStream::new(request_stream))
+        .await
+        .map_err(|err| Status::internal(format!("failed to push manifest: {:?}", err)))?;
+
     Ok(())
 }
 
+pub(crate) fn get_hostname() -> String {
+    let mut hostname = [0u8; 256];
+
+    let result = unsafe { libc::gethostname(hostname.as_mut_ptr() as *mut libc::c_char, 256) };
+
+    if result != 0 {
+        return String::new();
+    }
+
+    let length = hostname
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(hostname.len());
+
+    String::from_utf8_lossy(&hostname[..length]).to_string()
+}
+
+async fn push_provenance(
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+    artifact_name: &str,
+    chunk_bounds: ChunkBounds,
+    manifest_hash: &ArtifactDigest,
+    provenance: &ArtifactProvenance,
+) -> Result<(), Status> {
+    let private_key_path = get_private_key_path();
+
+    let payload = serde_json::to_string(provenance)
+        .map_err(|err| Status::internal(format!("failed to serialize provenance: {:?}", err)))?;
+
+    let payload_signature = vorpal_notary::sign(private_key_path.clone(), payload.as_bytes())
+        .await
+        .map_err(|err| Status::internal(format!("failed to sign provenance: {:?}", err)))?;
+
+    let envelope = ArtifactProvenanceEnvelope {
+        payload,
+        signature: vorpal_notary::encode_signature(&payload_signature),
+    };
+
+    let provenance_data = serde_json::to_vec(&envelope)
+        .map_err(|err| Status::internal(format!("failed to serialize provenance: {:?}", err)))?;
+
+    let provenance_signature = vorpal_notary::sign(private_key_path.clone(), &provenance_data)
+        .await
+        .map_err(|err| Status::internal(format!("failed to sign provenance: {:?}", err)))?;
+
+    let provenance_signature_fingerprint =
+        vorpal_notary::get_private_key_fingerprint(private_key_path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to get key fingerprint: {:?}", err)))?;
+
+    let request_hash = manifest_hash.to_string();
+    let request_name = artifact_name.to_string();
+
+    let (request_stream, _) = stream_chunks(provenance_data, chunk_bounds, move |data| {
+        RegistryPushRequest {
+            compression: RegistryCompression::Zstd as i32,
+            data,
+            data_signature: provenance_signature.to_vec(),
+            data_signature_fingerprint: provenance_signature_fingerprint.clone(),
+            delta_base: String::new(),
+            hash: request_hash.clone(),
+            kind: RegistryKind::ArtifactProvenance as i32,
+            name: request_name.clone(),
+        }
+    });
+
+    registry_client
+        .push(ReceiverStream::new(request_stream))
+        .await
+        .map_err(|err| Status::internal(format!("failed to push provenance: {:?}", err)))?;
+
+    Ok(())
+}
+
+async fn get_dictionary(
+    dictionary_id: u32,
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+) -> Result<Vec<u8>, Status> {
+    let dictionary_path = get_dictionary_path(&dictionary_id.to_string());
+
+    if dictionary_path.exists() {
+        return read(&dictionary_path)
+            .await
+            .map_err(|err| Status::internal(format!("failed to read dictionary: {:?}", err)));
+    }
+
+    let pull_request = RegistryRequest {
+        accept_dictionary: false,
+        hash: dictionary_id.to_string(),
+        kind: RegistryKind::Dictionary as i32,
+        name: "dictionary".to_string(),
+    };
+
+    let mut response = registry_client
+        .pull(pull_request)
+        .await
+        .map_err(|status| Status::internal(format!("failed to pull dictionary: {:?}", status)))?
+        .into_inner();
+
+    let mut dictionary = vec![];
+    let mut dictionary_digest = None;
+
+    while let Some(res) = response.message().await? {
+        if !res.data_digest.is_empty() {
+            dictionary_digest = Some(
+                res.data_digest
+                    .parse::<ArchiveDigest>()
+                    .map_err(|err| Status::data_loss(format!("dictionary rejected: {}", err)))?,
+            );
+        }
+
+        dictionary.extend(res.data);
+    }
+
+    verify_pulled_data(dictionary_digest.as_ref(), &dictionary, "dictionary")
+        .map_err(|err| Status::data_loss(format!("dictionary rejected: {}", err)))?;
+
+    write(&dictionary_path, &dictionary)
+        .await
+        .map_err(|err| Status::internal(format!("failed to write dictionary: {:?}", err)))?;
+
+    Ok(dictionary)
+}
+
+fn get_push_client(
+    registries: &RegistryClients,
+) -> Result<RegistryServiceClient<RegistryChannel>, Status> {
+    registries
+        .push()
+        .map_err(|err| Status::unavailable(format!("failed to connect to registry: {}", err)))
+}
+
 async fn pull_source_archives(
     artifact: &Artifact,
     workspace_path: &Path,
-    registry_client: &mut RegistryServiceClient<tonic::transport::Channel>,
+    registries: &RegistryClients,
     tx: &Sender<Result<ArtifactBuildResponse, Status>>,
-) -> Result<(), Status> {
+) -> Result<Vec<ArtifactBuildDuration>, Status> {
     let workspace_source_dir_path = workspace_path.join("source");
 
     if let Err(err) = create_dir_all(&workspace_source_dir_path).await {
@@ -513,17 +1960,40 @@ async fn pull_source_archives(
         )));
     }
 
+    let mut durations = vec![];
+
     for source in artifact.sources.iter() {
-        handle_source(source, &workspace_source_dir_path, registry_client, tx).await?;
+        let source_started = Instant::now();
+
+        handle_source(source, &workspace_source_dir_path, registries, tx).await?;
+
+        durations.push(ArtifactBuildDuration {
+            duration_ms: source_started.elapsed().as_millis() as u64,
+            kind: ArtifactBuildDurationKind::Source as i32,
+            name: source.name.clone(),
+        });
     }
 
-    Ok(())
+    Ok(durations)
+}
+
+/// Returns a failed source pull as a `SourceError` status, `RegistryUnavailable` when the registry
+/// could not be reached.
+fn get_source_pull_status(source: &ArtifactSourceId, status: Status) -> Status {
+    let error = status.message().to_string();
+    let name = source.name.clone();
+
+    if status.code() == Code::Unavailable {
+        return SourceError::RegistryUnavailable { error, name }.to_status();
+    }
+
+    SourceError::Download { error, name }.to_status()
 }
 
 async fn handle_source(
     source: &ArtifactSourceId,
     workspace_source_dir_path: &Path,
-    registry_client: &mut RegistryServiceClient<tonic::transport::Channel>,
+    registries: &RegistryClients,
     tx: &Sender<Result<ArtifactBuildResponse, Status>>,
 ) -> Result<(), Status> {
     let workspace_source_path = workspace_source_dir_path.join(&source.name);
@@ -535,14 +2005,23 @@ async fn handle_source(
         )));
     }
 
-    let source_cache_path = get_cache_path(&source.hash, &source.name);
+    let source_digest = source
+        .hash
+        .parse::<SourceDigest>()
+        .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+    let source_cache_path = get_cache_path(&source_digest, &source.name);
 
     if source_cache_path.exists() {
         let source_cache_files = get_file_paths(&source_cache_path, vec![], vec![])
             .map_err(|err| Status::internal(format!("failed to get source files: {:?}", err)))?;
 
-        send_message(
+        send_event(
             tx,
+            ArtifactBuildPhase::Unpack,
+            &source.name,
+            0,
+            0,
             format!("copying source: {}-{}", source.name, source.hash),
         )
         .await?;
@@ -556,7 +2035,7 @@ async fn handle_source(
         .map_err(|err| Status::internal(format!("failed to copy source files: {:?}", err)))?;
 
         for path in workspace_source_files.iter() {
-            if let Err(err) = set_timestamps(path).await {
+            if let Err(err) = sanitize_file(path).await {
                 return Err(Status::internal(format!(
                     "failed to sanitize output files: {:?}",
                     err
@@ -567,11 +2046,15 @@ async fn handle_source(
         return Ok(());
     }
 
-    let source_archive_path = get_source_archive_path(&source.hash, &source.name);
+    let source_archive_path = get_source_archive_path(&source_digest, &source.name);
 
     if source_archive_path.exists() {
-        send_message(
+        send_event(
             tx,
+            ArtifactBuildPhase::Unpack,
+            &source.name,
+            0,
+            0,
             format!("caching source: {}-{}", source.name, source.hash),
         )
         .await?;
@@ -583,18 +2066,23 @@ async fn handle_source(
             )));
         }
 
-        if let Err(err) = unpack_zstd(&source_cache_path, &source_archive_path).await {
-            return Err(Status::internal(format!(
-                "failed to unpack source archive: {:?}",
-                err
-            )));
+        if let Err(err) = unpack_archive(&source_cache_path, &source_archive_path).await {
+            return Err(SourceError::Unpack {
+                error: err.to_string(),
+                name: source.name.clone(),
+            }
+            .to_status());
         }
 
         let source_cache_files = get_file_paths(&source_cache_path, vec![], vec![])
             .map_err(|err| Status::internal(format!("failed to get source files: {:?}", err)))?;
 
-        send_message(
+        send_event(
             tx,
+            ArtifactBuildPhase::Unpack,
+            &source.name,
+            0,
+            0,
             format!("copying source: {}-{}", source.name, source.hash),
         )
         .await?;
@@ -608,7 +2096,7 @@ async fn handle_source(
         .map_err(|err| Status::internal(format!("failed to copy source files: {:?}", err)))?;
 
         for path in workspace_source_files.iter() {
-            if let Err(err) = set_timestamps(path).await {
+            if let Err(err) = sanitize_file(path).await {
                 return Err(Status::internal(format!(
                     "failed to sanitize output files: {:?}",
                     err
@@ -619,46 +2107,133 @@ async fn handle_source(
         return Ok(());
     }
 
-    send_message(
+    send_event(
         tx,
+        ArtifactBuildPhase::Download,
+        &source.name,
+        0,
+        0,
         format!("pulling source: {}-{}", source.name, source.hash),
     )
     .await?;
 
     let pull_request = RegistryRequest {
+        accept_dictionary: true,
         hash: source.hash.clone(),
-        name: source.name.clone(),
         kind: RegistryKind::ArtifactSource as i32,
+        name: source.name.clone(),
     };
 
-    let response = registry_client.pull(pull_request).await.map_err(|status| {
-        Status::internal(format!("failed to pull source archive: {:?}", status))
-    })?;
+    let Some(mut registry) = registries
+        .find(&pull_request)
+        .await
+        .map_err(|status| get_source_pull_status(source, status))?
+    else {
+        return Err(get_source_pull_status(
+            source,
+            Status::not_found("source not found in any registry"),
+        ));
+    };
+
+    let response = registry
+        .client
+        .pull(pull_request)
+        .await
+        .map_err(|status| get_source_pull_status(source, status))?;
 
     let mut response = response.into_inner();
-    let mut response_data = Vec::new();
+    let mut response_digest = None;
+
+    // Chunks go straight to a sandbox file, moved into the source cache once verified, so large
+    // sources are never held in memory and an interrupted pull never leaves a partial archive
+
+    let pull_archive_path = create_sandbox_file(Some("tar.zst"))
+        .await
+        .map_err(|err| Status::internal(format!("failed to create source archive: {:?}", err)))?;
 
-    while let Ok(message) = response.message().await {
-        if message.is_none() {
+    let mut pull_archive = ArchiveWriter::create(&pull_archive_path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to create source archive: {:?}", err)))?;
+
+    loop {
+        let message = response
+            .message()
+            .await
+            .map_err(|status| get_source_pull_status(source, status))?;
+
+        let Some(res) = message else {
             break;
+        };
+
+        if !res.data_digest.is_empty() {
+            response_digest = Some(res.data_digest.parse::<ArchiveDigest>().map_err(|err| {
+                SourceError::Download {
+                    error: format!("source archive rejected: {}: {}", source.hash, err),
+                    name: source.name.clone(),
+                }
+                .to_status()
+            })?);
         }
 
-        if let Some(res) = message {
-            if !res.data.is_empty() {
-                response_data.extend(res.data);
-            }
+        if !res.data.is_empty() {
+            wait_network_limit(res.data.len()).await;
+
+            pull_archive.write(&res.data).await.map_err(|err| {
+                Status::internal(format!("failed to write source archive: {:?}", err))
+            })?;
+
+            send_event(
+                tx,
+                ArtifactBuildPhase::Download,
+                &source.name,
+                pull_archive.size(),
+                0,
+                String::new(),
+            )
+            .await?;
         }
     }
 
-    if response_data.is_empty() {
-        return Ok(());
+    if pull_archive.size() == 0 {
+        let _ = remove_file(&pull_archive_path).await;
+
+        return Err(SourceError::EmptySource {
+            name: source.name.clone(),
+        }
+        .to_status());
     }
 
-    let mut source_archive = File::create(&source_archive_path)
+    // A digest mismatch here is a corrupt transfer, the source hash itself is checked by the config
+
+    send_event(
+        tx,
+        ArtifactBuildPhase::Hash,
+        &source.name,
+        pull_archive.size(),
+        pull_archive.size(),
+        format!("verifying source: {}-{}", source.name, source.hash),
+    )
+    .await?;
+
+    let dictionary_id = pull_archive
+        .finish(response_digest.as_ref(), &source.name)
         .await
-        .map_err(|err| Status::internal(format!("failed to create source archive: {:?}", err)))?;
+        .map_err(|err| {
+            SourceError::Download {
+                error: format!("source archive rejected: {}: {}", source.hash, err),
+                name: source.name.clone(),
+            }
+            .to_status()
+        })?;
+
+    if let Some(dictionary_id) = dictionary_id {
+        let dictionary = get_dictionary(dictionary_id, &mut registry.client).await?;
+
+        decompress_archive_dictionary_file(&pull_archive_path, &dictionary)
+            .map_err(|err| Status::internal(format!("failed to decompress source: {:?}", err)))?;
+    }
 
-    if let Err(err) = source_archive.write_all(&response_data).await {
+    if let Err(err) = rename(&pull_archive_path, &source_archive_path).await {
         return Err(Status::internal(format!(
             "failed to write source archive: {:?}",
             err
@@ -672,8 +2247,12 @@ async fn handle_source(
         )));
     }
 
-    send_message(
+    send_event(
         tx,
+        ArtifactBuildPhase::Unpack,
+        &source.name,
+        0,
+        0,
         format!("caching source: {}-{}", source.name, source.hash),
     )
     .await?;
@@ -685,18 +2264,23 @@ async fn handle_source(
         )));
     }
 
-    if let Err(err) = unpack_zstd(&source_cache_path, &source_archive_path).await {
-        return Err(Status::internal(format!(
-            "failed to unpack source archive: {:?}",
-            err
-        )));
+    if let Err(err) = unpack_archive(&source_cache_path, &source_archive_path).await {
+        return Err(SourceError::Unpack {
+            error: err.to_string(),
+            name: source.name.clone(),
+        }
+        .to_status());
     }
 
     let source_cache_files = get_file_paths(&source_cache_path, vec![], vec![])
         .map_err(|err| Status::internal(format!("failed to get source files: {:?}", err)))?;
 
-    send_message(
+    send_event(
         tx,
+        ArtifactBuildPhase::Unpack,
+        &source.name,
+        0,
+        0,
         format!("copying source: {}-{}", source.name, source.hash),
     )
     .await?;
@@ -710,7 +2294,7 @@ async fn handle_source(
     .map_err(|err| Status::internal(format!("failed to copy source files: {:?}", err)))?;
 
     for path in workspace_source_files.iter() {
-        if let Err(err) = set_timestamps(path).await {
+        if let Err(err) = sanitize_file(path).await {
             return Err(Status::internal(format!(
                 "failed to sanitize output files: {:?}",
                 err
@@ -720,3 +2304,258 @@ async fn handle_source(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn get_dir_names(path: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        names.sort();
+
+        names
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        let secrets = [
+            ArtifactStepEnvironment {
+                key: "TOKEN".to_string(),
+                value: "hunter2".to_string(),
+            },
+            ArtifactStepEnvironment {
+                key: "EMPTY".to_string(),
+                value: String::new(),
+            },
+        ];
+
+        assert_eq!(
+            redact_secrets("login hunter2 ok hunter2".to_string(), &secrets),
+            format!("login {} ok {}", SECRET_REDACTED, SECRET_REDACTED)
+        );
+    }
+
+    async fn get_step_environment(host_environments: &[ArtifactStepEnvironment]) -> Vec<String> {
+        let artifact_path = tempfile::tempdir().unwrap();
+        let workspace_path = tempfile::tempdir().unwrap();
+
+        let (tx, mut rx) = mpsc::channel(100);
+
+        run_step(
+            vec![],
+            vec![],
+            "example".to_string(),
+            artifact_path.path(),
+            host_environments,
+            &[],
+            vec![],
+            None,
+            Some("/usr/bin/env".to_string()),
+            vec![],
+            None,
+            None,
+            None,
+            None,
+            &tx,
+            workspace_path.path(),
+        )
+        .await
+        .unwrap();
+
+        drop(tx);
+
+        let mut lines = vec![];
+
+        while let Some(response) = rx.recv().await {
+            lines.push(response.unwrap().output);
+        }
+
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_run_step_clean_environment() {
+        std::env::set_var("VORPAL_TEST_HOST_VARIABLE", "leaked");
+
+        // Only names passed through by the worker reach the step
+
+        let lines = get_step_environment(&[]).await;
+
+        assert!(lines.iter().any(|line| line.starts_with("VORPAL_OUTPUT=")));
+        assert!(!lines.iter().any(|line| line.contains("leaked")));
+        assert!(!lines.iter().any(|line| line.starts_with("PATH=")));
+
+        let host_environments = get_host_environments(&["VORPAL_TEST_HOST_VARIABLE".to_string()]);
+
+        let lines = get_step_environment(&host_environments).await;
+
+        assert!(lines.contains(&"VORPAL_TEST_HOST_VARIABLE=leaked".to_string()));
+
+        std::env::remove_var("VORPAL_TEST_HOST_VARIABLE");
+    }
+
+    #[test]
+    fn test_manifest_excludes_secrets() {
+        let request = ArtifactBuildRequest {
+            artifact: Some(Artifact {
+                name: "example".to_string(),
+                ..Default::default()
+            }),
+            system: ArtifactSystem::X8664Linux as i32,
+            ..Default::default()
+        };
+
+        let with_secrets = ArtifactBuildRequest {
+            force: true,
+            secrets: vec![ArtifactStepEnvironment {
+                key: "TOKEN".to_string(),
+                value: "hunter2".to_string(),
+            }],
+            ..request.clone()
+        };
+
+        let manifest = serde_json::to_string(&request).unwrap();
+        let manifest_with_secrets = serde_json::to_string(&with_secrets).unwrap();
+
+        assert!(!manifest_with_secrets.contains("hunter2"));
+        assert_eq!(manifest, manifest_with_secrets);
+    }
+
+    #[test]
+    fn test_is_process_alive() {
+        assert!(is_process_alive(std::process::id()));
+
+        // Init always runs, and signalling it is refused for other users (EPERM)
+
+        assert!(is_process_alive(1));
+
+        assert!(!is_process_alive(0));
+        assert!(!is_process_alive(999999999));
+        assert!(!is_process_alive(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_lock_waits_for_concurrent_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("test-ab.artifact.lock");
+
+        let digest = "a".repeat(64).parse::<ArtifactDigest>().unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+
+        let (lock, waited) = acquire_lock(&lock_path, &digest, &tx).await.unwrap();
+
+        assert!(!waited);
+
+        let second = tokio::spawn({
+            let digest = digest.clone();
+            let lock_path = lock_path.clone();
+            let tx = tx.clone();
+
+            async move { acquire_lock(&lock_path, &digest, &tx).await }
+        });
+
+        let waiting = rx.recv().await.unwrap().unwrap();
+
+        assert!(waiting.output.starts_with("waiting for in-progress build"));
+        assert!(!second.is_finished());
+
+        let released = Instant::now();
+
+        drop(lock);
+
+        let (second_lock, second_waited) = second.await.unwrap().unwrap();
+
+        assert!(second_waited);
+
+        // Woken by the release rather than the next poll
+
+        assert!(released.elapsed() < LOCK_WAIT_INTERVAL / 2);
+        assert_eq!(get_dir_names(dir.path()), ["test-ab.artifact.lock"]);
+
+        drop(second_lock);
+
+        assert!(get_dir_names(dir.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_lock_breaks_stale_lock_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("test-ab.artifact.lock");
+
+        // PIDs never reach this value, so the holder is not alive
+
+        write(&lock_path, "999999999\nstale").await.unwrap();
+
+        let digest = "a".repeat(64).parse::<ArtifactDigest>().unwrap();
+
+        let (tx, _rx) = mpsc::channel(16);
+
+        let acquires = (0..2)
+            .map(|_| {
+                let digest = digest.clone();
+                let lock_path = lock_path.clone();
+                let tx = tx.clone();
+
+                tokio::spawn(async move { acquire_lock(&lock_path, &digest, &tx).await })
+            })
+            .collect::<Vec<_>>();
+
+        sleep(Duration::from_millis(500)).await;
+
+        let (finished, waiting): (Vec<_>, Vec<_>) =
+            acquires.into_iter().partition(|a| a.is_finished());
+
+        assert_eq!(finished.len(), 1);
+
+        let lock_data = read_to_string(&lock_path).await.unwrap();
+
+        assert_eq!(get_lock_data_pid(&lock_data), Some(std::process::id()));
+
+        for acquire in finished {
+            drop(acquire.await.unwrap().unwrap());
+        }
+
+        for acquire in waiting {
+            let _lock = acquire.await.unwrap().unwrap();
+
+            assert_eq!(get_dir_names(dir.path()), ["test-ab.artifact.lock"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_released_when_build_panics() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("test-ab.artifact.lock");
+
+        let digest = "a".repeat(64).parse::<ArtifactDigest>().unwrap();
+
+        let (tx, _rx) = mpsc::channel(16);
+
+        let build = tokio::spawn({
+            let digest = digest.clone();
+            let lock_path = lock_path.clone();
+            let tx = tx.clone();
+
+            async move {
+                let _lock = acquire_lock(&lock_path, &digest, &tx).await.unwrap();
+
+                panic!("build panicked");
+            }
+        });
+
+        assert!(build.await.unwrap_err().is_panic());
+        assert!(get_dir_names(dir.path()).is_empty());
+
+        // The lock of this worker would otherwise be alive forever
+
+        let (_lock, waited) = acquire_lock(&lock_path, &digest, &tx).await.unwrap();
+
+        assert!(!waited);
+    }
+}
@@ -0,0 +1,545 @@
+use crate::artifact::get_registry_compression;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::{ffi::OsStrExt, fs::symlink},
+    path::Path,
+};
+use tokio::{
+    fs::{read, read_to_string, remove_file, write},
+    task::spawn_blocking,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code::NotFound, Status};
+use tracing::debug;
+use vorpal_schema::vorpal::{
+    artifact::v0::{Artifact, ArtifactSystem},
+    registry::v0::{
+        registry_service_client::RegistryServiceClient, RegistryKind, RegistryPushRequest,
+        RegistryRequest,
+    },
+};
+use vorpal_store::{
+    archives::{compress_archive, unpack_archive, ArchiveCompression, ArchiveWriter},
+    chunks::{stream_chunks, wait_network_limit, ChunkBounds},
+    digests::{ArchiveDigest, StepDigest},
+    grpc::{RegistryChannel, RegistryClients},
+    paths::{get_file_paths, get_private_key_path, get_sandbox_dir_path, get_store_dir_path},
+    temps::{create_sandbox_file, SandboxGuard},
+};
+
+// Kept at the root of both snapshots of a step while they are archived, with the output and
+// workspace paths the step ran at
+const SNAPSHOT_PATHS_FILE: &str = ".vorpal-snapshot-paths";
+
+fn get_snapshot_names(artifact_name: &str) -> [String; 2] {
+    [
+        format!("{}-output", artifact_name),
+        format!("{}-workspace", artifact_name),
+    ]
+}
+
+/// Derives a digest per step from its inputs and the digests of the steps before it.
+///
+/// Artifact and source digests are part of every step digest, so any dependency change
+/// invalidates all cached steps of the artifact. The target system is too, so snapshots built
+/// for one system are never restored on another. So are the store and sandbox directories, which
+/// keeps the output and workspace paths of a restored step the same length as the ones its
+/// snapshots were taken at (see `replace_snapshot_paths`).
+pub fn get_step_digests(
+    artifact: &Artifact,
+    system: ArtifactSystem,
+) -> Result<Vec<StepDigest>, Status> {
+    let mut digests: Vec<StepDigest> = vec![];
+
+    for step in artifact.steps.iter() {
+        let inputs = serde_json::json!({
+            "artifacts": artifact.artifacts,
+            "environments": artifact.environments,
+            "paths": [get_store_dir_path(), get_sandbox_dir_path()],
+            "previous": digests.last().map(|d| d.to_string()),
+            "sources": artifact.sources,
+            "step": step,
+            "system": system.as_str_name(),
+        });
+
+        let inputs = serde_json::to_vec(&inputs)
+            .map_err(|err| Status::internal(format!("failed to serialize step: {:?}", err)))?;
+
+        digests.push(StepDigest::from_inputs(&inputs));
+    }
+
+    Ok(digests)
+}
+
+async fn pull_snapshot(
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+    digest: &StepDigest,
+    name: &str,
+    target_path: &Path,
+) -> Result<(), Status> {
+    let request = RegistryRequest {
+        accept_dictionary: false,
+        hash: digest.to_string(),
+        kind: RegistryKind::ArtifactStep as i32,
+        name: name.to_string(),
+    };
+
+    let mut response = registry_client.pull(request).await?.into_inner();
+    let mut data_digest = None;
+
+    // Chunks go straight to a sandbox file, workspace snapshots can be far larger than memory
+
+    let archive =
+        SandboxGuard::new(create_sandbox_file(Some("tar.zst")).await.map_err(|err| {
+            Status::internal(format!("failed to create step archive: {:?}", err))
+        })?);
+
+    let mut archive_writer = ArchiveWriter::create(archive.path())
+        .await
+        .map_err(|err| Status::internal(format!("failed to create step archive: {:?}", err)))?;
+
+    while let Some(res) = response.message().await? {
+        if !res.data_digest.is_empty() {
+            data_digest = Some(res.data_digest.parse::<ArchiveDigest>().map_err(|err| {
+                Status::data_loss(format!("step snapshot rejected: {}: {}", name, err))
+            })?);
+        }
+
+        wait_network_limit(res.data.len()).await;
+
+        archive_writer
+            .write(&res.data)
+            .await
+            .map_err(|err| Status::internal(format!("failed to write step archive: {:?}", err)))?;
+    }
+
+    archive_writer
+        .finish(data_digest.as_ref(), name)
+        .await
+        .map_err(|err| Status::data_loss(format!("step snapshot rejected: {}: {}", name, err)))?;
+
+    unpack_archive(target_path, archive.path())
+        .await
+        .map_err(|err| Status::internal(format!("failed to unpack step archive: {:?}", err)))?;
+
+    Ok(())
+}
+
+fn replace_file_paths(path: &Path, replacements: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+    let longest = replacements.iter().map(|(from, _)| from.len()).max();
+    let Some(overlap) = longest.map(|len| len.saturating_sub(1)) else {
+        return Ok(());
+    };
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut buffer: Vec<u8> = vec![];
+    let mut buffer_offset = 0u64;
+    let mut chunk = vec![0u8; 1024 * 1024];
+    let mut matches: Vec<(u64, usize)> = vec![];
+    let mut next_offset = 0u64;
+
+    loop {
+        let read_len = file.read(&mut chunk)?;
+
+        buffer.extend_from_slice(&chunk[..read_len]);
+
+        for start in 0..buffer.len() {
+            let offset = buffer_offset + start as u64;
+
+            if offset < next_offset {
+                continue;
+            }
+
+            let found = replacements
+                .iter()
+                .position(|(from, _)| buffer[start..].starts_with(from));
+
+            if let Some(index) = found {
+                matches.push((offset, index));
+                next_offset = offset + replacements[index].0.len() as u64;
+            }
+        }
+
+        if read_len == 0 {
+            break;
+        }
+
+        // Keep the tail, a path may continue in the next chunk
+
+        let keep = buffer.len().min(overlap);
+        let drain = buffer.len() - keep;
+
+        buffer.drain(..drain);
+        buffer_offset += drain as u64;
+    }
+
+    for (offset, index) in matches {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&replacements[index].1)?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the paths a snapshot was taken at with the paths it is restored at, in the files and
+/// symlink targets under `root_path`.
+///
+/// Every `from` and `to` pair has the same length, so files are rewritten in place and binaries
+/// keep their offsets.
+fn replace_snapshot_paths(
+    root_path: &Path,
+    replacements: &[(Vec<u8>, Vec<u8>)],
+) -> Result<(), Status> {
+    if replacements.iter().any(|(from, to)| from.len() != to.len()) {
+        return Err(Status::internal("step snapshot paths differ in length"));
+    }
+
+    let paths = get_file_paths(&root_path.to_path_buf(), vec![], vec![])
+        .map_err(|err| Status::internal(format!("failed to get step files: {:?}", err)))?;
+
+    for path in paths {
+        let metadata = fs::symlink_metadata(&path)
+            .map_err(|err| Status::internal(format!("failed to read step file: {:?}", err)))?;
+
+        if metadata.is_symlink() {
+            let target = fs::read_link(&path)
+                .map_err(|err| Status::internal(format!("failed to read step link: {:?}", err)))?;
+
+            let target = target.as_os_str().as_bytes();
+
+            let Some((from, to)) = replacements
+                .iter()
+                .find(|(from, _)| target.starts_with(from))
+            else {
+                continue;
+            };
+
+            let target = [to.as_slice(), &target[from.len()..]].concat();
+
+            fs::remove_file(&path)
+                .and_then(|_| symlink(std::ffi::OsStr::from_bytes(&target), &path))
+                .map_err(|err| Status::internal(format!("failed to write step link: {:?}", err)))?;
+
+            continue;
+        }
+
+        if metadata.is_file() {
+            replace_file_paths(&path, replacements)
+                .map_err(|err| Status::internal(format!("failed to write step file: {:?}", err)))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores the output and workspace snapshots of a cached step, from the first registry with
+/// both of them.
+///
+/// Snapshots hold the absolute output and workspace paths of the build they were taken from,
+/// which are rewritten to `artifact_path` and `workspace_path`.
+pub async fn pull_step_snapshot(
+    registries: &RegistryClients,
+    artifact_name: &str,
+    artifact_path: &Path,
+    digest: &StepDigest,
+    workspace_path: &Path,
+) -> Result<bool, Status> {
+    let [output_name, workspace_name] = get_snapshot_names(artifact_name);
+
+    let output_request = RegistryRequest {
+        accept_dictionary: false,
+        hash: digest.to_string(),
+        kind: RegistryKind::ArtifactStep as i32,
+        name: output_name.clone(),
+    };
+
+    let Some(mut registry) = registries.find(&output_request).await? else {
+        return Ok(false);
+    };
+
+    let workspace_request = RegistryRequest {
+        name: workspace_name.clone(),
+        ..output_request
+    };
+
+    match registry.client.exists(workspace_request).await {
+        Ok(_) => {}
+        Err(status) if status.code() == NotFound => return Ok(false),
+        Err(status) => return Err(status),
+    }
+
+    let registry_client = &mut registry.client;
+
+    pull_snapshot(registry_client, digest, &output_name, artifact_path).await?;
+    pull_snapshot(registry_client, digest, &workspace_name, workspace_path).await?;
+
+    let snapshot_paths = read_to_string(artifact_path.join(SNAPSHOT_PATHS_FILE))
+        .await
+        .map_err(|err| Status::data_loss(format!("step snapshot paths not found: {:?}", err)))?;
+
+    for path in [artifact_path, workspace_path] {
+        remove_file(path.join(SNAPSHOT_PATHS_FILE))
+            .await
+            .map_err(|err| Status::internal(format!("failed to remove step paths: {:?}", err)))?;
+    }
+
+    let Some((snapshot_output, snapshot_workspace)) = snapshot_paths.split_once('\n') else {
+        return Err(Status::data_loss("step snapshot paths rejected"));
+    };
+
+    let replacements = vec![
+        (
+            snapshot_output.as_bytes().to_vec(),
+            artifact_path.as_os_str().as_bytes().to_vec(),
+        ),
+        (
+            snapshot_workspace.as_bytes().to_vec(),
+            workspace_path.as_os_str().as_bytes().to_vec(),
+        ),
+    ];
+
+    for path in [artifact_path.to_path_buf(), workspace_path.to_path_buf()] {
+        let replacements = replacements.clone();
+
+        spawn_blocking(move || replace_snapshot_paths(&path, &replacements))
+            .await
+            .map_err(|err| Status::internal(format!("failed to join step task: {:?}", err)))??;
+    }
+
+    Ok(true)
+}
+
+async fn push_snapshot(
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+    archive_compression: ArchiveCompression,
+    chunk_bounds: ChunkBounds,
+    digest: &StepDigest,
+    name: &str,
+    source_path: &Path,
+) -> Result<(), Status> {
+    let source_path = source_path.to_path_buf();
+
+    let source_files = get_file_paths(&source_path, vec![], vec![])
+        .map_err(|err| Status::internal(format!("failed to get step files: {:?}", err)))?;
+
+    let archive_path = create_sandbox_file(Some("tar.zst"))
+        .await
+        .map_err(|err| Status::internal(format!("failed to create step archive: {:?}", err)))?;
+
+    compress_archive(
+        archive_compression,
+        &source_path,
+        &source_files,
+        &archive_path,
+    )
+    .await
+    .map_err(|err| Status::internal(format!("failed to compress step: {:?}", err)))?;
+
+    let data = read(&archive_path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to read step archive: {:?}", err)))?;
+
+    remove_file(&archive_path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to remove step archive: {:?}", err)))?;
+
+    let private_key_path = get_private_key_path();
+
+    if !private_key_path.exists() {
+        return Err(Status::internal("private key not found"));
+    }
+
+    let data_signature = vorpal_notary::sign(private_key_path.clone(), &data)
+        .await
+        .map_err(|err| Status::internal(format!("failed to sign step: {:?}", err)))?;
+
+    let data_signature_fingerprint = vorpal_notary::get_private_key_fingerprint(private_key_path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to get key fingerprint: {:?}", err)))?;
+
+    let request_compression = get_registry_compression(archive_compression);
+    let request_hash = digest.to_string();
+    let request_name = name.to_string();
+
+    let (request_stream, request_summary) =
+        stream_chunks(data, chunk_bounds, move |data| RegistryPushRequest {
+            compression: request_compression as i32,
+            data,
+            data_signature: data_signature.to_vec(),
+            data_signature_fingerprint: data_signature_fingerprint.clone(),
+            delta_base: String::new(),
+            hash: request_hash.clone(),
+            kind: RegistryKind::ArtifactStep as i32,
+            name: request_name.clone(),
+        });
+
+    registry_client
+        .push(ReceiverStream::new(request_stream))
+        .await
+        .map_err(|err| Status::internal(format!("failed to push step: {:?}", err)))?;
+
+    if let Ok(summary) = request_summary.await {
+        debug!("push transfer: {}", summary);
+    }
+
+    Ok(())
+}
+
+/// Pushes the output and workspace snapshots of a completed step.
+pub async fn push_step_snapshot(
+    registry_client: &mut RegistryServiceClient<RegistryChannel>,
+    archive_compression: ArchiveCompression,
+    artifact_name: &str,
+    artifact_path: &Path,
+    chunk_bounds: ChunkBounds,
+    digest: &StepDigest,
+    workspace_path: &Path,
+) -> Result<(), Status> {
+    let [output_name, workspace_name] = get_snapshot_names(artifact_name);
+
+    let snapshot_paths = format!("{}\n{}", artifact_path.display(), workspace_path.display());
+
+    for path in [artifact_path, workspace_path] {
+        write(path.join(SNAPSHOT_PATHS_FILE), &snapshot_paths)
+            .await
+            .map_err(|err| Status::internal(format!("failed to write step paths: {:?}", err)))?;
+    }
+
+    let mut result = push_snapshot(
+        registry_client,
+        archive_compression,
+        chunk_bounds,
+        digest,
+        &output_name,
+        artifact_path,
+    )
+    .await;
+
+    if result.is_ok() {
+        result = push_snapshot(
+            registry_client,
+            archive_compression,
+            chunk_bounds,
+            digest,
+            &workspace_name,
+            workspace_path,
+        )
+        .await;
+    }
+
+    // Remove the paths before the next step runs, they are never part of the artifact
+
+    for path in [artifact_path, workspace_path] {
+        remove_file(path.join(SNAPSHOT_PATHS_FILE))
+            .await
+            .map_err(|err| Status::internal(format!("failed to remove step paths: {:?}", err)))?;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_link, write};
+    use vorpal_schema::vorpal::artifact::v0::ArtifactStep;
+
+    fn get_artifact() -> Artifact {
+        let step = |script: &str| ArtifactStep {
+            script: Some(script.to_string()),
+            ..Default::default()
+        };
+
+        Artifact {
+            name: "example".to_string(),
+            steps: vec![step("make"), step("make install")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_get_step_digests() {
+        let artifact = get_artifact();
+
+        let digests = get_step_digests(&artifact, ArtifactSystem::X8664Linux).unwrap();
+
+        assert_eq!(digests.len(), 2);
+        assert_ne!(digests[0], digests[1]);
+        assert_eq!(
+            digests,
+            get_step_digests(&artifact, ArtifactSystem::X8664Linux).unwrap()
+        );
+
+        // Every step of another system gets another digest
+
+        let other = get_step_digests(&artifact, ArtifactSystem::Aarch64Linux).unwrap();
+
+        assert!(digests.iter().zip(other.iter()).all(|(a, b)| a != b));
+
+        // Changing a step keeps the digests of the steps before it
+
+        let mut changed = artifact.clone();
+
+        changed.steps[1].script = Some("make install-strip".to_string());
+
+        let changed = get_step_digests(&changed, ArtifactSystem::X8664Linux).unwrap();
+
+        assert_eq!(digests[0], changed[0]);
+        assert_ne!(digests[1], changed[1]);
+    }
+
+    #[test]
+    fn test_replace_snapshot_paths() {
+        let root = tempfile::tempdir().unwrap();
+
+        let replacements = vec![
+            (b"/store/aaaa".to_vec(), b"/store/bbbb".to_vec()),
+            (b"/sandbox/1111".to_vec(), b"/sandbox/2222".to_vec()),
+        ];
+
+        write(
+            root.path().join("config"),
+            "prefix=/store/aaaa\nsrc=/sandbox/1111/src\nother=/store/aaa\n",
+        )
+        .unwrap();
+
+        // Paths across the chunks a file is read in
+
+        let mut large = vec![0u8; 1024 * 1024 - 4];
+
+        large.extend_from_slice(b"/store/aaaa/lib");
+
+        write(root.path().join("large"), &large).unwrap();
+
+        symlink("/store/aaaa/bin/tool", root.path().join("tool")).unwrap();
+        symlink("/usr/bin/env", root.path().join("env")).unwrap();
+
+        replace_snapshot_paths(root.path(), &replacements).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.path().join("config")).unwrap(),
+            "prefix=/store/bbbb\nsrc=/sandbox/2222/src\nother=/store/aaa\n"
+        );
+
+        let large = fs::read(root.path().join("large")).unwrap();
+
+        assert_eq!(large.len(), 1024 * 1024 + 11);
+        assert!(large.ends_with(b"/store/bbbb/lib"));
+
+        assert_eq!(
+            read_link(root.path().join("tool")).unwrap(),
+            Path::new("/store/bbbb/bin/tool")
+        );
+        assert_eq!(
+            read_link(root.path().join("env")).unwrap(),
+            Path::new("/usr/bin/env")
+        );
+
+        // Paths of another length are never replaced
+
+        let replacements = vec![(b"/store/bbbb".to_vec(), b"/store/cc".to_vec())];
+
+        assert!(replace_snapshot_paths(root.path(), &replacements).is_err());
+    }
+}
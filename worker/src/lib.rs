@@ -1,2 +1,8 @@
+pub mod agent;
 pub mod artifact;
+mod cache;
+pub mod limits;
+pub mod push;
+pub mod queue;
 pub mod service;
+pub mod webhook;
@@ -0,0 +1,399 @@
+use std::{
+    fs::{read_to_string, OpenOptions},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::process::ExitStatusExt,
+    },
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+use tokio::{
+    fs::{create_dir, remove_dir, write},
+    process::Command,
+};
+use tracing::{debug, warn};
+
+// Period used for the cgroup cpu quota
+const CGROUP_CPU_PERIOD: u64 = 100_000;
+
+// Lowercase output of common runtimes and shells when an allocation fails
+const ALLOCATION_FAILURE_MARKERS: [&str; 7] = [
+    "cannot allocate",
+    "failed to allocate",
+    "memory allocation of",
+    "memoryerror",
+    "out of memory",
+    "std::bad_alloc",
+    "unable to allocate",
+];
+
+// Signals processes die of when the address space limit stops the stack or an unchecked
+// allocation from growing
+const ALLOCATION_FAILURE_SIGNALS: [i32; 3] = [libc::SIGABRT, libc::SIGBUS, libc::SIGSEGV];
+
+/// Returns whether a line of step output reports a failed allocation.
+pub fn is_allocation_failure(line: &str) -> bool {
+    let line = line.to_lowercase();
+
+    ALLOCATION_FAILURE_MARKERS
+        .iter()
+        .any(|marker| line.contains(marker))
+}
+
+/// Resource limits applied to a single step process.
+///
+/// On Linux with a delegated cgroup v2 hierarchy the step runs in its own cgroup, which
+/// enforces both limits and records out-of-memory kills. Otherwise cpu is limited by
+/// affinity (which `nproc` respects) and memory by the address space rlimit.
+#[derive(Debug, Default)]
+pub struct StepLimits {
+    cgroup: Option<PathBuf>,
+    cgroup_procs: Option<OwnedFd>,
+    cpu: Option<u32>,
+    fallback: Option<String>,
+    memory_bytes: Option<u64>,
+}
+
+// Returns the cgroup of the worker when it delegates the cpu and memory controllers, or why
+// step cgroups can not be used
+fn get_cgroup_parent_path() -> Result<PathBuf, String> {
+    let cgroup = read_to_string("/proc/self/cgroup")
+        .map_err(|err| format!("failed to read /proc/self/cgroup: {}", err))?;
+
+    let cgroup = cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| "no cgroup v2 hierarchy".to_string())?;
+
+    let parent = Path::new("/sys/fs/cgroup").join(cgroup.trim_start_matches('/'));
+
+    let controllers_path = parent.join("cgroup.subtree_control");
+
+    let controllers = read_to_string(&controllers_path)
+        .map_err(|err| format!("failed to read {}: {}", controllers_path.display(), err))?;
+
+    let controllers = controllers.split_whitespace().collect::<Vec<_>>();
+
+    if !controllers.contains(&"cpu") || !controllers.contains(&"memory") {
+        return Err(format!(
+            "{} does not delegate the cpu and memory controllers",
+            parent.display()
+        ));
+    }
+
+    Ok(parent)
+}
+
+impl StepLimits {
+    pub async fn new(name: &str, cpu: Option<u32>, memory_bytes: Option<u64>) -> Self {
+        let mut limits = Self {
+            cpu,
+            memory_bytes,
+            ..Default::default()
+        };
+
+        if cpu.is_none() && memory_bytes.is_none() {
+            return limits;
+        }
+
+        match get_cgroup_parent_path() {
+            Ok(parent) => {
+                let cgroup = parent.join(format!("vorpal-{}", name));
+
+                match limits.create_cgroup(&cgroup).await {
+                    Ok(procs) => {
+                        debug!("step cgroup: {}", cgroup.display());
+
+                        limits.cgroup = Some(cgroup);
+                        limits.cgroup_procs = Some(procs);
+                    }
+
+                    Err(err) => {
+                        let _ = remove_dir(&cgroup).await;
+
+                        limits.fallback = Some(format!("failed to create step cgroup: {}", err));
+                    }
+                }
+            }
+
+            Err(reason) => limits.fallback = Some(reason),
+        }
+
+        if let Some(reason) = &limits.fallback {
+            warn!("cgroup delegation not available, using rlimits: {}", reason);
+        }
+
+        limits
+    }
+
+    async fn create_cgroup(&self, cgroup: &Path) -> std::io::Result<OwnedFd> {
+        create_dir(cgroup).await?;
+
+        if let Some(cpu) = self.cpu {
+            let quota = CGROUP_CPU_PERIOD * cpu as u64;
+
+            write(
+                cgroup.join("cpu.max"),
+                format!("{} {}", quota, CGROUP_CPU_PERIOD),
+            )
+            .await?;
+        }
+
+        if let Some(memory_bytes) = self.memory_bytes {
+            write(cgroup.join("memory.max"), memory_bytes.to_string()).await?;
+            write(cgroup.join("memory.swap.max"), "0").await?;
+        }
+
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(cgroup.join("cgroup.procs"))?;
+
+        Ok(procs.into())
+    }
+
+    /// Applies the limits to `command` before it is spawned.
+    pub fn apply(&self, command: &mut Command) {
+        if let Some(cpu) = self.cpu {
+            command.env("MAKEFLAGS", format!("-j{}", cpu));
+        }
+
+        if let Some(procs) = &self.cgroup_procs {
+            let procs = procs.as_raw_fd();
+
+            // SAFETY: only async-signal-safe calls on an fd opened before fork
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::write(procs, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+
+            return;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(cpu) = self.cpu {
+            // SAFETY: `cpu_set_t` is plain data and the calls only touch the local set
+            let cpu_set = unsafe {
+                let mut current: libc::cpu_set_t = std::mem::zeroed();
+                let mut limited: libc::cpu_set_t = std::mem::zeroed();
+
+                libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut current);
+
+                let mut count = 0;
+
+                for index in 0..libc::CPU_SETSIZE as usize {
+                    if count < cpu && libc::CPU_ISSET(index, &current) {
+                        libc::CPU_SET(index, &mut limited);
+                        count += 1;
+                    }
+                }
+
+                limited
+            };
+
+            // SAFETY: `sched_setaffinity` is async-signal-safe
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &cpu_set) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+
+        if let Some(memory_bytes) = self.memory_bytes {
+            let limit = libc::rlimit {
+                rlim_cur: memory_bytes as libc::rlim_t,
+                rlim_max: memory_bytes as libc::rlim_t,
+            };
+
+            // SAFETY: `setrlimit` is async-signal-safe
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    /// Returns why limits are applied with affinity and rlimits instead of a step cgroup.
+    pub fn fallback(&self) -> Option<&str> {
+        self.fallback.as_deref()
+    }
+
+    /// Returns true when the step exceeded its memory limit.
+    ///
+    /// With a step cgroup this is an out-of-memory kill it recorded. Under the address space
+    /// rlimit the kernel never kills the step, so a failed step counts when its output reported
+    /// a failed allocation (`allocation_failed`) or it died of a signal such failures raise.
+    pub fn is_memory_exceeded(&self, status: &ExitStatus, allocation_failed: bool) -> bool {
+        if self.cgroup.is_some() {
+            return self.is_memory_killed();
+        }
+
+        if self.memory_bytes.is_none() || status.success() {
+            return false;
+        }
+
+        allocation_failed
+            || status
+                .signal()
+                .is_some_and(|signal| ALLOCATION_FAILURE_SIGNALS.contains(&signal))
+    }
+
+    /// Returns the error reported for a step that exceeded its memory limit.
+    pub fn get_memory_exceeded_message(&self) -> String {
+        let memory_bytes = self.memory_bytes.unwrap_or_default();
+
+        match self.cgroup {
+            Some(_) => format!("sandbox killed by memory limit: {} bytes", memory_bytes),
+            None => format!(
+                "sandbox killed by memory limit: {} bytes (allocation failed under the address space limit)",
+                memory_bytes
+            ),
+        }
+    }
+
+    fn is_memory_killed(&self) -> bool {
+        let Some(cgroup) = &self.cgroup else {
+            return false;
+        };
+
+        let Ok(events) = read_to_string(cgroup.join("memory.events")) else {
+            return false;
+        };
+
+        events
+            .lines()
+            .filter_map(|line| line.strip_prefix("oom_kill "))
+            .any(|count| count.trim().parse::<u64>().unwrap_or(0) > 0)
+    }
+
+    pub async fn cleanup(self) {
+        let Some(cgroup) = self.cgroup else {
+            return;
+        };
+
+        drop(self.cgroup_procs);
+
+        if let Err(err) = remove_dir(&cgroup).await {
+            warn!("failed to remove step cgroup: {:?}", err);
+        }
+    }
+}
+
+/// Returns the bytes available to unprivileged users on the filesystem containing `path`.
+pub fn get_available_disk_bytes(path: &Path) -> std::io::Result<u64> {
+    let path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[test]
+    fn test_is_allocation_failure() {
+        let failures = [
+            "bash: xrealloc: cannot allocate 62996480 bytes",
+            "memory allocation of 1073741824 bytes failed",
+            "terminate called after throwing an instance of 'std::bad_alloc'",
+            "MemoryError",
+            "fatal error: runtime: out of memory",
+            "FATAL ERROR: Reached heap limit Allocation failed - JavaScript heap out of memory",
+            "cc1plus: out of memory allocating 65536 bytes",
+            "mmap: Cannot allocate memory",
+        ];
+
+        for line in failures {
+            assert!(is_allocation_failure(line), "{}", line);
+        }
+
+        assert!(!is_allocation_failure("error: linking with `cc` failed"));
+        assert!(!is_allocation_failure("allocated 12 buffers"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_exceeded_without_cgroup() {
+        let limits = StepLimits {
+            fallback: Some("no cgroup v2 hierarchy".to_string()),
+            memory_bytes: Some(64 * 1024 * 1024),
+            ..Default::default()
+        };
+
+        // Shells and runtimes report failed allocations and exit, the kernel kills nothing
+
+        let mut command = Command::new("bash");
+
+        command
+            .args([
+                "-c",
+                "x=$(head -c 268435456 /dev/zero | tr '\\0' a); echo ${#x}",
+            ])
+            .stderr(std::process::Stdio::piped());
+
+        limits.apply(&mut command);
+
+        let mut child = command.spawn().unwrap();
+
+        let stderr = child.stderr.take().unwrap();
+        let mut lines = BufReader::new(stderr).lines();
+        let mut allocation_failed = false;
+
+        while let Some(line) = lines.next_line().await.unwrap() {
+            allocation_failed |= is_allocation_failure(&line);
+        }
+
+        let status = child.wait().await.unwrap();
+
+        assert!(!status.success());
+        assert!(limits.is_memory_exceeded(&status, allocation_failed));
+
+        assert_eq!(
+            limits.get_memory_exceeded_message(),
+            "sandbox killed by memory limit: 67108864 bytes (allocation failed under the address space limit)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_exceeded_requires_failure() {
+        let limits = StepLimits {
+            memory_bytes: Some(64 * 1024 * 1024),
+            ..Default::default()
+        };
+
+        let status = Command::new("true").status().await.unwrap();
+
+        assert!(!limits.is_memory_exceeded(&status, true));
+
+        let status = Command::new("false").status().await.unwrap();
+
+        assert!(!limits.is_memory_exceeded(&status, false));
+        assert!(limits.is_memory_exceeded(&status, true));
+
+        // Without a memory limit failures are never attributed to it
+
+        let limits = StepLimits::default();
+
+        assert!(!limits.is_memory_exceeded(&status, true));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::artifact::push_artifact;
+use anyhow::{bail, Error, Result};
+use std::{
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    fs::{create_dir_all, read, read_dir, read_to_string, remove_dir_all, write},
+    sync::{mpsc, Notify},
+    time::sleep,
+};
+use tonic::Status;
+use tracing::{info, warn};
+use vorpal_schema::vorpal::{
+    artifact::v0::{ArtifactId, ArtifactProvenance, ArtifactPushPolicy},
+    registry::v0::registry_service_client::RegistryServiceClient,
+};
+use vorpal_store::{
+    chunks::{ChunkBounds, CHUNK_MESSAGE_SIZE_LIMIT},
+    digests::ArtifactDigest,
+    grpc::{get_registry_channel, get_registry_token},
+    paths::{copy_file, get_push_dir_path, get_push_path},
+};
+
+const PUSH_ARCHIVE_FILE: &str = "artifact.tar.zst";
+const PUSH_MANIFEST_FILE: &str = "artifact.json";
+const PUSH_PROVENANCE_FILE: &str = "provenance.json";
+
+const PUSH_ATTEMPTS_MAX: u32 = 5;
+const PUSH_DELAY_INITIAL: Duration = Duration::from_secs(2);
+const PUSH_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// When a worker pushes the artifacts it builds to the registry (`--push-policy`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PushPolicy {
+    #[default]
+    Always,
+    Async,
+    Never,
+}
+
+impl PushPolicy {
+    /// Returns the policy requested by a build, `None` when the build keeps the worker policy.
+    pub fn from_request(policy: ArtifactPushPolicy) -> Option<Self> {
+        match policy {
+            ArtifactPushPolicy::Always => Some(Self::Always),
+            ArtifactPushPolicy::Async => Some(Self::Async),
+            ArtifactPushPolicy::Never => Some(Self::Never),
+            ArtifactPushPolicy::UnknownPushPolicy => None,
+        }
+    }
+
+    pub fn as_request(&self) -> ArtifactPushPolicy {
+        match self {
+            Self::Always => ArtifactPushPolicy::Always,
+            Self::Async => ArtifactPushPolicy::Async,
+            Self::Never => ArtifactPushPolicy::Never,
+        }
+    }
+}
+
+impl fmt::Display for PushPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Always => write!(f, "always"),
+            Self::Async => write!(f, "async"),
+            Self::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for PushPolicy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "always" => Ok(Self::Always),
+            "async" => Ok(Self::Async),
+            "never" => Ok(Self::Never),
+            _ => bail!(
+                "unsupported push policy: {} (expected always, async or never)",
+                value
+            ),
+        }
+    }
+}
+
+/// Keeps the archive, manifest and provenance of a built artifact in the push directory until
+/// it is pushed, by the push queue or `vorpal store push`.
+pub(crate) async fn write_pending_push(
+    archive_path: &Path,
+    artifact_name: &str,
+    manifest_hash: &ArtifactDigest,
+    manifest_json: &str,
+    provenance: &ArtifactProvenance,
+) -> Result<(), Status> {
+    let push_path = get_push_path(manifest_hash, artifact_name);
+
+    let provenance_json = serde_json::to_string(provenance)
+        .map_err(|err| Status::internal(format!("failed to serialize provenance: {:?}", err)))?;
+
+    create_dir_all(&push_path)
+        .await
+        .map_err(|err| Status::internal(format!("failed to create push path: {:?}", err)))?;
+
+    copy_file(archive_path, &push_path.join(PUSH_ARCHIVE_FILE))
+        .await
+        .map_err(|err| Status::internal(format!("failed to write push archive: {:?}", err)))?;
+
+    write(push_path.join(PUSH_MANIFEST_FILE), manifest_json)
+        .await
+        .map_err(|err| Status::internal(format!("failed to write push manifest: {:?}", err)))?;
+
+    write(push_path.join(PUSH_PROVENANCE_FILE), provenance_json)
+        .await
+        .map_err(|err| Status::internal(format!("failed to write push provenance: {:?}", err)))?;
+
+    Ok(())
+}
+
+/// Returns the artifacts of `digest` waiting in the push directory.
+pub async fn get_pending_pushes(digest: &ArtifactDigest) -> Result<Vec<ArtifactId>> {
+    let push_dir_path = get_push_dir_path();
+
+    if !push_dir_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let suffix = format!("-{}", digest);
+
+    let mut artifact_ids = vec![];
+
+    let mut entries = read_dir(&push_dir_path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        if let Some(name) = file_name.strip_suffix(&suffix) {
+            artifact_ids.push(ArtifactId {
+                hash: digest.to_string(),
+                name: name.to_string(),
+            });
+        }
+    }
+
+    artifact_ids.sort();
+
+    Ok(artifact_ids)
+}
+
+/// Pushes an artifact kept by `write_pending_push`, removed from the push directory once the
+/// registry has it.
+pub async fn push_pending(
+    chunk_bounds: ChunkBounds,
+    registry: &str,
+    artifact_id: &ArtifactId,
+) -> Result<()> {
+    let manifest_hash = artifact_id.hash.parse::<ArtifactDigest>()?;
+
+    let push_path = get_push_path(&manifest_hash, &artifact_id.name);
+
+    if !push_path.exists() {
+        bail!("no pending push: {}-{}", artifact_id.name, artifact_id.hash);
+    }
+
+    let archive_data = read(push_path.join(PUSH_ARCHIVE_FILE)).await?;
+    let manifest_json = read_to_string(push_path.join(PUSH_MANIFEST_FILE)).await?;
+    let provenance_json = read_to_string(push_path.join(PUSH_PROVENANCE_FILE)).await?;
+
+    let provenance = serde_json::from_str::<ArtifactProvenance>(&provenance_json)?;
+
+    let registry_channel = get_registry_channel(registry, get_registry_token().as_deref()).await?;
+
+    let mut registry_client = RegistryServiceClient::new(registry_channel)
+        .max_decoding_message_size(CHUNK_MESSAGE_SIZE_LIMIT);
+
+    push_artifact(
+        &mut registry_client,
+        archive_data,
+        &artifact_id.name,
+        chunk_bounds,
+        &manifest_hash,
+        &manifest_json,
+        &provenance,
+    )
+    .await?;
+
+    remove_dir_all(&push_path).await?;
+
+    Ok(())
+}
+
+/// Pushes the artifacts of `async` builds in the background, one at a time so pushes do not
+/// compete for the uplink with each other.
+#[derive(Clone, Debug)]
+pub struct PushQueue {
+    idle: Arc<Notify>,
+    pending: Arc<AtomicUsize>,
+    sender: mpsc::UnboundedSender<ArtifactId>,
+}
+
+impl PushQueue {
+    pub fn new(chunk_bounds: ChunkBounds, registry: String) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<ArtifactId>();
+
+        let idle = Arc::new(Notify::new());
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let queue_idle = idle.clone();
+        let queue_pending = pending.clone();
+
+        tokio::spawn(async move {
+            while let Some(artifact_id) = receiver.recv().await {
+                push_queued(chunk_bounds, &registry, &artifact_id).await;
+
+                if queue_pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    queue_idle.notify_waiters();
+                }
+            }
+        });
+
+        Self {
+            idle,
+            pending,
+            sender,
+        }
+    }
+
+    pub fn push(&self, artifact_id: ArtifactId) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+
+        if let Err(err) = self.sender.send(artifact_id) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+
+            warn!(
+                "push queue stopped, push it with `vorpal store push --digest {}`",
+                err.0.hash
+            );
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Waits until every queued push finished, before the worker shuts down.
+    pub async fn flush(&self) {
+        loop {
+            let idle = self.idle.notified();
+
+            if self.pending() == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+/// Pushes a queued artifact with exponential backoff, it stays in the push directory when every
+/// attempt failed.
+async fn push_queued(chunk_bounds: ChunkBounds, registry: &str, artifact_id: &ArtifactId) {
+    let mut delay = PUSH_DELAY_INITIAL;
+
+    for attempt in 1..=PUSH_ATTEMPTS_MAX {
+        let err = match push_pending(chunk_bounds, registry, artifact_id).await {
+            Ok(_) => {
+                info!("pushed: {}-{}", artifact_id.name, artifact_id.hash);
+
+                return;
+            }
+
+            Err(err) => err,
+        };
+
+        if attempt == PUSH_ATTEMPTS_MAX {
+            warn!(
+                "push of {}-{} failed after {} attempts, push it with `vorpal store push --digest {}`: {:#}",
+                artifact_id.name, artifact_id.hash, attempt, artifact_id.hash, err
+            );
+
+            return;
+        }
+
+        warn!(
+            "push of {}-{} failed (attempt {}/{}), retrying in {}s: {:#}",
+            artifact_id.name,
+            artifact_id.hash,
+            attempt,
+            PUSH_ATTEMPTS_MAX,
+            delay.as_secs(),
+            err
+        );
+
+        sleep(delay).await;
+
+        delay = (delay * 2).min(PUSH_DELAY_MAX);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::available_parallelism,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc::Sender, OwnedSemaphorePermit, Semaphore},
+    time::interval,
+};
+use tonic::Status;
+use vorpal_schema::vorpal::artifact::v0::{ArtifactBuildResponse, ArtifactBuildStream};
+
+// Interval of queue position messages sent to waiting clients
+const QUEUE_MESSAGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Half of the available cpus, at least one.
+pub fn get_default_max_concurrent_builds() -> usize {
+    available_parallelism()
+        .map(|cpus| cpus.get() / 2)
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Limits the builds running at once on a worker, queuing the rest in arrival order.
+#[derive(Clone, Debug)]
+pub struct BuildQueue {
+    max: usize,
+    next_ticket: Arc<AtomicU64>,
+    semaphore: Arc<Semaphore>,
+    waiting: Arc<Mutex<VecDeque<u64>>>,
+}
+
+impl Default for BuildQueue {
+    fn default() -> Self {
+        Self::new(get_default_max_concurrent_builds())
+    }
+}
+
+// Removes a waiting build from the queue when it starts or its client goes away
+struct BuildQueueTicket<'a> {
+    id: u64,
+    waiting: &'a Mutex<VecDeque<u64>>,
+}
+
+impl Drop for BuildQueueTicket<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.waiting.lock().unwrap();
+
+        waiting.retain(|id| *id != self.id);
+    }
+}
+
+impl BuildQueue {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            next_ticket: Arc::new(AtomicU64::new(0)),
+            semaphore: Arc::new(Semaphore::new(max)),
+            waiting: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn queued(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    pub fn running(&self) -> usize {
+        self.max - self.semaphore.available_permits()
+    }
+
+    /// Waits for a build slot, sending the queue position to `tx` while queued. The slot is
+    /// released when the returned permit is dropped.
+    pub async fn acquire(
+        &self,
+        tx: &Sender<Result<ArtifactBuildResponse, Status>>,
+    ) -> Result<OwnedSemaphorePermit, Status> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let ticket = BuildQueueTicket {
+            id: self.next_ticket.fetch_add(1, Ordering::Relaxed),
+            waiting: &self.waiting,
+        };
+
+        self.waiting.lock().unwrap().push_back(ticket.id);
+
+        let permit = self.semaphore.clone().acquire_owned();
+
+        tokio::pin!(permit);
+
+        let mut messages = interval(QUEUE_MESSAGE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                permit = &mut permit => {
+                    return permit.map_err(|_| Status::unavailable("build queue closed"));
+                }
+
+                _ = messages.tick() => {
+                    let position = self
+                        .waiting
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .position(|id| *id == ticket.id)
+                        .unwrap_or_default()
+                        + 1;
+
+                    let response = ArtifactBuildResponse {
+                        durations: vec![],
+                        event: None,
+                        output: format!("queued: position {}", position),
+                        stream: ArtifactBuildStream::UnknownStream as i32,
+                    };
+
+                    if tx.send(Ok(response)).await.is_err() {
+                        return Err(Status::cancelled("client disconnected while queued"));
+                    }
+                }
+            }
+        }
+    }
+}
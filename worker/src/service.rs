@@ -1,13 +1,25 @@
-use crate::artifact::ArtifactServer;
+use crate::{
+    artifact::{
+        log_signing_key_fingerprint, remove_interrupted_builds, ArtifactServer,
+        DEFAULT_MIN_DISK_BYTES,
+    },
+    push::PushPolicy,
+    queue::get_default_max_concurrent_builds,
+};
 use anyhow::Result;
 use std::env::consts::{ARCH, OS};
-use tonic::transport::Server;
+use tonic::{codec::CompressionEncoding, transport::Server};
 use vorpal_schema::{
     get_artifact_system, vorpal::artifact::v0::artifact_service_server::ArtifactServiceServer,
 };
-use vorpal_store::paths::get_public_key_path;
+use vorpal_store::{
+    archives::ArchiveCompression,
+    chunks::ChunkBounds,
+    grpc::{get_max_message_size, RegistryUrls},
+    paths::get_public_key_path,
+};
 
-pub async fn listen(registry: &str, port: u16) -> Result<()> {
+pub async fn listen(registries: &RegistryUrls, port: u16) -> Result<()> {
     let public_key_path = get_public_key_path();
 
     if !public_key_path.exists() {
@@ -22,8 +34,24 @@ pub async fn listen(registry: &str, port: u16) -> Result<()> {
         .parse()
         .expect("failed to parse address");
 
-    let artifact_service =
-        ArtifactServiceServer::new(ArtifactServer::new(registry.to_string(), system));
+    remove_interrupted_builds().await?;
+
+    log_signing_key_fingerprint().await;
+
+    let artifact_service = ArtifactServiceServer::new(ArtifactServer::new(
+        ArchiveCompression::default(),
+        ChunkBounds::default(),
+        vec![],
+        None,
+        get_default_max_concurrent_builds(),
+        DEFAULT_MIN_DISK_BYTES,
+        PushPolicy::default(),
+        registries.clone(),
+        false,
+        system,
+    ))
+    .accept_compressed(CompressionEncoding::Gzip)
+    .max_decoding_message_size(get_max_message_size());
 
     Server::builder()
         .add_service(artifact_service)
@@ -0,0 +1,423 @@
+use crate::artifact::get_hostname;
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{fs::read, time::sleep};
+use tonic::{Code, Status};
+use tracing::warn;
+use vorpal_schema::{
+    get_artifact_system_name,
+    vorpal::artifact::v0::{ArtifactBuildRequest, ArtifactSystem},
+};
+use vorpal_store::{digests::ArtifactDigest, http::get_http_client_builder};
+
+// Header with the hex HMAC-SHA256 of the body, keyed with the shared secret
+pub const EVENT_SIGNATURE_HEADER: &str = "x-vorpal-signature";
+
+// Deliveries are retried on server errors and failed connections only
+const EVENT_DELIVERY_ATTEMPTS: u32 = 3;
+const EVENT_DELIVERY_RETRY_DELAY: Duration = Duration::from_secs(1);
+const EVENT_DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Longer build errors are cut in events, the full error stays in the worker log
+const EVENT_ERROR_SIZE_MAX: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildOutcome {
+    Cached,
+    Failure,
+    Started,
+    Success,
+}
+
+impl BuildOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BuildOutcome::Cached => "cached",
+            BuildOutcome::Failure => "failure",
+            BuildOutcome::Started => "started",
+            BuildOutcome::Success => "success",
+        }
+    }
+}
+
+/// Receiver of build events, posted as JSON documents.
+#[derive(Clone)]
+pub struct EventWebhook {
+    client: Client,
+    secret: Option<Vec<u8>>,
+    url: String,
+}
+
+impl fmt::Debug for EventWebhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventWebhook")
+            .field("signed", &self.secret.is_some())
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+impl EventWebhook {
+    /// Events are signed when `secret_path` is set, with its content less trailing whitespace.
+    pub async fn new(url: &str, secret_path: Option<&str>) -> Result<Self> {
+        let secret = match secret_path {
+            None => None,
+            Some(path) => {
+                let secret = read(path)
+                    .await
+                    .with_context(|| format!("failed to read event webhook secret: {}", path))?;
+
+                let secret = secret.trim_ascii_end().to_vec();
+
+                if secret.is_empty() {
+                    bail!("event webhook secret is empty: {}", path);
+                }
+
+                Some(secret)
+            }
+        };
+
+        let client = get_http_client_builder()?
+            .timeout(EVENT_DELIVERY_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            client,
+            secret,
+            url: url.to_string(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Posts `event` in the background, failures are logged and never reach the build.
+    pub fn send(&self, event: Value) {
+        let webhook = self.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = webhook.deliver(&event).await {
+                warn!("failed to deliver build event to {}: {}", webhook.url, err);
+            }
+        });
+    }
+
+    async fn deliver(&self, event: &Value) -> Result<()> {
+        let body = serde_json::to_vec(event)?;
+
+        let mut attempt = 1;
+
+        loop {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone());
+
+            if let Some(secret) = &self.secret {
+                request =
+                    request.header(EVENT_SIGNATURE_HEADER, get_event_signature(secret, &body));
+            }
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) if response.status().is_server_error() => {
+                    format!("status {}", response.status())
+                }
+                Ok(response) => bail!("status {}", response.status()),
+                Err(err) => err.to_string(),
+            };
+
+            if attempt >= EVENT_DELIVERY_ATTEMPTS {
+                bail!("{} (after {} attempts)", error, attempt);
+            }
+
+            sleep(EVENT_DELIVERY_RETRY_DELAY * attempt).await;
+
+            attempt += 1;
+        }
+    }
+}
+
+/// Returns the signature header value of `body`, `sha256=<hex>` of its HMAC-SHA256.
+pub fn get_event_signature(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any size");
+
+    mac.update(body);
+
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+fn get_event_error(status: &Status) -> String {
+    let mut error = format!("{}: {}", status.code(), status.message());
+
+    if error.len() > EVENT_ERROR_SIZE_MAX {
+        let mut end = EVENT_ERROR_SIZE_MAX;
+
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        error.truncate(end);
+    }
+
+    error
+}
+
+/// Events of one build request, identified by the artifact digest the worker derives from it.
+pub struct BuildEvents {
+    digest: String,
+    name: String,
+    system: ArtifactSystem,
+    webhook: EventWebhook,
+}
+
+impl BuildEvents {
+    pub fn new(request: &ArtifactBuildRequest, webhook: EventWebhook) -> Self {
+        let digest = serde_json::to_string(request)
+            .map(|manifest| ArtifactDigest::from_manifest(manifest.as_bytes()).to_string())
+            .unwrap_or_default();
+
+        let name = request
+            .artifact
+            .as_ref()
+            .map(|artifact| artifact.name.clone())
+            .unwrap_or_default();
+
+        let system =
+            ArtifactSystem::try_from(request.system).unwrap_or(ArtifactSystem::UnknownSystem);
+
+        Self {
+            digest,
+            name,
+            system,
+            webhook,
+        }
+    }
+
+    pub fn started(&self) {
+        self.send(BuildOutcome::Started, None, None);
+    }
+
+    /// Sends the outcome of the build, an `already_exists` error is reported as cached.
+    pub fn finished(&self, result: &Result<(), Status>, duration: Duration) {
+        match result {
+            Ok(_) => self.send(BuildOutcome::Success, Some(duration), None),
+            Err(err) if err.code() == Code::AlreadyExists => {
+                self.send(BuildOutcome::Cached, Some(duration), None)
+            }
+            Err(err) => self.send(
+                BuildOutcome::Failure,
+                Some(duration),
+                Some(get_event_error(err)),
+            ),
+        }
+    }
+
+    fn send(&self, outcome: BuildOutcome, duration: Option<Duration>, error: Option<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+
+        self.webhook.send(json!({
+            "digest": self.digest,
+            "duration_ms": duration.map(|duration| duration.as_millis() as u64),
+            "error": error,
+            "hostname": get_hostname(),
+            "name": self.name,
+            "outcome": outcome.as_str(),
+            "system": get_artifact_system_name(self.system),
+            "timestamp": timestamp,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+        time::timeout,
+    };
+    use vorpal_schema::vorpal::artifact::v0::Artifact;
+
+    // Headers and body of a request received by the test listener
+    type TestRequest = (BTreeMap<String, String>, Vec<u8>);
+
+    async fn read_request(stream: &mut TcpStream) -> TestRequest {
+        let mut data = vec![];
+        let mut buffer = [0; 4096];
+
+        let header_end = loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+
+            data.extend_from_slice(&buffer[..read]);
+
+            if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+
+        let headers = String::from_utf8_lossy(&data[..header_end])
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.to_lowercase(), value.trim().to_string()))
+            .collect::<BTreeMap<_, _>>();
+
+        let body_size = headers["content-length"].parse::<usize>().unwrap();
+
+        while data.len() < header_end + body_size {
+            let read = stream.read(&mut buffer).await.unwrap();
+
+            data.extend_from_slice(&buffer[..read]);
+        }
+
+        (headers, data[header_end..].to_vec())
+    }
+
+    // Answers requests with `statuses` in turn, then with 200
+    async fn get_test_listener(statuses: Vec<u16>) -> (String, mpsc::Receiver<TestRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+
+        let (tx, rx) = mpsc::channel(10);
+
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let request = read_request(&mut stream).await;
+
+                let status = statuses.next().unwrap_or(200);
+
+                let response = format!(
+                    "HTTP/1.1 {} Test\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+
+                tx.send(request).await.unwrap();
+            }
+        });
+
+        (url, rx)
+    }
+
+    async fn get_test_webhook(url: &str) -> EventWebhook {
+        let secret_dir = tempfile::tempdir().unwrap();
+        let secret_path = secret_dir.path().join("secret");
+
+        tokio::fs::write(&secret_path, "secret\n").await.unwrap();
+
+        EventWebhook::new(url, Some(secret_path.to_str().unwrap()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_server_errors() {
+        let (url, mut rx) = get_test_listener(vec![500]).await;
+
+        let webhook = get_test_webhook(&url).await;
+
+        webhook
+            .deliver(&json!({ "outcome": "started" }))
+            .await
+            .unwrap();
+
+        // The same signed body is posted again after the server error
+
+        for _ in 0..2 {
+            let (headers, body) = rx.recv().await.unwrap();
+
+            assert_eq!(body, br#"{"outcome":"started"}"#);
+            assert_eq!(headers["content-type"], "application/json");
+            assert_eq!(
+                headers[EVENT_SIGNATURE_HEADER],
+                get_event_signature(b"secret", &body)
+            );
+        }
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_client_error() {
+        let (url, mut rx) = get_test_listener(vec![404]).await;
+
+        let webhook = EventWebhook::new(&url, None).await.unwrap();
+
+        let error = webhook.deliver(&json!({})).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "status 404 Not Found");
+
+        // Client errors are not retried, and unsigned webhooks send no signature
+
+        let (headers, _) = rx.recv().await.unwrap();
+
+        assert!(!headers.contains_key(EVENT_SIGNATURE_HEADER));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_events() {
+        let (url, mut rx) = get_test_listener(vec![]).await;
+
+        let request = ArtifactBuildRequest {
+            artifact: Some(Artifact {
+                name: "example".to_string(),
+                ..Default::default()
+            }),
+            system: ArtifactSystem::X8664Linux as i32,
+            ..Default::default()
+        };
+
+        let events = BuildEvents::new(&request, get_test_webhook(&url).await);
+
+        let results = [
+            (Ok(()), "success", None),
+            (
+                Err(Status::already_exists("artifact exists")),
+                "cached",
+                None,
+            ),
+            (
+                Err(Status::internal("x".repeat(1024))),
+                "failure",
+                Some(EVENT_ERROR_SIZE_MAX),
+            ),
+        ];
+
+        for (result, outcome, error_size) in results {
+            events.finished(&result, Duration::from_millis(1500));
+
+            let (_, body) = timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            let event: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(event["digest"], events.digest.as_str());
+            assert_eq!(event["duration_ms"], 1500);
+            assert_eq!(event["name"], "example");
+            assert_eq!(event["outcome"], outcome);
+            assert_eq!(event["system"], "x86_64-linux");
+            assert_eq!(event["error"].as_str().map(|error| error.len()), error_size);
+        }
+    }
+}